@@ -0,0 +1,47 @@
+#!/bin/sh
+# Resolve backtrace addresses in a kernel log to functions and source lines
+#
+#   ./scripts/symbolize.sh < crash.log
+#   ./scripts/symbolize.sh target/aarch64-unknown-none/release/akuma < crash.log
+#
+# Lines like "  #3  0x0000000040081234" get the symbol appended; everything
+# else is passed through. Uses llvm-addr2line (from `rustup component add
+# llvm-tools`) or a cross binutils addr2line, whichever is found first.
+
+ELF="${1:-target/aarch64-unknown-none/debug/akuma}"
+
+if [ ! -f "$ELF" ]; then
+    echo "symbolize: kernel ELF '$ELF' not found" >&2
+    exit 1
+fi
+
+ADDR2LINE=""
+for tool in llvm-addr2line aarch64-none-elf-addr2line aarch64-linux-gnu-addr2line; do
+    if command -v "$tool" >/dev/null 2>&1; then
+        ADDR2LINE="$tool"
+        break
+    fi
+done
+if [ -z "$ADDR2LINE" ]; then
+    SYSROOT="$(rustc --print sysroot 2>/dev/null)"
+    FOUND="$(find "$SYSROOT" -name llvm-addr2line -type f 2>/dev/null | head -n 1)"
+    [ -n "$FOUND" ] && ADDR2LINE="$FOUND"
+fi
+if [ -z "$ADDR2LINE" ]; then
+    echo "symbolize: no addr2line found (try: rustup component add llvm-tools)" >&2
+    exit 1
+fi
+
+while IFS= read -r line; do
+    addr="$(printf '%s\n' "$line" | sed -n 's/^[[:space:]]*#[0-9]*[[:space:]]*\(0x[0-9a-fA-F]*\).*/\1/p')"
+    if [ -n "$addr" ]; then
+        # Return addresses point after the call; look up the call itself
+        # (#0 of a fault report is the faulting instruction, so it may
+        # resolve to the line just before it)
+        call="$(printf '0x%x' $((addr - 4)))"
+        sym="$("$ADDR2LINE" -e "$ELF" -f -C -p "$call" 2>/dev/null)"
+        printf '%s  %s\n' "$line" "$sym"
+    else
+        printf '%s\n' "$line"
+    fi
+done
@@ -1,11 +1,59 @@
-use spinning_top::Spinlock;
-use talc::ErrOnOom;
-use talc::{Span, Talc};
+use crate::error::{ErrorKind, KError, KResult, Subsystem};
+use crate::events::Event;
+use crate::lockdep::Spinlock;
+use crate::pmm;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use talc::{OomHandler, Span, Talc};
 
 #[global_allocator]
 static ALLOCATOR: Talck = Talck;
 
-static TALC: Spinlock<Talc<ErrOnOom>> = Spinlock::new(Talc::new(ErrOnOom));
+static TALC: Spinlock<Talc<PageGrowth>> =
+    Spinlock::new(Talc::new(PageGrowth { enabled: true }));
+
+/// Heap claimed from the page allocator at init
+const HEAP_INITIAL_SIZE: usize = 8 * 1024 * 1024;
+
+/// Minimum bytes requested from the page allocator when the heap grows
+const HEAP_GROW_MIN: usize = 1024 * 1024;
+
+/// Slack added to growth requests for talc's per-span and per-chunk metadata
+const HEAP_GROW_OVERHEAD: usize = 256;
+
+// Heap usage counters - updated with IRQs disabled inside alloc/dealloc
+static HEAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+static USED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+static LIVE_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static TOTAL_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+/// Snapshot of heap usage
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Total bytes claimed for the heap
+    pub total: usize,
+    /// Bytes currently allocated (requested sizes, excluding allocator overhead)
+    pub used: usize,
+    /// Highest value `used` has reached since boot
+    pub peak: usize,
+    /// Number of live allocations
+    pub allocations: usize,
+    /// Number of allocations made since boot
+    pub total_allocations: usize,
+    /// Largest single allocation that would currently succeed
+    pub largest_free: usize,
+}
+
+impl HeapStats {
+    /// Bytes not currently allocated (includes fragmentation and overhead)
+    #[cfg(feature = "shell")]
+    pub fn free(&self) -> usize {
+        self.total.saturating_sub(self.used)
+    }
+}
 
 /// No-op for backwards compatibility - IRQs are now always disabled during allocation
 pub fn enable_preemption_safe_alloc() {}
@@ -32,41 +80,1303 @@ fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
     result
 }
 
-pub fn init(heap_start: usize, heap_size: usize) -> Result<(), &'static str> {
-    if heap_size == 0 {
-        return Err("Heap size cannot be zero");
-    }
+/// Grows the heap from the page allocator when talc runs out of memory
+///
+/// Runs with the heap lock held and IRQs disabled; takes the page allocator
+/// lock beneath it (heap -> pmm is the only lock order).
+struct PageGrowth {
+    /// Cleared while probing so the probe can't grow the heap
+    enabled: bool,
+}
 
-    if heap_start == 0 {
-        return Err("Invalid heap start address");
+impl OomHandler for PageGrowth {
+    fn handle_oom(talc: &mut Talc<Self>, layout: core::alloc::Layout) -> Result<(), ()> {
+        if !talc.oom_handler.enabled {
+            return Err(());
+        }
+
+        let needed = layout.size() + layout.align() + HEAP_GROW_OVERHEAD;
+        let order = pmm::order_for_size(needed.max(HEAP_GROW_MIN));
+        let base = pmm::alloc_pages(order).ok_or(())?;
+        let size = pmm::PAGE_SIZE << order;
+
+        // SAFETY: the block is ours until freed, and nothing frees heap pages
+        unsafe {
+            talc.claim(Span::from_base_size(base as *mut u8, size))?;
+        }
+        HEAP_SIZE.fetch_add(size, Ordering::Relaxed);
+        Ok(())
     }
+}
 
-    unsafe {
-        let heap_ptr = heap_start as *mut u8;
-        let span = Span::from_base_size(heap_ptr, heap_size);
+/// Claim the initial heap from the page allocator (call after `pmm::init`)
+/// The heap grows on demand afterwards.
+pub fn init() -> KResult<()> {
+    let order = pmm::order_for_size(HEAP_INITIAL_SIZE);
+    let base = pmm::alloc_pages(order)
+        .ok_or(KError::with_context(ErrorKind::OutOfMemory, "initial heap"))?;
+    let size = pmm::PAGE_SIZE << order;
+
+    with_irqs_disabled(|| unsafe {
+        let span = Span::from_base_size(base as *mut u8, size);
         TALC.lock()
             .claim(span)
-            .map_err(|_| "Failed to claim heap memory")?;
+            .map(|_| ())
+            .map_err(|_| KError::with_context(ErrorKind::InvalidRegion, "heap claim"))
+    })?;
+
+    HEAP_SIZE.store(size, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Get a snapshot of heap usage
+///
+/// Probing the largest free block briefly takes the heap lock with IRQs
+/// disabled, so avoid calling this from hot paths. `total` counts only
+/// memory already claimed from the page allocator; the heap can grow
+/// further while free pages remain.
+pub fn stats() -> HeapStats {
+    let used = USED_BYTES.load(Ordering::Relaxed);
+    let total = HEAP_SIZE.load(Ordering::Relaxed);
+
+    HeapStats {
+        total,
+        used,
+        peak: PEAK_BYTES.load(Ordering::Relaxed),
+        allocations: LIVE_ALLOCS.load(Ordering::Relaxed),
+        total_allocations: TOTAL_ALLOCS.load(Ordering::Relaxed),
+        largest_free: largest_free_block(total.saturating_sub(used)),
+    }
+}
+
+/// Find the largest allocation that currently succeeds by binary search.
+/// Talc doesn't expose its free lists, so we probe with malloc/free.
+/// Heap growth is suspended during the probe.
+fn largest_free_block(upper_bound: usize) -> usize {
+    with_irqs_disabled(|| {
+        let mut talc = TALC.lock();
+        talc.oom_handler.enabled = false;
+        let mut lo = 0;
+        let mut hi = upper_bound;
+
+        while lo < hi {
+            let mid = lo + (hi - lo).div_ceil(2);
+            let layout = match core::alloc::Layout::from_size_align(mid, 8) {
+                Ok(layout) => layout,
+                Err(_) => break,
+            };
+
+            match unsafe { talc.malloc(layout) } {
+                Ok(ptr) => {
+                    unsafe { talc.free(ptr, layout) };
+                    lo = mid;
+                }
+                Err(_) => hi = mid - 1,
+            }
+        }
+
+        talc.oom_handler.enabled = true;
+        lo
+    })
+}
+
+// ============================================================================
+// Size Classes
+// ============================================================================
+//
+// Small allocations (<= 2 KB) come from per-size free lists backed by whole
+// pages, not from talc. Long SSH sessions interleave many small buffers
+// with large ones; keeping the small ones out of the general heap stops
+// them pinning holes between large blocks, so large allocations keep
+// succeeding after long uptimes. Class pages are never returned.
+
+/// Object sizes served from size classes (powers of two, naturally aligned)
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Pages claimed per refill (16 KB: eight of the largest class)
+const CLASS_REFILL_ORDER: usize = 2;
+
+/// RAM window covered by the class-page bitmap
+const CLASS_WINDOW_BASE: usize = 0x4000_0000;
+const CLASS_WINDOW_PAGES: usize = (1024 * 1024 * 1024) / pmm::PAGE_SIZE;
+
+struct SizeClass {
+    /// Address of the first free object (0 = empty)
+    free_list: usize,
+    pages: usize,
+    in_use: usize,
+    free: usize,
+}
+
+static CLASSES: Spinlock<[SizeClass; SIZE_CLASSES.len()]> = Spinlock::new(
+    [const {
+        SizeClass {
+            free_list: 0,
+            pages: 0,
+            in_use: 0,
+            free: 0,
+        }
+    }; SIZE_CLASSES.len()],
+);
+
+/// One bit per page in the window: set if the page belongs to a size class
+static CLASS_PAGES: [AtomicU64; CLASS_WINDOW_PAGES / 64] =
+    [const { AtomicU64::new(0) }; CLASS_WINDOW_PAGES / 64];
+
+/// New small allocations use size classes (`alloc.size_classes=0` disables)
+static SIZE_CLASSES_ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub fn set_size_classes_enabled(enabled: bool) {
+    SIZE_CLASSES_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Class index serving `layout`, if it's small enough
+fn class_for(layout: Layout) -> Option<usize> {
+    let need = layout.size().max(layout.align()).max(1);
+    SIZE_CLASSES.iter().position(|&size| size >= need)
+}
+
+fn is_class_page(addr: usize) -> bool {
+    let page = addr.wrapping_sub(CLASS_WINDOW_BASE) / pmm::PAGE_SIZE;
+    page < CLASS_WINDOW_PAGES
+        && CLASS_PAGES[page / 64].load(Ordering::Relaxed) & (1 << (page % 64)) != 0
+}
+
+fn mark_class_pages(base: usize, pages: usize) {
+    for i in 0..pages {
+        let page = (base - CLASS_WINDOW_BASE) / pmm::PAGE_SIZE + i;
+        CLASS_PAGES[page / 64].fetch_or(1 << (page % 64), Ordering::Relaxed);
+    }
+}
+
+/// Take an object from class `idx`, refilling from the page allocator
+/// Called with IRQs disabled. Returns null if no pages are left.
+fn class_alloc(idx: usize) -> *mut u8 {
+    let mut classes = CLASSES.lock();
+    let class = &mut classes[idx];
+
+    if class.free_list == 0 {
+        let block = match pmm::alloc_pages(CLASS_REFILL_ORDER) {
+            Some(block) => block,
+            None => return core::ptr::null_mut(),
+        };
+        let pages = 1 << CLASS_REFILL_ORDER;
+        let bytes = pmm::PAGE_SIZE * pages;
+        if block < CLASS_WINDOW_BASE
+            || block + bytes > CLASS_WINDOW_BASE + CLASS_WINDOW_PAGES * pmm::PAGE_SIZE
+        {
+            unsafe { pmm::free_pages(block, CLASS_REFILL_ORDER) };
+            return core::ptr::null_mut();
+        }
+        mark_class_pages(block, pages);
+        HEAP_SIZE.fetch_add(bytes, Ordering::Relaxed);
+
+        // Thread the new objects onto the free list
+        let size = SIZE_CLASSES[idx];
+        for obj in (block..block + bytes).step_by(size).rev() {
+            // SAFETY: obj is inside the block we just claimed
+            unsafe { *(obj as *mut usize) = class.free_list };
+            class.free_list = obj;
+        }
+        class.pages += pages;
+        class.free += bytes / size;
+    }
+
+    let obj = class.free_list;
+    // SAFETY: obj is a free object; its first word links to the next one
+    class.free_list = unsafe { *(obj as *const usize) };
+    class.free -= 1;
+    class.in_use += 1;
+    obj as *mut u8
+}
+
+/// Return an object to class `idx` (called with IRQs disabled)
+fn class_free(idx: usize, ptr: *mut u8) {
+    let mut classes = CLASSES.lock();
+    let class = &mut classes[idx];
+    // SAFETY: ptr came from class_alloc for this class and is no longer used;
+    // every class holds at least two words. The second is cleared so the
+    // scrubber treats the object as not yet poisoned.
+    unsafe {
+        *(ptr as *mut usize) = class.free_list;
+        *(ptr as *mut usize).add(1) = 0;
+    }
+    class.free_list = ptr as usize;
+    class.free += 1;
+    class.in_use -= 1;
+}
+
+// ============================================================================
+// Fragmentation Report
+// ============================================================================
+
+/// Free-block histogram buckets: [64 << i, 64 << (i + 1)), last is open-ended
+#[cfg(feature = "shell")]
+pub const FRAG_BUCKETS: usize = 16;
+
+/// Smallest free block counted by the report
+#[cfg(feature = "shell")]
+const FRAG_MIN_BLOCK: usize = 64;
+
+/// Most free blocks extracted per report (the rest is summarized)
+#[cfg(feature = "shell")]
+const FRAG_MAX_BLOCKS: usize = 128;
+
+/// Usage of one size class
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone, Copy)]
+pub struct ClassStats {
+    pub size: usize,
+    pub pages: usize,
+    pub in_use: usize,
+    pub free: usize,
+}
+
+/// Free memory layout of the general heap plus size-class usage
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone, Copy)]
+pub struct FragReport {
+    /// Free talc blocks per size bucket
+    pub histogram: [usize; FRAG_BUCKETS],
+    /// Free talc blocks found (>= 64 bytes)
+    pub blocks: usize,
+    /// Bytes in those blocks
+    pub free_bytes: usize,
+    pub largest: usize,
+    /// More than FRAG_MAX_BLOCKS blocks exist; the histogram is partial
+    pub truncated: bool,
+    pub classes: [ClassStats; SIZE_CLASSES.len()],
+}
+
+#[cfg(feature = "shell")]
+impl FragReport {
+    /// Share of free heap memory outside the largest block (0 = one free block)
+    pub fn fragmentation_percent(&self) -> usize {
+        if self.free_bytes == 0 {
+            return 0;
+        }
+        100 - self.largest * 100 / self.free_bytes
+    }
+
+    /// Lower bound of histogram bucket `i`
+    pub fn bucket_floor(i: usize) -> usize {
+        FRAG_MIN_BLOCK << i
+    }
+}
+
+#[cfg(feature = "shell")]
+fn frag_bucket(size: usize) -> usize {
+    let mut bucket = 0;
+    while bucket + 1 < FRAG_BUCKETS && size >= FRAG_MIN_BLOCK << (bucket + 1) {
+        bucket += 1;
+    }
+    bucket
+}
+
+/// Build a free-block histogram of the general heap
+///
+/// Talc doesn't expose its free lists, so blocks are found by repeatedly
+/// taking the largest free block (binary-search probe) until only small
+/// fragments remain, then all are released. Holds the heap lock with IRQs
+/// disabled throughout; meant for diagnostics, not hot paths.
+#[cfg(feature = "shell")]
+pub fn fragmentation_report() -> FragReport {
+    let mut histogram = [0; FRAG_BUCKETS];
+    let mut taken: [(usize, usize); FRAG_MAX_BLOCKS] = [(0, 0); FRAG_MAX_BLOCKS];
+    let mut count = 0;
+    let mut free_bytes = 0;
+    let mut largest = 0;
+    let mut truncated = false;
+
+    with_irqs_disabled(|| {
+        let mut talc = TALC.lock();
+        talc.oom_handler.enabled = false;
+        let mut upper = HEAP_SIZE.load(Ordering::Relaxed);
+
+        loop {
+            // Largest block still free
+            let (mut lo, mut hi) = (0, upper);
+            while lo < hi {
+                let mid = lo + (hi - lo).div_ceil(2);
+                let layout = Layout::from_size_align(mid, 8).unwrap();
+                match unsafe { talc.malloc(layout) } {
+                    Ok(ptr) => {
+                        unsafe { talc.free(ptr, layout) };
+                        lo = mid;
+                    }
+                    Err(_) => hi = mid - 1,
+                }
+            }
+            if lo < FRAG_MIN_BLOCK {
+                break;
+            }
+            if count == FRAG_MAX_BLOCKS {
+                truncated = true;
+                break;
+            }
+
+            let layout = Layout::from_size_align(lo, 8).unwrap();
+            let ptr = match unsafe { talc.malloc(layout) } {
+                Ok(ptr) => ptr.as_ptr() as usize,
+                Err(_) => break,
+            };
+            taken[count] = (ptr, lo);
+            count += 1;
+            histogram[frag_bucket(lo)] += 1;
+            free_bytes += lo;
+            largest = largest.max(lo);
+            upper = lo; // Next block can't be larger
+        }
+
+        for &(ptr, size) in taken.iter().take(count) {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            unsafe { talc.free(core::ptr::NonNull::new_unchecked(ptr as *mut u8), layout) };
+        }
+        talc.oom_handler.enabled = true;
+    });
+
+    let classes = with_irqs_disabled(|| {
+        let classes = CLASSES.lock();
+        core::array::from_fn(|i| ClassStats {
+            size: SIZE_CLASSES[i],
+            pages: classes[i].pages,
+            in_use: classes[i].in_use,
+            free: classes[i].free,
+        })
+    });
+
+    FragReport {
+        histogram,
+        blocks: count,
+        free_bytes,
+        largest,
+        truncated,
+        classes,
+    }
+}
+
+// ============================================================================
+// Fallible Allocation
+// ============================================================================
+
+/// Heap exhausted (after OOM callbacks had their chance)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+impl core::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("out of memory")
+    }
+}
+
+impl From<AllocError> for KError {
+    fn from(_: AllocError) -> Self {
+        KError::new(ErrorKind::OutOfMemory)
+    }
+}
+
+/// Empty Vec with room for `capacity` elements
+pub fn try_vec<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    let mut v = Vec::new();
+    v.try_reserve_exact(capacity).map_err(|_| AllocError)?;
+    Ok(v)
+}
+
+/// Zero-filled byte buffer of `len` bytes
+pub fn try_zeroed_vec(len: usize) -> Result<Vec<u8>, AllocError> {
+    let mut v = try_vec(len)?;
+    v.resize(len, 0);
+    Ok(v)
+}
+
+/// Append `data` to `vec`, growing it fallibly
+#[cfg(any(feature = "shell", feature = "http"))]
+pub fn try_extend(vec: &mut Vec<u8>, data: &[u8]) -> Result<(), AllocError> {
+    vec.try_reserve(data.len()).map_err(|_| AllocError)?;
+    vec.extend_from_slice(data);
+    Ok(())
+}
+
+/// Move `value` to the heap, handing it back if there's no memory
+pub fn try_box<T>(value: T) -> Result<Box<T>, T> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+
+    // SAFETY: non-zero size; on success the memory is initialized before
+    // ownership passes to Box with the same layout
+    unsafe {
+        let ptr = alloc::alloc::alloc(layout) as *mut T;
+        if ptr.is_null() {
+            return Err(value);
+        }
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+// ============================================================================
+// Zero-on-Free
+// ============================================================================
+
+/// Wipe every block as it is freed, so secrets don't linger in free memory
+/// On by default with the `zero-on-free` feature; `alloc.zero_on_free=1`
+/// on the command line turns it on at boot.
+static ZERO_ON_FREE: AtomicBool = AtomicBool::new(cfg!(feature = "zero-on-free"));
+
+pub fn set_zero_on_free(enabled: bool) {
+    ZERO_ON_FREE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn zero_on_free() -> bool {
+    ZERO_ON_FREE.load(Ordering::Relaxed)
+}
+
+// ============================================================================
+// Aligned Allocation
+// ============================================================================
+
+/// Heap buffer of `T`s with a guaranteed alignment
+///
+/// `Vec` only promises `align_of::<T>()`; device rings and DMA buffers
+/// need more (virtqueue rings, whole cache lines so invalidation can't
+/// clobber neighbouring data). Freed with the same layout on drop.
+pub struct AlignedBuf<T: Copy = u8> {
+    ptr: core::ptr::NonNull<T>,
+    len: usize,
+    layout: Layout,
+}
+
+// SAFETY: AlignedBuf owns its memory exclusively, like Box<[T]>
+unsafe impl<T: Copy + Send> Send for AlignedBuf<T> {}
+unsafe impl<T: Copy + Sync> Sync for AlignedBuf<T> {}
+
+impl<T: Copy> core::ops::Deref for AlignedBuf<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // SAFETY: ptr holds len initialized elements
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Copy> core::ops::DerefMut for AlignedBuf<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        // SAFETY: as above, and we own the memory exclusively
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Copy> Drop for AlignedBuf<T> {
+    fn drop(&mut self) {
+        // SAFETY: allocated in alloc_aligned_slice with this layout
+        unsafe { alloc::alloc::dealloc(self.ptr.as_ptr() as *mut u8, self.layout) };
+    }
+}
+
+/// `count` elements set to `fill`, aligned to at least `align` bytes
+///
+/// `align` must be a power of two; it is raised to `align_of::<T>()` if lower.
+pub fn alloc_aligned_slice<T: Copy>(
+    count: usize,
+    align: usize,
+    fill: T,
+) -> Result<AlignedBuf<T>, AllocError> {
+    let size = core::mem::size_of::<T>()
+        .checked_mul(count)
+        .ok_or(AllocError)?;
+    let align = align.max(core::mem::align_of::<T>());
+    let layout = Layout::from_size_align(size.max(1), align).map_err(|_| AllocError)?;
+
+    // SAFETY: non-zero size; every element is written before use
+    let ptr = unsafe { alloc::alloc::alloc(layout) as *mut T };
+    let ptr = core::ptr::NonNull::new(ptr).ok_or(AllocError)?;
+    for i in 0..count {
+        // SAFETY: i < count, inside the allocation
+        unsafe { ptr.as_ptr().add(i).write(fill) };
+    }
+
+    Ok(AlignedBuf {
+        ptr,
+        len: count,
+        layout,
+    })
+}
+
+/// Zeroed byte buffer of `size` bytes aligned to `align`
+pub fn alloc_aligned(size: usize, align: usize) -> Result<AlignedBuf<u8>, AllocError> {
+    alloc_aligned_slice(size, align, 0u8)
+}
+
+/// Ring of `entries` default-initialized `T`s, aligned to `align`
+///
+/// Virtqueue layouts need power-of-two queue sizes; anything else is refused.
+pub fn alloc_ring<T: Copy + Default>(
+    entries: usize,
+    align: usize,
+) -> Result<AlignedBuf<T>, AllocError> {
+    if !entries.is_power_of_two() {
+        return Err(AllocError);
+    }
+    alloc_aligned_slice(entries, align, T::default())
+}
+
+// ============================================================================
+// Out-of-Memory Callbacks
+// ============================================================================
+
+/// Called when an allocation fails; returns bytes released (0 if none)
+///
+/// Runs with IRQs disabled from inside the allocator. It may free memory
+/// but must not allocate - nested allocations skip the callbacks.
+pub type OomCallback = fn(Layout) -> usize;
+
+const MAX_OOM_CALLBACKS: usize = 4;
+
+static OOM_CALLBACKS: Spinlock<[Option<OomCallback>; MAX_OOM_CALLBACKS]> =
+    Spinlock::new([None; MAX_OOM_CALLBACKS]);
+static IN_OOM: AtomicBool = AtomicBool::new(false);
+static OOM_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Register a callback run when the heap (and page allocator) is exhausted
+/// If any callback reports released memory, the allocation is retried once.
+pub fn register_oom_callback(callback: OomCallback) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut callbacks = OOM_CALLBACKS.lock();
+        match callbacks.iter_mut().find(|c| c.is_none()) {
+            Some(slot) => {
+                *slot = Some(callback);
+                Ok(())
+            }
+            None => Err(KError::with_context(ErrorKind::LimitReached, "OOM callbacks")),
+        }
+    })
+}
+
+/// Number of allocations that failed outright since boot
+pub fn oom_events() -> usize {
+    OOM_EVENTS.load(Ordering::Relaxed)
+}
+
+/// Run the OOM callbacks (called with IRQs disabled, heap lock released)
+/// Returns true if any of them released memory.
+fn run_oom_callbacks(layout: Layout) -> bool {
+    if IN_OOM.swap(true, Ordering::Acquire) {
+        return false;
+    }
+
+    let callbacks = *OOM_CALLBACKS.lock();
+    let mut released = 0;
+    for callback in callbacks.iter().flatten() {
+        released += callback(layout);
+    }
+
+    IN_OOM.store(false, Ordering::Release);
+    released > 0
+}
+
+// ============================================================================
+// Allocation Tracking
+// ============================================================================
+
+/// Slots in the tracking table (power of two)
+const TRACK_CAPACITY: usize = 4096;
+
+/// Return addresses recorded per allocation (innermost first)
+pub const TRACK_DEPTH: usize = 4;
+
+/// Empty marker for the open-addressed table
+///
+/// Removal shifts later entries of the probe run back instead of leaving
+/// tombstones, so probes stay short however long tracking runs.
+const SLOT_EMPTY: usize = 0;
+
+/// A live allocation recorded while tracking is on
+#[derive(Debug, Clone, Copy)]
+pub struct TrackedAlloc {
+    pub ptr: usize,
+    pub size: usize,
+    /// Allocation sequence number (compare against `checkpoint`)
+    pub seq: u64,
+    /// Return addresses from the frame-pointer chain, 0 = unknown
+    pub callers: [usize; TRACK_DEPTH],
+}
+
+struct Tracker {
+    /// Table of TRACK_CAPACITY entries in pages from the page allocator
+    table: usize,
+    order: usize,
+    live: usize,
+    /// Allocations not recorded because the table was full
+    dropped: usize,
+    next_seq: u64,
+}
+
+impl Tracker {
+    fn slot(&self, i: usize) -> *mut TrackedAlloc {
+        (self.table + i * core::mem::size_of::<TrackedAlloc>()) as *mut TrackedAlloc
+    }
+
+    fn hash(ptr: usize) -> usize {
+        (ptr >> 4).wrapping_mul(0x9E37_79B9) & (TRACK_CAPACITY - 1)
     }
 
+    fn insert(&mut self, ptr: usize, size: usize, callers: [usize; TRACK_DEPTH]) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if self.table == 0 {
+            return;
+        }
+
+        // Keep a quarter free so probes stay short
+        if self.live >= TRACK_CAPACITY * 3 / 4 {
+            self.dropped += 1;
+            return;
+        }
+
+        let mut i = Self::hash(ptr);
+        loop {
+            // SAFETY: i < TRACK_CAPACITY and the table is ours
+            let entry = unsafe { &mut *self.slot(i) };
+            if entry.ptr == SLOT_EMPTY {
+                *entry = TrackedAlloc {
+                    ptr,
+                    size,
+                    seq,
+                    callers,
+                };
+                self.live += 1;
+                return;
+            }
+            i = (i + 1) & (TRACK_CAPACITY - 1);
+        }
+    }
+
+    fn remove(&mut self, ptr: usize) {
+        if self.table == 0 {
+            return;
+        }
+        let mut i = Self::hash(ptr);
+        for _ in 0..TRACK_CAPACITY {
+            // SAFETY: as above
+            let entry = unsafe { &mut *self.slot(i) };
+            if entry.ptr == SLOT_EMPTY {
+                return; // Allocated before tracking started, or dropped
+            }
+            if entry.ptr == ptr {
+                self.shift_back(i);
+                self.live -= 1;
+                return;
+            }
+            i = (i + 1) & (TRACK_CAPACITY - 1);
+        }
+    }
+
+    /// Empty slot `hole`, moving back entries whose probe run crosses it
+    fn shift_back(&mut self, mut hole: usize) {
+        let mask = TRACK_CAPACITY - 1;
+        let mut i = hole;
+        loop {
+            i = (i + 1) & mask;
+            // SAFETY: as above
+            let entry = unsafe { *self.slot(i) };
+            if entry.ptr == SLOT_EMPTY {
+                break;
+            }
+            // Entry may move to the hole if its home slot isn't in (hole, i]
+            let home = Self::hash(entry.ptr);
+            if (i.wrapping_sub(home) & mask) >= (i.wrapping_sub(hole) & mask) {
+                unsafe { *self.slot(hole) = entry };
+                hole = i;
+            }
+        }
+        unsafe { (*self.slot(hole)).ptr = SLOT_EMPTY };
+    }
+
+    #[cfg(feature = "shell")]
+    fn entries(&self) -> impl Iterator<Item = &TrackedAlloc> {
+        // SAFETY: every slot is initialized (table zeroed on enable)
+        (0..TRACK_CAPACITY)
+            .map(move |i| unsafe { &*self.slot(i) })
+            .filter(|e| e.ptr != SLOT_EMPTY)
+    }
+}
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static TRACKER: Spinlock<Tracker> = Spinlock::new(Tracker {
+    table: 0,
+    order: 0,
+    live: 0,
+    dropped: 0,
+    next_seq: 0,
+});
+
+/// Walk the frame-pointer chain from the caller of the allocator
+/// Stops early at anything that doesn't look like a frame record.
+#[inline(always)]
+fn capture_callers() -> [usize; TRACK_DEPTH] {
+    let mut callers = [0; TRACK_DEPTH];
+    crate::backtrace::walk(crate::backtrace::frame_pointer(), &mut callers);
+    callers
+}
+
+/// Start recording live allocations
+///
+/// The table lives in pages from the page allocator, so it costs nothing
+/// while tracking is off. Only allocations made after this call are seen.
+pub fn enable_tracking() -> KResult<()> {
+    if TRACKING.load(Ordering::Acquire) {
+        return Ok(());
+    }
+
+    let bytes = TRACK_CAPACITY * core::mem::size_of::<TrackedAlloc>();
+    let order = pmm::order_for_size(bytes);
+    let table = pmm::alloc_pages(order)
+        .ok_or(KError::with_context(ErrorKind::OutOfMemory, "allocation tracking table"))?;
+    unsafe {
+        core::ptr::write_bytes(table as *mut u8, 0, bytes);
+    }
+
+    with_irqs_disabled(|| {
+        let mut tracker = TRACKER.lock();
+        tracker.table = table;
+        tracker.order = order;
+        tracker.live = 0;
+        tracker.dropped = 0;
+        TRACKING.store(true, Ordering::Release);
+    });
     Ok(())
 }
 
+/// Stop recording and release the table
+#[cfg(feature = "shell")]
+pub fn disable_tracking() {
+    let (table, order) = with_irqs_disabled(|| {
+        let mut tracker = TRACKER.lock();
+        TRACKING.store(false, Ordering::Release);
+        let old = (tracker.table, tracker.order);
+        tracker.table = 0;
+        tracker.live = 0;
+        old
+    });
+
+    if table != 0 {
+        unsafe { pmm::free_pages(table, order) };
+    }
+}
+
+/// True while allocations are being recorded
+#[cfg(feature = "shell")]
+pub fn tracking_enabled() -> bool {
+    TRACKING.load(Ordering::Acquire)
+}
+
+/// Sequence number marking "now"; pass to `allocations_since` later
+#[cfg(feature = "shell")]
+pub fn checkpoint() -> u64 {
+    with_irqs_disabled(|| TRACKER.lock().next_seq)
+}
+
+/// Tracked allocations still live, and allocations missed because the table was full
+#[cfg(feature = "shell")]
+pub fn tracking_stats() -> (usize, usize) {
+    with_irqs_disabled(|| {
+        let tracker = TRACKER.lock();
+        (tracker.live, tracker.dropped)
+    })
+}
+
+/// Allocations made after `checkpoint` that are still live, oldest first
+#[cfg(feature = "shell")]
+pub fn allocations_since(checkpoint: u64) -> Vec<TrackedAlloc> {
+    if !tracking_enabled() {
+        return Vec::new();
+    }
+
+    // Size the result outside the tracker lock - allocating takes it too
+    let (count, end) = with_irqs_disabled(|| {
+        let tracker = TRACKER.lock();
+        let count = tracker.entries().filter(|e| e.seq >= checkpoint).count();
+        (count, tracker.next_seq)
+    });
+
+    let mut out = match try_vec(count) {
+        Ok(v) => v,
+        Err(_) => return Vec::new(),
+    };
+
+    with_irqs_disabled(|| {
+        let tracker = TRACKER.lock();
+        for e in tracker.entries() {
+            // Skip our own allocation of `out`
+            if e.seq >= checkpoint && e.seq < end && out.len() < out.capacity() {
+                out.push(*e);
+            }
+        }
+    });
+
+    out.sort_unstable_by_key(|e| e.seq);
+    out
+}
+
+// ============================================================================
+// Burst Profiler
+// ============================================================================
+//
+// For a short window (started from the shell) every allocation is counted
+// into a size histogram under the subsystem tag that was current when it
+// was made, alongside the number of network packets handled, to show what
+// the hot path allocates per packet. Tags are set with `tag_scope` around
+// the code being attributed. They describe the CPU, not the thread: if the
+// tagged code is preempted, the other thread's allocations land under its
+// tag too, which the single-threaded network loop makes rare.
+
+/// Histogram buckets: <=16, <=32, ... <=64K, larger
+pub const PROFILE_BUCKETS: usize = 14;
+
+const TAGS: usize = Subsystem::ALL.len();
+
+static PROFILING: AtomicBool = AtomicBool::new(false);
+/// Window bounds, in `timer::cycles`
+#[cfg(feature = "shell")]
+static PROFILE_START: AtomicU64 = AtomicU64::new(0);
+static PROFILE_END: AtomicU64 = AtomicU64::new(0);
+static PROFILE_PACKETS: AtomicU64 = AtomicU64::new(0);
+static PROFILE_BYTES: [AtomicU64; TAGS] = [const { AtomicU64::new(0) }; TAGS];
+static PROFILE_HIST: [[AtomicU64; PROFILE_BUCKETS]; TAGS] =
+    [const { [const { AtomicU64::new(0) }; PROFILE_BUCKETS] }; TAGS];
+
+/// Subsystem new allocations are attributed to (index into Subsystem::ALL)
+static CURRENT_TAG: AtomicU8 = AtomicU8::new(0);
+
+/// Restores the previous allocation tag when dropped
+pub struct TagGuard {
+    previous: u8,
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        CURRENT_TAG.store(self.previous, Ordering::Relaxed);
+    }
+}
+
+/// Attribute allocations to `subsystem` until the guard is dropped
+pub fn tag_scope(subsystem: Subsystem) -> TagGuard {
+    let tag = Subsystem::ALL.iter().position(|&s| s == subsystem).unwrap_or(0) as u8;
+    TagGuard {
+        previous: CURRENT_TAG.swap(tag, Ordering::Relaxed),
+    }
+}
+
+/// Histogram bucket for an allocation of `size` bytes
+pub fn profile_bucket(size: usize) -> usize {
+    let bits = (usize::BITS - size.max(1).saturating_sub(1).leading_zeros()) as usize;
+    bits.saturating_sub(4).min(PROFILE_BUCKETS - 1)
+}
+
+/// Largest size in bucket `i` (None for the open-ended last bucket)
+#[cfg(feature = "shell")]
+pub fn profile_bucket_limit(i: usize) -> Option<usize> {
+    (i + 1 < PROFILE_BUCKETS).then(|| 16 << i)
+}
+
+/// Start a profiling window of `duration_us`, discarding the previous one
+#[cfg(feature = "shell")]
+pub fn start_profile(duration_us: u64) {
+    PROFILING.store(false, Ordering::Release);
+    for tag in 0..TAGS {
+        PROFILE_BYTES[tag].store(0, Ordering::Relaxed);
+        for bucket in &PROFILE_HIST[tag] {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+    PROFILE_PACKETS.store(0, Ordering::Relaxed);
+    let now = crate::timer::cycles();
+    PROFILE_START.store(now, Ordering::Relaxed);
+    let duration = crate::timer::us_to_cycles(duration_us);
+    PROFILE_END.store(now.saturating_add(duration), Ordering::Relaxed);
+    PROFILING.store(true, Ordering::Release);
+}
+
+/// End the window early
+#[cfg(feature = "shell")]
+pub fn stop_profile() {
+    if PROFILING.swap(false, Ordering::AcqRel) {
+        let now = crate::timer::cycles();
+        PROFILE_END.fetch_min(now, Ordering::Relaxed);
+    }
+}
+
+/// True while the window is open
+fn profile_window_open() -> bool {
+    PROFILING.load(Ordering::Relaxed)
+        && crate::timer::cycles() < PROFILE_END.load(Ordering::Relaxed)
+}
+
+/// Count a network packet handled during the window
+pub fn profile_packet() {
+    if profile_window_open() {
+        PROFILE_PACKETS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Called from the allocator for every successful allocation
+#[inline]
+fn profile_alloc(size: usize) {
+    if !PROFILING.load(Ordering::Relaxed) || !profile_window_open() {
+        return;
+    }
+    let tag = (CURRENT_TAG.load(Ordering::Relaxed) as usize).min(TAGS - 1);
+    PROFILE_BYTES[tag].fetch_add(size as u64, Ordering::Relaxed);
+    PROFILE_HIST[tag][profile_bucket(size)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Allocations made under one subsystem tag during the window
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone, Copy)]
+pub struct TagProfile {
+    pub subsystem: Subsystem,
+    pub allocs: u64,
+    pub bytes: u64,
+    pub histogram: [u64; PROFILE_BUCKETS],
+}
+
+/// Snapshot of the current or last profiling window
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone)]
+pub struct ProfileReport {
+    /// Still collecting
+    pub running: bool,
+    /// Window length so far (or in total, once finished)
+    pub elapsed_us: u64,
+    pub packets: u64,
+    /// Tags that saw at least one allocation
+    pub tags: Vec<TagProfile>,
+}
+
+#[cfg(feature = "shell")]
+impl ProfileReport {
+    pub fn total_allocs(&self) -> u64 {
+        self.tags.iter().map(|t| t.allocs).sum()
+    }
+
+    /// Allocations per packet, in hundredths (None without packets)
+    pub fn allocs_per_packet_x100(&self) -> Option<u64> {
+        (self.packets > 0).then(|| self.total_allocs() * 100 / self.packets)
+    }
+}
+
+#[cfg(feature = "shell")]
+pub fn profile_report() -> ProfileReport {
+    let now = crate::timer::cycles();
+    let running = profile_window_open();
+    let end = if running {
+        now
+    } else {
+        PROFILE_END.load(Ordering::Relaxed).min(now)
+    };
+    let elapsed_us =
+        crate::timer::cycles_to_us(end.saturating_sub(PROFILE_START.load(Ordering::Relaxed)));
+
+    let mut tags = Vec::new();
+    for (i, &subsystem) in Subsystem::ALL.iter().enumerate() {
+        let mut histogram = [0u64; PROFILE_BUCKETS];
+        for (count, bucket) in histogram.iter_mut().zip(&PROFILE_HIST[i]) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        let allocs: u64 = histogram.iter().sum();
+        if allocs > 0 {
+            tags.push(TagProfile {
+                subsystem,
+                allocs,
+                bytes: PROFILE_BYTES[i].load(Ordering::Relaxed),
+                histogram,
+            });
+        }
+    }
+
+    ProfileReport {
+        running,
+        elapsed_us,
+        packets: PROFILE_PACKETS.load(Ordering::Relaxed),
+        tags,
+    }
+}
+
+// ============================================================================
+// Idle Scrubbing
+// ============================================================================
+//
+// A background thread (`alloc.scrub=1`) checks allocator metadata while the
+// network is quiet, a few objects at a time, to catch slow corruption -
+// use-after-free writes, stray pointers - before it crashes something:
+//
+// - free size-class objects are filled with a poison pattern and tagged in
+//   their second word; a later pass reports any whose poison changed
+// - size-class free lists are checked for links outside class pages or
+//   off the object grid, and for lengths that don't match the counts
+// - the page allocator's free lists are validated (`pmm::validate`)
+//
+// talc's own chunk metadata isn't reachable from outside, so blocks larger
+// than the biggest size class aren't covered.
+
+/// Free objects visited per step (the class lock is held with IRQs masked)
+const SCRUB_STEP_OBJECTS: usize = 64;
+
+/// Fill byte for free objects
+pub const SCRUB_POISON: u8 = 0x6b;
+
+/// XORed with the address, stored in the second word of poisoned objects
+const SCRUB_MARKER: usize = 0x5c7b_b1ed_f7ee_0b1e;
+
+/// The network counts as quiet after this long without packets
+const SCRUB_IDLE_US: u64 = 200_000;
+
+/// Pause between steps
+const SCRUB_INTERVAL_US: u64 = 10_000;
+
+static SCRUB_PASSES: AtomicU64 = AtomicU64::new(0);
+static SCRUB_POISONED: AtomicU64 = AtomicU64::new(0);
+static SCRUB_VERIFIED: AtomicU64 = AtomicU64::new(0);
+static SCRUB_CORRUPTIONS: AtomicU64 = AtomicU64::new(0);
+/// Address of the last corrupted object or list entry found
+static SCRUB_LAST_BAD: AtomicUsize = AtomicUsize::new(0);
+
+/// Position in the current pass: class, and free objects of it already seen
+static SCRUB_CURSOR: Spinlock<(usize, usize)> = Spinlock::new((0, 0));
+
+/// Scrubber counters since boot
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone, Copy)]
+pub struct ScrubStats {
+    /// Complete passes over all classes and the page allocator
+    pub passes: u64,
+    /// Free objects filled with poison
+    pub poisoned: u64,
+    /// Poisoned objects found intact on a later pass
+    pub verified: u64,
+    /// Problems found
+    pub corruptions: u64,
+    /// Where the last one was (0 if it wasn't at a single address)
+    pub last_bad_addr: usize,
+}
+
+#[cfg(feature = "shell")]
+pub fn scrub_stats() -> ScrubStats {
+    ScrubStats {
+        passes: SCRUB_PASSES.load(Ordering::Relaxed),
+        poisoned: SCRUB_POISONED.load(Ordering::Relaxed),
+        verified: SCRUB_VERIFIED.load(Ordering::Relaxed),
+        corruptions: SCRUB_CORRUPTIONS.load(Ordering::Relaxed),
+        last_bad_addr: SCRUB_LAST_BAD.load(Ordering::Relaxed),
+    }
+}
+
+fn scrub_corruption(addr: usize, what: &'static str) -> KError {
+    SCRUB_CORRUPTIONS.fetch_add(1, Ordering::Relaxed);
+    SCRUB_LAST_BAD.store(addr, Ordering::Relaxed);
+    KError::with_context(ErrorKind::Corrupted, what)
+}
+
+/// Verify a poisoned free object, or poison it if it isn't yet
+///
+/// Returns false if it was poisoned but the poison has since changed; it
+/// is poisoned afresh either way.
+///
+/// # Safety
+/// `obj` must be a free object of `size` bytes on a size-class free list,
+/// with the class lock held.
+unsafe fn scrub_object(obj: usize, size: usize) -> bool {
+    let word = core::mem::size_of::<usize>();
+    let marker = (obj as *mut usize).wrapping_add(1);
+    let body = (obj + 2 * word) as *mut u8;
+    let len = size - 2 * word;
+    unsafe {
+        let poisoned = *marker == SCRUB_MARKER ^ obj;
+        if poisoned && core::slice::from_raw_parts(body, len).iter().all(|&b| b == SCRUB_POISON) {
+            SCRUB_VERIFIED.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        core::ptr::write_bytes(body, SCRUB_POISON, len);
+        *marker = SCRUB_MARKER ^ obj;
+        SCRUB_POISONED.fetch_add(1, Ordering::Relaxed);
+        !poisoned
+    }
+}
+
+/// Visit up to SCRUB_STEP_OBJECTS free objects of class `idx` after the
+/// first `skip`
+///
+/// Returns where to continue (None once the class is done) and the first
+/// problem found. A damaged list ends the class for this pass.
+fn scrub_class(idx: usize, skip: usize) -> (Option<usize>, Option<KError>) {
+    let classes = CLASSES.lock();
+    let class = &classes[idx];
+    let size = SIZE_CLASSES[idx];
+
+    let mut node = class.free_list;
+    let mut seen = 0;
+    let mut visited = 0;
+    let mut error = None;
+    while node != 0 {
+        if !is_class_page(node) || !node.is_multiple_of(size) {
+            return (None, Some(scrub_corruption(node, "size-class free list link")));
+        }
+        if seen >= class.free {
+            return (None, Some(scrub_corruption(node, "size-class free list length")));
+        }
+        if seen >= skip {
+            // SAFETY: node is a free object of this class (checked above)
+            if !unsafe { scrub_object(node, size) } && error.is_none() {
+                error = Some(scrub_corruption(node, "free object written after free"));
+            }
+            visited += 1;
+            if visited == SCRUB_STEP_OBJECTS {
+                return (Some(seen + 1), error);
+            }
+        }
+        seen += 1;
+        // SAFETY: as above; the first word links to the next free object
+        node = unsafe { *(node as *const usize) };
+    }
+    if seen != class.free && error.is_none() {
+        error = Some(scrub_corruption(class.free_list, "size-class free count"));
+    }
+    (None, error)
+}
+
+/// Do one bounded piece of a scrub pass
+///
+/// Returns true when this step finished a pass. Problems are counted and
+/// returned as `Corrupted`; the next step carries on after them.
+pub fn scrub_step() -> KResult<bool> {
+    with_irqs_disabled(|| {
+        let mut cursor = SCRUB_CURSOR.lock();
+        let (idx, skip) = *cursor;
+
+        if idx < SIZE_CLASSES.len() {
+            let (next, error) = scrub_class(idx, skip);
+            *cursor = match next {
+                Some(skip) => (idx, skip),
+                None => (idx + 1, 0),
+            };
+            return match error {
+                Some(e) => Err(e),
+                None => Ok(false),
+            };
+        }
+
+        *cursor = (0, 0);
+        SCRUB_PASSES.fetch_add(1, Ordering::Relaxed);
+        pmm::validate().map(|_| true).inspect_err(|_| {
+            SCRUB_CORRUPTIONS.fetch_add(1, Ordering::Relaxed);
+            SCRUB_LAST_BAD.store(0, Ordering::Relaxed);
+        })
+    })
+}
+
+/// Start the scrubber thread
+///
+/// It runs in the background class and steps every SCRUB_INTERVAL_US
+/// while no packets have moved for SCRUB_IDLE_US, sleeping otherwise, so
+/// busy periods aren't slowed.
+pub fn start_scrubber() -> KResult<usize> {
+    crate::threading::spawn_fn_background("scrubber", || {
+        let packets = || {
+            let (rx, tx) = crate::network::packet_counts();
+            rx + tx
+        };
+        let mut last_packets = packets();
+        let mut quiet_since = crate::timer::uptime_us();
+        loop {
+            crate::threading::sleep_us(SCRUB_INTERVAL_US);
+
+            let now = crate::timer::uptime_us();
+            let current = packets();
+            if current != last_packets {
+                last_packets = current;
+                quiet_since = now;
+                continue;
+            }
+            if now - quiet_since < SCRUB_IDLE_US {
+                continue;
+            }
+
+            if let Err(e) = scrub_step() {
+                crate::kevent!(
+                    Event::HeapCorruption,
+                    "[ALLOC] Scrubber: {} at {:#x}",
+                    e,
+                    SCRUB_LAST_BAD.load(Ordering::Relaxed)
+                );
+            }
+        }
+    })
+}
+
+/// Record a successful allocation (called with IRQs disabled)
+#[inline]
+fn record_alloc(size: usize) {
+    let used = USED_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_BYTES.fetch_max(used, Ordering::Relaxed);
+    LIVE_ALLOCS.fetch_add(1, Ordering::Relaxed);
+    TOTAL_ALLOCS.fetch_add(1, Ordering::Relaxed);
+    profile_alloc(size);
+}
+
+/// Record a deallocation (called with IRQs disabled)
+#[inline]
+fn record_free(size: usize) {
+    USED_BYTES.fetch_sub(size, Ordering::Relaxed);
+    LIVE_ALLOCS.fetch_sub(1, Ordering::Relaxed);
+}
+
 struct Talck;
 
 unsafe impl core::alloc::GlobalAlloc for Talck {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let callers = if TRACKING.load(Ordering::Relaxed) {
+            capture_callers()
+        } else {
+            [0; TRACK_DEPTH]
+        };
+
         // Always disable IRQs during allocation to prevent context switch deadlock
         with_irqs_disabled(|| unsafe {
-            let result = TALC
-                .lock()
-                .malloc(layout)
-                .map(|ptr| ptr.as_ptr())
-                .unwrap_or(core::ptr::null_mut());
+            let try_malloc = || {
+                // Small requests from size classes; talc if they're out of pages
+                if SIZE_CLASSES_ENABLED.load(Ordering::Relaxed)
+                    && let Some(idx) = class_for(layout)
+                {
+                    let ptr = class_alloc(idx);
+                    if !ptr.is_null() {
+                        return ptr;
+                    }
+                }
+                TALC.lock()
+                    .malloc(layout)
+                    .map(|ptr| ptr.as_ptr())
+                    .unwrap_or(core::ptr::null_mut())
+            };
+
+            let mut result = try_malloc();
+
+            // Give OOM callbacks a chance to release memory, then retry once
+            if result.is_null() && run_oom_callbacks(layout) {
+                result = try_malloc();
+            }
 
             // Log allocation failures - use only static strings to avoid recursion!
             if result.is_null() {
+                OOM_EVENTS.fetch_add(1, Ordering::Relaxed);
                 crate::console::print("[ALLOC FAIL]");
+            } else {
+                record_alloc(layout.size());
+                if TRACKING.load(Ordering::Relaxed) {
+                    TRACKER.lock().insert(result as usize, layout.size(), callers);
+                }
             }
 
             result
@@ -84,10 +1394,21 @@ unsafe impl core::alloc::GlobalAlloc for Talck {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        // Scrub before the block can be handed out again
+        if ZERO_ON_FREE.load(Ordering::Relaxed) {
+            unsafe { crate::secret::wipe_raw(ptr, layout.size()) };
+        }
+
         // Always disable IRQs during deallocation to prevent context switch deadlock
         with_irqs_disabled(|| unsafe {
-            TALC.lock()
-                .free(core::ptr::NonNull::new_unchecked(ptr), layout);
+            match class_for(layout) {
+                Some(idx) if is_class_page(ptr as usize) => class_free(idx, ptr),
+                _ => TALC.lock().free(core::ptr::NonNull::new_unchecked(ptr), layout),
+            }
+            record_free(layout.size());
+            if TRACKING.load(Ordering::Relaxed) {
+                TRACKER.lock().remove(ptr as usize);
+            }
         })
     }
 
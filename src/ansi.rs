@@ -0,0 +1,318 @@
+//! ANSI/VT100 Escape Sequences
+//!
+//! A single state machine for decoding terminal input (arrow keys, function
+//! keys, bracketed paste) and helpers for emitting cursor movement,
+//! colors and screen/line erasure. Anything that talks to an interactive terminal - the SSH
+//! shell, the serial console, full-screen views - should go through this
+//! module instead of matching escape bytes by hand.
+
+use alloc::vec::Vec;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+const ESC: u8 = 0x1B;
+
+/// Maximum numeric parameters tracked in a CSI sequence (extra ones are ignored)
+const MAX_PARAMS: usize = 4;
+
+// ============================================================================
+// Decoded Keys
+// ============================================================================
+
+/// A key decoded from terminal input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// Printable byte (ASCII or part of a UTF-8 sequence)
+    Char(u8),
+    /// Control combination, stored as the lowercase letter (Ctrl-C = Ctrl(b'c'))
+    Ctrl(u8),
+    /// Alt/Meta combination (ESC followed by a byte)
+    Alt(u8),
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    /// Function key F1-F12
+    F(u8),
+    /// Start of bracketed paste (ESC [ 200 ~)
+    PasteStart,
+    /// End of bracketed paste (ESC [ 201 ~)
+    PasteEnd,
+    /// Well-formed escape sequence we don't recognise
+    Unknown,
+}
+
+// ============================================================================
+// Input Parser
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+    Ss3,
+}
+
+/// Incremental escape sequence decoder
+///
+/// Feed it raw bytes one at a time; it returns a `Key` whenever a complete
+/// key has been decoded. Sequences may be split across reads.
+pub struct AnsiParser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    param_count: usize,
+    last_was_cr: bool,
+}
+
+impl AnsiParser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            param_count: 0,
+            last_was_cr: false,
+        }
+    }
+
+    /// Feed one byte, returning a key if one is complete
+    pub fn feed(&mut self, byte: u8) -> Option<Key> {
+        match self.state {
+            State::Ground => self.feed_ground(byte),
+            State::Escape => self.feed_escape(byte),
+            State::Csi => self.feed_csi(byte),
+            State::Ss3 => {
+                self.state = State::Ground;
+                Some(match byte {
+                    b'A' => Key::Up,
+                    b'B' => Key::Down,
+                    b'C' => Key::Right,
+                    b'D' => Key::Left,
+                    b'H' => Key::Home,
+                    b'F' => Key::End,
+                    b'P'..=b'S' => Key::F(byte - b'P' + 1),
+                    _ => Key::Unknown,
+                })
+            }
+        }
+    }
+
+    fn feed_ground(&mut self, byte: u8) -> Option<Key> {
+        // Terminals send CR, LF or CRLF for Enter - report CRLF once
+        let after_cr = self.last_was_cr;
+        self.last_was_cr = byte == b'\r';
+
+        match byte {
+            ESC => {
+                self.state = State::Escape;
+                None
+            }
+            b'\r' => Some(Key::Enter),
+            b'\n' if after_cr => None,
+            b'\n' => Some(Key::Enter),
+            b'\t' => Some(Key::Tab),
+            0x7F | 0x08 => Some(Key::Backspace),
+            0x01..=0x1A => Some(Key::Ctrl(byte - 1 + b'a')),
+            0x00 | 0x1C..=0x1F => Some(Key::Unknown),
+            _ => Some(Key::Char(byte)),
+        }
+    }
+
+    fn feed_escape(&mut self, byte: u8) -> Option<Key> {
+        match byte {
+            b'[' => {
+                self.state = State::Csi;
+                self.params = [0; MAX_PARAMS];
+                self.param_count = 0;
+                None
+            }
+            b'O' => {
+                self.state = State::Ss3;
+                None
+            }
+            ESC => Some(Key::Escape), // Stay in Escape for the second ESC
+            _ => {
+                self.state = State::Ground;
+                Some(Key::Alt(byte))
+            }
+        }
+    }
+
+    fn feed_csi(&mut self, byte: u8) -> Option<Key> {
+        match byte {
+            b'0'..=b'9' => {
+                if self.param_count == 0 {
+                    self.param_count = 1;
+                }
+                let idx = self.param_count - 1;
+                if idx < MAX_PARAMS {
+                    let digit = (byte - b'0') as u16;
+                    self.params[idx] = self.params[idx].saturating_mul(10).saturating_add(digit);
+                }
+                None
+            }
+            b';' => {
+                if self.param_count == 0 {
+                    self.param_count = 1;
+                }
+                self.param_count += 1;
+                None
+            }
+            // Private markers and intermediates ('?', '<', ' ', ...) - keep going
+            0x20..=0x3F => None,
+            // Final byte
+            0x40..=0x7E => {
+                self.state = State::Ground;
+                Some(self.dispatch_csi(byte))
+            }
+            // Anything else aborts the sequence
+            _ => {
+                self.state = State::Ground;
+                Some(Key::Unknown)
+            }
+        }
+    }
+
+    fn dispatch_csi(&self, final_byte: u8) -> Key {
+        match final_byte {
+            b'A' => Key::Up,
+            b'B' => Key::Down,
+            b'C' => Key::Right,
+            b'D' => Key::Left,
+            b'H' => Key::Home,
+            b'F' => Key::End,
+            b'~' => match self.params[0] {
+                1 | 7 => Key::Home,
+                2 => Key::Insert,
+                3 => Key::Delete,
+                4 | 8 => Key::End,
+                5 => Key::PageUp,
+                6 => Key::PageDown,
+                11..=15 => Key::F((self.params[0] - 10) as u8),
+                17..=21 => Key::F((self.params[0] - 11) as u8),
+                23 | 24 => Key::F((self.params[0] - 12) as u8),
+                200 => Key::PasteStart,
+                201 => Key::PasteEnd,
+                _ => Key::Unknown,
+            },
+            _ => Key::Unknown,
+        }
+    }
+}
+
+impl Default for AnsiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ============================================================================
+// Output Helpers
+// ============================================================================
+
+/// Append a decimal number without going through core::fmt
+fn push_decimal(out: &mut Vec<u8>, mut n: u16) {
+    let mut digits = [0u8; 5];
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        len += 1;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    for &d in digits[..len].iter().rev() {
+        out.push(d);
+    }
+}
+
+fn push_csi(out: &mut Vec<u8>, n: u16, final_byte: u8) {
+    out.extend_from_slice(b"\x1b[");
+    push_decimal(out, n);
+    out.push(final_byte);
+}
+
+/// Move the cursor right n columns
+pub fn cursor_forward(out: &mut Vec<u8>, n: u16) {
+    if n > 0 {
+        push_csi(out, n, b'C');
+    }
+}
+
+/// Move the cursor left n columns
+pub fn cursor_back(out: &mut Vec<u8>, n: u16) {
+    if n > 0 {
+        push_csi(out, n, b'D');
+    }
+}
+
+// ============================================================================
+// Screen and Line Erasure
+// ============================================================================
+
+/// Clear the whole screen and home the cursor
+pub const CLEAR_SCREEN: &[u8] = b"\x1b[2J\x1b[H";
+
+/// Erase from the cursor to the end of the line
+pub const ERASE_TO_EOL: &[u8] = b"\x1b[K";
+
+/// Erase the character left of the cursor (echo for Backspace)
+pub const RUBOUT: &[u8] = b"\x08 \x08";
+
+pub fn clear_screen(out: &mut Vec<u8>) {
+    out.extend_from_slice(CLEAR_SCREEN);
+}
+
+// ============================================================================
+// Colors
+// ============================================================================
+
+/// Foreground colors in use (SGR 31, 33, 36)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Red,
+    Yellow,
+    Cyan,
+}
+
+impl Color {
+    /// SGR sequence selecting this foreground color
+    pub const fn sequence(&self) -> &'static [u8] {
+        match self {
+            Color::Red => b"\x1b[31m",
+            Color::Yellow => b"\x1b[33m",
+            Color::Cyan => b"\x1b[36m",
+        }
+    }
+}
+
+/// Reset color and attributes
+pub const RESET_STYLE: &[u8] = b"\x1b[0m";
+
+pub fn set_color(out: &mut Vec<u8>, color: Color) {
+    out.extend_from_slice(color.sequence());
+}
+
+pub fn reset_style(out: &mut Vec<u8>) {
+    out.extend_from_slice(RESET_STYLE);
+}
+
+/// Append `text` in `color`, then reset
+pub fn colored(out: &mut Vec<u8>, color: Color, text: &[u8]) {
+    set_color(out, color);
+    out.extend_from_slice(text);
+    reset_style(out);
+}
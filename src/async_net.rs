@@ -6,16 +6,26 @@
 //! - Async TCP stream for reading/writing
 
 use alloc::boxed::Box;
-use alloc::vec;
+use alloc::sync::Arc;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
 use embassy_net::tcp::TcpSocket;
 use embassy_net::{Config, Ipv4Address, Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4};
+use embassy_net_driver::Driver;
 use embassy_time::Duration;
 use virtio_drivers::device::net::VirtIONetRaw;
 use virtio_drivers::transport::mmio::{MmioTransport, VirtIOHeader};
 
-use crate::console;
-use crate::embassy_virtio_driver::EmbassyVirtioDriver;
-use crate::virtio_hal::VirtioHal;
+use crate::driver;
+use crate::embassy_virtio_driver::{self, EmbassyVirtioDriver};
+use crate::error::{ErrorKind, KError, KResult};
+use crate::handles::{self, Handle, ResourceKind};
+use crate::klog::{self, Level};
+use crate::kobject::{self, KObjType, KObject};
+use crate::lockdep::Spinlock;
+use crate::network::Service;
+use crate::slab::SlabCache;
+use crate::virtio_hal::{self, VIRTIO_MMIO_ADDRS, VirtioHal};
 
 // ============================================================================
 // Constants
@@ -28,10 +38,17 @@ const MAX_SOCKETS: usize = 16;
 const TCP_RX_BUFFER_SIZE: usize = 4096;
 const TCP_TX_BUFFER_SIZE: usize = 4096;
 
-/// QEMU virt machine virtio MMIO addresses
-const VIRTIO_MMIO_ADDRS: [usize; 8] = [
-    0x0a000000, 0x0a000200, 0x0a000400, 0x0a000600, 0x0a000800, 0x0a000a00, 0x0a000c00, 0x0a000e00,
-];
+/// RX and TX buffers share one slab cache, so they must be the same size
+const _: () = assert!(TCP_RX_BUFFER_SIZE == TCP_TX_BUFFER_SIZE);
+
+/// Fixed-size socket buffers come from a slab cache instead of the heap
+static SOCKET_BUFFERS: SlabCache = SlabCache::new("tcp-buffer", TCP_RX_BUFFER_SIZE, 16);
+
+/// Kernel object name of the network device (parent of every socket)
+const NET_DEVICE_NAME: &str = "virtio-net";
+
+/// virtio-mmio slot and address of the device in use, while a stack is up
+static ACTIVE_DEVICE: Spinlock<Option<(usize, usize)>> = Spinlock::new(None);
 
 // ============================================================================
 // Network Stack
@@ -45,21 +62,28 @@ pub struct NetworkInit {
 
 /// Initialize the async network stack
 /// Returns the stack and runner on success
-pub fn init() -> Result<NetworkInit, &'static str> {
+///
+/// Fails with `AlreadyInitialized` while a stack is up; `shutdown` first
+/// to bring up a fresh one.
+pub fn init() -> KResult<NetworkInit> {
+    if is_up() {
+        return Err(KError::with_context(ErrorKind::AlreadyInitialized, "network"));
+    }
+
+    crate::network::apply_config();
+    crate::network::apply_shaper_config(embassy_virtio_driver::IFACE_NAME);
+
     log("[AsyncNet] Initializing async network stack...\n");
 
     // Find virtio-net device
-    let mut found_device: Option<EmbassyVirtioDriver> = None;
+    let mut found_device: Option<(EmbassyVirtioDriver, usize, usize)> = None;
 
     for (i, &addr) in VIRTIO_MMIO_ADDRS.iter().enumerate() {
-        // SAFETY: Reading from MMIO registers at known QEMU virt machine addresses
-        let device_id = unsafe { core::ptr::read_volatile((addr + 0x008) as *const u32) };
-        if device_id != 1 {
+        if virtio_hal::mmio_device_id(addr) != virtio_hal::DEVICE_ID_NET {
             continue;
         }
 
-        log("[AsyncNet] Found virtio-net at slot ");
-        console::print(&alloc::format!("{}\n", i));
+        log(&alloc::format!("[AsyncNet] Found virtio-net at slot {}\n", i));
 
         let header_ptr = match core::ptr::NonNull::new(addr as *mut VirtIOHeader) {
             Some(p) => p,
@@ -70,7 +94,7 @@ pub fn init() -> Result<NetworkInit, &'static str> {
         let transport = match unsafe { MmioTransport::new(header_ptr) } {
             Ok(t) => t,
             Err(_) => {
-                log("[AsyncNet] Failed to create transport\n");
+                warn("[AsyncNet] Failed to create transport\n");
                 continue;
             }
         };
@@ -78,35 +102,37 @@ pub fn init() -> Result<NetworkInit, &'static str> {
         let net = match VirtIONetRaw::<VirtioHal, MmioTransport, 16>::new(transport) {
             Ok(n) => n,
             Err(_) => {
-                log("[AsyncNet] Failed to init virtio device\n");
+                warn("[AsyncNet] Failed to init virtio device\n");
                 continue;
             }
         };
 
-        found_device = Some(EmbassyVirtioDriver::new(net));
+        found_device = Some((EmbassyVirtioDriver::new(net)?, i, addr));
+        embassy_virtio_driver::enable_rx_interrupt(i, addr);
         break;
     }
 
-    let device = found_device.ok_or("No virtio-net device found")?;
+    let (device, slot, addr) =
+        found_device.ok_or(KError::with_context(ErrorKind::NoDevice, "virtio-net"))?;
+    // The device object outlives restarts of the stack
+    if kobject::find(KObjType::Device, NET_DEVICE_NAME).is_none()
+        && let Err(e) = kobject::create_pinned(KObjType::Device, NET_DEVICE_NAME, None)
+    {
+        warn(&alloc::format!("[AsyncNet] No kernel object for the device: {}\n", e));
+    }
+    // So are its power hooks
+    if let Err(e) = driver::register(Arc::new(VirtioNetDriver))
+        && e.kind() != ErrorKind::AlreadyExists
+    {
+        warn(&alloc::format!("[AsyncNet] Driver hooks not registered: {}\n", e));
+    }
 
     // Log MAC address
     let mac = device.mac_address();
-    log("[AsyncNet] MAC: ");
-    for (i, b) in mac.iter().enumerate() {
-        if i > 0 {
-            console::print(":");
-        }
-        console::print(&alloc::format!("{:02x}", b));
-    }
-    log("\n");
-
-    // Create static storage for the network resources
-    // These are leaked to get 'static lifetimes
-    let resources_box = Box::new(StackResources::<MAX_SOCKETS>::new());
-    let resources_ref: &'static mut StackResources<MAX_SOCKETS> = Box::leak(resources_box);
-
-    // Random seed from timer
-    let seed = crate::timer::uptime_us();
+    log(&alloc::format!(
+        "[AsyncNet] MAC: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\n",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    ));
 
     // Static IP configuration for QEMU user-mode networking
     let config = Config::ipv4_static(StaticConfigV4 {
@@ -115,15 +141,73 @@ pub fn init() -> Result<NetworkInit, &'static str> {
         dns_servers: Default::default(),
     });
 
-    // Create the stack - returns (Stack, Runner)
-    let (stack, runner) = embassy_net::new(device, config, resources_ref, seed);
+    let (stack, runner) = build_stack(device, config);
 
     log("[AsyncNet] IP: 10.0.2.15/24, Gateway: 10.0.2.2\n");
     log("[AsyncNet] Async network stack ready\n");
 
+    *ACTIVE_DEVICE.lock() = Some((slot, addr));
     Ok(NetworkInit { stack, runner })
 }
 
+/// Whether a stack is up (`init` succeeded and no `shutdown` since)
+pub fn is_up() -> bool {
+    ACTIVE_DEVICE.lock().is_some()
+}
+
+/// Stop the device under the current stack so it can be dropped
+///
+/// Resets the NIC and releases its interrupt; the caller then drops the
+/// stack's runner and every future using the stack, and may `init` again.
+/// Sockets still open on the old stack see their connections vanish.
+pub fn shutdown() -> KResult<()> {
+    let (slot, addr) = ACTIVE_DEVICE
+        .lock()
+        .take()
+        .ok_or(KError::with_context(ErrorKind::NotInitialized, "network"))?;
+    embassy_virtio_driver::reset_device(slot, addr);
+    log("[AsyncNet] Network stack shut down\n");
+    Ok(())
+}
+
+/// Power hooks of the virtio NIC
+struct VirtioNetDriver;
+
+impl driver::Driver for VirtioNetDriver {
+    fn name(&self) -> &'static str {
+        NET_DEVICE_NAME
+    }
+
+    /// Bringing the device back needs a new stack, which `net restart`
+    /// does; a suspend that keeps the stack isn't supported yet
+    fn suspend(&self) -> KResult<()> {
+        if is_up() {
+            Err(KError::with_context(ErrorKind::Unsupported, "virtio-net suspend"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn shutdown(&self) -> KResult<()> {
+        if is_up() { shutdown() } else { Ok(()) }
+    }
+}
+
+/// Create a stack on any device (the virtio NIC, or a simulated link in tests)
+///
+/// The socket storage is leaked to get the `'static` lifetime sockets need,
+/// so only call this for stacks that live until shutdown or test stacks
+/// (each `net restart` leaks one socket table).
+pub fn build_stack<D: Driver>(device: D, config: Config) -> (Stack<'static>, Runner<'static, D>) {
+    let resources: &'static mut StackResources<MAX_SOCKETS> =
+        Box::leak(Box::new(StackResources::<MAX_SOCKETS>::new()));
+
+    // Random seed from timer
+    let seed = crate::timer::uptime_us();
+
+    embassy_net::new(device, config, resources, seed)
+}
+
 // ============================================================================
 // Async TCP Listener
 // ============================================================================
@@ -143,16 +227,8 @@ impl TcpListener {
     /// Accept a new connection
     /// Returns a TcpStream for the accepted connection
     pub async fn accept(&self) -> Result<TcpStream, TcpError> {
-        // Allocate buffers for this connection
-        let rx_buffer = vec![0u8; TCP_RX_BUFFER_SIZE].into_boxed_slice();
-        let tx_buffer = vec![0u8; TCP_TX_BUFFER_SIZE].into_boxed_slice();
-
-        // Leak the buffers for 'static lifetime
-        let rx_ref: &'static mut [u8] = Box::leak(rx_buffer);
-        let tx_ref: &'static mut [u8] = Box::leak(tx_buffer);
-
         // Create socket - Stack is Copy so we can clone it
-        let mut socket = TcpSocket::new(self.stack, rx_ref, tx_ref);
+        let mut socket = PooledSocket::new(self.stack).ok_or(TcpError::AcceptFailed)?;
         socket.set_timeout(Some(Duration::from_secs(60)));
 
         // Accept connection
@@ -161,7 +237,155 @@ impl TcpListener {
             .await
             .map_err(|_| TcpError::AcceptFailed)?;
 
-        Ok(TcpStream { socket })
+        Ok(TcpStream::from_socket(socket))
+    }
+}
+
+// ============================================================================
+// Pooled Socket
+// ============================================================================
+
+/// RX/TX buffers borrowed from SOCKET_BUFFERS
+struct SocketBuffers {
+    rx: NonNull<u8>,
+    tx: NonNull<u8>,
+}
+
+impl Drop for SocketBuffers {
+    fn drop(&mut self) {
+        // SAFETY: both came from SOCKET_BUFFERS and the socket using them
+        // has already been dropped (see PooledSocket field order)
+        unsafe {
+            SOCKET_BUFFERS.free(self.rx);
+            SOCKET_BUFFERS.free(self.tx);
+        }
+    }
+}
+
+/// The socket and the buffers it borrows
+struct SocketInner {
+    // Field order matters: the socket must drop before its buffers
+    socket: TcpSocket<'static>,
+    _buffers: SocketBuffers,
+    kobj: Option<Arc<KObject>>,
+}
+
+/// Socket address the reclaim action frees through
+struct SocketRef(NonNull<SocketInner>);
+
+// SAFETY: sockets only live on the thread polling the network. Their owner
+// is that thread, which never exits, or a task it polls, which it releases
+// itself - so the reclaim action also runs there.
+unsafe impl Send for SocketRef {}
+
+impl SocketRef {
+    fn get(&self) -> *mut SocketInner {
+        self.0.as_ptr()
+    }
+}
+
+/// A TCP socket whose buffers are returned to the slab cache on drop
+///
+/// embassy-net wants `'static` buffers; we hand it slab objects and only
+/// free them after the socket (and its entry in the stack) is gone. The
+/// socket is tracked in `handles` under the thread or task that created it,
+/// so one leaked by a finished task is still closed and freed.
+pub struct PooledSocket {
+    inner: NonNull<SocketInner>,
+    handle: Handle,
+}
+
+impl PooledSocket {
+    /// Create a socket on `stack`, or None if buffers can't be allocated
+    pub fn new(stack: Stack<'static>) -> Option<Self> {
+        let rx = SOCKET_BUFFERS.alloc()?;
+        let tx = match SOCKET_BUFFERS.alloc() {
+            Some(tx) => tx,
+            None => {
+                // SAFETY: rx was just allocated and never handed out
+                unsafe { SOCKET_BUFFERS.free(rx) };
+                return None;
+            }
+        };
+
+        // SAFETY: each object is TCP_*_BUFFER_SIZE bytes, exclusively ours,
+        // and outlives the socket because SocketBuffers drops after it
+        let (rx_ref, tx_ref): (&'static mut [u8], &'static mut [u8]) = unsafe {
+            (
+                core::slice::from_raw_parts_mut(rx.as_ptr(), TCP_RX_BUFFER_SIZE),
+                core::slice::from_raw_parts_mut(tx.as_ptr(), TCP_TX_BUFFER_SIZE),
+            )
+        };
+
+        // Listed under the network device; a missing object only affects `kobj tree`
+        let device = kobject::find(KObjType::Device, NET_DEVICE_NAME);
+        let kobj = kobject::create(KObjType::Socket, "tcp", device.as_ref()).ok();
+
+        let inner = crate::allocator::try_box(SocketInner {
+            socket: TcpSocket::new(stack, rx_ref, tx_ref),
+            _buffers: SocketBuffers { rx, tx },
+            kobj,
+        })
+        .ok()?;
+        let inner = NonNull::from(Box::leak(inner));
+
+        let socket_ref = SocketRef(inner);
+        let handle = handles::track(ResourceKind::Socket, move || {
+            // SAFETY: the holder outlived its owner and never touches the
+            // socket again
+            drop(unsafe { Box::from_raw(socket_ref.get()) });
+        });
+        match handle {
+            Ok(handle) => Some(Self { inner, handle }),
+            Err(_) => {
+                // SAFETY: never handed out
+                drop(unsafe { Box::from_raw(inner.as_ptr()) });
+                None
+            }
+        }
+    }
+
+    /// Kernel object describing this socket
+    #[cfg(feature = "ssh")]
+    pub fn kobject(&self) -> Option<&Arc<KObject>> {
+        self.inner().kobj.as_ref()
+    }
+
+    /// Handle tracking this socket
+    #[cfg(feature = "ssh")]
+    pub fn handle(&self) -> Handle {
+        self.handle
+    }
+
+    fn inner(&self) -> &SocketInner {
+        // SAFETY: freed only by our Drop, or by the reclaim action once we
+        // can no longer be used
+        unsafe { self.inner.as_ref() }
+    }
+}
+
+impl Drop for PooledSocket {
+    fn drop(&mut self) {
+        // If the handle is gone the table already freed the socket
+        if handles::untrack(self.handle) {
+            // SAFETY: allocated in new() and not used again
+            drop(unsafe { Box::from_raw(self.inner.as_ptr()) });
+        }
+    }
+}
+
+impl Deref for PooledSocket {
+    type Target = TcpSocket<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner().socket
+    }
+}
+
+impl DerefMut for PooledSocket {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: as in inner(); &mut self makes the access exclusive
+        unsafe { &mut self.inner.as_mut().socket }
     }
 }
 
@@ -170,20 +394,68 @@ impl TcpListener {
 // ============================================================================
 
 /// Async TCP stream for reading and writing
-/// Uses 'static lifetime since we leak socket buffers
+///
+/// Traffic is accounted to the stream's service, and reads and writes wait
+/// while that service is over its bandwidth cap (see `network`).
 pub struct TcpStream {
-    socket: TcpSocket<'static>,
+    socket: PooledSocket,
+    service: Service,
 }
 
 impl TcpStream {
     /// Create a TcpStream from an already-connected socket
-    pub fn from_socket(socket: TcpSocket<'static>) -> Self {
-        Self { socket }
+    pub fn from_socket(socket: PooledSocket) -> Self {
+        Self {
+            socket,
+            service: Service::Other,
+        }
+    }
+
+    /// Open an outbound connection to `remote`
+    /// Gives up after `timeout` if the handshake doesn't complete.
+    pub async fn connect(
+        stack: Stack<'static>,
+        remote: embassy_net::IpEndpoint,
+        timeout: Duration,
+    ) -> Result<Self, TcpError> {
+        let mut socket = PooledSocket::new(stack).ok_or(TcpError::ConnectFailed)?;
+        socket.set_timeout(Some(Duration::from_secs(60)));
+
+        match embassy_time::with_timeout(timeout, socket.connect(remote)).await {
+            Ok(Ok(())) => Ok(Self::from_socket(socket)),
+            _ => {
+                socket.abort();
+                Err(TcpError::ConnectFailed)
+            }
+        }
+    }
+
+    /// Kernel object describing the underlying socket
+    #[cfg(feature = "ssh")]
+    pub fn kobject(&self) -> Option<&Arc<KObject>> {
+        self.socket.kobject()
+    }
+
+    /// Handle tracking the underlying socket
+    #[cfg(feature = "ssh")]
+    pub fn handle(&self) -> Handle {
+        self.socket.handle()
+    }
+
+    /// Account this stream's traffic to `service`
+    pub fn set_service(&mut self, service: Service) {
+        self.service = service;
     }
 
     /// Read data from the stream
     /// Returns the number of bytes read, or 0 if connection closed
     pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TcpError> {
+        // Over the cap: let the bucket refill before taking more
+        let wait_us = crate::network::rx_wait_us(self.service);
+        if wait_us > 0 {
+            embassy_time::Timer::after(Duration::from_micros(wait_us)).await;
+        }
+
         let result = self
             .socket
             .read(buf)
@@ -192,25 +464,35 @@ impl TcpStream {
 
         // Track bytes received
         if let Ok(n) = &result {
-            crate::network::add_bytes_rx(*n as u64);
+            crate::network::add_bytes_rx(self.service, *n as u64);
         }
 
         result
     }
 
     /// Write data to the stream
-    /// Returns the number of bytes written
+    /// Returns the number of bytes written (may be short under a cap)
     pub async fn write(&mut self, data: &[u8]) -> Result<usize, TcpError> {
+        let allowed = loop {
+            match crate::network::reserve_tx(self.service, data.len()) {
+                Ok(n) => break n,
+                Err(wait_us) => {
+                    embassy_time::Timer::after(Duration::from_micros(wait_us)).await;
+                }
+            }
+        };
+
         let result = self
             .socket
-            .write(data)
+            .write(&data[..allowed])
             .await
             .map_err(|_| TcpError::WriteFailed);
 
-        // Track bytes transmitted
-        if let Ok(n) = &result {
-            crate::network::add_bytes_tx(*n as u64);
-        }
+        // Track bytes transmitted; tokens for bytes the socket didn't take
+        // go back to the cap
+        let written = *result.as_ref().unwrap_or(&0);
+        crate::network::add_bytes_tx(self.service, written as u64);
+        crate::network::refund_tx(self.service, allowed - written);
 
         result
     }
@@ -228,7 +510,94 @@ impl TcpStream {
         Ok(())
     }
 
+    /// Read into several buffers in order, filling each before the next
+    ///
+    /// Scatters out of the socket's receive buffer; like `write_vectored`,
+    /// the device side is still one descriptor per frame.
+    /// Returns the number of bytes read, or 0 if connection closed
+    pub async fn read_vectored(&mut self, bufs: &mut [&mut [u8]]) -> Result<usize, TcpError> {
+        let wait_us = crate::network::rx_wait_us(self.service);
+        if wait_us > 0 {
+            embassy_time::Timer::after(Duration::from_micros(wait_us)).await;
+        }
+
+        let result = self
+            .socket
+            .read_with(|rx| {
+                let n = scatter(rx, bufs);
+                (n, n)
+            })
+            .await
+            .map_err(|_| TcpError::ReadFailed);
+
+        if let Ok(n) = &result {
+            crate::network::add_bytes_rx(self.service, *n as u64);
+        }
+
+        result
+    }
+
+    /// Write several buffers in order, as if they were one
+    ///
+    /// Each slice is copied straight into the socket's transmit buffer, so a
+    /// header and payload don't need joining into one allocation first.
+    /// That copy is the only gather: the stack still builds each frame in
+    /// one TX buffer and the virtio-net driver sends it as a single
+    /// descriptor, not a descriptor chain.
+    /// Returns the number of bytes written (may be short under a cap).
+    pub async fn write_vectored(&mut self, bufs: &[&[u8]]) -> Result<usize, TcpError> {
+        self.write_gather(bufs, 0).await
+    }
+
+    /// Write every byte of several buffers, in order
+    #[cfg(feature = "http")]
+    pub async fn write_all_vectored(&mut self, bufs: &[&[u8]]) -> Result<(), TcpError> {
+        let total: usize = bufs.iter().map(|b| b.len()).sum();
+        let mut written = 0;
+        while written < total {
+            let n = self.write_gather(bufs, written).await?;
+            if n == 0 {
+                return Err(TcpError::WriteFailed);
+            }
+            written += n;
+        }
+        Ok(())
+    }
+
+    /// `write_vectored`, skipping the first `skip` bytes of `bufs`
+    async fn write_gather(&mut self, bufs: &[&[u8]], skip: usize) -> Result<usize, TcpError> {
+        let remaining = bufs.iter().map(|b| b.len()).sum::<usize>().saturating_sub(skip);
+        if remaining == 0 {
+            return Ok(0);
+        }
+        let allowed = loop {
+            match crate::network::reserve_tx(self.service, remaining) {
+                Ok(n) => break n,
+                Err(wait_us) => {
+                    embassy_time::Timer::after(Duration::from_micros(wait_us)).await;
+                }
+            }
+        };
+
+        let result = self
+            .socket
+            .write_with(|tx| {
+                let limit = tx.len().min(allowed);
+                let n = gather(bufs, skip, &mut tx[..limit]);
+                (n, n)
+            })
+            .await
+            .map_err(|_| TcpError::WriteFailed);
+
+        let written = *result.as_ref().unwrap_or(&0);
+        crate::network::add_bytes_tx(self.service, written as u64);
+        crate::network::refund_tx(self.service, allowed - written);
+
+        result
+    }
+
     /// Flush the stream
+    #[cfg(any(feature = "shell", feature = "http"))]
     pub async fn flush(&mut self) -> Result<(), TcpError> {
         self.socket.flush().await.map_err(|_| TcpError::FlushFailed)
     }
@@ -254,11 +623,51 @@ impl TcpStream {
     }
 
     /// Get the remote endpoint
+    #[cfg(feature = "shell")]
     pub fn remote_endpoint(&self) -> Option<embassy_net::IpEndpoint> {
         self.socket.remote_endpoint()
     }
 }
 
+// ============================================================================
+// Scatter-Gather Helpers
+// ============================================================================
+
+/// Copy `bufs`, minus their first `skip` bytes, into `out`
+/// Returns the number of bytes copied.
+pub fn gather(bufs: &[&[u8]], mut skip: usize, out: &mut [u8]) -> usize {
+    let mut copied = 0;
+    for buf in bufs {
+        if skip >= buf.len() {
+            skip -= buf.len();
+            continue;
+        }
+        let src = &buf[skip..];
+        skip = 0;
+        let n = src.len().min(out.len() - copied);
+        out[copied..copied + n].copy_from_slice(&src[..n]);
+        copied += n;
+        if copied == out.len() {
+            break;
+        }
+    }
+    copied
+}
+
+/// Copy `src` across `bufs` in order; returns the number of bytes copied
+pub fn scatter(src: &[u8], bufs: &mut [&mut [u8]]) -> usize {
+    let mut copied = 0;
+    for buf in bufs.iter_mut() {
+        let n = buf.len().min(src.len() - copied);
+        buf[..n].copy_from_slice(&src[copied..copied + n]);
+        copied += n;
+        if copied == src.len() {
+            break;
+        }
+    }
+    copied
+}
+
 // ============================================================================
 // Error Types
 // ============================================================================
@@ -267,16 +676,35 @@ impl TcpStream {
 #[derive(Debug, Clone, Copy)]
 pub enum TcpError {
     AcceptFailed,
+    ConnectFailed,
     ReadFailed,
     WriteFailed,
     FlushFailed,
     ConnectionClosed,
 }
 
+impl From<TcpError> for KError {
+    fn from(e: TcpError) -> Self {
+        let kind = match e {
+            TcpError::AcceptFailed => ErrorKind::AcceptFailed,
+            TcpError::ConnectFailed => ErrorKind::ConnectFailed,
+            TcpError::ReadFailed => ErrorKind::ReadFailed,
+            TcpError::WriteFailed => ErrorKind::WriteFailed,
+            TcpError::FlushFailed => ErrorKind::FlushFailed,
+            TcpError::ConnectionClosed => ErrorKind::ConnectionClosed,
+        };
+        KError::new(kind)
+    }
+}
+
 // ============================================================================
 // Logging
 // ============================================================================
 
 fn log(msg: &str) {
-    console::print(msg);
+    klog::log(Level::Info, "async_net", msg);
+}
+
+fn warn(msg: &str) {
+    klog::log(Level::Warn, "async_net", msg);
 }
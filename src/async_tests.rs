@@ -2,18 +2,28 @@
 //!
 //! These tests verify:
 //! - Embassy timer functionality
+//! - Executor record/replay on virtual time
 //! - Loopback network interface
 //! - Async TCP client-server communication
+//! - TCP over a simulated lossy link
+//! - Async VFS file I/O
 //!
 //! Run these tests after network initialization via `run_all()`.
 
 use alloc::format;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::pin;
+use core::task::Poll;
 
+use embassy_net::{Config, IpAddress, IpEndpoint, Ipv4Address, Ipv4Cidr, Runner, Stack, StaticConfigV4};
 use embassy_time::{Duration, Instant, Timer};
 
+use crate::async_net::{self, TcpListener, TcpStream};
 use crate::console;
-use crate::embassy_net_driver::LoopbackDevice;
+use crate::embassy_net_driver::{LoopbackDevice, SimConfig, SimDevice, SimLink};
 use crate::executor;
+use crate::replay;
 
 // ============================================================================
 // Test Runner
@@ -34,11 +44,19 @@ pub fn run_all() -> bool {
     all_pass &= test_embassy_timer();
     all_pass &= test_timer_multiple();
     all_pass &= test_timer_accuracy();
+    all_pass &= test_executor_replay();
 
     // Loopback network tests
     all_pass &= test_loopback_device_creation();
     all_pass &= test_loopback_stack_init();
 
+    // Simulated link tests
+    all_pass &= test_sim_link_tcp();
+    all_pass &= test_sim_link_ssh_banner();
+
+    // Filesystem tests
+    all_pass &= test_vfs_async_io();
+
     console::print("\n==================================\n");
     console::print(&format!(
         "Async Tests: {}\n",
@@ -179,6 +197,337 @@ fn test_loopback_stack_init() -> bool {
     success
 }
 
+// ============================================================================
+// Simulated Link Tests
+// ============================================================================
+
+/// Bytes sent across the simulated link (several windows' worth)
+const SIM_PAYLOAD: usize = 16 * 1024;
+
+const SIM_PORT: u16 = 7070;
+
+/// A link that delays, drops and reorders frames; the fixed seed makes the
+/// same frames go missing on every run
+const LOSSY_LINK: SimConfig = SimConfig {
+    latency_us: 500,
+    loss_percent: 5,
+    reorder_percent: 10,
+    seed: 0x5eed,
+};
+
+/// Stacks at 192.168.77.1 and .2 on the two ends of `link`
+fn sim_stacks(
+    link: &SimLink,
+) -> (
+    (Stack<'static>, Runner<'static, SimDevice>),
+    (Stack<'static>, Runner<'static, SimDevice>),
+) {
+    let config = |host: u8| {
+        Config::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 77, host), 24),
+            gateway: None,
+            dns_servers: Default::default(),
+        })
+    };
+    let (a, b) = link.ends();
+    (
+        async_net::build_stack(a, config(1)),
+        async_net::build_stack(b, config(2)),
+    )
+}
+
+/// Run `future` while driving both stacks of a simulated link
+fn run_on_link<T>(
+    runner_a: &mut Runner<'static, SimDevice>,
+    runner_b: &mut Runner<'static, SimDevice>,
+    future: impl Future<Output = T>,
+    timeout_us: u64,
+) -> T {
+    let mut run_a = pin!(runner_a.run());
+    let mut run_b = pin!(runner_b.run());
+    let mut future = pin!(future);
+    run_async_test_for(
+        core::future::poll_fn(|cx| {
+            let _ = run_a.as_mut().poll(cx);
+            let _ = run_b.as_mut().poll(cx);
+            future.as_mut().poll(cx)
+        }),
+        timeout_us,
+    )
+}
+
+/// Run two futures concurrently until both finish
+async fn join<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    let mut done_a = None;
+    let mut done_b = None;
+    core::future::poll_fn(|cx| {
+        if done_a.is_none()
+            && let Poll::Ready(v) = a.as_mut().poll(cx)
+        {
+            done_a = Some(v);
+        }
+        if done_b.is_none()
+            && let Poll::Ready(v) = b.as_mut().poll(cx)
+        {
+            done_b = Some(v);
+        }
+        match (done_a.take(), done_b.take()) {
+            (Some(a), Some(b)) => Poll::Ready((a, b)),
+            (a, b) => {
+                done_a = a;
+                done_b = b;
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+fn sim_server_endpoint() -> IpEndpoint {
+    IpEndpoint::new(IpAddress::v4(192, 168, 77, 2), SIM_PORT)
+}
+
+/// Test: TCP delivers every byte in order over a lossy, reordering link,
+/// written and read through the vectored calls
+fn test_sim_link_tcp() -> bool {
+    console::print("\n[ASYNC TEST] TCP over simulated lossy link\n");
+
+    let link = SimLink::new(LOSSY_LINK);
+    let ((stack_a, mut runner_a), (stack_b, mut runner_b)) = sim_stacks(&link);
+    let payload: Vec<u8> = (0..SIM_PAYLOAD).map(|i| (i * 7) as u8).collect();
+
+    let server = async {
+        let mut stream = TcpListener::new(stack_b, SIM_PORT).accept().await.ok()?;
+        let mut received = Vec::new();
+        let mut head = [0u8; 100];
+        let mut body = [0u8; 412];
+        while received.len() < SIM_PAYLOAD {
+            match stream.read_vectored(&mut [&mut head, &mut body]).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    received.extend_from_slice(&head[..n.min(head.len())]);
+                    received.extend_from_slice(&body[..n.saturating_sub(head.len())]);
+                }
+            }
+        }
+        Some(received)
+    };
+    let client = async {
+        let mut stream = TcpStream::connect(stack_a, sim_server_endpoint(), Duration::from_secs(10))
+            .await
+            .ok()?;
+        // Whatever the first gathered write leaves is sent by write_all
+        let (head, tail) = payload.split_at(64);
+        let n = stream.write_vectored(&[head, tail]).await.ok()?;
+        stream.write_all(&payload[n..]).await.ok()?;
+        // Waits for the peer to ACK everything, retransmissions included
+        stream.flush().await.ok()?;
+        stream.close();
+        // Handed back so the socket isn't torn down under the reader
+        Some(stream)
+    };
+
+    let (received, sent) = run_on_link(&mut runner_a, &mut runner_b, join(server, client), 30_000_000);
+
+    let stats = link.stats();
+    let sent = sent.is_some();
+    let intact = received.is_some_and(|r| r == payload);
+    console::print(&format!(
+        "  frames: {} sent, {} dropped, {} reordered\n",
+        stats.sent, stats.dropped, stats.reordered
+    ));
+    console::print(&format!("  sent: {}, received intact: {}\n", sent, intact));
+
+    let success = sent && intact;
+    console::print(&format!(
+        "  Result: {}\n",
+        if success { "PASS" } else { "FAIL" }
+    ));
+    success
+}
+
+/// Test: The SSH server sends its version banner over a lossy link and
+/// lets go of the connection when the client hangs up
+fn test_sim_link_ssh_banner() -> bool {
+    console::print("\n[ASYNC TEST] SSH banner over simulated lossy link\n");
+
+    let link = SimLink::new(LOSSY_LINK);
+    let ((stack_a, mut runner_a), (stack_b, mut runner_b)) = sim_stacks(&link);
+
+    let server = async {
+        if let Ok(stream) = TcpListener::new(stack_b, SIM_PORT).accept().await {
+            crate::ssh::handle_connection(stream).await;
+        }
+    };
+    let client = async {
+        let mut stream = TcpStream::connect(stack_a, sim_server_endpoint(), Duration::from_secs(10))
+            .await
+            .ok()?;
+        let mut banner = Vec::new();
+        let mut buf = [0u8; 64];
+        while !banner.ends_with(b"\r\n") && banner.len() < 255 {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => banner.extend_from_slice(&buf[..n]),
+            }
+        }
+        stream.close();
+        stream.flush().await.ok()?;
+        Some(banner)
+    };
+
+    let ((), banner) = run_on_link(&mut runner_a, &mut runner_b, join(server, client), 30_000_000);
+
+    let ok = banner.is_some_and(|b| b.starts_with(b"SSH-2.0-") && b.ends_with(b"\r\n"));
+    console::print(&format!("  banner received, server finished: {}\n", ok));
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+// ============================================================================
+// VFS Tests
+// ============================================================================
+
+/// Test: Async and blocking file I/O see the same data
+fn test_vfs_async_io() -> bool {
+    use crate::vfs;
+    use alloc::sync::Arc;
+
+    console::print("\n[ASYNC TEST] VFS async file I/O\n");
+
+    if let Err(e) = vfs::mount("/vfstest", Arc::new(vfs::RamFs::new())) {
+        console::print(&format!("  Mount failed: {}\n", e));
+        console::print("  Result: FAIL\n");
+        return false;
+    }
+
+    let (written, read_back, tail) = run_async_test(async {
+        let mut file = vfs::create("/vfstest/data.bin").ok()?;
+        let data: alloc::vec::Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let written = file.write_at_async(0, &data).await.ok()?;
+        let read_back = vfs::read_file("/vfstest/data.bin").ok()? == data;
+        let mut buf = [0u8; 16];
+        let n = file.read_at_async(4992, &mut buf).await.ok()?;
+        Some((written, read_back, n == 8 && buf[..8] == data[4992..]))
+    })
+    .unwrap_or((0, false, false));
+
+    let listed = vfs::list("/vfstest")
+        .is_ok_and(|entries| entries.iter().any(|e| e.name == "data.bin" && e.size == 5000));
+    let mounted = vfs::list("/").is_ok_and(|entries| entries.iter().any(|e| e.name == "vfstest" && e.is_dir));
+    let _ = vfs::unmount("/vfstest");
+    let unmounted = vfs::open("/vfstest/data.bin").is_err();
+
+    console::print(&format!(
+        "  written: {}, read back: {}, tail: {}, listed: {}, mount listed: {}, unmounted: {}\n",
+        written, read_back, tail, listed, mounted, unmounted
+    ));
+
+    let success = written == 5000 && read_back && tail && listed && mounted && unmounted;
+    console::print(&format!(
+        "  Result: {}\n",
+        if success { "PASS" } else { "FAIL" }
+    ));
+    success
+}
+
+// ============================================================================
+// Replay Tests
+// ============================================================================
+
+/// Pending once (waking itself), then ready
+async fn yield_once() {
+    let mut yielded = false;
+    core::future::poll_fn(|cx| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Three tasks interleaved by timers and self-wakes, appending to `out`
+fn replay_scenario(out: &core::cell::RefCell<Vec<u8>>) -> replay::Executor<'_> {
+    let mut executor = replay::Executor::new(0);
+    executor.spawn(async move {
+        for _ in 0..3 {
+            Timer::after(Duration::from_millis(3)).await;
+            out.borrow_mut().push(b'a');
+        }
+    });
+    executor.spawn(async move {
+        for _ in 0..4 {
+            Timer::after(Duration::from_millis(2)).await;
+            out.borrow_mut().push(b'b');
+        }
+    });
+    executor.spawn(async move {
+        for _ in 0..2 {
+            yield_once().await;
+            out.borrow_mut().push(b'c');
+        }
+    });
+    executor
+}
+
+/// Test: A recorded run replays to the same result, on virtual time
+fn test_executor_replay() -> bool {
+    console::print("\n[ASYNC TEST] Executor record and replay\n");
+
+    let recorded_out = core::cell::RefCell::new(Vec::new());
+    let trace = match replay_scenario(&recorded_out).record() {
+        Ok(trace) => trace,
+        Err(e) => {
+            console::print(&format!("  record failed: {}\n  Result: FAIL\n", e));
+            return false;
+        }
+    };
+    // The clock only moves to alarm times, starting from 0
+    let last_advance = trace.events.iter().rev().find_map(|event| match event {
+        replay::TraceEvent::Advance(us) => Some(*us),
+        _ => None,
+    });
+    let virtual_time = last_advance == Some(9_000);
+
+    let text = trace.to_text();
+    let parsed = replay::Trace::parse(&text).ok();
+    let round_trip = parsed.as_ref() == Some(&trace);
+
+    let replayed_out = core::cell::RefCell::new(Vec::new());
+    let replayed = replay_scenario(&replayed_out).replay(&trace);
+    let same = replayed.is_ok() && *replayed_out.borrow() == *recorded_out.borrow();
+
+    // Cut short, the replay ends with tasks unfinished
+    let mut short = trace.clone();
+    short.events.truncate(trace.events.len() / 2);
+    let short_out = core::cell::RefCell::new(Vec::new());
+    let diverged = replay_scenario(&short_out).replay(&short);
+    let caught = matches!(diverged, Err(d) if d.index == short.events.len() && d.expected.is_none());
+
+    console::print(&format!(
+        "  output: {}, events: {}, virtual time: {}, text round trip: {}, replayed same: {}, truncated: {}\n",
+        core::str::from_utf8(&recorded_out.borrow()).unwrap_or("?"),
+        trace.events.len(),
+        virtual_time,
+        round_trip,
+        same,
+        diverged.err().map(|d| format!("{}", d)).unwrap_or_default()
+    ));
+
+    let success = virtual_time && round_trip && same && caught && recorded_out.borrow().len() == 9;
+    console::print(&format!(
+        "  Result: {}\n",
+        if success { "PASS" } else { "FAIL" }
+    ));
+    success
+}
+
 // ============================================================================
 // Test Infrastructure
 // ============================================================================
@@ -189,8 +538,16 @@ fn run_async_test<F, T>(future: F) -> T
 where
     F: core::future::Future<Output = T>,
 {
-    use core::pin::pin;
-    use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    run_async_test_for(future, 5_000_000)
+}
+
+/// `run_async_test` with a custom timeout, for tests that wait on
+/// retransmissions
+fn run_async_test_for<F, T>(future: F, timeout_us: u64) -> T
+where
+    F: core::future::Future<Output = T>,
+{
+    use core::task::{Context, RawWaker, RawWakerVTable, Waker};
 
     let mut future = pin!(future);
 
@@ -207,9 +564,7 @@ where
     let waker = unsafe { Waker::from_raw(dummy_raw_waker()) };
     let mut cx = Context::from_waker(&waker);
 
-    // Poll with timeout (max 5 seconds)
-    let start = crate::timer::uptime_us();
-    let timeout_us = 5_000_000; // 5 seconds
+    let stopwatch = crate::timer::Stopwatch::start();
 
     loop {
         // Check embassy time alarms
@@ -220,8 +575,8 @@ where
             Poll::Ready(result) => return result,
             Poll::Pending => {
                 // Check timeout
-                if crate::timer::uptime_us() - start > timeout_us {
-                    panic!("Async test timed out after 5 seconds");
+                if stopwatch.elapsed_us() > timeout_us {
+                    panic!("Async test timed out after {} ms", timeout_us / 1000);
                 }
 
                 // Small busy-wait to avoid tight spinning
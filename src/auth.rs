@@ -0,0 +1,312 @@
+//! Authentication
+//!
+//! Protocol code (the SSH state machine, later a REST API) asks the current
+//! `AuthProvider` whether credentials are valid and what a user may do; it
+//! never looks at user records itself. The default provider, `ConfigAuth`,
+//! reads users from kernel settings:
+//!
+//! ```text
+//! auth.users=alice:admin,bob:user
+//! auth.alice.password=pbkdf2-sha256$<iterations>$<salt, hex>$<hash, hex>
+//! auth.bob.ed25519=<raw 32-byte public key, hex>
+//! auth.idle_lock_min=15            (all users)
+//! auth.alice.idle_lock_min=5       (overrides it for alice; 0 = never;
+//!                                   users without a password never lock)
+//! ```
+//!
+//! Passwords are stored as PBKDF2-HMAC-SHA256 with a per-user random salt
+//! and at least `MIN_PBKDF2_ITERATIONS` rounds; on the host:
+//!
+//! ```text
+//! python3 - <<'EOF'
+//! import hashlib, os
+//! salt = os.urandom(16)
+//! key = hashlib.pbkdf2_hmac("sha256", b"secret", salt, 20000)
+//! print(f"pbkdf2-sha256$20000${salt.hex()}${key.hex()}")
+//! EOF
+//! ```
+//!
+//! Without `auth.users` every login is accepted as admin, as before users
+//! could be configured. Other backends (TOTP, RADIUS/LDAP over the network)
+//! implement the trait and are returned from `provider` instead.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt::Write;
+use core::time::Duration;
+use sha2::{Digest, Sha256};
+
+use crate::lockdep::Spinlock;
+
+/// Fewest PBKDF2 rounds a stored password may use; weaker hashes never match
+pub const MIN_PBKDF2_ITERATIONS: u32 = 10_000;
+
+/// Longest salt accepted, in bytes
+const MAX_SALT: usize = 64;
+
+// ============================================================================
+// Roles
+// ============================================================================
+
+/// What a user may do, least privileged first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Read-only access to statistics
+    Guest,
+    /// Shell access
+    User,
+    /// May change kernel state (log levels, clearing buffers, ...)
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Guest => "guest",
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Role> {
+        [Role::Guest, Role::User, Role::Admin]
+            .into_iter()
+            .find(|r| r.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+// ============================================================================
+// Provider Trait
+// ============================================================================
+
+/// A source of users and credentials
+pub trait AuthProvider: Send + Sync {
+    /// Short name for logs
+    fn name(&self) -> &'static str;
+
+    fn verify_password(&self, user: &str, password: &[u8]) -> bool;
+
+    /// Is `key` (public key blob contents for `algorithm`) allowed for `user`?
+    ///
+    /// Only the key is checked here; the protocol proves possession
+    /// separately by verifying a signature.
+    #[cfg(feature = "ssh")]
+    fn verify_pubkey(&self, user: &str, algorithm: &str, key: &[u8]) -> bool;
+
+    /// Role granted to `user`, None if unknown
+    fn role(&self, user: &str) -> Option<Role>;
+
+    /// May `user` do something that needs `required`?
+    fn authorize(&self, user: &str, required: Role) -> bool {
+        self.role(user).is_some_and(|role| role >= required)
+    }
+
+    /// Idle time after which `user`'s shell locks until they enter
+    /// their password again, None for no lock
+    ///
+    /// Key-only users must get None: the lock prompt can't check a key.
+    #[cfg(feature = "ssh")]
+    fn idle_lock(&self, _user: &str) -> Option<Duration> {
+        None
+    }
+
+    /// True if the provider accepts anyone (no users configured)
+    fn is_open(&self) -> bool {
+        false
+    }
+}
+
+// ============================================================================
+// Password Hashing
+// ============================================================================
+
+/// Decode a hex string into `out`; false unless it is exactly the right length
+fn decode_hex(text: &str, out: &mut [u8]) -> bool {
+    let text = text.as_bytes();
+    if text.len() != out.len() * 2 {
+        return false;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        let pair = core::str::from_utf8(&text[i * 2..i * 2 + 2]).unwrap_or("");
+        match u8::from_str_radix(pair, 16) {
+            Ok(b) => *byte = b,
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+/// PBKDF2-HMAC-SHA256 (RFC 8018), one 32-byte output block
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    // HMAC key block: long passwords are hashed first
+    let mut key = [0u8; 64];
+    if password.len() > key.len() {
+        key[..32].copy_from_slice(&Sha256::digest(password));
+    } else {
+        key[..password.len()].copy_from_slice(password);
+    }
+    let mut inner_pad = key;
+    let mut outer_pad = key;
+    for (i, o) in inner_pad.iter_mut().zip(outer_pad.iter_mut()) {
+        *i ^= 0x36;
+        *o ^= 0x5c;
+    }
+    let mut inner = Sha256::new();
+    inner.update(inner_pad);
+    let mut outer = Sha256::new();
+    outer.update(outer_pad);
+    crate::secret::wipe(&mut key);
+    crate::secret::wipe(&mut inner_pad);
+    crate::secret::wipe(&mut outer_pad);
+
+    // The padded keys are hashed once; each round continues from a copy
+    let mac = |parts: &[&[u8]]| -> [u8; 32] {
+        let mut h = inner.clone();
+        for part in parts {
+            h.update(part);
+        }
+        let mut o = outer.clone();
+        o.update(h.finalize());
+        o.finalize().into()
+    };
+    let mut u = mac(&[salt, &1u32.to_be_bytes()]);
+    let mut out = u;
+    for _ in 1..iterations {
+        u = mac(&[&u]);
+        for (o, b) in out.iter_mut().zip(u) {
+            *o ^= b;
+        }
+    }
+    out
+}
+
+/// The `auth.<user>.password` value for `password`
+pub fn hash_password(password: &[u8], salt: &[u8], iterations: u32) -> String {
+    let mut text = alloc::format!("pbkdf2-sha256${}$", iterations);
+    for b in salt {
+        let _ = write!(text, "{:02x}", b);
+    }
+    text.push('$');
+    for b in pbkdf2_sha256(password, salt, iterations) {
+        let _ = write!(text, "{:02x}", b);
+    }
+    text
+}
+
+/// Check `password` against a stored `pbkdf2-sha256$...` value
+fn verify_hash(stored: &str, password: &[u8]) -> bool {
+    let mut fields = stored.split('$');
+    let (Some("pbkdf2-sha256"), Some(iterations), Some(salt_hex), Some(hash_hex), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return false;
+    };
+    let Ok(iterations) = iterations.parse::<u32>() else {
+        return false;
+    };
+    let mut salt = [0u8; MAX_SALT];
+    let salt_len = salt_hex.len() / 2;
+    let mut expected = [0u8; 32];
+    if iterations < MIN_PBKDF2_ITERATIONS
+        || salt_len == 0
+        || salt_len > MAX_SALT
+        || !decode_hex(salt_hex, &mut salt[..salt_len])
+        || !decode_hex(hash_hex, &mut expected)
+    {
+        return false;
+    }
+    constant_time_eq(&pbkdf2_sha256(password, &salt[..salt_len], iterations), &expected)
+}
+
+/// Compare without stopping at the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// ============================================================================
+// Config Provider
+// ============================================================================
+
+/// Users from `auth.*` kernel settings
+pub struct ConfigAuth;
+
+impl ConfigAuth {
+    fn setting(user: &str, field: &str) -> Option<String> {
+        crate::config::get(&alloc::format!("auth.{}.{}", user, field))
+    }
+}
+
+impl AuthProvider for ConfigAuth {
+    fn name(&self) -> &'static str {
+        "config"
+    }
+
+    fn verify_password(&self, user: &str, password: &[u8]) -> bool {
+        if self.is_open() {
+            return true;
+        }
+        if self.role(user).is_none() {
+            return false;
+        }
+        Self::setting(user, "password").is_some_and(|stored| verify_hash(&stored, password))
+    }
+
+    #[cfg(feature = "ssh")]
+    fn verify_pubkey(&self, user: &str, algorithm: &str, key: &[u8]) -> bool {
+        if self.is_open() {
+            return true;
+        }
+        if algorithm != "ssh-ed25519" || self.role(user).is_none() {
+            return false;
+        }
+        let mut expected = [0u8; 32];
+        match Self::setting(user, "ed25519") {
+            Some(hex) if decode_hex(&hex, &mut expected) => constant_time_eq(key, &expected),
+            _ => false,
+        }
+    }
+
+    fn role(&self, user: &str) -> Option<Role> {
+        let users = match crate::config::get("auth.users") {
+            Some(users) => users,
+            None => return Some(Role::Admin),
+        };
+        users
+            .split(',')
+            .filter_map(|entry| entry.split_once(':'))
+            .find(|(name, _)| *name == user)
+            .and_then(|(_, role)| Role::parse(role))
+    }
+
+    #[cfg(feature = "ssh")]
+    fn idle_lock(&self, user: &str) -> Option<Duration> {
+        // Without a password there is nothing to unlock with
+        Self::setting(user, "password")?;
+        let minutes = Self::setting(user, "idle_lock_min")
+            .and_then(|m| m.parse::<u64>().ok())
+            .or_else(|| crate::config::get_u64("auth.idle_lock_min"))?;
+        (minutes > 0).then(|| Duration::from_secs(minutes * 60))
+    }
+
+    fn is_open(&self) -> bool {
+        crate::config::get("auth.users").is_none()
+    }
+}
+
+// ============================================================================
+// Current Provider
+// ============================================================================
+
+static PROVIDER: Spinlock<Option<Arc<dyn AuthProvider>>> = Spinlock::new(None);
+
+/// The backend in use (`ConfigAuth`)
+pub fn provider() -> Arc<dyn AuthProvider> {
+    PROVIDER
+        .lock()
+        .get_or_insert_with(|| Arc::new(ConfigAuth))
+        .clone()
+}
@@ -0,0 +1,108 @@
+//! Stack Backtraces
+//!
+//! The kernel is built with frame pointers (`force-frame-pointers=yes` in
+//! `.cargo/config.toml`), so every function's prologue pushes a frame
+//! record - the caller's x29 and the return address - and points x29 at
+//! it. Walking that chain gives the return addresses of every active call
+//! without unwind tables.
+//!
+//! Addresses are printed raw; `scripts/symbolize.sh` turns a captured log
+//! into function names and source lines with the kernel ELF.
+
+use core::fmt;
+
+// ============================================================================
+// Frame Walking
+// ============================================================================
+
+/// Return addresses kept per backtrace (innermost first)
+pub const MAX_FRAMES: usize = 32;
+
+/// Frame records must lie in RAM (boot stack or thread stacks)
+const STACK_RANGE: core::ops::Range<usize> = 0x4000_0000..0x4800_0000;
+
+/// Follow the frame-pointer chain starting at frame record `fp`
+///
+/// Fills `out` with return addresses, innermost first, and returns how
+/// many were found. Stops early at anything that doesn't look like a frame
+/// record, so a corrupted stack gives a short trace rather than a fault.
+pub fn walk(mut fp: usize, out: &mut [usize]) -> usize {
+    let mut len = 0;
+    while len < out.len() {
+        if !fp.is_multiple_of(8) || !STACK_RANGE.contains(&fp) {
+            break;
+        }
+        // SAFETY: fp is an aligned address in RAM
+        let (next, lr) = unsafe { (*(fp as *const usize), *((fp + 8) as *const usize)) };
+        if lr == 0 {
+            break;
+        }
+        out[len] = lr;
+        len += 1;
+        // Frames move toward higher addresses as we unwind
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    len
+}
+
+/// Current frame pointer
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe {
+        core::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack));
+    }
+    fp
+}
+
+// ============================================================================
+// Backtrace
+// ============================================================================
+
+/// Return addresses of the active calls, captured without allocating
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    frames: [usize; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Backtrace of the caller
+    #[inline(always)]
+    pub fn capture() -> Self {
+        Self::from_frame(None, frame_pointer())
+    }
+
+    /// Backtrace of interrupted code: `pc` (if known) followed by the chain
+    /// from frame record `fp`, e.g. ELR and x29 from an exception frame
+    pub fn from_frame(pc: Option<usize>, fp: usize) -> Self {
+        let mut frames = [0; MAX_FRAMES];
+        let mut len = 0;
+        if let Some(pc) = pc {
+            frames[0] = pc;
+            len = 1;
+        }
+        len += walk(fp, &mut frames[len..]);
+        Self { frames, len }
+    }
+
+    /// Addresses, innermost first
+    pub fn frames(&self) -> &[usize] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.len == 0 {
+            return writeln!(f, "  <no frames>");
+        }
+        for (i, addr) in self.frames().iter().enumerate() {
+            writeln!(f, "  #{:<2} {:#018x}", i, addr)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+//! Kernel Configuration
+//!
+//! Settings come from the kernel command line (`bootargs` in the device
+//! tree's /chosen node) as whitespace-separated `key=value` pairs, e.g.
+//! `sched.coop_timeout_policy=kill sched.coop_timeout_ms=2000`. Values can
+//! be overridden at runtime with `set`. Unknown keys are kept and ignored.
+//!
+//! The command line is copied out of the DTB before the page allocator
+//! takes over RAM, so `init` must run before `pmm::init` and doesn't
+//! allocate; overrides need the heap.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::lockdep::Spinlock;
+
+/// Flattened device tree header magic (big-endian)
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/// Longest command line we keep
+const CMDLINE_MAX: usize = 1024;
+
+struct Cmdline {
+    buf: [u8; CMDLINE_MAX],
+    len: usize,
+}
+
+static CMDLINE: Spinlock<Cmdline> = Spinlock::new(Cmdline {
+    buf: [0; CMDLINE_MAX],
+    len: 0,
+});
+
+/// Runtime overrides, searched before the command line
+static OVERRIDES: Spinlock<Vec<(String, String)>> = Spinlock::new(Vec::new());
+
+/// Run a closure with IRQs disabled (config may be read from IRQ context)
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Initialization
+// ============================================================================
+
+/// Copy the command line out of the device tree at `dtb_ptr`
+///
+/// `ram` is the RAM range; a pointer outside it (QEMU doesn't pass a DTB to
+/// bare ELF kernels) leaves the configuration empty. Returns true if a
+/// command line was found.
+pub fn init(dtb_ptr: usize, ram: core::ops::Range<usize>) -> bool {
+    if dtb_ptr == 0 || !dtb_ptr.is_multiple_of(8) || !ram.contains(&dtb_ptr) {
+        return false;
+    }
+
+    // SAFETY: pointer is aligned and inside RAM
+    let magic = u32::from_be(unsafe { core::ptr::read_volatile(dtb_ptr as *const u32) });
+    if magic != FDT_MAGIC {
+        return false;
+    }
+
+    // SAFETY: header magic checked above; fdt validates the rest
+    let fdt = match unsafe { fdt::Fdt::from_ptr(dtb_ptr as *const u8) } {
+        Ok(fdt) => fdt,
+        Err(_) => return false,
+    };
+
+    let bootargs = fdt
+        .find_node("/chosen")
+        .and_then(|node| node.property("bootargs"))
+        .and_then(|prop| prop.as_str());
+
+    match bootargs {
+        Some(args) => {
+            set_cmdline(args);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Replace the command line (truncated to CMDLINE_MAX bytes)
+pub fn set_cmdline(args: &str) {
+    with_irqs_disabled(|| {
+        let mut cmdline = CMDLINE.lock();
+        let len = args.len().min(CMDLINE_MAX);
+        cmdline.buf[..len].copy_from_slice(&args.as_bytes()[..len]);
+        cmdline.len = len;
+    })
+}
+
+// ============================================================================
+// Lookup
+// ============================================================================
+
+/// Value for `key`, from runtime overrides first, then the command line
+pub fn get(key: &str) -> Option<String> {
+    with_irqs_disabled(|| {
+        let overrides = OVERRIDES.lock();
+        if let Some((_, v)) = overrides.iter().find(|(k, _)| k == key) {
+            return Some(v.clone());
+        }
+        drop(overrides);
+
+        let cmdline = CMDLINE.lock();
+        let text = core::str::from_utf8(&cmdline.buf[..cmdline.len]).unwrap_or("");
+        // Later occurrences win, like most command-line parsers
+        text.split_whitespace()
+            .filter_map(|arg| arg.split_once('='))
+            .rfind(|(k, _)| *k == key)
+            .map(|(_, v)| String::from(v))
+    })
+}
+
+/// Numeric value for `key`, or None if missing or not a number
+pub fn get_u64(key: &str) -> Option<u64> {
+    get(key)?.parse().ok()
+}
+
+/// Boolean value for `key` (1/0, true/false, on/off, yes/no)
+pub fn get_bool(key: &str) -> Option<bool> {
+    match get(key)?.as_str() {
+        "1" | "true" | "on" | "yes" => Some(true),
+        "0" | "false" | "off" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Override `key` at runtime
+pub fn set(key: &str, value: &str) {
+    with_irqs_disabled(|| {
+        let mut overrides = OVERRIDES.lock();
+        match overrides.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = String::from(value),
+            None => overrides.push((String::from(key), String::from(value))),
+        }
+    })
+}
+
+/// Drop the runtime override of `key`, falling back to the command line
+pub fn unset(key: &str) {
+    with_irqs_disabled(|| OVERRIDES.lock().retain(|(k, _)| k != key))
+}
+
+/// True for settings that hold credentials (password hashes, keys) and
+/// must not be shown to non-admins or copied into reports
+#[cfg(feature = "shell")]
+pub fn is_secret(key: &str) -> bool {
+    let last = key.rsplit('.').next().unwrap_or(key);
+    key.starts_with("auth.") || matches!(last, "password" | "key" | "secret" | "psk" | "token")
+}
+
+/// All settings as (key, value), command line first, then overrides
+pub fn entries() -> Vec<(String, String)> {
+    with_irqs_disabled(|| {
+        let mut out: Vec<(String, String)> = Vec::new();
+        {
+            let cmdline = CMDLINE.lock();
+            let text = core::str::from_utf8(&cmdline.buf[..cmdline.len]).unwrap_or("");
+            for (k, v) in text.split_whitespace().filter_map(|arg| arg.split_once('=')) {
+                match out.iter_mut().find(|(ek, _)| ek == k) {
+                    Some((_, ev)) => *ev = String::from(v),
+                    None => out.push((String::from(k), String::from(v))),
+                }
+            }
+        }
+        for (k, v) in OVERRIDES.lock().iter() {
+            match out.iter_mut().find(|(ek, _)| ek == k) {
+                Some((_, ev)) => *ev = v.clone(),
+                None => out.push((k.clone(), v.clone())),
+            }
+        }
+        out
+    })
+}
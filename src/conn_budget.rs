@@ -0,0 +1,181 @@
+//! Connection Memory Budgets
+//!
+//! Each accepted connection costs one boxed future, and an async fn keeps
+//! everything that lives across an `.await` inline in its future - so the
+//! allocation made at accept time grows with every field a session gains.
+//! Two tools keep that in check:
+//!
+//! - `admit::<MAX, _>(service, future)` fails the *build* if the future is
+//!   bigger than `MAX` bytes, so a size regression is caught in the change
+//!   that causes it, and records the size for the report
+//! - `HeapState<T>` moves one piece of per-connection state into its own
+//!   allocation, leaving a pointer in the future; the bytes are charged to
+//!   the connection's service while it lives
+//!
+//! `/connbudget.txt` reports both per service.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::fmt::Write;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+
+use crate::allocator;
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+use crate::network::Service;
+
+/// Run a closure with IRQs disabled
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Accounting
+// ============================================================================
+
+/// Memory of one service's connections
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BudgetStats {
+    /// Size of the connection future `admit` last saw
+    pub future_bytes: usize,
+    /// The budget it was checked against
+    pub future_budget: usize,
+    /// Bytes in live `HeapState`s
+    pub heap_bytes: usize,
+    /// Highest `heap_bytes` since boot
+    pub peak_heap_bytes: usize,
+    /// Live `HeapState`s
+    pub splits: usize,
+}
+
+impl BudgetStats {
+    const fn new() -> Self {
+        Self {
+            future_bytes: 0,
+            future_budget: 0,
+            heap_bytes: 0,
+            peak_heap_bytes: 0,
+            splits: 0,
+        }
+    }
+}
+
+static BUDGETS: Spinlock<[BudgetStats; Service::ALL.len()]> =
+    Spinlock::new([const { BudgetStats::new() }; Service::ALL.len()]);
+
+/// Counters of `service`
+pub fn stats(service: Service) -> BudgetStats {
+    with_irqs_disabled(|| BUDGETS.lock()[service as usize])
+}
+
+/// One line per service that has admitted a connection or split state
+pub fn report() -> String {
+    let mut out = String::new();
+    for service in Service::ALL {
+        let st = stats(service);
+        if st.future_budget == 0 && st.peak_heap_bytes == 0 {
+            continue;
+        }
+        let _ = writeln!(
+            out,
+            "{} future {}/{} heap {} peak {} splits {}",
+            service.as_str(),
+            st.future_bytes,
+            st.future_budget,
+            st.heap_bytes,
+            st.peak_heap_bytes,
+            st.splits
+        );
+    }
+    out
+}
+
+// ============================================================================
+// Future Size Budget
+// ============================================================================
+
+/// Hand back `future` unchanged, refusing to compile if it is larger than
+/// `MAX` bytes
+///
+/// Call where a connection future is created, before it is boxed; the
+/// size is also recorded for `service` so the report shows the headroom.
+pub fn admit<const MAX: usize, F: Future>(service: Service, future: F) -> F {
+    const {
+        assert!(
+            core::mem::size_of::<F>() <= MAX,
+            "connection future exceeds its size budget; move state into a HeapState"
+        )
+    };
+    with_irqs_disabled(|| {
+        let st = &mut BUDGETS.lock()[service as usize];
+        st.future_bytes = core::mem::size_of::<F>();
+        st.future_budget = MAX;
+    });
+    future
+}
+
+// ============================================================================
+// Split State
+// ============================================================================
+
+/// Per-connection state kept in its own heap allocation
+///
+/// Dereferences to the value; its size is charged to the service until
+/// it is dropped.
+pub struct HeapState<T> {
+    value: Box<T>,
+    service: Service,
+}
+
+impl<T> HeapState<T> {
+    /// Move `value` to the heap on behalf of a `service` connection
+    ///
+    /// Fails with `OutOfMemory` rather than panicking, so the caller can
+    /// close the connection.
+    pub fn new(service: Service, value: T) -> KResult<Self> {
+        let value = allocator::try_box(value)
+            .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "connection state"))?;
+        with_irqs_disabled(|| {
+            let st = &mut BUDGETS.lock()[service as usize];
+            st.heap_bytes += core::mem::size_of::<T>();
+            st.peak_heap_bytes = st.peak_heap_bytes.max(st.heap_bytes);
+            st.splits += 1;
+        });
+        Ok(Self { value, service })
+    }
+}
+
+impl<T> Deref for HeapState<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for HeapState<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T> Drop for HeapState<T> {
+    fn drop(&mut self) {
+        with_irqs_disabled(|| {
+            let st = &mut BUDGETS.lock()[self.service as usize];
+            st.heap_bytes = st.heap_bytes.saturating_sub(core::mem::size_of::<T>());
+            st.splits = st.splits.saturating_sub(1);
+        });
+    }
+}
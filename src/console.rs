@@ -1,67 +1,707 @@
-use crate::alloc::string::ToString;
+use crate::error::{ErrorKind, KError, KResult};
+use crate::line_edit::{Edit, LineEditor};
+use crate::lockdep::Spinlock;
+use crate::pl011::{INT_RT, INT_RX, INT_TX, Pl011, UartConfig};
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering};
 
-const UART0_BASE: usize = 0x0900_0000;
-const UART0_DR: *mut u8 = UART0_BASE as *mut u8; // Data register (offset 0x00)
-const UART0_FR: *const u32 = (UART0_BASE + 0x18) as *const u32; // Flag register (offset 0x18)
-const RXFE: u32 = 1 << 4; // Receive FIFO empty flag
-const TXFF: u32 = 1 << 5; // Transmit FIFO full flag
+const UART0_BASE: usize = crate::uart::PORTS[0].base;
 
-unsafe fn putchar(c: u8) {
-    // Write directly to UART data register
-    unsafe {
-        UART0_DR.write_volatile(c);
+/// UART0 interrupt (SPI 1 on QEMU virt)
+pub const UART0_IRQ: u32 = crate::uart::PORTS[0].irq;
+
+// SAFETY: UART0_BASE is the PL011 on QEMU virt and only the console drives it
+static UART0: Pl011 = unsafe { Pl011::new(UART0_BASE) };
+
+// ============================================================================
+// Backends
+// ============================================================================
+
+/// Device(s) the console uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// PL011 only (the default)
+    Uart,
+    /// virtio-console only, for hosts without a PL011
+    Virtio,
+    /// Output to both, input from the UART
+    Both,
+}
+
+impl Backend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Backend::Uart => "uart",
+            Backend::Virtio => "virtio",
+            Backend::Both => "both",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Backend> {
+        [Backend::Uart, Backend::Virtio, Backend::Both]
+            .into_iter()
+            .find(|b| b.as_str().eq_ignore_ascii_case(name))
     }
 }
 
-// blocking print
-pub fn print(s: &str) {
-    for c in s.bytes() {
-        unsafe {
-            putchar(c);
+static BACKEND: AtomicU8 = AtomicU8::new(Backend::Uart as u8);
+
+pub fn backend() -> Backend {
+    match BACKEND.load(Ordering::Acquire) {
+        1 => Backend::Virtio,
+        2 => Backend::Both,
+        _ => Backend::Uart,
+    }
+}
+
+/// Switch console devices
+///
+/// A virtio console that wasn't in use yet first gets everything still in
+/// the dmesg ring, so it shows the boot log too.
+pub fn set_backend(backend: Backend) -> KResult<()> {
+    if backend != Backend::Uart && !crate::virtio_console::is_present() {
+        return Err(KError::with_context(ErrorKind::NoDevice, "virtio-console"));
+    }
+    let previous = self::backend();
+    if previous == Backend::Uart && backend != Backend::Uart {
+        crate::virtio_console::write(&crate::dmesg::read_all());
+    }
+    BACKEND.store(backend as u8, Ordering::Release);
+    Ok(())
+}
+
+// ============================================================================
+// TX Ring
+// ============================================================================
+
+/// Size of the software transmit buffer
+const TX_RING_SIZE: usize = 8192;
+
+struct TxRing {
+    buf: [u8; TX_RING_SIZE],
+    head: usize, // Next byte to transmit
+    len: usize,
+}
+
+impl TxRing {
+    const fn new() -> Self {
+        Self {
+            buf: [0; TX_RING_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Queue as many bytes as fit, returning how many were taken
+    fn push(&mut self, bytes: &[u8]) -> usize {
+        let count = bytes.len().min(TX_RING_SIZE - self.len);
+        for &b in &bytes[..count] {
+            let idx = (self.head + self.len) % TX_RING_SIZE;
+            self.buf[idx] = b;
+            self.len += 1;
+        }
+        count
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let b = self.buf[self.head];
+        self.head = (self.head + 1) % TX_RING_SIZE;
+        self.len -= 1;
+        Some(b)
+    }
+}
+
+static TX_RING: Spinlock<TxRing> = Spinlock::new(TxRing::new());
+
+/// Once set, output goes through TX_RING and is drained by the UART TX IRQ
+static TX_IRQ_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Number of times a writer found the ring full and had to wait for the UART
+static TX_STALLS: AtomicU64 = AtomicU64::new(0);
+
+// ============================================================================
+// RX Ring
+// ============================================================================
+
+/// Size of the software receive buffer
+const RX_RING_SIZE: usize = 256;
+
+struct RxRing {
+    buf: [u8; RX_RING_SIZE],
+    head: usize, // Next byte to read
+    len: usize,
+}
+
+impl RxRing {
+    const fn new() -> Self {
+        Self {
+            buf: [0; RX_RING_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Store a byte, returning false if the ring is full
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == RX_RING_SIZE {
+            return false;
+        }
+        self.buf[(self.head + self.len) % RX_RING_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
         }
+        let b = self.buf[self.head];
+        self.head = (self.head + 1) % RX_RING_SIZE;
+        self.len -= 1;
+        Some(b)
+    }
+
+    #[cfg(feature = "ssh")]
+    fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
     }
 }
 
-pub fn has_char() -> bool {
+static RX_RING: Spinlock<RxRing> = Spinlock::new(RxRing::new());
+
+/// Once set, input is collected by the UART RX IRQ into RX_RING
+static RX_IRQ_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Bytes dropped because the RX ring was full
+static RX_OVERRUNS: AtomicU64 = AtomicU64::new(0);
+
+/// Run a closure with IRQs disabled so the TX IRQ can't contend for the ring
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
     unsafe {
-        let flags = UART0_FR.read_volatile();
-        (flags & RXFE) == 0 // If RXFE is 0, data is available
+        core::arch::asm!("msr daif, {}", in(reg) daif);
     }
+    result
 }
 
-// blocking read
-fn _getchar_blocking() -> u8 {
+/// True if a writer waiting for ring space may yield instead of spinning:
+/// IRQs unmasked (so the TX IRQ drains the ring meanwhile and we're not in
+/// a handler) and not inside a `no_preempt` scope
+fn can_yield() -> bool {
+    let daif: u64;
     unsafe {
-        // Wait until data is available
-        while !has_char() {}
-        // Read the character
-        UART0_DR.read_volatile()
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
     }
+    daif & (1 << 7) == 0 && !crate::sched::preempt_disabled()
 }
 
-// non-blocking read (only call if has_char() is true!)
-pub fn getchar() -> u8 {
-    unsafe { UART0_DR.read_volatile() }
+/// Move bytes from the ring into the hardware FIFO until either runs out.
+/// The TX interrupt stays unmasked only while the ring still has data.
+fn drain_to_fifo(ring: &mut TxRing) {
+    while ring.len > 0 && !UART0.tx_full() {
+        if let Some(b) = ring.pop() {
+            UART0.write_byte(b);
+        }
+    }
+
+    if ring.len == 0 {
+        UART0.disable_interrupts(INT_TX);
+    } else {
+        UART0.enable_interrupts(INT_TX);
+    }
+}
+
+/// Text output: kept in dmesg, and sent to the console devices unless a
+/// binary transfer owns them
+fn write_out(bytes: &[u8]) {
+    crate::dmesg::record(bytes);
+    if RAW_MODE.load(Ordering::Acquire) {
+        RAW_HELD_BACK.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        return;
+    }
+    transmit(bytes);
+}
+
+/// Send bytes to the console devices exactly as given
+fn transmit(bytes: &[u8]) {
+    match backend() {
+        Backend::Uart => {}
+        Backend::Virtio => return crate::virtio_console::write(bytes),
+        Backend::Both => crate::virtio_console::write(bytes),
+    }
+
+    if !TX_IRQ_MODE.load(Ordering::Acquire) {
+        for &b in bytes {
+            UART0.write_byte_blocking(b);
+        }
+        return;
+    }
+
+    let mut remaining = bytes;
+    while !remaining.is_empty() {
+        let pushed = with_irqs_disabled(|| {
+            let mut ring = TX_RING.lock();
+            let n = ring.push(remaining);
+            drain_to_fifo(&mut ring);
+            n
+        });
+
+        if pushed == 0 {
+            // Ring and FIFO both full - wait for the UART to make room,
+            // letting other threads run if this context may block
+            TX_STALLS.fetch_add(1, Ordering::Relaxed);
+            if can_yield() {
+                crate::threading::yield_now();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+        remaining = &remaining[pushed..];
+    }
+}
+
+/// Move received bytes from the hardware FIFO into the RX ring
+fn drain_rx_fifo(ring: &mut RxRing) {
+    while let Some(b) = UART0.read_byte() {
+        if !ring.push(b) {
+            RX_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// UART interrupt handler - refills the TX FIFO and drains the RX FIFO
+pub fn uart_irq_handler(_irq: u32) {
+    let status = UART0.masked_interrupts();
+
+    if status & (INT_RX | INT_RT) != 0 {
+        UART0.clear_interrupts(INT_RX | INT_RT);
+        let mut ring = RX_RING.lock();
+        drain_rx_fifo(&mut ring);
+    }
+
+    if status & INT_TX != 0 {
+        UART0.clear_interrupts(INT_TX);
+        // IRQs are masked in handler context, so this can't deadlock with a writer
+        let mut ring = TX_RING.lock();
+        drain_to_fifo(&mut ring);
+    }
+}
+
+/// Switch console output to the interrupt-driven TX ring
+/// Call once the GIC and IRQ dispatch are up
+pub fn enable_irq_driven_tx() {
+    crate::irq::register_handler(UART0_IRQ, uart_irq_handler);
+    TX_IRQ_MODE.store(true, Ordering::Release);
+}
+
+/// Collect console input with the RX interrupt instead of polling the FIFO
+/// Call once the GIC and IRQ dispatch are up
+pub fn enable_irq_driven_rx() {
+    crate::irq::register_handler(UART0_IRQ, uart_irq_handler);
+    // Keep anything typed before now
+    with_irqs_disabled(|| drain_rx_fifo(&mut RX_RING.lock()));
+    RX_IRQ_MODE.store(true, Ordering::Release);
+    UART0.enable_interrupts(INT_RX | INT_RT);
+}
+
+/// Number of input bytes dropped because nobody read them in time
+#[cfg(feature = "shell")]
+pub fn rx_overruns() -> u64 {
+    RX_OVERRUNS.load(Ordering::Relaxed)
+}
+
+/// Block until every buffered byte has been handed to the UART
+pub fn flush() {
+    with_irqs_disabled(|| {
+        let mut ring = TX_RING.lock();
+        while let Some(b) = ring.pop() {
+            UART0.write_byte_blocking(b);
+        }
+        UART0.disable_interrupts(INT_TX);
+    });
+}
+
+/// Drop back to polled output for the panic path
+///
+/// Uses try_lock so a panic raised while the ring is held can still print;
+/// in that case any buffered bytes are abandoned.
+pub fn panic_flush() {
+    TX_IRQ_MODE.store(false, Ordering::Release);
+    // The panic message must reach the wire even mid-transfer
+    RAW_MODE.store(false, Ordering::Release);
+    if let Some(mut ring) = TX_RING.try_lock() {
+        while let Some(b) = ring.pop() {
+            UART0.write_byte_blocking(b);
+        }
+    }
+    UART0.disable_interrupts(INT_TX);
+}
+
+/// Line settings last applied to the console UART
+static UART_CONFIG: Spinlock<UartConfig> = Spinlock::new(UartConfig::DEFAULT);
+
+/// Program the console UART's line settings
+///
+/// Buffered output is written out at the old settings first.
+pub fn init_uart(config: &UartConfig) -> KResult<()> {
+    flush();
+    with_irqs_disabled(|| {
+        UART0.init(config)?;
+        *UART_CONFIG.lock() = *config;
+        Ok(())
+    })
+}
+
+/// Line settings of the console UART
+pub fn uart_config() -> UartConfig {
+    with_irqs_disabled(|| *UART_CONFIG.lock())
+}
+
+/// Baud rate the console UART is actually running at
+pub fn uart_baud_rate() -> u32 {
+    UART0.baud_rate(uart_config().clock_hz)
+}
+
+/// Number of times output stalled because the TX ring was full
+#[cfg(feature = "shell")]
+pub fn tx_stalls() -> u64 {
+    TX_STALLS.load(Ordering::Relaxed)
+}
+
+// ============================================================================
+// Console Multiplexer
+// ============================================================================
+//
+// Output always goes to the UART, and every byte is also kept in the dmesg
+// ring; remote consoles (SSH sessions) that attach follow that ring, so
+// kernel output shows up on serial and on each of them at once.
+//
+// Input comes from exactly one console, the active one. While a remote
+// console is active, its keystrokes are fed into REMOTE_RX and serial input
+// waits in the UART ring until input is switched back.
+
+/// A console interactive input can come from
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleId {
+    Serial,
+    Remote(u32),
+}
+
+#[cfg(feature = "shell")]
+struct RemoteConsole {
+    id: u32,
+    name: String,
+}
+
+#[cfg(feature = "shell")]
+static REMOTES: Spinlock<Vec<RemoteConsole>> = Spinlock::new(Vec::new());
+
+#[cfg(feature = "ssh")]
+static NEXT_REMOTE_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Id of the remote console providing input, 0 for serial
+static ACTIVE_REMOTE: AtomicU32 = AtomicU32::new(0);
+
+/// Input typed on the active remote console
+static REMOTE_RX: Spinlock<RxRing> = Spinlock::new(RxRing::new());
+
+/// Register a remote console, returning its id
+///
+/// The caller mirrors output itself by following `dmesg::read_since` from
+/// the position at attach time, and must `detach` when it goes away.
+#[cfg(feature = "ssh")]
+pub fn attach(name: &str) -> u32 {
+    let id = NEXT_REMOTE_ID.fetch_add(1, Ordering::Relaxed);
+    with_irqs_disabled(|| {
+        REMOTES.lock().push(RemoteConsole {
+            id,
+            name: String::from(name),
+        })
+    });
+    id
+}
+
+/// Unregister a remote console; input falls back to serial if it was active
+#[cfg(feature = "ssh")]
+pub fn detach(id: u32) {
+    with_irqs_disabled(|| REMOTES.lock().retain(|r| r.id != id));
+    if ACTIVE_REMOTE
+        .compare_exchange(id, 0, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+    {
+        with_irqs_disabled(|| REMOTE_RX.lock().clear());
+    }
+}
+
+/// Serial plus every attached remote console, with their names
+#[cfg(feature = "shell")]
+pub fn consoles() -> Vec<(ConsoleId, String)> {
+    let mut list = Vec::new();
+    list.push((ConsoleId::Serial, String::from("uart0")));
+    with_irqs_disabled(|| {
+        for r in REMOTES.lock().iter() {
+            list.push((ConsoleId::Remote(r.id), r.name.clone()));
+        }
+    });
+    list
+}
+
+/// The console input currently comes from
+#[cfg(feature = "shell")]
+pub fn active() -> ConsoleId {
+    match ACTIVE_REMOTE.load(Ordering::Acquire) {
+        0 => ConsoleId::Serial,
+        id => ConsoleId::Remote(id),
+    }
+}
+
+/// Take console input from `console` from now on
+#[cfg(feature = "ssh")]
+pub fn set_active(console: ConsoleId) -> KResult<()> {
+    let id = match console {
+        ConsoleId::Serial => 0,
+        ConsoleId::Remote(id) => {
+            if !with_irqs_disabled(|| REMOTES.lock().iter().any(|r| r.id == id)) {
+                return Err(KError::with_context(ErrorKind::NotFound, "console not attached"));
+            }
+            id
+        }
+    };
+    // Keystrokes meant for the previous console must not leak to the caller
+    with_irqs_disabled(|| REMOTE_RX.lock().clear());
+    ACTIVE_REMOTE.store(id, Ordering::Release);
+    Ok(())
+}
+
+/// Hand input typed on remote console `id` to the console
+///
+/// Ignored unless that console is active. Returns false if bytes were
+/// dropped (not active, or nobody is reading and the ring is full).
+#[cfg(feature = "ssh")]
+pub fn push_input(id: u32, bytes: &[u8]) -> bool {
+    if ACTIVE_REMOTE.load(Ordering::Acquire) != id {
+        return false;
+    }
+    with_irqs_disabled(|| {
+        let mut ring = REMOTE_RX.lock();
+        let mut all = true;
+        for &b in bytes {
+            if !ring.push(b) {
+                RX_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+                all = false;
+            }
+        }
+        all
+    })
+}
+
+fn remote_input_active() -> bool {
+    ACTIVE_REMOTE.load(Ordering::Acquire) != 0
+}
+
+// ============================================================================
+// Raw Mode
+// ============================================================================
+
+/// Set while a binary transfer owns the console devices
+static RAW_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Text bytes kept off the wire by raw mode since boot
+static RAW_HELD_BACK: AtomicU64 = AtomicU64::new(0);
+
+/// Exclusive use of the console devices for `write_bytes`
+///
+/// While one exists, text output (`print`, the log) still goes to dmesg
+/// but not to the devices, so it can't corrupt the binary stream. Dropping
+/// it restores text output and notes how much was held back.
+pub struct RawMode {
+    held_back_at_start: u64,
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        RAW_MODE.store(false, Ordering::Release);
+        let held = raw_held_back() - self.held_back_at_start;
+        if held > 0 {
+            crate::println!("[console] {} bytes of output held back during raw mode (see dmesg)", held);
+        }
+    }
+}
+
+/// Take the console for a binary transfer
+///
+/// Fails with `AlreadyExists` while another transfer holds it.
+pub fn enter_raw() -> KResult<RawMode> {
+    if RAW_MODE.swap(true, Ordering::AcqRel) {
+        return Err(KError::with_context(ErrorKind::AlreadyExists, "console raw mode"));
+    }
+    Ok(RawMode {
+        held_back_at_start: raw_held_back(),
+    })
+}
+
+pub fn is_raw() -> bool {
+    RAW_MODE.load(Ordering::Acquire)
+}
+
+/// Text bytes kept off the wire by raw mode since boot
+pub fn raw_held_back() -> u64 {
+    RAW_HELD_BACK.load(Ordering::Relaxed)
+}
+
+// ============================================================================
+// Public API
+// ============================================================================
+
+// buffered print (blocking only if the TX ring is full)
+pub fn print(s: &str) {
+    write_out(s.as_bytes());
+}
+
+/// Send arbitrary bytes to the console devices, 8-bit clean
+///
+/// Unlike `print` the bytes aren't text: they bypass dmesg and are sent
+/// even in raw mode, so XMODEM blocks, gdb packets or a pcap stream reach
+/// the serial port unaltered.
+pub fn write_bytes(bytes: &[u8]) {
+    transmit(bytes);
+}
+
+/// `core::fmt` sink that writes straight to the console, no heap involved
+///
+/// Each `write_str` is pushed separately, so output from other threads
+/// may land between the pieces of one formatted message.
+pub struct Writer;
+
+impl core::fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_out(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    let _ = core::fmt::Write::write_fmt(&mut Writer, args);
+}
+
+/// Format to the console without allocating
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::console::_print(format_args!($($arg)*))
+    };
+}
+
+/// Format to the console without allocating, then end the line
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::console::print("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::console::_print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Next received byte, if any (never blocks)
+pub fn read_byte() -> Option<u8> {
+    if remote_input_active() {
+        with_irqs_disabled(|| REMOTE_RX.lock().pop())
+    } else if backend() == Backend::Virtio {
+        crate::virtio_console::read_byte()
+    } else if RX_IRQ_MODE.load(Ordering::Acquire) {
+        with_irqs_disabled(|| RX_RING.lock().pop())
+    } else {
+        UART0.read_byte()
+    }
+}
+
+/// Wait for the next received byte
+///
+/// With RX interrupts on (or input coming from a remote or virtio console),
+/// other threads run while waiting.
+pub fn read_byte_blocking() -> u8 {
+    loop {
+        if let Some(c) = read_byte() {
+            return c;
+        }
+        let fed_by_others = RX_IRQ_MODE.load(Ordering::Acquire)
+            || remote_input_active()
+            || backend() == Backend::Virtio;
+        if fed_by_others && !crate::sched::preempt_disabled() {
+            crate::threading::yield_now();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
 }
 
 const BUFFER_SIZE: usize = 100;
 
+/// Editor (and history) shared by interactive `read_line` calls
+static LINE_EDITOR: Spinlock<LineEditor> = Spinlock::new(LineEditor::new());
+
+/// Read until Enter, appending to `buffer` (terminator included)
+///
+/// With echo the line can be edited (arrows, Ctrl-U, history - see
+/// `line_edit`) and Ctrl-C starts it over; without echo only Backspace/DEL
+/// works, and never erases what `buffer` held before the call. Returns the
+/// buffer length.
 pub fn read_line(buffer: &mut Vec<u8>, with_echo: bool) -> usize {
+    if with_echo {
+        return read_line_edited(buffer);
+    }
+    let start = buffer.len();
     loop {
-        if has_char() {
-            let c: u8 = getchar();
-            buffer.push(c);
-            if with_echo {
-                print(&(c as char).to_string());
+        let c = read_byte_blocking();
+        match c {
+            0x08 | 0x7F => {
+                if buffer.len() > start {
+                    buffer.pop();
+                }
             }
-            if c == b'\n' || c == b'\r' {
+            b'\n' | b'\r' => {
+                buffer.push(c);
                 return buffer.len();
             }
+            _ => buffer.push(c),
         }
     }
 }
 
+fn read_line_edited(buffer: &mut Vec<u8>) -> usize {
+    // Borrow the shared editor so its lock isn't held while we block; a
+    // concurrent reader just gets a fresh one
+    let mut editor = core::mem::replace(&mut *LINE_EDITOR.lock(), LineEditor::new());
+    let mut out = Vec::new();
+    let line = loop {
+        let c = read_byte_blocking();
+        out.clear();
+        let edit = editor.feed(c, &mut out);
+        write_out(&out);
+        if let Some(Edit::Line(line)) = edit {
+            break line;
+        }
+    };
+    *LINE_EDITOR.lock() = editor;
+    buffer.extend_from_slice(&line);
+    buffer.push(b'\n');
+    buffer.len()
+}
+
 pub fn print_as_akuma(s: &str) {
     print("≽ܫ≼ ... ");
     print(s);
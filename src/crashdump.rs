@@ -0,0 +1,223 @@
+//! Crash Dump Region
+//!
+//! The panic handler formats its report into a reserved RAM region before
+//! printing anything, so the report survives even if console output is
+//! lost. The region sits between the boot stack and the heap and is not
+//! cleared by a warm reset, so the next boot can recover the report.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+
+use crate::backtrace::Backtrace;
+use crate::lockdep::Spinlock;
+
+// ============================================================================
+// Region Layout
+// ============================================================================
+
+/// Reserved region for crash reports (below the heap, above the boot stack)
+pub const CRASH_REGION_BASE: usize = 0x4070_0000;
+pub const CRASH_REGION_SIZE: usize = 64 * 1024;
+
+/// Marks a region holding a complete report ("AKUMADMP")
+const CRASH_MAGIC: u64 = 0x414B_554D_4144_4D50;
+
+#[repr(C)]
+struct CrashHeader {
+    magic: u64,
+    len: u64,
+    uptime_us: u64,
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<CrashHeader>();
+const DATA_CAPACITY: usize = CRASH_REGION_SIZE - HEADER_SIZE;
+
+fn header() -> *mut CrashHeader {
+    CRASH_REGION_BASE as *mut CrashHeader
+}
+
+fn data() -> *mut u8 {
+    (CRASH_REGION_BASE + HEADER_SIZE) as *mut u8
+}
+
+/// Set on first panic so a panic inside the panic path doesn't recurse
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Report recovered from the previous boot
+static PREVIOUS: Spinlock<Option<String>> = Spinlock::new(None);
+
+// ============================================================================
+// Capturing
+// ============================================================================
+
+/// Formats into the crash region without touching the heap
+struct RegionWriter {
+    len: usize,
+}
+
+impl Write for RegionWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(DATA_CAPACITY - self.len);
+        // SAFETY: stays within the reserved region
+        unsafe {
+            core::ptr::copy_nonoverlapping(s.as_ptr(), data().add(self.len), n);
+        }
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Longest valid UTF-8 prefix of the stored report
+fn stored_text(len: usize) -> &'static str {
+    // SAFETY: the region is reserved for us and len <= DATA_CAPACITY
+    let bytes = unsafe { core::slice::from_raw_parts(data(), len.min(DATA_CAPACITY)) };
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+    }
+}
+
+/// Format the panic report into the crash region and return it
+///
+/// Must not allocate or take locks: it runs with the system in an unknown
+/// state, possibly from inside the allocator or the console.
+pub fn record_panic(info: &PanicInfo, backtrace: &Backtrace) -> &'static str {
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        return "Nested panic while handling panic\n";
+    }
+
+    let mut w = RegionWriter { len: 0 };
+    if let Some(location) = info.location() {
+        let _ = writeln!(w, "Location: {}:{}", location.file(), location.line());
+    }
+    let _ = writeln!(w, "Message: {}", info.message());
+    let uptime = crate::timer::uptime_us();
+    let _ = writeln!(w, "Uptime: {} us", uptime);
+    let _ = write!(w, "Backtrace:\n{}", backtrace);
+
+    // SAFETY: header lies in the reserved region; magic written last
+    unsafe {
+        let h = header();
+        core::ptr::addr_of_mut!((*h).len).write_volatile(w.len as u64);
+        core::ptr::addr_of_mut!((*h).uptime_us).write_volatile(uptime);
+        core::ptr::addr_of_mut!((*h).magic).write_volatile(CRASH_MAGIC);
+    }
+
+    stored_text(w.len)
+}
+
+// ============================================================================
+// Panic Policy
+// ============================================================================
+
+/// What the panic handler does once the report is captured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicAction {
+    /// Print the report and stop, for inspection (default)
+    Halt = 0,
+    /// Reset at once without printing; the report survives in the region
+    Reset = 1,
+    /// Print the report, wait `panic.reset_delay_s`, then reset
+    DumpReset = 2,
+}
+
+impl PanicAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PanicAction::Halt => "halt",
+            PanicAction::Reset => "reset",
+            PanicAction::DumpReset => "dump-reset",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<PanicAction> {
+        [PanicAction::Halt, PanicAction::Reset, PanicAction::DumpReset]
+            .into_iter()
+            .find(|a| a.as_str() == name)
+    }
+}
+
+/// Default wait before a dump-and-reset
+const DEFAULT_RESET_DELAY_S: u32 = 10;
+
+// Kept in atomics: the panic handler must not read config (it allocates)
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+static RESET_DELAY_S: AtomicU32 = AtomicU32::new(DEFAULT_RESET_DELAY_S);
+
+/// Load `panic.action` and `panic.reset_delay_s` (call once the heap is up)
+pub fn load_policy() {
+    if let Some(name) = crate::config::get("panic.action") {
+        match PanicAction::parse(&name) {
+            Some(action) => set_panic_action(action),
+            None => crate::kwarn!("[Crash] Unknown panic.action '{}', halting on panic", name),
+        }
+    }
+    if let Some(delay) = crate::config::get_u64("panic.reset_delay_s") {
+        RESET_DELAY_S.store(delay.min(u32::MAX as u64) as u32, Ordering::Relaxed);
+    }
+}
+
+pub fn panic_action() -> PanicAction {
+    match PANIC_ACTION.load(Ordering::Relaxed) {
+        1 => PanicAction::Reset,
+        2 => PanicAction::DumpReset,
+        _ => PanicAction::Halt,
+    }
+}
+
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+/// Seconds a dump-and-reset waits before resetting
+pub fn reset_delay_s() -> u32 {
+    RESET_DELAY_S.load(Ordering::Relaxed)
+}
+
+// ============================================================================
+// Recovery
+// ============================================================================
+
+/// Recover a report left by the previous boot (call after the heap is up)
+/// Returns true if one was found
+pub fn init() -> bool {
+    // SAFETY: header lies in the reserved region
+    let (magic, len) = unsafe {
+        let h = header();
+        (
+            core::ptr::addr_of!((*h).magic).read_volatile(),
+            core::ptr::addr_of!((*h).len).read_volatile() as usize,
+        )
+    };
+
+    if magic != CRASH_MAGIC || len > DATA_CAPACITY {
+        return false;
+    }
+
+    let report = String::from(stored_text(len));
+    *PREVIOUS.lock() = Some(report);
+    clear_region();
+    true
+}
+
+fn clear_region() {
+    // SAFETY: header lies in the reserved region
+    unsafe {
+        core::ptr::addr_of_mut!((*header()).magic).write_volatile(0);
+    }
+}
+
+/// Crash report recovered from the previous boot, if any
+#[cfg(any(feature = "shell", feature = "fs"))]
+pub fn previous_report() -> Option<String> {
+    PREVIOUS.lock().clone()
+}
+
+/// Forget the recovered report
+#[cfg(feature = "shell")]
+pub fn clear_previous() {
+    *PREVIOUS.lock() = None;
+}
@@ -0,0 +1,452 @@
+//! Control Protocol Server
+//!
+//! A small request/response protocol over TCP for host-side management
+//! tools (`akumactl`), so scripts don't have to drive the SSH shell and
+//! scrape its output. Every message is a frame: a 4-byte big-endian
+//! length followed by that many bytes of payload.
+//!
+//! ```text
+//! request:  [len: u32 BE] [op: u8] [args ...]
+//! response: [len: u32 BE] [status: u8] [body ...]
+//! ```
+//!
+//! The first request must be `AUTH` (`user\0password`), checked against
+//! the same `auth` provider as SSH; after that the connection keeps the
+//! user's role. `COMMAND` runs a shell command line through the SSH
+//! shell's command table, with the same admin gating.
+//!
+//! The protocol is plain TCP, so the password and every request can be
+//! read on the way. Admin requests are only served to peers on the same
+//! machine: 127.0.0.0/8, or an address listed in `ctl.admin_hosts`
+//! (with QEMU user networking a forwarded host port arrives from
+//! 10.0.2.2). Everyone else is held to the user role, and so is everyone
+//! while no users are configured (`auth.users` unset).
+//!
+//! The port comes from `ctl.port` (default 0, disabled). One client is
+//! served at a time and idle clients are dropped after a minute.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use embassy_net::{IpEndpoint, Stack};
+use embassy_time::{Duration, Timer, with_timeout};
+
+use crate::async_net::{TcpListener, TcpStream};
+use crate::auth::{self, Role};
+use crate::error::{ErrorKind, KError, KResult};
+use crate::events::Event;
+use crate::klog::{self, Filter, Level};
+use crate::network::Service;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// Largest frame payload accepted or sent
+pub const MAX_FRAME: usize = 64 * 1024;
+
+/// Clients that send nothing for this long are disconnected
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Failed AUTH requests before the connection is closed
+const MAX_AUTH_ATTEMPTS: u32 = 3;
+
+// ============================================================================
+// Protocol
+// ============================================================================
+
+/// Request operation codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Op {
+    /// `user\0password`
+    Auth = 1,
+    /// A shell command line
+    Command = 2,
+    /// Network and latency statistics (no arguments)
+    Stats = 3,
+    /// Thread counts (no arguments)
+    Threads = 4,
+    /// Log records from a sequence number (u64 BE); the body is the next
+    /// sequence number (u64 BE) followed by the records as text
+    LogTail = 5,
+    /// A setting's value, or every setting when the key is empty
+    ConfigGet = 6,
+    /// `key=value`, admin only
+    ConfigSet = 7,
+}
+
+impl Op {
+    pub fn from_u8(op: u8) -> Option<Op> {
+        match op {
+            1 => Some(Op::Auth),
+            2 => Some(Op::Command),
+            3 => Some(Op::Stats),
+            4 => Some(Op::Threads),
+            5 => Some(Op::LogTail),
+            6 => Some(Op::ConfigGet),
+            7 => Some(Op::ConfigSet),
+            _ => None,
+        }
+    }
+}
+
+/// Response status codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0,
+    /// Unknown op or malformed arguments
+    BadRequest = 1,
+    /// Not authenticated yet, or wrong credentials
+    AuthRequired = 2,
+    /// The user's role doesn't allow the request
+    Denied = 3,
+    NotFound = 4,
+}
+
+/// Frame `payload` for sending
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + payload.len());
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Split the first complete frame off `data`
+///
+/// Returns the payload and the bytes consumed, `Ok(None)` if more data is
+/// needed, or a `Protocol` error if the announced length is over `MAX_FRAME`.
+pub fn decode_frame(data: &[u8]) -> KResult<Option<(&[u8], usize)>> {
+    if data.len() < 4 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    if len > MAX_FRAME {
+        return Err(KError::with_context(ErrorKind::Protocol, "ctl frame too large"));
+    }
+    if data.len() < 4 + len {
+        return Ok(None);
+    }
+    Ok(Some((&data[4..4 + len], 4 + len)))
+}
+
+fn response(status: Status, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + body.len());
+    out.push(status as u8);
+    out.extend_from_slice(body);
+    out
+}
+
+// ============================================================================
+// Dispatch
+// ============================================================================
+
+/// Is `peer` trusted with admin requests over this unencrypted channel?
+pub fn is_admin_peer(peer: IpEndpoint) -> bool {
+    let addr = alloc::format!("{}", peer.addr);
+    addr.starts_with("127.")
+        || crate::config::get("ctl.admin_hosts")
+            .is_some_and(|hosts| hosts.split(',').any(|host| host.trim() == addr))
+}
+
+/// Per-connection state
+#[derive(Default)]
+pub struct Session {
+    user: Option<String>,
+    auth_failures: u32,
+    /// The peer may make admin requests (see `is_admin_peer`)
+    admin_peer: bool,
+}
+
+impl Session {
+    /// Session for a remote peer, held to the user role at most
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Session for a peer that passed `is_admin_peer`
+    pub fn admin_peer() -> Self {
+        Self {
+            admin_peer: true,
+            ..Self::default()
+        }
+    }
+
+    /// Role `user` gets on this connection
+    fn effective_role(&self, user: &str) -> Option<Role> {
+        let provider = auth::provider();
+        let role = provider.role(user)?;
+        // Open mode lets anyone in under any name; that must not be admin
+        if role == Role::Admin && (!self.admin_peer || provider.is_open()) {
+            return Some(Role::User);
+        }
+        Some(role)
+    }
+
+    fn has_role(&self, required: Role) -> bool {
+        self.user
+            .as_deref()
+            .and_then(|user| self.effective_role(user))
+            .is_some_and(|role| role >= required)
+    }
+}
+
+/// Shell output uses CRLF for terminals; host tools get plain newlines
+fn strip_cr(mut text: Vec<u8>) -> Vec<u8> {
+    text.retain(|&b| b != b'\r');
+    text
+}
+
+fn authenticate(session: &mut Session, args: &[u8]) -> Vec<u8> {
+    let (user, password) = match args.iter().position(|&b| b == 0) {
+        Some(pos) => (&args[..pos], &args[pos + 1..]),
+        None => return response(Status::BadRequest, b"expected user\\0password"),
+    };
+    let user = match core::str::from_utf8(user) {
+        Ok(user) if !user.is_empty() => user,
+        _ => return response(Status::BadRequest, b"bad user name"),
+    };
+
+    let provider = auth::provider();
+    if provider.verify_password(user, password) && provider.authorize(user, Role::Guest) {
+        crate::kevent!(Event::AuthSuccess, "[CTL] User '{}' authenticated", user);
+        let role = session.effective_role(user).map_or("guest", |r| r.as_str());
+        session.user = Some(String::from(user));
+        return response(Status::Ok, role.as_bytes());
+    }
+
+    session.auth_failures += 1;
+    crate::kevent!(
+        Event::AuthFailure,
+        "[CTL] Rejected login for '{}' ({})",
+        user,
+        provider.name()
+    );
+    response(Status::AuthRequired, b"authentication failed")
+}
+
+fn threads_report() -> Vec<u8> {
+    let (ready, running, terminated) = crate::threading::thread_stats();
+    let mut report = alloc::format!(
+        "threads {}/{}\nready {}\nrunning {}\nterminated {}\ncoop_timeouts {}\nstarvation_events {}\n",
+        crate::threading::thread_count(),
+        crate::threading::max_threads(),
+        ready,
+        running,
+        terminated,
+        crate::threading::cooperative_timeouts(),
+        crate::threading::starvation_events()
+    );
+    crate::threading::for_each_thread(|t| {
+        let name = if t.name.is_empty() { "-" } else { t.name };
+        report.push_str(&alloc::format!("tid {} {} cpu_us {}\n", t.tid, name, t.cpu_time_us));
+    });
+    report.into_bytes()
+}
+
+fn log_tail(args: &[u8]) -> Vec<u8> {
+    let from = match <[u8; 8]>::try_from(args) {
+        Ok(bytes) => u64::from_be_bytes(bytes),
+        Err(_) => return response(Status::BadRequest, b"expected a u64 sequence number"),
+    };
+    let (records, next) = klog::records_since(from, &Filter::ALL);
+    let mut out = response(Status::Ok, &next.to_be_bytes());
+    for r in records.iter() {
+        let line = match r.event {
+            Some(event) => alloc::format!(
+                "{} [{}] {} [E{}] {}\n",
+                klog::Timestamp::of(r),
+                r.level.as_str(),
+                r.module,
+                event.id(),
+                r.text
+            ),
+            None => alloc::format!(
+                "{} [{}] {} {}\n",
+                klog::Timestamp::of(r),
+                r.level.as_str(),
+                r.module,
+                r.text
+            ),
+        };
+        if out.len() + line.len() > MAX_FRAME {
+            // The client continues from the first record left out
+            out[1..9].copy_from_slice(&r.seq.to_be_bytes());
+            break;
+        }
+        out.extend_from_slice(line.as_bytes());
+    }
+    out
+}
+
+fn config_get(key: &str) -> Vec<u8> {
+    if key.is_empty() {
+        let mut out = response(Status::Ok, b"");
+        for (key, value) in crate::config::entries() {
+            out.extend_from_slice(alloc::format!("{}={}\n", key, value).as_bytes());
+        }
+        return out;
+    }
+    match crate::config::get(key) {
+        Some(value) => response(Status::Ok, value.as_bytes()),
+        None => response(Status::NotFound, key.as_bytes()),
+    }
+}
+
+fn config_set(args: &[u8]) -> Vec<u8> {
+    let pair = core::str::from_utf8(args).ok().and_then(|s| s.split_once('='));
+    match pair {
+        Some((key, value)) if !key.trim().is_empty() => {
+            crate::config::set(key.trim(), value.trim());
+            log(&alloc::format!("[CTL] Set {}={}\n", key.trim(), value.trim()));
+            response(Status::Ok, b"")
+        }
+        _ => response(Status::BadRequest, b"expected key=value"),
+    }
+}
+
+/// Handle one request payload and build the response payload
+pub fn dispatch(session: &mut Session, request: &[u8]) -> Vec<u8> {
+    let (op, args) = match request.split_first() {
+        Some((&op, args)) => (Op::from_u8(op), args),
+        None => return response(Status::BadRequest, b"empty request"),
+    };
+    let op = match op {
+        Some(op) => op,
+        None => return response(Status::BadRequest, b"unknown op"),
+    };
+
+    if op == Op::Auth {
+        return authenticate(session, args);
+    }
+    if session.user.is_none() {
+        return response(Status::AuthRequired, b"send AUTH first");
+    }
+
+    match op {
+        Op::Auth => unreachable!(),
+        Op::Command => {
+            if crate::shell::needs_admin(args) && !session.has_role(Role::Admin) {
+                return response(Status::Denied, b"permission denied");
+            }
+            if !session.has_role(Role::User) {
+                return response(Status::Denied, b"permission denied");
+            }
+            let output = strip_cr(crate::shell::execute_command(args));
+            response(Status::Ok, &output)
+        }
+        Op::Stats => response(Status::Ok, &strip_cr(crate::shell::execute_command(b"stats"))),
+        Op::Threads => response(Status::Ok, &threads_report()),
+        Op::LogTail => log_tail(args),
+        Op::ConfigGet => {
+            let Ok(key) = core::str::from_utf8(args).map(str::trim) else {
+                return response(Status::BadRequest, b"bad key");
+            };
+            // Listing everything would include password hashes and keys
+            let secret = key.is_empty() || crate::config::is_secret(key);
+            if secret && !session.has_role(Role::Admin) {
+                return response(Status::Denied, b"permission denied");
+            }
+            config_get(key)
+        }
+        Op::ConfigSet => {
+            if !session.has_role(Role::Admin) {
+                return response(Status::Denied, b"permission denied");
+            }
+            config_set(args)
+        }
+    }
+}
+
+// ============================================================================
+// Connection Handling
+// ============================================================================
+
+async fn read_frame(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let mut chunk = [0u8; 512];
+    loop {
+        match decode_frame(buffer) {
+            Ok(Some((payload, used))) => {
+                let payload = payload.to_vec();
+                buffer.drain(..used);
+                return Some(payload);
+            }
+            Ok(None) => {}
+            Err(_) => return None,
+        }
+        match with_timeout(IDLE_TIMEOUT, stream.read(&mut chunk)).await {
+            Ok(Ok(n)) if n > 0 => {
+                if crate::allocator::try_extend(buffer, &chunk[..n]).is_err() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream) {
+    let mut session = match stream.remote_endpoint() {
+        Some(peer) if is_admin_peer(peer) => Session::admin_peer(),
+        _ => Session::new(),
+    };
+    let mut buffer = Vec::new();
+
+    while let Some(request) = read_frame(&mut stream, &mut buffer).await {
+        let mut reply = dispatch(&mut session, &request);
+        if reply.len() > MAX_FRAME {
+            reply.truncate(MAX_FRAME);
+        }
+        if stream.write_all(&encode_frame(&reply)).await.is_err() {
+            break;
+        }
+        if session.auth_failures >= MAX_AUTH_ATTEMPTS {
+            break;
+        }
+    }
+    let _ = stream.flush().await;
+    stream.close();
+}
+
+// ============================================================================
+// Accept Loop
+// ============================================================================
+
+/// Run the control protocol accept loop
+pub async fn run(stack: Stack<'static>) {
+    let port = crate::config::get_u64("ctl.port").unwrap_or(0) as u16;
+    if port == 0 {
+        return;
+    }
+    log(&alloc::format!("[CTL Server] Listening on port {}\n", port));
+
+    let listener = TcpListener::new(stack, port);
+
+    loop {
+        match listener.accept().await {
+            Ok(mut stream) => {
+                stream.set_service(Service::Ctl);
+                handle_connection(stream).await;
+            }
+            Err(e) => {
+                warn(&alloc::format!(
+                    "[CTL Server] Accept error: {:?}, retrying...\n",
+                    e
+                ));
+                Timer::after(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Logging
+// ============================================================================
+
+fn log(msg: &str) {
+    klog::log(Level::Info, "ctl_server", msg);
+}
+
+fn warn(msg: &str) {
+    klog::log(Level::Warn, "ctl_server", msg);
+}
@@ -0,0 +1,216 @@
+//! Heap Defragmentation Hints
+//!
+//! Talc never moves a live block, so a long-running kernel ends up with
+//! its free memory cut into pieces by buffers that were sized at some peak
+//! and kept ever since: the log ring, the handle table, the kobject
+//! registry. Only their owners can move them. Owners register a
+//! `HintHandler`; `run` (the admin `defrag` command, meant for a
+//! maintenance window) asks every handler to drop what it can rebuild
+//! (`Hint::DropCaches`) and then to move long-lived buffers into fresh,
+//! exactly-sized allocations (`Hint::Reallocate`), which talc places low
+//! in the heap now that the caches are gone.
+//!
+//! The heap is measured with `allocator::fragmentation_report` before and
+//! after, and the result is logged as `Event::HeapDefrag`.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::allocator;
+use crate::error::{ErrorKind, KError, KResult};
+use crate::events::Event;
+use crate::lockdep::Spinlock;
+
+/// Registered handlers at most
+const MAX_HANDLERS: usize = 8;
+
+/// Run a closure with IRQs disabled
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Handlers
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hint {
+    /// Free memory that can be rebuilt on demand
+    DropCaches,
+    /// Move long-lived buffers into fresh, exactly-sized allocations
+    Reallocate,
+}
+
+/// Acts on a hint; returns bytes released (`DropCaches`) or moved
+/// (`Reallocate`). Runs in thread context and may allocate.
+pub type HintHandler = fn(Hint) -> usize;
+
+static HANDLERS: Spinlock<[Option<(&'static str, HintHandler)>; MAX_HANDLERS]> =
+    Spinlock::new([None; MAX_HANDLERS]);
+
+/// Register `handler` under `name`
+pub fn register(name: &'static str, handler: HintHandler) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut handlers = HANDLERS.lock();
+        if handlers.iter().flatten().any(|(n, _)| *n == name) {
+            return Err(KError::with_context(ErrorKind::AlreadyExists, name));
+        }
+        let slot = handlers
+            .iter_mut()
+            .find(|h| h.is_none())
+            .ok_or(KError::with_context(ErrorKind::NoFreeSlots, "defrag handlers"))?;
+        *slot = Some((name, handler));
+        Ok(())
+    })
+}
+
+/// Remove the handler registered under `name`
+pub fn unregister(name: &str) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut handlers = HANDLERS.lock();
+        let slot = handlers
+            .iter_mut()
+            .find(|h| h.is_some_and(|(n, _)| n == name))
+            .ok_or(KError::with_context(ErrorKind::NotFound, "defrag handler"))?;
+        *slot = None;
+        Ok(())
+    })
+}
+
+/// Register the kernel's own handlers
+pub fn init() {
+    let builtin: [(&'static str, HintHandler); 3] = [
+        ("klog", crate::klog::defrag),
+        ("kobject", crate::kobject::defrag),
+        ("handles", crate::handles::defrag),
+    ];
+    for (name, handler) in builtin {
+        if let Err(e) = register(name, handler) {
+            crate::kwarn!("[Defrag] {} handler not registered: {}", name, e);
+        }
+    }
+}
+
+// ============================================================================
+// Reallocation Helpers
+// ============================================================================
+
+/// Move `vec` into a fresh allocation of exactly its length
+///
+/// Returns the bytes moved; 0 if it is empty or no memory was available
+/// (then `vec` is left as it was).
+pub fn reallocate_vec<T>(vec: &mut Vec<T>) -> usize {
+    if vec.capacity() == 0 {
+        return 0;
+    }
+    let mut fresh = Vec::new();
+    if fresh.try_reserve_exact(vec.len()).is_err() {
+        return 0;
+    }
+    fresh.append(vec);
+    *vec = fresh;
+    vec.len() * core::mem::size_of::<T>()
+}
+
+/// `reallocate_vec` for a ring buffer; keeps the element order
+pub fn reallocate_deque<T>(deque: &mut VecDeque<T>) -> usize {
+    if deque.capacity() == 0 {
+        return 0;
+    }
+    let mut fresh = VecDeque::new();
+    if fresh.try_reserve_exact(deque.len()).is_err() {
+        return 0;
+    }
+    fresh.append(deque);
+    *deque = fresh;
+    deque.len() * core::mem::size_of::<T>()
+}
+
+// ============================================================================
+// Running
+// ============================================================================
+
+/// Free memory of the general heap at one point
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapSummary {
+    pub free_bytes: usize,
+    pub largest: usize,
+    /// Free blocks of 64 bytes or more
+    pub blocks: usize,
+    pub fragmentation_percent: usize,
+}
+
+#[cfg(feature = "shell")]
+impl HeapSummary {
+    fn now() -> Self {
+        let r = allocator::fragmentation_report();
+        HeapSummary {
+            free_bytes: r.free_bytes,
+            largest: r.largest,
+            blocks: r.blocks,
+            fragmentation_percent: r.fragmentation_percent(),
+        }
+    }
+}
+
+/// Outcome of one `run`
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DefragReport {
+    pub before: HeapSummary,
+    pub after: HeapSummary,
+    /// Bytes handlers released for `DropCaches`
+    pub released: usize,
+    /// Bytes handlers moved for `Reallocate`
+    pub moved: usize,
+    /// Handlers asked
+    pub handlers: usize,
+}
+
+/// Ask every handler to drop caches, then to reallocate
+///
+/// Takes the heap lock twice for the reports; run it when a pause is
+/// acceptable, not on a hot path.
+#[cfg(feature = "shell")]
+pub fn run() -> DefragReport {
+    let handlers: [Option<(&'static str, HintHandler)>; MAX_HANDLERS] =
+        with_irqs_disabled(|| *HANDLERS.lock());
+    let mut report = DefragReport {
+        before: HeapSummary::now(),
+        handlers: handlers.iter().flatten().count(),
+        ..DefragReport::default()
+    };
+
+    // Drop first, so the buffers moved next land in the space it freed
+    for (_, handler) in handlers.iter().flatten() {
+        report.released += handler(Hint::DropCaches);
+    }
+    for (_, handler) in handlers.iter().flatten() {
+        report.moved += handler(Hint::Reallocate);
+    }
+
+    report.after = HeapSummary::now();
+    crate::kevent!(
+        Event::HeapDefrag,
+        "[Defrag] {} handler(s) released {} bytes, moved {} bytes; fragmentation {}% -> {}%, largest free {} -> {} KB",
+        report.handlers,
+        report.released,
+        report.moved,
+        report.before.fragmentation_percent,
+        report.after.fragmentation_percent,
+        report.before.largest / 1024,
+        report.after.largest / 1024
+    );
+    report
+}
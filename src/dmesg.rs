@@ -0,0 +1,133 @@
+//! Kernel Message Buffer
+//!
+//! Everything written to the console is also kept in a fixed ring of the
+//! last `DMESG_SIZE` bytes, from the first boot message on, so output that
+//! scrolled past on serial can be read back later (SSH `dmesg`, HTTP
+//! `/dmesg.txt`). The ring lives in .bss, so it works before the heap is up.
+
+use alloc::vec::Vec;
+
+use crate::lockdep::Spinlock;
+
+/// Bytes of console output kept
+pub const DMESG_SIZE: usize = 16 * 1024;
+
+/// Run a closure with IRQs disabled (IRQ handlers print too)
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Byte Ring
+// ============================================================================
+
+/// Ring that keeps the most recent `N` bytes written to it
+///
+/// Positions count every byte ever written, so a reader can ask for
+/// "everything since position p" and learn how much it missed.
+pub struct ByteRing<const N: usize> {
+    buf: [u8; N],
+    /// Total bytes written
+    written: u64,
+}
+
+impl<const N: usize> ByteRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            written: 0,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        // Only the tail can survive
+        let bytes = &bytes[bytes.len().saturating_sub(N)..];
+        let start = (self.written % N as u64) as usize;
+        let first = bytes.len().min(N - start);
+        self.buf[start..start + first].copy_from_slice(&bytes[..first]);
+        self.buf[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+        self.written += bytes.len() as u64;
+    }
+
+    /// Position of the next byte to be written
+    pub fn position(&self) -> u64 {
+        self.written
+    }
+
+    /// Oldest position still held
+    pub fn oldest(&self) -> u64 {
+        self.written.saturating_sub(N as u64)
+    }
+
+    /// Bytes from `from` (clamped to what's still held) up to now
+    pub fn copy_since(&self, from: u64) -> Vec<u8> {
+        let from = from.clamp(self.oldest(), self.written);
+        let len = (self.written - from) as usize;
+        let mut out = Vec::new();
+        if out.try_reserve(len).is_err() {
+            return out;
+        }
+        let start = (from % N as u64) as usize;
+        let first = len.min(N - start);
+        out.extend_from_slice(&self.buf[start..start + first]);
+        out.extend_from_slice(&self.buf[..len - first]);
+        out
+    }
+
+    #[cfg(feature = "shell")]
+    pub fn clear(&mut self) {
+        self.written = 0;
+    }
+}
+
+// ============================================================================
+// Kernel Buffer
+// ============================================================================
+
+static DMESG: Spinlock<ByteRing<DMESG_SIZE>> = Spinlock::new(ByteRing::new());
+
+/// Append console output (called by the console for every write)
+///
+/// Skipped rather than waited for if the ring is busy, so a panic raised
+/// while it's held can still print.
+pub fn record(bytes: &[u8]) {
+    with_irqs_disabled(|| {
+        if let Some(mut ring) = DMESG.try_lock() {
+            ring.push(bytes);
+        }
+    });
+}
+
+/// Everything still in the buffer, oldest first
+pub fn read_all() -> Vec<u8> {
+    read_since(0).0
+}
+
+/// Output since position `from` and the position to continue from
+pub fn read_since(from: u64) -> (Vec<u8>, u64) {
+    with_irqs_disabled(|| {
+        let ring = DMESG.lock();
+        (ring.copy_since(from), ring.position())
+    })
+}
+
+/// Position the next recorded byte will have
+#[cfg(feature = "ssh")]
+pub fn position() -> u64 {
+    with_irqs_disabled(|| DMESG.lock().position())
+}
+
+#[cfg(feature = "shell")]
+pub fn clear() {
+    with_irqs_disabled(|| DMESG.lock().clear());
+}
@@ -0,0 +1,235 @@
+//! Driver Power Hooks
+//!
+//! Every path that stops the machine or its devices - graceful reboot and
+//! power-off today, kexec and snapshots later - has to quiesce devices
+//! first, so DMA into memory that is about to be reused stops and the next
+//! kernel finds them reset. Rather than each path knowing every device,
+//! drivers register a `Driver` with optional `suspend`, `resume` and
+//! `shutdown` hooks, and the paths call `suspend_all`, `resume_all` or
+//! `shutdown_all`.
+//!
+//! Devices go down in reverse registration order (a device registered
+//! after the one it depends on stops first) and come back up in
+//! registration order.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+
+/// Run a closure with IRQs disabled
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Driver Trait
+// ============================================================================
+
+/// Power management hooks of one device driver
+///
+/// Every hook is optional; the defaults do nothing. Hooks run in thread
+/// context with interrupts enabled, one driver at a time.
+pub trait Driver: Send + Sync {
+    /// Unique name, e.g. `virtio-net`
+    fn name(&self) -> &'static str;
+
+    /// Stop the device so it can be resumed later (snapshots, kexec that
+    /// may be aborted); no DMA or interrupts until `resume`
+    fn suspend(&self) -> KResult<()> {
+        Ok(())
+    }
+
+    /// Undo `suspend`
+    fn resume(&self) -> KResult<()> {
+        Ok(())
+    }
+
+    /// Stop the device for good before reboot, power-off or kexec
+    fn shutdown(&self) -> KResult<()> {
+        Ok(())
+    }
+}
+
+static DRIVERS: Spinlock<Vec<Arc<dyn Driver>>> = Spinlock::new(Vec::new());
+
+/// Add `driver` to the registry
+///
+/// Fails with `AlreadyExists` if a driver of that name is registered.
+pub fn register(driver: Arc<dyn Driver>) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut drivers = DRIVERS.lock();
+        if drivers.iter().any(|d| d.name() == driver.name()) {
+            return Err(KError::with_context(ErrorKind::AlreadyExists, "driver"));
+        }
+        drivers
+            .try_reserve(1)
+            .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "driver registry"))?;
+        drivers.push(driver);
+        Ok(())
+    })
+}
+
+/// Remove the driver called `name`
+pub fn unregister(name: &str) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut drivers = DRIVERS.lock();
+        let pos = drivers
+            .iter()
+            .position(|d| d.name() == name)
+            .ok_or(KError::with_context(ErrorKind::NotFound, "driver"))?;
+        drivers.remove(pos);
+        Ok(())
+    })
+}
+
+/// Names of registered drivers, in registration order
+#[cfg(feature = "shell")]
+pub fn names() -> Vec<&'static str> {
+    with_irqs_disabled(|| DRIVERS.lock().iter().map(|d| d.name()).collect())
+}
+
+/// The registry in registration order, copied so hooks run without the lock
+fn snapshot() -> Vec<Arc<dyn Driver>> {
+    with_irqs_disabled(|| DRIVERS.lock().clone())
+}
+
+// ============================================================================
+// Running Hooks
+// ============================================================================
+
+/// Suspend every driver, last registered first
+///
+/// If one fails, the drivers already suspended are resumed again and its
+/// error is returned, so the system is left as it was.
+pub fn suspend_all() -> KResult<()> {
+    let drivers = snapshot();
+    for (i, driver) in drivers.iter().enumerate().rev() {
+        if let Err(e) = driver.suspend() {
+            crate::kerror!("[Driver] {} failed to suspend: {}", driver.name(), e);
+            for resumed in &drivers[i + 1..] {
+                if let Err(e) = resumed.resume() {
+                    crate::kerror!("[Driver] {} failed to resume: {}", resumed.name(), e);
+                }
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Resume every driver, first registered first; returns how many failed
+pub fn resume_all() -> usize {
+    let mut failed = 0;
+    for driver in snapshot() {
+        if let Err(e) = driver.resume() {
+            crate::kerror!("[Driver] {} failed to resume: {}", driver.name(), e);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+/// Shut every driver down, last registered first; returns how many failed
+///
+/// A failure is logged and the rest still run: the machine is going down
+/// either way.
+pub fn shutdown_all() -> usize {
+    let mut failed = 0;
+    for driver in snapshot().iter().rev() {
+        if let Err(e) = driver.shutdown() {
+            crate::kerror!("[Driver] {} failed to shut down: {}", driver.name(), e);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+// ============================================================================
+// Graceful Shutdown
+// ============================================================================
+
+/// Grace period before a requested shutdown, so the reply to whoever asked
+/// for it (usually over the network) still goes out
+#[cfg(feature = "shell")]
+const SHUTDOWN_DELAY_US: u64 = 250_000;
+
+/// What to do once drivers are shut down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerAction {
+    Reboot,
+    PowerOff,
+}
+
+impl PowerAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerAction::Reboot => "reboot",
+            PowerAction::PowerOff => "power-off",
+        }
+    }
+
+    #[cfg(feature = "shell")]
+    fn to_u8(self) -> u8 {
+        self as u8 + 1
+    }
+
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(PowerAction::Reboot),
+            2 => Some(PowerAction::PowerOff),
+            _ => None,
+        }
+    }
+}
+
+/// Requested action (0 = none) and when it is due
+static SHUTDOWN_ACTION: AtomicU8 = AtomicU8::new(0);
+static SHUTDOWN_AT: AtomicU64 = AtomicU64::new(0);
+
+/// Ask the main loop to shut the system down shortly
+#[cfg(feature = "shell")]
+pub fn request_shutdown(action: PowerAction) {
+    SHUTDOWN_AT.store(crate::timer::uptime_us() + SHUTDOWN_DELAY_US, Ordering::Release);
+    SHUTDOWN_ACTION.store(action.to_u8(), Ordering::Release);
+}
+
+/// The requested action once it is due
+pub fn shutdown_due() -> Option<PowerAction> {
+    let action = PowerAction::from_u8(SHUTDOWN_ACTION.load(Ordering::Acquire))?;
+    (crate::timer::uptime_us() >= SHUTDOWN_AT.load(Ordering::Acquire)).then_some(action)
+}
+
+/// Shut every driver down, then reboot or power off through PSCI
+///
+/// Halts if the firmware refuses.
+pub fn shutdown_system(action: PowerAction) -> ! {
+    crate::kinfo!("[Driver] Shutting down drivers for {}", action.as_str());
+    let failed = shutdown_all();
+    if failed > 0 {
+        crate::kwarn!("[Driver] {} driver(s) failed to shut down", failed);
+    }
+    crate::console::flush();
+
+    let err = match action {
+        PowerAction::Reboot => crate::psci::system_reset(),
+        PowerAction::PowerOff => crate::psci::system_off(),
+    };
+    crate::console::print(&alloc::format!("PSCI {} failed ({}), halting\n", action.as_str(), err));
+    loop {
+        // SAFETY: wfi only waits for the next interrupt
+        unsafe { core::arch::asm!("wfi") }
+    }
+}
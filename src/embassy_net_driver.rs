@@ -3,10 +3,15 @@
 //! Provides:
 //! - LoopbackDevice: A loopback network device for testing async networking
 //!   without real hardware. Packets sent are immediately available to receive.
+//! - SimDevice: One end of a simulated Ethernet link with configurable
+//!   latency, loss and reordering, so two stacks can talk to each other
+//!   deterministically (same seed, same impairments) inside the kernel tests.
 
 use alloc::collections::VecDeque;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::cell::RefCell;
+use core::task::Waker;
 
 use critical_section::Mutex;
 use embassy_net_driver::{Capabilities, Driver, HardwareAddress, LinkState, RxToken, TxToken};
@@ -198,20 +203,270 @@ impl<'a> TxToken for LoopbackTxToken<'a> {
             let mut state_ref = state.borrow_mut();
 
             // Allocate buffer for the packet
-            let mut data = alloc::vec![0u8; len];
+            let mut data = match crate::allocator::try_zeroed_vec(len) {
+                Ok(data) => data,
+                Err(_) => {
+                    // Out of memory - let the stack build the frame, then drop it
+                    let mut scratch = [0u8; MAX_PACKET_SIZE];
+                    let len = len.min(MAX_PACKET_SIZE);
+                    return f(&mut scratch[..len]);
+                }
+            };
             let result = f(&mut data);
 
             // Queue the packet for reception (loopback behavior)
-            if state_ref.rx_queue.len() < LOOPBACK_QUEUE_SIZE {
+            if state_ref.rx_queue.len() < LOOPBACK_QUEUE_SIZE
+                && state_ref.rx_queue.try_reserve(1).is_ok()
+            {
                 state_ref.rx_queue.push_back(LoopbackPacket { data });
             }
-            // If queue is full, packet is dropped (like a real network)
+            // If queue is full (or memory is), packet is dropped (like a real network)
 
             result
         })
     }
 }
 
+// ============================================================================
+// Simulated Link
+// ============================================================================
+
+/// Frames that can be in flight in each direction before the link drops them
+const SIM_QUEUE_SIZE: usize = 64;
+
+/// Impairments applied to every frame crossing a simulated link
+#[derive(Debug, Clone, Copy)]
+pub struct SimConfig {
+    /// One-way delay before a frame can be received
+    pub latency_us: u64,
+    /// Chance a frame is silently dropped
+    pub loss_percent: u8,
+    /// Chance a frame overtakes the one queued before it
+    pub reorder_percent: u8,
+    /// PRNG seed; the same seed gives the same drops and swaps
+    pub seed: u64,
+}
+
+/// What a simulated link did to the frames sent over it (both directions)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SimStats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub reordered: u64,
+}
+
+struct InFlight {
+    /// Uptime at which the frame may be received
+    due_us: u64,
+    data: Vec<u8>,
+}
+
+struct SimState {
+    config: SimConfig,
+    /// xorshift64 state
+    rng: u64,
+    /// Frames travelling towards side 0 and side 1
+    queues: [VecDeque<InFlight>; 2],
+    /// Receivers waiting on each side
+    wakers: [Option<Waker>; 2],
+    stats: SimStats,
+}
+
+impl SimState {
+    /// Roll a percentage chance with the link's PRNG
+    fn chance(&mut self, percent: u8) -> bool {
+        if percent == 0 {
+            return false;
+        }
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng % 100) < percent as u64
+    }
+
+    /// Put a frame sent from `from` on the wire
+    fn send(&mut self, from: usize, data: Vec<u8>) {
+        self.stats.sent += 1;
+        let to = 1 - from;
+        if self.chance(self.config.loss_percent)
+            || self.queues[to].len() >= SIM_QUEUE_SIZE
+            || self.queues[to].try_reserve(1).is_err()
+        {
+            self.stats.dropped += 1;
+            return;
+        }
+        let frame = InFlight {
+            due_us: crate::timer::uptime_us() + self.config.latency_us,
+            data,
+        };
+        let reorder = !self.queues[to].is_empty() && self.chance(self.config.reorder_percent);
+        if reorder {
+            // Overtake the previous frame, arriving when it would have
+            let prev = self.queues[to].len() - 1;
+            let due_us = self.queues[to][prev].due_us;
+            self.queues[to].insert(prev, InFlight { due_us, ..frame });
+            self.stats.reordered += 1;
+        } else {
+            self.queues[to].push_back(frame);
+        }
+        if let Some(waker) = self.wakers[to].take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A simulated Ethernet link between two devices
+///
+/// Keep this handle to watch or change the link after its ends have been
+/// handed to embassy-net stacks.
+#[derive(Clone)]
+pub struct SimLink {
+    state: Arc<Mutex<RefCell<SimState>>>,
+}
+
+impl SimLink {
+    pub fn new(config: SimConfig) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(RefCell::new(SimState {
+                config,
+                // xorshift gets stuck at zero
+                rng: config.seed.max(1),
+                queues: [VecDeque::new(), VecDeque::new()],
+                wakers: [None, None],
+                stats: SimStats::default(),
+            }))),
+        }
+    }
+
+    /// The two ends, each a `Driver` for its own stack; what one sends the
+    /// other receives, after the link's impairments
+    pub fn ends(&self) -> (SimDevice, SimDevice) {
+        let end = |side: usize| SimDevice {
+            link: self.state.clone(),
+            side,
+            mac_addr: [0x02, 0x00, 0x00, 0x00, 0x51, side as u8 + 1],
+        };
+        (end(0), end(1))
+    }
+
+    /// Counters for both directions
+    pub fn stats(&self) -> SimStats {
+        critical_section::with(|cs| self.state.borrow(cs).borrow().stats)
+    }
+}
+
+/// One end of a `SimLink`
+pub struct SimDevice {
+    link: Arc<Mutex<RefCell<SimState>>>,
+    side: usize,
+    mac_addr: [u8; 6],
+}
+
+impl Driver for SimDevice {
+    type RxToken<'a>
+        = SimRxToken
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = SimTxToken<'a>
+    where
+        Self: 'a;
+
+    fn receive(
+        &mut self,
+        cx: &mut core::task::Context,
+    ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let frame = critical_section::with(|cs| {
+            let mut state = self.link.borrow(cs).borrow_mut();
+            let queue = &mut state.queues[self.side];
+            match queue.front() {
+                Some(front) if front.due_us <= crate::timer::uptime_us() => {
+                    queue.pop_front().map(|f| f.data)
+                }
+                Some(_) => {
+                    // Still in flight - there is no timer to wake us when it
+                    // lands, so ask to be polled again
+                    cx.waker().wake_by_ref();
+                    None
+                }
+                None => {
+                    state.wakers[self.side] = Some(cx.waker().clone());
+                    None
+                }
+            }
+        })?;
+        Some((
+            SimRxToken { data: frame },
+            SimTxToken {
+                link: &self.link,
+                side: self.side,
+            },
+        ))
+    }
+
+    fn transmit(&mut self, _cx: &mut core::task::Context) -> Option<Self::TxToken<'_>> {
+        // A full queue drops frames like a congested wire, so sending never waits
+        Some(SimTxToken {
+            link: &self.link,
+            side: self.side,
+        })
+    }
+
+    fn link_state(&mut self, _cx: &mut core::task::Context) -> LinkState {
+        LinkState::Up
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let mut caps = Capabilities::default();
+        caps.max_transmission_unit = MAX_PACKET_SIZE;
+        caps.max_burst_size = Some(SIM_QUEUE_SIZE);
+        caps
+    }
+
+    fn hardware_address(&self) -> HardwareAddress {
+        HardwareAddress::Ethernet(self.mac_addr)
+    }
+}
+
+/// A frame already taken off the wire
+pub struct SimRxToken {
+    data: Vec<u8>,
+}
+
+impl RxToken for SimRxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.data)
+    }
+}
+
+pub struct SimTxToken<'a> {
+    link: &'a Mutex<RefCell<SimState>>,
+    side: usize,
+}
+
+impl<'a> TxToken for SimTxToken<'a> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut data = match crate::allocator::try_zeroed_vec(len) {
+            Ok(data) => data,
+            Err(_) => {
+                // Out of memory - build the frame and lose it on the wire
+                let mut scratch = [0u8; MAX_PACKET_SIZE];
+                let len = len.min(MAX_PACKET_SIZE);
+                return f(&mut scratch[..len]);
+            }
+        };
+        let result = f(&mut data);
+        critical_section::with(|cs| self.link.borrow(cs).borrow_mut().send(self.side, data));
+        result
+    }
+}
+
 // ============================================================================
 // Helper Functions
 // ============================================================================
@@ -5,6 +5,7 @@
 
 use core::arch::asm;
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::Waker;
 
 use critical_section::Mutex;
@@ -17,6 +18,12 @@ const TICK_HZ: u64 = 1_000_000;
 /// Maximum number of concurrent wake requests
 const QUEUE_SIZE: usize = 8;
 
+/// `VIRTUAL_NOW` value meaning "use the hardware counter"
+const REAL_TIME: u64 = u64::MAX;
+
+/// Virtual clock in ticks, for deterministic replay (see `replay.rs`)
+static VIRTUAL_NOW: AtomicU64 = AtomicU64::new(REAL_TIME);
+
 struct ScheduledWake {
     at: u64,
     waker: Option<Waker>,
@@ -90,7 +97,10 @@ fn ticks_to_counter(ticks: u64) -> u64 {
 
 impl Driver for EmbassyTimeDriver {
     fn now(&self) -> u64 {
-        counter_to_ticks(read_counter())
+        match VIRTUAL_NOW.load(Ordering::Acquire) {
+            REAL_TIME => counter_to_ticks(read_counter()),
+            virtual_now => virtual_now,
+        }
     }
 
     fn schedule_wake(&self, at: u64, waker: &Waker) {
@@ -137,6 +147,9 @@ impl Driver for EmbassyTimeDriver {
 impl EmbassyTimeDriver {
     /// Update hardware timer to fire at the earliest scheduled wake time
     fn update_hardware_timer_locked(&self, queue: &[ScheduledWake; QUEUE_SIZE]) {
+        if VIRTUAL_NOW.load(Ordering::Acquire) != REAL_TIME {
+            return; // Alarms fire when the virtual clock is advanced
+        }
         let mut earliest = u64::MAX;
 
         for entry in queue.iter() {
@@ -159,6 +172,7 @@ impl EmbassyTimeDriver {
     /// Check and fire any expired wakers - call from timer interrupt
     pub fn check_alarms(&self) {
         let now = self.now();
+        let irq = crate::latency::take_irq_entry();
 
         critical_section::with(|cs| {
             let mut queue = self.queue.borrow(cs).borrow_mut();
@@ -166,6 +180,10 @@ impl EmbassyTimeDriver {
             for entry in queue.iter_mut() {
                 if entry.waker.is_some() && entry.at <= now {
                     if let Some(waker) = entry.waker.take() {
+                        // Virtual deadlines say nothing about real latency
+                        if VIRTUAL_NOW.load(Ordering::Relaxed) == REAL_TIME {
+                            crate::latency::record_alarm_wake(ticks_to_counter(entry.at), irq);
+                        }
                         waker.wake();
                     }
                     entry.at = u64::MAX;
@@ -176,6 +194,18 @@ impl EmbassyTimeDriver {
             self.update_hardware_timer_locked(&queue);
         });
     }
+
+    /// Wake and drop every scheduled alarm
+    fn wake_all(&self) {
+        critical_section::with(|cs| {
+            for entry in self.queue.borrow(cs).borrow_mut().iter_mut() {
+                if let Some(waker) = entry.waker.take() {
+                    waker.wake();
+                }
+                entry.at = u64::MAX;
+            }
+        });
+    }
 }
 
 /// Call this from your timer interrupt handler to check Embassy alarms
@@ -183,6 +213,50 @@ pub fn on_timer_interrupt() {
     DRIVER.check_alarms();
 }
 
+// ============================================================================
+// Virtual Time
+// ============================================================================
+
+/// Run embassy time from a virtual clock starting at `start` ticks (µs),
+/// or go back to the hardware counter with `None`
+///
+/// Pending alarms are woken either way (a spurious wake is always
+/// allowed), so their futures re-arm against the new clock. Only for
+/// replay while nothing else is waiting on embassy time.
+pub fn set_virtual_time(start: Option<u64>) {
+    VIRTUAL_NOW.store(start.unwrap_or(REAL_TIME), Ordering::Release);
+    DRIVER.wake_all();
+}
+
+/// Move the virtual clock forward to `now` and fire the alarms that are due
+pub fn advance_virtual_time(now: u64) {
+    let current = VIRTUAL_NOW.load(Ordering::Acquire);
+    if current != REAL_TIME && now > current {
+        VIRTUAL_NOW.store(now, Ordering::Release);
+    }
+    DRIVER.check_alarms();
+}
+
+/// Earliest scheduled alarm in ticks, if any
+pub fn next_alarm() -> Option<u64> {
+    critical_section::with(|cs| {
+        DRIVER
+            .queue
+            .borrow(cs)
+            .borrow()
+            .iter()
+            .filter(|entry| entry.waker.is_some())
+            .map(|entry| entry.at)
+            .min()
+    })
+}
+
+/// Wake `waker` in `delay_us` microseconds (for drivers that have to wait
+/// on time rather than on an interrupt)
+pub fn wake_after_us(delay_us: u64, waker: &Waker) {
+    DRIVER.schedule_wake(DRIVER.now().saturating_add(delay_us), waker);
+}
+
 /// Initialize the Embassy time driver
 /// Call this early in boot, before using any Embassy async functionality
 pub fn init() {
@@ -195,7 +269,7 @@ pub fn init() {
 
 // Implement critical-section for our bare metal environment
 // Using a nesting counter approach since RawRestoreState is ()
-use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use core::sync::atomic::AtomicU8;
 
 static CS_NESTING: AtomicU8 = AtomicU8::new(0);
 static CS_SAVED_DAIF: AtomicU64 = AtomicU64::new(0);
@@ -2,9 +2,13 @@
 //!
 //! Wraps the VirtioNetDevice to implement embassy_net_driver::Driver trait,
 //! enabling async networking with embassy-net.
+//!
+//! Receive is interrupt driven: the hard IRQ handler only acknowledges the
+//! device and raises the `NetRx` softirq, whose bottom half wakes the
+//! stack's RX waker so frames are processed in thread context.
 
-use alloc::boxed::Box;
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::Waker;
 
 use critical_section::Mutex;
@@ -12,7 +16,9 @@ use embassy_net_driver::{Capabilities, Driver, HardwareAddress, LinkState, RxTok
 use virtio_drivers::device::net::VirtIONetRaw;
 use virtio_drivers::transport::mmio::MmioTransport;
 
-use crate::virtio_hal::VirtioHal;
+use crate::allocator::{self, AlignedBuf, AllocError};
+use crate::softirq::{self, SoftIrq};
+use crate::virtio_hal::{self, VirtioHal};
 
 // ============================================================================
 // Constants
@@ -20,25 +26,39 @@ use crate::virtio_hal::VirtioHal;
 
 const VIRTIO_BUFFER_SIZE: usize = 2048;
 
+/// Interface name for per-interface settings (`net.shape.eth0.rate`)
+pub const IFACE_NAME: &str = "eth0";
+
+/// virtio-mmio InterruptStatus / InterruptACK register offsets
+const MMIO_INTERRUPT_STATUS: usize = 0x060;
+const MMIO_INTERRUPT_ACK: usize = 0x064;
+
+/// virtio-mmio Status register; writing 0 resets the device
+const MMIO_STATUS: usize = 0x070;
+
+/// Packet buffers own whole cache lines, so invalidating them after a
+/// device write can't discard neighbouring heap data
+const VIRTIO_BUFFER_ALIGN: usize = 64;
+
 // ============================================================================
 // RX Data Buffer
 // ============================================================================
 
 struct RxData {
-    buffer: Box<[u8; VIRTIO_BUFFER_SIZE]>,
+    buffer: AlignedBuf,
     offset: usize,
     len: usize,
     valid: bool,
 }
 
 impl RxData {
-    fn new() -> Self {
-        Self {
-            buffer: Box::new([0u8; VIRTIO_BUFFER_SIZE]),
+    fn new() -> Result<Self, AllocError> {
+        Ok(Self {
+            buffer: allocator::alloc_aligned(VIRTIO_BUFFER_SIZE, VIRTIO_BUFFER_ALIGN)?,
             offset: 0,
             len: 0,
             valid: false,
-        }
+        })
     }
 }
 
@@ -49,29 +69,26 @@ impl RxData {
 /// Embassy-compatible wrapper for virtio-net device
 pub struct EmbassyVirtioDriver {
     inner: VirtIONetRaw<VirtioHal, MmioTransport, 16>,
-    tx_buffer: Box<[u8; VIRTIO_BUFFER_SIZE]>,
+    tx_buffer: AlignedBuf,
     rx_pending_token: Option<u16>,
     rx_data: RefCell<RxData>,
     mac_addr: [u8; 6],
-    /// Waker to notify when RX data is available
-    rx_waker: Mutex<RefCell<Option<Waker>>>,
     /// Waker to notify when TX is ready
     tx_waker: Mutex<RefCell<Option<Waker>>>,
 }
 
 impl EmbassyVirtioDriver {
     /// Create a new Embassy virtio driver from a raw virtio-net device
-    pub fn new(inner: VirtIONetRaw<VirtioHal, MmioTransport, 16>) -> Self {
+    pub fn new(inner: VirtIONetRaw<VirtioHal, MmioTransport, 16>) -> Result<Self, AllocError> {
         let mac = inner.mac_address();
-        Self {
+        Ok(Self {
             inner,
-            tx_buffer: Box::new([0u8; VIRTIO_BUFFER_SIZE]),
+            tx_buffer: allocator::alloc_aligned(VIRTIO_BUFFER_SIZE, VIRTIO_BUFFER_ALIGN)?,
             rx_pending_token: None,
-            rx_data: RefCell::new(RxData::new()),
+            rx_data: RefCell::new(RxData::new()?),
             mac_addr: mac,
-            rx_waker: Mutex::new(RefCell::new(None)),
             tx_waker: Mutex::new(RefCell::new(None)),
-        }
+        })
     }
 
     /// Get the MAC address
@@ -128,15 +145,6 @@ impl EmbassyVirtioDriver {
         false
     }
 
-    /// Wake any pending RX waker
-    pub fn wake_rx(&self) {
-        critical_section::with(|cs| {
-            if let Some(waker) = self.rx_waker.borrow(cs).borrow_mut().take() {
-                waker.wake();
-            }
-        });
-    }
-
     /// Wake any pending TX waker
     pub fn wake_tx(&self) {
         critical_section::with(|cs| {
@@ -147,6 +155,57 @@ impl EmbassyVirtioDriver {
     }
 }
 
+// ============================================================================
+// RX Interrupt
+// ============================================================================
+
+/// Waker of the stack waiting for a frame; outside the driver so the
+/// bottom half can reach it
+static RX_WAKER: Mutex<RefCell<Option<Waker>>> = Mutex::new(RefCell::new(None));
+
+/// RX interrupts taken since boot
+static RX_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// `NetRx` bottom half: let the stack pull the new frames
+fn wake_rx() {
+    critical_section::with(|cs| {
+        if let Some(waker) = RX_WAKER.borrow(cs).borrow_mut().take() {
+            waker.wake();
+        }
+    });
+}
+
+/// Take the interrupt of the device in virtio-mmio `slot` at `addr`
+pub fn enable_rx_interrupt(slot: usize, addr: usize) {
+    softirq::register(SoftIrq::NetRx, wake_rx);
+    virtio_hal::register_irq(slot, move |_irq| {
+        // SAFETY: addr is the transport of the device this driver owns
+        unsafe {
+            let status = core::ptr::read_volatile((addr + MMIO_INTERRUPT_STATUS) as *const u32);
+            core::ptr::write_volatile((addr + MMIO_INTERRUPT_ACK) as *mut u32, status);
+        }
+        RX_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+        softirq::raise(SoftIrq::NetRx);
+    });
+}
+
+/// Stop the device in `slot` at `addr`: release its interrupt and reset
+/// it, so it no longer touches the queues of the driver about to be dropped
+pub fn reset_device(slot: usize, addr: usize) {
+    virtio_hal::unregister_irq(slot);
+    // SAFETY: addr is the transport of the device this driver owns
+    unsafe {
+        core::ptr::write_volatile((addr + MMIO_STATUS) as *mut u32, 0);
+    }
+    // A waker left behind would belong to the old stack
+    critical_section::with(|cs| RX_WAKER.borrow(cs).borrow_mut().take());
+}
+
+#[cfg(feature = "fs")]
+pub fn rx_interrupts() -> u64 {
+    RX_INTERRUPTS.load(Ordering::Relaxed)
+}
+
 // ============================================================================
 // Embassy Driver Implementation
 // ============================================================================
@@ -177,21 +236,23 @@ impl Driver for EmbassyVirtioDriver {
                 },
             ))
         } else {
-            // Store waker for later notification
+            // Store waker for the RX bottom half
             critical_section::with(|cs| {
-                self.rx_waker
-                    .borrow(cs)
-                    .borrow_mut()
-                    .replace(cx.waker().clone());
+                RX_WAKER.borrow(cs).borrow_mut().replace(cx.waker().clone());
             });
             None
         }
     }
 
     fn transmit(&mut self, cx: &mut core::task::Context) -> Option<Self::TxToken<'_>> {
-        // Virtio-net can always transmit (we don't track queue fullness for simplicity)
-        // In a real implementation, we'd check if the TX queue is full
-        let _ = cx; // Store waker if needed
+        // Virtio-net can always transmit (we don't track queue fullness for simplicity),
+        // but the egress shaper may hold frames back; ask to be polled again
+        // when it has room
+        let wait = crate::network::shaper_wait_us(IFACE_NAME);
+        if wait > 0 {
+            crate::embassy_time_driver::wake_after_us(wait, cx.waker());
+            return None;
+        }
         Some(VirtioTxToken { device: self })
     }
 
@@ -231,6 +292,7 @@ impl<'a> RxToken for VirtioRxToken<'a> {
         let data = &mut rx.buffer[offset..offset + len];
         let result = f(data);
         rx.valid = false;
+        crate::network::count_packet_rx();
         result
     }
 }
@@ -249,7 +311,11 @@ impl<'a> TxToken for VirtioTxToken<'a> {
         F: FnOnce(&mut [u8]) -> R,
     {
         let result = f(&mut self.device.tx_buffer[..len]);
+        // One descriptor per frame: the stack hands over whole frames, so
+        // vectored socket writes are gathered before they get here
         let _ = self.device.inner.send(&self.device.tx_buffer[..len]);
+        crate::network::count_packet_tx();
+        crate::network::shaper_charge(IFACE_NAME, len);
         result
     }
 }
@@ -0,0 +1,247 @@
+//! Kernel Error Type
+//!
+//! `KError` pairs an `ErrorKind` with optional static context. Every kind
+//! belongs to a subsystem and has a stable numeric code (subsystem in the
+//! high byte), so callers can branch on the kind while the shell and logs
+//! print something like `E0301 net: no device (virtio-net)`.
+
+use core::fmt;
+
+// ============================================================================
+// Subsystems
+// ============================================================================
+
+/// Kernel area an error originates from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    General = 0x00,
+    Memory = 0x01,
+    Thread = 0x02,
+    Net = 0x03,
+    Fs = 0x04,
+    Ssh = 0x05,
+    Device = 0x06,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 7] = [
+        Subsystem::General,
+        Subsystem::Memory,
+        Subsystem::Thread,
+        Subsystem::Net,
+        Subsystem::Fs,
+        Subsystem::Ssh,
+        Subsystem::Device,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Subsystem::General => "kernel",
+            Subsystem::Memory => "memory",
+            Subsystem::Thread => "thread",
+            Subsystem::Net => "net",
+            Subsystem::Fs => "fs",
+            Subsystem::Ssh => "ssh",
+            Subsystem::Device => "device",
+        }
+    }
+}
+
+// ============================================================================
+// Error Kinds
+// ============================================================================
+
+/// What went wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // General
+    InvalidArgument,
+    NotInitialized,
+    AlreadyInitialized,
+    Unsupported,
+    TimedOut,
+    LimitReached,
+
+    // Memory
+    OutOfMemory,
+    InvalidRegion,
+    Corrupted,
+
+    // Threading
+    NoFreeSlots,
+
+    // Networking
+    NoDevice,
+    AcceptFailed,
+    ConnectFailed,
+    ReadFailed,
+    WriteFailed,
+    FlushFailed,
+    ConnectionClosed,
+
+    // Filesystem
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    NoSpace,
+
+    // SSH
+    Protocol,
+    Crypto,
+    AuthFailed,
+
+    // Devices
+    DeviceInit,
+}
+
+impl ErrorKind {
+    /// Subsystem and index within it
+    fn parts(&self) -> (Subsystem, u8) {
+        use ErrorKind::*;
+        match self {
+            InvalidArgument => (Subsystem::General, 1),
+            NotInitialized => (Subsystem::General, 2),
+            AlreadyInitialized => (Subsystem::General, 3),
+            Unsupported => (Subsystem::General, 4),
+            TimedOut => (Subsystem::General, 5),
+            LimitReached => (Subsystem::General, 6),
+
+            OutOfMemory => (Subsystem::Memory, 1),
+            InvalidRegion => (Subsystem::Memory, 2),
+            Corrupted => (Subsystem::Memory, 3),
+
+            NoFreeSlots => (Subsystem::Thread, 1),
+
+            NoDevice => (Subsystem::Net, 1),
+            AcceptFailed => (Subsystem::Net, 2),
+            ConnectFailed => (Subsystem::Net, 3),
+            ReadFailed => (Subsystem::Net, 4),
+            WriteFailed => (Subsystem::Net, 5),
+            FlushFailed => (Subsystem::Net, 6),
+            ConnectionClosed => (Subsystem::Net, 7),
+
+            NotFound => (Subsystem::Fs, 1),
+            AlreadyExists => (Subsystem::Fs, 2),
+            NotADirectory => (Subsystem::Fs, 3),
+            IsADirectory => (Subsystem::Fs, 4),
+            NoSpace => (Subsystem::Fs, 5),
+
+            Protocol => (Subsystem::Ssh, 1),
+            Crypto => (Subsystem::Ssh, 2),
+            AuthFailed => (Subsystem::Ssh, 3),
+
+            DeviceInit => (Subsystem::Device, 1),
+        }
+    }
+
+    pub fn subsystem(&self) -> Subsystem {
+        self.parts().0
+    }
+
+    /// Stable numeric code: subsystem << 8 | index
+    pub fn code(&self) -> u16 {
+        let (subsystem, index) = self.parts();
+        (subsystem as u16) << 8 | index as u16
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        use ErrorKind::*;
+        match self {
+            InvalidArgument => "invalid argument",
+            NotInitialized => "not initialized",
+            AlreadyInitialized => "already initialized",
+            Unsupported => "unsupported",
+            TimedOut => "timed out",
+            LimitReached => "limit reached",
+            OutOfMemory => "out of memory",
+            InvalidRegion => "invalid memory region",
+            Corrupted => "metadata corrupted",
+            NoFreeSlots => "no free thread slots",
+            NoDevice => "no device",
+            AcceptFailed => "accept failed",
+            ConnectFailed => "connect failed",
+            ReadFailed => "read failed",
+            WriteFailed => "write failed",
+            FlushFailed => "flush failed",
+            ConnectionClosed => "connection closed",
+            NotFound => "not found",
+            AlreadyExists => "already exists",
+            NotADirectory => "not a directory",
+            IsADirectory => "is a directory",
+            NoSpace => "no space",
+            Protocol => "protocol error",
+            Crypto => "crypto failure",
+            AuthFailed => "authentication failed",
+            DeviceInit => "device init failed",
+        }
+    }
+}
+
+// ============================================================================
+// KError
+// ============================================================================
+
+/// Kernel error: a kind plus optional static context
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KError {
+    kind: ErrorKind,
+    context: Option<&'static str>,
+}
+
+pub type KResult<T> = Result<T, KError>;
+
+impl KError {
+    pub const fn new(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            context: None,
+        }
+    }
+
+    /// Attach a short description of what was being attempted
+    pub const fn with_context(kind: ErrorKind, context: &'static str) -> Self {
+        Self {
+            kind,
+            context: Some(context),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn subsystem(&self) -> Subsystem {
+        self.kind.subsystem()
+    }
+
+    pub fn code(&self) -> u16 {
+        self.kind.code()
+    }
+
+    pub fn context(&self) -> Option<&'static str> {
+        self.context
+    }
+}
+
+impl From<ErrorKind> for KError {
+    fn from(kind: ErrorKind) -> Self {
+        KError::new(kind)
+    }
+}
+
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "E{:04X} {}: {}",
+            self.code(),
+            self.subsystem().as_str(),
+            self.kind.as_str()
+        )?;
+        if let Some(context) = self.context {
+            write!(f, " ({})", context)?;
+        }
+        Ok(())
+    }
+}
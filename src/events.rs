@@ -0,0 +1,160 @@
+//! Kernel Event Catalog
+//!
+//! Stable numeric IDs for the kernel events worth alerting on. A record
+//! logged with `kevent!` carries its event, and sinks print the ID next to
+//! the text (`[E2001] ...`), so monitoring can match on `E2001` instead of
+//! wording that may change.
+//!
+//! IDs are grouped by thousands (1xxx boot, 2xxx auth, 3xxx memory,
+//! 4xxx scheduler, 5xxx crash). Once released an ID keeps its meaning:
+//! add new events, never renumber or reuse old ones.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::klog::Level;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Event {
+    /// Heap and page allocator are up
+    BootMemoryReady = 1001,
+    /// Exception vectors, GIC and timer are up
+    BootInterruptsReady = 1002,
+    /// Preemptive scheduling started
+    BootSchedulerReady = 1003,
+    /// Network stack initialized
+    BootNetworkReady = 1004,
+    /// Network stack failed to come up
+    BootNetworkFailed = 1005,
+    /// Servers are running
+    BootComplete = 1006,
+
+    /// Rejected password or public key signature
+    AuthFailure = 2001,
+    AuthSuccess = 2002,
+    /// Wrong password at an idle-lock prompt
+    UnlockFailure = 2003,
+
+    /// Heap allocation failed even after OOM callbacks ran
+    OutOfMemory = 3001,
+    /// The idle scrubber found damaged allocator metadata or a free
+    /// object written after it was freed
+    HeapCorruption = 3002,
+    /// Defragmentation hints ran (see `defrag`)
+    HeapDefrag = 3003,
+
+    /// A ready thread waited past the starvation threshold
+    Starvation = 4001,
+    /// A cooperative thread ran past its time limit
+    CoopTimeout = 4002,
+    /// A thread's stack canary was damaged
+    StackOverflow = 4003,
+    /// A thread was terminated with `threading::kill`
+    ThreadKilled = 4004,
+
+    /// A crash report from the previous boot was recovered
+    PreviousCrash = 5001,
+}
+
+impl Event {
+    pub const ALL: [Event; 17] = [
+        Event::BootMemoryReady,
+        Event::BootInterruptsReady,
+        Event::BootSchedulerReady,
+        Event::BootNetworkReady,
+        Event::BootNetworkFailed,
+        Event::BootComplete,
+        Event::AuthFailure,
+        Event::AuthSuccess,
+        Event::UnlockFailure,
+        Event::OutOfMemory,
+        Event::HeapCorruption,
+        Event::HeapDefrag,
+        Event::Starvation,
+        Event::CoopTimeout,
+        Event::StackOverflow,
+        Event::ThreadKilled,
+        Event::PreviousCrash,
+    ];
+
+    pub fn id(&self) -> u16 {
+        *self as u16
+    }
+
+    pub fn from_id(id: u16) -> Option<Event> {
+        Event::ALL.into_iter().find(|e| e.id() == id)
+    }
+
+    /// Dotted name, as stable as the ID
+    #[cfg(feature = "shell")]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::BootMemoryReady => "boot.memory_ready",
+            Event::BootInterruptsReady => "boot.interrupts_ready",
+            Event::BootSchedulerReady => "boot.scheduler_ready",
+            Event::BootNetworkReady => "boot.network_ready",
+            Event::BootNetworkFailed => "boot.network_failed",
+            Event::BootComplete => "boot.complete",
+            Event::AuthFailure => "auth.failure",
+            Event::AuthSuccess => "auth.success",
+            Event::UnlockFailure => "auth.unlock_failure",
+            Event::OutOfMemory => "mem.oom",
+            Event::HeapCorruption => "mem.corruption",
+            Event::HeapDefrag => "mem.defrag",
+            Event::Starvation => "sched.starvation",
+            Event::CoopTimeout => "sched.coop_timeout",
+            Event::StackOverflow => "sched.stack_overflow",
+            Event::ThreadKilled => "sched.thread_killed",
+            Event::PreviousCrash => "crash.previous",
+        }
+    }
+
+    /// Level the event is logged at
+    pub fn level(&self) -> Level {
+        match self {
+            Event::BootNetworkFailed
+            | Event::OutOfMemory
+            | Event::HeapCorruption
+            | Event::StackOverflow => Level::Error,
+            Event::AuthFailure
+            | Event::UnlockFailure
+            | Event::Starvation
+            | Event::CoopTimeout
+            | Event::ThreadKilled
+            | Event::PreviousCrash => Level::Warn,
+            _ => Level::Info,
+        }
+    }
+}
+
+/// Log an event with formatted text from the calling module
+#[macro_export]
+macro_rules! kevent {
+    ($event:expr, $($arg:tt)*) => {{
+        let event: $crate::events::Event = $event;
+        let module = $crate::klog::module_name(module_path!());
+        if $crate::klog::enabled(event.level(), module) {
+            $crate::klog::log_event_args(event, module, format_args!($($arg)*));
+        }
+    }};
+}
+
+// ============================================================================
+// Deferred Events
+// ============================================================================
+
+/// OOM count already reported
+static OOM_REPORTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Log events that can't be logged where they happen
+///
+/// The allocator can't log its own failures (logging allocates), so it
+/// only counts them; this reports new ones. Call from a thread context
+/// that runs regularly.
+pub fn poll() {
+    let oom = crate::allocator::oom_events();
+    let reported = OOM_REPORTED.swap(oom, Ordering::Relaxed);
+    if oom > reported {
+        crate::kevent!(Event::OutOfMemory, "[ALLOC] {} allocation(s) failed", oom - reported);
+    }
+}
@@ -1,57 +1,143 @@
 // ARM64 Exception handling
 
 use core::arch::global_asm;
+use core::fmt;
+
+use crate::backtrace::Backtrace;
 
 // Exception vector table
+//
+// The kernel only ever runs at EL1 on SP_EL1, so only the "current EL with
+// SPx" group has real handlers. Every other entry - and SError from
+// anywhere - means something went badly wrong (a bus error from a bad
+// MMIO access, a corrupted SPSel, a stray eret to a lower EL) and goes to
+// `report_handler`, which saves an ExceptionFrame and hands it to Rust
+// with the entry's index (group * 4 + kind) to be reported.
 global_asm!(
     r#"
 .section .text.exceptions
+
+.macro unexpected_vector index
+    .balign 0x80
+    sub sp, sp, #288
+    stp x0, x1, [sp, #0]
+    mov x1, #\index
+    b report_handler
+.endm
+
 .balign 0x800
 
 .global exception_vector_table
 exception_vector_table:
     // Current EL with SP0
-    .balign 0x80
-    b default_exception_handler   // Synchronous
-    .balign 0x80
-    b irq_handler                  // IRQ
-    .balign 0x80
-    b default_exception_handler   // FIQ
-    .balign 0x80
-    b default_exception_handler   // SError
+    unexpected_vector 0            // Synchronous
+    unexpected_vector 1            // IRQ
+    unexpected_vector 2            // FIQ
+    unexpected_vector 3            // SError
 
     // Current EL with SPx
     .balign 0x80
-    b default_exception_handler   // Synchronous
+    b sync_handler                 // Synchronous
     .balign 0x80
     b irq_handler                  // IRQ
     .balign 0x80
-    b default_exception_handler   // FIQ
-    .balign 0x80
-    b default_exception_handler   // SError
+    b fiq_handler                  // FIQ
+    unexpected_vector 7            // SError
 
     // Lower EL using AArch64
-    .balign 0x80
-    b default_exception_handler   // Synchronous
-    .balign 0x80
-    b irq_handler                  // IRQ
-    .balign 0x80
-    b default_exception_handler   // FIQ
-    .balign 0x80
-    b default_exception_handler   // SError
+    unexpected_vector 8            // Synchronous
+    unexpected_vector 9            // IRQ
+    unexpected_vector 10           // FIQ
+    unexpected_vector 11           // SError
 
     // Lower EL using AArch32
-    .balign 0x80
-    b default_exception_handler   // Synchronous
-    .balign 0x80
-    b irq_handler                  // IRQ
-    .balign 0x80
-    b default_exception_handler   // FIQ
-    .balign 0x80
-    b default_exception_handler   // SError
+    unexpected_vector 12           // Synchronous
+    unexpected_vector 13           // IRQ
+    unexpected_vector 14           // FIQ
+    unexpected_vector 15           // SError
+
+// Unexpected exception - x0/x1 are already saved and x1 holds the vector
+// index; finishes the ExceptionFrame and reports it. Never returns.
+report_handler:
+    stp x2, x3, [sp, #16]
+    stp x4, x5, [sp, #32]
+    stp x6, x7, [sp, #48]
+    stp x8, x9, [sp, #64]
+    stp x10, x11, [sp, #80]
+    stp x12, x13, [sp, #96]
+    stp x14, x15, [sp, #112]
+    stp x16, x17, [sp, #128]
+    stp x18, x19, [sp, #144]
+    stp x20, x21, [sp, #160]
+    stp x22, x23, [sp, #176]
+    stp x24, x25, [sp, #192]
+    stp x26, x27, [sp, #208]
+    stp x28, x29, [sp, #224]
+    str x30, [sp, #240]
+    mrs x9, elr_el1
+    mrs x10, spsr_el1
+    stp x9, x10, [sp, #248]
+    mrs x9, esr_el1
+    mrs x10, far_el1
+    stp x9, x10, [sp, #264]
+
+    mov x0, sp
+    bl rust_unexpected_exception
+1:  wfe
+    b 1b
+
+// Synchronous exception handler - builds an ExceptionFrame (x0-x30, ELR,
+// SPSR, ESR, FAR) on the stack and passes it to Rust; ELR and SPSR are
+// reloaded from the frame so the handler can change where execution resumes
+sync_handler:
+    sub sp, sp, #288
+    stp x0, x1, [sp, #0]
+    stp x2, x3, [sp, #16]
+    stp x4, x5, [sp, #32]
+    stp x6, x7, [sp, #48]
+    stp x8, x9, [sp, #64]
+    stp x10, x11, [sp, #80]
+    stp x12, x13, [sp, #96]
+    stp x14, x15, [sp, #112]
+    stp x16, x17, [sp, #128]
+    stp x18, x19, [sp, #144]
+    stp x20, x21, [sp, #160]
+    stp x22, x23, [sp, #176]
+    stp x24, x25, [sp, #192]
+    stp x26, x27, [sp, #208]
+    stp x28, x29, [sp, #224]
+    str x30, [sp, #240]
+    mrs x9, elr_el1
+    mrs x10, spsr_el1
+    stp x9, x10, [sp, #248]
+    mrs x9, esr_el1
+    mrs x10, far_el1
+    stp x9, x10, [sp, #264]
+
+    mov x0, sp
+    bl rust_sync_handler
+
+    ldp x9, x10, [sp, #248]
+    msr elr_el1, x9
+    msr spsr_el1, x10
+    ldp x0, x1, [sp, #0]
+    ldp x2, x3, [sp, #16]
+    ldp x4, x5, [sp, #32]
+    ldp x6, x7, [sp, #48]
+    ldp x8, x9, [sp, #64]
+    ldp x10, x11, [sp, #80]
+    ldp x12, x13, [sp, #96]
+    ldp x14, x15, [sp, #112]
+    ldp x16, x17, [sp, #128]
+    ldp x18, x19, [sp, #144]
+    ldp x20, x21, [sp, #160]
+    ldp x22, x23, [sp, #176]
+    ldp x24, x25, [sp, #192]
+    ldp x26, x27, [sp, #208]
+    ldp x28, x29, [sp, #224]
+    ldr x30, [sp, #240]
+    add sp, sp, #288
 
-// Default exception handler - just returns
-default_exception_handler:
     eret
 
 // IRQ handler - saves context and calls Rust handler
@@ -74,9 +160,25 @@ irq_handler:
     stp x28, x29, [sp, #-16]!
     str x30, [sp, #-16]!
 
-    // Call Rust IRQ handler
+    // Save the exception return state: handlers run with IRQs unmasked,
+    // and a nested IRQ overwrites ELR_EL1/SPSR_EL1
+    mrs x9, elr_el1
+    mrs x10, spsr_el1
+    stp x9, x10, [sp, #-16]!
+
+    // The FIQ may preempt IRQ handlers now that ELR/SPSR are safe
+    msr daifclr, #1
+
+    // Call Rust IRQ handler with the saved state
+    mov x0, sp
     bl rust_irq_handler
 
+    // Restore the exception return state with IRQs and FIQs masked again
+    msr daifset, #1
+    ldp x9, x10, [sp], #16
+    msr elr_el1, x9
+    msr spsr_el1, x10
+
     // Restore all registers
     ldr x30, [sp], #16
     ldp x28, x29, [sp], #16
@@ -95,6 +197,35 @@ irq_handler:
     ldp x2, x3, [sp], #16
     ldp x0, x1, [sp], #16
 
+    eret
+
+// FIQ handler - saves the caller-saved registers and calls Rust. FIQs and
+// IRQs stay masked throughout, so nothing nests inside it
+fiq_handler:
+    stp x0, x1, [sp, #-16]!
+    stp x2, x3, [sp, #-16]!
+    stp x4, x5, [sp, #-16]!
+    stp x6, x7, [sp, #-16]!
+    stp x8, x9, [sp, #-16]!
+    stp x10, x11, [sp, #-16]!
+    stp x12, x13, [sp, #-16]!
+    stp x14, x15, [sp, #-16]!
+    stp x16, x17, [sp, #-16]!
+    stp x18, x30, [sp, #-16]!
+
+    bl rust_fiq_handler
+
+    ldp x18, x30, [sp], #16
+    ldp x16, x17, [sp], #16
+    ldp x14, x15, [sp], #16
+    ldp x12, x13, [sp], #16
+    ldp x10, x11, [sp], #16
+    ldp x8, x9, [sp], #16
+    ldp x6, x7, [sp], #16
+    ldp x4, x5, [sp], #16
+    ldp x2, x3, [sp], #16
+    ldp x0, x1, [sp], #16
+
     eret
 "#
 );
@@ -115,26 +246,414 @@ pub fn init() {
             vbar = in(reg) vbar
         );
 
-        // Enable IRQs by clearing the I bit in DAIF
+        // Enable SErrors, IRQs and FIQs by clearing the A, I and F bits in
+        // DAIF; a masked SError would stay pending and never be reported
         core::arch::asm!(
-            "msr daifclr, #2" // Clear IRQ mask (bit 1)
+            "msr daifclr, #7" // Clear SError (bit 2), IRQ (bit 1) and FIQ (bit 0) masks
         );
     }
 }
 
+/// Start of what `irq_handler` pushed, lowest address first (the other
+/// registers follow above, in pairs down from x26/x27)
+#[repr(C)]
+pub struct IrqFrame {
+    pub elr: u64,
+    pub spsr: u64,
+    pub x30: u64,
+    _pad: u64,
+    pub x28: u64,
+    pub x29: u64,
+}
+
 /// Rust IRQ handler called from assembly
 #[unsafe(no_mangle)]
-extern "C" fn rust_irq_handler() {
+extern "C" fn rust_irq_handler(frame: &IrqFrame) {
+    crate::latency::irq_entered();
+
     // Acknowledge the interrupt and get IRQ number
     if let Some(irq) = crate::gic::acknowledge_irq() {
         // Special handling for scheduler SGI
         if irq == crate::gic::SGI_SCHEDULER {
             // SGI handler calls EOI itself before context switching
-            crate::threading::sgi_scheduler_handler(irq);
+            crate::irq::record_fire(irq, 0);
+            crate::threading::sgi_scheduler_handler(irq, frame);
         } else {
             // Normal IRQs: call handler then EOI
             crate::irq::dispatch_irq(irq);
             crate::gic::end_of_interrupt(irq);
         }
+    } else {
+        crate::irq::record_spurious();
+    }
+}
+
+/// Rust FIQ handler called from assembly
+#[unsafe(no_mangle)]
+extern "C" fn rust_fiq_handler() {
+    crate::irq::dispatch_fiq();
+}
+
+// ============================================================================
+// Exception Syndrome Decoding
+// ============================================================================
+
+// Exception classes (ESR_EL1.EC) the kernel can run into
+pub const EC_UNKNOWN: u8 = 0x00;
+pub const EC_WFI_WFE: u8 = 0x01;
+pub const EC_FP_ACCESS: u8 = 0x07;
+pub const EC_ILLEGAL_STATE: u8 = 0x0e;
+pub const EC_SVC64: u8 = 0x15;
+pub const EC_SYSREG: u8 = 0x18;
+pub const EC_INSTRUCTION_ABORT_LOWER: u8 = 0x20;
+pub const EC_INSTRUCTION_ABORT: u8 = 0x21;
+pub const EC_PC_ALIGNMENT: u8 = 0x22;
+pub const EC_DATA_ABORT_LOWER: u8 = 0x24;
+pub const EC_DATA_ABORT: u8 = 0x25;
+pub const EC_SP_ALIGNMENT: u8 = 0x26;
+pub const EC_FP_EXCEPTION: u8 = 0x2c;
+pub const EC_SERROR: u8 = 0x2f;
+pub const EC_BREAKPOINT_LOWER: u8 = 0x30;
+pub const EC_BREAKPOINT: u8 = 0x31;
+pub const EC_STEP_LOWER: u8 = 0x32;
+pub const EC_STEP: u8 = 0x33;
+pub const EC_WATCHPOINT_LOWER: u8 = 0x34;
+pub const EC_WATCHPOINT: u8 = 0x35;
+pub const EC_BRK64: u8 = 0x3c;
+
+/// A raw ESR_EL1 value, displayed as a human-readable description
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Esr(pub u64);
+
+impl Esr {
+    /// Exception class
+    pub fn ec(&self) -> u8 {
+        ((self.0 >> 26) & 0x3f) as u8
+    }
+
+    /// Instruction-specific syndrome
+    pub fn iss(&self) -> u32 {
+        (self.0 & 0x1ff_ffff) as u32
+    }
+
+    pub fn is_data_abort(&self) -> bool {
+        matches!(self.ec(), EC_DATA_ABORT | EC_DATA_ABORT_LOWER)
+    }
+
+    pub fn is_instruction_abort(&self) -> bool {
+        matches!(self.ec(), EC_INSTRUCTION_ABORT | EC_INSTRUCTION_ABORT_LOWER)
+    }
+
+    /// FAR_EL1 holds the faulting address for this exception
+    pub fn far_valid(&self) -> bool {
+        let iss = self.iss();
+        match self.ec() {
+            // FnV: FAR is not valid
+            _ if self.is_data_abort() || self.is_instruction_abort() => iss & (1 << 10) == 0,
+            EC_PC_ALIGNMENT | EC_WATCHPOINT | EC_WATCHPOINT_LOWER => true,
+            _ => false,
+        }
+    }
+
+    /// BRK: execution can continue after the instruction
+    pub fn is_breakpoint(&self) -> bool {
+        self.ec() == EC_BRK64
+    }
+
+    /// Short name of the exception class
+    pub fn class_name(&self) -> &'static str {
+        match self.ec() {
+            EC_UNKNOWN => "Unknown reason",
+            EC_WFI_WFE => "Trapped WFI/WFE",
+            EC_FP_ACCESS => "Trapped FP/SIMD access",
+            EC_ILLEGAL_STATE => "Illegal execution state",
+            EC_SVC64 => "SVC",
+            EC_SYSREG => "Trapped system register access",
+            EC_INSTRUCTION_ABORT_LOWER => "Instruction abort (lower EL)",
+            EC_INSTRUCTION_ABORT => "Instruction abort",
+            EC_PC_ALIGNMENT => "PC alignment fault",
+            EC_DATA_ABORT_LOWER => "Data abort (lower EL)",
+            EC_DATA_ABORT => "Data abort",
+            EC_SP_ALIGNMENT => "SP alignment fault",
+            EC_FP_EXCEPTION => "Floating-point exception",
+            EC_SERROR => "SError",
+            EC_BREAKPOINT_LOWER | EC_BREAKPOINT => "Hardware breakpoint",
+            EC_STEP_LOWER | EC_STEP => "Software step",
+            EC_WATCHPOINT_LOWER | EC_WATCHPOINT => "Watchpoint",
+            EC_BRK64 => "BRK",
+            _ => "Unhandled exception class",
+        }
+    }
+}
+
+/// Describe a data/instruction fault status code (DFSC/IFSC)
+fn fault_status(code: u32) -> (&'static str, Option<u32>) {
+    let level = Some(code & 0x3);
+    match code {
+        0b000000..=0b000011 => ("address size fault", level),
+        0b000100..=0b000111 => ("translation fault", level),
+        0b001001..=0b001011 => ("access flag fault", level),
+        0b001101..=0b001111 => ("permission fault", level),
+        0b010000 => ("synchronous external abort", None),
+        0b010100..=0b010111 => ("external abort on table walk", level),
+        0b011000 => ("parity/ECC error", None),
+        0b100001 => ("alignment fault", None),
+        0b110000 => ("TLB conflict abort", None),
+        0b110001 => ("unsupported atomic hardware update", None),
+        _ => ("unknown fault", None),
+    }
+}
+
+impl fmt::Display for Esr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let iss = self.iss();
+        write!(f, "{}", self.class_name())?;
+        match self.ec() {
+            EC_SVC64 | EC_BRK64 => write!(f, " #{:#x}", iss & 0xffff),
+            EC_SYSREG => {
+                let op0 = (iss >> 20) & 0x3;
+                let op2 = (iss >> 17) & 0x7;
+                let op1 = (iss >> 14) & 0x7;
+                let crn = (iss >> 10) & 0xf;
+                let rt = (iss >> 5) & 0x1f;
+                let crm = (iss >> 1) & 0xf;
+                let dir = if iss & 1 != 0 { "read (MRS)" } else { "write (MSR)" };
+                write!(
+                    f,
+                    ": {} S{}_{}_C{}_C{}_{} with x{}",
+                    dir, op0, op1, crn, crm, op2, rt
+                )
+            }
+            _ if self.is_data_abort() || self.is_instruction_abort() => {
+                let (what, level) = fault_status(iss & 0x3f);
+                write!(f, ": {}", what)?;
+                if let Some(level) = level {
+                    write!(f, ", level {}", level)?;
+                }
+                if self.is_data_abort() {
+                    let access = if iss & (1 << 8) != 0 {
+                        "cache maintenance"
+                    } else if iss & (1 << 6) != 0 {
+                        "write"
+                    } else {
+                        "read"
+                    };
+                    write!(f, ", {}", access)?;
+                    // ISV: access size and register are known
+                    if iss & (1 << 24) != 0 {
+                        let size = 1 << ((iss >> 22) & 0x3);
+                        let reg = (iss >> 16) & 0x1f;
+                        let width = if iss & (1 << 15) != 0 { 'x' } else { 'w' };
+                        write!(f, " of {} bytes via {}{}", size, width, reg)?;
+                    }
+                }
+                if iss & (1 << 7) != 0 {
+                    write!(f, " during stage 1 table walk")?;
+                }
+                if iss & (1 << 9) != 0 {
+                    write!(f, " (external)")?;
+                }
+                Ok(())
+            }
+            EC_SERROR => {
+                // IDS: the syndrome is implementation defined
+                if iss & (1 << 24) != 0 {
+                    return write!(f, ": implementation defined syndrome {:#x}", iss & 0xff_ffff);
+                }
+                let what = match iss & 0x3f {
+                    0b000000 => "uncategorized",
+                    0b010001 => "asynchronous SError",
+                    _ => "unknown fault",
+                };
+                let severity = match (iss >> 10) & 0x7 {
+                    0b000 => "uncontainable",
+                    0b001 => "unrecoverable",
+                    0b010 => "restartable",
+                    0b011 => "recoverable",
+                    0b110 => "corrected",
+                    _ => "reserved severity",
+                };
+                write!(f, ": {}, {}", what, severity)?;
+                if iss & (1 << 9) != 0 {
+                    write!(f, " (external)")?;
+                }
+                Ok(())
+            }
+            EC_UNKNOWN | EC_ILLEGAL_STATE | EC_PC_ALIGNMENT | EC_SP_ALIGNMENT => Ok(()),
+            _ => write!(f, " (EC {:#04x}, ISS {:#x})", self.ec(), iss),
+        }
+    }
+}
+
+// ============================================================================
+// Fault Reports
+// ============================================================================
+
+/// Registers saved by `sync_handler`, lowest address first
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExceptionFrame {
+    pub x: [u64; 31],
+    pub elr: u64,
+    pub spsr: u64,
+    pub esr: u64,
+    pub far: u64,
+    _pad: u64,
+}
+
+/// Bytes `sync_handler` reserves below the interrupted stack pointer
+const FRAME_SIZE: u64 = core::mem::size_of::<ExceptionFrame>() as u64;
+
+const _: () = assert!(FRAME_SIZE == 288);
+
+/// A fatal exception, displayed as the syndrome plus a register dump
+pub struct FaultReport<'a> {
+    pub frame: &'a ExceptionFrame,
+    /// Stack pointer when the exception was taken
+    pub sp: u64,
+    /// Faulting thread, None if the scheduler lock was held
+    pub thread: Option<usize>,
+}
+
+impl fmt::Display for FaultReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = self.frame;
+        let esr = Esr(frame.esr);
+        write!(f, "{} at {:#x}", esr, frame.elr)?;
+        if esr.far_valid() {
+            write!(f, ", address {:#x}", frame.far)?;
+        }
+        writeln!(f)?;
+        match self.thread {
+            Some(tid) => write!(f, "Thread: {}", tid)?,
+            None => write!(f, "Thread: ?")?,
+        }
+        writeln!(
+            f,
+            ", ESR {:#010x}, FAR {:#018x}, SPSR {:#010x}, SP {:#018x}",
+            frame.esr, frame.far, frame.spsr, self.sp
+        )?;
+        for (i, chunk) in frame.x.chunks(4).enumerate() {
+            for (j, value) in chunk.iter().enumerate() {
+                write!(f, "{}x{:<2} {:#018x}", if j == 0 { "" } else { "  " }, i * 4 + j, value)?;
+            }
+            writeln!(f)?;
+        }
+        let backtrace = Backtrace::from_frame(Some(frame.elr as usize), frame.x[29] as usize);
+        write!(f, "Backtrace:\n{}", backtrace)
+    }
+}
+
+/// Rust synchronous exception handler called from assembly
+///
+/// BRK is logged and skipped so it can be used as a debugging trap point;
+/// anything else is a kernel bug and panics with the decoded syndrome and
+/// the saved registers, so they end up in the crash report.
+#[unsafe(no_mangle)]
+extern "C" fn rust_sync_handler(frame: &mut ExceptionFrame) {
+    let esr = Esr(frame.esr);
+    if esr.is_breakpoint() {
+        let elr = frame.elr;
+        crate::irq::as_handler(|| crate::kwarn!("[Exception] {} at {:#x}", esr, elr));
+        // BRK is always 4 bytes; eret resumes at the next instruction
+        frame.elr += 4;
+        return;
+    }
+    if crate::probe::handle_abort(frame) {
+        return;
+    }
+
+    let report = FaultReport {
+        sp: frame as *const ExceptionFrame as u64 + FRAME_SIZE,
+        thread: crate::threading::try_current_thread_id(),
+        frame,
+    };
+    panic!("{}", report);
+}
+
+// ============================================================================
+// Unexpected Exceptions
+// ============================================================================
+
+/// An entry of the exception vector table, by index (group * 4 + kind)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vector(pub u8);
+
+impl Vector {
+    /// What was taken: synchronous exception, IRQ, FIQ or SError
+    pub fn kind(&self) -> &'static str {
+        match self.0 & 0x3 {
+            0 => "Synchronous exception",
+            1 => "IRQ",
+            2 => "FIQ",
+            _ => "SError",
+        }
+    }
+
+    /// Where it was taken from
+    pub fn source(&self) -> &'static str {
+        match (self.0 >> 2) & 0x3 {
+            0 => "current EL (SP0)",
+            1 => "current EL (SPx)",
+            2 => "lower EL (AArch64)",
+            _ => "lower EL (AArch32)",
+        }
+    }
+
+    /// ESR_EL1 describes this exception (otherwise it is left over from
+    /// an earlier one)
+    pub fn has_syndrome(&self) -> bool {
+        matches!(self.0 & 0x3, 0 | 3)
+    }
+}
+
+impl fmt::Display for Vector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} from {}", self.kind(), self.source())
+    }
+}
+
+/// Exception level and stack pointer the interrupted code ran with, from
+/// SPSR.M (e.g. "EL1h" = EL1 on SP_EL1)
+pub fn spsr_mode(spsr: u64) -> &'static str {
+    if spsr & (1 << 4) != 0 {
+        return "AArch32";
+    }
+    match spsr & 0xf {
+        0b0000 => "EL0t",
+        0b0100 => "EL1t",
+        0b0101 => "EL1h",
+        0b1000 => "EL2t",
+        0b1001 => "EL2h",
+        _ => "illegal mode",
+    }
+}
+
+/// Rust handler of the vector entries the kernel never expects, called
+/// from `report_handler`
+///
+/// Panics with the vector, the mode the interrupted code ran in and the
+/// saved registers - with the decoded syndrome for SErrors and
+/// synchronous exceptions - so bus errors from bad MMIO accesses show up
+/// in the crash report instead of hanging the machine.
+#[unsafe(no_mangle)]
+extern "C" fn rust_unexpected_exception(frame: &ExceptionFrame, vector: u64) -> ! {
+    let vector = Vector(vector as u8);
+    let sp = frame as *const ExceptionFrame as u64 + FRAME_SIZE;
+    if vector.has_syndrome() {
+        let report = FaultReport {
+            sp,
+            thread: crate::threading::try_current_thread_id(),
+            frame,
+        };
+        panic!("Unexpected {} in {}: {}", vector, spsr_mode(frame.spsr), report);
     }
+    panic!(
+        "Unexpected {} in {} at {:#x}, SPSR {:#010x}, SP {:#018x}",
+        vector,
+        spsr_mode(frame.spsr),
+        frame.elr,
+        frame.spsr,
+        sp
+    );
 }
@@ -7,8 +7,6 @@ use core::cell::UnsafeCell;
 use core::sync::atomic::{AtomicBool, Ordering};
 use embassy_executor::{Spawner, raw};
 
-use crate::console;
-
 // ============================================================================
 // Executor Storage
 // ============================================================================
@@ -62,7 +60,7 @@ static EXECUTOR: ExecutorStorage = ExecutorStorage::new();
 /// Must be called once before any async tasks can be spawned
 pub fn init() {
     if EXECUTOR.init() {
-        console::print("[Executor] Embassy executor initialized\n");
+        crate::kinfo!("[Executor] Embassy executor initialized");
     }
 }
 
@@ -127,46 +125,3 @@ pub fn on_timer_tick() {
 pub fn has_tasks() -> bool {
     EXECUTOR.get().is_some()
 }
-
-// ============================================================================
-// IRQ Work Queue (kept for interrupt -> task communication)
-// ============================================================================
-
-use alloc::vec::Vec;
-use spinning_top::Spinlock;
-
-/// Types of work that can be queued from interrupt context
-pub enum IrqWork {
-    /// Signal that the executor should poll
-    RunExecutorOnce,
-    /// Wake a specific task (by signaling via waker)
-    WakeTask,
-}
-
-static IRQ_WORK_QUEUE: Spinlock<Vec<IrqWork>> = Spinlock::new(Vec::new());
-
-/// Queue work from IRQ context (safe to call from interrupts)
-pub fn queue_irq_work(work: IrqWork) {
-    if let Some(mut queue) = IRQ_WORK_QUEUE.try_lock() {
-        queue.push(work);
-    }
-}
-
-/// Process pending IRQ work (call from main loop)
-pub fn process_irq_work() {
-    let work_items = {
-        if let Some(mut queue) = IRQ_WORK_QUEUE.try_lock() {
-            core::mem::take(&mut *queue)
-        } else {
-            return;
-        }
-    };
-
-    for work in work_items {
-        match work {
-            IrqWork::RunExecutorOnce | IrqWork::WakeTask => {
-                run_once();
-            }
-        }
-    }
-}
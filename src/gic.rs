@@ -2,6 +2,9 @@
 // For QEMU ARM virt machine
 
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::{ErrorKind, KError, KResult};
 
 // GIC distributor base address for QEMU virt machine
 const GICD_BASE: usize = 0x0800_0000;
@@ -10,6 +13,8 @@ const GICC_BASE: usize = 0x0801_0000;
 
 // GIC Distributor registers
 const GICD_CTLR: usize = GICD_BASE + 0x000; // Control Register
+const GICD_TYPER: usize = GICD_BASE + 0x004; // Interrupt Controller Type Register
+const GICD_IGROUPR: usize = GICD_BASE + 0x080; // Interrupt Group Registers
 const GICD_ISENABLER: usize = GICD_BASE + 0x100; // Interrupt Set-Enable Registers
 const GICD_ICENABLER: usize = GICD_BASE + 0x180; // Interrupt Clear-Enable Registers
 const GICD_IPRIORITYR: usize = GICD_BASE + 0x400; // Interrupt Priority Registers
@@ -19,14 +24,46 @@ const GICD_SGIR: usize = GICD_BASE + 0xF00; // Software Generated Interrupt Regi
 // GIC CPU Interface registers
 const GICC_CTLR: usize = GICC_BASE + 0x000; // CPU Interface Control Register
 const GICC_PMR: usize = GICC_BASE + 0x004; // Interrupt Priority Mask Register
+const GICC_BPR: usize = GICC_BASE + 0x008; // Binary Point Register
 const GICC_IAR: usize = GICC_BASE + 0x00C; // Interrupt Acknowledge Register
 const GICC_EOIR: usize = GICC_BASE + 0x010; // End of Interrupt Register
+const GICC_RPR: usize = GICC_BASE + 0x014; // Running Priority Register
+
+// GICD_CTLR bits
+const GICD_CTLR_ENABLE_GRP0: u32 = 1 << 0;
+const GICD_CTLR_ENABLE_GRP1: u32 = 1 << 1;
+
+// GICC_CTLR bits
+const GICC_CTLR_ENABLE_GRP0: u32 = 1 << 0;
+const GICC_CTLR_ENABLE_GRP1: u32 = 1 << 1;
+const GICC_CTLR_ACK_CTL: u32 = 1 << 2; // IAR acknowledges Group 1 too
+const GICC_CTLR_FIQ_EN: u32 = 1 << 3; // Signal Group 0 as FIQ
+const GICC_CTLR_CBPR: u32 = 1 << 4; // BPR controls both groups
+
+// First Shared Peripheral Interrupt; 0-15 are SGIs, 16-31 per-CPU PPIs
+pub const FIRST_SPI: u32 = 32;
 
 // SGI numbers (0-15)
 pub const SGI_SCHEDULER: u32 = 0; // SGI 0 for scheduling
 
+// Interrupt priorities (0 = most urgent). An active interrupt can only be
+// preempted by one with a numerically lower priority.
+pub const PRIORITY_FIQ: u8 = 0x00; // The Group 0 interrupt delivered as FIQ
+pub const PRIORITY_TIMER: u8 = 0x40; // Scheduler tick, preempts device handlers
+pub const PRIORITY_DEFAULT: u8 = 0xA0; // Device interrupts
+pub const PRIORITY_SCHEDULER: u8 = 0xE0; // Scheduler SGI, only runs once no handler is active
+
+/// Set once `init` has programmed the distributor and CPU interface
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
 /// Initialize the GIC
-pub fn init() {
+///
+/// Only once: a second call would disable every line drivers have
+/// enabled since, so it fails with `AlreadyInitialized` instead.
+pub fn init() -> KResult<()> {
+    if INITIALIZED.swap(true, Ordering::AcqRel) {
+        return Err(KError::with_context(ErrorKind::AlreadyInitialized, "gic"));
+    }
     unsafe {
         // Disable distributor
         write_volatile(GICD_CTLR as *mut u32, 0);
@@ -36,9 +73,10 @@ pub fn init() {
             write_volatile((GICD_ICENABLER + i * 4) as *mut u32, 0xFFFF_FFFF);
         }
 
-        // Set all interrupts to lowest priority
+        // Set all interrupts to the device priority
+        let default = u32::from_ne_bytes([PRIORITY_DEFAULT; 4]);
         for i in 0..256 {
-            write_volatile((GICD_IPRIORITYR + i * 4) as *mut u32, 0xA0A0_A0A0);
+            write_volatile((GICD_IPRIORITYR + i * 4) as *mut u32, default);
         }
 
         // Route all interrupts to CPU 0
@@ -46,16 +84,37 @@ pub fn init() {
             write_volatile((GICD_ITARGETSR + i * 4) as *mut u32, 0x0101_0101);
         }
 
+        // Everything is Group 1 (IRQ); Group 0 is reserved for the FIQ
+        for i in 0..32 {
+            write_volatile((GICD_IGROUPR + i * 4) as *mut u32, 0xFFFF_FFFF);
+        }
+
         // Enable distributor
-        write_volatile(GICD_CTLR as *mut u32, 1);
+        write_volatile(
+            GICD_CTLR as *mut u32,
+            GICD_CTLR_ENABLE_GRP0 | GICD_CTLR_ENABLE_GRP1,
+        );
 
         // Configure CPU interface
         // Set priority mask to allow all interrupts
         write_volatile(GICC_PMR as *mut u32, 0xFF);
 
-        // Enable CPU interface
-        write_volatile(GICC_CTLR as *mut u32, 1);
+        // Use every implemented priority bit for preemption (the GIC
+        // clamps this to its minimum binary point)
+        write_volatile(GICC_BPR as *mut u32, 0);
+
+        // Enable CPU interface: Group 0 as FIQ, Group 1 as IRQ, one IAR
+        // for both
+        write_volatile(
+            GICC_CTLR as *mut u32,
+            GICC_CTLR_ENABLE_GRP0
+                | GICC_CTLR_ENABLE_GRP1
+                | GICC_CTLR_ACK_CTL
+                | GICC_CTLR_FIQ_EN
+                | GICC_CTLR_CBPR,
+        );
     }
+    Ok(())
 }
 
 /// Enable a specific IRQ
@@ -133,3 +192,99 @@ pub fn set_priority(irq: u32, priority: u8) {
         write_volatile(reg, priority);
     }
 }
+
+/// Deliver an interrupt as FIQ (Group 0) or as IRQ (Group 1)
+pub fn set_fiq(irq: u32, fiq: bool) {
+    if irq >= 1020 {
+        return;
+    }
+
+    unsafe {
+        let reg = (GICD_IGROUPR + ((irq / 32) * 4) as usize) as *mut u32;
+        let bit = 1u32 << (irq % 32);
+        let groups = read_volatile(reg);
+        write_volatile(reg, if fiq { groups & !bit } else { groups | bit });
+    }
+}
+
+/// True if the interrupt is delivered as FIQ
+pub fn is_fiq(irq: u32) -> bool {
+    if irq >= 1020 {
+        return false;
+    }
+
+    unsafe {
+        let reg = (GICD_IGROUPR + ((irq / 32) * 4) as usize) as *const u32;
+        read_volatile(reg) & (1 << (irq % 32)) == 0
+    }
+}
+
+/// Read back an interrupt's priority
+pub fn priority(irq: u32) -> u8 {
+    if irq >= 1020 {
+        return 0xFF;
+    }
+
+    unsafe { read_volatile((GICD_IPRIORITYR + irq as usize) as *const u8) }
+}
+
+/// Only signal interrupts more urgent than `mask`; returns the old mask
+pub fn set_priority_mask(mask: u8) -> u8 {
+    unsafe {
+        let old = read_volatile(GICC_PMR as *const u32) as u8;
+        write_volatile(GICC_PMR as *mut u32, mask as u32);
+        old
+    }
+}
+
+/// Priority of the most urgent active interrupt (0xFF when idle)
+pub fn running_priority() -> u8 {
+    unsafe { read_volatile(GICC_RPR as *const u32) as u8 }
+}
+
+// ============================================================================
+// Affinity
+// ============================================================================
+//
+// GICv2 routes each SPI to a set of CPUs through an 8-bit target mask in
+// GICD_ITARGETSR (bit n = CPU interface n); SGIs and PPIs always go to the
+// CPU they belong to. A GICv3 would use GICD_IROUTER with affinity values
+// instead, but QEMU virt gives us a v2 unless told otherwise.
+
+/// Number of CPU interfaces the distributor serves
+pub fn cpu_count() -> u32 {
+    unsafe { ((read_volatile(GICD_TYPER as *const u32) >> 5) & 0x7) + 1 }
+}
+
+/// Target mask of the CPU reading it (the banked ITARGETSR0 reports our own bit)
+pub fn current_cpu_mask() -> u8 {
+    unsafe { read_volatile(GICD_ITARGETSR as *const u8) }
+}
+
+/// CPUs an interrupt is delivered to, as a target mask
+pub fn targets(irq: u32) -> u8 {
+    if irq >= 1020 {
+        return 0;
+    }
+
+    unsafe { read_volatile((GICD_ITARGETSR + irq as usize) as *const u8) }
+}
+
+/// Deliver SPI `irq` to the CPUs in `mask` (bit n = CPU n)
+///
+/// Fails with `InvalidArgument` for SGIs and PPIs, whose targets are fixed,
+/// and for a mask that is empty or names CPUs the GIC doesn't have.
+pub fn set_targets(irq: u32, mask: u8) -> KResult<()> {
+    if !(FIRST_SPI..1020).contains(&irq) {
+        return Err(KError::with_context(ErrorKind::InvalidArgument, "irq not an SPI"));
+    }
+    let present = ((1u32 << cpu_count()) - 1) as u8;
+    if mask == 0 || mask & !present != 0 {
+        return Err(KError::with_context(ErrorKind::InvalidArgument, "irq target cpus"));
+    }
+
+    unsafe {
+        write_volatile((GICD_ITARGETSR + irq as usize) as *mut u8, mask);
+    }
+    Ok(())
+}
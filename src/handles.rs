@@ -0,0 +1,429 @@
+//! Resource Handle Table
+//!
+//! Kernel-wide registry of resources tagged with the thread or task that
+//! owns them. Releasing a handle drops the resource; when a thread is
+//! reclaimed by `threading::cleanup_terminated` every handle it still owns
+//! is released, so a thread that dies (or is killed) without tidying up
+//! doesn't leak what it held.
+//!
+//! Sockets, SSH console sessions, shared memory and VFS mounts are covered.
+//! VFS file opens, timers and DMA buffers are not: opens are plain values
+//! dropped with their holder, timer callbacks are static, and DMA memory
+//! belongs to virtio queues that outlive the thread that set them up.
+//!
+//! Resources whose holder keeps them (sockets, shared memory, mounts,
+//! console sessions) are *tracked* instead: the table keeps only a reclaim
+//! action, run if the owner goes away while the resource is still tracked.
+//! Async tasks (`Task`) own what they register while they are polled, and
+//! release it when they finish.
+
+use crate::defrag::{self, Hint};
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// Upper bound on live handles, to catch runaway leaks early
+const MAX_HANDLES: usize = 1024;
+
+/// Run a closure with IRQs disabled so the table can be used from any context
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// Opaque handle to a registered resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handle(u32);
+
+#[cfg(feature = "shell")]
+impl Handle {
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Who a resource belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    /// A kernel thread (by thread id)
+    Thread(usize),
+    /// An async task (id from `new_task`)
+    Task(usize),
+}
+
+/// What a handle refers to (for listings and accounting)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    Socket,
+    Session,
+    Shm,
+    Mount,
+    Other,
+}
+
+#[cfg(feature = "shell")]
+impl ResourceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResourceKind::Socket => "socket",
+            ResourceKind::Session => "session",
+            ResourceKind::Shm => "shm",
+            ResourceKind::Mount => "mount",
+            ResourceKind::Other => "other",
+        }
+    }
+}
+
+/// Summary of one live handle
+#[derive(Debug, Clone, Copy)]
+pub struct HandleInfo {
+    pub handle: Handle,
+    pub owner: Owner,
+    pub kind: ResourceKind,
+}
+
+struct Entry {
+    info: HandleInfo,
+    resource: Box<dyn Any + Send>,
+}
+
+static TABLE: Spinlock<Vec<Entry>> = Spinlock::new(Vec::new());
+static NEXT_HANDLE: AtomicU32 = AtomicU32::new(1);
+#[cfg(any(feature = "shell", feature = "http"))]
+static NEXT_TASK: AtomicUsize = AtomicUsize::new(1);
+
+/// Task scopes entered on each thread, innermost last: (thread id, task id)
+static SCOPES: Spinlock<Vec<(usize, usize)>> = Spinlock::new(Vec::new());
+
+// ============================================================================
+// Registration
+// ============================================================================
+
+/// Register `resource` as owned by the current thread or task
+pub fn register<T: Any + Send>(kind: ResourceKind, resource: T) -> KResult<Handle> {
+    register_for(current_owner(), kind, resource)
+}
+
+/// Register `resource` on behalf of `owner`
+/// On failure the resource is dropped.
+pub fn register_for<T: Any + Send>(
+    owner: Owner,
+    kind: ResourceKind,
+    resource: T,
+) -> KResult<Handle> {
+    // Box outside the table lock
+    let resource: Box<dyn Any + Send> = crate::allocator::try_box(resource)
+        .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "handle"))?;
+    match insert(owner, kind, resource) {
+        Ok(handle) => Ok(handle),
+        // Drop the resource outside the lock
+        Err(entry) => {
+            drop(entry);
+            Err(KError::with_context(ErrorKind::LimitReached, "handle table"))
+        }
+    }
+}
+
+/// Add an entry, or hand it back if the table is full
+fn insert(
+    owner: Owner,
+    kind: ResourceKind,
+    resource: Box<dyn Any + Send>,
+) -> Result<Handle, Entry> {
+    let handle = Handle(NEXT_HANDLE.fetch_add(1, Ordering::Relaxed));
+    let entry = Entry {
+        info: HandleInfo {
+            handle,
+            owner,
+            kind,
+        },
+        resource,
+    };
+
+    with_irqs_disabled(|| {
+        let mut table = TABLE.lock();
+        if table.len() >= MAX_HANDLES || table.try_reserve(1).is_err() {
+            return Err(entry);
+        }
+        table.push(entry);
+        Ok(handle)
+    })
+}
+
+// ============================================================================
+// Tracked Resources
+// ============================================================================
+
+/// Release action for a resource kept by its holder rather than the table
+struct Reclaim(Option<Box<dyn FnOnce() + Send>>);
+
+impl Drop for Reclaim {
+    fn drop(&mut self) {
+        if let Some(reclaim) = self.0.take() {
+            reclaim();
+        }
+    }
+}
+
+/// Track a resource the caller keeps, on behalf of the current owner
+///
+/// `reclaim` frees it if the handle is released while still tracked - the
+/// owner exited or was killed before the holder let go. A holder that frees
+/// the resource itself calls `untrack` first.
+pub fn track(kind: ResourceKind, reclaim: impl FnOnce() + Send + 'static) -> KResult<Handle> {
+    let oom = || KError::with_context(ErrorKind::OutOfMemory, "handle");
+    let reclaim: Box<dyn FnOnce() + Send> = crate::allocator::try_box(reclaim).map_err(|_| oom())?;
+    let resource: Box<dyn Any + Send> = crate::allocator::try_box(Reclaim(Some(reclaim)))
+        .map_err(|mut rejected| {
+            rejected.0 = None;
+            oom()
+        })?;
+
+    insert(current_owner(), kind, resource).map_err(|entry| {
+        // Not tracked, so the caller still frees the resource
+        if let Ok(mut rejected) = entry.resource.downcast::<Reclaim>() {
+            rejected.0 = None;
+        }
+        KError::with_context(ErrorKind::LimitReached, "handle table")
+    })
+}
+
+/// Stop tracking a resource its holder is about to free
+///
+/// Returns false if the handle was already released: the reclaim action has
+/// run and the resource is no longer the holder's to free.
+pub fn untrack(handle: Handle) -> bool {
+    match take::<Reclaim>(handle) {
+        Some(mut reclaim) => {
+            reclaim.0 = None;
+            true
+        }
+        None => false,
+    }
+}
+
+// ============================================================================
+// Tasks
+// ============================================================================
+
+/// An async task as an owner of handles
+///
+/// Poll the task's future inside `enter` so what it registers belongs to the
+/// task; dropping the `Task` after the future (it finished or was cancelled)
+/// releases whatever it left behind.
+pub struct Task(usize);
+
+/// Guard from `Task::enter`
+pub struct TaskScope {
+    tid: usize,
+    task: usize,
+    entered: bool,
+}
+
+/// Allocate a task owner
+#[cfg(any(feature = "shell", feature = "http"))]
+pub fn new_task() -> Task {
+    Task(NEXT_TASK.fetch_add(1, Ordering::Relaxed))
+}
+
+impl Task {
+    pub fn owner(&self) -> Owner {
+        Owner::Task(self.0)
+    }
+
+    /// Make this task the current owner on this thread until the guard drops
+    pub fn enter(&self) -> TaskScope {
+        let tid = crate::threading::current_thread_id();
+        let entered = with_irqs_disabled(|| {
+            let mut scopes = SCOPES.lock();
+            if scopes.try_reserve(1).is_err() {
+                return false;
+            }
+            scopes.push((tid, self.0));
+            true
+        });
+        TaskScope {
+            tid,
+            task: self.0,
+            entered,
+        }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        let released = release_owner(self.owner());
+        if released > 0 {
+            crate::kinfo!("[Handles] Released {} handle(s) left by task {}", released, self.0);
+        }
+    }
+}
+
+impl Drop for TaskScope {
+    fn drop(&mut self) {
+        if !self.entered {
+            return;
+        }
+        with_irqs_disabled(|| {
+            let mut scopes = SCOPES.lock();
+            if let Some(pos) = scopes.iter().rposition(|&s| s == (self.tid, self.task)) {
+                scopes.remove(pos);
+            }
+        });
+    }
+}
+
+/// Owner of what runs now: the innermost task entered on this thread,
+/// otherwise the thread itself
+pub fn current_owner() -> Owner {
+    let tid = crate::threading::current_thread_id();
+    with_irqs_disabled(|| {
+        SCOPES
+            .lock()
+            .iter()
+            .rev()
+            .find(|s| s.0 == tid)
+            .map(|s| Owner::Task(s.1))
+    })
+    .unwrap_or(Owner::Thread(tid))
+}
+
+/// Hand `handle` to another owner, e.g. an accepted connection to the task
+/// serving it
+#[cfg(feature = "ssh")]
+pub fn set_owner(handle: Handle, owner: Owner) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut table = TABLE.lock();
+        match table.iter_mut().find(|e| e.info.handle == handle) {
+            Some(entry) => {
+                entry.info.owner = owner;
+                Ok(())
+            }
+            None => Err(KError::with_context(ErrorKind::NotFound, "handle")),
+        }
+    })
+}
+
+// ============================================================================
+// Access and Release
+// ============================================================================
+
+fn remove_entry(handle: Handle) -> Option<Entry> {
+    with_irqs_disabled(|| {
+        let mut table = TABLE.lock();
+        let pos = table.iter().position(|e| e.info.handle == handle)?;
+        Some(table.swap_remove(pos))
+    })
+}
+
+/// Release a handle, dropping its resource
+pub fn release(handle: Handle) -> KResult<()> {
+    match remove_entry(handle) {
+        Some(entry) => {
+            drop(entry);
+            Ok(())
+        }
+        None => Err(KError::with_context(ErrorKind::NotFound, "handle")),
+    }
+}
+
+/// Remove a handle and hand its resource back to the caller
+/// Returns None if the handle is unknown or holds a different type.
+pub fn take<T: Any + Send>(handle: Handle) -> Option<T> {
+    let entry = remove_entry(handle)?;
+    if !entry.resource.is::<T>() {
+        // Wrong type: put it back untouched
+        with_irqs_disabled(|| TABLE.lock().push(entry));
+        return None;
+    }
+    entry.resource.downcast::<T>().ok().map(|b| *b)
+}
+
+/// Run `f` on the resource behind `handle`
+///
+/// The table lock is held (with IRQs disabled) while `f` runs, so keep it
+/// short and don't touch the handle table from inside it.
+pub fn with<T: Any + Send, R>(handle: Handle, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+    with_irqs_disabled(|| {
+        let mut table = TABLE.lock();
+        let entry = table.iter_mut().find(|e| e.info.handle == handle)?;
+        entry.resource.downcast_mut::<T>().map(f)
+    })
+}
+
+/// Release every handle owned by `owner`, returning how many were released
+pub fn release_owner(owner: Owner) -> usize {
+    let mut released = Vec::new();
+    with_irqs_disabled(|| {
+        // A killed thread can't leave the scopes it was in
+        if let Owner::Thread(tid) = owner {
+            SCOPES.lock().retain(|s| s.0 != tid);
+        }
+        let mut table = TABLE.lock();
+        let mut i = 0;
+        while i < table.len() {
+            if table[i].info.owner == owner {
+                released.push(table.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+    });
+
+    // Drop resources outside the lock - their destructors may take other locks
+    let count = released.len();
+    drop(released);
+    count
+}
+
+// ============================================================================
+// Inspection
+// ============================================================================
+
+/// Snapshot of all live handles
+#[cfg(feature = "shell")]
+pub fn list() -> Vec<HandleInfo> {
+    let count = with_irqs_disabled(|| TABLE.lock().len());
+    let mut out = Vec::with_capacity(count);
+    with_irqs_disabled(|| {
+        for entry in TABLE.lock().iter().take(out.capacity()) {
+            out.push(entry.info);
+        }
+    });
+    out
+}
+
+/// Number of handles held by `owner`
+pub fn count_owned_by(owner: Owner) -> usize {
+    with_irqs_disabled(|| {
+        TABLE
+            .lock()
+            .iter()
+            .filter(|e| e.info.owner == owner)
+            .count()
+    })
+}
+
+/// Defrag hint handler: compact the table after a burst of releases
+pub fn defrag(hint: Hint) -> usize {
+    match hint {
+        Hint::DropCaches => 0,
+        Hint::Reallocate => with_irqs_disabled(|| defrag::reallocate_vec(&mut TABLE.lock())),
+    }
+}
@@ -0,0 +1,318 @@
+//! HTTP File Browser
+//!
+//! Read-only HTTP/1.1 access to kernel-generated files (crash report,
+//! configuration, memory and network statistics), so they can be pulled
+//! with curl without an SSH client:
+//!
+//! ```text
+//! curl http://localhost:8080/
+//! curl -r 0-1023 http://localhost:8080/crashdump.txt
+//! ```
+//!
+//! `GET` and `HEAD` only, one connection at a time, single byte ranges.
+//! The port comes from `http.port` (default 80; 0, or anything above 65535,
+//! disables the server).
+//! Files come from the VFS and are read with its async API chunk by chunk,
+//! so a large download never holds the executor for a whole file.
+
+use alloc::vec::Vec;
+use embassy_net::Stack;
+use embassy_time::{Duration, Timer};
+
+use crate::async_net::{TcpListener, TcpStream};
+use crate::klog::{self, Level};
+use crate::network::Service;
+use crate::vfs;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+const DEFAULT_HTTP_PORT: u16 = 80;
+
+/// Longest request head (request line + headers) we accept
+const MAX_REQUEST_SIZE: usize = 2048;
+
+/// Bytes written per chunk when streaming a body
+const WRITE_CHUNK: usize = 1024;
+
+// ============================================================================
+// Directory Listing
+// ============================================================================
+
+fn directory_listing(path: &str) -> Option<Vec<u8>> {
+    let entries = crate::vfs::list(path).ok()?;
+    let base = path.trim_end_matches('/');
+    let mut out = alloc::format!("<html><body><h1>Akuma {}/</h1><ul>\n", base);
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        out.push_str(&alloc::format!(
+            "<li><a href=\"{0}/{1}{2}\">{1}{2}</a></li>\n",
+            base,
+            entry.name,
+            slash
+        ));
+    }
+    out.push_str("</ul></body></html>\n");
+    Some(out.into_bytes())
+}
+
+// ============================================================================
+// Request Parsing
+// ============================================================================
+
+/// Parse a `Range` header value against a body of `len` bytes
+///
+/// Returns Ok(Some((first, last))) with an inclusive range, Ok(None) if the
+/// header isn't a single byte range we understand (serve the whole body),
+/// or Err(()) if it can't be satisfied (416).
+pub fn parse_range(value: &str, len: usize) -> Result<Option<(usize, usize)>, ()> {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+
+    let range = match (start.parse::<usize>().ok(), end.parse::<usize>().ok()) {
+        // bytes=first-last
+        (Some(first), Some(last)) if first <= last => (first, last.min(len.saturating_sub(1))),
+        // bytes=first-
+        (Some(first), None) if end.is_empty() => (first, len.saturating_sub(1)),
+        // bytes=-suffix
+        (None, Some(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return Err(());
+            }
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+
+    if len == 0 || range.0 >= len {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+struct Request<'a> {
+    method: &'a str,
+    path: &'a str,
+    range: Option<&'a str>,
+}
+
+fn parse_request(head: &str) -> Option<Request<'_>> {
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next()?.split(' ');
+    let method = parts.next()?;
+    let path = parts.next()?;
+    let range = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("range"))
+        .map(|(_, value)| value.trim());
+    Some(Request {
+        method,
+        path,
+        range,
+    })
+}
+
+// ============================================================================
+// Connection Handler
+// ============================================================================
+
+async fn send_status(stream: &mut TcpStream, status: &str) {
+    let body = alloc::format!("{}\n", status);
+    let head = alloc::format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    let _ = stream
+        .write_all_vectored(&[head.as_bytes(), body.as_bytes()])
+        .await;
+}
+
+async fn send_body(stream: &mut TcpStream, body: &[u8], content_type: &str, head_only: bool) {
+    let head = alloc::format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        content_type,
+        body.len()
+    );
+    let body = if head_only { &[][..] } else { body };
+    if stream.write_all_vectored(&[head.as_bytes(), body]).await.is_ok() {
+        let _ = stream.flush().await;
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream) {
+    // Read the request head
+    let mut head: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 512];
+    let head_len = loop {
+        if let Some(pos) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if head.len() >= MAX_REQUEST_SIZE {
+            send_status(&mut stream, "431 Request Header Fields Too Large").await;
+            stream.close();
+            return;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => {
+                stream.close();
+                return;
+            }
+            Ok(n) => {
+                if crate::allocator::try_extend(&mut head, &buf[..n]).is_err() {
+                    stream.close();
+                    return;
+                }
+            }
+        }
+    };
+
+    let text = core::str::from_utf8(&head[..head_len]).unwrap_or("");
+    let request = match parse_request(text) {
+        Some(request) => request,
+        None => {
+            send_status(&mut stream, "400 Bad Request").await;
+            stream.close();
+            return;
+        }
+    };
+
+    let head_only = match request.method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            send_status(&mut stream, "405 Method Not Allowed").await;
+            stream.close();
+            return;
+        }
+    };
+
+    log(&alloc::format!("[HTTP] {} {}\n", request.method, request.path));
+
+    let path = request.path.split('?').next().unwrap_or("/");
+    if path.ends_with('/') {
+        match directory_listing(path) {
+            Some(body) => send_body(&mut stream, &body, "text/html", head_only).await,
+            None => send_status(&mut stream, "404 Not Found").await,
+        }
+        stream.close();
+        return;
+    }
+
+    let mut file = match vfs::open(path) {
+        Ok(file) => file,
+        Err(_) => {
+            send_status(&mut stream, "404 Not Found").await;
+            stream.close();
+            return;
+        }
+    };
+    let size = file.size();
+
+    let range = match request.range.map(|r| parse_range(r, size)) {
+        Some(Ok(range)) => range,
+        Some(Err(())) => {
+            let head = alloc::format!(
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                size
+            );
+            let _ = stream.write_all(head.as_bytes()).await;
+            stream.close();
+            return;
+        }
+        None => None,
+    };
+
+    let (status, first, last) = match range {
+        Some((first, last)) => ("206 Partial Content", first, last),
+        None => ("200 OK", 0, size.saturating_sub(1)),
+    };
+    let length = if size == 0 { 0 } else { last - first + 1 };
+
+    let mut head = alloc::format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n",
+        status,
+        length
+    );
+    if range.is_some() {
+        head.push_str(&alloc::format!("Content-Range: bytes {}-{}/{}\r\n", first, last, size));
+    }
+    head.push_str("\r\n");
+
+    if stream.write_all(head.as_bytes()).await.is_ok() && !head_only {
+        let mut chunk = [0u8; WRITE_CHUNK];
+        let mut offset = first;
+        let end = first + length;
+        while offset < end {
+            let want = (end - offset).min(WRITE_CHUNK);
+            let n = match file.read_at_async(offset, &mut chunk[..want]).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if stream.write_all(&chunk[..n]).await.is_err() {
+                break;
+            }
+            offset += n;
+        }
+        let _ = stream.flush().await;
+    }
+    stream.close();
+}
+
+// ============================================================================
+// HTTP Server Accept Loop
+// ============================================================================
+
+/// Run the HTTP file browser accept loop
+pub async fn run(stack: Stack<'static>) {
+    let configured = crate::config::get_u64("http.port");
+    let Ok(port) = configured.map_or(Ok(DEFAULT_HTTP_PORT), u16::try_from) else {
+        log(&alloc::format!(
+            "[HTTP Server] Disabled (http.port={} is out of range)\n",
+            configured.unwrap_or(0)
+        ));
+        return;
+    };
+    if port == 0 {
+        log("[HTTP Server] Disabled (http.port=0)\n");
+        return;
+    }
+    log(&alloc::format!("[HTTP Server] Serving kernel files on port {}\n", port));
+
+    let listener = TcpListener::new(stack, port);
+
+    loop {
+        match listener.accept().await {
+            Ok(mut stream) => {
+                stream.set_service(Service::Http);
+                handle_connection(stream).await;
+            }
+            Err(e) => {
+                warn(&alloc::format!(
+                    "[HTTP Server] Accept error: {:?}, retrying...\n",
+                    e
+                ));
+                Timer::after(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Logging
+// ============================================================================
+
+fn log(msg: &str) {
+    klog::log(Level::Info, "http_server", msg);
+}
+
+fn warn(msg: &str) {
+    klog::log(Level::Warn, "http_server", msg);
+}
@@ -1,9 +1,25 @@
 // IRQ handler registration and dispatch
+//
+// Handlers run with IRQs unmasked, so a more urgent interrupt (by GIC
+// priority, see gic.rs) can preempt a running handler: the scheduler tick
+// isn't held up by a slow device handler. The GIC's running priority keeps
+// equal and less urgent interrupts out until the handler's EOI.
 
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use spinning_top::Spinlock;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering};
 
-type IrqHandler = fn(u32);
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+
+/// A registered handler, with whatever device state it captured
+///
+/// Shared so dispatch can call it without holding the table lock; the
+/// inner lock is never contended, since a line doesn't preempt itself.
+type IrqHandler = Arc<Spinlock<dyn FnMut(u32) + Send>>;
+
+/// FIQ handlers are plain functions: they run with every lock possibly held
+type FiqHandler = fn(u32);
 
 struct IrqHandlers {
     handlers: Vec<Option<IrqHandler>>,
@@ -13,16 +29,82 @@ static IRQ_HANDLERS: Spinlock<IrqHandlers> = Spinlock::new(IrqHandlers {
     handlers: Vec::new(),
 });
 
-/// Register an IRQ handler
-pub fn register_handler(irq: u32, handler: IrqHandler) {
-    let mut handlers = IRQ_HANDLERS.lock();
+/// Handlers currently running (more than one if a handler unmasks IRQs)
+///
+/// Global rather than per-CPU: only the boot CPU takes interrupts.
+static IRQ_DEPTH: AtomicU32 = AtomicU32::new(0);
+
+/// Let more urgent interrupts preempt handlers (`irq.nested`, default on)
+static NESTING: AtomicBool = AtomicBool::new(true);
+
+/// Handlers entered while another was running
+static NESTED_IRQS: AtomicU64 = AtomicU64::new(0);
 
-    // Ensure the handlers vector is large enough
-    while handlers.handlers.len() <= irq as usize {
-        handlers.handlers.push(None);
+/// Run a closure with IRQs disabled (the handler table is read from IRQ context)
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
     }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+/// True while running an IRQ handler
+pub fn in_irq() -> bool {
+    IRQ_DEPTH.load(Ordering::Relaxed) > 0
+}
+
+/// Run `f` as interrupt context, so `in_irq` is true inside it
+///
+/// `dispatch_irq` does this for registered handlers; the scheduler SGI
+/// uses it around the part that runs before switching threads.
+pub fn as_handler<R>(f: impl FnOnce() -> R) -> R {
+    IRQ_DEPTH.fetch_add(1, Ordering::Relaxed);
+    let result = f();
+    IRQ_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+/// Allow or forbid handlers from being preempted
+pub fn set_nesting(enabled: bool) {
+    NESTING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn nesting() -> bool {
+    NESTING.load(Ordering::Relaxed)
+}
+
+/// Number of handlers that preempted another handler
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn nested_count() -> u64 {
+    NESTED_IRQS.load(Ordering::Relaxed)
+}
+
+/// Register an IRQ handler
+///
+/// The handler may be a closure owning its device's state, so drivers
+/// don't need global statics to find it. It replaces any handler already
+/// on the line; the old one is dropped once it has finished running.
+pub fn register_handler(irq: u32, handler: impl FnMut(u32) + Send + 'static) {
+    let handler: IrqHandler = Arc::new(Spinlock::new(handler));
+    let old = with_irqs_disabled(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+
+        // Ensure the handlers vector is large enough
+        while handlers.handlers.len() <= irq as usize {
+            handlers.handlers.push(None);
+        }
 
-    handlers.handlers[irq as usize] = Some(handler);
+        handlers.handlers[irq as usize].replace(handler)
+    });
+    // Outside the lock: the captured state may run its own Drop
+    drop(old);
 
     // Enable the IRQ in GIC
     crate::gic::enable_irq(irq);
@@ -30,11 +112,11 @@ pub fn register_handler(irq: u32, handler: IrqHandler) {
 
 /// Unregister an IRQ handler
 pub fn unregister_handler(irq: u32) {
-    let mut handlers = IRQ_HANDLERS.lock();
-
-    if (irq as usize) < handlers.handlers.len() {
-        handlers.handlers[irq as usize] = None;
-    }
+    let old = with_irqs_disabled(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        handlers.handlers.get_mut(irq as usize).and_then(Option::take)
+    });
+    drop(old);
 
     // Disable the IRQ in GIC
     crate::gic::disable_irq(irq);
@@ -42,14 +124,249 @@ pub fn unregister_handler(irq: u32) {
 
 /// Dispatch an IRQ to its registered handler
 pub fn dispatch_irq(irq: u32) {
-    // Copy the handler out while holding the lock, then call it without the lock
-    // This prevents deadlocks if the handler needs to register/unregister handlers
+    // Take a reference to the handler while holding the lock, then call it
+    // without the lock. This prevents deadlocks if the handler needs to
+    // register/unregister handlers, and keeps it alive if it is replaced
+    // while running (the last reference may then be dropped here, in IRQ
+    // context - the heap is IRQ-safe).
     let handler = {
         let handlers = IRQ_HANDLERS.lock();
-        handlers.handlers.get(irq as usize).copied().flatten()
+        handlers.handlers.get(irq as usize).cloned().flatten()
+    };
+
+    let handler = match handler {
+        Some(handler) => handler,
+        None => {
+            if let Some(line) = line_stats(irq) {
+                line.unhandled.fetch_add(1, Ordering::Relaxed);
+            }
+            return;
+        }
+    };
+
+    if in_irq() {
+        NESTED_IRQS.fetch_add(1, Ordering::Relaxed);
+    }
+    let nest = NESTING.load(Ordering::Relaxed);
+    let depth = IRQ_DEPTH.load(Ordering::Relaxed) as usize;
+    if let Some(child) = CHILD_CYCLES.get(depth) {
+        child.store(0, Ordering::Relaxed);
+    }
+    let start = crate::timer::cycles();
+    as_handler(|| {
+        // The exception entry saved ELR/SPSR on the stack, so a nested
+        // exception can't clobber them
+        if nest {
+            unsafe { core::arch::asm!("msr daifclr, #2") };
+        }
+        (*handler.lock())(irq);
+        if nest {
+            unsafe { core::arch::asm!("msr daifset, #2") };
+        }
+    });
+    let elapsed = crate::timer::cycles().wrapping_sub(start);
+
+    // Time spent in handlers that preempted this one is theirs
+    let children = CHILD_CYCLES.get(depth).map_or(0, |c| c.load(Ordering::Relaxed));
+    if depth > 0
+        && let Some(parent) = CHILD_CYCLES.get(depth - 1)
+    {
+        parent.fetch_add(elapsed, Ordering::Relaxed);
+    }
+    record_fire(irq, elapsed.saturating_sub(children));
+}
+
+// ============================================================================
+// FIQ
+// ============================================================================
+//
+// One interrupt line can be delivered as FIQ (GIC Group 0, most urgent
+// priority) for timing-critical work. It preempts IRQ handlers and runs
+// even inside `with_irqs_disabled` sections, which only mask IRQs - so the
+// handler may interrupt code holding any lock. It must only touch atomics:
+// no locks, no allocation, no logging.
+
+/// Line number meaning "no FIQ registered"
+const NO_FIQ: u32 = u32::MAX;
+
+static FIQ_LINE: AtomicU32 = AtomicU32::new(NO_FIQ);
+static FIQ_HANDLER: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+static FIQ_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Deliver `irq` as FIQ to `handler`
+///
+/// Only one FIQ handler can be registered; a second fails with
+/// `AlreadyExists`. SGIs and PPIs work as well as SPIs.
+pub fn register_fiq(irq: u32, handler: FiqHandler) -> KResult<()> {
+    if irq >= 1020 {
+        return Err(KError::with_context(ErrorKind::InvalidArgument, "fiq line"));
+    }
+    if FIQ_LINE
+        .compare_exchange(NO_FIQ, irq, Ordering::AcqRel, Ordering::Relaxed)
+        .is_err()
+    {
+        return Err(KError::with_context(ErrorKind::AlreadyExists, "fiq"));
+    }
+    // Publish the handler before the line is enabled
+    FIQ_HANDLER.store(handler as *mut (), Ordering::Release);
+
+    crate::gic::set_priority(irq, crate::gic::PRIORITY_FIQ);
+    crate::gic::set_fiq(irq, true);
+    crate::gic::enable_irq(irq);
+    Ok(())
+}
+
+/// Stop delivering `irq` as FIQ; it goes back to being a disabled IRQ line
+pub fn unregister_fiq(irq: u32) -> KResult<()> {
+    if FIQ_LINE.load(Ordering::Acquire) != irq {
+        return Err(KError::with_context(ErrorKind::NotFound, "fiq"));
+    }
+
+    crate::gic::disable_irq(irq);
+    crate::gic::set_fiq(irq, false);
+    crate::gic::set_priority(irq, crate::gic::PRIORITY_DEFAULT);
+    FIQ_LINE.store(NO_FIQ, Ordering::Release);
+    Ok(())
+}
+
+/// Line delivered as FIQ, if any
+pub fn fiq_line() -> Option<u32> {
+    let line = FIQ_LINE.load(Ordering::Acquire);
+    (line != NO_FIQ).then_some(line)
+}
+
+/// FIQs handled since boot
+pub fn fiq_count() -> u64 {
+    FIQ_COUNT.load(Ordering::Relaxed)
+}
+
+/// Acknowledge and handle a FIQ (called from the FIQ vector)
+pub(crate) fn dispatch_fiq() {
+    let Some(irq) = crate::gic::acknowledge_irq() else {
+        record_spurious();
+        return;
     };
 
-    if let Some(handler) = handler {
+    let handler = FIQ_HANDLER.load(Ordering::Acquire);
+    if irq == FIQ_LINE.load(Ordering::Acquire) && !handler.is_null() {
+        // SAFETY: only ever stored from a `FiqHandler` in `register_fiq`
+        let handler: FiqHandler = unsafe { core::mem::transmute(handler) };
         handler(irq);
+        FIQ_COUNT.fetch_add(1, Ordering::Relaxed);
+    } else if let Some(line) = line_stats(irq) {
+        line.unhandled.fetch_add(1, Ordering::Relaxed);
+    }
+    crate::gic::end_of_interrupt(irq);
+}
+
+// ============================================================================
+// Statistics
+// ============================================================================
+
+/// Interrupt lines with their own counters (SGIs, PPIs and the SPIs QEMU
+/// virt uses); higher lines are dispatched but not counted
+const TRACKED_LINES: usize = 256;
+
+/// Deepest nesting whose handlers' time is split from their parents'
+const MAX_NESTING: usize = 8;
+
+struct LineStats {
+    count: AtomicU64,
+    /// Handler time in cycles, excluding handlers that preempted it
+    total_cycles: AtomicU64,
+    max_cycles: AtomicU64,
+    /// Fired with no handler registered
+    unhandled: AtomicU64,
+}
+
+impl LineStats {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            total_cycles: AtomicU64::new(0),
+            max_cycles: AtomicU64::new(0),
+            unhandled: AtomicU64::new(0),
+        }
+    }
+}
+
+static LINE_STATS: [LineStats; TRACKED_LINES] = [const { LineStats::new() }; TRACKED_LINES];
+
+/// Cycles spent in nested handlers, per nesting depth of their parent
+static CHILD_CYCLES: [AtomicU64; MAX_NESTING] = [const { AtomicU64::new(0) }; MAX_NESTING];
+
+/// Acknowledges that returned no interrupt (GIC ID 1023)
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+/// Counters for one interrupt line
+#[cfg(all(feature = "fs", feature = "shell"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IrqStats {
+    pub count: u64,
+    /// Time in the handler, excluding handlers that preempted it
+    pub total_ns: u64,
+    pub max_ns: u64,
+    /// Times it fired without a handler registered
+    pub unhandled: u64,
+}
+
+fn line_stats(irq: u32) -> Option<&'static LineStats> {
+    LINE_STATS.get(irq as usize)
+}
+
+/// Count a handled interrupt that took `cycles`
+///
+/// `dispatch_irq` does this itself; the scheduler SGI, which bypasses it,
+/// reports here with 0 cycles (its time includes the thread switch).
+pub fn record_fire(irq: u32, cycles: u64) {
+    if let Some(line) = line_stats(irq) {
+        line.count.fetch_add(1, Ordering::Relaxed);
+        line.total_cycles.fetch_add(cycles, Ordering::Relaxed);
+        line.max_cycles.fetch_max(cycles, Ordering::Relaxed);
+    }
+}
+
+/// Count an acknowledge that found no pending interrupt
+pub fn record_spurious() {
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counters for `irq` (all zero for untracked lines)
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn irq_stats(irq: u32) -> IrqStats {
+    match line_stats(irq) {
+        Some(line) => IrqStats {
+            count: line.count.load(Ordering::Relaxed),
+            total_ns: crate::timer::cycles_to_ns(line.total_cycles.load(Ordering::Relaxed)),
+            max_ns: crate::timer::cycles_to_ns(line.max_cycles.load(Ordering::Relaxed)),
+            unhandled: line.unhandled.load(Ordering::Relaxed),
+        },
+        None => IrqStats::default(),
+    }
+}
+
+/// Counters for every line that has fired, lowest line first
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn all_stats() -> Vec<(u32, IrqStats)> {
+    (0..TRACKED_LINES as u32)
+        .map(|irq| (irq, irq_stats(irq)))
+        .filter(|(_, st)| st.count > 0 || st.unhandled > 0)
+        .collect()
+}
+
+/// Acknowledges that found nothing pending
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Zero every line's counters and the spurious count
+pub fn reset_stats() {
+    for line in LINE_STATS.iter() {
+        line.count.store(0, Ordering::Relaxed);
+        line.total_cycles.store(0, Ordering::Relaxed);
+        line.max_cycles.store(0, Ordering::Relaxed);
+        line.unhandled.store(0, Ordering::Relaxed);
     }
+    SPURIOUS.store(0, Ordering::Relaxed);
 }
@@ -0,0 +1,718 @@
+//! Kernel Log
+//!
+//! Log records carry a level, the module that produced them and a sequence
+//! number. Every record is handed to each registered sink; two are built
+//! in: the console, and a ring of recent records that SSH `log tail`
+//! follows. More sinks (files, remote collectors) register with
+//! `register_sink`.
+//!
+//! Records below the current level are dropped before they are formatted.
+//! The level is global (`log.level`, default info) with per-module
+//! overrides (`log.modules=ssh:debug,threading:trace`), and both can be
+//! changed at runtime with `set_level` / `set_module_level` or the SSH
+//! `log level` command. The `kerror!`..`ktrace!` macros log with the
+//! calling module's name; `kevent!` also tags the record with a stable ID
+//! from the event catalog (`events`).
+//!
+//! Every record carries the uptime it was logged at, and the console and
+//! `log tail` print it in front of the text (`[    12.345678]`); with
+//! `log.utc=on` the UTC time (from the RTC) is printed next to it.
+//!
+//! Records logged from IRQ handlers don't reach the sinks directly: they
+//! are staged in a lock-free per-CPU buffer and passed on by a flusher
+//! thread (see "IRQ Staging" below).
+
+use crate::console;
+use crate::defrag::{self, Hint};
+use crate::events::Event;
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+
+/// Records kept in the ring for followers
+const RING_RECORDS: usize = 256;
+
+/// Most sinks that can be registered at once
+const MAX_SINKS: usize = 8;
+
+/// Run a closure with IRQs disabled (IRQ handlers may log)
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Records
+// ============================================================================
+
+/// Severity, most severe first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub const ALL: [Level; 5] = [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    /// Parse a level name (case-insensitive)
+    pub fn parse(name: &str) -> Option<Level> {
+        Level::ALL
+            .into_iter()
+            .find(|l| l.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+/// One log message
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub seq: u64,
+    /// When it was logged (microseconds since boot)
+    pub uptime_us: u64,
+    pub level: Level,
+    pub module: &'static str,
+    /// Catalog entry, for records logged with `kevent!`
+    pub event: Option<Event>,
+    /// Message text without the trailing newline
+    pub text: String,
+}
+
+/// Selects records by level and module
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone, Copy)]
+pub struct Filter<'a> {
+    /// Least severe level let through
+    pub max_level: Level,
+    /// Only this module (None = all)
+    pub module: Option<&'a str>,
+}
+
+#[cfg(feature = "shell")]
+impl Filter<'_> {
+    pub const ALL: Filter<'static> = Filter {
+        max_level: Level::Trace,
+        module: None,
+    };
+
+    pub fn matches(&self, record: &Record) -> bool {
+        record.level <= self.max_level && self.module.is_none_or(|m| m == record.module)
+    }
+}
+
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(1);
+
+// ============================================================================
+// Timestamps
+// ============================================================================
+
+/// Also print UTC in timestamps
+static UTC_TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+
+pub fn utc_timestamps() -> bool {
+    UTC_TIMESTAMPS.load(Ordering::Relaxed)
+}
+
+pub fn set_utc_timestamps(enabled: bool) {
+    UTC_TIMESTAMPS.store(enabled, Ordering::Relaxed);
+}
+
+/// A record's time as printed in front of it: `[    12.345678]`, or
+/// `[    12.345678 2026-01-02T03:04:05.678901Z]` with UTC timestamps
+/// on (once the RTC has set the clock). Formats without allocating.
+pub struct Timestamp {
+    uptime_us: u64,
+    utc: bool,
+}
+
+impl Timestamp {
+    pub fn of(record: &Record) -> Self {
+        Self {
+            uptime_us: record.uptime_us,
+            utc: utc_timestamps(),
+        }
+    }
+}
+
+impl core::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "[{:>6}.{:06}", self.uptime_us / 1_000_000, self.uptime_us % 1_000_000)?;
+        if self.utc
+            && let Some(utc_us) = crate::timer::utc_at_uptime_us(self.uptime_us)
+        {
+            write!(f, " {}", crate::timer::Iso8601::new(utc_us))?;
+        }
+        f.write_str("]")
+    }
+}
+
+// ============================================================================
+// Levels
+// ============================================================================
+
+/// Least severe level logged by modules without an override
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Per-module overrides of MAX_LEVEL
+static MODULE_LEVELS: Spinlock<Vec<(String, Level)>> = Spinlock::new(Vec::new());
+
+/// Set while MODULE_LEVELS is non-empty, so the common case skips the lock
+static HAVE_MODULE_LEVELS: AtomicBool = AtomicBool::new(false);
+
+/// Global level
+pub fn level() -> Level {
+    Level::ALL[MAX_LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+/// Change the global level
+pub fn set_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Override the level for `module`, or remove its override with None
+pub fn set_module_level(module: &str, level: Option<Level>) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut levels = MODULE_LEVELS.lock();
+        let pos = levels.iter().position(|(m, _)| m == module);
+        match (pos, level) {
+            (Some(i), Some(level)) => levels[i].1 = level,
+            (Some(i), None) => {
+                levels.swap_remove(i);
+            }
+            (None, Some(level)) => {
+                levels
+                    .try_reserve(1)
+                    .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "log levels"))?;
+                levels.push((String::from(module), level));
+            }
+            (None, None) => {}
+        }
+        HAVE_MODULE_LEVELS.store(!levels.is_empty(), Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+/// Modules with their own level
+#[cfg(feature = "shell")]
+pub fn module_levels() -> Vec<(String, Level)> {
+    with_irqs_disabled(|| MODULE_LEVELS.lock().clone())
+}
+
+/// Would a record at `level` from `module` be logged?
+pub fn enabled(level: Level, module: &str) -> bool {
+    if HAVE_MODULE_LEVELS.load(Ordering::Relaxed) {
+        let over = with_irqs_disabled(|| {
+            MODULE_LEVELS
+                .lock()
+                .iter()
+                .find(|(m, _)| m == module)
+                .map(|(_, l)| *l)
+        });
+        if let Some(max) = over {
+            return level <= max;
+        }
+    }
+    level <= self::level()
+}
+
+/// Apply `log.level` and `log.modules` from the command line
+pub fn init() {
+    if let Some(value) = crate::config::get("log.level") {
+        match Level::parse(&value) {
+            Some(level) => set_level(level),
+            None => crate::println!("[klog] Unknown log.level '{}', using {}", value, level().as_str()),
+        }
+    }
+    if let Some(utc) = crate::config::get_bool("log.utc") {
+        set_utc_timestamps(utc);
+    }
+    if let Some(value) = crate::config::get("log.modules") {
+        for item in value.split(',').filter(|i| !i.is_empty()) {
+            match item.split_once(':').and_then(|(m, l)| Some((m, Level::parse(l)?))) {
+                Some((module, level)) => {
+                    let _ = set_module_level(module, Some(level));
+                }
+                None => crate::println!("[klog] Ignoring log.modules entry '{}'", item),
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Sinks
+// ============================================================================
+
+/// Receives every record; called with IRQs enabled unless the logger
+/// itself runs in IRQ context, so keep it short and non-blocking
+pub type Sink = fn(&Record);
+
+static SINKS: Spinlock<[Option<(&'static str, Sink)>; MAX_SINKS]> = Spinlock::new(
+    [
+        Some(("console", console_sink)),
+        Some(("ring", ring_sink)),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    ],
+);
+
+/// Add a sink under `name`
+pub fn register_sink(name: &'static str, sink: Sink) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut sinks = SINKS.lock();
+        if sinks.iter().flatten().any(|(n, _)| *n == name) {
+            return Err(KError::with_context(ErrorKind::AlreadyExists, name));
+        }
+        let slot = sinks
+            .iter_mut()
+            .find(|s| s.is_none())
+            .ok_or(KError::with_context(ErrorKind::NoFreeSlots, "log sinks"))?;
+        *slot = Some((name, sink));
+        Ok(())
+    })
+}
+
+/// Remove the sink registered under `name`
+pub fn unregister_sink(name: &str) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut sinks = SINKS.lock();
+        let slot = sinks
+            .iter_mut()
+            .find(|s| s.is_some_and(|(n, _)| n == name))
+            .ok_or(KError::with_context(ErrorKind::NotFound, "log sink"))?;
+        *slot = None;
+        Ok(())
+    })
+}
+
+/// Names of the registered sinks
+#[cfg(feature = "shell")]
+pub fn sinks() -> Vec<&'static str> {
+    let names: [Option<&'static str>; MAX_SINKS] =
+        with_irqs_disabled(|| SINKS.lock().map(|s| s.map(|(n, _)| n)));
+    names.into_iter().flatten().collect()
+}
+
+fn console_sink(record: &Record) {
+    crate::print!("{} ", Timestamp::of(record));
+    if let Some(event) = record.event {
+        crate::print!("[E{}] ", event.id());
+    }
+    console::print(&record.text);
+    console::print("\n");
+}
+
+// ============================================================================
+// Ring
+// ============================================================================
+
+static RING: Spinlock<VecDeque<Record>> = Spinlock::new(VecDeque::new());
+
+fn ring_sink(record: &Record) {
+    with_irqs_disabled(|| {
+        let mut ring = RING.lock();
+        if ring.len() >= RING_RECORDS {
+            ring.pop_front();
+        } else if ring.try_reserve(1).is_err() {
+            return; // Out of memory: followers miss this record
+        }
+        ring.push_back(record.clone());
+    });
+}
+
+/// Records with `seq >= from` that pass `filter`, oldest first
+///
+/// Returns the records and the sequence number to ask for next. Records
+/// that already fell out of the ring are skipped silently.
+#[cfg(feature = "shell")]
+pub fn records_since(from: u64, filter: &Filter) -> (Vec<Record>, u64) {
+    with_irqs_disabled(|| {
+        let ring = RING.lock();
+        let out = ring
+            .iter()
+            .filter(|r| r.seq >= from && filter.matches(r))
+            .cloned()
+            .collect();
+        let next = ring.back().map_or(from, |r| r.seq + 1).max(from);
+        (out, next)
+    })
+}
+
+/// OOM callback: drop the older half of the ring
+///
+/// Returns the text bytes released. Records are only freed, never
+/// allocated, and a busy ring is left alone.
+pub fn reclaim(_layout: Layout) -> usize {
+    let Some(mut ring) = RING.try_lock() else {
+        return 0;
+    };
+    let mut released = 0;
+    for _ in 0..ring.len().div_ceil(2) {
+        if let Some(record) = ring.pop_front() {
+            released += record.text.capacity();
+        }
+    }
+    released
+}
+
+/// Defrag hint handler: move the ring into an exactly-sized buffer
+pub fn defrag(hint: Hint) -> usize {
+    match hint {
+        Hint::DropCaches => 0,
+        Hint::Reallocate => with_irqs_disabled(|| defrag::reallocate_deque(&mut RING.lock())),
+    }
+}
+
+/// Sequence number the next record will get
+pub fn next_seq() -> u64 {
+    NEXT_SEQ.load(Ordering::Relaxed)
+}
+
+// ============================================================================
+// Logging
+// ============================================================================
+
+/// Log `msg` from `module` at `level`
+///
+/// A single trailing newline is stripped; sinks add their own.
+pub fn log(level: Level, module: &'static str, msg: &str) {
+    if enabled(level, module) {
+        let msg = msg.strip_suffix('\n').unwrap_or(msg);
+        if crate::irq::in_irq() {
+            stage(level, module, None, format_args!("{}", msg));
+        } else {
+            emit(level, module, None, String::from(msg));
+        }
+    }
+}
+
+/// Log preformatted arguments; used by the macros once `enabled` passed
+#[doc(hidden)]
+pub fn log_args(level: Level, module: &'static str, args: core::fmt::Arguments) {
+    if crate::irq::in_irq() {
+        stage(level, module, None, args);
+    } else {
+        emit(level, module, None, format_text(args));
+    }
+}
+
+/// Log a catalog event; used by `kevent!` once `enabled` passed
+#[doc(hidden)]
+pub fn log_event_args(event: Event, module: &'static str, args: core::fmt::Arguments) {
+    if crate::irq::in_irq() {
+        stage(event.level(), module, Some(event), args);
+    } else {
+        emit(event.level(), module, Some(event), format_text(args));
+    }
+}
+
+fn format_text(args: core::fmt::Arguments) -> String {
+    let mut text = String::new();
+    let _ = core::fmt::Write::write_fmt(&mut text, args);
+    if text.ends_with('\n') {
+        text.pop();
+    }
+    text
+}
+
+/// Last path segment of `module_path!()` ("akuma::ssh" -> "ssh")
+#[doc(hidden)]
+pub fn module_name(path: &'static str) -> &'static str {
+    path.rsplit("::").next().unwrap_or(path)
+}
+
+fn emit(level: Level, module: &'static str, event: Option<Event>, text: String) {
+    dispatch(Record {
+        seq: 0,
+        uptime_us: crate::timer::uptime_us(),
+        level,
+        module,
+        event,
+        text,
+    });
+}
+
+/// Number a record and hand it to every sink
+fn dispatch(mut record: Record) {
+    record.seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+
+    let sinks = with_irqs_disabled(|| *SINKS.lock());
+    for (_, sink) in sinks.iter().flatten() {
+        sink(&record);
+    }
+}
+
+// ============================================================================
+// IRQ Staging
+// ============================================================================
+//
+// An IRQ handler may have interrupted a thread halfway through printing a
+// line, or while it holds a sink's lock, so records logged in interrupt
+// context aren't handed to the sinks there. They are formatted into a free
+// slot of the current CPU's staging area instead - no locks, no heap - and
+// `flush_staged` later passes them on in the order they were logged, from
+// the flusher thread. Slots are claimed with a compare-exchange, so nested
+// handlers can stage at the same time. A record that finds no free slot is
+// dropped and counted; text longer than a slot is cut short with "...".
+
+/// CPUs with their own staging area (higher CPU numbers share the last)
+const STAGING_CPUS: usize = 4;
+
+/// Records each CPU can hold until the flusher runs (a starvation report
+/// is one line per thread)
+const STAGING_SLOTS: usize = 64;
+
+/// Bytes of text kept per staged record
+pub const STAGED_TEXT: usize = 120;
+
+const SLOT_FREE: u8 = 0;
+/// Owned by a producer filling it in, or by the flusher emptying it
+const SLOT_BUSY: u8 = 1;
+const SLOT_READY: u8 = 2;
+
+struct StagedRecord {
+    /// Position in staging order across all CPUs
+    order: u64,
+    uptime_us: u64,
+    level: Level,
+    module: &'static str,
+    event: Option<Event>,
+    len: usize,
+    text: [u8; STAGED_TEXT],
+}
+
+struct StagingSlot {
+    state: AtomicU8,
+    record: UnsafeCell<StagedRecord>,
+}
+
+// SAFETY: `record` is only accessed by whoever moved `state` to SLOT_BUSY,
+// and published with Release/Acquire on `state`
+unsafe impl Sync for StagingSlot {}
+
+impl StagingSlot {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(SLOT_FREE),
+            record: UnsafeCell::new(StagedRecord {
+                order: 0,
+                uptime_us: 0,
+                level: Level::Info,
+                module: "",
+                event: None,
+                len: 0,
+                text: [0; STAGED_TEXT],
+            }),
+        }
+    }
+}
+
+static STAGING: [[StagingSlot; STAGING_SLOTS]; STAGING_CPUS] =
+    [const { [const { StagingSlot::new() }; STAGING_SLOTS] }; STAGING_CPUS];
+
+static STAGE_ORDER: AtomicU64 = AtomicU64::new(0);
+static STAGED: AtomicU64 = AtomicU64::new(0);
+static STAGE_DROPS: AtomicU64 = AtomicU64::new(0);
+
+fn cpu_index() -> usize {
+    let mpidr: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, mpidr_el1", out(reg) mpidr);
+    }
+    ((mpidr & 0xff) as usize).min(STAGING_CPUS - 1)
+}
+
+/// Formats into a slot's text, cutting at a character boundary when full
+struct SlotWriter<'a> {
+    buf: &'a mut [u8; STAGED_TEXT],
+    len: usize,
+    truncated: bool,
+}
+
+impl core::fmt::Write for SlotWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if self.truncated {
+            return Ok(());
+        }
+        let room = STAGED_TEXT - self.len;
+        let mut take = s.len().min(room);
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.buf[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        if take < s.len() {
+            self.truncated = true;
+        }
+        Ok(())
+    }
+}
+
+/// Stage a record logged in interrupt context
+fn stage(level: Level, module: &'static str, event: Option<Event>, args: core::fmt::Arguments) {
+    let area = &STAGING[cpu_index()];
+    let Some(slot) = area.iter().find(|slot| {
+        slot.state
+            .compare_exchange(SLOT_FREE, SLOT_BUSY, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }) else {
+        STAGE_DROPS.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+
+    // SAFETY: the slot is ours until it is marked ready
+    let record = unsafe { &mut *slot.record.get() };
+    record.order = STAGE_ORDER.fetch_add(1, Ordering::Relaxed);
+    record.uptime_us = crate::timer::uptime_us();
+    record.level = level;
+    record.module = module;
+    record.event = event;
+    let mut writer = SlotWriter {
+        buf: &mut record.text,
+        len: 0,
+        truncated: false,
+    };
+    let _ = core::fmt::Write::write_fmt(&mut writer, args);
+    let (mut len, truncated) = (writer.len, writer.truncated);
+    if truncated {
+        // Make room for the marker without splitting a character
+        len = len.min(STAGED_TEXT - 3);
+        while len > 0 && (record.text[len] & 0xc0) == 0x80 {
+            len -= 1;
+        }
+        record.text[len..len + 3].copy_from_slice(b"...");
+        len += 3;
+    }
+    record.len = len;
+    STAGED.fetch_add(1, Ordering::Relaxed);
+    slot.state.store(SLOT_READY, Ordering::Release);
+}
+
+/// Pass staged records to the sinks, oldest first; returns how many
+///
+/// Call from thread context (the flusher does this continuously).
+pub fn flush_staged() -> usize {
+    // (staging order, record)
+    let mut records: Vec<(u64, Record)> = Vec::new();
+    for slot in STAGING.iter().flatten() {
+        if slot
+            .state
+            .compare_exchange(SLOT_READY, SLOT_BUSY, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            continue;
+        }
+        // SAFETY: the slot is ours until it is marked free
+        let record = unsafe { &*slot.record.get() };
+        let text = String::from_utf8_lossy(&record.text[..record.len]);
+        let text = String::from(text.strip_suffix('\n').unwrap_or(&text));
+        records.push((
+            record.order,
+            Record {
+                seq: 0,
+                uptime_us: record.uptime_us,
+                level: record.level,
+                module: record.module,
+                event: record.event,
+                text,
+            },
+        ));
+        slot.state.store(SLOT_FREE, Ordering::Release);
+    }
+
+    records.sort_unstable_by_key(|(order, _)| *order);
+    let count = records.len();
+    for (_, record) in records {
+        dispatch(record);
+    }
+    count
+}
+
+/// Records staged from interrupt context since boot, and how many of
+/// those were dropped because the staging area was full
+#[cfg(feature = "shell")]
+pub fn staging_stats() -> (u64, u64) {
+    (STAGED.load(Ordering::Relaxed), STAGE_DROPS.load(Ordering::Relaxed))
+}
+
+/// Pause between flusher passes
+const FLUSH_INTERVAL_MS: u64 = 10;
+
+/// Start the thread that flushes staged records
+///
+/// It runs in the background class, so it only runs while nothing else
+/// is ready, and sleeps between passes so the CPU can idle.
+pub fn start_flusher() -> KResult<usize> {
+    crate::threading::spawn_fn_background("klogd", || {
+        loop {
+            flush_staged();
+            crate::threading::sleep_ms(FLUSH_INTERVAL_MS);
+        }
+    })
+}
+
+/// Log at `level` from the calling module; arguments are only formatted
+/// if the level is enabled
+#[macro_export]
+macro_rules! klog {
+    ($level:expr, $($arg:tt)*) => {{
+        let module = $crate::klog::module_name(module_path!());
+        if $crate::klog::enabled($level, module) {
+            $crate::klog::log_args($level, module, format_args!($($arg)*));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! kerror {
+    ($($arg:tt)*) => { $crate::klog!($crate::klog::Level::Error, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! kwarn {
+    ($($arg:tt)*) => { $crate::klog!($crate::klog::Level::Warn, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! kinfo {
+    ($($arg:tt)*) => { $crate::klog!($crate::klog::Level::Info, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! kdebug {
+    ($($arg:tt)*) => { $crate::klog!($crate::klog::Level::Debug, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! ktrace {
+    ($($arg:tt)*) => { $crate::klog!($crate::klog::Level::Trace, $($arg)*) };
+}
@@ -0,0 +1,246 @@
+//! Kernel Objects
+//!
+//! Reference-counted identity for long-lived kernel things - devices,
+//! sockets, sessions, mounts - so the live hierarchy can be dumped with
+//! `kobj tree`. Each object has a type, a name and an optional parent;
+//! a child holds a reference to its parent, so a parent stays listed for
+//! as long as anything below it is alive. That makes "who still holds
+//! this socket" a matter of reading the tree.
+//!
+//! Owners keep an `Arc<KObject>` next to the resource it describes. The
+//! registry itself only holds weak references, except for objects created
+//! with `create_pinned` (devices), which live forever.
+
+use crate::defrag::{self, Hint};
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Run a closure with IRQs disabled so objects can be dropped from any context
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Types
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KObjType {
+    Device,
+    Socket,
+    Session,
+    Mount,
+}
+
+impl KObjType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KObjType::Device => "device",
+            KObjType::Socket => "socket",
+            KObjType::Session => "session",
+            KObjType::Mount => "mount",
+        }
+    }
+}
+
+/// A kernel object; share it as `Arc<KObject>`
+pub struct KObject {
+    id: u32,
+    ty: KObjType,
+    name: String,
+    parent: Option<Arc<KObject>>,
+}
+
+impl KObject {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl core::fmt::Debug for KObject {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "KObject#{}({} {})", self.id, self.ty.as_str(), self.name)
+    }
+}
+
+impl Drop for KObject {
+    fn drop(&mut self) {
+        let id = self.id;
+        with_irqs_disabled(|| {
+            let mut registry = REGISTRY.lock();
+            if let Some(pos) = registry.iter().position(|e| e.id == id) {
+                registry.swap_remove(pos);
+            }
+        });
+    }
+}
+
+/// Snapshot of one live object
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone)]
+pub struct KObjInfo {
+    pub id: u32,
+    pub ty: KObjType,
+    pub name: String,
+    pub parent: Option<u32>,
+    /// Strong references held by owners and children
+    pub refs: usize,
+}
+
+struct Entry {
+    id: u32,
+    object: Weak<KObject>,
+    /// Keeps pinned objects alive
+    pinned: Option<Arc<KObject>>,
+}
+
+static REGISTRY: Spinlock<Vec<Entry>> = Spinlock::new(Vec::new());
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+// ============================================================================
+// Creation and Lookup
+// ============================================================================
+
+fn insert(
+    ty: KObjType,
+    name: &str,
+    parent: Option<&Arc<KObject>>,
+    pinned: bool,
+) -> KResult<Arc<KObject>> {
+    let mut owned_name = String::new();
+    owned_name
+        .try_reserve(name.len())
+        .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "kobject"))?;
+    owned_name.push_str(name);
+
+    let object = Arc::new(KObject {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        ty,
+        name: owned_name,
+        parent: parent.cloned(),
+    });
+
+    let entry = Entry {
+        id: object.id,
+        object: Arc::downgrade(&object),
+        pinned: pinned.then(|| object.clone()),
+    };
+    let registered = with_irqs_disabled(|| {
+        let mut registry = REGISTRY.lock();
+        if registry.try_reserve(1).is_err() {
+            return Err(entry);
+        }
+        registry.push(entry);
+        Ok(())
+    });
+
+    match registered {
+        Ok(()) => Ok(object),
+        // Dropping the entry (and object) outside the lock
+        Err(_) => Err(KError::with_context(ErrorKind::OutOfMemory, "kobject registry")),
+    }
+}
+
+/// Create an object, listed while any `Arc` to it is alive
+pub fn create(ty: KObjType, name: &str, parent: Option<&Arc<KObject>>) -> KResult<Arc<KObject>> {
+    insert(ty, name, parent, false)
+}
+
+/// Create an object that is never freed (devices present for the whole uptime)
+pub fn create_pinned(
+    ty: KObjType,
+    name: &str,
+    parent: Option<&Arc<KObject>>,
+) -> KResult<Arc<KObject>> {
+    insert(ty, name, parent, true)
+}
+
+/// First live object with this type and name
+pub fn find(ty: KObjType, name: &str) -> Option<Arc<KObject>> {
+    with_irqs_disabled(|| {
+        REGISTRY
+            .lock()
+            .iter()
+            .filter_map(|e| e.object.upgrade())
+            .find(|o| o.ty == ty && o.name == name)
+    })
+}
+
+/// Snapshot of every live object, oldest first
+#[cfg(feature = "shell")]
+pub fn list() -> Vec<KObjInfo> {
+    let mut out = with_irqs_disabled(|| {
+        let registry = REGISTRY.lock();
+        let mut out = Vec::new();
+        if out.try_reserve(registry.len()).is_err() {
+            return out;
+        }
+        for entry in registry.iter() {
+            // Nothing else runs while the lock is held, so this temporary
+            // reference is never the last one and can't re-enter Drop
+            if let Some(object) = entry.object.upgrade() {
+                let pinned = entry.pinned.is_some() as usize;
+                out.push(KObjInfo {
+                    id: object.id,
+                    ty: object.ty,
+                    name: object.name.clone(),
+                    parent: object.parent.as_ref().map(|p| p.id),
+                    refs: Arc::strong_count(&object) - 1 - pinned,
+                });
+            }
+        }
+        out
+    });
+    out.sort_by_key(|info| info.id);
+    out
+}
+
+/// Render the object hierarchy, one object per line, children indented
+///
+/// Lines end with `newline` so the output suits both the console and SSH.
+#[cfg(feature = "shell")]
+pub fn tree(newline: &str) -> String {
+    let objects = list();
+    let mut out = String::new();
+
+    fn walk(objects: &[KObjInfo], parent: Option<u32>, depth: usize, newline: &str, out: &mut String) {
+        for info in objects.iter().filter(|o| o.parent == parent) {
+            out.push_str(&alloc::format!(
+                "{:indent$}{} #{} {} (refs {}){}",
+                "",
+                info.ty.as_str(),
+                info.id,
+                info.name,
+                info.refs,
+                newline,
+                indent = depth * 2
+            ));
+            walk(objects, Some(info.id), depth + 1, newline, out);
+        }
+    }
+
+    walk(&objects, None, 0, newline, &mut out);
+    out
+}
+
+/// Defrag hint handler: compact the registry
+pub fn defrag(hint: Hint) -> usize {
+    match hint {
+        Hint::DropCaches => 0,
+        Hint::Reallocate => with_irqs_disabled(|| defrag::reallocate_vec(&mut REGISTRY.lock())),
+    }
+}
@@ -0,0 +1,227 @@
+//! Latency Histograms
+//!
+//! HDR-style histograms for the delays that decide how responsive the
+//! kernel is, so scheduler and executor changes can be judged by their
+//! tail latency:
+//!
+//! - IRQ to wake: from the timer IRQ at (or after) an embassy alarm's
+//!   deadline until the alarm's waker is invoked
+//! - wake to run: from a thread becoming ready until it's switched in
+//! - IRQ to bottom half: from a softirq being raised until it runs
+//!
+//! Buckets are log-linear: each power of two is split into
+//! `SUB_BUCKETS` linear steps, so every recorded value is off by at most
+//! 1/8 of itself. Recording is lock-free and safe from IRQ context.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Linear steps per power of two (as a bit count)
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+
+/// Values up to 2^32 ns (~4.3 s) get their own bucket; larger ones are clamped
+const MAX_MAGNITUDE: u32 = 32;
+const BUCKETS: usize = (MAX_MAGNITUDE - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS;
+
+// ============================================================================
+// Histogram
+// ============================================================================
+
+pub struct Histogram {
+    counts: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+/// Percentiles and extremes of a histogram, in nanoseconds
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Summary {
+    pub count: u64,
+    pub mean: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let magnitude = (63 - value.leading_zeros()).min(MAX_MAGNITUDE - 1);
+    let value = value.min((1 << MAX_MAGNITUDE) - 1);
+    let sub = (value >> (magnitude - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    (magnitude - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + sub
+}
+
+/// Highest value that lands in bucket `index`
+#[cfg(feature = "shell")]
+fn bucket_upper(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let magnitude = (index / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+    let sub = (index % SUB_BUCKETS) as u64;
+    let step = 1u64 << (magnitude - SUB_BUCKET_BITS);
+    ((SUB_BUCKETS as u64 + sub) << (magnitude - SUB_BUCKET_BITS)) + step - 1
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            counts: [const { AtomicU64::new(0) }; BUCKETS],
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    /// Add one sample
+    pub fn record(&self, ns: u64) {
+        self.counts[bucket_index(ns)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(ns, Ordering::Relaxed);
+        self.max.fetch_max(ns, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "shell")]
+    pub fn reset(&self) {
+        for c in self.counts.iter() {
+            c.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.sum.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+
+    /// Smallest bucket bound at or below which `per_mille`/1000 of the samples fall
+    #[cfg(feature = "shell")]
+    fn percentile(&self, counts: &[u64; BUCKETS], total: u64, per_mille: u64) -> u64 {
+        let target = (total * per_mille).div_ceil(1000).max(1);
+        let mut seen = 0;
+        for (i, &n) in counts.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return bucket_upper(i);
+            }
+        }
+        0
+    }
+
+    #[cfg(feature = "shell")]
+    pub fn summary(&self) -> Summary {
+        let counts: [u64; BUCKETS] =
+            core::array::from_fn(|i| self.counts[i].load(Ordering::Relaxed));
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return Summary::default();
+        }
+        let max = self.max.load(Ordering::Relaxed);
+        Summary {
+            count: total,
+            mean: self.sum.load(Ordering::Relaxed) / self.count.load(Ordering::Relaxed).max(1),
+            p50: self.percentile(&counts, total, 500).min(max),
+            p90: self.percentile(&counts, total, 900).min(max),
+            p99: self.percentile(&counts, total, 990).min(max),
+            p999: self.percentile(&counts, total, 999).min(max),
+            max,
+        }
+    }
+}
+
+// ============================================================================
+// Kernel Metrics
+// ============================================================================
+
+/// Which delay a histogram measures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    IrqToWake,
+    WakeToRun,
+    IrqToBottomHalf,
+}
+
+impl Metric {
+    #[cfg(feature = "shell")]
+    pub const ALL: [Metric; 3] = [Metric::IrqToWake, Metric::WakeToRun, Metric::IrqToBottomHalf];
+
+    #[cfg(feature = "shell")]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Metric::IrqToWake => "irq->wake",
+            Metric::WakeToRun => "wake->run",
+            Metric::IrqToBottomHalf => "irq->bh",
+        }
+    }
+
+    fn histogram(&self) -> &'static Histogram {
+        match self {
+            Metric::IrqToWake => &IRQ_TO_WAKE,
+            Metric::WakeToRun => &WAKE_TO_RUN,
+            Metric::IrqToBottomHalf => &IRQ_TO_BOTTOM_HALF,
+        }
+    }
+}
+
+static IRQ_TO_WAKE: Histogram = Histogram::new();
+static WAKE_TO_RUN: Histogram = Histogram::new();
+static IRQ_TO_BOTTOM_HALF: Histogram = Histogram::new();
+
+/// Counter value at the first IRQ since alarms were last checked (0 = none)
+static FIRST_IRQ_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub fn record(metric: Metric, ns: u64) {
+    metric.histogram().record(ns);
+}
+
+#[cfg(feature = "shell")]
+pub fn summary(metric: Metric) -> Summary {
+    metric.histogram().summary()
+}
+
+/// Clear all histograms
+#[cfg(feature = "shell")]
+pub fn reset() {
+    for metric in Metric::ALL {
+        metric.histogram().reset();
+    }
+}
+
+/// Note IRQ entry (called first thing in the IRQ handler)
+#[inline]
+pub fn irq_entered() {
+    let now = crate::timer::read_counter();
+    let _ = FIRST_IRQ_COUNTER.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/// Start of an alarm check: returns the first IRQ since the last check
+pub fn take_irq_entry() -> u64 {
+    FIRST_IRQ_COUNTER.swap(0, Ordering::Relaxed)
+}
+
+/// Record an alarm waker being invoked
+///
+/// `deadline` is the alarm time and `irq` the first IRQ entry since the
+/// previous check (both in counter ticks). The delay runs from whichever
+/// is later: an alarm polled before any IRQ arrived still counts from its
+/// deadline.
+pub fn record_alarm_wake(deadline: u64, irq: u64) {
+    let now = crate::timer::read_counter();
+    let start = deadline.max(irq);
+    record(Metric::IrqToWake, crate::timer::cycles_to_ns(now.saturating_sub(start)));
+}
+
+/// Format a nanosecond value with a readable unit
+#[cfg(feature = "shell")]
+pub fn format_ns(ns: u64) -> alloc::string::String {
+    if ns < 10_000 {
+        alloc::format!("{}ns", ns)
+    } else if ns < 10_000_000 {
+        alloc::format!("{}us", ns / 1000)
+    } else {
+        alloc::format!("{}ms", ns / 1_000_000)
+    }
+}
@@ -0,0 +1,233 @@
+//! Line Editor
+//!
+//! Readline-style editing for interactive input that arrives one byte at a
+//! time (the serial console). Keys are decoded with `ansi::AnsiParser`; the
+//! editor keeps the line and cursor and writes the terminal updates needed
+//! to keep the screen in step into a caller-supplied buffer, so it doesn't
+//! care where its bytes come from or go.
+//!
+//! Supported keys: Left/Right (Ctrl-B/F), Home/End (Ctrl-A/E), Backspace,
+//! Delete, Ctrl-U (kill to start), Ctrl-K (kill to end), Up/Down (Ctrl-P/N)
+//! through history, Ctrl-C (discard line), Ctrl-D (end of input on an
+//! empty line).
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::ansi::{self, AnsiParser, Key};
+
+/// Longest line accepted (further input is ignored)
+pub const MAX_LINE: usize = 256;
+
+/// Lines kept in history
+pub const HISTORY_SIZE: usize = 32;
+
+/// Outcome of a key that ended editing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit {
+    /// Enter was pressed (line without terminator)
+    Line(Vec<u8>),
+    /// Ctrl-C: the line was discarded
+    Interrupt,
+    /// Ctrl-D on an empty line
+    Eof,
+}
+
+pub struct LineEditor {
+    parser: AnsiParser,
+    line: Vec<u8>,
+    /// Byte offset of the cursor in `line`
+    cursor: usize,
+    history: VecDeque<Vec<u8>>,
+    /// History entry being shown (None = the line being typed)
+    browsing: Option<usize>,
+    /// Line being typed, kept while browsing history
+    stash: Vec<u8>,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self {
+            parser: AnsiParser::new(),
+            line: Vec::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            browsing: None,
+            stash: Vec::new(),
+        }
+    }
+
+    pub fn line(&self) -> &[u8] {
+        &self.line
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// History, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &[u8]> {
+        self.history.iter().map(|l| l.as_slice())
+    }
+
+    /// Add a line to history (empty lines and repeats are skipped)
+    pub fn push_history(&mut self, line: &[u8]) {
+        if line.is_empty() || self.history.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(line.to_vec());
+    }
+
+    /// Feed one input byte, appending terminal output to `out`
+    ///
+    /// Returns Some once a line is finished; the editor is then ready for
+    /// the next one.
+    pub fn feed(&mut self, byte: u8, out: &mut Vec<u8>) -> Option<Edit> {
+        let key = self.parser.feed(byte)?;
+        match key {
+            Key::Char(c) => self.insert(c, out),
+            Key::Enter => {
+                out.extend_from_slice(b"\r\n");
+                let line = self.take_line();
+                self.push_history(&line);
+                return Some(Edit::Line(line));
+            }
+            Key::Ctrl(b'c') => {
+                out.extend_from_slice(b"^C\r\n");
+                self.take_line();
+                return Some(Edit::Interrupt);
+            }
+            Key::Ctrl(b'd') if self.line.is_empty() => return Some(Edit::Eof),
+            Key::Backspace => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    out.push(0x08);
+                    self.delete_at_cursor(out);
+                }
+            }
+            Key::Delete | Key::Ctrl(b'd') => {
+                if self.cursor < self.line.len() {
+                    self.delete_at_cursor(out);
+                }
+            }
+            Key::Left | Key::Ctrl(b'b') => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    ansi::cursor_back(out, 1);
+                }
+            }
+            Key::Right | Key::Ctrl(b'f') => {
+                if self.cursor < self.line.len() {
+                    self.cursor += 1;
+                    ansi::cursor_forward(out, 1);
+                }
+            }
+            Key::Home | Key::Ctrl(b'a') => self.move_to(0, out),
+            Key::End | Key::Ctrl(b'e') => self.move_to(self.line.len(), out),
+            Key::Ctrl(b'u') if self.cursor > 0 => {
+                let start = self.cursor;
+                self.move_to(0, out);
+                self.line.drain(..start);
+                self.redraw_tail(start, out);
+            }
+            Key::Ctrl(b'k') => {
+                self.line.truncate(self.cursor);
+                out.extend_from_slice(ansi::ERASE_TO_EOL);
+            }
+            Key::Up | Key::Ctrl(b'p') => self.browse_older(out),
+            Key::Down | Key::Ctrl(b'n') => self.browse_newer(out),
+            _ => {}
+        }
+        None
+    }
+
+    fn take_line(&mut self) -> Vec<u8> {
+        self.cursor = 0;
+        self.browsing = None;
+        self.stash.clear();
+        core::mem::take(&mut self.line)
+    }
+
+    fn insert(&mut self, c: u8, out: &mut Vec<u8>) {
+        if self.line.len() >= MAX_LINE {
+            return;
+        }
+        self.line.insert(self.cursor, c);
+        self.cursor += 1;
+        out.push(c);
+        // Shift the rest of the line right
+        let tail = &self.line[self.cursor..];
+        out.extend_from_slice(tail);
+        ansi::cursor_back(out, tail.len() as u16);
+    }
+
+    /// Remove the byte under the cursor (screen cursor already there)
+    fn delete_at_cursor(&mut self, out: &mut Vec<u8>) {
+        self.line.remove(self.cursor);
+        self.redraw_tail(1, out);
+    }
+
+    /// Rewrite the line from the cursor after it shrank by `removed` bytes,
+    /// leaving the screen cursor where it was
+    fn redraw_tail(&self, removed: usize, out: &mut Vec<u8>) {
+        let tail = &self.line[self.cursor..];
+        out.extend_from_slice(tail);
+        if removed == 1 {
+            out.push(b' ');
+        } else {
+            out.extend_from_slice(ansi::ERASE_TO_EOL);
+        }
+        let back = tail.len() + (removed == 1) as usize;
+        ansi::cursor_back(out, back as u16);
+    }
+
+    fn move_to(&mut self, pos: usize, out: &mut Vec<u8>) {
+        if pos < self.cursor {
+            ansi::cursor_back(out, (self.cursor - pos) as u16);
+        } else if pos > self.cursor {
+            ansi::cursor_forward(out, (pos - self.cursor) as u16);
+        }
+        self.cursor = pos;
+    }
+
+    /// Replace the whole line on screen and in the buffer
+    fn replace_line(&mut self, line: Vec<u8>, out: &mut Vec<u8>) {
+        self.move_to(0, out);
+        out.extend_from_slice(ansi::ERASE_TO_EOL);
+        out.extend_from_slice(&line);
+        self.cursor = line.len();
+        self.line = line;
+    }
+
+    fn browse_older(&mut self, out: &mut Vec<u8>) {
+        let index = match self.browsing {
+            Some(0) => return,
+            Some(i) => i - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.stash = self.line.clone();
+                self.history.len() - 1
+            }
+        };
+        self.browsing = Some(index);
+        self.replace_line(self.history[index].clone(), out);
+    }
+
+    fn browse_newer(&mut self, out: &mut Vec<u8>) {
+        let line = match self.browsing {
+            None => return,
+            Some(i) if i + 1 < self.history.len() => {
+                self.browsing = Some(i + 1);
+                self.history[i + 1].clone()
+            }
+            Some(_) => {
+                self.browsing = None;
+                core::mem::take(&mut self.stash)
+            }
+        };
+        self.replace_line(line, out);
+    }
+}
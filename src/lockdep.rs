@@ -0,0 +1,412 @@
+//! Lock Dependency Checking
+//!
+//! A deadlock between two locks needs two code paths that take them in
+//! opposite orders, and usually only shows once both run at the same
+//! moment. With the `lockdep` feature every acquisition is checked against
+//! the orders seen so far instead: taking B while holding A records "A
+//! before B", and taking A while holding B later panics with both chains -
+//! the locks the thread holds and the recorded path from B back to A - the
+//! first time the inverted path runs rather than the first time it hangs.
+//!
+//! Locks are grouped into classes by type (`Spinlock<Tracker>`,
+//! `threading::Mutex<Queue>`), so every instance of a per-connection lock
+//! shares one class. Nesting two locks of one class isn't checked; locking
+//! the same lock twice is. Interrupt handlers count as part of the thread
+//! they interrupted.
+//!
+//! `Spinlock` here is what the rest of the kernel locks with: plain
+//! `spinning_top::Spinlock` without the feature, a checked wrapper with
+//! it. `threading::Mutex` reports to `acquire`/`release` itself.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::threading::MAX_THREADS;
+
+#[cfg(not(feature = "lockdep"))]
+pub use spinning_top::Spinlock;
+
+/// Whether acquisitions are checked (the `lockdep` feature)
+pub const ENABLED: bool = cfg!(feature = "lockdep");
+
+/// Lock classes tracked at most; locks of later ones go unchecked
+const MAX_CLASSES: usize = 128;
+
+/// Locks one thread can hold and still be checked
+const MAX_HELD: usize = 16;
+
+const _: () = assert!(MAX_CLASSES <= 128, "dependencies are u128 masks");
+
+/// Set once a report is made, so the panic path's own locking isn't
+/// checked against a graph that is known to be inconsistent
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Acquisitions of locks whose class didn't fit in the table
+static UNTRACKED: AtomicU64 = AtomicU64::new(0);
+
+/// Run a closure with IRQs disabled
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Dependency Graph
+// ============================================================================
+
+#[derive(Clone, Copy)]
+struct Held {
+    class: u8,
+    addr: usize,
+}
+
+struct Graph {
+    names: [&'static str; MAX_CLASSES],
+    classes: usize,
+    /// Bit `b` of `after[a]`: class `b` was taken while `a` was held
+    after: [u128; MAX_CLASSES],
+    /// Locks each thread holds, oldest first
+    held: [[Held; MAX_HELD]; MAX_THREADS],
+    /// May exceed `MAX_HELD`; the locks past it aren't recorded
+    depth: [usize; MAX_THREADS],
+}
+
+/// The checker's own lock: a plain spinlock, so it isn't checked itself
+static GRAPH: spinning_top::Spinlock<Graph> = spinning_top::Spinlock::new(Graph {
+    names: [""; MAX_CLASSES],
+    classes: 0,
+    after: [0; MAX_CLASSES],
+    held: [[Held { class: 0, addr: 0 }; MAX_HELD]; MAX_THREADS],
+    depth: [0; MAX_THREADS],
+});
+
+impl Graph {
+    fn find(&self, name: &str) -> Option<usize> {
+        self.names[..self.classes].iter().position(|n| *n == name)
+    }
+
+    /// Index of class `name`, added if new (None once the table is full)
+    fn class(&mut self, name: &'static str) -> Option<usize> {
+        if let Some(class) = self.find(name) {
+            return Some(class);
+        }
+        if self.classes == MAX_CLASSES {
+            return None;
+        }
+        self.names[self.classes] = name;
+        self.classes += 1;
+        Some(self.classes - 1)
+    }
+
+    /// Shortest recorded chain of classes from `from` to `to`, written to
+    /// `out`; returns its length
+    fn path(&self, from: usize, to: usize, out: &mut [u8; MAX_CLASSES]) -> Option<usize> {
+        let mut prev = [u8::MAX; MAX_CLASSES];
+        let mut queue = [0u8; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        let mut seen: u128 = 1 << from;
+        queue[0] = from as u8;
+        while head < tail && seen & (1 << to) == 0 {
+            let a = queue[head] as usize;
+            head += 1;
+            let mut next = self.after[a] & !seen;
+            while next != 0 {
+                let b = next.trailing_zeros() as usize;
+                next &= next - 1;
+                seen |= 1 << b;
+                prev[b] = a as u8;
+                queue[tail] = b as u8;
+                tail += 1;
+            }
+        }
+        if seen & (1 << to) == 0 {
+            return None;
+        }
+        // Walk back from `to`, then reverse
+        let mut len = 0;
+        let mut at = to;
+        loop {
+            out[len] = at as u8;
+            len += 1;
+            if at == from {
+                break;
+            }
+            at = prev[at] as usize;
+        }
+        out[..len].reverse();
+        Some(len)
+    }
+
+    fn report(&self, tid: usize, acquiring: usize) -> Report {
+        let mut report = Report {
+            tid,
+            acquiring: self.names[acquiring],
+            held: [""; MAX_HELD],
+            held_len: self.depth[tid].min(MAX_HELD),
+            chain: [""; MAX_CLASSES],
+            chain_len: 0,
+        };
+        for (name, held) in report.held.iter_mut().zip(&self.held[tid][..report.held_len]) {
+            *name = self.names[held.class as usize];
+        }
+        report
+    }
+}
+
+/// Why an acquisition was refused, displayed as the panic message
+struct Report {
+    tid: usize,
+    acquiring: &'static str,
+    held: [&'static str; MAX_HELD],
+    held_len: usize,
+    /// Recorded order from `acquiring` to a held class (empty: the lock
+    /// itself is already held)
+    chain: [&'static str; MAX_CLASSES],
+    chain_len: usize,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.chain_len == 0 {
+            writeln!(f, "lockdep: thread {} locks {} again", self.tid, self.acquiring)?;
+        } else {
+            writeln!(
+                f,
+                "lockdep: thread {} takes {} while holding {}, against the order seen before",
+                self.tid,
+                self.acquiring,
+                self.chain[self.chain_len - 1]
+            )?;
+        }
+        writeln!(f, "Held by this thread, oldest first:")?;
+        for name in &self.held[..self.held_len] {
+            writeln!(f, "  {}", name)?;
+        }
+        writeln!(f, "  -> {}", self.acquiring)?;
+        if self.chain_len > 0 {
+            writeln!(f, "Recorded before:")?;
+            writeln!(f, "  {}", self.chain[0])?;
+            for name in &self.chain[1..self.chain_len] {
+                writeln!(f, "  -> {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Hooks
+// ============================================================================
+
+/// Check taking lock `addr` of class `name` against the locks the current
+/// thread holds, then record it as held
+///
+/// Call before spinning or blocking on the lock, so an inverted order
+/// panics instead of hanging. A successful try-lock (`trylock`) can't
+/// deadlock, so it is only recorded.
+pub fn acquire(name: &'static str, addr: usize, trylock: bool) {
+    if !ENABLED || REPORTING.load(Ordering::Relaxed) {
+        return;
+    }
+    let tid = crate::threading::tls_tid();
+    let report = with_irqs_disabled(|| {
+        let mut graph = GRAPH.lock();
+        let Some(class) = graph.class(name) else {
+            UNTRACKED.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let depth = graph.depth[tid];
+        let checked = if trylock { 0 } else { depth.min(MAX_HELD) };
+        for i in 0..checked {
+            let held = graph.held[tid][i];
+            let held_class = held.class as usize;
+            if held.addr == addr {
+                return Some(graph.report(tid, class));
+            }
+            if held_class == class || graph.after[held_class] & (1 << class) != 0 {
+                continue;
+            }
+            let mut chain = [0u8; MAX_CLASSES];
+            if let Some(len) = graph.path(class, held_class, &mut chain) {
+                let mut report = graph.report(tid, class);
+                for (name, &c) in report.chain.iter_mut().zip(&chain[..len]) {
+                    *name = graph.names[c as usize];
+                }
+                report.chain_len = len;
+                return Some(report);
+            }
+            graph.after[held_class] |= 1 << class;
+        }
+        if depth < MAX_HELD {
+            graph.held[tid][depth] = Held {
+                class: class as u8,
+                addr,
+            };
+        }
+        graph.depth[tid] = depth + 1;
+        None
+    });
+    if let Some(report) = report {
+        REPORTING.store(true, Ordering::Relaxed);
+        panic!("{}", report);
+    }
+}
+
+/// Record lock `addr` as released by the current thread
+pub fn release(addr: usize) {
+    if !ENABLED || REPORTING.load(Ordering::Relaxed) {
+        return;
+    }
+    let tid = crate::threading::tls_tid();
+    with_irqs_disabled(|| {
+        let mut graph = GRAPH.lock();
+        let graph = &mut *graph;
+        let depth = graph.depth[tid];
+        let stored = depth.min(MAX_HELD);
+        match graph.held[tid][..stored].iter().rposition(|h| h.addr == addr) {
+            Some(i) => {
+                graph.held[tid].copy_within(i + 1..stored, i);
+                graph.depth[tid] = depth - 1;
+            }
+            // One of the unrecorded ones past MAX_HELD
+            None if depth > MAX_HELD => graph.depth[tid] = depth - 1,
+            // Untracked class, or taken by another thread
+            None => {}
+        }
+    });
+}
+
+/// Forget what thread `tid` held (an exit hook: a killed thread's locks
+/// aren't the next thread's in that slot)
+fn forget_thread(tid: usize) {
+    with_irqs_disabled(|| GRAPH.lock().depth[tid] = 0);
+}
+
+/// Hook the checker into thread exit
+pub fn init() {
+    if !ENABLED {
+        return;
+    }
+    if let Err(e) = crate::threading::register_exit_hook("lockdep", forget_thread) {
+        crate::kwarn!("[Lockdep] Exit hook not registered: {}", e);
+    }
+}
+
+// ============================================================================
+// Queries
+// ============================================================================
+
+/// Whether taking a lock of class `acquiring` while holding one of class
+/// `holding` would go against a recorded order
+pub fn inversion(holding: &str, acquiring: &str) -> bool {
+    with_irqs_disabled(|| {
+        let graph = GRAPH.lock();
+        match (graph.find(holding), graph.find(acquiring)) {
+            (Some(h), Some(a)) if h != a => graph.path(a, h, &mut [0; MAX_CLASSES]).is_some(),
+            _ => false,
+        }
+    })
+}
+
+#[cfg(all(feature = "fs", feature = "shell"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LockdepStats {
+    pub classes: usize,
+    /// Recorded "A before B" pairs
+    pub dependencies: usize,
+    /// Acquisitions not checked because the class table was full
+    pub untracked: u64,
+}
+
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn stats() -> LockdepStats {
+    with_irqs_disabled(|| {
+        let graph = GRAPH.lock();
+        LockdepStats {
+            classes: graph.classes,
+            dependencies: graph.after.iter().map(|a| a.count_ones() as usize).sum(),
+            untracked: UNTRACKED.load(Ordering::Relaxed),
+        }
+    })
+}
+
+// ============================================================================
+// Checked Spinlock
+// ============================================================================
+
+/// `spinning_top::Spinlock` that reports to the checker
+#[cfg(feature = "lockdep")]
+pub struct Spinlock<T: ?Sized> {
+    inner: spinning_top::Spinlock<T>,
+}
+
+#[cfg(feature = "lockdep")]
+impl<T> Spinlock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: spinning_top::Spinlock::new(value),
+        }
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl<T: ?Sized> Spinlock<T> {
+    fn addr(&self) -> usize {
+        (self as *const Self).cast::<u8>() as usize
+    }
+
+    pub fn lock(&self) -> SpinlockGuard<'_, T> {
+        acquire(core::any::type_name::<Self>(), self.addr(), false);
+        SpinlockGuard {
+            guard: self.inner.lock(),
+            addr: self.addr(),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        acquire(core::any::type_name::<Self>(), self.addr(), true);
+        Some(SpinlockGuard {
+            guard,
+            addr: self.addr(),
+        })
+    }
+}
+
+#[cfg(feature = "lockdep")]
+pub struct SpinlockGuard<'a, T: ?Sized> {
+    guard: spinning_top::guard::SpinlockGuard<'a, T>,
+    addr: usize,
+}
+
+#[cfg(feature = "lockdep")]
+impl<T: ?Sized> core::ops::Deref for SpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl<T: ?Sized> core::ops::DerefMut for SpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl<T: ?Sized> Drop for SpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        release(self.addr);
+    }
+}
@@ -1,35 +1,97 @@
 #![no_std]
 #![no_main]
-#![feature(never_type)]
 
 extern crate alloc;
 
 mod akuma;
 mod allocator;
+mod ansi;
 mod async_net;
+#[cfg(feature = "tests")]
 mod async_tests;
+#[cfg(feature = "shell")]
+mod auth;
+mod backtrace;
 mod boot;
+mod config;
+#[cfg(feature = "ssh")]
+mod conn_budget;
 mod console;
+mod crashdump;
+#[cfg(feature = "shell")]
+mod ctl_server;
+mod defrag;
+mod dmesg;
+mod driver;
 mod embassy_net_driver;
 mod embassy_time_driver;
 mod embassy_virtio_driver;
+mod error;
+mod events;
 mod exceptions;
 mod executor;
 mod gic;
+mod handles;
+#[cfg(feature = "http")]
+mod http_server;
 mod irq;
+mod klog;
+mod kobject;
+mod latency;
+mod line_edit;
+mod lockdep;
+mod mmu;
+mod netboot;
 mod netcat_server;
 mod network;
+mod pl011;
+mod pmm;
+mod probe;
+mod psci;
+mod replay;
+mod sched;
+mod sched_policy;
+mod secret;
+#[cfg(feature = "shell")]
+mod shell;
+mod shm;
+mod slab;
+mod softirq;
+#[cfg(feature = "ssh")]
 mod ssh;
+#[cfg(feature = "ssh")]
 mod ssh_crypto;
+#[cfg(feature = "ssh")]
 mod ssh_server;
+#[cfg(all(feature = "fs", feature = "shell"))]
+mod sysreport;
+#[cfg(feature = "tests")]
 mod tests;
 mod threading;
 mod timer;
+#[cfg(feature = "tls")]
+mod tls;
+mod uart;
+#[cfg(feature = "fs")]
+mod vfs;
+mod virtio_console;
 mod virtio_hal;
-
-use alloc::string::ToString;
-
+mod vmm;
+mod workers;
+mod workqueue;
+#[cfg(feature = "tls")]
+mod x509;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::Future;
 use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use error::Subsystem;
+use events::Event;
 
 /// Halt the CPU in a low-power wait loop. Safe wrapper around wfi.
 #[inline]
@@ -43,63 +105,210 @@ fn halt() -> ! {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // Mask IRQs so nothing interleaves with the report
+    // SAFETY: only changes the interrupt mask
+    unsafe { core::arch::asm!("msr daifset, #2") };
+
+    // Capture into RAM first - printing may fail if we panicked in the console
+    let backtrace = backtrace::Backtrace::capture();
+    let report = crashdump::record_panic(info, &backtrace);
+
+    // The report is already in the crash region, which survives the reset
+    let action = crashdump::panic_action();
+    if action == crashdump::PanicAction::Reset {
+        psci::system_reset();
+        halt()
+    }
+
+    // Stop relying on the TX interrupt - it may never fire again
+    console::panic_flush();
     console::print("\n\n!!! PANIC !!!\n");
-    if let Some(location) = info.location() {
-        console::print("Location: ");
-        console::print(location.file());
-        console::print(":");
-        console::print(&location.line().to_string());
-        console::print("\n");
+    console::print(report);
+
+    if action == crashdump::PanicAction::DumpReset {
+        let delay = crashdump::reset_delay_s();
+        println!("Resetting in {} s", delay);
+        timer::delay_ms(delay as u64 * 1000);
+        let err = psci::system_reset();
+        println!("PSCI reset failed ({}), halting", err);
     }
-    console::print("Message: ");
-    console::print(&alloc::format!("{}\n", info.message()));
     halt()
 }
 
 /// Minimal unsafe entry point - immediately delegates to safe kernel_main
 #[unsafe(no_mangle)]
-pub extern "C" fn rust_start(_dtb_ptr: usize) -> ! {
-    kernel_main()
+pub extern "C" fn rust_start(dtb_ptr: usize) -> ! {
+    kernel_main(dtb_ptr)
 }
 
 /// Main kernel initialization - all safe code
-fn kernel_main() -> ! {
+fn kernel_main(dtb_ptr: usize) -> ! {
     const RAM_BASE: usize = 0x40000000;
 
+    // Don't rely on firmware having set up the UART; settings from the
+    // command line are applied once the heap is up
+    let _ = console::init_uart(&pl011::UartConfig::DEFAULT);
+
     let ram_size = 128 * 1024 * 1024; // 128 MB
 
     let code_and_stack = ram_size / 16; // 1/16 of total RAM
     let heap_start = RAM_BASE + code_and_stack;
 
-    let heap_size = if ram_size > code_and_stack {
-        ram_size - code_and_stack
-    } else {
+    if ram_size <= code_and_stack + mmu::DMA_POOL_SIZE {
         console::print("Not enough RAM for heap\n");
         halt();
+    }
+
+    // Map the kernel W^X and turn on caches. No heap yet, so only report
+    // the outcome once the allocator is up.
+    let mmu_result = mmu::init(ram_size, heap_start);
+
+    // Copy the command line out of the DTB before the page allocator reuses its RAM
+    let have_cmdline = config::init(dtb_ptr, RAM_BASE..RAM_BASE + ram_size);
+
+    // All RAM above the kernel goes to the page allocator, the heap grows
+    // from it, except the DMA pool at the top
+    let dma_pool = mmu::dma_pool_base(ram_size);
+    if let Err(e) = pmm::init(heap_start, dma_pool - heap_start)
+        .and_then(|_| pmm::init_dma(dma_pool, mmu::DMA_POOL_SIZE))
+    {
+        println!("Page allocator init failed: {}", e);
+        halt();
+    }
+
+    if let Err(e) = allocator::init() {
+        println!("Allocator init failed: {}", e);
+        halt();
+    }
+
+    let default_uart = pl011::UartConfig::DEFAULT;
+    let uart = pl011::UartConfig {
+        baud: config::get_u64("uart.baud")
+            .map_or(default_uart.baud, |b| u32::try_from(b).unwrap_or(0)),
+        clock_hz: config::get_u64("uart.clock_hz")
+            .map_or(default_uart.clock_hz, |c| u32::try_from(c).unwrap_or(0)),
+        flow_control: config::get_bool("uart.flow_control").unwrap_or(false),
     };
+    if uart != default_uart {
+        match console::init_uart(&uart) {
+            Ok(()) => println!(
+                "UART: {} baud{}",
+                console::uart_baud_rate(),
+                if uart.flow_control { ", RTS/CTS" } else { "" }
+            ),
+            Err(e) => println!("UART settings rejected: {}", e),
+        }
+    }
+
+    println!(
+        "Page allocator initialized: {} MB (+{} KB DMA pool)",
+        (dma_pool - heap_start) / 1024 / 1024,
+        mmu::DMA_POOL_SIZE / 1024
+    );
+    kevent!(
+        Event::BootMemoryReady,
+        "Heap initialized: {} MB (grows on demand)",
+        allocator::stats().total / 1024 / 1024
+    );
+
+    match mmu_result {
+        Ok(()) => console::print("MMU enabled: .text r-x, .rodata r--, data rw- (WXN)\n"),
+        Err(e) => {
+            println!("MMU init failed, running without protection: {}", e);
+        }
+    }
+
+    if config::get_bool("alloc.track") == Some(true) {
+        match allocator::enable_tracking() {
+            Ok(()) => console::print("Allocation tracking enabled\n"),
+            Err(e) => {
+                println!("Allocation tracking unavailable: {}", e);
+            }
+        }
+    }
+
+    if let Some(enabled) = config::get_bool("alloc.zero_on_free") {
+        allocator::set_zero_on_free(enabled);
+    }
+    if allocator::zero_on_free() {
+        console::print("Freed heap blocks are zeroed\n");
+    }
+    if let Some(enabled) = config::get_bool("alloc.size_classes") {
+        allocator::set_size_classes_enabled(enabled);
+    }
+
+    klog::init();
+    defrag::init();
+
+    // What the kernel can give back when the heap runs out
+    let oom_callbacks: [(&str, allocator::OomCallback); 2] =
+        [("slab", slab::reclaim), ("klog", klog::reclaim)];
+    for (name, callback) in oom_callbacks {
+        if let Err(e) = allocator::register_oom_callback(callback) {
+            println!("OOM callback {} not registered: {}", name, e);
+        }
+    }
 
-    if let Err(e) = allocator::init(heap_start, heap_size) {
-        console::print("Allocator init failed: ");
-        console::print(e);
+    if have_cmdline {
+        console::print("Command line: ");
+        for (key, value) in config::entries() {
+            print!("{}={} ", key, value);
+        }
         console::print("\n");
+    }
+
+    // Kernel image, boot stack, crash region and MMIO must not overlap the heap
+    if let Err(e) = vmm::init(heap_start) {
+        println!("Memory layout invalid: {}", e);
         halt();
     }
+    psci::init();
+    crashdump::load_policy();
+    if crashdump::init() {
+        kevent!(
+            Event::PreviousCrash,
+            "Recovered crash report from previous boot (see `crashdump`)"
+        );
+    }
+    #[cfg(feature = "fs")]
+    if let Err(e) = vfs::init() {
+        println!("VFS mount failed: {}", e);
+    }
 
-    console::print("Heap initialized: ");
-    console::print(&(heap_size / 1024 / 1024).to_string());
-    console::print(" MB\n");
+    // Kernel log on a second UART (log.uart=uart1), console left to the shell
+    if let Some(name) = config::get("log.uart") {
+        match uart::route_logs(&name, &pl011::UartConfig::DEFAULT) {
+            Ok(()) => println!("Kernel log moved to {}", name),
+            Err(e) => println!("Kernel log stays on the console ({}: {})", name, e),
+        }
+    }
+
+    // Optional virtio console (console.backend=uart|virtio|both)
+    if virtio_console::init().is_ok() {
+        let backend = config::get("console.backend")
+            .and_then(|name| console::Backend::parse(&name))
+            .unwrap_or(console::Backend::Both);
+        match console::set_backend(backend) {
+            Ok(()) => println!("virtio-console found, console backend: {}", backend.as_str()),
+            Err(e) => println!("virtio-console backend failed: {}", e),
+        }
+    }
 
     // Initialize GIC (Generic Interrupt Controller)
-    gic::init();
-    console::print("GIC initialized\n");
+    match gic::init() {
+        Ok(()) => console::print("GIC initialized\n"),
+        Err(e) => println!("GIC init failed: {}", e),
+    }
 
     // Set up exception vectors and enable IRQs
     exceptions::init();
     console::print("IRQ handling enabled\n");
 
     // Initialize timer
-    timer::init();
-    console::print("Timer initialized\n");
+    if let Err(e) = timer::init() {
+        println!("Timer init failed: {}", e);
+    }
+    kevent!(Event::BootInterruptsReady, "Timer initialized");
 
     // Initialize Embassy time driver (bridges ARM timer to Embassy async)
     embassy_time_driver::init();
@@ -110,9 +319,7 @@ fn kernel_main() -> ! {
 
     // Check timer hardware
     let freq = timer::read_frequency();
-    console::print("Timer frequency: ");
-    console::print(&freq.to_string());
-    console::print(" Hz\n");
+    println!("Timer frequency: {} Hz", freq);
 
     // Read UTC time from PL031 RTC hardware
     if timer::init_utc_from_rtc() {
@@ -121,36 +328,50 @@ fn kernel_main() -> ! {
         console::print("Warning: RTC not available, UTC time not set\n");
     }
 
-    console::print("Current UTC time: ");
-    console::print(&timer::utc_iso8601());
-    console::print("\n");
-
-    console::print("Uptime: ");
-    console::print(&(timer::uptime_us() / 1_000_000).to_string());
-    console::print(" seconds\n");
+    println!("Current UTC time: {}", timer::utc_iso8601());
+    println!("Uptime: {} seconds", timer::uptime_us() / 1_000_000);
 
     // Initialize threading (but don't enable timer yet!)
     console::print("Initializing threading...\n");
-    threading::init();
-    console::print("Threading system initialized\n");
+    match threading::init() {
+        Ok(()) => console::print("Threading system initialized\n"),
+        Err(e) => println!("Threading init failed: {}", e),
+    }
+    lockdep::init();
 
     // =========================================================================
     // Now enable preemptive scheduling (timer interrupts)
     // =========================================================================
     console::print("Configuring scheduler SGI...\n");
+    // Lowest priority: the scheduler never switches threads under an active handler
+    gic::set_priority(gic::SGI_SCHEDULER, gic::PRIORITY_SCHEDULER);
     gic::enable_irq(gic::SGI_SCHEDULER);
 
     console::print("Registering timer IRQ...\n");
+    // Most urgent: the tick preempts slow device handlers
+    gic::set_priority(30, gic::PRIORITY_TIMER);
     irq::register_handler(30, |irq| timer::timer_irq_handler(irq));
+    if let Some(enabled) = config::get_bool("irq.nested") {
+        irq::set_nesting(enabled);
+    }
+
+    console::print("Enabling interrupt-driven console output...\n");
+    console::enable_irq_driven_tx();
+    console::enable_irq_driven_rx();
 
     console::print("Enabling timer...\n");
-    timer::enable_timer_interrupts(10_000); // 10ms intervals
-    console::print("Preemptive scheduling enabled (10ms timer -> SGI)\n");
+    timer::enable_timer_interrupts(timer::tick_interval_us()); // sched.tick_us, default 10ms
+    kevent!(
+        Event::BootSchedulerReady,
+        "Preemptive scheduling enabled ({}us timer -> SGI)",
+        timer::tick_interval_us()
+    );
 
     // Enable IRQ-safe allocations now that preemption is active
     allocator::enable_preemption_safe_alloc();
 
     // Run system tests (includes allocator tests)
+    #[cfg(feature = "tests")]
     if !tests::run_all() {
         console::print("\n!!! SYSTEM TESTS FAILED - HALTING !!!\n");
         halt();
@@ -159,11 +380,37 @@ fn kernel_main() -> ! {
     // =========================================================================
     // Run async tests (before network takes over the main loop)
     // =========================================================================
+    #[cfg(feature = "tests")]
     if !async_tests::run_all() {
         console::print("\n!!! ASYNC TESTS FAILED - HALTING !!!\n");
         halt();
     }
 
+    // Records logged from IRQ handlers are staged until this thread passes
+    // them on
+    if let Err(e) = klog::start_flusher() {
+        println!("Log flusher failed to start: {}", e);
+    }
+    if let Err(e) = threading::start_reaper() {
+        println!("Thread reaper failed to start: {}", e);
+    }
+    if let Err(e) = softirq::start() {
+        println!("softirqd failed to start: {}", e);
+    }
+    if let Err(e) = workers::start() {
+        println!("Worker pool failed to start: {}", e);
+    }
+    if let Err(e) = workqueue::start() {
+        println!("Work queue failed to start: {}", e);
+    }
+
+    if config::get_bool("alloc.scrub") == Some(true) {
+        match allocator::start_scrubber() {
+            Ok(_) => console::print("Idle heap scrubber started\n"),
+            Err(e) => println!("Heap scrubber failed to start: {}", e),
+        }
+    }
+
     // =========================================================================
     // Async Network initialization and main loop
     // =========================================================================
@@ -172,16 +419,14 @@ fn kernel_main() -> ! {
     // Initialize the async network stack
     let net_init = match async_net::init() {
         Ok(init) => {
-            console::print("[AsyncNet] Network initialized successfully\n");
+            kevent!(Event::BootNetworkReady, "[AsyncNet] Network initialized successfully");
             init
         }
         Err(e) => {
-            console::print("[AsyncNet] Network init failed: ");
-            console::print(e);
-            console::print("\n");
+            kevent!(Event::BootNetworkFailed, "[AsyncNet] Network init failed: {}", e);
             console::print("[Idle] Entering idle loop (no network)\n");
             loop {
-                threading::yield_now();
+                threading::sleep_ms(100);
             }
         }
     };
@@ -189,8 +434,32 @@ fn kernel_main() -> ! {
     console::print("--- Async Network Initialization Done ---\n\n");
     
     // Initialize SSH host key
+    #[cfg(feature = "ssh")]
     ssh::init_host_key();
 
+    // The main thread polls the network: keep it ahead of bulk workers
+    let network_thread = threading::current_thread_id();
+    if let Err(e) = threading::set_priority(network_thread, sched_policy::PRIORITY_NETWORK) {
+        println!("Network thread priority not set: {}", e);
+    }
+    // Or a guaranteed share of every period, for predictable latency
+    if let Some(value) = config::get("sched.network_deadline") {
+        let params = value.split_once(':').and_then(|(period, budget)| {
+            Some(threading::DeadlineParams {
+                period_us: period.trim().parse().ok()?,
+                budget_us: budget.trim().parse().ok()?,
+            })
+        });
+        match params {
+            Some(params) => {
+                if let Err(e) = threading::set_deadline_class(network_thread, Some(params)) {
+                    println!("Network thread deadline not set: {}", e);
+                }
+            }
+            None => println!("Ignoring sched.network_deadline '{}'", value),
+        }
+    }
+
     // Run the async main loop in the main thread
     // This drives both the network runner and the SSH server
     run_async_main(net_init);
@@ -198,50 +467,169 @@ fn kernel_main() -> ! {
 
 /// Run the async main loop
 /// This is the main entry point for async networking
-fn run_async_main(net_init: async_net::NetworkInit) -> ! {
-    use core::future::Future;
-    use core::pin::Pin;
+fn run_async_main(mut net_init: async_net::NetworkInit) -> ! {
     use core::task::{Context, RawWaker, RawWakerVTable, Waker};
 
-    console::print("[AsyncMain] Starting async network loop...\n");
+    kevent!(Event::BootComplete, "[AsyncMain] Starting async network loop...");
+    #[cfg(feature = "ssh")]
     console::print("[AsyncMain] SSH Server: Connect with ssh -o StrictHostKeyChecking=no user@localhost -p 2222\n");
 
-    // Simple waker that does nothing (we poll in a loop)
+    // We poll in a loop; a wakeup only tells the loop not to idle
     static VTABLE: RawWakerVTable = RawWakerVTable::new(
         |_| RawWaker::new(core::ptr::null(), &VTABLE),
-        |_| {},
-        |_| {},
+        |_| MAIN_LOOP_WOKEN.store(true, Ordering::Release),
+        |_| MAIN_LOOP_WOKEN.store(true, Ordering::Release),
         |_| {},
     );
     let raw_waker = RawWaker::new(core::ptr::null(), &VTABLE);
     let waker = unsafe { Waker::from_raw(raw_waker) };
     let mut cx = Context::from_waker(&waker);
 
+    loop {
+        // Returns once `net restart` has been asked for, with the device
+        // stopped and every socket of the old stack dropped
+        serve_network(net_init, &mut cx);
+
+        console::print("[AsyncMain] Restarting network stack...\n");
+        net_init = loop {
+            match async_net::init() {
+                Ok(init) => break init,
+                Err(e) => {
+                    println!("[AsyncMain] Network restart failed: {}, retrying", e);
+                    let retry_at = timer::uptime_us() + 1_000_000;
+                    while timer::uptime_us() < retry_at {
+                        poll_background();
+                    }
+                }
+            }
+        };
+        network::note_restart();
+        console::print("[AsyncMain] Network stack restarted\n");
+    }
+}
+
+/// Drive one network stack and its servers until a restart is due
+fn serve_network(net_init: async_net::NetworkInit, cx: &mut core::task::Context<'_>) {
     let mut runner = net_init.runner;
     let stack = net_init.stack;
 
-    // Create futures for both the network runner and SSH server
     let mut runner_fut = runner.run();
-    let mut ssh_fut = ssh_server::run(stack);
-
-    // Pin the futures
     let mut runner_pinned = unsafe { Pin::new_unchecked(&mut runner_fut) };
-    let mut ssh_pinned = unsafe { Pin::new_unchecked(&mut ssh_fut) };
+
+    // Settings and keys fetched over the network must be in place before
+    // any server accepts a login
+    if netboot::pending() {
+        let mut boot_fut = netboot::run(stack);
+        let mut boot_pinned = unsafe { Pin::new_unchecked(&mut boot_fut) };
+        loop {
+            {
+                let _tag = allocator::tag_scope(Subsystem::Net);
+                let _ = runner_pinned.as_mut().poll(cx);
+            }
+            if boot_pinned.as_mut().poll(cx).is_ready() {
+                break;
+            }
+            poll_background();
+        }
+    }
+
+    let mut servers = servers(stack);
 
     loop {
         // Poll the network runner
-        let _ = runner_pinned.as_mut().poll(&mut cx);
-        
-        // Poll the SSH server
-        let _ = ssh_pinned.as_mut().poll(&mut cx);
-        
-        // Process pending IRQ work
-        executor::process_irq_work();
-        
-        // Poll the executor for any other tasks
-        executor::run_once();
-        
-        // Yield to other threads (cooperative multitasking)
-        threading::yield_now();
+        {
+            let _tag = allocator::tag_scope(Subsystem::Net);
+            let _ = runner_pinned.as_mut().poll(cx);
+        }
+
+        for server in servers.iter_mut() {
+            let _tag = allocator::tag_scope(server.subsystem);
+            let _scope = server.task.enter();
+            let _ = server.future.as_mut().poll(cx);
+        }
+
+        if let Some(action) = driver::shutdown_due() {
+            driver::shutdown_system(action);
+        }
+
+        if network::take_restart() {
+            // Stop the device before its driver and buffers are dropped
+            if let Err(e) = async_net::shutdown() {
+                println!("[AsyncMain] Network shutdown failed: {}", e);
+            }
+            return;
+        }
+
+        poll_background();
     }
 }
+
+/// A network server the main loop polls, and whose allocations it
+/// charges to `subsystem`
+///
+/// Each server is a task in the handle table: what it leaves registered is
+/// released when the network restarts and the server is dropped.
+struct Server {
+    subsystem: Subsystem,
+    // Field order matters: the future drops before its task is released
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    task: handles::Task,
+}
+
+/// The servers this build includes, for one network stack
+///
+/// Each optional subsystem registers its server here behind its feature;
+/// a `--no-default-features` build serves nothing.
+#[cfg_attr(
+    not(any(feature = "ssh", feature = "http", feature = "shell")),
+    allow(unused_variables)
+)]
+fn servers(stack: embassy_net::Stack<'static>) -> Vec<Server> {
+    vec![
+        // SSH shell
+        #[cfg(feature = "ssh")]
+        Server {
+            subsystem: Subsystem::Ssh,
+            future: Box::pin(ssh_server::run(stack)),
+            task: handles::new_task(),
+        },
+        // HTTP file browser
+        #[cfg(feature = "http")]
+        Server {
+            subsystem: Subsystem::Fs,
+            future: Box::pin(http_server::run(stack)),
+            task: handles::new_task(),
+        },
+        // Control protocol
+        #[cfg(feature = "shell")]
+        Server {
+            subsystem: Subsystem::Ssh,
+            future: Box::pin(ctl_server::run(stack)),
+            task: handles::new_task(),
+        },
+    ]
+}
+
+/// Set by the main loop's waker: a future it polls can make progress
+static MAIN_LOOP_WOKEN: AtomicBool = AtomicBool::new(false);
+
+/// Work the main loop does besides the network, then yield (and idle
+/// until the next interrupt if nothing at all is left to do)
+fn poll_background() {
+    // Run interrupt bottom halves (network RX among them)
+    softirq::run_pending();
+
+    // Poll the executor for any other tasks
+    executor::run_once();
+
+    // Report events recorded where logging isn't possible
+    events::poll();
+
+    // Yield to other threads (cooperative multitasking)
+    threading::yield_now();
+
+    // Nothing woke the futures, no bottom half is pending and no other
+    // thread is ready: wait in wfi instead of spinning. The network IRQ or
+    // the next timer tick ends it.
+    threading::idle_unless(|| MAIN_LOOP_WOKEN.swap(false, Ordering::AcqRel) || softirq::pending());
+}
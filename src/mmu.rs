@@ -0,0 +1,356 @@
+//! MMU and W^X Kernel Mapping
+//!
+//! Identity-maps the address space with 4 KB granule, 39-bit VA tables:
+//!
+//! - 0x0000_0000..0x4000_0000: device memory (GIC, UART, RTC, virtio), never executable
+//! - kernel region (RAM base up to the heap): 4 KB pages so each section
+//!   gets its own permissions - .text read+execute, .rodata read-only,
+//!   everything else (data, bss, boot stack, crash region) read-write no-execute
+//! - rest of RAM: 2 MB read-write no-execute blocks
+//! - last 2 MB of RAM: the DMA pool, Normal Non-cacheable, so memory shared
+//!   with devices needs no cache maintenance (see `pmm::alloc_dma_pages`)
+//!
+//! SCTLR_EL1.WXN is set as well, so no writable page can ever be executed.
+//! A stray write into code or constants now takes a permission fault
+//! instead of silently corrupting the kernel.
+
+use crate::error::{ErrorKind, KError, KResult};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// ============================================================================
+// Descriptor Bits
+// ============================================================================
+
+const ENTRIES: usize = 512;
+const PAGE_SIZE: usize = 4096;
+const L2_BLOCK_SIZE: usize = 2 * 1024 * 1024;
+const L1_BLOCK_SIZE: usize = 1024 * 1024 * 1024;
+
+const DESC_VALID: u64 = 1 << 0;
+const DESC_TABLE: u64 = 1 << 1; // Table (L1/L2) or page (L3); clear = block
+const DESC_AF: u64 = 1 << 10; // Access flag (no AF faults)
+const DESC_SH_INNER: u64 = 3 << 8;
+const DESC_AP_RO: u64 = 1 << 7; // AP[2]: read-only at EL1
+const DESC_PXN: u64 = 1 << 53;
+const DESC_UXN: u64 = 1 << 54;
+const DESC_ADDR_MASK: u64 = 0x0000_FFFF_FFFF_F000;
+
+// MAIR attribute indices
+const ATTR_DEVICE: u64 = 0; // Device-nGnRnE
+const ATTR_NORMAL: u64 = 1; // Normal, write-back read/write-allocate
+const ATTR_NORMAL_NC: u64 = 2; // Normal, inner and outer non-cacheable
+const MAIR_VALUE: u64 = (0xFF << 8) | (0x44 << 16); // Attr0 = 0x00
+const DESC_ATTR_MASK: u64 = 7 << 2;
+
+// TCR_EL1
+const TCR_T0SZ: u64 = 64 - 39;
+const TCR_IRGN0_WBWA: u64 = 1 << 8;
+const TCR_ORGN0_WBWA: u64 = 1 << 10;
+const TCR_SH0_INNER: u64 = 3 << 12;
+const TCR_TG0_4K: u64 = 0 << 14;
+const TCR_EPD1: u64 = 1 << 23; // No TTBR1 walks
+const TCR_IPS_SHIFT: u64 = 32;
+
+// SCTLR_EL1
+const SCTLR_M: u64 = 1 << 0;
+const SCTLR_C: u64 = 1 << 2;
+const SCTLR_I: u64 = 1 << 12;
+const SCTLR_WXN: u64 = 1 << 19;
+
+/// Start of RAM on QEMU virt
+const RAM_BASE: usize = 0x4000_0000;
+
+/// Top of the boot stack (set in boot.rs); it sits right above the image
+pub const BOOT_STACK_TOP: usize = 0x4010_0000;
+
+/// L3 tables for the kernel region (each covers 2 MB)
+const KERNEL_L3_TABLES: usize = 4;
+
+/// Size of the non-cacheable DMA pool at the top of RAM
+pub const DMA_POOL_SIZE: usize = L2_BLOCK_SIZE;
+
+// ============================================================================
+// Tables
+// ============================================================================
+
+#[repr(C, align(4096))]
+struct PageTable([u64; ENTRIES]);
+
+static mut L1_TABLE: PageTable = PageTable([0; ENTRIES]);
+static mut L2_RAM: PageTable = PageTable([0; ENTRIES]);
+static mut L3_KERNEL: [PageTable; KERNEL_L3_TABLES] =
+    [const { PageTable([0; ENTRIES]) }; KERNEL_L3_TABLES];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Access permissions of a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
+pub enum Perm {
+    ReadExecute,
+    ReadOnly,
+    ReadWrite,
+}
+
+#[cfg(feature = "shell")]
+impl Perm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Perm::ReadExecute => "r-x",
+            Perm::ReadOnly => "r--",
+            Perm::ReadWrite => "rw-",
+        }
+    }
+}
+
+// Section boundaries from linker.ld (page aligned)
+unsafe extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __kernel_end: u8;
+}
+
+/// Kernel section boundaries
+#[derive(Debug, Clone, Copy)]
+pub struct Sections {
+    pub text: (usize, usize),
+    pub rodata: (usize, usize),
+    pub kernel_end: usize,
+}
+
+pub fn sections() -> Sections {
+    // Linker-provided symbols; only their addresses are used
+    Sections {
+        text: (
+            core::ptr::addr_of!(__text_start) as usize,
+            core::ptr::addr_of!(__text_end) as usize,
+        ),
+        rodata: (
+            core::ptr::addr_of!(__rodata_start) as usize,
+            core::ptr::addr_of!(__rodata_end) as usize,
+        ),
+        kernel_end: core::ptr::addr_of!(__kernel_end) as usize,
+    }
+}
+
+/// Start of the DMA pool for `ram_size` bytes of RAM (`DMA_POOL_SIZE` long)
+pub fn dma_pool_base(ram_size: usize) -> usize {
+    RAM_BASE + ram_size - DMA_POOL_SIZE
+}
+
+fn normal_flags(perm: Perm) -> u64 {
+    let base = DESC_AF | DESC_SH_INNER | (ATTR_NORMAL << 2) | DESC_UXN;
+    match perm {
+        Perm::ReadExecute => base | DESC_AP_RO,
+        Perm::ReadOnly => base | DESC_AP_RO | DESC_PXN,
+        Perm::ReadWrite => base | DESC_PXN,
+    }
+}
+
+fn device_flags() -> u64 {
+    DESC_AF | (ATTR_DEVICE << 2) | DESC_PXN | DESC_UXN
+}
+
+fn dma_flags() -> u64 {
+    DESC_AF | DESC_SH_INNER | (ATTR_NORMAL_NC << 2) | DESC_PXN | DESC_UXN
+}
+
+fn flags_to_perm(desc: u64) -> Perm {
+    if desc & DESC_AP_RO == 0 {
+        Perm::ReadWrite
+    } else if desc & DESC_PXN == 0 {
+        Perm::ReadExecute
+    } else {
+        Perm::ReadOnly
+    }
+}
+
+fn kernel_page_perm(addr: usize, s: &Sections) -> Perm {
+    if addr >= s.text.0 && addr < s.text.1 {
+        Perm::ReadExecute
+    } else if addr >= s.rodata.0 && addr < s.rodata.1 {
+        Perm::ReadOnly
+    } else {
+        Perm::ReadWrite
+    }
+}
+
+// ============================================================================
+// Setup
+// ============================================================================
+
+/// Build the identity map and turn on the MMU and caches
+///
+/// `kernel_region_end` is where the heap begins; everything below it is
+/// mapped with 4 KB pages. Must run before anything relies on memory
+/// attributes (e.g. before the heap is set up).
+pub fn init(ram_size: usize, kernel_region_end: usize) -> KResult<()> {
+    if ENABLED.load(Ordering::Acquire) {
+        return Err(KError::with_context(ErrorKind::AlreadyInitialized, "mmu"));
+    }
+
+    let s = sections();
+    let kernel_region = kernel_region_end - RAM_BASE;
+    if kernel_region > KERNEL_L3_TABLES * L2_BLOCK_SIZE
+        || !kernel_region.is_multiple_of(L2_BLOCK_SIZE)
+        || s.kernel_end > kernel_region_end
+        || s.kernel_end >= BOOT_STACK_TOP
+        || ram_size > L1_BLOCK_SIZE
+        || !ram_size.is_multiple_of(L2_BLOCK_SIZE)
+        || kernel_region_end > dma_pool_base(ram_size)
+    {
+        return Err(KError::with_context(ErrorKind::InvalidRegion, "kernel layout"));
+    }
+
+    // SAFETY: runs once on the boot CPU with the MMU off; nothing else
+    // touches the tables until the MMU is enabled below
+    unsafe {
+        let l1 = &mut *core::ptr::addr_of_mut!(L1_TABLE);
+        let l2 = &mut *core::ptr::addr_of_mut!(L2_RAM);
+        let l3 = &mut *core::ptr::addr_of_mut!(L3_KERNEL);
+
+        l1.0 = [0; ENTRIES];
+        l2.0 = [0; ENTRIES];
+
+        // L1[0]: first GB is MMIO
+        l1.0[0] = DESC_VALID | device_flags();
+
+        // L1[1]: RAM, via L2
+        l1.0[1] = DESC_VALID | DESC_TABLE | (l2 as *const PageTable as u64);
+
+        let l2_entries = ram_size.div_ceil(L2_BLOCK_SIZE);
+        for (i, entry) in l2.0.iter_mut().enumerate().take(l2_entries) {
+            let addr = RAM_BASE + i * L2_BLOCK_SIZE;
+            if addr < kernel_region_end {
+                let table = &mut l3[i];
+                for (j, page) in table.0.iter_mut().enumerate() {
+                    let page_addr = addr + j * PAGE_SIZE;
+                    *page = DESC_VALID
+                        | DESC_TABLE
+                        | page_addr as u64
+                        | normal_flags(kernel_page_perm(page_addr, &s));
+                }
+                *entry = DESC_VALID | DESC_TABLE | (table as *const PageTable as u64);
+            } else if addr >= dma_pool_base(ram_size) {
+                *entry = DESC_VALID | addr as u64 | dma_flags();
+            } else {
+                *entry = DESC_VALID | addr as u64 | normal_flags(Perm::ReadWrite);
+            }
+        }
+
+        // Physical address size supported by the CPU
+        let mmfr0: u64;
+        core::arch::asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0, options(nomem, nostack));
+        let ips = (mmfr0 & 0xF).min(5);
+
+        let tcr = TCR_T0SZ
+            | TCR_IRGN0_WBWA
+            | TCR_ORGN0_WBWA
+            | TCR_SH0_INNER
+            | TCR_TG0_4K
+            | TCR_EPD1
+            | (ips << TCR_IPS_SHIFT);
+
+        core::arch::asm!(
+            "dsb ish",
+            "msr mair_el1, {mair}",
+            "msr tcr_el1, {tcr}",
+            "msr ttbr0_el1, {ttbr}",
+            "isb",
+            "tlbi vmalle1",
+            "ic iallu",
+            "dsb ish",
+            "isb",
+            mair = in(reg) MAIR_VALUE,
+            tcr = in(reg) tcr,
+            ttbr = in(reg) l1 as *const PageTable as u64,
+            options(nostack),
+        );
+
+        let mut sctlr: u64;
+        core::arch::asm!("mrs {}, sctlr_el1", out(reg) sctlr, options(nomem, nostack));
+        sctlr |= SCTLR_M | SCTLR_C | SCTLR_I | SCTLR_WXN;
+        core::arch::asm!("msr sctlr_el1, {}", "isb", in(reg) sctlr, options(nostack));
+    }
+
+    ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// True once the MMU is on
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+// ============================================================================
+// Queries
+// ============================================================================
+
+/// Leaf descriptor mapping `addr`, from our own tables
+fn descriptor(addr: usize) -> Option<u64> {
+    if !enabled() {
+        return None;
+    }
+
+    let l1_idx = addr / L1_BLOCK_SIZE;
+    if l1_idx >= ENTRIES {
+        return None;
+    }
+
+    // SAFETY: tables are only written by `init`
+    unsafe {
+        let l1 = &*core::ptr::addr_of!(L1_TABLE);
+        let d1 = l1.0[l1_idx];
+        if d1 & DESC_VALID == 0 {
+            return None;
+        }
+        if d1 & DESC_TABLE == 0 {
+            return Some(d1);
+        }
+
+        let l2 = &*((d1 & DESC_ADDR_MASK) as *const PageTable);
+        let d2 = l2.0[(addr % L1_BLOCK_SIZE) / L2_BLOCK_SIZE];
+        if d2 & DESC_VALID == 0 {
+            return None;
+        }
+        if d2 & DESC_TABLE == 0 {
+            return Some(d2);
+        }
+
+        let l3 = &*((d2 & DESC_ADDR_MASK) as *const PageTable);
+        let d3 = l3.0[(addr % L2_BLOCK_SIZE) / PAGE_SIZE];
+        if d3 & DESC_VALID == 0 {
+            return None;
+        }
+        Some(d3)
+    }
+}
+
+/// Permissions of the mapping covering `addr`, from our own tables
+pub fn permissions(addr: usize) -> Option<Perm> {
+    descriptor(addr).map(flags_to_perm)
+}
+
+/// Is `addr` mapped as cacheable normal memory?
+pub fn cacheable(addr: usize) -> Option<bool> {
+    descriptor(addr).map(|desc| desc & DESC_ATTR_MASK == ATTR_NORMAL << 2)
+}
+
+/// Ask the hardware whether an EL1 write to `addr` would be permitted
+pub fn can_write(addr: usize) -> bool {
+    let par: u64;
+    // SAFETY: address translation only; no memory is accessed
+    unsafe {
+        core::arch::asm!(
+            "at s1e1w, {addr}",
+            "isb",
+            "mrs {par}, par_el1",
+            addr = in(reg) addr,
+            par = out(reg) par,
+            options(nostack),
+        );
+    }
+    par & 1 == 0
+}
@@ -0,0 +1,451 @@
+//! Network Boot Configuration
+//!
+//! The kernel has no persistent filesystem, so a stateless image can take
+//! its settings from the network instead of a long command line:
+//!
+//! ```text
+//! config=tftp://10.0.2.2/akuma.conf
+//! authorized_keys=http://10.0.2.2:8000/keys
+//! ```
+//!
+//! Once the network is up, and before any server accepts a login, `run`
+//! fetches the `config` URL and applies each `key=value` line as a runtime
+//! override (`#` starts a comment), then fetches `authorized_keys` - which
+//! may come from the fetched config - in OpenSSH format:
+//!
+//! ```text
+//! ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAI... alice@laptop
+//! ```
+//!
+//! The comment names the user (up to any `@`), whose key becomes
+//! `auth.<user>.ed25519`; the user must be listed in `auth.users`. URLs
+//! take an IPv4 address (there is no DNS client) and an optional port.
+//! A fetch that keeps failing is logged and boot continues with the
+//! command-line settings. Builds without the `ssh` feature skip
+//! `authorized_keys`.
+//!
+//! With the `tls` feature, `https://` URLs are fetched over TLS 1.3. The
+//! server is checked against `tls.roots` or `tls.pins` (see `tls`), which
+//! must then be given on the command line.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "ssh")]
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Stack};
+use embassy_time::{Duration, Timer, with_timeout};
+
+use crate::async_net::TcpStream;
+use crate::config;
+use crate::error::{ErrorKind, KError, KResult};
+use crate::network::{self, Service};
+#[cfg(feature = "ssh")]
+use crate::ssh_crypto::read_string;
+#[cfg(feature = "tls")]
+use crate::tls::{ServerName, TlsStream, TrustStore};
+
+/// Largest file fetched; configuration is a few lines
+const MAX_FETCH: usize = 64 * 1024;
+
+/// Attempts per URL before giving up
+const FETCH_ATTEMPTS: usize = 3;
+
+/// Wait between attempts
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// TCP handshake and each read of an HTTP fetch
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Retransmit a TFTP packet after this long without an answer
+const TFTP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Retransmissions of one TFTP packet before the transfer fails
+const TFTP_RETRIES: usize = 5;
+
+/// TFTP data block size (RFC 1350)
+const TFTP_BLOCK: usize = 512;
+
+/// Set once `run` has finished, so a network restart doesn't fetch again
+static LOADED: AtomicBool = AtomicBool::new(false);
+
+// ============================================================================
+// URLs
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Tftp,
+    Http,
+    #[cfg(feature = "tls")]
+    Https,
+}
+
+impl Scheme {
+    fn default_port(self) -> u16 {
+        match self {
+            Scheme::Tftp => 69,
+            Scheme::Http => 80,
+            #[cfg(feature = "tls")]
+            Scheme::Https => 443,
+        }
+    }
+}
+
+/// URL prefix of each scheme
+const SCHEMES: &[(&str, Scheme)] = &[
+    ("tftp://", Scheme::Tftp),
+    ("http://", Scheme::Http),
+    #[cfg(feature = "tls")]
+    ("https://", Scheme::Https),
+];
+
+/// `tftp://`, `http://` or `https://` URL with an IPv4 host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootUrl {
+    pub scheme: Scheme,
+    pub addr: [u8; 4],
+    pub port: u16,
+    /// Starts with `/`
+    pub path: String,
+}
+
+impl BootUrl {
+    pub fn parse(text: &str) -> KResult<Self> {
+        let bad = || KError::with_context(ErrorKind::InvalidArgument, "boot URL");
+        let (scheme, rest) = SCHEMES
+            .iter()
+            .find_map(|&(prefix, scheme)| Some((scheme, text.strip_prefix(prefix)?)))
+            .ok_or_else(bad)?;
+
+        let (authority, path) = rest.split_at(rest.find('/').ok_or_else(bad)?);
+        let (host, port) = match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| bad())?),
+            None => (authority, scheme.default_port()),
+        };
+
+        let mut addr = [0u8; 4];
+        let mut octets = host.split('.');
+        for byte in addr.iter_mut() {
+            *byte = octets.next().and_then(|o| o.parse().ok()).ok_or_else(bad)?;
+        }
+        if octets.next().is_some() || path.len() < 2 {
+            return Err(bad());
+        }
+
+        Ok(Self {
+            scheme,
+            addr,
+            port,
+            path: String::from(path),
+        })
+    }
+
+    fn endpoint(&self) -> IpEndpoint {
+        let [a, b, c, d] = self.addr;
+        IpEndpoint::new(IpAddress::v4(a, b, c, d), self.port)
+    }
+}
+
+// ============================================================================
+// Applying Fetched Files
+// ============================================================================
+
+/// Apply `key=value` lines as configuration overrides; returns how many
+pub fn apply_config(text: &str) -> usize {
+    let mut applied = 0;
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if let Some((key, value)) = line.split_once('=') {
+            let key = key.trim();
+            if !key.is_empty() {
+                config::set(key, value.trim());
+                applied += 1;
+            }
+        }
+    }
+    applied
+}
+
+/// Decode standard base64 (padding optional)
+#[cfg(any(feature = "ssh", feature = "tls"))]
+pub fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+            b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            bits |= value(c)? << (18 - 6 * i);
+        }
+        let bytes = bits.to_be_bytes();
+        out.extend_from_slice(&bytes[1..chunk.len()]);
+    }
+    Some(out)
+}
+
+/// User and ed25519 key of one `authorized_keys` line, None for blank,
+/// comment, malformed or non-ed25519 lines
+#[cfg(feature = "ssh")]
+pub fn parse_authorized_key(line: &str) -> Option<(String, [u8; 32])> {
+    let mut fields = line.split_whitespace();
+    if fields.next()? != "ssh-ed25519" {
+        return None;
+    }
+    let blob = decode_base64(fields.next()?)?;
+    let user = fields.next()?.split('@').next()?;
+
+    let mut offset = 0;
+    if read_string(&blob, &mut offset)? != b"ssh-ed25519" {
+        return None;
+    }
+    let key: [u8; 32] = read_string(&blob, &mut offset)?.try_into().ok()?;
+    (!user.is_empty()).then(|| (String::from(user), key))
+}
+
+/// Install every key in an `authorized_keys` file; returns how many
+#[cfg(feature = "ssh")]
+pub fn apply_authorized_keys(text: &str) -> usize {
+    let mut applied = 0;
+    for (user, key) in text.lines().filter_map(parse_authorized_key) {
+        let mut hex = String::with_capacity(64);
+        for byte in key {
+            let _ = write!(hex, "{:02x}", byte);
+        }
+        config::set(&alloc::format!("auth.{}.ed25519", user), &hex);
+        applied += 1;
+    }
+    applied
+}
+
+// ============================================================================
+// Fetching
+// ============================================================================
+
+/// HTTP/1.0 GET request for `url.path`
+fn http_request(url: &BootUrl) -> String {
+    let [a, b, c, d] = url.addr;
+    alloc::format!(
+        "GET {} HTTP/1.0\r\nHost: {}.{}.{}.{}\r\nConnection: close\r\n\r\n",
+        url.path, a, b, c, d
+    )
+}
+
+/// Append a chunk of response, refusing responses past the fetch limit
+fn append_response(response: &mut Vec<u8>, data: &[u8]) -> KResult<()> {
+    if response.len() + data.len() > MAX_FETCH + 1024 {
+        return Err(KError::with_context(ErrorKind::LimitReached, "netboot http"));
+    }
+    response.extend_from_slice(data);
+    Ok(())
+}
+
+/// Body of a complete response, if its status is 200
+fn http_body(mut response: Vec<u8>) -> KResult<Vec<u8>> {
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| KError::with_context(ErrorKind::Protocol, "netboot http"))?;
+    let status = response.split(|&b| b == b' ').nth(1).unwrap_or(&[]);
+    if status != b"200" {
+        return Err(KError::with_context(ErrorKind::NotFound, "netboot http"));
+    }
+    Ok(response.split_off(split + 4))
+}
+
+/// GET `url.path` over HTTP/1.0 and return the body of a 200 response
+async fn fetch_http(stack: Stack<'static>, url: &BootUrl) -> KResult<Vec<u8>> {
+    let mut stream = TcpStream::connect(stack, url.endpoint(), HTTP_TIMEOUT).await?;
+    stream.write_all(http_request(url).as_bytes()).await?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let n = with_timeout(HTTP_TIMEOUT, stream.read(&mut buf))
+            .await
+            .map_err(|_| KError::with_context(ErrorKind::TimedOut, "netboot http"))??;
+        if n == 0 {
+            break;
+        }
+        append_response(&mut response, &buf[..n])?;
+    }
+    stream.close();
+    http_body(response)
+}
+
+/// `fetch_http` over TLS, trusting `tls.roots` and `tls.pins`
+#[cfg(feature = "tls")]
+async fn fetch_https(stack: Stack<'static>, url: &BootUrl) -> KResult<Vec<u8>> {
+    let trust = TrustStore::from_config()?;
+    if trust.is_empty() {
+        return Err(KError::with_context(ErrorKind::InvalidArgument, "https needs tls.roots or tls.pins"));
+    }
+    let name = ServerName::Ip(url.addr);
+    let mut stream = TlsStream::connect(stack, url.endpoint(), name, &trust, HTTP_TIMEOUT).await?;
+    stream.write_all(http_request(url).as_bytes()).await?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let n = with_timeout(HTTP_TIMEOUT, stream.read(&mut buf))
+            .await
+            .map_err(|_| KError::with_context(ErrorKind::TimedOut, "netboot https"))??;
+        if n == 0 {
+            break;
+        }
+        append_response(&mut response, &buf[..n])?;
+    }
+    stream.close().await;
+    http_body(response)
+}
+
+/// Read `url.path` with a TFTP (RFC 1350) octet-mode transfer
+async fn fetch_tftp(stack: Stack<'static>, url: &BootUrl) -> KResult<Vec<u8>> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buf = vec![0u8; 2048];
+    let mut tx_buf = vec![0u8; 1024];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    // An ephemeral port that differs between boots, like a real client
+    let local_port = 49152 + (crate::timer::uptime_us() % 16384) as u16;
+    socket
+        .bind(local_port)
+        .map_err(|_| KError::with_context(ErrorKind::ConnectFailed, "netboot tftp"))?;
+
+    // Read request: opcode 1, file name, mode
+    let mut packet = vec![0, 1];
+    packet.extend_from_slice(&url.path.as_bytes()[1..]);
+    packet.push(0);
+    packet.extend_from_slice(b"octet\0");
+    let mut peer = url.endpoint();
+
+    let mut file = Vec::new();
+    let mut block: u16 = 1;
+    let mut frame = [0u8; 4 + TFTP_BLOCK];
+    loop {
+        // Send the request or last ACK until the next block arrives
+        let mut received = None;
+        for _ in 0..TFTP_RETRIES {
+            socket
+                .send_to(&packet, peer)
+                .await
+                .map_err(|_| KError::with_context(ErrorKind::WriteFailed, "netboot tftp"))?;
+            network::add_bytes_tx(Service::Tftp, packet.len() as u64);
+            if let Ok(Ok((n, meta))) = with_timeout(TFTP_TIMEOUT, socket.recv_from(&mut frame)).await {
+                network::add_bytes_rx(Service::Tftp, n as u64);
+                received = Some((n, meta.endpoint));
+                break;
+            }
+        }
+        let (n, from) = received.ok_or_else(|| KError::with_context(ErrorKind::TimedOut, "netboot tftp"))?;
+        if n < 4 {
+            continue;
+        }
+        match u16::from_be_bytes([frame[0], frame[1]]) {
+            // DATA: the server answers from its own port (the transfer ID)
+            3 => {
+                if u16::from_be_bytes([frame[2], frame[3]]) != block {
+                    continue; // Duplicate of a block we have; resend our ACK
+                }
+                peer = from;
+                if file.len() + n - 4 > MAX_FETCH {
+                    return Err(KError::with_context(ErrorKind::LimitReached, "netboot tftp"));
+                }
+                file.extend_from_slice(&frame[4..n]);
+                packet = vec![0, 4];
+                packet.extend_from_slice(&block.to_be_bytes());
+                if n - 4 < TFTP_BLOCK {
+                    // Last block: acknowledge it once and finish
+                    if socket.send_to(&packet, peer).await.is_ok() {
+                        network::add_bytes_tx(Service::Tftp, packet.len() as u64);
+                    }
+                    return Ok(file);
+                }
+                block = block.wrapping_add(1);
+            }
+            // ERROR (file not found, access violation, ...)
+            5 => return Err(KError::with_context(ErrorKind::NotFound, "netboot tftp")),
+            _ => continue,
+        }
+    }
+}
+
+/// Fetch `url`, retrying a few times
+async fn fetch(stack: Stack<'static>, url: &BootUrl) -> KResult<Vec<u8>> {
+    let mut attempt = 1;
+    loop {
+        let result = match url.scheme {
+            Scheme::Tftp => fetch_tftp(stack, url).await,
+            Scheme::Http => fetch_http(stack, url).await,
+            #[cfg(feature = "tls")]
+            Scheme::Https => fetch_https(stack, url).await,
+        };
+        match result {
+            Ok(data) => return Ok(data),
+            Err(e) if attempt >= FETCH_ATTEMPTS => return Err(e),
+            Err(e) => {
+                crate::kwarn!("[NetBoot] Fetch attempt {} failed: {}, retrying", attempt, e);
+                attempt += 1;
+                Timer::after(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Fetch the file the `key` setting points to and apply it with `apply`
+async fn load(stack: Stack<'static>, key: &str, apply: fn(&str) -> usize) {
+    let Some(text) = config::get(key) else {
+        return;
+    };
+    let url = match BootUrl::parse(&text) {
+        Ok(url) => url,
+        Err(e) => {
+            crate::kerror!("[NetBoot] {}={}: {}", key, text, e);
+            return;
+        }
+    };
+    match fetch(stack, &url).await {
+        Ok(data) => {
+            let applied = apply(&String::from_utf8_lossy(&data));
+            crate::kinfo!("[NetBoot] {}: {} bytes, {} entries applied", text, data.len(), applied);
+        }
+        Err(e) => crate::kerror!("[NetBoot] Failed to fetch {}: {}", text, e),
+    }
+}
+
+/// Whether the command line asks for configuration from the network that
+/// hasn't been fetched yet
+pub fn pending() -> bool {
+    !LOADED.load(Ordering::Acquire)
+        && (config::get("config").is_some() || config::get("authorized_keys").is_some())
+}
+
+/// Fetch and apply the `config` then `authorized_keys` URLs
+///
+/// Call once the stack is up and before servers start; later calls (after
+/// a network restart) do nothing.
+pub async fn run(stack: Stack<'static>) {
+    if LOADED.load(Ordering::Acquire) {
+        return;
+    }
+    stack.wait_config_up().await;
+    load(stack, "config", apply_config).await;
+    #[cfg(feature = "ssh")]
+    load(stack, "authorized_keys", apply_authorized_keys).await;
+    LOADED.store(true, Ordering::Release);
+}
@@ -8,7 +8,8 @@ use embassy_time::{Duration, Timer};
 
 use crate::akuma::AKUMA_79;
 use crate::async_net::{TcpListener, TcpStream};
-use crate::console;
+use crate::klog::{self, Level};
+use crate::network::Service;
 
 // ============================================================================
 // Constants
@@ -74,7 +75,7 @@ async fn handle_connection(mut stream: TcpStream) {
                 let _ = stream.write_all(data).await;
             }
             Err(_) => {
-                log("[Netcat] Read error\n");
+                klog::log(Level::Warn, "netcat_server", "[Netcat] Read error\n");
                 break;
             }
         }
@@ -97,16 +98,15 @@ pub async fn run(stack: Stack<'static>) {
 
     loop {
         match listener.accept().await {
-            Ok(stream) => {
+            Ok(mut stream) => {
+                stream.set_service(Service::Telnet);
                 log("[Netcat Server] Accepted new connection\n");
                 handle_connection(stream).await;
                 log("[Netcat Server] Connection handled, listening again...\n");
             }
             Err(e) => {
-                log(&alloc::format!(
-                    "[Netcat Server] Accept error: {:?}, retrying...\n",
-                    e
-                ));
+                let msg = alloc::format!("[Netcat Server] Accept error: {:?}, retrying...\n", e);
+                klog::log(Level::Warn, "netcat_server", &msg);
                 Timer::after(Duration::from_millis(100)).await;
             }
         }
@@ -118,6 +118,6 @@ pub async fn run(stack: Stack<'static>) {
 // ============================================================================
 
 fn log(msg: &str) {
-    console::print(msg);
+    klog::log(Level::Info, "netcat_server", msg);
 }
 
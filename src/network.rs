@@ -2,8 +2,21 @@
 //!
 //! Provides network statistics tracking for the async network stack.
 //! The actual networking is handled by async_net module.
+//!
+//! Traffic is also accounted per service, and each service can be capped
+//! with a token bucket (`net.cap.<service>=<bytes/sec>` on the kernel
+//! command line, e.g. `net.cap.ssh=65536`) so one runaway transfer can't
+//! monopolize the single virtio queue.
+//!
+//! Separately, each interface can have an egress shaper in front of its TX
+//! queue (`net.shape.<iface>.rate` and `.burst`). QEMU user-mode networking
+//! buffers whatever it is given, so without one a bulk transfer fills the
+//! host-side queue and every interactive packet waits behind it.
 
-use spinning_top::Spinlock;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::lockdep::Spinlock;
 
 // ============================================================================
 // Statistics (protected by spinlock)
@@ -13,6 +26,8 @@ struct NetStats {
     bytes_rx: u64,
     bytes_tx: u64,
     connections: u64,
+    packets_rx: u64,
+    packets_tx: u64,
 }
 
 impl NetStats {
@@ -21,33 +36,471 @@ impl NetStats {
             bytes_rx: 0,
             bytes_tx: 0,
             connections: 0,
+            packets_rx: 0,
+            packets_tx: 0,
         }
     }
 }
 
 static NET_STATS: Spinlock<NetStats> = Spinlock::new(NetStats::new());
 
+/// Run a closure with IRQs disabled (stats are updated from several tasks)
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Services
+// ============================================================================
+
+/// Network service a stream belongs to, for accounting and caps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    Ssh,
+    Http,
+    Tftp,
+    Telnet,
+    /// Host-side control protocol
+    Ctl,
+    Other,
+}
+
+impl Service {
+    pub const ALL: [Service; 6] = [
+        Service::Ssh,
+        Service::Http,
+        Service::Tftp,
+        Service::Telnet,
+        Service::Ctl,
+        Service::Other,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Service::Ssh => "ssh",
+            Service::Http => "http",
+            Service::Tftp => "tftp",
+            Service::Telnet => "telnet",
+            Service::Ctl => "ctl",
+            Service::Other => "other",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Per-service traffic counters
+#[cfg(any(feature = "shell", feature = "fs"))]
+#[derive(Debug, Clone, Copy)]
+pub struct ServiceStats {
+    pub bytes_rx: u64,
+    pub bytes_tx: u64,
+    /// Times a read or write had to wait for the cap
+    pub throttled: u64,
+    /// Cap in bytes per second (None = unlimited)
+    pub cap: Option<u64>,
+}
+
+/// Token bucket: `tokens` refill at `rate` bytes/sec up to `burst`
+/// Reads are charged after the fact, so tokens can go negative (debt).
+struct Bucket {
+    rate: u64,
+    burst: i64,
+    tokens: i64,
+    last_refill_us: u64,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        // One second of traffic, but at least a full segment
+        Self::with_burst(rate, rate.max(1500))
+    }
+
+    fn with_burst(rate: u64, burst: u64) -> Self {
+        let burst = burst as i64;
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill_us: crate::timer::uptime_us(),
+        }
+    }
+
+    fn refill(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.last_refill_us);
+        let earned = (elapsed as u128 * self.rate as u128 / 1_000_000) as i64;
+        if earned > 0 {
+            self.tokens = (self.tokens + earned).min(self.burst);
+            // Only advance by the time actually converted to tokens
+            self.last_refill_us += (earned as u128 * 1_000_000 / self.rate as u128) as u64;
+        }
+        if self.tokens == self.burst {
+            self.last_refill_us = now;
+        }
+    }
+
+    /// Microseconds until at least one token is available
+    fn wait_us(&self) -> u64 {
+        if self.tokens > 0 {
+            return 0;
+        }
+        let needed = (1 - self.tokens) as u128;
+        (needed * 1_000_000).div_ceil(self.rate as u128) as u64
+    }
+}
+
+struct ServiceState {
+    bytes_rx: u64,
+    bytes_tx: u64,
+    throttled: u64,
+    bucket: Option<Bucket>,
+}
+
+impl ServiceState {
+    const fn new() -> Self {
+        Self {
+            bytes_rx: 0,
+            bytes_tx: 0,
+            throttled: 0,
+            bucket: None,
+        }
+    }
+}
+
+static SERVICES: Spinlock<[ServiceState; Service::ALL.len()]> =
+    Spinlock::new([const { ServiceState::new() }; Service::ALL.len()]);
+
 // ============================================================================
 // Statistics API
 // ============================================================================
 
 /// Increment the connection counter
+#[cfg(feature = "ssh")]
 pub fn increment_connections() {
     NET_STATS.lock().connections += 1;
 }
 
 /// Add to bytes received counter
-pub fn add_bytes_rx(bytes: u64) {
+/// Received bytes count against the service's cap.
+pub fn add_bytes_rx(service: Service, bytes: u64) {
     NET_STATS.lock().bytes_rx += bytes;
+    with_irqs_disabled(|| {
+        let mut services = SERVICES.lock();
+        let state = &mut services[service.index()];
+        state.bytes_rx += bytes;
+        if let Some(bucket) = state.bucket.as_mut() {
+            bucket.refill(crate::timer::uptime_us());
+            bucket.tokens -= bytes as i64;
+        }
+    });
 }
 
 /// Add to bytes transmitted counter
-pub fn add_bytes_tx(bytes: u64) {
+pub fn add_bytes_tx(service: Service, bytes: u64) {
     NET_STATS.lock().bytes_tx += bytes;
+    with_irqs_disabled(|| SERVICES.lock()[service.index()].bytes_tx += bytes);
+}
+
+/// Count a frame received from the NIC
+pub fn count_packet_rx() {
+    with_irqs_disabled(|| NET_STATS.lock().packets_rx += 1);
+    crate::allocator::profile_packet();
+}
+
+/// Count a frame handed to the NIC
+pub fn count_packet_tx() {
+    with_irqs_disabled(|| NET_STATS.lock().packets_tx += 1);
+    crate::allocator::profile_packet();
+}
+
+/// Frames (received, transmitted) since boot
+pub fn packet_counts() -> (u64, u64) {
+    with_irqs_disabled(|| {
+        let s = NET_STATS.lock();
+        (s.packets_rx, s.packets_tx)
+    })
 }
 
 /// Get network statistics: (connections, bytes_rx, bytes_tx)
+#[cfg(any(feature = "shell", feature = "fs"))]
 pub fn get_stats() -> (u64, u64, u64) {
     let s = NET_STATS.lock();
     (s.connections, s.bytes_rx, s.bytes_tx)
 }
+
+/// Per-service counters and cap
+#[cfg(any(feature = "shell", feature = "fs"))]
+pub fn service_stats(service: Service) -> ServiceStats {
+    with_irqs_disabled(|| {
+        let services = SERVICES.lock();
+        let state = &services[service.index()];
+        ServiceStats {
+            bytes_rx: state.bytes_rx,
+            bytes_tx: state.bytes_tx,
+            throttled: state.throttled,
+            cap: state.bucket.as_ref().map(|b| b.rate),
+        }
+    })
+}
+
+// ============================================================================
+// Bandwidth Caps
+// ============================================================================
+
+/// Cap `service` at `bytes_per_sec` (None or 0 removes the cap)
+pub fn set_bandwidth_cap(service: Service, bytes_per_sec: Option<u64>) {
+    with_irqs_disabled(|| {
+        SERVICES.lock()[service.index()].bucket = match bytes_per_sec {
+            Some(rate) if rate > 0 => Some(Bucket::new(rate)),
+            _ => None,
+        };
+    })
+}
+
+/// Ask to send up to `want` bytes for `service`
+///
+/// Returns Ok(n) with the number of bytes that may be sent now (the tokens
+/// are consumed; hand back what isn't sent with `refund_tx`), or
+/// Err(wait_us) if the cap is exhausted.
+pub fn reserve_tx(service: Service, want: usize) -> Result<usize, u64> {
+    with_irqs_disabled(|| {
+        let mut services = SERVICES.lock();
+        let state = &mut services[service.index()];
+        let bucket = match state.bucket.as_mut() {
+            Some(bucket) => bucket,
+            None => return Ok(want),
+        };
+        bucket.refill(crate::timer::uptime_us());
+        if bucket.tokens <= 0 {
+            state.throttled += 1;
+            return Err(bucket.wait_us());
+        }
+        let granted = want.min(bucket.tokens as usize);
+        bucket.tokens -= granted as i64;
+        Ok(granted)
+    })
+}
+
+/// Give back tokens from `reserve_tx` that weren't sent (a short or
+/// failed write)
+pub fn refund_tx(service: Service, unused: usize) {
+    if unused == 0 {
+        return;
+    }
+    with_irqs_disabled(|| {
+        if let Some(bucket) = SERVICES.lock()[service.index()].bucket.as_mut() {
+            bucket.tokens = (bucket.tokens + unused as i64).min(bucket.burst);
+        }
+    })
+}
+
+/// Microseconds a reader of `service` should wait before reading again
+/// (0 if it's within its cap)
+pub fn rx_wait_us(service: Service) -> u64 {
+    with_irqs_disabled(|| {
+        let mut services = SERVICES.lock();
+        let state = &mut services[service.index()];
+        let bucket = match state.bucket.as_mut() {
+            Some(bucket) => bucket,
+            None => return 0,
+        };
+        bucket.refill(crate::timer::uptime_us());
+        let wait = bucket.wait_us();
+        if wait > 0 {
+            state.throttled += 1;
+        }
+        wait
+    })
+}
+
+// ============================================================================
+// Egress Shaping
+// ============================================================================
+
+/// Largest Ethernet frame; a shaper's burst is never smaller
+const MAX_FRAME: u64 = 1514;
+
+/// Egress shaper settings and counters for one interface
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone, Copy)]
+pub struct ShaperStats {
+    /// Bytes per second
+    pub rate: u64,
+    /// Bytes that may go out back to back
+    pub burst: u64,
+    /// Bytes sent through the shaper
+    pub bytes: u64,
+    /// Times the interface had to wait to transmit
+    pub delayed: u64,
+}
+
+struct Shaper {
+    iface: &'static str,
+    bucket: Bucket,
+    bytes: u64,
+    delayed: u64,
+}
+
+static SHAPERS: Spinlock<Vec<Shaper>> = Spinlock::new(Vec::new());
+
+/// Default burst: 20 ms of traffic, so the host queue stays short
+fn default_burst(rate: u64) -> u64 {
+    rate / 50
+}
+
+/// Shape `iface` to `rate` bytes/sec with bursts of `burst` bytes (rounded
+/// up to one frame); a rate of 0 removes the shaper
+pub fn set_shaper(iface: &'static str, rate: u64, burst: Option<u64>) {
+    with_irqs_disabled(|| {
+        let mut shapers = SHAPERS.lock();
+        shapers.retain(|s| s.iface != iface);
+        if rate == 0 {
+            return;
+        }
+        let burst = burst.unwrap_or_else(|| default_burst(rate)).max(MAX_FRAME);
+        if shapers.try_reserve(1).is_ok() {
+            shapers.push(Shaper {
+                iface,
+                bucket: Bucket::with_burst(rate, burst),
+                bytes: 0,
+                delayed: 0,
+            });
+        }
+    })
+}
+
+/// Microseconds `iface` must wait before handing the NIC another frame
+/// (0 if it may send now)
+pub fn shaper_wait_us(iface: &str) -> u64 {
+    with_irqs_disabled(|| {
+        let mut shapers = SHAPERS.lock();
+        let shaper = match shapers.iter_mut().find(|s| s.iface == iface) {
+            Some(shaper) => shaper,
+            None => return 0,
+        };
+        shaper.bucket.refill(crate::timer::uptime_us());
+        let wait = shaper.bucket.wait_us();
+        if wait > 0 {
+            shaper.delayed += 1;
+        }
+        wait
+    })
+}
+
+/// Charge a frame of `bytes` sent on `iface` (may leave the bucket in debt)
+pub fn shaper_charge(iface: &str, bytes: usize) {
+    with_irqs_disabled(|| {
+        let mut shapers = SHAPERS.lock();
+        if let Some(shaper) = shapers.iter_mut().find(|s| s.iface == iface) {
+            shaper.bucket.refill(crate::timer::uptime_us());
+            shaper.bucket.tokens -= bytes as i64;
+            shaper.bytes += bytes as u64;
+        }
+    })
+}
+
+/// Interfaces with a shaper and their counters
+#[cfg(feature = "shell")]
+pub fn shaper_stats() -> Vec<(&'static str, ShaperStats)> {
+    with_irqs_disabled(|| {
+        SHAPERS
+            .lock()
+            .iter()
+            .map(|s| {
+                let stats = ShaperStats {
+                    rate: s.bucket.rate,
+                    burst: s.bucket.burst as u64,
+                    bytes: s.bytes,
+                    delayed: s.delayed,
+                };
+                (s.iface, stats)
+            })
+            .collect()
+    })
+}
+
+/// Set up `iface`'s shaper from `net.shape.<iface>.rate` and `.burst`
+pub fn apply_shaper_config(iface: &'static str) {
+    let rate = crate::config::get_u64(&alloc::format!("net.shape.{}.rate", iface));
+    let burst = crate::config::get_u64(&alloc::format!("net.shape.{}.burst", iface));
+    if let Some(rate) = rate.filter(|&r| r > 0) {
+        set_shaper(iface, rate, burst);
+        crate::kinfo!("[Net] {} shaped to {} bytes/s", iface, rate);
+    }
+}
+
+/// Load caps from `net.cap.<service>` settings
+pub fn apply_config() {
+    for service in Service::ALL {
+        let key = alloc::format!("net.cap.{}", service.as_str());
+        if let Some(rate) = crate::config::get_u64(&key) {
+            set_bandwidth_cap(service, Some(rate));
+            crate::kinfo!(
+                "[Net] {} capped at {} bytes/s",
+                service.as_str(),
+                rate
+            );
+        }
+    }
+}
+
+// ============================================================================
+// Restart
+// ============================================================================
+
+/// Grace period before a requested restart, so the reply to whoever asked
+/// for it (usually over the network) still goes out
+#[cfg(feature = "shell")]
+const RESTART_DELAY_US: u64 = 250_000;
+
+/// Uptime at which the main loop should rebuild the stack (0 = not asked)
+static RESTART_AT: AtomicU64 = AtomicU64::new(0);
+
+static RESTARTS: AtomicU64 = AtomicU64::new(0);
+
+/// Ask the main loop to tear the network stack down and bring it up again,
+/// e.g. to recover from a wedged driver without rebooting
+#[cfg(feature = "shell")]
+pub fn request_restart() {
+    let at = crate::timer::uptime_us() + RESTART_DELAY_US;
+    let _ = RESTART_AT.compare_exchange(0, at, Ordering::AcqRel, Ordering::Relaxed);
+}
+
+/// Whether a requested restart is due; clears the request if so
+pub fn take_restart() -> bool {
+    let at = RESTART_AT.load(Ordering::Acquire);
+    at != 0
+        && crate::timer::uptime_us() >= at
+        && RESTART_AT
+            .compare_exchange(at, 0, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+}
+
+/// Whether a restart has been requested and not yet carried out
+#[cfg(feature = "shell")]
+pub fn restart_pending() -> bool {
+    RESTART_AT.load(Ordering::Acquire) != 0
+}
+
+/// Count one completed restart
+pub fn note_restart() {
+    RESTARTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Restarts since boot
+#[cfg(any(feature = "shell", feature = "fs"))]
+pub fn restarts() -> u64 {
+    RESTARTS.load(Ordering::Relaxed)
+}
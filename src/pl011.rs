@@ -0,0 +1,253 @@
+//! PL011 UART Driver
+//!
+//! Register-level access to an ARM PrimeCell PL011 UART. Buffering and
+//! interrupt handling policy live in the console module; this driver only
+//! pokes registers and never blocks unless a method says so.
+//!
+//! `init` programs the line settings (baud rate, 8N1, FIFOs, flow control)
+//! rather than relying on firmware having done so, which QEMU does but real
+//! boards don't always.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::error::{ErrorKind, KError, KResult};
+
+// ============================================================================
+// Registers
+// ============================================================================
+
+const UART_DR: usize = 0x000; // Data register
+const UART_FR: usize = 0x018; // Flag register
+const UART_IBRD: usize = 0x024; // Integer baud rate divisor
+const UART_FBRD: usize = 0x028; // Fractional baud rate divisor
+const UART_LCR_H: usize = 0x02C; // Line control
+const UART_CR: usize = 0x030; // Control register
+const UART_IFLS: usize = 0x034; // FIFO interrupt level select
+const UART_IMSC: usize = 0x038; // Interrupt mask set/clear
+const UART_MIS: usize = 0x040; // Masked interrupt status
+const UART_ICR: usize = 0x044; // Interrupt clear
+const UART_PERIPH_ID0: usize = 0xFE0; // Peripheral ID (4 registers)
+const UART_PCELL_ID0: usize = 0xFF0; // PrimeCell ID (4 registers)
+
+/// Low byte of PeriphID0..3 for a PL011 (revision nibble masked out)
+const PERIPH_ID: [u32; 4] = [0x11, 0x10, 0x04, 0x00];
+/// Low byte of PCellID0..3 for every PrimeCell
+const PCELL_ID: [u32; 4] = [0x0D, 0xF0, 0x05, 0xB1];
+
+// Flag register bits
+const FR_BUSY: u32 = 1 << 3;
+const FR_RXFE: u32 = 1 << 4; // Receive FIFO empty
+const FR_TXFF: u32 = 1 << 5; // Transmit FIFO full
+const FR_TXFE: u32 = 1 << 7; // Transmit FIFO empty
+
+// Line control bits
+const LCR_H_FEN: u32 = 1 << 4; // Enable FIFOs
+const LCR_H_WLEN_8: u32 = 0b11 << 5; // 8 data bits
+
+// Control register bits
+const CR_UARTEN: u32 = 1 << 0;
+const CR_TXE: u32 = 1 << 8;
+const CR_RXE: u32 = 1 << 9;
+const CR_RTSEN: u32 = 1 << 14; // RTS hardware flow control
+const CR_CTSEN: u32 = 1 << 15; // CTS hardware flow control
+
+// Interrupt bits (IMSC / MIS / ICR)
+pub const INT_RX: u32 = 1 << 4; // Receive
+pub const INT_TX: u32 = 1 << 5; // Transmit
+pub const INT_RT: u32 = 1 << 6; // Receive timeout
+const INT_ALL: u32 = 0x7ff;
+
+// FIFO levels: interrupt at 1/2 full (TX and RX)
+const IFLS_HALF: u32 = (0b010 << 3) | 0b010;
+
+// ============================================================================
+// Line Settings
+// ============================================================================
+
+/// Line settings applied by `Pl011::init` (always 8N1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UartConfig {
+    pub baud: u32,
+    /// Reference clock (UARTCLK) the divisors are computed from
+    pub clock_hz: u32,
+    /// RTS/CTS hardware flow control
+    pub flow_control: bool,
+}
+
+impl UartConfig {
+    /// 115200 baud from the 24 MHz clock QEMU's virt board gives the UART
+    pub const DEFAULT: UartConfig = UartConfig {
+        baud: 115_200,
+        clock_hz: 24_000_000,
+        flow_control: false,
+    };
+}
+
+/// Integer and fractional (64ths) divisors for `baud` from `clock_hz`,
+/// None if the UART can't get close to that rate
+pub fn divisors(clock_hz: u32, baud: u32) -> Option<(u32, u32)> {
+    if baud == 0 {
+        return None;
+    }
+    // clock / (16 * baud) in 1/64 units, rounded
+    let div = (clock_hz as u64 * 4 + baud as u64 / 2) / baud as u64;
+    let (ibrd, fbrd) = ((div >> 6) as u32, (div & 0x3f) as u32);
+    if ibrd == 0 || ibrd > 0xffff || (ibrd == 0xffff && fbrd != 0) {
+        return None;
+    }
+    Some((ibrd, fbrd))
+}
+
+// ============================================================================
+// Driver
+// ============================================================================
+
+/// A PL011 UART at a fixed MMIO base address
+pub struct Pl011 {
+    base: usize,
+}
+
+impl Pl011 {
+    /// Create a driver for the UART at `base`
+    ///
+    /// # Safety
+    /// `base` must be the MMIO address of a PL011 that nothing else drives.
+    pub const unsafe fn new(base: usize) -> Self {
+        Self { base }
+    }
+
+    #[inline]
+    fn read(&self, offset: usize) -> u32 {
+        // SAFETY: base points at PL011 registers (see `new`)
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+
+    #[inline]
+    fn write(&self, offset: usize, value: u32) {
+        // SAFETY: base points at PL011 registers (see `new`)
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    /// Program baud rate, 8N1 framing, FIFOs and flow control, then enable
+    /// the transmitter and receiver
+    ///
+    /// Waits for the transmitter to finish first; anything still in the
+    /// receive FIFO is lost. Interrupt masks are kept.
+    pub fn init(&self, config: &UartConfig) -> KResult<()> {
+        let (ibrd, fbrd) = divisors(config.clock_hz, config.baud)
+            .ok_or(KError::with_context(ErrorKind::InvalidArgument, "uart baud rate"))?;
+
+        while !self.tx_idle() {
+            core::hint::spin_loop();
+        }
+        self.write(UART_CR, 0);
+        // Clearing FEN flushes the FIFOs
+        self.write(UART_LCR_H, 0);
+        self.write(UART_ICR, INT_ALL);
+
+        self.write(UART_IBRD, ibrd);
+        self.write(UART_FBRD, fbrd);
+        // Divisors only take effect on the LCR_H write that follows
+        self.write(UART_LCR_H, LCR_H_WLEN_8 | LCR_H_FEN);
+        self.write(UART_IFLS, IFLS_HALF);
+
+        let mut cr = CR_UARTEN | CR_TXE | CR_RXE;
+        if config.flow_control {
+            cr |= CR_RTSEN | CR_CTSEN;
+        }
+        self.write(UART_CR, cr);
+        Ok(())
+    }
+
+    /// Baud rate the divisors are currently set for
+    pub fn baud_rate(&self, clock_hz: u32) -> u32 {
+        let div = ((self.read(UART_IBRD) & 0xffff) << 6) | (self.read(UART_FBRD) & 0x3f);
+        if div == 0 {
+            return 0;
+        }
+        (clock_hz as u64 * 4 / div as u64) as u32
+    }
+
+    /// True if the ID registers say a PL011 is at this address
+    ///
+    /// Unpopulated MMIO on QEMU virt usually reads as zero, but the reads
+    /// are probed so an address that aborts also counts as a mismatch.
+    pub fn is_present(&self) -> bool {
+        let id = |offset| crate::probe::read32(self.base + offset).map(|v| v & 0xff);
+        (0..4).all(|i| {
+            let (Ok(periph), Ok(pcell)) = (id(UART_PERIPH_ID0 + i * 4), id(UART_PCELL_ID0 + i * 4))
+            else {
+                return false;
+            };
+            // PeriphID2 carries the revision in its top nibble
+            let periph = if i == 2 { periph & 0x0f } else { periph };
+            periph == PERIPH_ID[i] && pcell == PCELL_ID[i]
+        })
+    }
+
+    /// True if the transmit FIFO cannot accept another byte
+    #[inline]
+    pub fn tx_full(&self) -> bool {
+        self.read(UART_FR) & FR_TXFF != 0
+    }
+
+    /// True once every queued byte has left the shift register
+    pub fn tx_idle(&self) -> bool {
+        let fr = self.read(UART_FR);
+        fr & FR_TXFE != 0 && fr & FR_BUSY == 0
+    }
+
+    /// True if the receive FIFO holds no data
+    #[inline]
+    pub fn rx_empty(&self) -> bool {
+        self.read(UART_FR) & FR_RXFE != 0
+    }
+
+    /// Write a byte without checking FIFO space (caller checks `tx_full`)
+    #[inline]
+    pub fn write_byte(&self, byte: u8) {
+        self.write(UART_DR, byte as u32);
+    }
+
+    /// Write a byte, spinning while the transmit FIFO is full
+    pub fn write_byte_blocking(&self, byte: u8) {
+        while self.tx_full() {
+            core::hint::spin_loop();
+        }
+        self.write_byte(byte);
+    }
+
+    /// Read a byte if one is available
+    #[inline]
+    pub fn read_byte(&self) -> Option<u8> {
+        if self.rx_empty() {
+            None
+        } else {
+            Some(self.read(UART_DR) as u8)
+        }
+    }
+
+    /// Unmask the given interrupt sources
+    pub fn enable_interrupts(&self, mask: u32) {
+        let imsc = self.read(UART_IMSC);
+        self.write(UART_IMSC, imsc | mask);
+    }
+
+    /// Mask the given interrupt sources
+    pub fn disable_interrupts(&self, mask: u32) {
+        let imsc = self.read(UART_IMSC);
+        self.write(UART_IMSC, imsc & !mask);
+    }
+
+    /// Pending interrupts that are not masked
+    #[inline]
+    pub fn masked_interrupts(&self) -> u32 {
+        self.read(UART_MIS)
+    }
+
+    /// Acknowledge the given interrupt sources
+    #[inline]
+    pub fn clear_interrupts(&self, mask: u32) {
+        self.write(UART_ICR, mask);
+    }
+}
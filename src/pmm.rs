@@ -0,0 +1,369 @@
+//! Physical Page-Frame Allocator
+//!
+//! Buddy allocator over all RAM above the kernel image. Blocks are 2^order
+//! contiguous 4 KB pages; freeing a block merges it with its buddy when both
+//! are free. The byte heap (see `allocator`) grows by requesting blocks from
+//! here, and anything needing large physically contiguous memory (DMA
+//! buffers, future address spaces) allocates pages directly.
+//!
+//! Memory shared with devices comes from a second, small buddy allocator
+//! over the DMA pool, which the MMU maps non-cacheable (`alloc_dma_pages`).
+//!
+//! Physical == virtual on QEMU virt, so addresses are returned as usize.
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Largest block is 2^MAX_ORDER pages (128 MB)
+pub const MAX_ORDER: usize = 15;
+
+/// Per-page state byte: head of a free block of the given order
+const STATE_FREE: u8 = 0x80;
+/// Per-page state byte: allocated, metadata, or interior of a block
+const STATE_USED: u8 = 0x00;
+
+/// Run a closure with IRQs disabled to prevent deadlock with IRQ-time users
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Buddy Allocator
+// ============================================================================
+
+/// Intrusive free-list node stored in the first bytes of each free block
+#[repr(C)]
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+}
+
+/// Buddy allocator over one contiguous range of pages
+pub struct BuddyAllocator {
+    /// Address of page 0
+    base: usize,
+    /// Number of managed pages (including metadata pages)
+    pages: usize,
+    /// One state byte per page, stored in the first pages of the region
+    state: usize,
+    free_lists: [usize; MAX_ORDER + 1],
+    free_pages: usize,
+    metadata_pages: usize,
+}
+
+/// Page allocator statistics
+#[derive(Debug, Clone, Copy)]
+pub struct PageStats {
+    pub total_pages: usize,
+    pub free_pages: usize,
+    pub metadata_pages: usize,
+    /// Number of free blocks of each order
+    pub free_blocks: [usize; MAX_ORDER + 1],
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        Self {
+            base: 0,
+            pages: 0,
+            state: 0,
+            free_lists: [0; MAX_ORDER + 1],
+            free_pages: 0,
+            metadata_pages: 0,
+        }
+    }
+
+    fn get_state(&self, page: usize) -> u8 {
+        // SAFETY: page < self.pages and the state array has self.pages bytes
+        unsafe { *((self.state + page) as *const u8) }
+    }
+
+    fn set_state(&mut self, page: usize, value: u8) {
+        // SAFETY: as above
+        unsafe { *((self.state + page) as *mut u8) = value }
+    }
+
+    fn page_addr(&self, page: usize) -> usize {
+        self.base + page * PAGE_SIZE
+    }
+
+    fn push_free(&mut self, page: usize, order: usize) {
+        let addr = self.page_addr(page);
+        let head = self.free_lists[order];
+        // SAFETY: addr is the start of a free block we own
+        unsafe {
+            let node = addr as *mut FreeBlock;
+            (*node).next = head;
+            (*node).prev = 0;
+            if head != 0 {
+                (*(head as *mut FreeBlock)).prev = addr;
+            }
+        }
+        self.free_lists[order] = addr;
+        self.set_state(page, STATE_FREE | order as u8);
+        self.free_pages += 1 << order;
+    }
+
+    fn remove_free(&mut self, page: usize, order: usize) {
+        let addr = self.page_addr(page);
+        // SAFETY: addr is on the free list for this order
+        unsafe {
+            let node = addr as *mut FreeBlock;
+            let (next, prev) = ((*node).next, (*node).prev);
+            if prev != 0 {
+                (*(prev as *mut FreeBlock)).next = next;
+            } else {
+                self.free_lists[order] = next;
+            }
+            if next != 0 {
+                (*(next as *mut FreeBlock)).prev = prev;
+            }
+        }
+        self.set_state(page, STATE_USED);
+        self.free_pages -= 1 << order;
+    }
+
+    /// Take over [base, base + size); an unaligned base is rounded up and
+    /// the bytes skipped come off the size
+    pub fn init(&mut self, base: usize, size: usize) -> KResult<()> {
+        let aligned = (base + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let pages = size.saturating_sub(aligned - base) / PAGE_SIZE;
+        let base = aligned;
+        if pages < 2 {
+            return Err(KError::with_context(
+                ErrorKind::InvalidRegion,
+                "page allocator region too small",
+            ));
+        }
+
+        // State bytes live at the start of the region
+        let metadata_pages = pages.div_ceil(PAGE_SIZE);
+        self.base = base;
+        self.pages = pages;
+        self.state = base;
+        self.metadata_pages = metadata_pages;
+        self.free_lists = [0; MAX_ORDER + 1];
+        self.free_pages = 0;
+
+        for page in 0..pages {
+            self.set_state(page, STATE_USED);
+        }
+
+        // Carve the rest into the largest naturally aligned blocks that fit
+        let mut page = metadata_pages;
+        while page < pages {
+            let mut order = MAX_ORDER;
+            while order > 0 && (!page.is_multiple_of(1 << order) || page + (1 << order) > pages) {
+                order -= 1;
+            }
+            self.push_free(page, order);
+            page += 1 << order;
+        }
+
+        Ok(())
+    }
+
+    pub fn alloc(&mut self, order: usize) -> Option<usize> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        // Smallest non-empty list at or above the requested order
+        let mut found = order;
+        while found <= MAX_ORDER && self.free_lists[found] == 0 {
+            found += 1;
+        }
+        if found > MAX_ORDER {
+            return None;
+        }
+
+        let addr = self.free_lists[found];
+        let page = (addr - self.base) / PAGE_SIZE;
+        self.remove_free(page, found);
+
+        // Split down, returning upper halves to the free lists
+        while found > order {
+            found -= 1;
+            self.push_free(page + (1 << found), found);
+        }
+
+        Some(addr)
+    }
+
+    fn free(&mut self, addr: usize, order: usize) {
+        let mut page = (addr - self.base) / PAGE_SIZE;
+        let mut order = order;
+
+        while order < MAX_ORDER {
+            let buddy = page ^ (1 << order);
+            if buddy + (1 << order) > self.pages
+                || self.get_state(buddy) != STATE_FREE | order as u8
+            {
+                break;
+            }
+            self.remove_free(buddy, order);
+            page = page.min(buddy);
+            order += 1;
+        }
+
+        self.push_free(page, order);
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.page_addr(self.metadata_pages) && addr < self.page_addr(self.pages)
+    }
+
+    /// Check every free list: nodes inside the region, state bytes and
+    /// back links matching, and the page count adding up
+    fn validate(&self) -> KResult<usize> {
+        let corrupted = |what| Err(KError::with_context(ErrorKind::Corrupted, what));
+        let mut pages = 0;
+        for order in 0..=MAX_ORDER {
+            let mut prev = 0;
+            let mut node = self.free_lists[order];
+            while node != 0 {
+                if !self.contains(node) || !(node - self.base).is_multiple_of(PAGE_SIZE) {
+                    return corrupted("free block outside page allocator");
+                }
+                let page = (node - self.base) / PAGE_SIZE;
+                if self.get_state(page) != STATE_FREE | order as u8 {
+                    return corrupted("free block state byte");
+                }
+                // SAFETY: node is a page-aligned address inside the region
+                let block = unsafe { &*(node as *const FreeBlock) };
+                if block.prev != prev {
+                    return corrupted("free list back link");
+                }
+                pages += 1 << order;
+                // A cycle would count past every page there is
+                if pages > self.free_pages {
+                    return corrupted("free list longer than free page count");
+                }
+                prev = node;
+                node = block.next;
+            }
+        }
+        if pages != self.free_pages {
+            return corrupted("free page count");
+        }
+        Ok(pages)
+    }
+
+    pub fn stats(&self) -> PageStats {
+        let mut free_blocks = [0; MAX_ORDER + 1];
+        for (order, count) in free_blocks.iter_mut().enumerate() {
+            let mut node = self.free_lists[order];
+            while node != 0 {
+                *count += 1;
+                // SAFETY: node is on a free list
+                node = unsafe { (*(node as *const FreeBlock)).next };
+            }
+        }
+
+        PageStats {
+            total_pages: self.pages,
+            free_pages: self.free_pages,
+            metadata_pages: self.metadata_pages,
+            free_blocks,
+        }
+    }
+}
+
+static PMM: Spinlock<BuddyAllocator> = Spinlock::new(BuddyAllocator::new());
+
+/// Pages of the non-cacheable DMA pool
+static DMA_POOL: Spinlock<BuddyAllocator> = Spinlock::new(BuddyAllocator::new());
+
+// ============================================================================
+// Public API
+// ============================================================================
+
+/// Hand the RAM range [base, base + size) to the page allocator
+pub fn init(base: usize, size: usize) -> KResult<()> {
+    with_irqs_disabled(|| PMM.lock().init(base, size))
+}
+
+/// Smallest order whose block holds `bytes`
+pub fn order_for_size(bytes: usize) -> usize {
+    let pages = bytes.div_ceil(PAGE_SIZE).max(1);
+    pages.next_power_of_two().trailing_zeros() as usize
+}
+
+/// Allocate 2^order contiguous pages, aligned to their size
+pub fn alloc_pages(order: usize) -> Option<usize> {
+    with_irqs_disabled(|| PMM.lock().alloc(order))
+}
+
+/// Return a block from `alloc_pages`
+///
+/// # Safety
+/// `addr` and `order` must match a previous `alloc_pages` call and the
+/// memory must no longer be in use.
+pub unsafe fn free_pages(addr: usize, order: usize) {
+    with_irqs_disabled(|| {
+        let mut pmm = PMM.lock();
+        if pmm.contains(addr) {
+            pmm.free(addr, order);
+        }
+    })
+}
+
+/// Hand the DMA pool [base, base + size) to its own page allocator
+pub fn init_dma(base: usize, size: usize) -> KResult<()> {
+    with_irqs_disabled(|| DMA_POOL.lock().init(base, size))
+}
+
+/// Allocate 2^order contiguous pages from the DMA pool, aligned to their
+/// size; the memory is mapped non-cacheable
+pub fn alloc_dma_pages(order: usize) -> Option<usize> {
+    with_irqs_disabled(|| DMA_POOL.lock().alloc(order))
+}
+
+/// Return a block from `alloc_dma_pages`
+///
+/// # Safety
+/// `addr` and `order` must match a previous `alloc_dma_pages` call and
+/// no device may still access the memory.
+pub unsafe fn free_dma_pages(addr: usize, order: usize) {
+    with_irqs_disabled(|| {
+        let mut pool = DMA_POOL.lock();
+        if pool.contains(addr) {
+            pool.free(addr, order);
+        }
+    })
+}
+
+/// DMA pool statistics
+pub fn dma_stats() -> PageStats {
+    with_irqs_disabled(|| DMA_POOL.lock().stats())
+}
+
+/// Page allocator statistics
+pub fn stats() -> PageStats {
+    with_irqs_disabled(|| PMM.lock().stats())
+}
+
+/// Walk the free lists and check their links and bookkeeping
+///
+/// Returns the number of free pages, or `Corrupted` naming the first
+/// inconsistency found. Runs with IRQs disabled for the whole walk, which
+/// is short: it touches one word per free block, not the pages.
+pub fn validate() -> KResult<usize> {
+    with_irqs_disabled(|| PMM.lock().validate())
+}
@@ -0,0 +1,106 @@
+//! Safe MMIO Probing
+//!
+//! Optional devices (a second UART, the RTC, virtio slots) sit at fixed
+//! addresses on QEMU virt, but whether anything answers there depends on
+//! how QEMU was started. Reading an address nothing backs raises a data
+//! abort, which is normally fatal.
+//!
+//! `read32` opens a probe window around the address before reading it.
+//! If the read aborts inside the window, the sync exception handler asks
+//! `handle_abort` first: it records the fault, skips the load and lets the
+//! probe return `NoDevice` instead of panicking.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::exceptions::{Esr, ExceptionFrame};
+
+/// Register the probe load targets, so the handler knows what to zero
+const PROBE_REG: usize = 9;
+
+/// Address range of the probe in progress (empty when not probing)
+static WINDOW_START: AtomicUsize = AtomicUsize::new(0);
+static WINDOW_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Set by the abort handler when the probe faulted
+static FAULTED: AtomicBool = AtomicBool::new(false);
+
+/// Run a closure with IRQs disabled (the window belongs to one probe at a time)
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+/// Read the 32-bit register at `addr`, or `NoDevice` if the read aborts
+pub fn read32(addr: usize) -> KResult<u32> {
+    if !addr.is_multiple_of(4) {
+        return Err(KError::with_context(ErrorKind::InvalidArgument, "probe alignment"));
+    }
+    with_irqs_disabled(|| {
+        FAULTED.store(false, Ordering::Relaxed);
+        WINDOW_START.store(addr, Ordering::Relaxed);
+        WINDOW_END.store(addr + 4, Ordering::Relaxed);
+
+        let value: u64;
+        // SAFETY: a fault on this load is caught by `handle_abort`, which
+        // zeroes the destination register and resumes after the load
+        unsafe {
+            core::arch::asm!(
+                "ldr w9, [{addr}]",
+                addr = in(reg) addr,
+                out("x9") value,
+                options(nostack, readonly),
+            );
+        }
+
+        WINDOW_START.store(0, Ordering::Relaxed);
+        WINDOW_END.store(0, Ordering::Relaxed);
+        if FAULTED.load(Ordering::Relaxed) {
+            Err(KError::with_context(ErrorKind::NoDevice, "probe"))
+        } else {
+            Ok(value as u32)
+        }
+    })
+}
+
+/// True if a 32-bit read of `addr` completes
+pub fn is_readable(addr: usize) -> bool {
+    read32(addr).is_ok()
+}
+
+/// Claim a data abort raised by a probe
+///
+/// Called by the sync exception handler before treating the abort as
+/// fatal. Returns true if the fault was inside the open probe window, in
+/// which case the frame has been fixed up to resume after the load.
+pub fn handle_abort(frame: &mut ExceptionFrame) -> bool {
+    let esr = Esr(frame.esr);
+    if !esr.is_data_abort() {
+        return false;
+    }
+    let start = WINDOW_START.load(Ordering::Relaxed);
+    let end = WINDOW_END.load(Ordering::Relaxed);
+    if start == end {
+        return false;
+    }
+    // External aborts may not report an address; the window being open
+    // (with IRQs masked) is then enough to pin it on the probe
+    if esr.far_valid() && !(start..end).contains(&(frame.far as usize)) {
+        return false;
+    }
+
+    FAULTED.store(true, Ordering::Relaxed);
+    frame.x[PROBE_REG] = 0;
+    // The probe is a single 4-byte load
+    frame.elr += 4;
+    true
+}
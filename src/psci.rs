@@ -0,0 +1,50 @@
+//! PSCI Power Control
+//!
+//! System reset and power-off through the firmware's Power State Coordination Interface.
+//! QEMU virt implements PSCI itself and, when the kernel runs at EL1
+//! without EL3 firmware (the default), expects calls through `hvc`;
+//! `psci.method=smc` switches the conduit for boards with a secure monitor.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// PSCI 0.2 SYSTEM_OFF (SMC32 function ID)
+const SYSTEM_OFF: u32 = 0x8400_0008;
+
+/// PSCI 0.2 SYSTEM_RESET (SMC32 function ID)
+const SYSTEM_RESET: u32 = 0x8400_0009;
+
+/// Use `smc` instead of `hvc`
+static USE_SMC: AtomicBool = AtomicBool::new(false);
+
+/// Pick the conduit from `psci.method` (call once the heap is up)
+pub fn init() {
+    if let Some(method) = crate::config::get("psci.method") {
+        USE_SMC.store(method == "smc", Ordering::Relaxed);
+    }
+}
+
+/// Make a PSCI call with no arguments; returns the firmware's status
+fn call(function: u32) -> i64 {
+    let mut x0 = function as u64;
+    // SAFETY: PSCI calls only clobber registers the SMC calling convention
+    // allows; a call that doesn't return (reset) doesn't matter here
+    unsafe {
+        if USE_SMC.load(Ordering::Relaxed) {
+            core::arch::asm!("smc #0", inout("x0") x0, clobber_abi("C"));
+        } else {
+            core::arch::asm!("hvc #0", inout("x0") x0, clobber_abi("C"));
+        }
+    }
+    x0 as i64
+}
+
+/// Reset the machine; returns the PSCI error if the firmware refused
+pub fn system_reset() -> i64 {
+    call(SYSTEM_RESET)
+}
+
+/// Power the machine off (QEMU exits); returns the PSCI error if the
+/// firmware refused
+pub fn system_off() -> i64 {
+    call(SYSTEM_OFF)
+}
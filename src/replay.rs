@@ -0,0 +1,335 @@
+//! Deterministic Executor Replay
+//!
+//! An executor for reproducing async ordering bugs. In record mode it runs
+//! a set of tasks and logs every decision that could differ between runs:
+//! which tasks were woken, which one was polled next, and when time moved
+//! on. Time is virtual - embassy timers read a clock that only advances
+//! when every task is idle, jumping straight to the next alarm - so a
+//! trace doesn't depend on how fast the machine happened to be.
+//!
+//! Replay mode runs the same tasks again against a trace and forces the
+//! logged order, stopping at the first event the tasks don't match. A
+//! trace printed as text (`Trace::to_text`) from a run that misbehaved can
+//! be pasted into a test and replayed on every boot; editing the poll
+//! order in it explores other interleavings.
+//!
+//! The tasks' only inputs must be each other and time: wakes from real
+//! devices aren't reproducible. While a run is in progress the embassy
+//! clock is virtual for the whole kernel, so don't run one alongside the
+//! network loop.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Waker};
+
+use crate::embassy_time_driver;
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+
+/// Run a closure with IRQs disabled (wakers may be invoked from IRQ context)
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Trace
+// ============================================================================
+
+/// One scheduling decision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// The task's waker was invoked
+    Wake(usize),
+    /// The task was polled
+    Poll(usize),
+    /// The virtual clock jumped to this time (µs)
+    Advance(u64),
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceEvent::Wake(task) => write!(f, "wake {}", task),
+            TraceEvent::Poll(task) => write!(f, "poll {}", task),
+            TraceEvent::Advance(us) => write!(f, "advance {}", us),
+        }
+    }
+}
+
+/// A recorded run: the virtual start time and every decision in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    pub start_us: u64,
+    pub events: Vec<TraceEvent>,
+}
+
+impl Trace {
+    /// One line per event, after a `start` line
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "start {}", self.start_us);
+        for event in &self.events {
+            let _ = writeln!(out, "{}", event);
+        }
+        out
+    }
+
+    /// Parse the format `to_text` writes
+    pub fn parse(text: &str) -> KResult<Self> {
+        let bad = || KError::with_context(ErrorKind::InvalidArgument, "replay trace");
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+        let start_us = lines
+            .next()
+            .and_then(|line| line.strip_prefix("start "))
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(bad)?;
+
+        let mut events = Vec::new();
+        for line in lines {
+            let (kind, value) = line.split_once(' ').ok_or_else(bad)?;
+            let event = match kind {
+                "wake" => TraceEvent::Wake(value.parse().map_err(|_| bad())?),
+                "poll" => TraceEvent::Poll(value.parse().map_err(|_| bad())?),
+                "advance" => TraceEvent::Advance(value.parse().map_err(|_| bad())?),
+                _ => return Err(bad()),
+            };
+            events.push(event);
+        }
+        Ok(Self { start_us, events })
+    }
+}
+
+/// Where a replay stopped matching its trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the first event that couldn't be reproduced (the trace
+    /// length if the trace ended with tasks unfinished)
+    pub index: usize,
+    pub expected: Option<TraceEvent>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expected {
+            Some(event) => write!(f, "replay diverged at event {} ({})", self.index, event),
+            None => write!(f, "replay trace ended after {} events with tasks unfinished", self.index),
+        }
+    }
+}
+
+// ============================================================================
+// Executor
+// ============================================================================
+
+/// Tasks woken since the executor last looked, in wake order
+struct Woken(Spinlock<VecDeque<usize>>);
+
+struct TaskWaker {
+    task: usize,
+    woken: Arc<Woken>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        with_irqs_disabled(|| {
+            let mut queue = self.woken.0.lock();
+            // Capacity was reserved for every task, so this never allocates
+            if !queue.contains(&self.task) {
+                queue.push_back(self.task);
+            }
+        });
+    }
+}
+
+struct Task<'a> {
+    /// None once finished
+    future: Option<Pin<Box<dyn Future<Output = ()> + 'a>>>,
+    waker: Waker,
+}
+
+/// Puts embassy time back on the hardware clock when a run ends
+struct VirtualClock;
+
+impl VirtualClock {
+    fn enter(start_us: u64) -> Self {
+        embassy_time_driver::set_virtual_time(Some(start_us));
+        Self
+    }
+}
+
+impl Drop for VirtualClock {
+    fn drop(&mut self) {
+        embassy_time_driver::set_virtual_time(None);
+    }
+}
+
+/// Executor that records or replays its scheduling decisions
+pub struct Executor<'a> {
+    tasks: Vec<Task<'a>>,
+    woken: Arc<Woken>,
+    start_us: u64,
+}
+
+impl<'a> Executor<'a> {
+    /// An executor whose virtual clock starts at `start_us`
+    pub fn new(start_us: u64) -> Self {
+        Self {
+            tasks: Vec::new(),
+            woken: Arc::new(Woken(Spinlock::new(VecDeque::new()))),
+            start_us,
+        }
+    }
+
+    /// Add a task; tasks are numbered from 0 in spawn order
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'a) -> usize {
+        let task = self.tasks.len();
+        let waker = Waker::from(Arc::new(TaskWaker {
+            task,
+            woken: self.woken.clone(),
+        }));
+        self.tasks.push(Task {
+            future: Some(Box::pin(future)),
+            waker,
+        });
+        task
+    }
+
+    /// Run every task to completion, returning the trace of the run
+    ///
+    /// Fails with `TimedOut` if the tasks stall: none is woken and no
+    /// alarm is set.
+    pub fn record(mut self) -> KResult<Trace> {
+        let _clock = VirtualClock::enter(self.start_us);
+        self.start_all();
+
+        let mut events = Vec::new();
+        let mut ready = VecDeque::new();
+        loop {
+            for task in self.take_woken() {
+                events.push(TraceEvent::Wake(task));
+                ready.push_back(task);
+            }
+            if let Some(task) = ready.pop_front() {
+                events.push(TraceEvent::Poll(task));
+                self.poll(task);
+                continue;
+            }
+            if self.finished() {
+                break;
+            }
+            match embassy_time_driver::next_alarm() {
+                Some(at) => {
+                    events.push(TraceEvent::Advance(at));
+                    embassy_time_driver::advance_virtual_time(at);
+                }
+                None => return Err(KError::with_context(ErrorKind::TimedOut, "replay: tasks stalled")),
+            }
+        }
+
+        Ok(Trace {
+            start_us: self.start_us,
+            events,
+        })
+    }
+
+    /// Run the tasks in the order `trace` prescribes
+    ///
+    /// Each event must be possible when it comes up: a logged wake must
+    /// have happened, a polled task must be woken and time may only
+    /// advance to the next alarm once nothing is runnable.
+    pub fn replay(mut self, trace: &Trace) -> Result<(), Divergence> {
+        let _clock = VirtualClock::enter(trace.start_us);
+        self.start_all();
+
+        let mut woken: Vec<usize> = Vec::new();
+        let mut ready: Vec<usize> = Vec::new();
+        for (index, &event) in trace.events.iter().enumerate() {
+            woken.extend(self.take_woken());
+            let diverged = Divergence {
+                index,
+                expected: Some(event),
+            };
+            match event {
+                TraceEvent::Wake(task) => {
+                    let pos = woken.iter().position(|&t| t == task).ok_or(diverged)?;
+                    woken.remove(pos);
+                    ready.push(task);
+                }
+                TraceEvent::Poll(task) => {
+                    let pos = ready.iter().position(|&t| t == task).ok_or(diverged)?;
+                    ready.remove(pos);
+                    self.poll(task);
+                }
+                TraceEvent::Advance(at) => {
+                    if !ready.is_empty() || embassy_time_driver::next_alarm() != Some(at) {
+                        return Err(diverged);
+                    }
+                    embassy_time_driver::advance_virtual_time(at);
+                }
+            }
+        }
+
+        if self.finished() {
+            Ok(())
+        } else {
+            Err(Divergence {
+                index: trace.events.len(),
+                expected: None,
+            })
+        }
+    }
+
+    /// Every task starts out woken, in spawn order
+    fn start_all(&self) {
+        with_irqs_disabled(|| {
+            let mut queue = self.woken.0.lock();
+            queue.clear();
+            queue.reserve(self.tasks.len());
+            queue.extend(0..self.tasks.len());
+        });
+    }
+
+    fn take_woken(&self) -> VecDeque<usize> {
+        with_irqs_disabled(|| {
+            let mut queue = self.woken.0.lock();
+            let mut taken = VecDeque::with_capacity(self.tasks.len());
+            core::mem::swap(&mut *queue, &mut taken);
+            taken
+        })
+    }
+
+    fn poll(&mut self, task: usize) {
+        let task = &mut self.tasks[task];
+        if let Some(future) = task.future.as_mut() {
+            let mut cx = Context::from_waker(&task.waker);
+            if future.as_mut().poll(&mut cx).is_ready() {
+                task.future = None;
+            }
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.tasks.iter().all(|task| task.future.is_none())
+    }
+}
@@ -0,0 +1,120 @@
+//! Scheduler Scopes
+//!
+//! Short critical sections that must not be switched away from, without
+//! masking interrupts:
+//!
+//! - `no_preempt(|| ...)` - timer ticks are deferred until the scope ends;
+//!   IRQ handlers keep running
+//! - `pin_current(|| ...)` - the thread stays on the current CPU (a no-op
+//!   until SMP, but nesting is tracked so callers are ready for it)
+//!
+//! Both nest. Blocking (`threading::yield_now`) inside either is a bug; it is
+//! counted in `threading::scope_violations` and logged in debug builds.
+//!
+//! `with_deadline(timeout, || ...)` bounds the blocking waits made inside
+//! it: `wait_until`, `Mutex::lock`, `Condvar::wait`, `Barrier::wait` and
+//! `JoinHandle::join` return `TimedOut` once the deadline passes. The
+//! scheduler runs a waiting thread, or unblocks a blocked one, as soon as
+//! its deadline expires so the timeout is seen promptly.
+
+use core::time::Duration;
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::threading;
+
+/// Ends a `no_preempt` scope even if the closure unwinds
+struct PreemptGuard;
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        threading::preempt_enable();
+    }
+}
+
+/// Ends a `pin_current` scope even if the closure unwinds
+struct PinGuard;
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        threading::migrate_enable();
+    }
+}
+
+/// Restores the enclosing deadline when a `with_deadline` scope ends
+struct DeadlineGuard(u64);
+
+impl Drop for DeadlineGuard {
+    fn drop(&mut self) {
+        threading::set_deadline_us(self.0);
+    }
+}
+
+/// Run `f` without being preempted
+///
+/// A tick that arrives during the scope reschedules as soon as the
+/// outermost scope ends. Keep the body short and non-blocking.
+pub fn no_preempt<R>(f: impl FnOnce() -> R) -> R {
+    threading::preempt_disable();
+    let _guard = PreemptGuard;
+    f()
+}
+
+/// Run `f` without migrating to another CPU
+pub fn pin_current<R>(f: impl FnOnce() -> R) -> R {
+    threading::migrate_disable();
+    let _guard = PinGuard;
+    f()
+}
+
+/// True inside a `no_preempt` scope
+pub fn preempt_disabled() -> bool {
+    threading::preempt_depth() > 0
+}
+
+/// True inside a `pin_current` scope
+pub fn pinned() -> bool {
+    threading::pin_depth() > 0
+}
+
+/// Run `f` with its blocking waits bounded by `timeout`
+///
+/// Nested scopes can only shorten the deadline. `f` sees the timeout as a
+/// `TimedOut` error from `wait_until` or a blocking `threading` primitive
+/// and should propagate it.
+pub fn with_deadline<R>(timeout: Duration, f: impl FnOnce() -> KResult<R>) -> KResult<R> {
+    let now = crate::timer::uptime_us();
+    let deadline = now.saturating_add(timeout.as_micros() as u64).max(1);
+    let outer = threading::deadline_us();
+    let effective = if outer == 0 { deadline } else { outer.min(deadline) };
+
+    threading::set_deadline_us(effective);
+    let _guard = DeadlineGuard(outer);
+    f()
+}
+
+/// Time left before the current deadline (None outside `with_deadline`)
+pub fn deadline_remaining() -> Option<Duration> {
+    match threading::deadline_us() {
+        0 => None,
+        deadline => {
+            let now = crate::timer::uptime_us();
+            Some(Duration::from_micros(deadline.saturating_sub(now)))
+        }
+    }
+}
+
+/// Yield until `cond` holds, or fail with `TimedOut` at the current deadline
+///
+/// The building block for blocking waits in thread code. Without a
+/// deadline it waits indefinitely.
+pub fn wait_until(mut cond: impl FnMut() -> bool) -> KResult<()> {
+    loop {
+        if cond() {
+            return Ok(());
+        }
+        if threading::deadline_expired() {
+            return Err(KError::with_context(ErrorKind::TimedOut, "deadline"));
+        }
+        threading::yield_now();
+    }
+}
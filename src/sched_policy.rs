@@ -0,0 +1,291 @@
+//! Scheduling Policies
+//!
+//! The thread pool decides *whether* to switch - cooperative timeouts,
+//! `no_preempt` scopes, expired `with_deadline` waits, the background and
+//! deadline classes - and asks a `SchedPolicy` *which* thread runs next
+//! among normal threads. The policy owns its run queue, so trying a new
+//! scheduling algorithm means writing one more implementation here rather
+//! than forking `threading.rs`.
+//!
+//! Three policies ship with the kernel, selected with `sched.policy`:
+//!
+//! - `priority` (default) - the most urgent runnable thread first,
+//!   round-robin among equals (so plain round-robin while every thread
+//!   keeps the default priority)
+//! - `rr` - round-robin over every runnable thread, ignoring priorities
+//! - `edf` - earliest deadline first for threads that declare a relative
+//!   deadline, round-robin for the rest once none of those is runnable
+//!
+//! Policies are called with the pool lock held and IRQs masked, so they
+//! must not allocate or block; the queues here are fixed arrays indexed
+//! by tid.
+
+use alloc::boxed::Box;
+
+use crate::threading::MAX_THREADS;
+
+const _: () = assert!(MAX_THREADS <= 32, "run queues are u32 masks");
+
+/// Priority of threads that don't ask for one
+pub const DEFAULT_PRIORITY: u8 = 128;
+
+/// The network poll loop: runs ahead of worker threads, so packets are
+/// handled within a tick even while bulk work keeps the CPU busy
+pub const PRIORITY_NETWORK: u8 = 192;
+
+/// Bulk work (transfers, offloaded crypto) that should only use the time
+/// nothing more interactive wants
+pub const PRIORITY_BULK: u8 = 64;
+
+/// Per-thread scheduling parameters, kept in the thread slot and handed
+/// to the policy; each policy reads the ones it understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedParams {
+    /// Higher runs first (`priority` policy)
+    pub priority: u8,
+    /// Each run should finish within this long of becoming runnable
+    /// (`edf` policy; 0 = no deadline)
+    pub relative_deadline_us: u64,
+}
+
+impl SchedParams {
+    pub const fn new() -> Self {
+        Self {
+            priority: DEFAULT_PRIORITY,
+            relative_deadline_us: 0,
+        }
+    }
+}
+
+impl Default for SchedParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Picks the next thread to run
+///
+/// A tid is in the run queue from `enqueue` until `dequeue`, running or
+/// not. The pool re-checks thread state before switching, so a policy
+/// never has to track Ready/Running itself.
+pub trait SchedPolicy: Send {
+    /// Name used by `sched.policy` and diagnostics
+    fn name(&self) -> &'static str;
+
+    /// Add `tid` to the run queue, or update its parameters if present
+    fn enqueue(&mut self, tid: usize, params: SchedParams, now_us: u64);
+
+    /// Remove `tid`; it won't run again until enqueued
+    fn dequeue(&mut self, tid: usize);
+
+    /// `tid` is about to give up the CPU; `yielded` if it asked to
+    fn put_prev(&mut self, tid: usize, now_us: u64, yielded: bool);
+
+    /// The queued thread that should run after `current` (possibly
+    /// `current` itself), considering only tids `eligible` accepts
+    fn pick_next(&mut self, current: usize, eligible: &dyn Fn(usize) -> bool) -> Option<usize>;
+
+    /// Whether a thread `eligible` accepts should take the CPU from
+    /// `current` before its time slice is up; called from the timer tick
+    /// to decide if the scheduler needs to run at all
+    fn preempts(&self, current: usize, eligible: &dyn Fn(usize) -> bool) -> bool;
+}
+
+/// First queued tid after `current` in round-robin order (wrapping round
+/// to `current` itself) that `accept` takes
+fn round_robin(queued: u32, current: usize, accept: impl Fn(usize) -> bool) -> Option<usize> {
+    (1..=MAX_THREADS)
+        .map(|offset| (current + offset) % MAX_THREADS)
+        .find(|&tid| queued & (1 << tid) != 0 && accept(tid))
+}
+
+// ============================================================================
+// Round-Robin
+// ============================================================================
+
+/// Every runnable thread in turn
+pub struct RoundRobin {
+    queued: u32,
+}
+
+impl RoundRobin {
+    pub const fn new() -> Self {
+        Self { queued: 0 }
+    }
+}
+
+impl Default for RoundRobin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SchedPolicy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "rr"
+    }
+
+    fn enqueue(&mut self, tid: usize, _params: SchedParams, _now_us: u64) {
+        self.queued |= 1 << tid;
+    }
+
+    fn dequeue(&mut self, tid: usize) {
+        self.queued &= !(1 << tid);
+    }
+
+    fn put_prev(&mut self, _tid: usize, _now_us: u64, _yielded: bool) {}
+
+    fn pick_next(&mut self, current: usize, eligible: &dyn Fn(usize) -> bool) -> Option<usize> {
+        round_robin(self.queued, current, eligible)
+    }
+
+    fn preempts(&self, _current: usize, _eligible: &dyn Fn(usize) -> bool) -> bool {
+        false
+    }
+}
+
+// ============================================================================
+// Priority
+// ============================================================================
+
+/// Highest priority first; equal priorities take turns
+pub struct Priority {
+    queued: u32,
+    priority: [u8; MAX_THREADS],
+}
+
+impl Priority {
+    pub const fn new() -> Self {
+        Self {
+            queued: 0,
+            priority: [DEFAULT_PRIORITY; MAX_THREADS],
+        }
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SchedPolicy for Priority {
+    fn name(&self) -> &'static str {
+        "priority"
+    }
+
+    fn enqueue(&mut self, tid: usize, params: SchedParams, _now_us: u64) {
+        self.queued |= 1 << tid;
+        self.priority[tid] = params.priority;
+    }
+
+    fn dequeue(&mut self, tid: usize) {
+        self.queued &= !(1 << tid);
+    }
+
+    fn put_prev(&mut self, _tid: usize, _now_us: u64, _yielded: bool) {}
+
+    fn pick_next(&mut self, current: usize, eligible: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let top = (0..MAX_THREADS)
+            .filter(|&tid| self.queued & (1 << tid) != 0 && eligible(tid))
+            .map(|tid| self.priority[tid])
+            .max()?;
+        round_robin(self.queued, current, |tid| {
+            self.priority[tid] == top && eligible(tid)
+        })
+    }
+
+    fn preempts(&self, current: usize, eligible: &dyn Fn(usize) -> bool) -> bool {
+        (0..MAX_THREADS).any(|tid| {
+            self.queued & (1 << tid) != 0
+                && self.priority[tid] > self.priority[current]
+                && eligible(tid)
+        })
+    }
+}
+
+// ============================================================================
+// Earliest Deadline First
+// ============================================================================
+
+/// Earliest absolute deadline first; threads without a deadline share
+/// what's left round-robin
+///
+/// A thread's deadline is set when it is enqueued and renewed each time
+/// it yields (finishing one run); being preempted keeps the old one.
+pub struct Edf {
+    queued: u32,
+    relative_us: [u64; MAX_THREADS],
+    absolute_us: [u64; MAX_THREADS],
+}
+
+impl Edf {
+    pub const fn new() -> Self {
+        Self {
+            queued: 0,
+            relative_us: [0; MAX_THREADS],
+            absolute_us: [0; MAX_THREADS],
+        }
+    }
+}
+
+impl Default for Edf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SchedPolicy for Edf {
+    fn name(&self) -> &'static str {
+        "edf"
+    }
+
+    fn enqueue(&mut self, tid: usize, params: SchedParams, now_us: u64) {
+        self.queued |= 1 << tid;
+        self.relative_us[tid] = params.relative_deadline_us;
+        self.absolute_us[tid] = now_us.saturating_add(params.relative_deadline_us);
+    }
+
+    fn dequeue(&mut self, tid: usize) {
+        self.queued &= !(1 << tid);
+    }
+
+    fn put_prev(&mut self, tid: usize, now_us: u64, yielded: bool) {
+        if yielded && self.relative_us[tid] > 0 {
+            self.absolute_us[tid] = now_us.saturating_add(self.relative_us[tid]);
+        }
+    }
+
+    fn pick_next(&mut self, current: usize, eligible: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let earliest = (0..MAX_THREADS)
+            .filter(|&tid| {
+                self.queued & (1 << tid) != 0 && self.relative_us[tid] > 0 && eligible(tid)
+            })
+            .min_by_key(|&tid| self.absolute_us[tid]);
+        earliest.or_else(|| round_robin(self.queued, current, eligible))
+    }
+
+    fn preempts(&self, current: usize, eligible: &dyn Fn(usize) -> bool) -> bool {
+        let current_deadline = if self.relative_us[current] > 0 {
+            self.absolute_us[current]
+        } else {
+            u64::MAX
+        };
+        (0..MAX_THREADS).any(|tid| {
+            self.queued & (1 << tid) != 0
+                && self.relative_us[tid] > 0
+                && self.absolute_us[tid] < current_deadline
+                && eligible(tid)
+        })
+    }
+}
+
+/// The policy called `name` (`rr`, `priority` or `edf`), with an empty run queue
+pub fn by_name(name: &str) -> Option<Box<dyn SchedPolicy>> {
+    match name {
+        "rr" => Some(Box::new(RoundRobin::new())),
+        "priority" => Some(Box::new(Priority::new())),
+        "edf" => Some(Box::new(Edf::new())),
+        _ => None,
+    }
+}
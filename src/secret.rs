@@ -0,0 +1,201 @@
+//! Secret Memory
+//!
+//! Key material must not linger after it's dropped. `SecretBox<T>` and
+//! `SecretBytes` wipe their contents on drop; `wipe` and `wipe_replace`
+//! clear buffers and values in place. Wipes use volatile writes so the
+//! compiler can't elide them as dead stores.
+//!
+//! Copies the compiler makes while moving a value (e.g. into
+//! `SecretBox::new`) are not covered; build secrets in place where it
+//! matters. Heap blocks left behind by `Vec` growth are only cleared with
+//! the allocator's zero-on-free option (`allocator::set_zero_on_free`).
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::sync::atomic::{Ordering, compiler_fence};
+
+// ============================================================================
+// Wiping
+// ============================================================================
+
+/// Zero `len` bytes at `ptr` with volatile writes
+///
+/// # Safety
+/// `ptr` must be valid for `len` bytes of writes.
+pub unsafe fn wipe_raw(ptr: *mut u8, len: usize) {
+    for i in 0..len {
+        // SAFETY: caller guarantees ptr..ptr+len is writable
+        unsafe { core::ptr::write_volatile(ptr.add(i), 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Zero a byte buffer
+#[cfg(any(feature = "shell", feature = "tls"))]
+pub fn wipe(buf: &mut [u8]) {
+    // SAFETY: the slice is writable for its whole length
+    unsafe { wipe_raw(buf.as_mut_ptr(), buf.len()) };
+}
+
+/// Drop the value in `slot`, zero its bytes, then store `value` there
+#[cfg(feature = "ssh")]
+pub fn wipe_replace<T>(slot: &mut T, value: T) {
+    let ptr = slot as *mut T;
+    // SAFETY: the old value is dropped exactly once and the slot is
+    // re-initialized before anyone can observe the zeroed bytes
+    unsafe {
+        core::ptr::drop_in_place(ptr);
+        wipe_raw(ptr as *mut u8, core::mem::size_of::<T>());
+        core::ptr::write(ptr, value);
+    }
+}
+
+// ============================================================================
+// SecretBox
+// ============================================================================
+
+/// Heap-allocated value whose memory is wiped when it's dropped
+#[cfg(feature = "ssh")]
+pub struct SecretBox<T> {
+    inner: ManuallyDrop<Box<T>>,
+}
+
+#[cfg(feature = "ssh")]
+impl<T> SecretBox<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            inner: ManuallyDrop::new(Box::new(value)),
+        }
+    }
+}
+
+#[cfg(feature = "ssh")]
+impl<T: Clone> Clone for SecretBox<T> {
+    fn clone(&self) -> Self {
+        Self::new((**self.inner).clone())
+    }
+}
+
+#[cfg(feature = "ssh")]
+impl<T> core::ops::Deref for SecretBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+#[cfg(feature = "ssh")]
+impl<T> core::ops::DerefMut for SecretBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[cfg(feature = "ssh")]
+impl<T> core::fmt::Debug for SecretBox<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("SecretBox(..)")
+    }
+}
+
+#[cfg(feature = "ssh")]
+impl<T> Drop for SecretBox<T> {
+    fn drop(&mut self) {
+        // SAFETY: `inner` is never used again. The value is dropped in
+        // place, its bytes zeroed, and the allocation freed as
+        // MaybeUninit<T> so it isn't dropped twice.
+        unsafe {
+            let boxed = ManuallyDrop::take(&mut self.inner);
+            let ptr = Box::into_raw(boxed);
+            core::ptr::drop_in_place(ptr);
+            wipe_raw(ptr as *mut u8, core::mem::size_of::<T>());
+            drop(Box::from_raw(ptr as *mut MaybeUninit<T>));
+        }
+    }
+}
+
+// ============================================================================
+// SecretBytes
+// ============================================================================
+
+/// Byte buffer wiped on drop (derived keys, shared secrets)
+#[cfg(any(feature = "tls", feature = "ssh"))]
+#[derive(Default)]
+pub struct SecretBytes(Vec<u8>);
+
+#[cfg(any(feature = "tls", feature = "ssh"))]
+impl SecretBytes {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self(Vec::with_capacity(capacity))
+    }
+
+    /// Append bytes
+    ///
+    /// Reserve enough up front: a reallocation leaves the old block behind
+    /// for zero-on-free to clean up, if enabled.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.0.extend_from_slice(data);
+    }
+
+    #[cfg(feature = "ssh")]
+    pub fn push(&mut self, byte: u8) {
+        self.0.push(byte);
+    }
+
+    #[cfg(feature = "ssh")]
+    pub fn truncate(&mut self, len: usize) {
+        let old_len = self.0.len();
+        if len < old_len {
+            wipe(&mut self.0[len..old_len]);
+        }
+        self.0.truncate(len);
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "ssh"))]
+impl From<&[u8]> for SecretBytes {
+    fn from(data: &[u8]) -> Self {
+        let mut out = Self::with_capacity(data.len());
+        out.extend_from_slice(data);
+        out
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "ssh"))]
+impl core::ops::Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "ssh"))]
+impl core::ops::DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "ssh"))]
+impl core::fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "SecretBytes({} bytes)", self.0.len())
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "ssh"))]
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        // Wipe the whole capacity: truncated tails may hold key bytes too
+        let cap = self.0.capacity();
+        // SAFETY: the allocation is writable for its full capacity
+        unsafe { wipe_raw(self.0.as_mut_ptr(), cap) };
+    }
+}
@@ -0,0 +1,727 @@
+//! Shell Commands
+//!
+//! The command interpreter behind the SSH shell and the control protocol's
+//! `exec`: `execute_command` takes one line and returns its output, with
+//! no idea which transport it came from. Commands that need a live session
+//! (`log tail`, `console attach`) are handled by the SSH layer before a
+//! line gets here.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::akuma::AKUMA_79;
+use crate::allocator;
+use crate::ansi;
+use crate::config;
+use crate::console::{self, ConsoleId};
+use crate::crashdump;
+use crate::defrag;
+use crate::dmesg;
+use crate::driver::{self, PowerAction};
+use crate::events::Event;
+use crate::handles;
+use crate::klog::{self, Level};
+use crate::kobject;
+use crate::latency;
+use crate::network;
+use crate::pmm;
+use crate::slab;
+#[cfg(feature = "fs")]
+use crate::sysreport;
+use crate::threading;
+use crate::uart;
+use crate::vmm;
+
+// ============================================================================
+// Commands
+// ============================================================================
+
+/// Run one command line and return its output (CRLF line endings)
+pub fn execute_command(line: &[u8]) -> Vec<u8> {
+    let line = trim_bytes(line);
+    if line.is_empty() {
+        return Vec::new();
+    }
+
+    let (cmd, args) = split_first_word(line);
+    let mut response = Vec::new();
+
+    match cmd {
+        b"echo" => {
+            if !args.is_empty() {
+                response.extend_from_slice(args);
+            }
+            response.extend_from_slice(b"\r\n");
+        }
+        b"akuma" | b"cat" => {
+            // shows picture of a cat
+            // Convert \n to \r\n for proper SSH terminal display
+            for &byte in AKUMA_79 {
+                if byte == b'\n' {
+                    response.extend_from_slice(b"\r\n");
+                } else {
+                    response.push(byte);
+                }
+            }
+            if !AKUMA_79.ends_with(b"\n") {
+                response.extend_from_slice(b"\r\n");
+            }
+        }
+        b"events" => {
+            response.extend_from_slice(b"Event Catalog:\r\n");
+            for event in Event::ALL {
+                let line = alloc::format!(
+                    "  E{:<5} {:<6} {}\r\n",
+                    event.id(),
+                    event.level().as_str(),
+                    event.as_str()
+                );
+                response.extend_from_slice(line.as_bytes());
+            }
+        }
+        b"clear" => {
+            ansi::clear_screen(&mut response);
+        }
+        b"quit" | b"exit" => {
+            response.extend_from_slice(b"Goodbye!\r\n");
+        }
+        b"stats" => {
+            let (connections, bytes_rx, bytes_tx) = network::get_stats();
+            let stats = alloc::format!(
+                "Network Statistics:\r\n  Connections: {}\r\n  Bytes RX: {}\r\n  Bytes TX: {}\r\n",
+                connections, bytes_rx, bytes_tx
+            );
+            response.extend_from_slice(stats.as_bytes());
+            for service in network::Service::ALL {
+                let st = network::service_stats(service);
+                let cap = match st.cap {
+                    Some(rate) => alloc::format!("{} B/s", rate),
+                    None => alloc::string::String::from("unlimited"),
+                };
+                let line = alloc::format!(
+                    "  {:<7} rx {:>10} tx {:>10} throttled {:>6} cap {}\r\n",
+                    service.as_str(),
+                    st.bytes_rx,
+                    st.bytes_tx,
+                    st.throttled,
+                    cap
+                );
+                response.extend_from_slice(line.as_bytes());
+            }
+            for (iface, st) in network::shaper_stats() {
+                let line = alloc::format!(
+                    "  {:<7} shaped to {} B/s (burst {}), sent {} delayed {}\r\n",
+                    iface, st.rate, st.burst, st.bytes, st.delayed
+                );
+                response.extend_from_slice(line.as_bytes());
+            }
+            response.extend_from_slice(b"Latency:\r\n");
+            for metric in latency::Metric::ALL {
+                let s = latency::summary(metric);
+                let line = alloc::format!(
+                    "  {:<10} n={:<8} p50 {:>7} p90 {:>7} p99 {:>7} p99.9 {:>7} max {:>7}\r\n",
+                    metric.as_str(),
+                    s.count,
+                    latency::format_ns(s.p50),
+                    latency::format_ns(s.p90),
+                    latency::format_ns(s.p99),
+                    latency::format_ns(s.p999),
+                    latency::format_ns(s.max)
+                );
+                response.extend_from_slice(line.as_bytes());
+            }
+            if args == b"reset" {
+                latency::reset();
+                response.extend_from_slice(b"Latency histograms cleared\r\n");
+            }
+        }
+        b"meminfo" => {
+            let heap = allocator::stats();
+            let info = alloc::format!(
+                "Heap Statistics:\r\n  Total: {} KB\r\n  Used: {} KB\r\n  Free: {} KB\r\n  Peak: {} KB\r\n  Live allocations: {}\r\n  Total allocations: {}\r\n  Largest free block: {} KB\r\n",
+                heap.total / 1024,
+                heap.used / 1024,
+                heap.free() / 1024,
+                heap.peak / 1024,
+                heap.allocations,
+                heap.total_allocations,
+                heap.largest_free / 1024
+            );
+            response.extend_from_slice(info.as_bytes());
+            let pages = pmm::stats();
+            let info = alloc::format!(
+                "Page Frames:\r\n  Total: {} KB\r\n  Free: {} KB\r\n  Metadata: {} KB\r\n  Free blocks:",
+                pages.total_pages * pmm::PAGE_SIZE / 1024,
+                pages.free_pages * pmm::PAGE_SIZE / 1024,
+                pages.metadata_pages * pmm::PAGE_SIZE / 1024
+            );
+            response.extend_from_slice(info.as_bytes());
+            for (order, &count) in pages.free_blocks.iter().enumerate() {
+                if count > 0 {
+                    let info = alloc::format!(" {}x{}K", count, (pmm::PAGE_SIZE << order) / 1024);
+                    response.extend_from_slice(info.as_bytes());
+                }
+            }
+            response.extend_from_slice(b"\r\n");
+            let scrub = allocator::scrub_stats();
+            if scrub.poisoned > 0 || scrub.corruptions > 0 {
+                let info = alloc::format!(
+                    "Scrubber:\r\n  Passes: {}\r\n  Poisoned: {}\r\n  Verified: {}\r\n  Corruptions: {} (last at {:#x})\r\n",
+                    scrub.passes,
+                    scrub.poisoned,
+                    scrub.verified,
+                    scrub.corruptions,
+                    scrub.last_bad_addr
+                );
+                response.extend_from_slice(info.as_bytes());
+            }
+        }
+        b"slabinfo" => {
+            response.extend_from_slice(b"Slab Caches:\r\n");
+            for s in slab::stats() {
+                let line = alloc::format!(
+                    "  {:<16} size={:<6} slabs={:<4} in_use={:<6} free={}\r\n",
+                    s.name, s.object_size, s.slabs, s.in_use, s.free
+                );
+                response.extend_from_slice(line.as_bytes());
+            }
+        }
+        b"fraginfo" => {
+            let r = allocator::fragmentation_report();
+            let info = alloc::format!(
+                "Heap Free Blocks: {}{}\r\n  Free: {} KB\r\n  Largest: {} KB\r\n  Fragmented: {}%\r\n",
+                r.blocks,
+                if r.truncated { "+" } else { "" },
+                r.free_bytes / 1024,
+                r.largest / 1024,
+                r.fragmentation_percent()
+            );
+            response.extend_from_slice(info.as_bytes());
+            for (i, &n) in r.histogram.iter().enumerate() {
+                if n > 0 {
+                    let line = alloc::format!(
+                        "  >= {:<8} {}\r\n",
+                        allocator::FragReport::bucket_floor(i),
+                        n
+                    );
+                    response.extend_from_slice(line.as_bytes());
+                }
+            }
+            response.extend_from_slice(b"Size Classes:\r\n");
+            for c in r.classes.iter() {
+                let line = alloc::format!(
+                    "  size={:<6} pages={:<4} in_use={:<6} free={}\r\n",
+                    c.size, c.pages, c.in_use, c.free
+                );
+                response.extend_from_slice(line.as_bytes());
+            }
+        }
+        b"defrag" => {
+            let r = defrag::run();
+            let info = alloc::format!(
+                "Defrag Hints: {} handler(s)\r\n  Released: {} bytes\r\n  Moved: {} bytes\r\n  Fragmented: {}% -> {}%\r\n  Largest: {} KB -> {} KB\r\n  Free blocks: {} -> {}\r\n",
+                r.handlers,
+                r.released,
+                r.moved,
+                r.before.fragmentation_percent,
+                r.after.fragmentation_percent,
+                r.before.largest / 1024,
+                r.after.largest / 1024,
+                r.before.blocks,
+                r.after.blocks
+            );
+            response.extend_from_slice(info.as_bytes());
+        }
+        b"threads" => {
+            response.extend_from_slice(b"Threads:\r\n");
+            threading::for_each_thread(|t| {
+                let name = if t.name.is_empty() { "-" } else { t.name };
+                let line = alloc::format!(
+                    "  {:>2} {:<12} {:<10} prio {:>3} cpu {} ms\r\n",
+                    t.tid,
+                    name,
+                    t.state.as_str(),
+                    t.priority,
+                    t.cpu_time_us / 1000
+                );
+                response.extend_from_slice(line.as_bytes());
+            });
+            let line = alloc::format!("Killed since boot: {}\r\n", threading::kills());
+            response.extend_from_slice(line.as_bytes());
+        }
+        b"kill" => {
+            let tid = core::str::from_utf8(trim_bytes(args))
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok());
+            match tid {
+                Some(tid) => match threading::kill(tid) {
+                    Ok(()) => {
+                        let line = alloc::format!("Killed thread {}\r\n", tid);
+                        response.extend_from_slice(line.as_bytes());
+                    }
+                    Err(e) => {
+                        let line = alloc::format!("kill: {}\r\n", e);
+                        response.extend_from_slice(line.as_bytes());
+                    }
+                },
+                None => response.extend_from_slice(b"Usage: kill <tid>\r\n"),
+            }
+        }
+        b"kobj" => {
+            if args.is_empty() || args == b"tree" {
+                response.extend_from_slice(b"Kernel Objects:\r\n");
+                response.extend_from_slice(kobject::tree("\r\n").as_bytes());
+            } else {
+                response.extend_from_slice(b"Usage: kobj [tree]\r\n");
+            }
+        }
+        b"log" => {
+            log_level_command(args, &mut response);
+        }
+        b"dmesg" => {
+            if args == b"clear" {
+                dmesg::clear();
+                response.extend_from_slice(b"Kernel message buffer cleared\r\n");
+            } else {
+                push_crlf(&mut response, &dmesg::read_all());
+            }
+        }
+        b"console" if args.is_empty() || args == b"list" => {
+            let active = console::active();
+            response.extend_from_slice(b"Consoles (* = input):\r\n");
+            for (id, name) in console::consoles() {
+                let kind = match id {
+                    ConsoleId::Serial => String::from("serial"),
+                    ConsoleId::Remote(n) => alloc::format!("remote #{}", n),
+                };
+                let mark = if id == active { '*' } else { ' ' };
+                let line = alloc::format!("{} {:<12} {}\r\n", mark, name, kind);
+                response.extend_from_slice(line.as_bytes());
+            }
+            let line = alloc::format!(
+                "Serial: {} baud, {} TX stalls, {} RX overruns\r\n",
+                console::uart_baud_rate(),
+                console::tx_stalls(),
+                console::rx_overruns()
+            );
+            response.extend_from_slice(line.as_bytes());
+        }
+        b"config" => {
+            let entries = config::entries();
+            if entries.is_empty() {
+                response.extend_from_slice(b"No kernel command line settings\r\n");
+            }
+            for (key, value) in entries {
+                let line = alloc::format!("  {}={}\r\n", key, value);
+                response.extend_from_slice(line.as_bytes());
+            }
+        }
+        b"handles" => {
+            let handles = handles::list();
+            let header = alloc::format!("Handles: {}\r\n", handles.len());
+            response.extend_from_slice(header.as_bytes());
+            for h in handles {
+                let owner = match h.owner {
+                    handles::Owner::Thread(tid) => alloc::format!("thread {}", tid),
+                    handles::Owner::Task(id) => alloc::format!("task {}", id),
+                };
+                let line = alloc::format!(
+                    "  #{:<6} {:<8} {}\r\n",
+                    h.handle.id(),
+                    h.kind.as_str(),
+                    owner
+                );
+                response.extend_from_slice(line.as_bytes());
+            }
+        }
+        b"regions" => {
+            for r in vmm::list() {
+                let perm = r.perm.map(|p| p.as_str()).unwrap_or("---");
+                let line = alloc::format!(
+                    "  {:#010x}-{:#010x} {:>8} KB {} {:<6} {:<9} {}\r\n",
+                    r.base,
+                    r.end(),
+                    r.size / 1024,
+                    perm,
+                    r.kind.as_str(),
+                    r.state.as_str(),
+                    r.name
+                );
+                response.extend_from_slice(line.as_bytes());
+            }
+        }
+        b"leaks" => {
+            leaks_command(args, &mut response);
+        }
+        b"allocprof" => {
+            allocprof_command(args, &mut response);
+        }
+        b"crashdump" => {
+            if args == b"clear" {
+                crashdump::clear_previous();
+                response.extend_from_slice(b"Crash report cleared\r\n");
+            } else {
+                match crashdump::previous_report() {
+                    Some(report) => {
+                        response.extend_from_slice(b"Crash report from previous boot:\r\n");
+                        for &byte in report.as_bytes() {
+                            if byte == b'\n' {
+                                response.extend_from_slice(b"\r\n");
+                            } else {
+                                response.push(byte);
+                            }
+                        }
+                    }
+                    None => response.extend_from_slice(b"No crash report\r\n"),
+                }
+                let action = crashdump::panic_action();
+                let policy = match action {
+                    crashdump::PanicAction::DumpReset => alloc::format!(
+                        "On panic: {} after {} s\r\n",
+                        action.as_str(),
+                        crashdump::reset_delay_s()
+                    ),
+                    _ => alloc::format!("On panic: {}\r\n", action.as_str()),
+                };
+                response.extend_from_slice(policy.as_bytes());
+            }
+        }
+        b"net" => match trim_bytes(args) {
+            b"" => {
+                let state = if network::restart_pending() {
+                    "restarting"
+                } else if crate::async_net::is_up() {
+                    "up"
+                } else {
+                    "down"
+                };
+                let line = alloc::format!(
+                    "Network stack: {}\r\n  Restarts: {}\r\n",
+                    state,
+                    network::restarts()
+                );
+                response.extend_from_slice(line.as_bytes());
+            }
+            b"restart" => {
+                network::request_restart();
+                response.extend_from_slice(
+                    b"Restarting the network stack; open connections will drop\r\n",
+                );
+            }
+            _ => response.extend_from_slice(b"Usage: net [restart]\r\n"),
+        },
+        b"drivers" => {
+            let names = driver::names();
+            if names.is_empty() {
+                response.extend_from_slice(b"No drivers registered\r\n");
+            }
+            for name in names {
+                let line = alloc::format!("  {}\r\n", name);
+                response.extend_from_slice(line.as_bytes());
+            }
+        }
+        b"reboot" | b"poweroff" => {
+            let action = if cmd == b"reboot" {
+                PowerAction::Reboot
+            } else {
+                PowerAction::PowerOff
+            };
+            driver::request_shutdown(action);
+            let line = alloc::format!(
+                "Shutting down drivers for {}; the connection will drop\r\n",
+                action.as_str()
+            );
+            response.extend_from_slice(line.as_bytes());
+        }
+        #[cfg(feature = "fs")]
+        b"sysreport" => match sysreport::write() {
+            Ok((size, members)) => {
+                let line = alloc::format!(
+                    "Wrote {} ({} files, {} bytes)\r\nDownload it from the HTTP file browser at {}\r\n",
+                    sysreport::REPORT_PATH,
+                    members,
+                    size,
+                    sysreport::REPORT_PATH
+                );
+                response.extend_from_slice(line.as_bytes());
+            }
+            Err(e) => {
+                let line = alloc::format!("sysreport failed: {}\r\n", e);
+                response.extend_from_slice(line.as_bytes());
+            }
+        },
+        b"help" => {
+            response.extend_from_slice(b"Available commands:\r\n");
+            response.extend_from_slice(b"  echo <text>  - Echo back text\r\n");
+            response.extend_from_slice(b"  akuma        - Display ASCII art\r\n");
+            response.extend_from_slice(b"  stats        - Show network and latency statistics [reset]\r\n");
+            response.extend_from_slice(b"  net          - Show network stack state [restart]\r\n");
+            response.extend_from_slice(b"  drivers      - List drivers with power hooks\r\n");
+            response.extend_from_slice(b"  reboot       - Shut drivers down and reboot\r\n");
+            response.extend_from_slice(b"  poweroff     - Shut drivers down and power off\r\n");
+            response.extend_from_slice(b"  meminfo      - Show heap and page statistics\r\n");
+            response.extend_from_slice(b"  slabinfo     - Show slab cache usage\r\n");
+            response.extend_from_slice(b"  fraginfo     - Show heap fragmentation and size classes\r\n");
+            response.extend_from_slice(b"  defrag       - Ask long-lived buffers to reallocate, show before/after\r\n");
+            response.extend_from_slice(b"  crashdump    - Show last crash report [clear]\r\n");
+            response.extend_from_slice(b"  config       - Show kernel command line settings\r\n");
+            #[cfg(feature = "fs")]
+            response.extend_from_slice(b"  sysreport    - Write a diagnostics bundle to /tmp/sysreport.tar\r\n");
+            response.extend_from_slice(b"  leaks        - Live allocations since mark [on|off|mark]\r\n");
+            response.extend_from_slice(b"  allocprof    - Allocation sizes per subsystem and packet [start [s]|stop]\r\n");
+            response.extend_from_slice(b"  handles      - List resource handles and owners\r\n");
+            response.extend_from_slice(b"  regions      - List address-space regions\r\n");
+            response.extend_from_slice(b"  threads      - List threads with state, priority and CPU time\r\n");
+            response.extend_from_slice(b"  kill <tid>   - Terminate a hung thread\r\n");
+            response.extend_from_slice(b"  kobj tree    - Show live kernel objects and who holds them\r\n");
+            response.extend_from_slice(b"  dmesg        - Show console output since boot [clear]\r\n");
+            response.extend_from_slice(b"  log tail     - Follow kernel log [level] [module] (also dmesg -f)\r\n");
+            response.extend_from_slice(b"  log level    - Show or set log levels [level | module level|default]\r\n");
+            response.extend_from_slice(b"  events       - List kernel event IDs for monitoring\r\n");
+            response.extend_from_slice(b"  console      - Mirror the kernel console [list|attach|take]\r\n");
+            response.extend_from_slice(b"  clear        - Clear the screen\r\n");
+            response.extend_from_slice(b"  help         - Show this help\r\n");
+            response.extend_from_slice(b"  quit/exit    - Close connection\r\n");
+        }
+        _ => {
+            response.extend_from_slice(b"Unknown command: ");
+            response.extend_from_slice(cmd);
+            response.extend_from_slice(b"\r\nType 'help' for available commands.\r\n");
+        }
+    }
+
+    response
+}
+
+/// `log level`, `log level <level>`, `log level <module> <level|default>`
+fn log_level_command(args: &[u8], response: &mut Vec<u8>) {
+    const USAGE: &[u8] = b"Usage: log level [level | <module> <level|default>]\r\n";
+    let (sub, args) = split_first_word(args);
+    let args = match (sub, core::str::from_utf8(args)) {
+        (b"level", Ok(args)) => args,
+        _ => {
+            response.extend_from_slice(USAGE);
+            return;
+        }
+    };
+
+    let words: Vec<&str> = args.split_ascii_whitespace().collect();
+    let result = match words.as_slice() {
+        [] => Ok(()),
+        [level] => match Level::parse(level) {
+            Some(level) => {
+                klog::set_level(level);
+                Ok(())
+            }
+            None => Err(()),
+        },
+        [module, "default"] => klog::set_module_level(module, None).map_err(|_| ()),
+        [module, level] => match Level::parse(level) {
+            Some(level) => klog::set_module_level(module, Some(level)).map_err(|_| ()),
+            None => Err(()),
+        },
+        _ => Err(()),
+    };
+    if result.is_err() {
+        response.extend_from_slice(USAGE);
+        return;
+    }
+
+    let line = alloc::format!("Global: {}\r\n", klog::level().as_str());
+    response.extend_from_slice(line.as_bytes());
+    for (module, level) in klog::module_levels() {
+        let line = alloc::format!("  {:<14} {}\r\n", module, level.as_str());
+        response.extend_from_slice(line.as_bytes());
+    }
+    let (staged, dropped) = klog::staging_stats();
+    let line = alloc::format!("Staged from IRQs: {} ({} dropped)\r\n", staged, dropped);
+    response.extend_from_slice(line.as_bytes());
+    let line = alloc::format!("Sinks: {}\r\n", klog::sinks().join(", "));
+    response.extend_from_slice(line.as_bytes());
+    if let Some(port) = uart::log_port() {
+        let line = alloc::format!("Log port: {}\r\n", port);
+        response.extend_from_slice(line.as_bytes());
+    }
+}
+
+/// Checkpoint set by `leaks mark`
+static LEAK_CHECKPOINT: AtomicU64 = AtomicU64::new(0);
+
+/// Most allocations listed by `leaks`
+const LEAKS_MAX_LINES: usize = 50;
+
+fn leaks_command(args: &[u8], response: &mut Vec<u8>) {
+    match args {
+        b"on" => match allocator::enable_tracking() {
+            Ok(()) => {
+                LEAK_CHECKPOINT.store(allocator::checkpoint(), Ordering::Relaxed);
+                response.extend_from_slice(b"Allocation tracking enabled\r\n");
+            }
+            Err(e) => {
+                let line = alloc::format!("{}\r\n", e);
+                response.extend_from_slice(line.as_bytes());
+            }
+        },
+        b"off" => {
+            allocator::disable_tracking();
+            response.extend_from_slice(b"Allocation tracking disabled\r\n");
+        }
+        b"mark" => {
+            let mark = allocator::checkpoint();
+            LEAK_CHECKPOINT.store(mark, Ordering::Relaxed);
+            let line = alloc::format!("Checkpoint set at #{}\r\n", mark);
+            response.extend_from_slice(line.as_bytes());
+        }
+        _ => {
+            if !allocator::tracking_enabled() {
+                response.extend_from_slice(b"Allocation tracking is off (use 'leaks on')\r\n");
+                return;
+            }
+            let mark = LEAK_CHECKPOINT.load(Ordering::Relaxed);
+            let allocs = allocator::allocations_since(mark);
+            let bytes: usize = allocs.iter().map(|a| a.size).sum();
+            let (_, dropped) = allocator::tracking_stats();
+            let header = alloc::format!(
+                "Live allocations since #{}: {} ({} bytes, {} untracked)\r\n",
+                mark,
+                allocs.len(),
+                bytes,
+                dropped
+            );
+            response.extend_from_slice(header.as_bytes());
+            for a in allocs.iter().take(LEAKS_MAX_LINES) {
+                let line = alloc::format!(
+                    "  #{:<8} {:#010x} {:>8} bytes  from {:#x} <- {:#x} <- {:#x}\r\n",
+                    a.seq, a.ptr, a.size, a.callers[0], a.callers[1], a.callers[2]
+                );
+                response.extend_from_slice(line.as_bytes());
+            }
+            if allocs.len() > LEAKS_MAX_LINES {
+                let more = alloc::format!("  ... {} more\r\n", allocs.len() - LEAKS_MAX_LINES);
+                response.extend_from_slice(more.as_bytes());
+            }
+        }
+    }
+}
+
+/// Default `allocprof start` window
+const ALLOCPROF_DEFAULT_SECS: u64 = 10;
+
+/// `allocprof`, `allocprof start [secs]`, `allocprof stop`
+fn allocprof_command(args: &[u8], response: &mut Vec<u8>) {
+    let (sub, rest) = split_first_word(args);
+    match sub {
+        b"start" => {
+            let secs = core::str::from_utf8(trim_bytes(rest))
+                .ok()
+                .filter(|s| !s.is_empty())
+                .map_or(Some(ALLOCPROF_DEFAULT_SECS), |s| s.parse::<u64>().ok());
+            match secs {
+                Some(secs) if secs > 0 => {
+                    allocator::start_profile(secs * 1_000_000);
+                    let line = alloc::format!("Profiling allocations for {} s\r\n", secs);
+                    response.extend_from_slice(line.as_bytes());
+                }
+                _ => response.extend_from_slice(b"Usage: allocprof start [seconds]\r\n"),
+            }
+            return;
+        }
+        b"stop" => allocator::stop_profile(),
+        b"" => {}
+        _ => {
+            response.extend_from_slice(b"Usage: allocprof [start [seconds] | stop]\r\n");
+            return;
+        }
+    }
+
+    let report = allocator::profile_report();
+    let per_packet = match report.allocs_per_packet_x100() {
+        Some(x100) => alloc::format!("{}.{:02} per packet", x100 / 100, x100 % 100),
+        None => String::from("no packets"),
+    };
+    let header = alloc::format!(
+        "Allocation profile ({}, {} ms): {} allocs, {} packets, {}\r\n",
+        if report.running { "running" } else { "finished" },
+        report.elapsed_us / 1000,
+        report.total_allocs(),
+        report.packets,
+        per_packet
+    );
+    response.extend_from_slice(header.as_bytes());
+    for tag in &report.tags {
+        let line = alloc::format!(
+            "  {:<8} {:>8} allocs {:>10} bytes\r\n   ",
+            tag.subsystem.as_str(),
+            tag.allocs,
+            tag.bytes
+        );
+        response.extend_from_slice(line.as_bytes());
+        for (i, &count) in tag.histogram.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let bucket = match allocator::profile_bucket_limit(i) {
+                Some(limit) => alloc::format!(" <={}:{}", limit, count),
+                None => alloc::format!(" more:{}", count),
+            };
+            response.extend_from_slice(bucket.as_bytes());
+        }
+        response.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Commands that change kernel state need the admin role
+pub fn needs_admin(line: &[u8]) -> bool {
+    let (cmd, args) = split_first_word(trim_bytes(line));
+    let (sub, rest) = split_first_word(args);
+    match cmd {
+        b"crashdump" | b"dmesg" => sub == b"clear",
+        b"stats" => sub == b"reset",
+        b"leaks" => matches!(sub, b"on" | b"off" | b"mark"),
+        b"allocprof" => matches!(sub, b"start" | b"stop"),
+        b"log" => sub == b"level" && !trim_bytes(rest).is_empty(),
+        b"console" => sub == b"take",
+        b"net" => sub == b"restart",
+        b"reboot" | b"poweroff" | b"defrag" | b"kill" => true,
+        _ => false,
+    }
+}
+
+/// Copy console output, turning bare LF into CRLF for the terminal
+pub fn push_crlf(out: &mut Vec<u8>, bytes: &[u8]) {
+    for &byte in bytes {
+        if byte == b'\n' {
+            out.extend_from_slice(b"\r\n");
+        } else {
+            out.push(byte);
+        }
+    }
+}
+
+// ============================================================================
+// Byte Utilities
+// ============================================================================
+
+/// Trim leading and trailing ASCII whitespace from bytes
+pub fn trim_bytes(data: &[u8]) -> &[u8] {
+    let start = data
+        .iter()
+        .position(|&b| !b.is_ascii_whitespace())
+        .unwrap_or(data.len());
+    let end = data
+        .iter()
+        .rposition(|&b| !b.is_ascii_whitespace())
+        .map(|i| i + 1)
+        .unwrap_or(start);
+    &data[start..end]
+}
+
+/// Split at first whitespace, returning (first_word, rest_trimmed)
+pub fn split_first_word(data: &[u8]) -> (&[u8], &[u8]) {
+    if let Some(pos) = data.iter().position(|&b| b.is_ascii_whitespace()) {
+        (&data[..pos], trim_bytes(&data[pos..]))
+    } else {
+        (data, &[])
+    }
+}
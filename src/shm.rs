@@ -0,0 +1,330 @@
+//! Shared Memory Regions
+//!
+//! Named, page-backed buffers that several threads can hold at once: the
+//! packet capture ring, trace buffers and (later) user processes share one
+//! allocation instead of copying between owners. Each buffer is reference
+//! counted; the memory goes back to the page allocator when the last
+//! handle is dropped, so ownership is simply "whoever still holds one".
+//!
+//! Every handle is tracked in `handles` under the thread or task that got
+//! it, and stays there: another owner opens its own by name. A holder that
+//! is killed without dropping its handle doesn't pin the buffer.
+//!
+//! `Shm` can read and write; `ShmReader` can only read. The kernel address
+//! space is one identity map, so a reader's restriction is enforced by the
+//! type rather than the page tables - the backing region is still recorded
+//! in `vmm` and shows up in region listings as `shm`.
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::handles::{self, Handle, ResourceKind};
+use crate::lockdep::Spinlock;
+use crate::mmu::Perm;
+use crate::vmm::{self, Region};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::ops::Deref;
+
+/// Longest buffer name
+pub const MAX_NAME: usize = 32;
+
+/// Run a closure with IRQs disabled so buffers can be dropped from any context
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Buffers
+// ============================================================================
+
+struct Buffer {
+    name: String,
+    region: Region,
+    /// Requested size; the region is rounded up to whole pages
+    len: usize,
+}
+
+impl Buffer {
+    fn check(&self, offset: usize, len: usize) -> KResult<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(KError::with_context(ErrorKind::InvalidArgument, "shm range")),
+        }
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> KResult<()> {
+        self.check(offset, buf.len())?;
+        // SAFETY: in bounds of the region, which lives as long as self
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (self.region.base + offset) as *const u8,
+                buf.as_mut_ptr(),
+                buf.len(),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        let base = self.region.base;
+        with_irqs_disabled(|| {
+            let mut registry = REGISTRY.lock();
+            if let Some(pos) = registry.iter().position(|e| e.base == base) {
+                registry.swap_remove(pos);
+            }
+        });
+        let _ = vmm::unmap(self.region.id);
+    }
+}
+
+/// One tracked reference to a buffer
+struct Holder {
+    buffer: ManuallyDrop<Arc<Buffer>>,
+    handle: Handle,
+    /// Tracked under its owner, so it must not move to another thread
+    _owner: PhantomData<*const ()>,
+}
+
+/// Buffer address the reclaim action drops a reference through
+struct BufferRef(*const Buffer);
+
+// SAFETY: Buffer is Send + Sync; the pointer is only used to drop a reference
+unsafe impl Send for BufferRef {}
+
+impl BufferRef {
+    fn get(&self) -> *const Buffer {
+        self.0
+    }
+}
+
+impl Holder {
+    fn new(buffer: Arc<Buffer>) -> KResult<Self> {
+        let buffer_ref = BufferRef(Arc::as_ptr(&buffer));
+        let handle = handles::track(ResourceKind::Shm, move || {
+            // SAFETY: the holder outlived its owner, so it will never drop
+            // this reference itself
+            unsafe { Arc::decrement_strong_count(buffer_ref.get()) }
+        })?;
+        Ok(Self {
+            buffer: ManuallyDrop::new(buffer),
+            handle,
+            _owner: PhantomData,
+        })
+    }
+}
+
+impl Deref for Holder {
+    type Target = Buffer;
+
+    fn deref(&self) -> &Buffer {
+        &self.buffer
+    }
+}
+
+impl Drop for Holder {
+    fn drop(&mut self) {
+        // If the handle is gone the table already dropped our reference
+        if handles::untrack(self.handle) {
+            // SAFETY: not used again
+            unsafe { ManuallyDrop::drop(&mut self.buffer) };
+        }
+    }
+}
+
+/// Read-write handle to a shared buffer
+pub struct Shm(Holder);
+
+/// Read-only handle to a shared buffer
+pub struct ShmReader(Holder);
+
+impl Shm {
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// Size in bytes as requested at creation
+    pub fn size(&self) -> usize {
+        self.0.len
+    }
+
+    /// Start of the buffer, for producers that manage their own layout
+    ///
+    /// Other holders may access the memory at any time; synchronizing with
+    /// them (e.g. with atomics in the buffer) is up to the caller.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.0.region.base as *mut u8
+    }
+
+    /// Copy `buf.len()` bytes out, starting at `offset`
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> KResult<()> {
+        self.0.read(offset, buf)
+    }
+
+    /// Copy `data` in, starting at `offset`
+    pub fn write(&self, offset: usize, data: &[u8]) -> KResult<()> {
+        self.0.check(offset, data.len())?;
+        // SAFETY: in bounds of the region, which lives as long as self
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), self.as_mut_ptr().add(offset), data.len());
+        }
+        Ok(())
+    }
+
+    /// A handle to the same buffer that can only read
+    pub fn reader(&self) -> KResult<ShmReader> {
+        Holder::new(Arc::clone(&self.0.buffer)).map(ShmReader)
+    }
+}
+
+impl ShmReader {
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// Size in bytes as requested at creation
+    pub fn size(&self) -> usize {
+        self.0.len
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.0.region.base as *const u8
+    }
+
+    /// Copy `buf.len()` bytes out, starting at `offset`
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> KResult<()> {
+        self.0.read(offset, buf)
+    }
+}
+
+// ============================================================================
+// Registry
+// ============================================================================
+
+struct Entry {
+    /// Region base, unique while the buffer is alive
+    base: usize,
+    buffer: Weak<Buffer>,
+}
+
+static REGISTRY: Spinlock<Vec<Entry>> = Spinlock::new(Vec::new());
+
+/// Snapshot of one live buffer
+#[derive(Debug, Clone)]
+pub struct ShmInfo {
+    pub name: String,
+    pub base: usize,
+    pub len: usize,
+    /// Handles currently held, read-write and read-only alike
+    pub refs: usize,
+}
+
+fn lookup(name: &str) -> Option<Arc<Buffer>> {
+    with_irqs_disabled(|| {
+        REGISTRY
+            .lock()
+            .iter()
+            .filter_map(|e| e.buffer.upgrade())
+            .find(|b| b.name == name)
+    })
+}
+
+/// Create a zeroed buffer of `len` bytes called `name`
+///
+/// Fails with `AlreadyExists` while another buffer has the name.
+pub fn create(name: &str, len: usize) -> KResult<Shm> {
+    if name.is_empty() || name.len() > MAX_NAME || len == 0 {
+        return Err(KError::with_context(ErrorKind::InvalidArgument, "shm"));
+    }
+    if lookup(name).is_some() {
+        return Err(KError::with_context(ErrorKind::AlreadyExists, "shm"));
+    }
+
+    let mut owned_name = String::new();
+    owned_name
+        .try_reserve(name.len())
+        .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "shm"))?;
+    owned_name.push_str(name);
+
+    let region = vmm::map_anonymous(len, Perm::ReadWrite, "shm")?;
+    let buffer = Arc::new(Buffer {
+        name: owned_name,
+        region,
+        len,
+    });
+
+    let entry = Entry {
+        base: region.base,
+        buffer: Arc::downgrade(&buffer),
+    };
+    let registered = with_irqs_disabled(|| {
+        let mut registry = REGISTRY.lock();
+        // A racing create may have taken the name since the check above
+        if registry
+            .iter()
+            .filter_map(|e| e.buffer.upgrade())
+            .any(|b| b.name == name)
+        {
+            return Err(KError::with_context(ErrorKind::AlreadyExists, "shm"));
+        }
+        registry
+            .try_reserve(1)
+            .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "shm registry"))?;
+        registry.push(entry);
+        Ok(())
+    });
+
+    // On failure, dropping the buffer outside the lock frees its pages
+    registered.and_then(|()| Holder::new(buffer).map(Shm))
+}
+
+/// Read-write handle to the buffer called `name`
+pub fn open(name: &str) -> KResult<Shm> {
+    let buffer = lookup(name).ok_or(KError::with_context(ErrorKind::NotFound, "shm"))?;
+    Holder::new(buffer).map(Shm)
+}
+
+/// Read-only handle to the buffer called `name`
+pub fn open_reader(name: &str) -> KResult<ShmReader> {
+    let buffer = lookup(name).ok_or(KError::with_context(ErrorKind::NotFound, "shm"))?;
+    Holder::new(buffer).map(ShmReader)
+}
+
+/// Snapshot of every live buffer, lowest address first
+pub fn list() -> Vec<ShmInfo> {
+    let mut out = with_irqs_disabled(|| {
+        let registry = REGISTRY.lock();
+        let mut out = Vec::new();
+        if out.try_reserve(registry.len()).is_err() {
+            return out;
+        }
+        for entry in registry.iter() {
+            // Nothing else runs while the lock is held, so this temporary
+            // reference is never the last one and can't re-enter Drop
+            if let Some(buffer) = entry.buffer.upgrade() {
+                out.push(ShmInfo {
+                    name: buffer.name.clone(),
+                    base: entry.base,
+                    len: buffer.len,
+                    refs: Arc::strong_count(&buffer) - 1,
+                });
+            }
+        }
+        out
+    });
+    out.sort_by_key(|info| info.base);
+    out
+}
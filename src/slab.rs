@@ -0,0 +1,274 @@
+//! Slab Allocator for Fixed-Size Kernel Objects
+//!
+//! A `SlabCache` carves large blocks ("slabs") from the general heap into
+//! equal-sized objects kept on an intrusive free list, so allocating and
+//! freeing an object is O(1) and never fragments the main heap. A cache
+//! grows to its high-water mark and keeps its slabs, except that an idle
+//! cache (no object in use) gives them all back when the heap runs out
+//! (`reclaim`, an OOM callback).
+
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+use crate::lockdep::Spinlock;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// Minimum bytes requested from the heap per slab
+const SLAB_SIZE: usize = 16 * 1024;
+
+/// Free objects store the next-free address in their first word
+const MIN_OBJECT_SIZE: usize = core::mem::size_of::<usize>();
+
+/// Each slab ends in one word linking it to the previous slab
+const SLAB_LINK: usize = core::mem::size_of::<usize>();
+
+/// Run a closure with IRQs disabled so caches can be used near IRQ paths
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Slab Cache
+// ============================================================================
+
+struct SlabInner {
+    /// Address of the first free object (0 = empty)
+    free_list: usize,
+    /// Address of the newest slab (0 = none); each links to the one before
+    slab_list: usize,
+    slabs: usize,
+    in_use: usize,
+    free: usize,
+    registered: bool,
+}
+
+/// A cache of equal-sized objects
+///
+/// Declare caches as statics; they register themselves for `stats()` the
+/// first time they grow.
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    align: usize,
+    inner: Spinlock<SlabInner>,
+}
+
+/// Usage snapshot for one cache
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+    pub free: usize,
+}
+
+impl SlabCache {
+    /// Create a cache for objects of `size` bytes aligned to `align`
+    /// (`align` must be a power of two)
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let align = if align < MIN_OBJECT_SIZE {
+            MIN_OBJECT_SIZE
+        } else {
+            align
+        };
+        let size = if size < MIN_OBJECT_SIZE {
+            MIN_OBJECT_SIZE
+        } else {
+            size
+        };
+        let object_size = (size + align - 1) & !(align - 1);
+
+        Self {
+            name,
+            object_size,
+            align,
+            inner: Spinlock::new(SlabInner {
+                free_list: 0,
+                slab_list: 0,
+                slabs: 0,
+                in_use: 0,
+                free: 0,
+                registered: false,
+            }),
+        }
+    }
+
+    /// Size of each object in bytes (after alignment rounding)
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    /// Bytes of objects per slab (the link word follows them)
+    fn slab_objects_size(&self) -> usize {
+        if self.object_size > SLAB_SIZE {
+            self.object_size
+        } else {
+            SLAB_SIZE - SLAB_SIZE % self.object_size
+        }
+    }
+
+    fn slab_layout(&self) -> Option<Layout> {
+        Layout::from_size_align(self.slab_objects_size() + SLAB_LINK, self.align).ok()
+    }
+
+    /// Allocate a new slab and thread its objects onto the free list
+    fn grow(&self, inner: &mut SlabInner) -> bool {
+        let layout = match self.slab_layout() {
+            Some(layout) => layout,
+            None => return false,
+        };
+
+        // SAFETY: layout has non-zero size
+        let base = unsafe { alloc::alloc::alloc(layout) };
+        if base.is_null() {
+            return false;
+        }
+
+        let objects = self.slab_objects_size();
+        // SAFETY: the link word is the last one in the slab; objects are a
+        // multiple of `align` (at least a word) in size, so it is aligned
+        unsafe { ((base as usize + objects) as *mut usize).write(inner.slab_list) };
+        inner.slab_list = base as usize;
+
+        let count = objects / self.object_size;
+        for i in (0..count).rev() {
+            let obj = base as usize + i * self.object_size;
+            // SAFETY: obj lies inside the slab we just allocated
+            unsafe { (obj as *mut usize).write(inner.free_list) };
+            inner.free_list = obj;
+        }
+
+        inner.slabs += 1;
+        inner.free += count;
+        true
+    }
+
+    /// Allocate one object, growing the cache if needed
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        let register = with_irqs_disabled(|| {
+            let mut inner = self.inner.lock();
+            if inner.free_list == 0 && !self.grow(&mut inner) {
+                return None;
+            }
+
+            let obj = inner.free_list;
+            // SAFETY: obj is a free object holding the next-free address
+            inner.free_list = unsafe { (obj as *const usize).read() };
+            inner.free -= 1;
+            inner.in_use += 1;
+
+            let register = !inner.registered;
+            inner.registered = true;
+            Some((obj, register))
+        });
+
+        let (obj, register) = register?;
+        if register {
+            with_irqs_disabled(|| CACHES.lock().push(self));
+        }
+        NonNull::new(obj as *mut u8)
+    }
+
+    /// Return an object to the cache
+    ///
+    /// # Safety
+    /// `ptr` must come from `alloc` on this cache and must not be used again.
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
+        with_irqs_disabled(|| {
+            let mut inner = self.inner.lock();
+            let obj = ptr.as_ptr() as usize;
+            // SAFETY: caller guarantees ptr is an object we own exclusively
+            unsafe { (obj as *mut usize).write(inner.free_list) };
+            inner.free_list = obj;
+            inner.in_use -= 1;
+            inner.free += 1;
+        })
+    }
+
+    /// Give every slab back to the heap if no object is in use
+    ///
+    /// Returns the bytes released. Skips a cache that is busy (its lock is
+    /// held), so it is safe from the allocator's OOM path.
+    pub fn shrink(&self) -> usize {
+        let Some(layout) = self.slab_layout() else {
+            return 0;
+        };
+        with_irqs_disabled(|| {
+            let Some(mut inner) = self.inner.try_lock() else {
+                return 0;
+            };
+            if inner.in_use != 0 || inner.slabs == 0 {
+                return 0;
+            }
+
+            let mut slab = inner.slab_list;
+            while slab != 0 {
+                // SAFETY: slab came from grow() with this layout, and no
+                // object in it is in use
+                let prev = unsafe { ((slab + self.slab_objects_size()) as *const usize).read() };
+                unsafe { alloc::alloc::dealloc(slab as *mut u8, layout) };
+                slab = prev;
+            }
+
+            let released = inner.slabs * layout.size();
+            inner.slab_list = 0;
+            inner.free_list = 0;
+            inner.slabs = 0;
+            inner.free = 0;
+            released
+        })
+    }
+
+    /// Current usage of this cache
+    #[cfg(feature = "shell")]
+    pub fn stats(&self) -> SlabStats {
+        with_irqs_disabled(|| {
+            let inner = self.inner.lock();
+            SlabStats {
+                name: self.name,
+                object_size: self.object_size,
+                slabs: inner.slabs,
+                in_use: inner.in_use,
+                free: inner.free,
+            }
+        })
+    }
+}
+
+// ============================================================================
+// Cache Registry
+// ============================================================================
+
+static CACHES: Spinlock<Vec<&'static SlabCache>> = Spinlock::new(Vec::new());
+
+/// Usage of every cache that has allocated at least once
+#[cfg(feature = "shell")]
+pub fn stats() -> Vec<SlabStats> {
+    let caches: Vec<&'static SlabCache> = with_irqs_disabled(|| CACHES.lock().clone());
+    caches.iter().map(|c| c.stats()).collect()
+}
+
+/// OOM callback: release the slabs of every idle cache
+pub fn reclaim(_layout: Layout) -> usize {
+    // Never wait: the allocation that failed may be ours
+    match CACHES.try_lock() {
+        Some(caches) => caches.iter().map(|cache| cache.shrink()).sum(),
+        None => 0,
+    }
+}
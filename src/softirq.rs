@@ -0,0 +1,253 @@
+//! Deferred Interrupt Work
+//!
+//! Hard IRQ handlers should only acknowledge the device and note what
+//! happened; anything slower runs later as a bottom half, in thread
+//! context with interrupts enabled, where it may take locks, allocate and
+//! log like any other thread code.
+//!
+//! A handler either `raise`s one of the fixed softirq vectors (the network
+//! RX path raises `NetRx`) or `defer`s a closure. Pending work is run by
+//! `run_pending`, which the main network loop calls every iteration and
+//! the `softirqd` thread calls otherwise. One runner at a time: a vector's
+//! handler never runs concurrently with itself.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+
+/// Passes over the pending mask per `run_pending` call; work raised after
+/// that waits for the next call so an interrupt flood can't pin a thread
+const MAX_RESTARTS: usize = 10;
+
+/// Deferred closures held at once; `defer` fails beyond this
+const MAX_DEFERRED: usize = 64;
+
+/// Run a closure with IRQs disabled (the queues are filled from IRQ context)
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Vectors
+// ============================================================================
+
+/// Softirq vectors, run in this order when several are pending
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoftIrq {
+    /// Frames arrived on the network device
+    NetRx,
+    /// Closures queued with `defer`
+    Deferred,
+}
+
+impl SoftIrq {
+    pub const ALL: [SoftIrq; 2] = [SoftIrq::NetRx, SoftIrq::Deferred];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SoftIrq::NetRx => "net-rx",
+            SoftIrq::Deferred => "deferred",
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+const VECTORS: usize = SoftIrq::ALL.len();
+
+/// Bit per vector with work waiting
+static PENDING: AtomicU32 = AtomicU32::new(0);
+
+/// Set while a thread is inside `run_pending`
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Bottom half of one vector
+pub type Handler = fn();
+
+/// Handler per vector (`Deferred` is served by `run_deferred`)
+static HANDLERS: Spinlock<[Option<Handler>; VECTORS]> = Spinlock::new([None; VECTORS]);
+
+/// Counter value at the first `raise` of each vector since it last ran
+static RAISED_AT: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+
+static RAISED: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+static RUNS: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+
+/// Set the bottom half of `vector` (replacing any previous one)
+pub fn register(vector: SoftIrq, handler: Handler) {
+    with_irqs_disabled(|| HANDLERS.lock()[vector as usize] = Some(handler));
+}
+
+/// Mark `vector` pending; safe from any context, including hard IRQs
+pub fn raise(vector: SoftIrq) {
+    let now = crate::timer::read_counter();
+    let _ = RAISED_AT[vector as usize].compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed);
+    RAISED[vector as usize].fetch_add(1, Ordering::Relaxed);
+    PENDING.fetch_or(vector.bit(), Ordering::Release);
+    let softirqd = SOFTIRQD.load(Ordering::Acquire);
+    if softirqd != NO_THREAD {
+        let _ = crate::threading::unpark(softirqd);
+    }
+}
+
+/// Whether any bottom half waits to run
+pub fn pending() -> bool {
+    PENDING.load(Ordering::Acquire) != 0
+}
+
+// ============================================================================
+// Deferred Closures
+// ============================================================================
+
+type Work = Box<dyn FnOnce() + Send>;
+
+static DEFERRED: Spinlock<VecDeque<Work>> = Spinlock::new(VecDeque::new());
+
+/// Closures refused because the queue was full or memory ran out
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Run `work` later in thread context; safe from hard IRQs
+///
+/// Fails with `LimitReached` while `MAX_DEFERRED` closures are waiting.
+pub fn defer(work: impl FnOnce() + Send + 'static) -> KResult<()> {
+    let work: Work = Box::new(work);
+    let queued = with_irqs_disabled(|| {
+        let mut queue = DEFERRED.lock();
+        if queue.len() >= MAX_DEFERRED {
+            return Err(KError::with_context(ErrorKind::LimitReached, "softirq defer"));
+        }
+        queue
+            .try_reserve(1)
+            .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "softirq defer"))?;
+        queue.push_back(work);
+        Ok(())
+    });
+    if queued.is_ok() {
+        raise(SoftIrq::Deferred);
+    } else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    queued
+}
+
+/// Run the closures queued so far (later ones wait for the next pass)
+fn run_deferred() {
+    let queued = with_irqs_disabled(|| DEFERRED.lock().len());
+    for _ in 0..queued {
+        let Some(work) = with_irqs_disabled(|| DEFERRED.lock().pop_front()) else {
+            break;
+        };
+        work();
+    }
+}
+
+// ============================================================================
+// Running Bottom Halves
+// ============================================================================
+
+/// Run pending bottom halves; returns how many vectors ran
+///
+/// Call from thread context only. Returns at once if another thread is
+/// already running them (or this is a hard IRQ).
+pub fn run_pending() -> usize {
+    if crate::irq::in_irq() || PENDING.load(Ordering::Acquire) == 0 {
+        return 0;
+    }
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+
+    let mut ran = 0;
+    for _ in 0..MAX_RESTARTS {
+        let pending = PENDING.swap(0, Ordering::AcqRel);
+        if pending == 0 {
+            break;
+        }
+        for vector in SoftIrq::ALL {
+            if pending & vector.bit() == 0 {
+                continue;
+            }
+            let raised = RAISED_AT[vector as usize].swap(0, Ordering::Relaxed);
+            if raised != 0 {
+                let waited = crate::timer::read_counter().saturating_sub(raised);
+                crate::latency::record(
+                    crate::latency::Metric::IrqToBottomHalf,
+                    crate::timer::cycles_to_ns(waited),
+                );
+            }
+            match vector {
+                SoftIrq::Deferred => run_deferred(),
+                _ => {
+                    let handler = with_irqs_disabled(|| HANDLERS.lock()[vector as usize]);
+                    if let Some(handler) = handler {
+                        handler();
+                    }
+                }
+            }
+            RUNS[vector as usize].fetch_add(1, Ordering::Relaxed);
+            ran += 1;
+        }
+    }
+
+    RUNNING.store(false, Ordering::Release);
+    ran
+}
+
+/// `softirqd`'s tid, unparked by `raise` (NO_THREAD until started)
+static SOFTIRQD: AtomicUsize = AtomicUsize::new(NO_THREAD);
+const NO_THREAD: usize = usize::MAX;
+
+/// Start the `softirqd` thread, which runs bottom halves whenever the
+/// main loop isn't (before the network is up, or if it never comes up)
+///
+/// It parks while nothing is pending, so it doesn't keep the CPU busy.
+pub fn start() -> KResult<usize> {
+    let tid = crate::threading::spawn_named("softirqd", || loop {
+        run_pending();
+        if !pending() {
+            crate::threading::park();
+        }
+    })?;
+    SOFTIRQD.store(tid, Ordering::Release);
+    Ok(tid)
+}
+
+// ============================================================================
+// Statistics
+// ============================================================================
+
+/// Counters of one vector
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SoftIrqStats {
+    /// `raise` calls (several may be served by one run)
+    pub raised: u64,
+    /// Times the bottom half ran
+    pub runs: u64,
+}
+
+pub fn stats(vector: SoftIrq) -> SoftIrqStats {
+    SoftIrqStats {
+        raised: RAISED[vector as usize].load(Ordering::Relaxed),
+        runs: RUNS[vector as usize].load(Ordering::Relaxed),
+    }
+}
+
+/// Closures `defer` refused
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
@@ -6,28 +6,43 @@
 //! - ssh-ed25519 host key
 //! - aes128-ctr encryption
 //! - hmac-sha2-256 MAC
-//! - Accepts any authentication
+//! - password and ssh-ed25519 publickey authentication via `auth`
 //! - Shell with basic commands
 //! - Multiple concurrent SSH sessions
 
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::convert::TryInto;
-use spinning_top::Spinlock;
+use embassy_time::{Duration, with_timeout};
 
-use ed25519_dalek::{Signer, SigningKey, SECRET_KEY_LENGTH};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, SECRET_KEY_LENGTH};
 use hmac::Mac;
 use sha2::{Digest, Sha256};
 use x25519_dalek::PublicKey as X25519PublicKey;
 
-use crate::akuma::AKUMA_79;
+use crate::allocator::{self, AllocError};
+use crate::ansi::{self, AnsiParser, Color, Key};
+use crate::auth::{self, Role};
 use crate::async_net::{TcpError, TcpStream};
-use crate::console;
-use crate::network;
+use crate::conn_budget::HeapState;
+use crate::console::{self, ConsoleId};
+use crate::error::{ErrorKind, KError, KResult};
+use crate::events::Event;
+use crate::handles::{self, Handle, ResourceKind};
+use crate::klog::{self, Filter, Level};
+use crate::kobject::{self, KObjType, KObject};
+use crate::dmesg;
+use crate::lockdep::Spinlock;
+use crate::network::Service;
+use crate::secret::{self, SecretBox, SecretBytes};
+use crate::shell::{self, split_first_word, trim_bytes};
+use crate::workers;
 use crate::ssh_crypto::{
-    build_encrypted_packet, build_packet, derive_key, read_string, read_u32, split_first_word,
-    trim_bytes, write_namelist, write_string, write_u32, Aes128Ctr, CryptoState, HmacSha256,
-    SimpleRng, AES_IV_SIZE, AES_KEY_SIZE, MAC_KEY_SIZE, MAC_SIZE,
+    build_encrypted_packet, build_packet, derive_key, read_string, read_u32, write_namelist,
+    write_string, write_u32, Aes128Ctr, CryptoState, HmacSha256, SimpleRng, AES_IV_SIZE,
+    AES_KEY_SIZE, MAC_KEY_SIZE, MAC_SIZE,
 };
 
 // ============================================================================
@@ -48,11 +63,14 @@ const SSH_MSG_NEWKEYS: u8 = 21;
 const SSH_MSG_KEX_ECDH_INIT: u8 = 30;
 const SSH_MSG_KEX_ECDH_REPLY: u8 = 31;
 const SSH_MSG_USERAUTH_REQUEST: u8 = 50;
+const SSH_MSG_USERAUTH_FAILURE: u8 = 51;
 const SSH_MSG_USERAUTH_SUCCESS: u8 = 52;
+const SSH_MSG_USERAUTH_PK_OK: u8 = 60;
 const SSH_MSG_GLOBAL_REQUEST: u8 = 80;
 const SSH_MSG_REQUEST_FAILURE: u8 = 82;
 const SSH_MSG_CHANNEL_OPEN: u8 = 90;
 const SSH_MSG_CHANNEL_OPEN_CONFIRMATION: u8 = 91;
+const SSH_MSG_CHANNEL_OPEN_FAILURE: u8 = 92;
 const SSH_MSG_CHANNEL_DATA: u8 = 94;
 const SSH_MSG_CHANNEL_EOF: u8 = 96;
 const SSH_MSG_CHANNEL_CLOSE: u8 = 97;
@@ -60,6 +78,15 @@ const SSH_MSG_CHANNEL_REQUEST: u8 = 98;
 const SSH_MSG_CHANNEL_SUCCESS: u8 = 99;
 const SSH_MSG_CHANNEL_FAILURE: u8 = 100;
 
+// Disconnect and channel open failure reasons
+const SSH_DISCONNECT_PROTOCOL_ERROR: u32 = 2;
+const SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE: u32 = 14;
+const SSH_OPEN_ADMINISTRATIVELY_PROHIBITED: u32 = 1;
+
+/// Failed USERAUTH requests before the connection is closed (clients
+/// usually start with a `none` probe and try several keys)
+const MAX_AUTH_ATTEMPTS: u32 = 6;
+
 // Algorithm names
 const KEX_ALGO: &str = "curve25519-sha256";
 const HOST_KEY_ALGO: &str = "ssh-ed25519";
@@ -67,11 +94,16 @@ const CIPHER_ALGO: &str = "aes128-ctr";
 const MAC_ALGO: &str = "hmac-sha2-256";
 const COMPRESS_ALGO: &str = "none";
 
+/// Payloads at least this large are encrypted on a worker thread; for
+/// smaller ones (keystroke echo, short replies) the hop costs more than
+/// the cipher
+const OFFLOAD_MIN_PAYLOAD: usize = 4096;
+
 // ============================================================================
 // Shared Host Key (for all sessions)
 // ============================================================================
 
-static HOST_KEY: Spinlock<Option<SigningKey>> = Spinlock::new(None);
+static HOST_KEY: Spinlock<Option<SecretBox<SigningKey>>> = Spinlock::new(None);
 
 /// Initialize the shared host key (call once at startup)
 pub fn init_host_key() {
@@ -80,13 +112,14 @@ pub fn init_host_key() {
         let mut rng = SimpleRng::new();
         let mut key_bytes = [0u8; SECRET_KEY_LENGTH];
         rng.fill_bytes(&mut key_bytes);
-        *guard = Some(SigningKey::from_bytes(&key_bytes));
+        *guard = Some(SecretBox::new(SigningKey::from_bytes(&key_bytes)));
+        secret::wipe(&mut key_bytes);
         log("[SSH] Host key initialized\n");
     }
 }
 
 /// Get a clone of the shared host key
-fn get_host_key() -> Option<SigningKey> {
+fn get_host_key() -> Option<SecretBox<SigningKey>> {
     HOST_KEY.lock().clone()
 }
 
@@ -118,12 +151,29 @@ struct SshSession {
     client_kexinit: Vec<u8>,
     server_kexinit: Vec<u8>,
     session_id: [u8; 32],
-    host_key: Option<SigningKey>,
+    host_key: Option<SecretBox<SigningKey>>,
     crypto: CryptoState,
     input_buffer: Vec<u8>,
     channel_open: bool,
     client_channel: u32,
     line_buffer: Vec<u8>,
+    input_parser: AnsiParser,
+    /// Set while `log tail` is streaming records to the channel
+    tail: Option<LogTail>,
+    /// Set while attached to the kernel console
+    console: Option<ConsoleMirror>,
+    /// Authenticated user name
+    user: Option<String>,
+    /// Rejected USERAUTH requests so far
+    auth_failures: u32,
+    /// Idle time before the shell locks (from the auth provider)
+    idle_lock: Option<Duration>,
+    /// Uptime of the last keystroke, in microseconds
+    last_input_us: u64,
+    /// Set while the shell is locked waiting for the password
+    unlock: Option<UnlockPrompt>,
+    /// Listed under the connection's socket in `kobj tree`
+    _kobj: Option<Arc<KObject>>,
 }
 
 impl SshSession {
@@ -142,6 +192,15 @@ impl SshSession {
             channel_open: false,
             client_channel: 0,
             line_buffer: Vec::new(),
+            input_parser: AnsiParser::new(),
+            tail: None,
+            console: None,
+            user: None,
+            auth_failures: 0,
+            idle_lock: None,
+            last_input_us: 0,
+            unlock: None,
+            _kobj: None,
         }
     }
 }
@@ -178,39 +237,54 @@ fn build_kexinit(rng: &mut SimpleRng) -> Vec<u8> {
 // Key Exchange
 // ============================================================================
 
-fn handle_kex_ecdh_init(session: &mut SshSession, client_pubkey: &[u8]) -> Option<Vec<u8>> {
-    // Generate server ephemeral key pair using X25519
-    let mut secret_bytes = [0u8; 32];
-    session.rng.fill_bytes(&mut secret_bytes);
+/// What the key exchange needs from the session, owned so the expensive
+/// half can run on a worker thread
+struct KexInput {
+    server_secret: SecretBox<x25519_dalek::StaticSecret>,
+    client_pubkey: Vec<u8>,
+    host_key: SecretBox<SigningKey>,
+    /// V_C, V_S, I_C and I_S as SSH strings
+    transcript: Vec<u8>,
+    session_id: [u8; 32],
+}
+
+struct KexOutput {
+    reply: Vec<u8>,
+    session_id: [u8; 32],
+    /// New ciphers and MAC keys; the sequence numbers are unused
+    keys: CryptoState,
+}
 
-    let server_secret = x25519_dalek::StaticSecret::from(secret_bytes);
-    let server_public = X25519PublicKey::from(&server_secret);
+/// X25519, the exchange hash, its signature and key derivation
+fn compute_kex(input: KexInput) -> Option<KexOutput> {
+    let KexInput {
+        server_secret,
+        client_pubkey,
+        host_key,
+        transcript,
+        mut session_id,
+    } = input;
+    let server_public = X25519PublicKey::from(&*server_secret);
     let server_pubkey = server_public.as_bytes();
 
     // Parse client's X25519 public key
-    let client_pubkey_bytes: [u8; 32] = client_pubkey.try_into().ok()?;
+    let client_pubkey_bytes: [u8; 32] = client_pubkey.as_slice().try_into().ok()?;
     let client_public = X25519PublicKey::from(client_pubkey_bytes);
 
     // Compute shared secret via ECDH
     let shared_secret_point = server_secret.diffie_hellman(&client_public);
-    let shared_secret = shared_secret_point.as_bytes().to_vec();
+    let shared_secret = SecretBytes::from(&shared_secret_point.as_bytes()[..]);
 
-    let host_key = session.host_key.as_ref()?;
     let host_pubkey = host_key.verifying_key().to_bytes();
 
     let mut host_key_blob = Vec::new();
     write_string(&mut host_key_blob, b"ssh-ed25519");
     write_string(&mut host_key_blob, &host_pubkey);
 
-    let mut hash_data = Vec::new();
-    write_string(&mut hash_data, &session.client_version);
-    write_string(&mut hash_data, &session.server_version);
-    write_string(&mut hash_data, &session.client_kexinit);
-    write_string(&mut hash_data, &session.server_kexinit);
+    let mut hash_data = transcript;
     write_string(&mut hash_data, &host_key_blob);
-    write_string(&mut hash_data, client_pubkey);
+    write_string(&mut hash_data, &client_pubkey);
     write_string(&mut hash_data, server_pubkey);
-
     // K as mpint
     if !shared_secret.is_empty() && shared_secret[0] & 0x80 != 0 {
         write_u32(&mut hash_data, (shared_secret.len() + 1) as u32);
@@ -223,9 +297,11 @@ fn handle_kex_ecdh_init(session: &mut SshSession, client_pubkey: &[u8]) -> Optio
     let mut hasher = Sha256::new();
     hasher.update(&hash_data);
     let exchange_hash: [u8; 32] = hasher.finalize().into();
+    // hash_data holds K
+    secret::wipe(&mut hash_data);
 
-    if session.session_id == [0u8; 32] {
-        session.session_id = exchange_hash;
+    if session_id == [0u8; 32] {
+        session_id = exchange_hash;
     }
 
     let signature = host_key.sign(&exchange_hash);
@@ -234,67 +310,32 @@ fn handle_kex_ecdh_init(session: &mut SshSession, client_pubkey: &[u8]) -> Optio
     write_string(&mut sig_blob, signature.to_bytes().as_slice());
 
     // Derive encryption keys
-    let iv_c2s = derive_key(
-        &shared_secret,
-        &exchange_hash,
-        b'A',
-        &session.session_id,
-        AES_IV_SIZE,
-    );
-    let iv_s2c = derive_key(
-        &shared_secret,
-        &exchange_hash,
-        b'B',
-        &session.session_id,
-        AES_IV_SIZE,
-    );
-    let key_c2s = derive_key(
-        &shared_secret,
-        &exchange_hash,
-        b'C',
-        &session.session_id,
-        AES_KEY_SIZE,
-    );
-    let key_s2c = derive_key(
-        &shared_secret,
-        &exchange_hash,
-        b'D',
-        &session.session_id,
-        AES_KEY_SIZE,
-    );
-    let mac_c2s = derive_key(
-        &shared_secret,
-        &exchange_hash,
-        b'E',
-        &session.session_id,
-        MAC_KEY_SIZE,
-    );
-    let mac_s2c = derive_key(
-        &shared_secret,
-        &exchange_hash,
-        b'F',
-        &session.session_id,
-        MAC_KEY_SIZE,
-    );
+    let iv_c2s = derive_key(&shared_secret, &exchange_hash, b'A', &session_id, AES_IV_SIZE);
+    let iv_s2c = derive_key(&shared_secret, &exchange_hash, b'B', &session_id, AES_IV_SIZE);
+    let key_c2s = derive_key(&shared_secret, &exchange_hash, b'C', &session_id, AES_KEY_SIZE);
+    let key_s2c = derive_key(&shared_secret, &exchange_hash, b'D', &session_id, AES_KEY_SIZE);
+    let mac_c2s = derive_key(&shared_secret, &exchange_hash, b'E', &session_id, MAC_KEY_SIZE);
+    let mac_s2c = derive_key(&shared_secret, &exchange_hash, b'F', &session_id, MAC_KEY_SIZE);
 
     use ctr::cipher::KeyIvInit;
-    session.crypto.decrypt_cipher = Some(Aes128Ctr::new(
-        key_c2s[..AES_KEY_SIZE].try_into().unwrap(),
-        iv_c2s[..AES_IV_SIZE].try_into().unwrap(),
-    ));
-    session
-        .crypto
-        .decrypt_mac_key
-        .copy_from_slice(&mac_c2s[..MAC_KEY_SIZE]);
-
-    session.crypto.encrypt_cipher = Some(Aes128Ctr::new(
-        key_s2c[..AES_KEY_SIZE].try_into().unwrap(),
-        iv_s2c[..AES_IV_SIZE].try_into().unwrap(),
-    ));
-    session
-        .crypto
-        .encrypt_mac_key
-        .copy_from_slice(&mac_s2c[..MAC_KEY_SIZE]);
+    let mut keys = CryptoState::new();
+    secret::wipe_replace(
+        &mut keys.decrypt_cipher,
+        Some(Aes128Ctr::new(
+            key_c2s[..AES_KEY_SIZE].try_into().unwrap(),
+            iv_c2s[..AES_IV_SIZE].try_into().unwrap(),
+        )),
+    );
+    keys.decrypt_mac_key.copy_from_slice(&mac_c2s[..MAC_KEY_SIZE]);
+
+    secret::wipe_replace(
+        &mut keys.encrypt_cipher,
+        Some(Aes128Ctr::new(
+            key_s2c[..AES_KEY_SIZE].try_into().unwrap(),
+            iv_s2c[..AES_IV_SIZE].try_into().unwrap(),
+        )),
+    );
+    keys.encrypt_mac_key.copy_from_slice(&mac_s2c[..MAC_KEY_SIZE]);
 
     // Build KEX_ECDH_REPLY
     let mut reply = Vec::new();
@@ -303,6 +344,48 @@ fn handle_kex_ecdh_init(session: &mut SshSession, client_pubkey: &[u8]) -> Optio
     write_string(&mut reply, server_pubkey);
     write_string(&mut reply, &sig_blob);
 
+    Some(KexOutput {
+        reply,
+        session_id,
+        keys,
+    })
+}
+
+/// Answer KEX_ECDH_INIT and install the new keys; the computation runs on
+/// a worker so other connections keep being served meanwhile
+async fn handle_kex_ecdh_init(session: &mut SshSession, client_pubkey: &[u8]) -> Option<Vec<u8>> {
+    // Generate server ephemeral key pair using X25519
+    let mut secret_bytes = [0u8; 32];
+    session.rng.fill_bytes(&mut secret_bytes);
+
+    let server_secret = SecretBox::new(x25519_dalek::StaticSecret::from(secret_bytes));
+    secret::wipe(&mut secret_bytes);
+
+    let mut transcript = Vec::new();
+    write_string(&mut transcript, &session.client_version);
+    write_string(&mut transcript, &session.server_version);
+    write_string(&mut transcript, &session.client_kexinit);
+    write_string(&mut transcript, &session.server_kexinit);
+
+    let input = KexInput {
+        server_secret,
+        client_pubkey: client_pubkey.to_vec(),
+        host_key: session.host_key.clone()?,
+        transcript,
+        session_id: session.session_id,
+    };
+    let KexOutput {
+        reply,
+        session_id,
+        mut keys,
+    } = workers::offload(move || compute_kex(input)).await?;
+
+    session.session_id = session_id;
+    secret::wipe_replace(&mut session.crypto.decrypt_cipher, keys.decrypt_cipher.take());
+    session.crypto.decrypt_mac_key = keys.decrypt_mac_key;
+    secret::wipe_replace(&mut session.crypto.encrypt_cipher, keys.encrypt_cipher.take());
+    session.crypto.encrypt_mac_key = keys.encrypt_mac_key;
+
     Some(reply)
 }
 
@@ -319,7 +402,7 @@ async fn send_unencrypted_packet(
     payload: &[u8],
     session: &mut SshSession,
 ) -> Result<(), TcpError> {
-    let packet = build_packet(payload);
+    let packet = build_packet(payload).map_err(|_| TcpError::WriteFailed)?;
     session.crypto.encrypt_seq = session.crypto.encrypt_seq.wrapping_add(1);
     send_raw(stream, &packet).await
 }
@@ -329,10 +412,33 @@ async fn send_encrypted_packet(
     payload: &[u8],
     session: &mut SshSession,
 ) -> Result<(), TcpError> {
-    if let Some(cipher) = session.crypto.encrypt_cipher.as_mut() {
+    if payload.len() >= OFFLOAD_MIN_PAYLOAD
+        && let Some(mut cipher) = session.crypto.encrypt_cipher.take()
+    {
+        let payload = match try_copy(payload) {
+            Ok(payload) => payload,
+            Err(AllocError) => {
+                session.crypto.encrypt_cipher = Some(cipher);
+                return Err(TcpError::WriteFailed);
+            }
+        };
         let seq = session.crypto.encrypt_seq;
         session.crypto.encrypt_seq = seq.wrapping_add(1);
-        let packet = build_encrypted_packet(payload, cipher, &session.crypto.encrypt_mac_key, seq);
+        // The cipher travels to the worker and back; nothing else can
+        // send on this session until it returns
+        let mac_key = SecretBox::new(session.crypto.encrypt_mac_key);
+        let (packet, cipher) = workers::offload(move || {
+            let packet = build_encrypted_packet(&payload, &mut cipher, &mac_key, seq);
+            (packet, cipher)
+        })
+        .await;
+        session.crypto.encrypt_cipher = Some(cipher);
+        send_raw(stream, &packet.map_err(|_| TcpError::WriteFailed)?).await
+    } else if let Some(cipher) = session.crypto.encrypt_cipher.as_mut() {
+        let seq = session.crypto.encrypt_seq;
+        session.crypto.encrypt_seq = seq.wrapping_add(1);
+        let packet = build_encrypted_packet(payload, cipher, &session.crypto.encrypt_mac_key, seq)
+            .map_err(|_| TcpError::WriteFailed)?;
         send_raw(stream, &packet).await
     } else {
         Ok(())
@@ -369,63 +475,397 @@ async fn send_channel_data(
     send_packet(stream, &payload, session).await
 }
 
-fn execute_command(line: &[u8]) -> Vec<u8> {
-    let line = trim_bytes(line);
-    if line.is_empty() {
-        return Vec::new();
+// ============================================================================
+// Log Tail
+// ============================================================================
+
+/// How often a following session checks the log ring
+const TAIL_POLL_MS: u64 = 100;
+
+/// Records replayed when a tail starts
+const TAIL_BACKLOG: usize = 10;
+
+/// Follow state for `log tail` / `dmesg -f`
+struct LogTail {
+    next_seq: u64,
+    max_level: Level,
+    module: Option<String>,
+}
+
+impl LogTail {
+    fn filter(&self) -> Filter<'_> {
+        Filter {
+            max_level: self.max_level,
+            module: self.module.as_deref(),
+        }
     }
+}
 
+/// Parse `log tail [level] [module]` or `dmesg -f [level] [module]`
+///
+/// Returns None if the line isn't a tail command, Some(Err) with a usage
+/// message if its arguments are bad.
+fn parse_tail_command(line: &[u8]) -> Option<Result<LogTail, &'static [u8]>> {
+    let line = trim_bytes(line);
     let (cmd, args) = split_first_word(line);
-    let mut response = Vec::new();
+    let (sub, args) = split_first_word(args);
+    match (cmd, sub) {
+        (b"log", b"tail") | (b"dmesg", b"-f") => {}
+        _ => return None,
+    }
 
-    match cmd {
-        b"echo" => {
-            if !args.is_empty() {
-                response.extend_from_slice(args);
-            }
-            response.extend_from_slice(b"\r\n");
-        }
-        b"akuma" | b"cat" => {
-            // shows picture of a cat
-            // Convert \n to \r\n for proper SSH terminal display
-            for &byte in AKUMA_79 {
-                if byte == b'\n' {
-                    response.extend_from_slice(b"\r\n");
-                } else {
-                    response.push(byte);
+    let mut tail = LogTail {
+        next_seq: 0,
+        max_level: Level::Trace,
+        module: None,
+    };
+    let args = match core::str::from_utf8(args) {
+        Ok(args) => args,
+        Err(_) => return Some(Err(b"Usage: log tail [level] [module]\r\n")),
+    };
+    for word in args.split_ascii_whitespace() {
+        match Level::parse(word) {
+            Some(level) => tail.max_level = level,
+            None if tail.module.is_none() => tail.module = Some(String::from(word)),
+            None => return Some(Err(b"Usage: log tail [level] [module]\r\n")),
+        }
+    }
+
+    // Start with the last few matching records, like tail(1)
+    let (recent, _) = klog::records_since(0, &tail.filter());
+    tail.next_seq = recent.iter().rev().nth(TAIL_BACKLOG - 1).map_or(0, |r| r.seq);
+    Some(Ok(tail))
+}
+
+/// Highlight for a level in `log tail` output
+fn level_color(level: Level) -> Option<Color> {
+    match level {
+        Level::Error => Some(Color::Red),
+        Level::Warn => Some(Color::Yellow),
+        Level::Debug | Level::Trace => Some(Color::Cyan),
+        Level::Info => None,
+    }
+}
+
+/// Send records logged since the last call to a following session
+async fn send_tail_records(stream: &mut TcpStream, session: &mut SshSession) -> KResult<()> {
+    let (records, next) = match &session.tail {
+        Some(tail) => klog::records_since(tail.next_seq, &tail.filter()),
+        None => return Ok(()),
+    };
+    if let Some(tail) = session.tail.as_mut() {
+        tail.next_seq = next;
+    }
+
+    let mut out = Vec::new();
+    for r in records.iter() {
+        let time = alloc::format!("{} ", klog::Timestamp::of(r));
+        out.extend_from_slice(time.as_bytes());
+        let level = alloc::format!("[{:<5}]", r.level.as_str());
+        match level_color(r.level) {
+            Some(color) => ansi::colored(&mut out, color, level.as_bytes()),
+            None => out.extend_from_slice(level.as_bytes()),
+        }
+        let line = match r.event {
+            Some(event) => alloc::format!(" {:<12} [E{}] {}\r\n", r.module, event.id(), r.text),
+            None => alloc::format!(" {:<12} {}\r\n", r.module, r.text),
+        };
+        out.extend_from_slice(line.as_bytes());
+    }
+    if !out.is_empty() {
+        send_channel_data(stream, session, &out).await?;
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Console Mirror
+// ============================================================================
+
+/// Leaves the console mirror (Ctrl-C also works when not typing to it)
+const DETACH_KEY: u8 = 0x1D; // Ctrl-]
+
+/// A session attached to the kernel console with `console attach|take`
+///
+/// Output is followed through the dmesg ring; with `input` set, keystrokes
+/// are fed to the console as well. Detaches when dropped, or when the
+/// session's task is released without dropping it.
+struct ConsoleMirror {
+    id: u32,
+    next_pos: u64,
+    input: bool,
+    handle: Handle,
+}
+
+impl Drop for ConsoleMirror {
+    fn drop(&mut self) {
+        if handles::untrack(self.handle) {
+            console::detach(self.id);
+        }
+    }
+}
+
+/// `console attach` (output only) or `console take` (output and input)
+fn parse_console_command(line: &[u8]) -> Option<bool> {
+    let (cmd, args) = split_first_word(trim_bytes(line));
+    match (cmd, trim_bytes(args)) {
+        (b"console", b"attach") => Some(false),
+        (b"console", b"take") => Some(true),
+        _ => None,
+    }
+}
+
+fn attach_console(session: &SshSession, input: bool) -> KResult<ConsoleMirror> {
+    let name = alloc::format!("ssh:{}", session.user.as_deref().unwrap_or("?"));
+    let id = console::attach(&name);
+    let handle = match handles::track(ResourceKind::Session, move || console::detach(id)) {
+        Ok(handle) => handle,
+        Err(e) => {
+            console::detach(id);
+            return Err(e);
+        }
+    };
+    let mirror = ConsoleMirror {
+        id,
+        next_pos: dmesg::position(),
+        input,
+        handle,
+    };
+    if input {
+        console::set_active(ConsoleId::Remote(mirror.id))?;
+    }
+    Ok(mirror)
+}
+
+/// Send console output written since the last call to an attached session
+async fn send_console_output(stream: &mut TcpStream, session: &mut SshSession) -> KResult<()> {
+    let (bytes, next) = match &session.console {
+        Some(mirror) => dmesg::read_since(mirror.next_pos),
+        None => return Ok(()),
+    };
+    if let Some(mirror) = session.console.as_mut() {
+        mirror.next_pos = next;
+    }
+    if !bytes.is_empty() {
+        let mut out = Vec::new();
+        shell::push_crlf(&mut out, &bytes);
+        send_channel_data(stream, session, &out).await?;
+    }
+    Ok(())
+}
+
+/// Forward whatever a following session is waiting for
+async fn send_followed_output(stream: &mut TcpStream, session: &mut SshSession) -> KResult<()> {
+    send_tail_records(stream, session).await?;
+    send_console_output(stream, session).await
+}
+
+// ============================================================================
+// User Authentication
+// ============================================================================
+
+/// Outcome of a USERAUTH_REQUEST
+enum UserAuth {
+    Success(String),
+    /// Publickey query without a signature: the key would be accepted
+    KeyAcceptable { algorithm: Vec<u8>, blob: Vec<u8> },
+    Failure,
+}
+
+/// Check a USERAUTH_REQUEST payload (after the message type) against the
+/// auth provider
+///
+/// Publickey signatures cover the session id followed by the request
+/// itself up to the signature (RFC 4252 section 7).
+async fn check_userauth(payload: &[u8], session_id: &[u8; 32]) -> UserAuth {
+    let provider = auth::provider();
+    let mut offset = 0;
+    let parsed = (|| {
+        let user = core::str::from_utf8(read_string(payload, &mut offset)?).ok()?;
+        let _service = read_string(payload, &mut offset)?;
+        let method = read_string(payload, &mut offset)?;
+        Some((user, method))
+    })();
+    let (user, method) = match parsed {
+        Some(parsed) => parsed,
+        None => return UserAuth::Failure,
+    };
+
+    let accepted = match method {
+        b"none" => provider.is_open(),
+        b"password" => {
+            offset += 1; // FALSE (not a password change)
+            match read_string(payload, &mut offset) {
+                // Password hashing is slow on purpose: keep it off the
+                // network thread
+                Some(password) => {
+                    let password = SecretBytes::from(password);
+                    let name = String::from(user);
+                    let provider = provider.clone();
+                    workers::offload(move || provider.verify_password(&name, &password)).await
                 }
-            }
-            if !AKUMA_79.ends_with(b"\n") {
-                response.extend_from_slice(b"\r\n");
+                None => false,
             }
         }
-        b"quit" | b"exit" => {
-            response.extend_from_slice(b"Goodbye!\r\n");
+        b"publickey" => {
+            let has_signature = payload.get(offset).is_some_and(|&b| b != 0);
+            offset += 1;
+            let (algorithm, blob) = match (
+                read_string(payload, &mut offset),
+                read_string(payload, &mut offset),
+            ) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return UserAuth::Failure,
+            };
+            let mut blob_offset = 0;
+            let key = match (
+                read_string(blob, &mut blob_offset),
+                read_string(blob, &mut blob_offset),
+            ) {
+                (Some(name), Some(key)) if name == algorithm => key,
+                _ => return UserAuth::Failure,
+            };
+            let algo_name = core::str::from_utf8(algorithm).unwrap_or("");
+            if algorithm != HOST_KEY_ALGO.as_bytes() || !provider.verify_pubkey(user, algo_name, key) {
+                return UserAuth::Failure;
+            }
+            if !has_signature {
+                return UserAuth::KeyAcceptable {
+                    algorithm: algorithm.to_vec(),
+                    blob: blob.to_vec(),
+                };
+            }
+
+            let signed_len = offset;
+            let signature = read_string(payload, &mut offset).and_then(|sig| {
+                let mut sig_offset = 0;
+                let name = read_string(sig, &mut sig_offset)?;
+                let bytes: [u8; 64] = read_string(sig, &mut sig_offset)?.try_into().ok()?;
+                (name == algorithm).then(|| Signature::from_bytes(&bytes))
+            });
+            let verifying_key = key
+                .try_into()
+                .ok()
+                .and_then(|k: [u8; 32]| VerifyingKey::from_bytes(&k).ok());
+            match (signature, verifying_key) {
+                (Some(signature), Some(verifying_key)) => {
+                    let mut signed = Vec::with_capacity(37 + signed_len);
+                    write_string(&mut signed, session_id);
+                    signed.push(SSH_MSG_USERAUTH_REQUEST);
+                    signed.extend_from_slice(&payload[..signed_len]);
+                    verifying_key.verify(&signed, &signature).is_ok()
+                }
+                _ => false,
+            }
         }
-        b"stats" => {
-            let (connections, bytes_rx, bytes_tx) = network::get_stats();
-            let stats = alloc::format!(
-                "Network Statistics:\r\n  Connections: {}\r\n  Bytes RX: {}\r\n  Bytes TX: {}\r\n",
-                connections, bytes_rx, bytes_tx
+        _ => false,
+    };
+
+    if accepted {
+        UserAuth::Success(String::from(user))
+    } else {
+        // Probing with "none" and unsigned key queries are routine
+        if method != b"none" {
+            crate::kevent!(
+                Event::AuthFailure,
+                "[SSH] Rejected {} login for '{}' ({})",
+                core::str::from_utf8(method).unwrap_or("?"),
+                user,
+                provider.name()
             );
-            response.extend_from_slice(stats.as_bytes());
         }
-        b"help" => {
-            response.extend_from_slice(b"Available commands:\r\n");
-            response.extend_from_slice(b"  echo <text>  - Echo back text\r\n");
-            response.extend_from_slice(b"  akuma        - Display ASCII art\r\n");
-            response.extend_from_slice(b"  stats        - Show network statistics\r\n");
-            response.extend_from_slice(b"  help         - Show this help\r\n");
-            response.extend_from_slice(b"  quit/exit    - Close connection\r\n");
+        UserAuth::Failure
+    }
+}
+
+// ============================================================================
+// Idle Lock
+// ============================================================================
+
+/// Failed unlock attempts before the connection is closed
+const MAX_UNLOCK_ATTEMPTS: u32 = 3;
+
+/// Password being typed at the lock prompt
+struct UnlockPrompt {
+    entered: SecretBytes,
+    failures: u32,
+}
+
+/// Time left before the shell locks, if it can lock at all
+fn idle_lock_remaining(session: &SshSession) -> Option<Duration> {
+    if session.unlock.is_some() || !session.channel_open {
+        return None;
+    }
+    let lock_us = session.idle_lock?.as_micros();
+    let idle_us = crate::timer::uptime_us().saturating_sub(session.last_input_us);
+    Some(Duration::from_micros(lock_us.saturating_sub(idle_us)))
+}
+
+/// Blank the screen and ask for the password before accepting commands
+async fn lock_session(stream: &mut TcpStream, session: &mut SshSession) -> KResult<()> {
+    session.tail = None;
+    session.console = None;
+    session.line_buffer.clear();
+    session.unlock = Some(UnlockPrompt {
+        entered: SecretBytes::new(),
+        failures: 0,
+    });
+    log(&alloc::format!(
+        "[SSH] Session of '{}' locked after idling\n",
+        session.user.as_deref().unwrap_or("?")
+    ));
+
+    let mut out = Vec::new();
+    ansi::clear_screen(&mut out);
+    out.extend_from_slice(b"Session locked. Password: ");
+    send_channel_data(stream, session, &out).await?;
+    Ok(())
+}
+
+/// Feed a key to the lock prompt; returns true if the connection should close
+async fn handle_unlock_key(stream: &mut TcpStream, session: &mut SshSession, key: Key) -> KResult<bool> {
+    let prompt = match session.unlock.as_mut() {
+        Some(prompt) => prompt,
+        None => return Ok(false),
+    };
+    match key {
+        Key::Char(c) => prompt.entered.push(c),
+        Key::Backspace => {
+            let len = prompt.entered.len().saturating_sub(1);
+            prompt.entered.truncate(len);
         }
-        _ => {
-            response.extend_from_slice(b"Unknown command: ");
-            response.extend_from_slice(cmd);
-            response.extend_from_slice(b"\r\nType 'help' for available commands.\r\n");
+        Key::Ctrl(b'u') | Key::Ctrl(b'c') => prompt.entered.truncate(0),
+        Key::Enter => {
+            let user = session.user.clone().unwrap_or_default();
+            if auth::provider().verify_password(&user, &prompt.entered) {
+                session.unlock = None;
+                log(&alloc::format!("[SSH] Session of '{}' unlocked\n", user));
+                send_channel_data(stream, session, b"\r\nUnlocked\r\nakuma> ").await?;
+                return Ok(false);
+            }
+
+            prompt.entered.truncate(0);
+            prompt.failures += 1;
+            crate::kevent!(
+                Event::UnlockFailure,
+                "[SSH] Failed unlock attempt {} for '{}'",
+                prompt.failures,
+                user
+            );
+            if prompt.failures >= MAX_UNLOCK_ATTEMPTS {
+                send_channel_data(stream, session, b"\r\nToo many attempts\r\n").await?;
+                let mut close = vec![SSH_MSG_CHANNEL_CLOSE];
+                write_u32(&mut close, session.client_channel);
+                send_packet(stream, &close, session).await?;
+                session.channel_open = false;
+                session.state = SshState::Disconnected;
+                return Ok(true);
+            }
+            send_channel_data(stream, session, b"\r\nWrong password. Password: ").await?;
         }
+        _ => {}
     }
-
-    response
+    Ok(false)
 }
 
 fn is_quit_command(line: &[u8]) -> bool {
@@ -438,17 +878,103 @@ async fn handle_shell_input(
     stream: &mut TcpStream,
     session: &mut SshSession,
     data: &[u8],
-) -> Result<bool, TcpError> {
+) -> KResult<bool> {
+    if session.user.is_none() {
+        return protocol_error(stream, session, "not authenticated").await;
+    }
+    session.last_input_us = crate::timer::uptime_us();
     for &byte in data {
-        match byte {
-            b'\r' | b'\n' => {
+        // Attached to the console: raw bytes go to it (escape sequences
+        // included, so it can edit lines) until the detach key
+        if let Some(mirror) = &session.console {
+            let detach = byte == DETACH_KEY || (!mirror.input && byte == 0x03);
+            if detach {
+                session.console = None;
+                send_channel_data(stream, session, b"\r\nDetached from console\r\nakuma> ").await?;
+            } else if mirror.input {
+                console::push_input(mirror.id, &[byte]);
+            }
+            continue;
+        }
+
+        let key = match session.input_parser.feed(byte) {
+            Some(key) => key,
+            None => continue,
+        };
+
+        if session.unlock.is_some() {
+            if handle_unlock_key(stream, session, key).await? {
+                return Ok(true);
+            }
+            continue;
+        }
+
+        // Following the log: Ctrl-C stops, everything else is ignored
+        if session.tail.is_some() {
+            if key == Key::Ctrl(b'c') {
+                session.tail = None;
+                send_channel_data(stream, session, b"^C\r\nakuma> ").await?;
+            }
+            continue;
+        }
+
+        match key {
+            Key::Enter => {
                 let line = session.line_buffer.clone();
                 session.line_buffer.clear();
 
                 send_channel_data(stream, session, b"\r\n").await?;
 
+                match parse_tail_command(&line) {
+                    Some(Ok(tail)) => {
+                        session.tail = Some(tail);
+                        send_channel_data(stream, session, b"Following kernel log (Ctrl-C to stop)\r\n")
+                            .await?;
+                        send_tail_records(stream, session).await?;
+                        continue;
+                    }
+                    Some(Err(usage)) => {
+                        send_channel_data(stream, session, usage).await?;
+                        send_channel_data(stream, session, b"akuma> ").await?;
+                        continue;
+                    }
+                    None => {}
+                }
+
+                let admin = session
+                    .user
+                    .as_deref()
+                    .is_some_and(|user| auth::provider().authorize(user, Role::Admin));
+
+                if let Some(input) = parse_console_command(&line) {
+                    if input && !admin {
+                        send_channel_data(stream, session, b"Permission denied\r\nakuma> ").await?;
+                        continue;
+                    }
+                    match attach_console(session, input) {
+                        Ok(mirror) => {
+                            session.console = Some(mirror);
+                            let msg: &[u8] = if input {
+                                b"Attached to console with input (Ctrl-] to detach)\r\n"
+                            } else {
+                                b"Attached to console (Ctrl-] or Ctrl-C to detach)\r\n"
+                            };
+                            send_channel_data(stream, session, msg).await?;
+                        }
+                        Err(e) => {
+                            let msg = alloc::format!("console: {}\r\nakuma> ", e);
+                            send_channel_data(stream, session, msg.as_bytes()).await?;
+                        }
+                    }
+                    continue;
+                }
+
                 if !line.is_empty() {
-                    let response = execute_command(&line);
+                    let response = if shell::needs_admin(&line) && !admin {
+                        b"Permission denied\r\n".to_vec()
+                    } else {
+                        shell::execute_command(&line)
+                    };
                     if !response.is_empty() {
                         send_channel_data(stream, session, &response).await?;
                     }
@@ -465,18 +991,18 @@ async fn handle_shell_input(
 
                 send_channel_data(stream, session, b"akuma> ").await?;
             }
-            0x7F | 0x08 => {
+            Key::Backspace => {
                 if !session.line_buffer.is_empty() {
                     session.line_buffer.pop();
-                    send_channel_data(stream, session, b"\x08 \x08").await?;
+                    send_channel_data(stream, session, ansi::RUBOUT).await?;
                 }
             }
-            0x03 => {
+            Key::Ctrl(b'c') => {
                 session.line_buffer.clear();
                 send_channel_data(stream, session, b"^C\r\n").await?;
                 send_channel_data(stream, session, b"akuma> ").await?;
             }
-            0x04 => {
+            Key::Ctrl(b'd') => {
                 if session.line_buffer.is_empty() {
                     send_channel_data(stream, session, b"\r\nGoodbye!\r\n").await?;
                     let mut close = vec![SSH_MSG_CHANNEL_CLOSE];
@@ -487,10 +1013,12 @@ async fn handle_shell_input(
                     return Ok(true); // Signal disconnect
                 }
             }
-            _ if byte >= 0x20 && byte < 0x7F => {
-                session.line_buffer.push(byte);
-                send_channel_data(stream, session, &[byte]).await?;
+            Key::Char(c) if (0x20..0x7F).contains(&c) => {
+                session.line_buffer.push(c);
+                send_channel_data(stream, session, &[c]).await?;
             }
+            // Arrow keys, function keys etc. are consumed so their escape
+            // bytes don't end up in the line buffer
             _ => {}
         }
     }
@@ -501,17 +1029,81 @@ async fn handle_shell_input(
 // Message Handlers
 // ============================================================================
 
+/// Tell the client why and close; returns true (disconnect) for the caller
+async fn disconnect(
+    stream: &mut TcpStream,
+    session: &mut SshSession,
+    reason: u32,
+    description: &str,
+) -> KResult<bool> {
+    warn(&alloc::format!("[SSH] Disconnecting: {}\n", description));
+    let mut msg = vec![SSH_MSG_DISCONNECT];
+    write_u32(&mut msg, reason);
+    write_string(&mut msg, description.as_bytes());
+    write_string(&mut msg, b""); // Language tag
+    send_packet(stream, &msg, session).await?;
+    session.channel_open = false;
+    session.state = SshState::Disconnected;
+    stream.close();
+    Ok(true)
+}
+
+/// `disconnect` for a message the client shouldn't have sent
+async fn protocol_error(
+    stream: &mut TcpStream,
+    session: &mut SshSession,
+    what: &str,
+) -> KResult<bool> {
+    disconnect(stream, session, SSH_DISCONNECT_PROTOCOL_ERROR, what).await
+}
+
 async fn handle_message(
     stream: &mut TcpStream,
     msg_type: u8,
     payload: &[u8],
     session: &mut SshSession,
-) -> Result<bool, TcpError> {
-    log(&alloc::format!(
+) -> KResult<bool> {
+    debug(&alloc::format!(
         "[SSH] Received message type {}\n",
         msg_type
     ));
 
+    // Each step of the handshake only in its turn, and nothing on a
+    // channel before the user authenticated
+    let authenticated = session.state == SshState::Authenticated && session.user.is_some();
+    match msg_type {
+        SSH_MSG_NEWKEYS if session.state != SshState::AwaitingNewKeys => {
+            return protocol_error(stream, session, "unexpected NEWKEYS").await;
+        }
+        SSH_MSG_SERVICE_REQUEST if session.state != SshState::AwaitingServiceRequest => {
+            return protocol_error(stream, session, "unexpected service request").await;
+        }
+        // Requests after success are ignored (RFC 4252 section 5.1)
+        SSH_MSG_USERAUTH_REQUEST if authenticated => return Ok(false),
+        SSH_MSG_USERAUTH_REQUEST if session.state != SshState::AwaitingUserAuth => {
+            return protocol_error(stream, session, "unexpected auth request").await;
+        }
+        SSH_MSG_CHANNEL_OPEN if !authenticated => {
+            let mut offset = 0;
+            let sender =
+                read_string(payload, &mut offset).and_then(|_| read_u32(payload, &mut offset));
+            let Some(sender) = sender else {
+                return protocol_error(stream, session, "bad channel open").await;
+            };
+            let mut reply = vec![SSH_MSG_CHANNEL_OPEN_FAILURE];
+            write_u32(&mut reply, sender);
+            write_u32(&mut reply, SSH_OPEN_ADMINISTRATIVELY_PROHIBITED);
+            write_string(&mut reply, b"not authenticated");
+            write_string(&mut reply, b""); // Language tag
+            send_packet(stream, &reply, session).await?;
+            return Ok(false);
+        }
+        SSH_MSG_CHANNEL_OPEN_CONFIRMATION..=SSH_MSG_CHANNEL_FAILURE if !authenticated => {
+            return protocol_error(stream, session, "not authenticated").await;
+        }
+        _ => {}
+    }
+
     match msg_type {
         SSH_MSG_KEXINIT => {
             let mut full = vec![SSH_MSG_KEXINIT];
@@ -528,21 +1120,28 @@ async fn handle_message(
         SSH_MSG_KEX_ECDH_INIT => {
             let mut offset = 0;
             if let Some(client_pubkey) = read_string(payload, &mut offset) {
-                if let Some(reply) = handle_kex_ecdh_init(session, client_pubkey) {
+                if let Some(reply) = handle_kex_ecdh_init(session, client_pubkey).await {
                     send_unencrypted_packet(stream, &reply, session).await?;
 
                     let newkeys = vec![SSH_MSG_NEWKEYS];
                     send_unencrypted_packet(stream, &newkeys, session).await?;
                     session.state = SshState::AwaitingNewKeys;
                 } else {
-                    log("[SSH] KEX failed\n");
+                    return Err(KError::with_context(ErrorKind::Crypto, "key exchange"));
                 }
+            } else {
+                return Err(KError::with_context(ErrorKind::Protocol, "KEX_ECDH_INIT"));
             }
         }
 
         SSH_MSG_NEWKEYS => {
             log("[SSH] Encryption activated\n");
-            session.state = SshState::AwaitingServiceRequest;
+            // A re-key doesn't undo the login
+            session.state = if session.user.is_some() {
+                SshState::Authenticated
+            } else {
+                SshState::AwaitingServiceRequest
+            };
         }
 
         SSH_MSG_SERVICE_REQUEST => {
@@ -560,12 +1159,37 @@ async fn handle_message(
             }
         }
 
-        SSH_MSG_USERAUTH_REQUEST => {
-            let reply = vec![SSH_MSG_USERAUTH_SUCCESS];
-            send_packet(stream, &reply, session).await?;
-            session.state = SshState::Authenticated;
-            log("[SSH] User authenticated\n");
-        }
+        SSH_MSG_USERAUTH_REQUEST => match check_userauth(payload, &session.session_id).await {
+            UserAuth::Success(user) => {
+                let reply = vec![SSH_MSG_USERAUTH_SUCCESS];
+                send_packet(stream, &reply, session).await?;
+                session.state = SshState::Authenticated;
+                crate::kevent!(Event::AuthSuccess, "[SSH] User '{}' authenticated", user);
+                session.user = Some(user);
+            }
+            UserAuth::KeyAcceptable { algorithm, blob } => {
+                let mut reply = vec![SSH_MSG_USERAUTH_PK_OK];
+                write_string(&mut reply, &algorithm);
+                write_string(&mut reply, &blob);
+                send_packet(stream, &reply, session).await?;
+            }
+            UserAuth::Failure => {
+                session.auth_failures += 1;
+                if session.auth_failures >= MAX_AUTH_ATTEMPTS {
+                    return disconnect(
+                        stream,
+                        session,
+                        SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE,
+                        "too many authentication failures",
+                    )
+                    .await;
+                }
+                let mut reply = vec![SSH_MSG_USERAUTH_FAILURE];
+                write_namelist(&mut reply, &["publickey", "password"]);
+                reply.push(0); // No partial success
+                send_packet(stream, &reply, session).await?;
+            }
+        },
 
         SSH_MSG_CHANNEL_OPEN => {
             let mut offset = 0;
@@ -607,7 +1231,15 @@ async fn handle_message(
                     core::str::from_utf8(req_type)
                 ));
 
-                let success = matches!(req_type, b"pty-req" | b"shell" | b"env");
+                let shell_allowed = session
+                    .user
+                    .as_deref()
+                    .is_some_and(|user| auth::provider().authorize(user, Role::User));
+                let success = match req_type {
+                    b"pty-req" | b"env" => true,
+                    b"shell" => shell_allowed,
+                    _ => false,
+                };
 
                 if want_reply {
                     let msg_type = if success {
@@ -620,7 +1252,13 @@ async fn handle_message(
                     send_packet(stream, &full_reply, session).await?;
                 }
 
-                if req_type == b"shell" {
+                if req_type == b"shell" && shell_allowed {
+                    session.idle_lock = session
+                        .user
+                        .as_deref()
+                        .and_then(|user| auth::provider().idle_lock(user))
+                        .map(|d| Duration::from_micros(d.as_micros() as u64));
+                    session.last_input_us = crate::timer::uptime_us();
                     send_channel_data(stream, session, b"\r\n=================================\r\n")
                         .await?;
                     send_channel_data(stream, session, b"  Welcome to Akuma SSH Server\r\n")
@@ -687,13 +1325,24 @@ async fn handle_message(
 // Packet Processing
 // ============================================================================
 
-fn process_encrypted_packet(session: &mut SshSession) -> Option<(u8, Vec<u8>)> {
+/// Copy of `data`, or AllocError if the heap is exhausted
+fn try_copy(data: &[u8]) -> Result<Vec<u8>, AllocError> {
+    let mut copy = Vec::new();
+    allocator::try_extend(&mut copy, data)?;
+    Ok(copy)
+}
+
+/// Next complete packet in the input buffer, if any
+/// Err means the heap ran out and the connection must be closed.
+fn process_encrypted_packet(session: &mut SshSession) -> Result<Option<(u8, Vec<u8>)>, AllocError> {
     // Need at least 4 bytes for packet length
     if session.input_buffer.len() < 4 {
-        return None;
+        return Ok(None);
     }
 
-    let cipher = session.crypto.decrypt_cipher.as_mut()?;
+    let Some(cipher) = session.crypto.decrypt_cipher.as_mut() else {
+        return Ok(None);
+    };
 
     // Clone cipher to peek at packet length without advancing the real cipher
     use ctr::cipher::StreamCipher;
@@ -708,31 +1357,33 @@ fn process_encrypted_packet(session: &mut SshSession) -> Option<(u8, Vec<u8>)> {
     // Total size needed: 4 (length) + packet_len + MAC_SIZE
     let total_needed = 4 + packet_len + MAC_SIZE;
     if session.input_buffer.len() < total_needed {
-        return None;
+        return Ok(None);
     }
 
     // We have enough data - now decrypt for real
     let encrypted_data = &session.input_buffer[..4 + packet_len];
     let received_mac = &session.input_buffer[4 + packet_len..total_needed];
 
-    // Decrypt the packet
-    let mut decrypted = encrypted_data.to_vec();
+    // Decrypt the packet (copy first: the cipher must not advance if we can't)
+    let mut decrypted = try_copy(encrypted_data)?;
     cipher.apply_keystream(&mut decrypted);
 
     // Verify MAC: MAC(key, sequence_number || unencrypted_packet)
     let seq = session.crypto.decrypt_seq;
-    let mut mac = <HmacSha256 as Mac>::new_from_slice(&session.crypto.decrypt_mac_key).ok()?;
+    let Ok(mut mac) = <HmacSha256 as Mac>::new_from_slice(&session.crypto.decrypt_mac_key) else {
+        return Ok(None);
+    };
     mac.update(&seq.to_be_bytes());
     mac.update(&decrypted);
 
     if mac.verify_slice(received_mac).is_err() {
-        log(&alloc::format!(
+        warn(&alloc::format!(
             "[SSH] MAC verification failed (seq={}, pkt_len={}, buf_len={})\n",
             seq,
             packet_len,
             session.input_buffer.len()
         ));
-        return None;
+        return Ok(None);
     }
 
     session.crypto.decrypt_seq = seq.wrapping_add(1);
@@ -742,40 +1393,48 @@ fn process_encrypted_packet(session: &mut SshSession) -> Option<(u8, Vec<u8>)> {
     let payload_len = packet_len - padding_len - 1;
 
     if 5 + payload_len > decrypted.len() {
-        return None;
+        return Ok(None);
     }
 
     let msg_type = decrypted[5];
-    let payload = decrypted[6..5 + payload_len].to_vec();
+    // Reuse the decrypted copy for the payload
+    decrypted.truncate(5 + payload_len);
+    decrypted.drain(..6);
 
     // Remove processed packet from buffer
-    session.input_buffer = session.input_buffer[total_needed..].to_vec();
+    session.input_buffer.drain(..total_needed);
 
-    Some((msg_type, payload))
+    Ok(Some((msg_type, decrypted)))
 }
 
-fn process_unencrypted_packet(session: &mut SshSession) -> Option<(u8, Vec<u8>)> {
+/// Unencrypted counterpart of `process_encrypted_packet`
+fn process_unencrypted_packet(session: &mut SshSession) -> Result<Option<(u8, Vec<u8>)>, AllocError> {
     if session.input_buffer.len() < 5 {
-        return None;
+        return Ok(None);
     }
 
-    let packet_len = u32::from_be_bytes(session.input_buffer[..4].try_into().ok()?) as usize;
+    let packet_len = u32::from_be_bytes([
+        session.input_buffer[0],
+        session.input_buffer[1],
+        session.input_buffer[2],
+        session.input_buffer[3],
+    ]) as usize;
     let total_len = 4 + packet_len;
 
     if session.input_buffer.len() < total_len {
-        return None;
+        return Ok(None);
     }
 
     let padding_len = session.input_buffer[4] as usize;
     let payload_len = packet_len - padding_len - 1;
 
     let msg_type = session.input_buffer[5];
-    let payload = session.input_buffer[6..5 + payload_len].to_vec();
+    let payload = try_copy(&session.input_buffer[6..5 + payload_len])?;
 
     session.crypto.decrypt_seq = session.crypto.decrypt_seq.wrapping_add(1);
-    session.input_buffer = session.input_buffer[total_len..].to_vec();
+    session.input_buffer.drain(..total_len);
 
-    Some((msg_type, payload))
+    Ok(Some((msg_type, payload)))
 }
 
 // ============================================================================
@@ -787,24 +1446,68 @@ fn process_unencrypted_packet(session: &mut SshSession) -> Option<(u8, Vec<u8>)>
 pub async fn handle_connection(mut stream: TcpStream) {
     log("[SSH] New SSH connection\n");
 
-    let mut session = SshSession::new();
+    // The session and read buffer live across every await below; kept in
+    // their own allocations they don't count against the future's budget
+    let (mut session, mut buf) = match (
+        HeapState::new(Service::Ssh, SshSession::new()),
+        HeapState::new(Service::Ssh, [0u8; 512]),
+    ) {
+        (Ok(session), Ok(buf)) => (session, buf),
+        _ => {
+            warn("[SSH] Out of memory, closing connection\n");
+            stream.close();
+            return;
+        }
+    };
+    session._kobj = kobject::create(KObjType::Session, "ssh", stream.kobject()).ok();
 
     // Send our version
     if send_raw(&mut stream, SSH_VERSION).await.is_err() {
-        log("[SSH] Failed to send version\n");
+        warn("[SSH] Failed to send version\n");
         return;
     }
 
     // Main receive loop
-    let mut buf = [0u8; 512];
     loop {
-        match stream.read(&mut buf).await {
+        // A following session wakes up periodically to forward new output,
+        // and a shell with an idle lock wakes up when it's due to lock
+        let following = session.tail.is_some() || session.console.is_some();
+        let tail_poll = following.then(|| Duration::from_millis(TAIL_POLL_MS));
+        let wake = match (tail_poll, idle_lock_remaining(&session)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let read = match wake {
+            Some(wake) => match with_timeout(wake, stream.read(&mut buf[..])).await {
+                Ok(read) => read,
+                Err(_) => {
+                    let result = if idle_lock_remaining(&session).is_some_and(|d| d.as_ticks() == 0) {
+                        lock_session(&mut stream, &mut session).await
+                    } else {
+                        send_followed_output(&mut stream, &mut session).await
+                    };
+                    if let Err(e) = result {
+                        warn(&alloc::format!("[SSH] Closing connection: {}\n", e));
+                        stream.close();
+                        return;
+                    }
+                    continue;
+                }
+            },
+            None => stream.read(&mut buf[..]).await,
+        };
+
+        match read {
             Ok(0) => {
                 log("[SSH] Connection closed by peer\n");
                 break;
             }
             Ok(n) => {
-                session.input_buffer.extend_from_slice(&buf[..n]);
+                if allocator::try_extend(&mut session.input_buffer, &buf[..n]).is_err() {
+                    warn("[SSH] Out of memory, closing connection\n");
+                    stream.close();
+                    return;
+                }
 
                 // Handle version exchange
                 if session.state == SshState::AwaitingVersion {
@@ -841,7 +1544,7 @@ pub async fn handle_connection(mut stream: TcpStream) {
                     };
 
                     match packet {
-                        Some((msg_type, payload)) => {
+                        Ok(Some((msg_type, payload))) => {
                             match handle_message(&mut stream, msg_type, &payload, &mut session)
                                 .await
                             {
@@ -850,18 +1553,24 @@ pub async fn handle_connection(mut stream: TcpStream) {
                                     return;
                                 }
                                 Ok(false) => {}
-                                Err(_) => {
-                                    log("[SSH] Error handling message\n");
+                                Err(e) => {
+                                    warn(&alloc::format!("[SSH] Closing connection: {}\n", e));
+                                    stream.close();
                                     return;
                                 }
                             }
                         }
-                        None => break,
+                        Ok(None) => break,
+                        Err(AllocError) => {
+                            log("[SSH] Out of memory, closing connection\n");
+                            stream.close();
+                            return;
+                        }
                     }
                 }
             }
             Err(_) => {
-                log("[SSH] Read error\n");
+                warn("[SSH] Read error\n");
                 break;
             }
         }
@@ -875,5 +1584,13 @@ pub async fn handle_connection(mut stream: TcpStream) {
 // ============================================================================
 
 fn log(msg: &str) {
-    console::print(msg);
+    klog::log(Level::Info, "ssh", msg);
+}
+
+fn warn(msg: &str) {
+    klog::log(Level::Warn, "ssh", msg);
+}
+
+fn debug(msg: &str) {
+    klog::log(Level::Debug, "ssh", msg);
 }
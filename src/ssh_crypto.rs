@@ -5,7 +5,6 @@
 //! - Crypto state management (AES-CTR, HMAC)
 //! - SSH packet building and parsing
 //! - Key derivation functions
-//! - Byte utility functions
 
 use alloc::vec::Vec;
 use core::convert::TryInto;
@@ -15,6 +14,8 @@ use ctr::{Ctr128BE, cipher::StreamCipher};
 use hmac::{Hmac, Mac};
 use sha2::{Digest, Sha256};
 
+use crate::allocator::{self, AllocError};
+use crate::secret::{self, SecretBytes};
 use crate::timer;
 
 // ============================================================================
@@ -78,6 +79,16 @@ pub struct CryptoState {
     pub encrypt_seq: u32,
 }
 
+impl Drop for CryptoState {
+    fn drop(&mut self) {
+        // Session keys and cipher state must not outlive the connection
+        secret::wipe(&mut self.decrypt_mac_key);
+        secret::wipe(&mut self.encrypt_mac_key);
+        secret::wipe_replace(&mut self.decrypt_cipher, None);
+        secret::wipe_replace(&mut self.encrypt_cipher, None);
+    }
+}
+
 impl CryptoState {
     pub fn new() -> Self {
         Self {
@@ -134,7 +145,7 @@ pub fn read_string<'a>(data: &'a [u8], offset: &mut usize) -> Option<&'a [u8]> {
 }
 
 /// Build an unencrypted SSH packet
-pub fn build_packet(payload: &[u8]) -> Vec<u8> {
+pub fn build_packet(payload: &[u8]) -> Result<Vec<u8>, AllocError> {
     let padding_len = 8 - ((5 + payload.len()) % 8);
     let padding_len = if padding_len < 4 {
         padding_len + 8
@@ -143,14 +154,14 @@ pub fn build_packet(payload: &[u8]) -> Vec<u8> {
     };
 
     let packet_len = 1 + payload.len() + padding_len;
-    let mut packet = Vec::with_capacity(4 + packet_len);
+    let mut packet = allocator::try_vec(4 + packet_len)?;
 
     write_u32(&mut packet, packet_len as u32);
     packet.push(padding_len as u8);
     packet.extend_from_slice(payload);
     packet.resize(packet.len() + padding_len, 0);
 
-    packet
+    Ok(packet)
 }
 
 /// Build an encrypted SSH packet with MAC
@@ -159,7 +170,7 @@ pub fn build_encrypted_packet(
     cipher: &mut Aes128Ctr,
     mac_key: &[u8; MAC_KEY_SIZE],
     seq: u32,
-) -> Vec<u8> {
+) -> Result<Vec<u8>, AllocError> {
     let padding_len = 16 - ((5 + payload.len()) % 16);
     let padding_len = if padding_len < 4 {
         padding_len + 16
@@ -168,7 +179,8 @@ pub fn build_encrypted_packet(
     };
 
     let packet_len = 1 + payload.len() + padding_len;
-    let mut packet = Vec::with_capacity(4 + packet_len + MAC_SIZE);
+    // Sized for the MAC too, so nothing below reallocates
+    let mut packet = allocator::try_vec(4 + packet_len + MAC_SIZE)?;
 
     write_u32(&mut packet, packet_len as u32);
     packet.push(padding_len as u8);
@@ -192,7 +204,7 @@ pub fn build_encrypted_packet(
     // Append MAC
     packet.extend_from_slice(&mac_result);
 
-    packet
+    Ok(packet)
 }
 
 // ============================================================================
@@ -201,7 +213,8 @@ pub fn build_encrypted_packet(
 
 /// Derive a key using SSH key derivation function
 /// K1 = HASH(K || H || letter || session_id)
-pub fn derive_key(k: &[u8], h: &[u8], letter: u8, session_id: &[u8], size: usize) -> Vec<u8> {
+/// The result (and the encoded K) are wiped when dropped.
+pub fn derive_key(k: &[u8], h: &[u8], letter: u8, session_id: &[u8], size: usize) -> SecretBytes {
     let mut hasher = Sha256::new();
 
     // K is encoded as mpint (with leading zero if high bit set)
@@ -219,44 +232,19 @@ pub fn derive_key(k: &[u8], h: &[u8], letter: u8, session_id: &[u8], size: usize
     hasher.update(&[letter]);
     hasher.update(session_id);
 
-    let mut result: Vec<u8> = hasher.finalize().to_vec();
+    let mut result = SecretBytes::with_capacity(size.div_ceil(32) * 32);
+    result.extend_from_slice(&hasher.finalize());
 
     // If we need more bytes, continue hashing
     while result.len() < size {
         let mut hasher = Sha256::new();
         hasher.update(&k_mpint);
         hasher.update(h);
-        hasher.update(&result);
+        hasher.update(&result[..]);
         result.extend_from_slice(&hasher.finalize());
     }
 
     result.truncate(size);
+    secret::wipe(&mut k_mpint);
     result
 }
-
-// ============================================================================
-// Byte Utilities
-// ============================================================================
-
-/// Trim leading and trailing ASCII whitespace from bytes
-pub fn trim_bytes(data: &[u8]) -> &[u8] {
-    let start = data
-        .iter()
-        .position(|&b| !b.is_ascii_whitespace())
-        .unwrap_or(data.len());
-    let end = data
-        .iter()
-        .rposition(|&b| !b.is_ascii_whitespace())
-        .map(|i| i + 1)
-        .unwrap_or(start);
-    &data[start..end]
-}
-
-/// Split at first whitespace, returning (first_word, rest_trimmed)
-pub fn split_first_word(data: &[u8]) -> (&[u8], &[u8]) {
-    if let Some(pos) = data.iter().position(|&b| b.is_ascii_whitespace()) {
-        (&data[..pos], trim_bytes(&data[pos..]))
-    } else {
-        (data, &[])
-    }
-}
@@ -9,12 +9,15 @@ use core::future::Future;
 use core::pin::Pin;
 use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 
-use embassy_net::tcp::TcpSocket;
 use embassy_net::Stack;
 use embassy_time::Duration;
 
-use crate::async_net::TcpStream;
-use crate::console;
+use crate::allocator;
+use crate::async_net::{PooledSocket, TcpStream};
+use crate::conn_budget;
+use crate::handles;
+use crate::klog::{self, Level};
+use crate::network::Service;
 use crate::ssh;
 
 // ============================================================================
@@ -23,8 +26,10 @@ use crate::ssh;
 
 const SSH_PORT: u16 = 22;
 const MAX_CONNECTIONS: usize = 8;
-const TCP_RX_BUFFER_SIZE: usize = 4096;
-const TCP_TX_BUFFER_SIZE: usize = 4096;
+
+/// Largest session future the build accepts; boxed once per connection,
+/// so growth here multiplies by `MAX_CONNECTIONS`
+const SESSION_FUTURE_BUDGET: usize = 16 * 1024;
 
 // ============================================================================
 // Connection State
@@ -32,7 +37,10 @@ const TCP_TX_BUFFER_SIZE: usize = 4096;
 
 /// Active SSH connection being handled
 struct ActiveConnection {
+    // Field order matters: the future drops before its task releases
+    // whatever it left registered
     future: Pin<Box<dyn Future<Output = ()>>>,
+    task: handles::Task,
     id: usize,
 }
 
@@ -53,11 +61,11 @@ pub async fn run(stack: Stack<'static>) {
     ssh::init_host_key();
 
     // Active connections
-    let mut connections: Vec<ActiveConnection> = Vec::new();
+    let mut connections: Vec<ActiveConnection> = Vec::with_capacity(MAX_CONNECTIONS);
     let mut next_id: usize = 0;
 
     // Pre-allocate a listening socket (reused when no connections)
-    let mut listen_socket: Option<TcpSocket<'static>> = None;
+    let mut listen_socket: Option<PooledSocket> = None;
 
     // Create waker for manual polling
     static VTABLE: RawWakerVTable = RawWakerVTable::new(
@@ -77,7 +85,12 @@ pub async fn run(stack: Stack<'static>) {
         // =====================================================================
         let mut i = 0;
         while i < connections.len() {
-            match connections[i].future.as_mut().poll(&mut cx) {
+            let poll = {
+                let conn = &mut connections[i];
+                let _scope = conn.task.enter();
+                conn.future.as_mut().poll(&mut cx)
+            };
+            match poll {
                 Poll::Ready(()) => {
                     let conn = connections.swap_remove(i);
                     log(&alloc::format!(
@@ -98,7 +111,11 @@ pub async fn run(stack: Stack<'static>) {
         if connections.len() < MAX_CONNECTIONS {
             // Ensure we have a listening socket
             if listen_socket.is_none() {
-                listen_socket = Some(create_listen_socket(stack));
+                listen_socket = create_listen_socket(stack);
+                if listen_socket.is_none() {
+                    warn("[SSH Server] Out of socket buffers, retrying...\n");
+                    embassy_time::Timer::after(Duration::from_millis(100)).await;
+                }
             }
 
             if let Some(ref mut socket) = listen_socket {
@@ -119,12 +136,12 @@ pub async fn run(stack: Stack<'static>) {
 
                         // Take the socket and create a new one for listening
                         let connected_socket = listen_socket.take().unwrap();
-                        let stream = TcpStream::from_socket(connected_socket);
-                        let future = Box::pin(handle_connection_wrapper(stream, id));
-                        connections.push(ActiveConnection { future, id });
+                        let mut stream = TcpStream::from_socket(connected_socket);
+                        stream.set_service(Service::Ssh);
+                        start_connection(&mut connections, stream, id);
                         }
                         Err(e) => {
-                            log(&alloc::format!("[SSH Server] Accept error: {:?}\n", e));
+                            warn(&alloc::format!("[SSH Server] Accept error: {:?}\n", e));
                             // Reset the socket
                             listen_socket = None;
                         }
@@ -152,12 +169,12 @@ pub async fn run(stack: Stack<'static>) {
 
                         // Take the socket and create a new one for listening
                         let connected_socket = listen_socket.take().unwrap();
-                        let stream = TcpStream::from_socket(connected_socket);
-                        let future = Box::pin(handle_connection_wrapper(stream, id));
-                        connections.push(ActiveConnection { future, id });
+                        let mut stream = TcpStream::from_socket(connected_socket);
+                        stream.set_service(Service::Ssh);
+                        start_connection(&mut connections, stream, id);
                     }
                     Ok(Err(e)) => {
-                            log(&alloc::format!("[SSH Server] Accept error: {:?}\n", e));
+                            warn(&alloc::format!("[SSH Server] Accept error: {:?}\n", e));
                             listen_socket = None;
                         }
                         Err(_) => {
@@ -181,15 +198,40 @@ pub async fn run(stack: Stack<'static>) {
 }
 
 /// Create a new socket for listening
-fn create_listen_socket(stack: Stack<'static>) -> TcpSocket<'static> {
-    let rx_buffer = alloc::vec![0u8; TCP_RX_BUFFER_SIZE].into_boxed_slice();
-    let tx_buffer = alloc::vec![0u8; TCP_TX_BUFFER_SIZE].into_boxed_slice();
-    let rx_ref: &'static mut [u8] = Box::leak(rx_buffer);
-    let tx_ref: &'static mut [u8] = Box::leak(tx_buffer);
-
-    let mut socket = TcpSocket::new(stack, rx_ref, tx_ref);
+fn create_listen_socket(stack: Stack<'static>) -> Option<PooledSocket> {
+    let mut socket = PooledSocket::new(stack)?;
     socket.set_timeout(Some(Duration::from_secs(60)));
-    socket
+    // Keep idle (e.g. locked) sessions from hitting the timeout
+    socket.set_keep_alive(Some(Duration::from_secs(20)));
+    Some(socket)
+}
+
+/// Box the session future and add it to the active set
+/// If the heap is exhausted the connection is closed instead of panicking.
+fn start_connection(connections: &mut Vec<ActiveConnection>, stream: TcpStream, id: usize) {
+    // The session's task owns its socket from here on
+    let task = handles::new_task();
+    let _ = handles::set_owner(stream.handle(), task.owner());
+
+    let future = conn_budget::admit::<SESSION_FUTURE_BUDGET, _>(
+        Service::Ssh,
+        handle_connection_wrapper(stream, id),
+    );
+    match allocator::try_box(future) {
+        Ok(future) => {
+            connections.push(ActiveConnection {
+                future: Box::into_pin(future),
+                task,
+                id,
+            });
+        }
+        Err(future) => {
+            // Static message: formatting one would need the heap we just ran out of
+            warn("[SSH Server] Out of memory, closing connection\n");
+            // Dropping the unpolled future drops the stream and its socket
+            drop(future);
+        }
+    }
 }
 
 /// Wrapper for handle_connection that logs start/end
@@ -204,5 +246,9 @@ async fn handle_connection_wrapper(stream: TcpStream, id: usize) {
 // ============================================================================
 
 fn log(msg: &str) {
-    console::print(msg);
+    klog::log(Level::Info, "ssh_server", msg);
+}
+
+fn warn(msg: &str) {
+    klog::log(Level::Warn, "ssh_server", msg);
 }
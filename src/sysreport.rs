@@ -0,0 +1,241 @@
+//! Diagnostics Bundle
+//!
+//! `sysreport` gathers what a bug report needs - version and uptime, the
+//! configuration with credentials redacted, thread, heap, IRQ and network
+//! statistics, recent kernel messages and the last crash report - into one
+//! ustar archive in `/tmp`, so every report comes with the same files.
+//!
+//! The archive is plain POSIX tar: `tar xf sysreport.tar` unpacks it on
+//! the host. Members are generated in memory one after another; nothing is
+//! kept once the archive is written.
+
+use crate::error::KResult;
+use crate::vfs;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// Where the archive is written (replacing the previous one)
+pub const REPORT_PATH: &str = "/tmp/sysreport.tar";
+
+/// Kernel messages included, from the end of the console log
+const MAX_DMESG: usize = 64 * 1024;
+
+/// Tar block size
+const BLOCK: usize = 512;
+
+/// Shown in place of secret settings
+const REDACTED: &str = "<redacted>";
+
+// ============================================================================
+// Tar Writer
+// ============================================================================
+
+/// Write `value` as a NUL-terminated octal field filling `field`
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let mut v = value;
+    for i in (0..digits).rev() {
+        field[i] = b'0' + (v & 7) as u8;
+        v >>= 3;
+    }
+    field[digits] = 0;
+}
+
+/// Append one regular file to a ustar archive
+fn append_member(archive: &mut Vec<u8>, name: &str, data: &[u8], mtime: u64) {
+    let mut header = [0u8; BLOCK];
+    let name = &name.as_bytes()[..name.len().min(99)];
+    header[..name.len()].copy_from_slice(name);
+    put_octal(&mut header[100..108], 0o644); // mode
+    put_octal(&mut header[108..116], 0); // uid
+    put_octal(&mut header[116..124], 0); // gid
+    put_octal(&mut header[124..136], data.len() as u64);
+    put_octal(&mut header[136..148], mtime);
+    header[156] = b'0'; // regular file
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field as spaces
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|&b| b as u32).sum();
+    put_octal(&mut header[148..155], sum as u64);
+    header[155] = b' ';
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    let pad = (BLOCK - data.len() % BLOCK) % BLOCK;
+    archive.resize(archive.len() + pad, 0);
+}
+
+/// Close an archive with the two empty blocks tar expects
+fn finish(archive: &mut Vec<u8>) {
+    archive.resize(archive.len() + 2 * BLOCK, 0);
+}
+
+// ============================================================================
+// Members
+// ============================================================================
+
+fn version_txt() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "kernel akuma {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(out, "uptime_us {}", crate::timer::uptime_us());
+    let _ = writeln!(out, "utc {}", crate::timer::utc_iso8601_simple());
+    let _ = writeln!(out, "max_threads {}", crate::threading::max_threads());
+    out
+}
+
+fn config_txt() -> String {
+    let mut out = String::new();
+    for (key, value) in crate::config::entries() {
+        let value = if crate::config::is_secret(&key) {
+            REDACTED
+        } else {
+            value.as_str()
+        };
+        let _ = writeln!(out, "{}={}", key, value);
+    }
+    out
+}
+
+fn threads_txt() -> String {
+    use crate::threading;
+    let (ready, running, terminated) = threading::thread_stats();
+    let mut out = String::new();
+    let _ = writeln!(out, "threads {}/{}", threading::thread_count(), threading::max_threads());
+    let _ = writeln!(out, "policy {}", threading::policy_name());
+    let _ = writeln!(out, "ready {}\nrunning {}\nterminated {}", ready, running, terminated);
+    let _ = writeln!(out, "sleeping {}", threading::sleeping_count());
+    let _ = writeln!(out, "blocked {}", threading::blocked_count());
+    let _ = writeln!(out, "mutex_contentions {}", threading::mutex_contentions());
+    let _ = writeln!(out, "priority_boosts {}", threading::priority_boosts());
+    if crate::lockdep::ENABLED {
+        let lockdep = crate::lockdep::stats();
+        let _ = writeln!(
+            out,
+            "lockdep classes {} dependencies {} untracked {}",
+            lockdep.classes, lockdep.dependencies, lockdep.untracked
+        );
+    }
+    let _ = writeln!(out, "tick_us {}", crate::timer::tick_interval_us());
+    let _ = writeln!(out, "slice_us {}", threading::slice_us());
+    for band in 0..threading::PRIORITY_BANDS {
+        let priority = (band * 256 / threading::PRIORITY_BANDS) as u8;
+        let _ = writeln!(out, "slice_us.{} {}", priority, threading::priority_slice_us(priority));
+    }
+    let _ = writeln!(out, "coalesced_ticks {}", threading::coalesced_ticks());
+    let _ = writeln!(out, "coop_timeouts {}", threading::cooperative_timeouts());
+    let _ = writeln!(out, "starvation_events {}", threading::starvation_events());
+    let _ = writeln!(out, "stack_overflows {}", threading::stack_overflows());
+    let _ = writeln!(out, "deadline_overruns {}", threading::deadline_overruns());
+    let workers = crate::workers::stats();
+    let _ = writeln!(
+        out,
+        "workers {} queued {} completed {} inline {} queue_full {}",
+        workers.workers, workers.queued, workers.completed, workers.inline, workers.queue_full
+    );
+    let wq = crate::workqueue::stats();
+    let _ = writeln!(
+        out,
+        "workqueue {} pending {} delayed {} queued {} completed {} cancelled {} rejected {}",
+        wq.workers, wq.pending, wq.delayed, wq.queued, wq.completed, wq.cancelled, wq.rejected
+    );
+    threading::for_each_thread(|t| {
+        let stack = match t.stack {
+            Some((base, top)) => alloc::format!("{:#x}-{:#x}", base, top),
+            None => String::from("boot"),
+        };
+        let used = match threading::stack_high_water(t.tid) {
+            Some((used, size)) => alloc::format!("{}/{}", used, size),
+            None => String::from("-"),
+        };
+        let name = if t.name.is_empty() { "-" } else { t.name };
+        let _ = writeln!(
+            out,
+            "tid {} {} {} {} prio {} stack {} used {} cpu_us {}",
+            t.tid,
+            name,
+            t.state.as_str(),
+            t.class.as_str(),
+            t.priority,
+            stack,
+            used,
+            t.cpu_time_us
+        );
+    });
+    out
+}
+
+fn irqs_txt() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "spurious {}", crate::irq::spurious_count());
+    let _ = writeln!(out, "nested {}", crate::irq::nested_count());
+    for (irq, st) in crate::irq::all_stats() {
+        let _ = writeln!(
+            out,
+            "irq {} count {} total_ns {} max_ns {} unhandled {}",
+            irq, st.count, st.total_ns, st.max_ns, st.unhandled
+        );
+    }
+    out
+}
+
+/// Last `MAX_DMESG` bytes of the console log, starting at a line
+fn dmesg_txt() -> Vec<u8> {
+    let mut log = crate::dmesg::read_all();
+    if log.len() > MAX_DMESG {
+        let cut = log.len() - MAX_DMESG;
+        let start = log[cut..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(cut, |nl| cut + nl + 1);
+        log.drain(..start);
+    }
+    log
+}
+
+// ============================================================================
+// Report
+// ============================================================================
+
+/// Build the archive in memory; returns it and the number of members
+pub fn generate() -> (Vec<u8>, usize) {
+    let mtime = crate::timer::utc_time_us().unwrap_or(0) / 1_000_000;
+    let mut archive = Vec::new();
+
+    let generated: [(&str, Vec<u8>); 5] = [
+        ("sysreport/version.txt", version_txt().into_bytes()),
+        ("sysreport/config.txt", config_txt().into_bytes()),
+        ("sysreport/threads.txt", threads_txt().into_bytes()),
+        ("sysreport/irqs.txt", irqs_txt().into_bytes()),
+        ("sysreport/dmesg.txt", dmesg_txt()),
+    ];
+    for (name, data) in &generated {
+        append_member(&mut archive, name, data, mtime);
+    }
+    let mut members = generated.len();
+
+    // The statistics files the kernel filesystem already serves
+    for (name, path) in [
+        ("sysreport/meminfo.txt", "/meminfo.txt"),
+        ("sysreport/netstats.txt", "/netstats.txt"),
+        ("sysreport/connbudget.txt", "/connbudget.txt"),
+        ("sysreport/crashdump.txt", "/crashdump.txt"),
+    ] {
+        if let Ok(data) = vfs::read_file(path) {
+            append_member(&mut archive, name, &data, mtime);
+            members += 1;
+        }
+    }
+
+    finish(&mut archive);
+    (archive, members)
+}
+
+/// Write a fresh report to `REPORT_PATH`; returns its size and member count
+pub fn write() -> KResult<(usize, usize)> {
+    let (archive, members) = generate();
+    vfs::write_file(REPORT_PATH, &archive)?;
+    Ok((archive.len(), members))
+}
@@ -3,14 +3,28 @@
 //! Run with `tests::run_all()` after scheduler initialization.
 //! If tests fail, the kernel should halt.
 
+use crate::allocator;
+use crate::conn_budget::{self, HeapState};
 use crate::console;
+use crate::defrag::{self, Hint};
+use crate::handles;
+use crate::klog::{self, Filter, Level};
+use crate::kobject::{self, KObjType};
+use crate::latency;
+use crate::mmu;
+use crate::network;
+use crate::pmm;
+use crate::sched;
 use crate::threading;
+use crate::virtio_hal;
+use crate::vmm;
 use alloc::boxed::Box;
 use alloc::collections::VecDeque;
 use alloc::format;
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
 /// Run all system tests - returns true if all pass
 pub fn run_all() -> bool {
@@ -38,6 +52,22 @@ pub fn run_all() -> bool {
     all_pass &= test_string_operations();
     all_pass &= test_vec_of_vecs();
     all_pass &= test_adjacent_allocations();
+    all_pass &= test_heap_stats();
+    all_pass &= test_slab_cache();
+    all_pass &= test_page_allocator();
+    all_pass &= test_page_allocator_unaligned();
+    all_pass &= test_allocation_tracking();
+    all_pass &= test_wx_mappings();
+    all_pass &= test_dma_pool();
+    all_pass &= test_region_manager();
+    all_pass &= test_aligned_alloc();
+    all_pass &= test_secret_wipe();
+    all_pass &= test_kobject_tree();
+    all_pass &= test_size_classes();
+    all_pass &= test_alloc_profiler();
+    all_pass &= test_heap_scrubber();
+    all_pass &= test_shared_memory();
+    all_pass &= test_defrag_hints();
 
     // Common memory allocation patterns
     // NOTE: These tests hang during preemption - need investigation
@@ -50,6 +80,8 @@ pub fn run_all() -> bool {
 
     // Threading tests
     all_pass &= test_scheduler_init();
+    all_pass &= test_double_init();
+    all_pass &= test_driver_hooks();
     all_pass &= test_thread_stats();
     all_pass &= test_yield();
     all_pass &= test_cooperative_timeout();
@@ -62,6 +94,88 @@ pub fn run_all() -> bool {
     all_pass &= test_spawn_cooperative();
     all_pass &= test_yield_cycle();
     all_pass &= test_mixed_cooperative_preemptible();
+    all_pass &= test_starvation_detector();
+    all_pass &= test_cooperative_timeout_kill();
+    all_pass &= test_handle_cleanup_on_exit();
+    all_pass &= test_handle_cleanup_on_task_exit();
+    all_pass &= test_no_preempt_scope();
+    all_pass &= test_latency_histogram();
+    all_pass &= test_cycle_counter();
+    all_pass &= test_stack_canary();
+    all_pass &= test_with_deadline();
+    all_pass &= test_background_class();
+    all_pass &= test_sched_policy();
+    all_pass &= test_join_handle();
+    all_pass &= test_sleep();
+    all_pass &= test_tick_coalescing();
+    all_pass &= test_set_preemptible();
+    all_pass &= test_thread_priority();
+    all_pass &= test_blocking_mutex();
+    all_pass &= test_condvar();
+    all_pass &= test_worker_offload();
+    all_pass &= test_thread_names();
+    all_pass &= test_thread_exit();
+    all_pass &= test_thread_stack_size();
+    all_pass &= test_stack_high_water();
+    all_pass &= test_thread_local();
+    all_pass &= test_park_unpark();
+    all_pass &= test_idle_unless();
+    all_pass &= test_sched_tunables();
+    all_pass &= test_cpu_time();
+    all_pass &= test_priority_inheritance();
+    all_pass &= test_thread_kill();
+    all_pass &= test_lockup_backtrace();
+    all_pass &= test_lockdep_order();
+    all_pass &= test_workqueue();
+    all_pass &= test_barrier();
+    all_pass &= test_deadline_class();
+
+    // Network accounting
+    all_pass &= test_bandwidth_cap();
+    all_pass &= test_egress_shaper();
+    all_pass &= test_http_range_parsing();
+    all_pass &= test_scatter_gather();
+    all_pass &= test_conn_budget();
+
+    // TLS client
+    all_pass &= test_tls_client();
+    all_pass &= test_x509_chain();
+
+    // Logging
+    all_pass &= test_log_sinks();
+    all_pass &= test_irq_log_staging();
+    all_pass &= test_log_timestamps();
+    all_pass &= test_utc_format_cache();
+    all_pass &= test_dmesg_ring();
+    all_pass &= test_event_catalog();
+
+    // Authentication
+    all_pass &= test_config_auth();
+    all_pass &= test_netboot_parsing();
+    all_pass &= test_ctl_protocol();
+    all_pass &= test_sysreport();
+
+    // Console
+    all_pass &= test_line_editor();
+    all_pass &= test_console_mux();
+    all_pass &= test_console_read();
+    all_pass &= test_console_raw_mode();
+    all_pass &= test_uart_config();
+    all_pass &= test_uart_ports();
+
+    // Exceptions
+    all_pass &= test_esr_decoding();
+    all_pass &= test_fault_report();
+    all_pass &= test_unexpected_vectors();
+    all_pass &= test_backtrace();
+    all_pass &= test_panic_policy();
+    all_pass &= test_nested_irqs();
+    all_pass &= test_irq_stats();
+    all_pass &= test_irq_closure();
+    all_pass &= test_softirq();
+    all_pass &= test_mmio_probe();
+    all_pass &= test_irq_affinity();
+    all_pass &= test_fiq();
 
     console::print("\n==================================\n");
     console::print(&format!(
@@ -783,6 +897,700 @@ fn test_adjacent_allocations() -> bool {
     ok
 }
 
+/// Test: Heap statistics track allocations and frees
+fn test_heap_stats() -> bool {
+    console::print("\n[TEST] Heap statistics\n");
+
+    const SIZE: usize = 64 * 1024;
+
+    let before = allocator::stats();
+    console::print(&format!(
+        "  Before: used={} live={} largest_free={}\n",
+        before.used, before.allocations, before.largest_free
+    ));
+
+    let buf: Vec<u8> = vec![0u8; SIZE];
+    let during = allocator::stats();
+    console::print(&format!(
+        "  During: used={} live={} peak={}\n",
+        during.used, during.allocations, during.peak
+    ));
+
+    drop(buf);
+    let after = allocator::stats();
+    console::print(&format!("  After: used={} live={}\n", after.used, after.allocations));
+
+    let grew = during.used >= before.used + SIZE && during.allocations > before.allocations;
+    let peak_ok = during.peak >= during.used;
+    let shrank = after.used + SIZE <= during.used;
+    let free_ok = before.largest_free > SIZE && before.largest_free <= before.free();
+
+    let ok = grew && peak_ok && shrank && free_ok;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static TEST_SLAB: crate::slab::SlabCache = crate::slab::SlabCache::new("test-slab", 100, 16);
+
+/// Test: Slab cache hands out distinct aligned objects and reuses freed ones
+fn test_slab_cache() -> bool {
+    console::print("\n[TEST] Slab cache\n");
+
+    const COUNT: usize = 200; // More than one slab's worth
+
+    let mut objects = Vec::new();
+    for _ in 0..COUNT {
+        match TEST_SLAB.alloc() {
+            Some(ptr) => objects.push(ptr),
+            None => {
+                console::print("  Allocation failed\n");
+                console::print("  Result: FAIL\n");
+                return false;
+            }
+        }
+    }
+
+    let size = TEST_SLAB.object_size();
+    let aligned = objects.iter().all(|p| (p.as_ptr() as usize).is_multiple_of(16));
+    let mut addrs: Vec<usize> = objects.iter().map(|p| p.as_ptr() as usize).collect();
+    addrs.sort_unstable();
+    let distinct = addrs.windows(2).all(|w| w[1] - w[0] >= size);
+
+    let stats = TEST_SLAB.stats();
+    console::print(&format!(
+        "  Object size: {}, slabs: {}, in use: {}\n",
+        size, stats.slabs, stats.in_use
+    ));
+
+    let last = objects[COUNT - 1];
+    for &ptr in &objects {
+        unsafe { TEST_SLAB.free(ptr) };
+    }
+
+    // LIFO free list: the most recently freed object comes back first
+    let reused = match TEST_SLAB.alloc() {
+        Some(ptr) => {
+            let same = ptr == last;
+            unsafe { TEST_SLAB.free(ptr) };
+            same
+        }
+        None => false,
+    };
+
+    let after = TEST_SLAB.stats();
+    console::print(&format!(
+        "  Aligned: {}, distinct: {}, reused: {}, in use after free: {}\n",
+        aligned, distinct, reused, after.in_use
+    ));
+
+    let ok = aligned && distinct && reused && after.in_use == 0 && stats.slabs > 1;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Page allocator returns size-aligned blocks and merges buddies on free
+fn test_page_allocator() -> bool {
+    console::print("\n[TEST] Page allocator\n");
+
+    let before = pmm::stats().free_pages;
+
+    let small = pmm::alloc_pages(0);
+    let large = pmm::alloc_pages(4); // 64 KB
+    let (small, large) = match (small, large) {
+        (Some(s), Some(l)) => (s, l),
+        _ => {
+            console::print("  Allocation failed\n");
+            console::print("  Result: FAIL\n");
+            return false;
+        }
+    };
+
+    let aligned = small % pmm::PAGE_SIZE == 0 && large % (pmm::PAGE_SIZE << 4) == 0;
+    let disjoint = small + pmm::PAGE_SIZE <= large || large + (pmm::PAGE_SIZE << 4) <= small;
+
+    // Pages must be writable end to end
+    unsafe {
+        core::ptr::write_bytes(large as *mut u8, 0xA5, pmm::PAGE_SIZE << 4);
+    }
+    let writable = unsafe { *((large + (pmm::PAGE_SIZE << 4) - 1) as *const u8) } == 0xA5;
+
+    let during = pmm::stats().free_pages;
+    unsafe {
+        pmm::free_pages(small, 0);
+        pmm::free_pages(large, 4);
+    }
+    let after = pmm::stats().free_pages;
+
+    console::print(&format!(
+        "  Free pages: {} -> {} -> {}\n",
+        before, during, after
+    ));
+    console::print(&format!(
+        "  Aligned: {}, disjoint: {}, writable: {}\n",
+        aligned, disjoint, writable
+    ));
+
+    let ok = aligned && disjoint && writable && during == before - 17 && after == before;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: A page allocator over an unaligned range stays inside that range
+fn test_page_allocator_unaligned() -> bool {
+    console::print("\n[TEST] Page allocator unaligned base\n");
+
+    // Scratch region: 8 pages, handed over starting 100 bytes in
+    let block = match pmm::alloc_pages(3) {
+        Some(b) => b,
+        None => {
+            console::print("  Allocation failed\n");
+            console::print("  Result: FAIL\n");
+            return false;
+        }
+    };
+    let base = block + 100;
+    let size = (pmm::PAGE_SIZE << 3) - 100;
+
+    let mut scratch = pmm::BuddyAllocator::new();
+    let init = scratch.init(base, size);
+    let stats = scratch.stats();
+
+    // Every page handed out must end inside [base, base + size)
+    let mut inside = true;
+    let mut handed = 0;
+    while let Some(page) = scratch.alloc(0) {
+        inside &= page >= base && page + pmm::PAGE_SIZE <= base + size;
+        handed += 1;
+    }
+
+    unsafe { pmm::free_pages(block, 3) };
+
+    console::print(&format!(
+        "  Pages: {} total, {} metadata, {} handed out\n",
+        stats.total_pages, stats.metadata_pages, handed
+    ));
+
+    // Rounding up skips the rest of the first page, leaving 7
+    let ok = init.is_ok()
+        && stats.total_pages == 7
+        && handed == stats.total_pages - stats.metadata_pages
+        && inside;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Kernel sections are mapped W^X
+fn test_wx_mappings() -> bool {
+    console::print("\n[TEST] W^X mappings\n");
+
+    if !mmu::enabled() {
+        console::print("  MMU not enabled\n");
+        console::print("  Result: FAIL\n");
+        return false;
+    }
+
+    static CONSTANT: [u8; 16] = [0x5A; 16];
+    let code = test_wx_mappings as *const () as usize;
+    let rodata = CONSTANT.as_ptr() as usize;
+    let local = 0u64;
+    let stack = &local as *const u64 as usize;
+    let heap = Box::new(0u64);
+    let heap_addr = &*heap as *const u64 as usize;
+
+    let code_perm = mmu::permissions(code);
+    let rodata_perm = mmu::permissions(rodata);
+    let stack_perm = mmu::permissions(stack);
+    let heap_perm = mmu::permissions(heap_addr);
+
+    let show = |p: Option<mmu::Perm>| p.map(|p| p.as_str()).unwrap_or("unmapped");
+    console::print(&format!(
+        "  .text {}, .rodata {}, stack {}, heap {}\n",
+        show(code_perm),
+        show(rodata_perm),
+        show(stack_perm),
+        show(heap_perm)
+    ));
+
+    let code_ro = !mmu::can_write(code);
+    let rodata_ro = !mmu::can_write(rodata);
+    let stack_rw = mmu::can_write(stack);
+    let heap_rw = mmu::can_write(heap_addr);
+    console::print(&format!(
+        "  Hardware: .text ro {}, .rodata ro {}, stack rw {}, heap rw {}\n",
+        code_ro, rodata_ro, stack_rw, heap_rw
+    ));
+
+    let ok = code_perm == Some(mmu::Perm::ReadExecute)
+        && rodata_perm == Some(mmu::Perm::ReadOnly)
+        && stack_perm == Some(mmu::Perm::ReadWrite)
+        && heap_perm == Some(mmu::Perm::ReadWrite)
+        && code_ro
+        && rodata_ro
+        && stack_rw
+        && heap_rw;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: DMA buffers come from the non-cacheable pool and go back to it
+fn test_dma_pool() -> bool {
+    console::print("\n[TEST] DMA pool\n");
+
+    let before = pmm::dma_stats().free_pages;
+    let Some((paddr, vaddr)) = virtio_hal::dma_alloc_coherent(3 * pmm::PAGE_SIZE, 4096) else {
+        console::print("  dma_alloc_coherent failed\n");
+        console::print("  Result: FAIL\n");
+        return false;
+    };
+    let during = pmm::dma_stats().free_pages;
+
+    let heap = Box::new(0u64);
+    let heap_addr = &*heap as *const u64 as usize;
+    let dma_cached = mmu::cacheable(paddr);
+    let heap_cached = mmu::cacheable(heap_addr);
+    let writable = mmu::can_write(paddr);
+    let zeroed = unsafe { core::slice::from_raw_parts(vaddr.as_ptr(), 3 * pmm::PAGE_SIZE) }
+        .iter()
+        .all(|&b| b == 0);
+    console::print(&format!(
+        "  {:#x}: cacheable {:?} (heap {:?}), writable {}, zeroed {}\n",
+        paddr, dma_cached, heap_cached, writable, zeroed
+    ));
+
+    unsafe { virtio_hal::dma_free_coherent(vaddr, 3 * pmm::PAGE_SIZE, 4096) };
+    let after = pmm::dma_stats().free_pages;
+    console::print(&format!("  Free pool pages: {} -> {} -> {}\n", before, during, after));
+
+    // With the MMU off all RAM is non-cacheable, and the heap check is moot
+    let attrs_ok = if mmu::enabled() {
+        dma_cached == Some(false) && heap_cached == Some(true)
+    } else {
+        dma_cached.is_none()
+    };
+    let ok = attrs_ok && writable && zeroed && during == before - 4 && after == before;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Region manager hands out memory, refuses overlaps and frees on unmap
+fn test_region_manager() -> bool {
+    console::print("\n[TEST] Region manager\n");
+
+    let pages_before = pmm::stats().free_pages;
+
+    let anon = match vmm::map_anonymous(10_000, mmu::Perm::ReadWrite, "test-anon") {
+        Ok(r) => r,
+        Err(e) => {
+            console::print(&format!("  map_anonymous failed: {}\n", e));
+            console::print("  Result: FAIL\n");
+            return false;
+        }
+    };
+    let stack = match vmm::alloc_stack(16 * 1024, "test-stack") {
+        Ok(r) => r,
+        Err(e) => {
+            console::print(&format!("  alloc_stack failed: {}\n", e));
+            let _ = vmm::unmap(anon.id);
+            console::print("  Result: FAIL\n");
+            return false;
+        }
+    };
+
+    let zeroed = unsafe { core::slice::from_raw_parts(anon.base as *const u8, anon.size) }
+        .iter()
+        .all(|&b| b == 0);
+    let found = vmm::find(anon.base + 100).map(|r| r.id) == Some(anon.id);
+    let guard = vmm::find(stack.base - 1).map(|r| r.kind) == Some(vmm::RegionKind::Guard);
+    let kernel = vmm::find(test_region_manager as *const () as usize).map(|r| r.kind)
+        == Some(vmm::RegionKind::Kernel);
+    let overlap_refused = vmm::reserve(
+        anon.base,
+        pmm::PAGE_SIZE,
+        vmm::RegionKind::Fixed,
+        None,
+        "test-overlap",
+    )
+    .is_err();
+
+    console::print(&format!(
+        "  anon {:#x}+{:#x}, stack {:#x}+{:#x}\n",
+        anon.base, anon.size, stack.base, stack.size
+    ));
+
+    let unmapped = vmm::unmap(anon.id).is_ok() && vmm::unmap(stack.id).is_ok();
+    let guard_gone = vmm::find(stack.base - 1).is_none();
+    let pages_after = pmm::stats().free_pages;
+
+    console::print(&format!(
+        "  Zeroed: {}, found: {}, guard: {}, kernel: {}, overlap refused: {}\n",
+        zeroed, found, guard, kernel, overlap_refused
+    ));
+    console::print(&format!(
+        "  Unmapped: {}, guard gone: {}, free pages {} -> {}\n",
+        unmapped, guard_gone, pages_before, pages_after
+    ));
+
+    let ok = zeroed
+        && found
+        && guard
+        && kernel
+        && overlap_refused
+        && unmapped
+        && guard_gone
+        && pages_after == pages_before;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Aligned buffers and rings honour their alignment and are initialized
+fn test_aligned_alloc() -> bool {
+    console::print("\n[TEST] Aligned allocation\n");
+
+    let mut ok = true;
+    for &align in &[64usize, 256, 4096] {
+        match allocator::alloc_aligned(3000, align) {
+            Ok(mut buf) => {
+                let aligned = (buf.as_ptr() as usize).is_multiple_of(align);
+                let zeroed = buf.iter().all(|&b| b == 0);
+                buf[2999] = 0xAB;
+                console::print(&format!(
+                    "  align {}: addr {:#x}, aligned: {}, zeroed: {}\n",
+                    align,
+                    buf.as_ptr() as usize,
+                    aligned,
+                    zeroed
+                ));
+                ok &= aligned && zeroed && buf.len() == 3000 && buf[2999] == 0xAB;
+            }
+            Err(e) => {
+                console::print(&format!("  align {}: {}\n", align, e));
+                ok = false;
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Default, PartialEq)]
+    struct Desc {
+        addr: u64,
+        len: u32,
+        flags: u16,
+        next: u16,
+    }
+
+    let ring = allocator::alloc_ring::<Desc>(16, 16);
+    let ring_ok = match &ring {
+        Ok(r) => {
+            r.len() == 16 && (r.as_ptr() as usize).is_multiple_of(16) && r.iter().all(|d| *d == Desc::default())
+        }
+        Err(_) => false,
+    };
+    let odd_refused = allocator::alloc_ring::<Desc>(12, 16).is_err();
+    console::print(&format!(
+        "  Ring of 16: {}, size 12 refused: {}\n",
+        ring_ok, odd_refused
+    ));
+
+    ok &= ring_ok && odd_refused;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: SecretBox wipes on drop; zero-on-free wipes ordinary blocks
+///
+/// Freed memory is peeked at with volatile reads; only the middle of each
+/// block is checked since the allocator keeps free-list metadata at the edges.
+fn test_secret_wipe() -> bool {
+    console::print("\n[TEST] Secret wipe\n");
+
+    let peek = |addr: usize, range: core::ops::Range<usize>| -> bool {
+        range.into_iter().all(|i| unsafe { core::ptr::read_volatile((addr + i) as *const u8) } == 0)
+    };
+
+    let was_enabled = allocator::zero_on_free();
+    allocator::set_zero_on_free(false);
+
+    let secret = crate::secret::SecretBox::new([0xC5u8; 64]);
+    let secret_addr = secret.as_ptr() as usize;
+    drop(secret);
+    let secret_wiped = peek(secret_addr, 16..48);
+
+    allocator::set_zero_on_free(true);
+    let plain = Box::new([0x3Cu8; 256]);
+    let plain_addr = plain.as_ptr() as usize;
+    drop(plain);
+    let plain_wiped = peek(plain_addr, 64..192);
+    allocator::set_zero_on_free(was_enabled);
+
+    let mut bytes = crate::secret::SecretBytes::from(&[0xAAu8; 40][..]);
+    bytes.truncate(8);
+    let truncated = bytes.len() == 8 && bytes.iter().all(|&b| b == 0xAA);
+
+    console::print(&format!(
+        "  SecretBox wiped: {}, zero-on-free wiped: {}, truncate: {}\n",
+        secret_wiped, plain_wiped, truncated
+    ));
+
+    let ok = secret_wiped && plain_wiped && truncated;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Kernel objects list their parents and refcounts and vanish when dropped
+fn test_kobject_tree() -> bool {
+    console::print("\n[TEST] Kernel object tree\n");
+
+    let (parent, child) = match kobject::create(KObjType::Device, "test-dev", None)
+        .and_then(|p| kobject::create(KObjType::Socket, "test-sock", Some(&p)).map(|c| (p, c)))
+    {
+        Ok(pair) => pair,
+        Err(e) => {
+            console::print(&format!("  Create failed: {}\n", e));
+            console::print("  Result: FAIL\n");
+            return false;
+        }
+    };
+    let extra = child.clone();
+
+    let objects = kobject::list();
+    let p_info = objects.iter().find(|o| o.id == parent.id());
+    let c_info = objects.iter().find(|o| o.id == child.id());
+    // Parent: our Arc + the child's; child: two Arcs
+    let refs_ok = p_info.is_some_and(|p| p.refs == 2) && c_info.is_some_and(|c| c.refs == 2);
+    let linked = c_info.is_some_and(|c| c.parent == Some(parent.id()));
+    let tree = kobject::tree("\n");
+    let nested = tree.contains("device") && tree.contains("  socket");
+    let found = kobject::find(KObjType::Socket, "test-sock").is_some_and(|o| o.id() == child.id());
+
+    let ids = (parent.id(), child.id());
+    drop(extra);
+    drop(child);
+    drop(parent);
+    let gone = !kobject::list().iter().any(|o| o.id == ids.0 || o.id == ids.1);
+
+    console::print(&format!(
+        "  refs ok: {}, linked: {}, nested: {}, found: {}, gone after drop: {}\n",
+        refs_ok, linked, nested, found, gone
+    ));
+
+    let ok = refs_ok && linked && nested && found && gone;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Small allocations are served and reused by size classes; the
+/// fragmentation report is consistent
+fn test_size_classes() -> bool {
+    console::print("\n[TEST] Size classes and fragmentation\n");
+
+    let a = Box::new([0u8; 48]);
+    let a_addr = a.as_ptr() as usize;
+    let aligned = a_addr.is_multiple_of(64);
+    drop(a);
+    let b = Box::new([1u8; 48]);
+    let reused = b.as_ptr() as usize == a_addr;
+    drop(b);
+
+    let report = allocator::fragmentation_report();
+    let class = report.classes.iter().find(|c| c.size == 64);
+    let class_ok = class.is_some_and(|c| c.pages > 0);
+    let counted: usize = report.histogram.iter().sum();
+    let report_ok = report.blocks > 0
+        && counted == report.blocks
+        && report.largest <= report.free_bytes
+        && report.fragmentation_percent() <= 100;
+
+    console::print(&format!(
+        "  64-byte class: aligned {}, reused {}, pages present {}\n",
+        aligned, reused, class_ok
+    ));
+    console::print(&format!(
+        "  Free blocks: {}, {} KB free, largest {} KB, {}% fragmented\n",
+        report.blocks,
+        report.free_bytes / 1024,
+        report.largest / 1024,
+        report.fragmentation_percent()
+    ));
+
+    let ok = aligned && reused && class_ok && report_ok;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: The burst profiler buckets allocations under the current tag and
+/// counts packets only while its window is open
+fn test_alloc_profiler() -> bool {
+    console::print("\n[TEST] Allocation burst profiler\n");
+
+    use crate::error::Subsystem;
+
+    let buckets = allocator::profile_bucket(0) == 0
+        && allocator::profile_bucket(16) == 0
+        && allocator::profile_bucket(17) == 1
+        && allocator::profile_bucket(65536) == 12
+        && allocator::profile_bucket(65537) == allocator::PROFILE_BUCKETS - 1;
+
+    allocator::start_profile(5_000_000);
+    let boxed = {
+        let _tag = allocator::tag_scope(Subsystem::Device);
+        allocator::profile_packet();
+        allocator::profile_packet();
+        Box::new([0u8; 100])
+    };
+    let report = allocator::profile_report();
+    allocator::stop_profile();
+    allocator::profile_packet();
+    drop(boxed);
+
+    // Bucket 3 holds 65..=128 bytes
+    let tagged = report
+        .tags
+        .iter()
+        .find(|t| t.subsystem == Subsystem::Device)
+        .is_some_and(|t| t.allocs == 1 && t.bytes == 100 && t.histogram[3] == 1);
+    let packets = report.running && report.packets == 2 && report.allocs_per_packet_x100().is_some();
+    let stopped = {
+        let after = allocator::profile_report();
+        !after.running && after.packets == 2
+    };
+
+    console::print(&format!(
+        "  buckets: {}, tagged: {}, packets: {}, stopped: {}\n",
+        buckets, tagged, packets, stopped
+    ));
+
+    let ok = buckets && tagged && packets && stopped;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: The scrubber poisons free objects and notices one written after free
+fn test_heap_scrubber() -> bool {
+    console::print("\n[TEST] Idle heap scrubber\n");
+
+    // Step until a pass completes; problems are counted in the stats
+    fn run_pass() -> bool {
+        for _ in 0..1_000_000 {
+            if let Ok(true) = allocator::scrub_step() {
+                return true;
+            }
+        }
+        false
+    }
+
+    let pages_ok = pmm::validate().is_ok();
+
+    // Put some objects on the 128-byte free list
+    let boxes: Vec<Box<[u8; 128]>> = (0..32).map(|_| Box::new([0x11u8; 128])).collect();
+    drop(boxes);
+
+    let before = allocator::scrub_stats();
+    let (clean, damage_found, repoisoned) = sched::no_preempt(|| {
+        let first = run_pass();
+        let second = run_pass();
+        let clean = first && second && allocator::scrub_stats().corruptions == before.corruptions;
+
+        // Free an object, let it be poisoned, then scribble on it
+        let layout = core::alloc::Layout::new::<[u8; 128]>();
+        // SAFETY: plain allocation, written only while freed - which is the
+        // corruption the scrubber is meant to catch; preemption is off so
+        // nothing else can be handed the object meanwhile
+        let damage_found = unsafe {
+            let ptr = alloc::alloc::alloc(layout);
+            alloc::alloc::dealloc(ptr, layout);
+            run_pass();
+            let seen = allocator::scrub_stats().corruptions;
+            core::ptr::write_volatile(ptr.add(64), 0x42);
+            run_pass();
+            allocator::scrub_stats().corruptions == seen + 1
+                && allocator::scrub_stats().last_bad_addr == ptr as usize
+        };
+        // Re-poisoned, so the next pass is clean again
+        let seen = allocator::scrub_stats().corruptions;
+        run_pass();
+        (clean, damage_found, allocator::scrub_stats().corruptions == seen)
+    });
+
+    let after = allocator::scrub_stats();
+    console::print(&format!(
+        "  Passes: {}, poisoned: {}, verified: {}, corruptions: {}\n",
+        after.passes - before.passes,
+        after.poisoned - before.poisoned,
+        after.verified - before.verified,
+        after.corruptions - before.corruptions
+    ));
+    console::print(&format!(
+        "  Pages valid: {}, clean passes: {}, write after free caught: {}, re-poisoned: {}\n",
+        pages_ok, clean, damage_found, repoisoned
+    ));
+
+    let ok = pages_ok
+        && clean
+        && damage_found
+        && repoisoned
+        && after.poisoned > before.poisoned
+        && after.verified > before.verified;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Allocation tracking lists live allocations since a checkpoint
+fn test_allocation_tracking() -> bool {
+    console::print("\n[TEST] Allocation tracking\n");
+
+    let was_enabled = allocator::tracking_enabled();
+    if let Err(e) = allocator::enable_tracking() {
+        console::print(&format!("  Enable failed: {}\n", e));
+        console::print("  Result: FAIL\n");
+        return false;
+    }
+
+    let mark = allocator::checkpoint();
+    let a: Box<[u8; 200]> = Box::new([0u8; 200]);
+    let b: Box<[u8; 333]> = Box::new([0u8; 333]);
+    let a_ptr = a.as_ptr() as usize;
+    let b_ptr = b.as_ptr() as usize;
+
+    let live = allocator::allocations_since(mark);
+    let found_a = live.iter().any(|t| t.ptr == a_ptr && t.size == 200);
+    let found_b = live.iter().any(|t| t.ptr == b_ptr && t.size == 333);
+    let has_caller = live.iter().any(|t| t.callers[0] != 0);
+    drop(live);
+
+    drop(a);
+
+    // Churn through more allocations than the table has slots: freed
+    // entries must be reclaimed, not pile up, for lookups to keep working
+    let (_, dropped_before) = allocator::tracking_stats();
+    for i in 0..10_000usize {
+        drop(core::hint::black_box(Box::new(i)));
+    }
+    let (_, dropped_after) = allocator::tracking_stats();
+
+    let after = allocator::allocations_since(mark);
+    let a_gone = !after.iter().any(|t| t.ptr == a_ptr);
+    let b_still = after.iter().any(|t| t.ptr == b_ptr);
+    drop(after);
+    drop(b);
+
+    if !was_enabled {
+        allocator::disable_tracking();
+    }
+
+    console::print(&format!(
+        "  Found: {}/{}, caller recorded: {}, freed removed: {}, live kept: {}, dropped in churn: {}\n",
+        found_a,
+        found_b,
+        has_caller,
+        a_gone,
+        b_still,
+        dropped_after - dropped_before
+    ));
+
+    let ok = found_a && found_b && has_caller && a_gone && b_still && dropped_after == dropped_before;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
 // ============================================================================
 // Common Memory Allocation Patterns
 // ============================================================================
@@ -1214,15 +2022,9 @@ fn test_spawn_thread() -> bool {
     let count_before = threading::thread_count();
     console::print(&format!("  Threads before: {}\n", count_before));
 
-    // Try to spawn - simple thread that just marks itself terminated immediately
+    // Try to spawn - simple thread that returns immediately
     console::print("  Spawning test thread...");
-    match threading::spawn_fn(|| {
-        threading::mark_current_terminated();
-        loop {
-            threading::yield_now();
-            unsafe { core::arch::asm!("wfi") };
-        }
-    }) {
+    match threading::spawn_fn(|| {}) {
         Ok(tid) => {
             console::print(&format!(" OK (tid={})\n", tid));
 
@@ -1252,11 +2054,6 @@ fn test_spawn_and_run() -> bool {
     console::print("  Spawning thread that sets flag...");
     match threading::spawn_fn(|| {
         set_test_flag(true);
-        threading::mark_current_terminated();
-        loop {
-            threading::yield_now();
-            unsafe { core::arch::asm!("wfi") };
-        }
     }) {
         Ok(tid) => {
             console::print(&format!(" OK (tid={})\n", tid));
@@ -1299,13 +2096,7 @@ fn test_spawn_and_cleanup() -> bool {
 
     // Spawn thread
     console::print("  Spawning...");
-    let _tid = match threading::spawn_fn(|| {
-        threading::mark_current_terminated();
-        loop {
-            threading::yield_now();
-            unsafe { core::arch::asm!("wfi") };
-        }
-    }) {
+    let _tid = match threading::spawn_fn(|| {}) {
         Ok(t) => {
             console::print(&format!(" tid={}\n", t));
             t
@@ -1379,11 +2170,6 @@ fn test_spawn_multiple() -> bool {
     for i in 0..NUM_THREADS {
         match threading::spawn_fn(|| {
             increment_counter();
-            threading::mark_current_terminated();
-            loop {
-                threading::yield_now();
-                unsafe { core::arch::asm!("wfi") };
-            }
         }) {
             Ok(_) => {}
             Err(e) => {
@@ -1460,11 +2246,6 @@ fn test_spawn_and_yield() -> bool {
             increment_yield_count();
             threading::yield_now();
         }
-        threading::mark_current_terminated();
-        loop {
-            threading::yield_now();
-            unsafe { core::arch::asm!("wfi") };
-        }
     }) {
         Ok(tid) => console::print(&format!(" tid={}\n", tid)),
         Err(e) => {
@@ -1500,11 +2281,6 @@ fn test_spawn_cooperative() -> bool {
     console::print("  Spawning cooperative thread...");
     match threading::spawn_fn_cooperative(|| {
         set_test_flag(true);
-        threading::mark_current_terminated();
-        loop {
-            threading::yield_now();
-            unsafe { core::arch::asm!("wfi") };
-        }
     }) {
         Ok(tid) => console::print(&format!(" tid={}\n", tid)),
         Err(e) => {
@@ -1573,11 +2349,6 @@ fn test_yield_cycle() -> bool {
             increment_yield_cycle();
             threading::yield_now();
         }
-        threading::mark_current_terminated();
-        loop {
-            threading::yield_now();
-            unsafe { core::arch::asm!("wfi") };
-        }
     }) {
         Ok(tid) => console::print(&format!(" tid={}\n", tid)),
         Err(e) => {
@@ -1660,19 +2431,14 @@ fn test_mixed_cooperative_preemptible() -> bool {
     // Spawn cooperative thread: yields for ~5ms total
     console::print("  Spawning cooperative thread (5ms)...");
     match threading::spawn_fn_cooperative(|| {
-        let start = crate::timer::uptime_us();
+        let stopwatch = crate::timer::Stopwatch::start();
         let target = 5_000; // 5ms
 
-        while crate::timer::uptime_us() - start < target {
+        while stopwatch.elapsed_us() < target {
             threading::yield_now();
         }
 
         set_coop_done(true);
-        threading::mark_current_terminated();
-        loop {
-            threading::yield_now();
-            unsafe { core::arch::asm!("wfi") };
-        }
     }) {
         Ok(tid) => console::print(&format!(" tid={}\n", tid)),
         Err(e) => {
@@ -1684,21 +2450,16 @@ fn test_mixed_cooperative_preemptible() -> bool {
     // Spawn preemptible thread: busy-loops for ~15ms
     console::print("  Spawning preemptible thread (15ms)...");
     match threading::spawn_fn(|| {
-        let start = crate::timer::uptime_us();
+        let stopwatch = crate::timer::Stopwatch::start();
         let target = 15_000; // 15ms
 
         // Busy loop - will be preempted by timer
-        while crate::timer::uptime_us() - start < target {
+        while stopwatch.elapsed_us() < target {
             // Just spin
             unsafe { core::arch::asm!("nop") };
         }
 
         set_preempt_done(true);
-        threading::mark_current_terminated();
-        loop {
-            threading::yield_now();
-            unsafe { core::arch::asm!("wfi") };
-        }
     }) {
         Ok(tid) => console::print(&format!(" tid={}\n", tid)),
         Err(e) => {
@@ -1712,16 +2473,14 @@ fn test_mixed_cooperative_preemptible() -> bool {
 
     // Wait for both to complete (max 30ms with some margin)
     console::print("  Waiting for threads to complete...");
-    let wait_start = crate::timer::uptime_us();
+    let wait = crate::timer::Stopwatch::start();
     let max_wait = 50_000; // 50ms max
 
-    while (!get_coop_done() || !get_preempt_done())
-        && (crate::timer::uptime_us() - wait_start < max_wait)
-    {
+    while (!get_coop_done() || !get_preempt_done()) && wait.elapsed_us() < max_wait {
         threading::yield_now();
     }
 
-    let elapsed = (crate::timer::uptime_us() - wait_start) / 1000;
+    let elapsed = wait.elapsed_ms();
     console::print(&format!(" {}ms\n", elapsed));
 
     // Check completion
@@ -1742,3 +2501,4072 @@ fn test_mixed_cooperative_preemptible() -> bool {
     console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
     ok
 }
+
+static STARVATION_HOG_DONE: AtomicBool = AtomicBool::new(false);
+
+/// Test: A cooperative thread hogging the CPU is reported as starving others
+fn test_starvation_detector() -> bool {
+    console::print("\n[TEST] Starvation detector\n");
+
+    let old_threshold = threading::starvation_threshold_us();
+    threading::set_starvation_threshold_us(50_000);
+    let events_before = threading::starvation_events();
+    STARVATION_HOG_DONE.store(false, Ordering::Release);
+
+    // Cooperative thread spins without yielding, so this thread stays Ready
+    match threading::spawn_fn_cooperative(|| {
+        let stopwatch = crate::timer::Stopwatch::start();
+        while stopwatch.elapsed_us() < 300_000 {
+            core::hint::spin_loop();
+        }
+        STARVATION_HOG_DONE.store(true, Ordering::Release);
+    }) {
+        Ok(tid) => console::print(&format!("  Spawned hog thread tid={}\n", tid)),
+        Err(e) => {
+            console::print(&format!("  Spawn failed: {}\n", e));
+            threading::set_starvation_threshold_us(old_threshold);
+            return false;
+        }
+    }
+
+    for _ in 0..1000 {
+        if STARVATION_HOG_DONE.load(Ordering::Acquire) {
+            break;
+        }
+        threading::yield_now();
+    }
+
+    let events = threading::starvation_events() - events_before;
+    threading::set_starvation_threshold_us(old_threshold);
+    threading::cleanup_terminated();
+
+    console::print(&format!("  Starvation reports: {}\n", events));
+
+    let ok = STARVATION_HOG_DONE.load(Ordering::Acquire) && events > 0;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static TIMEOUT_VICTIM_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Test: A cooperative thread with the Kill policy is terminated when it overruns
+fn test_cooperative_timeout_kill() -> bool {
+    console::print("\n[TEST] Cooperative timeout kill policy\n");
+
+    let old_timeout = threading::cooperative_timeout_us();
+    threading::set_cooperative_timeout_us(50_000);
+    let timeouts_before = threading::cooperative_timeouts();
+    let count_before = threading::thread_count();
+    TIMEOUT_VICTIM_STARTED.store(false, Ordering::Release);
+
+    // Never yields - only the timeout policy can get it off the CPU
+    let spawned = threading::spawn_fn_cooperative_with_policy(
+        || {
+            TIMEOUT_VICTIM_STARTED.store(true, Ordering::Release);
+            loop {
+                core::hint::spin_loop();
+            }
+        },
+        threading::TimeoutPolicy::Kill,
+    );
+    threading::set_cooperative_timeout_us(old_timeout);
+
+    match spawned {
+        Ok(tid) => console::print(&format!("  Spawned victim tid={}\n", tid)),
+        Err(e) => {
+            console::print(&format!("  Spawn failed: {}\n", e));
+            return false;
+        }
+    }
+
+    for _ in 0..1000 {
+        if threading::cooperative_timeouts() > timeouts_before {
+            break;
+        }
+        threading::yield_now();
+    }
+
+    let (_, _, terminated) = threading::thread_stats();
+    let cleaned = threading::cleanup_terminated();
+    let count_after = threading::thread_count();
+
+    console::print(&format!(
+        "  Started: {}, timeouts: {}, terminated: {}, cleaned: {}\n",
+        TIMEOUT_VICTIM_STARTED.load(Ordering::Acquire),
+        threading::cooperative_timeouts() - timeouts_before,
+        terminated,
+        cleaned
+    ));
+
+    let ok = TIMEOUT_VICTIM_STARTED.load(Ordering::Acquire)
+        && threading::cooperative_timeouts() > timeouts_before
+        && terminated >= 1
+        && count_after == count_before;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static HANDLE_DROPPED: AtomicBool = AtomicBool::new(false);
+static HANDLE_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Resource that records when it is released
+struct DropProbe;
+
+impl Drop for DropProbe {
+    fn drop(&mut self) {
+        HANDLE_DROPPED.store(true, Ordering::Release);
+    }
+}
+
+/// Test: Handles left by a terminated thread are released on cleanup
+fn test_handle_cleanup_on_exit() -> bool {
+    console::print("\n[TEST] Handle cleanup on thread exit\n");
+
+    // Round trip on the current thread
+    let round_trip = match handles::register(handles::ResourceKind::Other, 42u32) {
+        Ok(h) => handles::with(h, |v: &mut u32| *v += 1).is_some() && handles::take::<u32>(h) == Some(43),
+        Err(_) => false,
+    };
+
+    // Releasing drops the resource at once; a second release finds nothing
+    HANDLE_DROPPED.store(false, Ordering::Release);
+    let released = match handles::register(handles::ResourceKind::Other, DropProbe) {
+        Ok(h) => {
+            handles::release(h).is_ok()
+                && HANDLE_DROPPED.load(Ordering::Acquire)
+                && handles::release(h).is_err()
+        }
+        Err(_) => false,
+    };
+
+    HANDLE_DROPPED.store(false, Ordering::Release);
+    HANDLE_REGISTERED.store(false, Ordering::Release);
+
+    // Thread registers a resource and exits without releasing it
+    let tid = match threading::spawn_fn(|| {
+        if handles::register(handles::ResourceKind::Other, DropProbe).is_ok() {
+            HANDLE_REGISTERED.store(true, Ordering::Release);
+        }
+    }) {
+        Ok(tid) => tid,
+        Err(e) => {
+            console::print(&format!("  Spawn failed: {}\n", e));
+            return false;
+        }
+    };
+
+    // Wait until it has registered and marked itself terminated
+    for _ in 0..100 {
+        let (_, _, terminated) = threading::thread_stats();
+        if HANDLE_REGISTERED.load(Ordering::Acquire) && terminated > 0 {
+            break;
+        }
+        threading::yield_now();
+    }
+
+    let owned = handles::count_owned_by(handles::Owner::Thread(tid));
+    let dropped_early = HANDLE_DROPPED.load(Ordering::Acquire);
+    threading::cleanup_terminated();
+    let dropped = HANDLE_DROPPED.load(Ordering::Acquire);
+    let left = handles::count_owned_by(handles::Owner::Thread(tid));
+
+    console::print(&format!(
+        "  Round trip: {}, release: {}, owned before cleanup: {}, released on cleanup: {}, left: {}\n",
+        round_trip,
+        released,
+        owned,
+        dropped && !dropped_early,
+        left
+    ));
+
+    let ok = round_trip && released && owned == 1 && !dropped_early && dropped && left == 0;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: What a task leaves registered is released when the task is dropped
+fn test_handle_cleanup_on_task_exit() -> bool {
+    use crate::{shm, vfs};
+    use alloc::sync::Arc;
+    console::print("\n[TEST] Handle cleanup on task exit\n");
+
+    let task = handles::new_task();
+    let (shm_ok, mounted) = {
+        let _scope = task.enter();
+        // A holder that never lets go, as if its future was leaked
+        let shm_ok = match shm::create("test-task-shm", 64) {
+            Ok(buffer) => {
+                core::mem::forget(buffer);
+                true
+            }
+            Err(_) => false,
+        };
+        let mounted = vfs::mount("/tasktest", Arc::new(vfs::RamFs::new())).is_ok();
+        (shm_ok, mounted)
+    };
+
+    let scope_left = handles::current_owner() != task.owner();
+    let owned = handles::count_owned_by(task.owner());
+    let leaked = shm::open("test-task-shm").is_ok();
+    drop(task);
+    let freed = shm::open("test-task-shm").is_err();
+    let unmounted = !vfs::mounts().iter().any(|(point, _)| point == "/tasktest");
+
+    console::print(&format!(
+        "  owned by task: {}, scope left: {}, shm freed: {}, unmounted: {}\n",
+        owned, scope_left, freed, unmounted
+    ));
+
+    let ok = shm_ok && mounted && scope_left && owned == 2 && leaked && freed && unmounted;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static SPINNER_COUNT: AtomicU64 = AtomicU64::new(0);
+static SPINNER_STOP: AtomicBool = AtomicBool::new(false);
+
+/// Test: No other thread runs inside a no_preempt scope; scopes nest
+fn test_no_preempt_scope() -> bool {
+    console::print("\n[TEST] No-preempt scope\n");
+
+    SPINNER_COUNT.store(0, Ordering::Release);
+    SPINNER_STOP.store(false, Ordering::Release);
+
+    // Preemptible spinner: only makes progress when it gets the CPU
+    if let Err(e) = threading::spawn_fn(|| {
+        while !SPINNER_STOP.load(Ordering::Acquire) {
+            SPINNER_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+    }) {
+        console::print(&format!("  Spawn failed: {}\n", e));
+        console::print("  Result: FAIL\n");
+        return false;
+    }
+
+    for _ in 0..100 {
+        if SPINNER_COUNT.load(Ordering::Acquire) > 0 {
+            break;
+        }
+        threading::yield_now();
+    }
+
+    let violations_before = threading::scope_violations();
+    let (frozen, depths) = sched::no_preempt(|| {
+        let outer = threading::preempt_depth();
+        let inner = sched::no_preempt(threading::preempt_depth);
+        let pinned = sched::pin_current(sched::pinned);
+        let start = SPINNER_COUNT.load(Ordering::Acquire);
+        crate::timer::delay_ms(50); // Several timer ticks
+        let end = SPINNER_COUNT.load(Ordering::Acquire);
+        (start == end, (outer, inner, pinned))
+    });
+
+    // Preemption is back: the spinner gets time again
+    let resumed_from = SPINNER_COUNT.load(Ordering::Acquire);
+    crate::timer::delay_ms(50);
+    let resumed = SPINNER_COUNT.load(Ordering::Acquire) > resumed_from;
+
+    SPINNER_STOP.store(true, Ordering::Release);
+    for _ in 0..100 {
+        if threading::thread_stats().2 > 0 {
+            break;
+        }
+        threading::yield_now();
+    }
+    threading::cleanup_terminated();
+
+    let clean = threading::scope_violations() == violations_before
+        && threading::preempt_depth() == 0
+        && !sched::pinned();
+
+    console::print(&format!(
+        "  Depths: outer={} inner={} pinned={}\n",
+        depths.0, depths.1, depths.2
+    ));
+    console::print(&format!(
+        "  Spinner frozen in scope: {}, resumed after: {}, clean: {}\n",
+        frozen, resumed, clean
+    ));
+
+    let ok = frozen && resumed && clean && depths == (1, 2, true);
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: A capped service gets its burst, then waits for the bucket to refill;
+/// tokens a short write didn't use can be handed back
+fn test_bandwidth_cap() -> bool {
+    console::print("\n[TEST] Bandwidth cap\n");
+
+    let service = network::Service::Http;
+    let throttled_before = network::service_stats(service).throttled;
+    network::set_bandwidth_cap(service, Some(10_000));
+
+    let burst = network::reserve_tx(service, 100_000);
+    network::refund_tx(service, 400);
+    let refunded = network::reserve_tx(service, 1000);
+    let exhausted = network::reserve_tx(service, 1000);
+    crate::timer::delay_ms(100);
+    let refilled = network::reserve_tx(service, 100_000);
+
+    let stats = network::service_stats(service);
+    network::set_bandwidth_cap(service, None);
+    let uncapped = network::reserve_tx(service, 100_000);
+
+    console::print(&format!(
+        "  Burst: {:?}, refunded: {:?}, exhausted: {:?}, after 100ms: {:?}, uncapped: {:?}\n",
+        burst, refunded, exhausted, refilled, uncapped
+    ));
+
+    // ~1000 bytes refill in 100ms; allow for timer slack
+    let ok = burst == Ok(10_000)
+        && matches!(refunded, Ok(n) if (400..500).contains(&n))
+        && matches!(exhausted, Err(wait) if wait > 0)
+        && matches!(refilled, Ok(n) if (500..=2000).contains(&n))
+        && uncapped == Ok(100_000)
+        && stats.cap == Some(10_000)
+        && stats.throttled > throttled_before;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// P-384 root of the replayed server, base64 DER
+const TLS_REPLAY_ROOT: &str = concat!(
+    "MIIBhTCCAQygAwIBAgIUIt1EsCRSOiDl7z/vcGmvOhflwK4wCgYIKoZIzj0EAwMwETEPMA0GA1UEAwwGUm9vdCBB",
+    "MCAXDTIwMDEwMTAwMDAwMFoYDzIwOTkwMTAxMDAwMDAwWjARMQ8wDQYDVQQDDAZSb290IEEwdjAQBgcqhkjOPQIB",
+    "BgUrgQQAIgNiAASNSI6usCqkwYAGbhgsytZr9du9L+Y5CnPIwqiCco7ZWApDYb4Sij5ybuS3LxCRYtlYte2xjmny",
+    "+u5UhivPn1ZH14PH9yeWgxiaarIB7FD0sQVnpgitKTOyjTaOhaQ/Vz6jIzAhMA8GA1UdEwEB/wQFMAMBAf8wDgYD",
+    "VR0PAQH/BAQDAgGGMAoGCCqGSM49BAMDA2cAMGQCMAoWw0WWzfUF6MAk5LFlteXwofViP1w9bQW/e0HcJX8jKOtF",
+    "jEE/30jtsuauBSRIaQIwFCn3EFDoLyGjWjFRfTjDDSGpgbW/3k60owlvqRXMNP3wSxvfV8Ezi7rEl8eapNBR",
+);
+
+/// Everything an OpenSSL 3.0 TLS 1.3 server sent to a client seeded with
+/// `tls_replay_seed` for boot.example.test: its handshake flight for
+/// `TLS_REPLAY_LEAF` under `TLS_REPLAY_ROOT`, "pong:hello", then
+/// close_notify
+const TLS_REPLAY_FLIGHT: &str = concat!(
+    "FgMDAHoCAAB2AwNHvpQPt0mjwNBQPywfgwJ/oyULol01vLzaFJwmu73CpiDh6O/2/QQLEhkgJy41PENKUVhfZm10",
+    "e4KJkJeepayzuhMBAAAuACsAAgMEADMAJAAdACCOpRcuahuNloNmLXcASGzqJXbgydiVncqyo6xdAVnNJRQDAwAB",
+    "ARcDAwAX0uaQjhMQDYOPOAY1jPhaXcZ7LwLDG4cXAwMBp3MEWNPv2dT/CJTz5eSH5wfpJJrE4GbdnKHkrYzprVFB",
+    "MXgKTl0sK+ojaxfToG2ldknuw1+5xA2crtyXaBZMb/YcGGLtkWv+NmRmOt28vAFinbeMMC33KH7ifhgJRKcg7TsQ",
+    "qTBIJDKQglGdbUPS4Un14/TfLyk5vV44NT2dnY2e4UHsQPrU2b+J0z6V2/HnWlHlTO8mWc3zRbrYuCeb58PMsZQ7",
+    "RkIs+1cTG1qwYsXNP1j0oDbmrT4kYTKt7+opFtZmRTvtPhT2fvnsmEYMzpTvcFKjb9d64NNN/HLV9wtwbHmYavYN",
+    "BgHK3UtuD/MFD3zbjIdG/gMhs+Te1YbZeQbjz9Hmxh+zNxFuoQus1sjYK/OSilJW4ElvQRuQEqd2LIk8y+e7l/OU",
+    "7OfMaHqT0mJjI2hmrZYZQ0gUXxGGBkHLHF2DmIicv4jXWTLHxHg4M9oJUoIYz5HYEAT2ns+648nvgm8pFw6HTWCV",
+    "WLF41GlPFIeIB3/YhbbcxeSx0u5f9183JB5s5yaNGjt3t20CNMrCPUrHQGQzZRueWsaOieshlp213wYXHBcDAwBf",
+    "9LnVuv3run+P9IhEBPdUPNsIDM07rOb6tN9yQ2TBRHP5XmAimwJEmoaMijLx+8HNVKPUTvyBPMiedwzt2TUDeVOb",
+    "aYF19kGjKcjhBpCypZVDGjI4C6wjfyr3vxdXYNAXAwMANcfPysEU6hhbS8qbQpNZ2Oeb5YeTaoDLFmMRLLdj6gWI",
+    "Vr7tl5XurwsltNbQvw56n785Cdq0FwMDABs6MAG09FXODtbLw8JdRpLbgwLRr7ehHjmypGEXAwMAE3ZSLhIGgRMe",
+    "dKRVetTV4Xcp8qQ=",
+);
+
+/// P-256 server certificate issued by `TLS_REPLAY_ROOT`, valid 2020-2099,
+/// with subjectAltNames 127.0.0.1 and *.example.test
+const TLS_REPLAY_LEAF: &str = concat!(
+    "MIIBhTCCAQugAwIBAgIUecR3XXMa01xv7TWiXZuG2cUN6AIwCgYIKoZIzj0EAwMwETEPMA0GA1UEAwwGUm9vdCBB",
+    "MCAXDTIwMDEwMTAwMDAwMFoYDzIwOTkwMTAxMDAwMDAwWjAPMQ0wCwYDVQQDDARsZWFmMFkwEwYHKoZIzj0CAQYI",
+    "KoZIzj0DAQcDQgAE19aPSJULQz72oVvA+yFmPxLjBpqjvCnaFuKX+xi6ZxUSEz06JeX1fc6q+GAWqSbQR/0MdTcl",
+    "bXjVIZrPF4kCdqNBMD8wDAYDVR0TAQH/BAIwADAOBgNVHQ8BAf8EBAMCB4AwHwYDVR0RBBgwFocEfwAAAYIOKi5l",
+    "eGFtcGxlLnRlc3QwCgYIKoZIzj0EAwMDaAAwZQIxAOKvXer1H4uxQBbVe5MYSOIyNM/PEL5WrzyL5psUD+fhBzuX",
+    "IWxOpjmTVuKz5Eyq6gIwFqX3Dvw3wzIuu0UsACPY4o7WtbQDJJiySARLQijwBzIj5p5hwo2m4EoDB/A8yOoI",
+);
+
+/// SHA-256 of the replayed leaf's SubjectPublicKeyInfo
+const TLS_REPLAY_PIN: &str = "c9d296e3649fc6328b2f1e92c33fade1c944705b129506e964316cdf27871540";
+
+fn tls_replay_seed() -> [u8; crate::tls::SEED_LEN] {
+    core::array::from_fn(|i| (i as u8).wrapping_mul(7).wrapping_add(1))
+}
+
+/// Test: the TLS client completes a recorded handshake, reads the data
+/// and close_notify, and fails under the wrong trust, a tampered record
+/// or a truncated stream
+fn test_tls_client() -> bool {
+    use crate::error::{ErrorKind, KError, KResult};
+    use crate::netboot::decode_base64;
+    use crate::tls::{ServerName, TlsStream, Transport, TrustStore};
+    use core::future::Future;
+    use core::task::{Context, Poll, Waker};
+
+    console::print("\n[TEST] TLS 1.3 client handshake replay\n");
+
+    /// Plays back the server's bytes; whatever the client sends is dropped
+    struct Replay {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl Transport for Replay {
+        async fn recv(&mut self, buf: &mut [u8]) -> KResult<usize> {
+            let n = buf.len().min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+
+        async fn send(&mut self, _data: &[u8]) -> KResult<()> {
+            Ok(())
+        }
+    }
+
+    // Every await completes at once on a replay, so one poll runs it all
+    let run = |data: Vec<u8>, name: ServerName, trust: &TrustStore| -> KResult<Vec<u8>> {
+        let seed = tls_replay_seed();
+        let mut future = core::pin::pin!(async {
+            let transport = Replay { data, pos: 0 };
+            let mut stream = TlsStream::handshake_seeded(transport, &name, trust, &seed).await?;
+            let mut received = Vec::new();
+            let mut buf = [0u8; 64];
+            loop {
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok(received);
+                }
+                received.extend_from_slice(&buf[..n]);
+            }
+        });
+        match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(KError::with_context(ErrorKind::TimedOut, "tls replay")),
+        }
+    };
+
+    let flight = decode_base64(TLS_REPLAY_FLIGHT).unwrap_or_default();
+    let mut roots = TrustStore::new();
+    let root_added = decode_base64(TLS_REPLAY_ROOT).is_some_and(|der| roots.add_root(&der).is_ok());
+    let mut pinned = TrustStore::new();
+    let mut pin = [0u8; 32];
+    for (i, byte) in pin.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&TLS_REPLAY_PIN[i * 2..i * 2 + 2], 16).unwrap_or(0);
+    }
+    pinned.add_pin(pin);
+    let mut wrong_pin = TrustStore::new();
+    wrong_pin.add_pin([0x5a; 32]);
+    let failed = |result: KResult<Vec<u8>>, kind| result.is_err_and(|e| e.kind() == kind);
+
+    let name = || ServerName::Dns(String::from("boot.example.test"));
+    let by_root = run(flight.clone(), name(), &roots);
+    let by_pin = run(flight.clone(), name(), &pinned);
+    console::print(&format!(
+        "  root added: {}, by root: {:?}, by pin: {}\n",
+        root_added,
+        by_root.as_ref().map(|d| String::from_utf8_lossy(d).into_owned()),
+        by_pin.is_ok()
+    ));
+    let accepted = root_added
+        && by_root.is_ok_and(|d| d == b"pong:hello")
+        && by_pin.is_ok_and(|d| d == b"pong:hello");
+
+    let untrusted = failed(run(flight.clone(), name(), &wrong_pin), ErrorKind::AuthFailed);
+    let mut tampered = flight.clone();
+    if let Some(byte) = tampered.get_mut(300) {
+        *byte ^= 1;
+    }
+    let tampered = failed(run(tampered, name(), &roots), ErrorKind::Crypto);
+    // Without close_notify (the last record) the end of data can't be trusted
+    let truncated = flight[..flight.len().saturating_sub(24)].to_vec();
+    let truncated = failed(run(truncated, name(), &roots), ErrorKind::ConnectionClosed);
+    console::print(&format!(
+        "  rejected: untrusted {}, tampered {}, truncated {}\n",
+        untrusted, tampered, truncated
+    ));
+
+    let ok = accepted && untrusted && tampered && truncated;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: certificate chains are checked against roots, pins, validity
+/// dates and subjectAltNames
+fn test_x509_chain() -> bool {
+    use crate::netboot::decode_base64;
+    use crate::x509::{self, Anchor, Certificate};
+
+    console::print("\n[TEST] X.509 chain verification\n");
+
+    let leaf = decode_base64(TLS_REPLAY_LEAF).unwrap_or_default();
+    let root = decode_base64(TLS_REPLAY_ROOT).unwrap_or_default();
+    let roots = [root.clone()];
+    // 2026-01-01, 2010-01-01 and 2100-01-01
+    let (now, before, after) = (Some(1_767_225_600), Some(1_262_304_000), Some(4_102_444_800));
+
+    let parsed = Certificate::parse(&leaf);
+    let names = parsed.as_ref().is_ok_and(|cert| {
+        cert.has_dns_name("boot.example.test")
+            && cert.has_dns_name("BOOT.Example.Test")
+            && !cert.has_dns_name("example.test")
+            && !cert.has_dns_name("a.b.example.test")
+            && cert.has_ip([127, 0, 0, 1])
+            && !cert.has_ip([10, 0, 2, 2])
+    });
+    let pin = parsed.as_ref().map(|cert| cert.spki_sha256()).unwrap_or([0; 32]);
+
+    let by_root = x509::verify_chain(&[&leaf], &roots, &[], now);
+    let by_pin = x509::verify_chain(&[&leaf], &[], &[pin], now);
+    let root_alone = x509::verify_chain(&[&root], &roots, &[], now);
+    console::print(&format!(
+        "  parsed: {}, names: {}, by root: {:?}, by pin: {:?}, root alone: {:?}\n",
+        parsed.is_ok(),
+        names,
+        by_root,
+        by_pin,
+        root_alone
+    ));
+    let accepted = names
+        && by_root.is_ok_and(|a| a == Anchor::Root)
+        && by_pin.is_ok_and(|a| a == Anchor::Pin)
+        && root_alone.is_ok_and(|a| a == Anchor::Root);
+
+    let early = x509::verify_chain(&[&leaf], &roots, &[], before).is_err();
+    let late = x509::verify_chain(&[&leaf], &roots, &[], after).is_err();
+    let no_anchor = x509::verify_chain(&[&leaf], &[], &[[0x5a; 32]], now).is_err();
+    let mut forged = leaf.clone();
+    if let Some(byte) = forged.get_mut(200) {
+        *byte ^= 1;
+    }
+    let forged = x509::verify_chain(&[&forged], &roots, &[], now).is_err();
+    let garbage = Certificate::parse(&leaf[..leaf.len() / 2]).is_err();
+    console::print(&format!(
+        "  rejected: not yet valid {}, expired {}, no anchor {}, forged {}, truncated {}\n",
+        early, late, no_anchor, forged, garbage
+    ));
+
+    let ok = accepted && early && late && no_anchor && forged && garbage;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: The egress shaper holds frames back once the burst is spent
+fn test_egress_shaper() -> bool {
+    console::print("\n[TEST] Egress shaper\n");
+
+    let iface = "test0";
+    let unshaped = network::shaper_wait_us(iface) == 0;
+
+    // 100 kB/s with a burst of three frames
+    network::set_shaper(iface, 100_000, Some(3 * 1514));
+    let mut sent = 0;
+    while network::shaper_wait_us(iface) == 0 && sent < 10 {
+        network::shaper_charge(iface, 1514);
+        sent += 1;
+    }
+    let wait = network::shaper_wait_us(iface);
+    crate::timer::delay_us(wait);
+    let resumed = network::shaper_wait_us(iface) == 0;
+
+    let stats = network::shaper_stats()
+        .into_iter()
+        .find(|(name, _)| *name == iface)
+        .map(|(_, st)| st);
+    network::set_shaper(iface, 0, None);
+    let removed = network::shaper_wait_us(iface) == 0
+        && !network::shaper_stats().iter().any(|(name, _)| *name == iface);
+
+    // A tiny burst is rounded up to a full frame
+    network::set_shaper(iface, 1000, Some(1));
+    let min_burst = network::shaper_stats()
+        .iter()
+        .any(|(name, st)| *name == iface && st.burst == 1514);
+    network::set_shaper(iface, 0, None);
+
+    console::print(&format!(
+        "  Sent {} frames in the burst, then waited {} us; stats {:?}\n",
+        sent, wait, stats
+    ));
+
+    // One frame's worth of debt at 100 kB/s is ~15 ms
+    let counted = stats.is_some_and(|st| st.bytes == sent * 1514 && st.delayed >= 2);
+    // A fourth frame may slip in on tokens earned while the loop ran
+    let ok = unshaped
+        && (3..=4).contains(&sent)
+        && (1..=20_000).contains(&wait)
+        && resumed
+        && counted
+        && removed
+        && min_burst;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: HTTP Range headers map to inclusive byte ranges
+fn test_http_range_parsing() -> bool {
+    console::print("\n[TEST] HTTP range parsing\n");
+
+    use crate::http_server::parse_range;
+    let cases = [
+        ("bytes=0-99", 1000, Ok(Some((0, 99)))),
+        ("bytes=500-", 1000, Ok(Some((500, 999)))),
+        ("bytes=-100", 1000, Ok(Some((900, 999)))),
+        ("bytes=900-5000", 1000, Ok(Some((900, 999)))),
+        ("bytes=1000-", 1000, Err(())),
+        ("bytes=-0", 1000, Err(())),
+        ("bytes=0-1,5-9", 1000, Ok(None)),
+        ("items=0-5", 1000, Ok(None)),
+    ];
+
+    let mut ok = true;
+    for (value, len, expected) in cases.iter() {
+        let got = parse_range(value, *len);
+        if got != *expected {
+            console::print(&format!(
+                "  {:?} (len {}): got {:?}, expected {:?}\n",
+                value, len, got, expected
+            ));
+            ok = false;
+        }
+    }
+    console::print(&format!("  {} cases checked\n", cases.len()));
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Gather and scatter copies keep byte order across slice boundaries
+fn test_scatter_gather() -> bool {
+    console::print("\n[TEST] Scatter-gather copies\n");
+
+    use crate::async_net::{gather, scatter};
+    let header = b"HEAD";
+    let payload = b"payload-bytes";
+    let bufs: [&[u8]; 3] = [header, &[], payload];
+
+    // Whole thing, then resumed mid-header into a short buffer
+    let mut out = [0u8; 32];
+    let n = gather(&bufs, 0, &mut out);
+    let whole = n == 17 && &out[..n] == b"HEADpayload-bytes";
+    let mut short = [0u8; 5];
+    let n = gather(&bufs, 2, &mut short);
+    let resumed = n == 5 && &short == b"ADpay";
+    let past_end = gather(&bufs, 17, &mut out) == 0;
+
+    let mut a = [0u8; 3];
+    let mut b = [0u8; 0];
+    let mut c = [0u8; 8];
+    let n = {
+        let mut dst: [&mut [u8]; 3] = [&mut a, &mut b, &mut c];
+        scatter(b"scattered", &mut dst)
+    };
+    let scattered = n == 9 && &a == b"sca" && &c[..6] == b"ttered" && c[6..] == [0, 0];
+
+    console::print(&format!(
+        "  whole: {}, resumed: {}, past end: {}, scattered: {}\n",
+        whole, resumed, past_end, scattered
+    ));
+
+    let ok = whole && resumed && past_end && scattered;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static SINK_RECORDS: AtomicUsize = AtomicUsize::new(0);
+
+fn counting_sink(record: &klog::Record) {
+    if record.module == "tests" {
+        SINK_RECORDS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Test: Log records reach registered sinks and filter by level and module
+fn test_log_sinks() -> bool {
+    console::print("\n[TEST] Log sinks and filters\n");
+
+    SINK_RECORDS.store(0, Ordering::Relaxed);
+    let registered = klog::register_sink("test", counting_sink).is_ok();
+    let duplicate_refused = klog::register_sink("test", counting_sink).is_err();
+
+    // Debug is below the default level; let it through for this module only
+    let _ = klog::set_module_level("tests", Some(Level::Debug));
+    let start = klog::next_seq();
+    klog::log(Level::Warn, "tests", "  log test: warn\n");
+    klog::log(Level::Debug, "tests", "  log test: debug\n");
+    let _ = klog::unregister_sink("test");
+    klog::log(Level::Info, "tests", "  log test: after unregister\n");
+    klog::log(Level::Trace, "tests", "  log test: trace (filtered)\n");
+    let overridden = klog::enabled(Level::Debug, "tests") && !klog::enabled(Level::Trace, "tests");
+    let _ = klog::set_module_level("tests", Some(Level::Warn));
+    let before_filtered = klog::next_seq();
+    crate::kinfo!("  log test: info macro (filtered)");
+    let macro_filtered = klog::next_seq() == before_filtered;
+    let _ = klog::set_module_level("tests", None);
+
+    let delivered = SINK_RECORDS.load(Ordering::Relaxed);
+    let warn_filter = Filter {
+        max_level: Level::Warn,
+        module: Some("tests"),
+    };
+    let (warnings, _) = klog::records_since(start, &warn_filter);
+    let (all, next) = klog::records_since(start, &Filter::ALL);
+    let mine = all.iter().filter(|r| r.module == "tests").count();
+    let stripped = warnings.first().is_some_and(|r| r.text == "  log test: warn");
+
+    console::print(&format!(
+        "  Sink calls: {} (expected 2), warn-only: {}, all: {}, next {} > start {}\n",
+        delivered,
+        warnings.len(),
+        mine,
+        next,
+        start
+    ));
+    console::print(&format!(
+        "  Override removed: {}, filtered before formatting: {}\n",
+        overridden, macro_filtered
+    ));
+
+    let ok = registered
+        && duplicate_refused
+        && delivered == 2
+        && warnings.len() == 1
+        && stripped
+        && mine == 3
+        && next > start
+        && overridden
+        && macro_filtered;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Records logged in interrupt context wait in staging until flushed
+fn test_irq_log_staging() -> bool {
+    console::print("\n[TEST] IRQ log staging\n");
+
+    let (staged_before, _) = klog::staging_stats();
+    let start = klog::next_seq();
+    crate::irq::as_handler(|| {
+        klog::log(Level::Warn, "tests", "  staged: first\n");
+        crate::kwarn!("  staged: {}", "second");
+        crate::kwarn!("  staged: {}", "x".repeat(klog::STAGED_TEXT * 2));
+    });
+    let in_irq_after = crate::irq::in_irq();
+    let (held, _) = klog::records_since(start, &Filter::ALL);
+    let held_back = !held.iter().any(|r| r.module == "tests");
+    let (staged_after, drops) = klog::staging_stats();
+
+    let flushed = klog::flush_staged();
+    let (records, _) = klog::records_since(start, &Filter::ALL);
+    let mine: Vec<&klog::Record> = records.iter().filter(|r| r.module == "tests").collect();
+    let in_order = mine.len() == 3
+        && mine[0].text == "  staged: first"
+        && mine[1].text == "  staged: second";
+    let truncated = mine
+        .get(2)
+        .is_some_and(|r| r.text.len() == klog::STAGED_TEXT && r.text.ends_with("..."));
+
+    console::print(&format!(
+        "  Staged: {}, held back: {}, flushed: {}, in order: {}, truncated: {}, drops: {}\n",
+        staged_after - staged_before,
+        held_back,
+        flushed,
+        in_order,
+        truncated,
+        drops
+    ));
+
+    let ok = staged_after - staged_before >= 3
+        && held_back
+        && !in_irq_after
+        && flushed >= 3
+        && in_order
+        && truncated;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Records carry the uptime they were logged at, printed in front
+fn test_log_timestamps() -> bool {
+    console::print("\n[TEST] Log timestamps\n");
+
+    let before = crate::timer::uptime_us();
+    let start = klog::next_seq();
+    klog::log(Level::Warn, "tests", "  timestamped\n");
+    let after = crate::timer::uptime_us();
+    let (records, _) = klog::records_since(start, &Filter::ALL);
+    let stamped = records
+        .iter()
+        .find(|r| r.module == "tests")
+        .is_some_and(|r| (before..=after).contains(&r.uptime_us));
+
+    let record = klog::Record {
+        seq: 0,
+        uptime_us: 12_000_345,
+        level: Level::Info,
+        module: "tests",
+        event: None,
+        text: String::new(),
+    };
+    let was_utc = klog::utc_timestamps();
+    klog::set_utc_timestamps(false);
+    let uptime_only = format!("{}", klog::Timestamp::of(&record));
+    klog::set_utc_timestamps(true);
+    let with_utc = format!("{}", klog::Timestamp::of(&record));
+    klog::set_utc_timestamps(was_utc);
+
+    let uptime_ok = uptime_only == "[    12.000345]";
+    // UTC is only shown once the RTC has set the clock
+    let utc_ok = match crate::timer::utc_time_us() {
+        Some(_) => with_utc.starts_with("[    12.000345 ") && with_utc.ends_with("Z]"),
+        None => with_utc == uptime_only,
+    };
+
+    console::print(&format!("  Uptime: {}, with UTC: {}\n", uptime_only, with_utc));
+
+    let ok = stamped && uptime_ok && utc_ok;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Cached ISO 8601 prefixes match a full render across rollovers
+fn test_utc_format_cache() -> bool {
+    use crate::timer::{DateTime, Iso8601};
+    console::print("\n[TEST] UTC format cache\n");
+
+    let reference = |us: u64| {
+        let t = DateTime::from_unix_us(us);
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+            t.year, t.month, t.day, t.hour, t.minute, t.second, t.microsecond
+        )
+    };
+
+    // Minute, hour, day and year rollovers, a leap day, then the clock
+    // stepping backwards as an adjustment would
+    let times: [u64; 8] = [
+        1_792_151_999_999_999, // 2026-10-16T11:59:59.999999Z
+        1_792_152_000_000_000, // 2026-10-16T12:00:00Z
+        1_792_152_059_999_999,
+        1_792_152_060_000_000,
+        1_767_225_599_999_999, // 2025-12-31T23:59:59.999999Z
+        1_767_225_600_000_000, // 2026-01-01T00:00:00Z
+        1_709_164_800_000_000, // 2024-02-29T00:00:00Z
+        1_792_151_999_999_999,
+    ];
+    let mut matched = true;
+    for us in times {
+        let cached = format!("{}", Iso8601::new(us));
+        if cached != reference(us) {
+            console::print(&format!("  {}: got {}, expected {}\n", us, cached, reference(us)));
+            matched = false;
+        }
+    }
+    let seconds = format!("{}", Iso8601::seconds(1_792_152_000_500_000)) == "2026-10-16T12:00:00Z";
+
+    // The same hour again is served from the cache
+    let _ = format!("{}", Iso8601::new(times[1]));
+    let before = crate::timer::utc_prefix_renders();
+    let _ = format!("{}", Iso8601::new(times[1] + 1_234_567));
+    let hits = crate::timer::utc_prefix_renders() == before;
+
+    console::print(&format!(
+        "  matched: {}, whole seconds: {}, cache hit: {}\n",
+        matched, seconds, hits
+    ));
+
+    let ok = matched && seconds && hits;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: The message ring keeps the newest bytes and captures console output
+fn test_dmesg_ring() -> bool {
+    console::print("\n[TEST] Kernel message ring\n");
+
+    use crate::dmesg::{self, ByteRing};
+    let mut ring: ByteRing<8> = ByteRing::new();
+    ring.push(b"abcde");
+    let start = ring.position();
+    ring.push(b"fghij");
+    let wrapped = ring.copy_since(0) == b"cdefghij" && ring.oldest() == 2;
+    let since = ring.copy_since(start) == b"fghij";
+    ring.push(b"0123456789AB");
+    let oversized = ring.copy_since(0) == b"456789AB" && ring.position() == 22;
+
+    let (_, from) = dmesg::read_since(0);
+    console::print("  dmesg marker 7f3a\n");
+    let (captured, _) = dmesg::read_since(from);
+    let console_captured = captured.windows(11).any(|w| w == b"marker 7f3a");
+    let bounded = dmesg::read_all().len() <= dmesg::DMESG_SIZE;
+
+    console::print(&format!(
+        "  wrapped: {}, since: {}, oversized: {}, console captured: {}, bounded: {}\n",
+        wrapped, since, oversized, console_captured, bounded
+    ));
+
+    let ok = wrapped && since && oversized && console_captured && bounded;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Event IDs are unique and round-trip, and kevent! tags the record
+fn test_event_catalog() -> bool {
+    console::print("\n[TEST] Event catalog\n");
+
+    use crate::events::Event;
+    let all = Event::ALL;
+    let unique = all
+        .iter()
+        .enumerate()
+        .all(|(i, a)| all[i + 1..].iter().all(|b| a.id() != b.id() && a.as_str() != b.as_str()));
+    let round_trip = all.iter().all(|e| Event::from_id(e.id()) == Some(*e));
+
+    let start = klog::next_seq();
+    crate::kevent!(Event::BootComplete, "  event test: tagged");
+    let filter = Filter {
+        max_level: Level::Trace,
+        module: Some("tests"),
+    };
+    let (records, _) = klog::records_since(start, &filter);
+    let tagged = records
+        .iter()
+        .any(|r| r.event == Some(Event::BootComplete) && r.text == "  event test: tagged");
+
+    console::print(&format!(
+        "  {} events, unique: {}, round trip: {}, tagged: {}\n",
+        all.len(),
+        unique,
+        round_trip,
+        tagged
+    ));
+
+    let ok = unique && round_trip && tagged;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: The cycle counter is monotonic and converts to and from time
+fn test_cycle_counter() -> bool {
+    use crate::timer::{self, Stopwatch};
+    console::print("\n[TEST] Cycle counter\n");
+
+    let freq = timer::cycle_frequency();
+    let mut last = timer::cycles();
+    let mut monotonic = true;
+    for _ in 0..10_000 {
+        let now = timer::cycles();
+        monotonic &= now >= last;
+        last = now;
+    }
+
+    let round_trip = timer::cycles_to_ns(timer::ns_to_cycles(1_000_000_000)) == 1_000_000_000
+        && timer::cycles_to_us(freq) == 1_000_000
+        && timer::us_to_cycles(1_000_000) == freq
+        // Saturates instead of wrapping (the counter runs at >= 1 MHz)
+        && timer::us_to_cycles(u64::MAX) == u64::MAX;
+
+    // A 2ms spin measured by the stopwatch agrees with uptime_us
+    let stopwatch = Stopwatch::start();
+    let start_us = timer::uptime_us();
+    timer::delay_us(2_000);
+    let measured_us = stopwatch.elapsed_us();
+    let measured_ns = stopwatch.elapsed_ns();
+    let uptime_delta = timer::uptime_us() - start_us;
+    let agrees = measured_us >= 2_000
+        && measured_us.abs_diff(uptime_delta) < 500
+        && measured_ns / 1000 >= measured_us;
+
+    console::print(&format!(
+        "  Frequency: {} Hz, monotonic: {}, conversions: {}\n",
+        freq, monotonic, round_trip
+    ));
+    console::print(&format!(
+        "  2ms delay: stopwatch {} us, uptime {} us\n",
+        measured_us, uptime_delta
+    ));
+
+    let ok = freq > 0 && monotonic && round_trip && agrees;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Latency histogram percentiles stay within bucket precision, and
+/// context switches feed the wake-to-run histogram
+fn test_latency_histogram() -> bool {
+    console::print("\n[TEST] Latency histogram\n");
+
+    static HIST: latency::Histogram = latency::Histogram::new();
+    HIST.reset();
+    // 1..=1000 us: p50 ~500us, p99 ~990us
+    for us in 1..=1000u64 {
+        HIST.record(us * 1000);
+    }
+    let s = HIST.summary();
+    let close = |got: u64, want: u64| got >= want && got <= want + want / 8;
+    let percentiles_ok = s.count == 1000
+        && close(s.p50, 500_000)
+        && close(s.p99, 990_000)
+        && s.max == 1_000_000
+        && s.p999 <= s.max
+        && s.mean == 500_500;
+
+    console::print(&format!(
+        "  n={} mean {} p50 {} p99 {} max {}\n",
+        s.count,
+        latency::format_ns(s.mean),
+        latency::format_ns(s.p50),
+        latency::format_ns(s.p99),
+        latency::format_ns(s.max)
+    ));
+
+    let before = latency::summary(latency::Metric::WakeToRun).count;
+    threading::yield_now();
+    threading::yield_now();
+    let after = latency::summary(latency::Metric::WakeToRun).count;
+    let switches_recorded = after > before || threading::thread_count() <= 1;
+    console::print(&format!(
+        "  Percentiles ok: {}, wake->run samples {} -> {}\n",
+        percentiles_ok, before, after
+    ));
+
+    let ok = percentiles_ok && switches_recorded;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static CANARY_SMASHED: AtomicBool = AtomicBool::new(false);
+static CANARY_STOP: AtomicBool = AtomicBool::new(false);
+
+/// Test: A thread that writes over its stack bottom is flagged on the next switch
+fn test_stack_canary() -> bool {
+    console::print("\n[TEST] Stack canary\n");
+
+    CANARY_SMASHED.store(false, Ordering::Release);
+    CANARY_STOP.store(false, Ordering::Release);
+    let was_kill = threading::stack_overflow_kill();
+    threading::set_stack_overflow_kill(false);
+    let before = threading::stack_overflows();
+
+    // Simulate an overflow by clobbering the lowest canary word
+    let tid = match threading::spawn_fn(|| {
+        if let Some((base, _)) = threading::current_stack_bounds() {
+            unsafe { core::ptr::write_volatile(base as *mut u64, 0) };
+            CANARY_SMASHED.store(true, Ordering::Release);
+        }
+        while !CANARY_STOP.load(Ordering::Acquire) {
+            threading::yield_now();
+        }
+    }) {
+        Ok(tid) => tid,
+        Err(e) => {
+            console::print(&format!("  Spawn failed: {}\n", e));
+            console::print("  Result: FAIL\n");
+            threading::set_stack_overflow_kill(was_kill);
+            return false;
+        }
+    };
+
+    for _ in 0..100 {
+        if CANARY_SMASHED.load(Ordering::Acquire) && threading::stack_overflowed(tid) {
+            break;
+        }
+        threading::yield_now();
+    }
+    let flagged = threading::stack_overflowed(tid);
+    let counted = threading::stack_overflows() == before + 1;
+    let own_stack_ok = !threading::stack_overflowed(threading::current_thread_id());
+
+    CANARY_STOP.store(true, Ordering::Release);
+    for _ in 0..100 {
+        if threading::thread_stats().2 > 0 {
+            break;
+        }
+        threading::yield_now();
+    }
+    threading::cleanup_terminated();
+    threading::set_stack_overflow_kill(was_kill);
+
+    console::print(&format!(
+        "  Thread {} flagged: {}, counted once: {}, caller clean: {}\n",
+        tid, flagged, counted, own_stack_ok
+    ));
+
+    let ok = flagged && counted && own_stack_ok;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: with_deadline turns a wait that never finishes into TimedOut,
+/// for wait_until, Mutex::lock and JoinHandle::join alike
+fn test_with_deadline() -> bool {
+    use core::time::Duration;
+    console::print("\n[TEST] with_deadline\n");
+
+    let stopwatch = crate::timer::Stopwatch::start();
+    let timed_out = sched::with_deadline(Duration::from_millis(50), || {
+        sched::wait_until(|| false)
+    });
+    let elapsed_ms = stopwatch.elapsed_ms();
+    let timeout_ok = matches!(&timed_out, Err(e) if e.kind() == crate::error::ErrorKind::TimedOut)
+        && (50..1000).contains(&elapsed_ms);
+
+    // Inner scopes can't extend the outer deadline
+    let nested = sched::with_deadline(Duration::from_millis(100), || {
+        sched::with_deadline(Duration::from_secs(10), || {
+            Ok(sched::deadline_remaining().is_some_and(|d| d <= Duration::from_millis(100)))
+        })
+    });
+    let nested_ok = matches!(nested, Ok(true));
+
+    let ready = sched::with_deadline(Duration::from_millis(50), || sched::wait_until(|| true));
+    let cleared = sched::deadline_remaining().is_none();
+
+    // Blocking on a held mutex and joining a busy thread time out too
+    static DEADLINE_LOCK: threading::Mutex<()> = threading::Mutex::new(());
+    static DEADLINE_GO: AtomicBool = AtomicBool::new(false);
+    DEADLINE_GO.store(false, Ordering::Release);
+    let holder = threading::spawn_joinable(|| {
+        let _guard = DEADLINE_LOCK.lock();
+        while !DEADLINE_GO.load(Ordering::Acquire) {
+            threading::sleep_ms(1);
+        }
+    });
+    for _ in 0..100 {
+        if DEADLINE_LOCK.is_locked() {
+            break;
+        }
+        threading::sleep_ms(1);
+    }
+    let timed_out = |result: crate::error::KResult<()>| {
+        matches!(result, Err(e) if e.kind() == crate::error::ErrorKind::TimedOut)
+    };
+    let lock_timed_out = timed_out(sched::with_deadline(Duration::from_millis(30), || {
+        DEADLINE_LOCK.lock().map(drop)
+    })) && DEADLINE_LOCK.waiters() == 0;
+    let join_timed_out = holder.is_ok_and(|handle| {
+        timed_out(sched::with_deadline(Duration::from_millis(30), || handle.join()))
+    });
+    DEADLINE_GO.store(true, Ordering::Release);
+    let released = DEADLINE_LOCK.lock().is_ok();
+
+    console::print(&format!(
+        "  Timed out after {} ms: {}, nested shortened: {}, ready ok: {}, cleared: {}\n",
+        elapsed_ms,
+        timeout_ok,
+        nested_ok,
+        ready.is_ok(),
+        cleared
+    ));
+    console::print(&format!(
+        "  lock timed out: {}, join timed out: {}, lock free afterwards: {}\n",
+        lock_timed_out, join_timed_out, released
+    ));
+
+    let ok = timeout_ok
+        && nested_ok
+        && ready.is_ok()
+        && cleared
+        && lock_timed_out
+        && join_timed_out
+        && released;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Config-backed users are checked by password, key and role, and
+/// get their idle lock time (none for key-only users)
+fn test_config_auth() -> bool {
+    console::print("\n[TEST] Config auth provider\n");
+
+    use crate::auth::{self, AuthProvider, ConfigAuth, Role, MIN_PBKDF2_ITERATIONS};
+    use sha2::{Digest, Sha256};
+
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<alloc::string::String>();
+    let key = [0x42u8; 32];
+    // RFC 7914 test vector
+    let vector = auth::hash_password(b"passwd", b"salt", 1)
+        == "pbkdf2-sha256$1$73616c74$55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc";
+    crate::config::set("auth.users", "tester:user,boss:admin,ops:user");
+    crate::config::set(
+        "auth.tester.password",
+        &auth::hash_password(b"hunter2", b"tester-salt", MIN_PBKDF2_ITERATIONS),
+    );
+    crate::config::set("auth.boss.ed25519", &hex(&key));
+    crate::config::set("auth.ops.ed25519", &hex(&key));
+    crate::config::set("auth.idle_lock_min", "15");
+    crate::config::set("auth.tester.idle_lock_min", "0");
+
+    let provider = ConfigAuth;
+    let closed = !provider.is_open();
+    let password = provider.verify_password("tester", b"hunter2")
+        && !provider.verify_password("tester", b"hunter3")
+        && !provider.verify_password("nobody", b"hunter2");
+    // Unsalted digests and too few rounds never match
+    crate::config::set("auth.boss.password", &hex(&Sha256::digest(b"hunter2")));
+    let weak_refused = !provider.verify_password("boss", b"hunter2");
+    crate::config::set("auth.boss.password", &auth::hash_password(b"hunter2", b"boss-salt", 1000));
+    let weak_refused = weak_refused && !provider.verify_password("boss", b"hunter2");
+    let pubkey = provider.verify_pubkey("boss", "ssh-ed25519", &key)
+        && !provider.verify_pubkey("boss", "ssh-ed25519", &[0x43; 32])
+        && !provider.verify_pubkey("tester", "ssh-ed25519", &key);
+    let roles = provider.authorize("tester", Role::User)
+        && !provider.authorize("tester", Role::Admin)
+        && provider.authorize("boss", Role::Admin)
+        && !provider.authorize("nobody", Role::Guest);
+    let idle_lock = provider.idle_lock("boss") == Some(core::time::Duration::from_secs(900))
+        && provider.idle_lock("tester").is_none()
+        && provider.idle_lock("ops").is_none();
+
+    for key in [
+        "auth.users",
+        "auth.tester.password",
+        "auth.boss.password",
+        "auth.boss.ed25519",
+        "auth.ops.ed25519",
+        "auth.idle_lock_min",
+        "auth.tester.idle_lock_min",
+    ] {
+        crate::config::unset(key);
+    }
+
+    console::print(&format!(
+        "  closed: {}, kdf vector: {}, password: {}, weak hashes refused: {}, pubkey: {}, roles: {}, idle lock: {}\n",
+        closed, vector, password, weak_refused, pubkey, roles, idle_lock
+    ));
+
+    let ok = closed && vector && password && weak_refused && pubkey && roles && idle_lock;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Control protocol framing, authentication and role checks
+fn test_ctl_protocol() -> bool {
+    console::print("\n[TEST] Control protocol\n");
+
+    use crate::auth::{self, MIN_PBKDF2_ITERATIONS};
+    use crate::ctl_server::{self, Op, Session, Status, MAX_FRAME};
+    use embassy_net::{IpAddress, IpEndpoint};
+
+    // A frame round-trips; partial input asks for more, oversized is refused
+    let frame = ctl_server::encode_frame(b"\x03");
+    let framing = frame == [0, 0, 0, 1, 3]
+        && matches!(ctl_server::decode_frame(&frame), Ok(Some((p, 5))) if p == b"\x03")
+        && matches!(ctl_server::decode_frame(&frame[..4]), Ok(None))
+        && ctl_server::decode_frame(&((MAX_FRAME as u32 + 1).to_be_bytes())).is_err();
+
+    crate::config::set("auth.users", "tester:user,boss:admin,ops:user");
+    crate::config::set(
+        "auth.tester.password",
+        &auth::hash_password(b"hunter2", b"tester-salt", MIN_PBKDF2_ITERATIONS),
+    );
+    crate::config::set(
+        "auth.boss.password",
+        &auth::hash_password(b"letmein", b"boss-salt", MIN_PBKDF2_ITERATIONS),
+    );
+
+    let status = |reply: &[u8]| reply.first().copied();
+    let request = |op: Op, args: &[u8]| {
+        let mut req = alloc::vec![op as u8];
+        req.extend_from_slice(args);
+        req
+    };
+
+    let mut session = Session::new();
+    let unauthenticated =
+        status(&ctl_server::dispatch(&mut session, &request(Op::Stats, b""))) == Some(Status::AuthRequired as u8);
+    let rejected = status(&ctl_server::dispatch(&mut session, &request(Op::Auth, b"tester\0wrong")))
+        == Some(Status::AuthRequired as u8);
+    let reply = ctl_server::dispatch(&mut session, &request(Op::Auth, b"tester\0hunter2"));
+    let accepted = reply == b"\x00user";
+
+    // A user may run commands and read settings, but not change them
+    let command = ctl_server::dispatch(&mut session, &request(Op::Command, b"echo hi"));
+    let echoed = command.first() == Some(&(Status::Ok as u8)) && !command.contains(&b'\r');
+    let denied = status(&ctl_server::dispatch(&mut session, &request(Op::ConfigSet, b"ctl.test=1")))
+        == Some(Status::Denied as u8)
+        && status(&ctl_server::dispatch(&mut session, &request(Op::ConfigGet, b"auth.users")))
+            == Some(Status::Denied as u8)
+        && status(&ctl_server::dispatch(&mut session, &request(Op::ConfigGet, b" auth.users ")))
+            == Some(Status::Denied as u8);
+    let tail = ctl_server::dispatch(&mut session, &request(Op::LogTail, &0u64.to_be_bytes()));
+    let tailed = tail.len() >= 9 && tail[0] == Status::Ok as u8;
+    let unknown = status(&ctl_server::dispatch(&mut session, &[0xee])) == Some(Status::BadRequest as u8);
+
+    // Admin needs a local peer; a remote one is held to the user role
+    let set = |session: &mut Session| {
+        status(&ctl_server::dispatch(session, &request(Op::ConfigSet, b"ctl.test=1")))
+    };
+    let mut remote = Session::new();
+    let mut local = Session::admin_peer();
+    let remote_role = ctl_server::dispatch(&mut remote, &request(Op::Auth, b"boss\0letmein"));
+    let local_role = ctl_server::dispatch(&mut local, &request(Op::Auth, b"boss\0letmein"));
+    let admin = remote_role == b"\x00user"
+        && set(&mut remote) == Some(Status::Denied as u8)
+        && local_role == b"\x00admin"
+        && set(&mut local) == Some(Status::Ok as u8);
+
+    let peer = |a, b, c, d| IpEndpoint::new(IpAddress::v4(a, b, c, d), 40000);
+    let peers_before = ctl_server::is_admin_peer(peer(127, 0, 0, 1)) && !ctl_server::is_admin_peer(peer(10, 0, 2, 2));
+    crate::config::set("ctl.admin_hosts", "192.168.1.9, 10.0.2.2");
+    let peers = peers_before && ctl_server::is_admin_peer(peer(10, 0, 2, 2));
+    crate::config::unset("ctl.admin_hosts");
+
+    crate::config::unset("auth.users");
+    crate::config::unset("auth.tester.password");
+    crate::config::unset("auth.boss.password");
+    crate::config::unset("ctl.test");
+
+    // With no users configured anyone gets in, but never as admin
+    let mut open = Session::admin_peer();
+    let open_role = ctl_server::dispatch(&mut open, &request(Op::Auth, b"anyone\0x"));
+    let open_mode = open_role == b"\x00user" && set(&mut open) == Some(Status::Denied as u8);
+
+    console::print(&format!(
+        "  framing: {}, auth: {}/{}/{}, command: {}, denied: {}, tail: {}, unknown op: {}\n",
+        framing, unauthenticated, rejected, accepted, echoed, denied, tailed, unknown
+    ));
+    console::print(&format!(
+        "  admin: remote {:?}, local {:?}, peers: {}, open mode: {:?}\n",
+        String::from_utf8_lossy(&remote_role[1..]),
+        String::from_utf8_lossy(&local_role[1..]),
+        peers,
+        String::from_utf8_lossy(open_role.get(1..).unwrap_or(&[]))
+    ));
+
+    let ok = framing
+        && unauthenticated
+        && rejected
+        && accepted
+        && echoed
+        && denied
+        && tailed
+        && unknown
+        && admin
+        && peers
+        && open_mode;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: The serial line editor handles cursor movement, kills and history
+fn test_line_editor() -> bool {
+    console::print("\n[TEST] Line editor\n");
+
+    use crate::line_edit::{Edit, LineEditor};
+
+    let mut editor = LineEditor::new();
+    let mut out = Vec::new();
+    let mut feed = |editor: &mut LineEditor, bytes: &[u8]| {
+        let mut result = None;
+        for &b in bytes {
+            if let Some(edit) = editor.feed(b, &mut out) {
+                result = Some(edit);
+            }
+        }
+        result
+    };
+
+    // "helo", Left, insert 'l', End, "!" -> "hello!"
+    feed(&mut editor, b"helo\x1b[Dl\x1b[F!");
+    let insert = editor.line() == b"hello!" && editor.cursor() == 6;
+
+    // Backspace, Home, Delete -> "ello"
+    feed(&mut editor, b"\x7f\x1b[H\x1b[3~");
+    let delete = editor.line() == b"ello" && editor.cursor() == 0;
+
+    // Ctrl-E, Left x2, Ctrl-U -> "lo" with the cursor at the start
+    feed(&mut editor, b"\x05\x1b[D\x1b[D\x15");
+    let kill = editor.line() == b"lo" && editor.cursor() == 0;
+
+    let first = feed(&mut editor, b"\r") == Some(Edit::Line(b"lo".to_vec()));
+    let second = feed(&mut editor, b"two\r") == Some(Edit::Line(b"two".to_vec()));
+
+    // Up twice reaches the oldest entry, Down past the newest restores the draft
+    feed(&mut editor, b"dr\x1b[A\x1b[A");
+    let older = editor.line() == b"lo";
+    feed(&mut editor, b"\x1b[B\x1b[B");
+    let draft = editor.line() == b"dr";
+    let history = older && draft && editor.history().count() == 2;
+
+    let interrupt = feed(&mut editor, b"\x03") == Some(Edit::Interrupt)
+        && editor.line().is_empty()
+        && feed(&mut editor, b"\x04") == Some(Edit::Eof);
+
+    console::print(&format!(
+        "  insert: {}, delete: {}, kill: {}, enter: {}, history: {}, ctrl-c/d: {}\n",
+        insert,
+        delete,
+        kill,
+        first && second,
+        history,
+        interrupt
+    ));
+
+    let ok = insert && delete && kill && first && second && history && interrupt;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Remote consoles attach, take input and hand it back on detach
+fn test_console_mux() -> bool {
+    console::print("\n[TEST] Console multiplexer\n");
+
+    use crate::console::ConsoleId;
+
+    let id = console::attach("test");
+    let listed = console::consoles().iter().any(|(c, name)| *c == ConsoleId::Remote(id) && name == "test");
+
+    // Input from an attached console only counts once it's active
+    let inactive = !console::push_input(id, b"x");
+    let switched = console::set_active(ConsoleId::Remote(id)).is_ok()
+        && console::active() == ConsoleId::Remote(id);
+    let pushed = console::push_input(id, b"ok");
+    let received = console::read_byte() == Some(b'o') && console::read_byte() == Some(b'k');
+
+    console::detach(id);
+    let fallback = console::active() == ConsoleId::Serial
+        && !console::consoles().iter().any(|(c, _)| *c == ConsoleId::Remote(id));
+    let unknown = console::set_active(ConsoleId::Remote(id)).is_err();
+
+    console::print(&format!(
+        "  listed: {}, inactive dropped: {}, switched: {}, input: {}, fallback: {}, unknown rejected: {}\n",
+        listed,
+        inactive,
+        switched,
+        pushed && received,
+        fallback,
+        unknown
+    ));
+
+    let ok = listed && inactive && switched && pushed && received && fallback && unknown;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: read_byte, read_byte_blocking and read_line take input from the
+/// active console, and Backspace stops at what the buffer already held
+fn test_console_read() -> bool {
+    console::print("\n[TEST] Console read\n");
+
+    use crate::console::ConsoleId;
+
+    let id = console::attach("test-read");
+    if console::set_active(ConsoleId::Remote(id)).is_err() {
+        console::detach(id);
+        console::print("  Could not switch input\n");
+        return false;
+    }
+
+    let empty = console::read_byte().is_none();
+    console::push_input(id, b"xy");
+    let bytes = console::read_byte() == Some(b'x') && console::read_byte_blocking() == b'y';
+
+    // Three Backspaces/DELs, but only two typed characters to erase
+    console::push_input(id, b"ab\x08\x7f\x7fc\r");
+    let mut line = Vec::from(&b"> "[..]);
+    let len = console::read_line(&mut line, false);
+    let kept = line == b"> c\r" && len == line.len();
+
+    console::detach(id);
+
+    console::print(&format!(
+        "  empty: {}, bytes: {}, line: {:?}\n",
+        empty,
+        bytes,
+        core::str::from_utf8(&line)
+    ));
+
+    let ok = empty && bytes && kept;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: PL011 baud divisors and the settings the console UART runs with
+fn test_uart_config() -> bool {
+    console::print("\n[TEST] UART configuration\n");
+
+    use crate::pl011::{self, UartConfig};
+    let standard = pl011::divisors(24_000_000, 115_200) == Some((13, 1));
+    let fast = pl011::divisors(48_000_000, 921_600) == Some((3, 16));
+    let rejected = pl011::divisors(24_000_000, 0).is_none()
+        && pl011::divisors(24_000_000, 10).is_none()
+        && pl011::divisors(24_000_000, 2_000_000).is_none();
+
+    // The console UART was programmed at boot; its divisors read back close
+    // to the configured rate
+    let config = console::uart_config();
+    let actual = console::uart_baud_rate();
+    let close = actual.abs_diff(config.baud) <= config.baud / 100;
+    let defaults =
+        UartConfig::DEFAULT.baud == 115_200 && UartConfig::DEFAULT.clock_hz == 24_000_000;
+
+    console::print(&format!(
+        "  Divisors: 115200 {}, 921600 {}, out of range rejected {}\n",
+        standard, fast, rejected
+    ));
+    console::print(&format!(
+        "  Console UART: {} baud configured, {} actual\n",
+        config.baud, actual
+    ));
+
+    let ok = standard && fast && rejected && close && defaults;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: UART ports are claimed by one owner at a time
+fn test_uart_ports() -> bool {
+    use crate::error::ErrorKind;
+    use crate::uart;
+    console::print("\n[TEST] UART ports\n");
+
+    let console_owned = uart::find("uart0") == Some(0) && uart::owner(0) == Some("console");
+    let console_refused =
+        matches!(uart::claim("uart0", "tests"), Err(e) if e.kind() == ErrorKind::AlreadyExists);
+    let unknown_refused =
+        matches!(uart::claim("uart9", "tests"), Err(e) if e.kind() == ErrorKind::NotFound);
+
+    // uart1 only exists when QEMU was given a second -serial, and may
+    // already carry the kernel log
+    let present = uart::is_present(1);
+    let second_ok = match (present, uart::owner(1)) {
+        (false, _) => {
+            matches!(uart::claim("uart1", "tests"), Err(e) if e.kind() == ErrorKind::NoDevice)
+        }
+        (true, Some(_)) => uart::claim("uart1", "tests").is_err(),
+        (true, None) => {
+            let claimed = uart::claim("uart1", "tests");
+            let owned = uart::owner(1) == Some("tests");
+            let exclusive = uart::claim("uart1", "other").is_err();
+            drop(claimed);
+            owned && exclusive && uart::owner(1).is_none()
+        }
+    };
+
+    console::print(&format!(
+        "  uart0 console: {}, refused: {}, unknown refused: {}, uart1 present: {} ok: {}\n",
+        console_owned, console_refused, unknown_refused, present, second_ok
+    ));
+
+    let ok = console_owned && console_refused && unknown_refused && second_ok;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: ESR_EL1 values decode into readable descriptions
+fn test_esr_decoding() -> bool {
+    use crate::exceptions::Esr;
+    console::print("\n[TEST] ESR decoding\n");
+
+    let cases: [(u64, &str); 6] = [
+        (0x97c1_8047, "Data abort: translation fault, level 3, write of 8 bytes via x1"),
+        (0x9600_0021, "Data abort: alignment fault, read"),
+        (0x8600_000e, "Instruction abort: permission fault, level 2"),
+        (0xf200_03e8, "BRK #0x3e8"),
+        (0x5600_0000, "SVC #0x0"),
+        (0x9a00_0000, "SP alignment fault"),
+    ];
+    let mut decoded = true;
+    for (esr, expected) in cases {
+        let text = format!("{}", Esr(esr));
+        if text != expected {
+            console::print(&format!("  {:#x}: got '{}', expected '{}'\n", esr, text, expected));
+            decoded = false;
+        }
+    }
+
+    // FAR is only meaningful for aborts without FnV; only BRK is resumable
+    let far = Esr(0x8600_000e).far_valid()
+        && !Esr(0x8600_040e).far_valid()
+        && !Esr(0x5600_0000).far_valid();
+    let resumable = Esr(0xf200_03e8).is_breakpoint() && !Esr(0x97c1_8047).is_breakpoint();
+
+    console::print(&format!(
+        "  decoded: {}, far valid: {}, resumable: {}\n",
+        decoded, far, resumable
+    ));
+
+    let ok = decoded && far && resumable;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Fault reports carry the fault address, thread and every register
+fn test_fault_report() -> bool {
+    use crate::exceptions::{ExceptionFrame, FaultReport};
+    console::print("\n[TEST] Fault report\n");
+
+    let mut frame = ExceptionFrame::default();
+    for (i, x) in frame.x.iter_mut().enumerate() {
+        *x = 0x1000 + i as u64;
+    }
+    frame.elr = 0x4008_1234;
+    frame.esr = 0x97c1_8047;
+    frame.far = 0xdead_0000;
+    let text = format!(
+        "{}",
+        FaultReport {
+            frame: &frame,
+            sp: 0x4010_0000,
+            thread: Some(3),
+        }
+    );
+
+    let header = text.starts_with("Data abort: translation fault") && text.contains("at 0x40081234, address 0xdead0000");
+    let thread = text.contains("Thread: 3") && text.contains("SP 0x0000000040100000");
+    let registers = text.contains("x0  0x0000000000001000") && text.contains("x30 0x000000000000101e");
+
+    console::print(&format!(
+        "  header: {}, thread: {}, registers: {}\n",
+        header, thread, registers
+    ));
+
+    let ok = header && thread && registers;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+#[inline(never)]
+fn backtrace_inner() -> crate::backtrace::Backtrace {
+    crate::backtrace::Backtrace::capture()
+}
+
+#[inline(never)]
+fn backtrace_outer() -> crate::backtrace::Backtrace {
+    let bt = backtrace_inner();
+    core::hint::black_box(bt)
+}
+
+/// Test: The frame-pointer walker follows real and synthetic chains
+fn test_backtrace() -> bool {
+    use crate::backtrace::{self, Backtrace};
+    console::print("\n[TEST] Backtrace\n");
+
+    // A live trace reaches through the test functions into the kernel image
+    let bt = backtrace_outer();
+    let live = bt.frames().len() >= 3
+        && bt.frames()[..3].iter().all(|&a| a % 4 == 0 && (0x4000_0000..0x4800_0000).contains(&a));
+    console::print(&format!("  Live trace ({} frames):\n{}", bt.frames().len(), bt));
+
+    // Three records on the stack, the last one ending the chain
+    let mut chain = [0usize; 6];
+    let base = chain.as_ptr() as usize;
+    chain[0] = base + 16;
+    chain[1] = 0x4000_1000;
+    chain[2] = base + 32;
+    chain[3] = 0x4000_2000;
+    chain[4] = 0;
+    chain[5] = 0x4000_3000;
+    let mut out = [0usize; 8];
+    let n = backtrace::walk(core::hint::black_box(&chain).as_ptr() as usize, &mut out);
+    let synthetic = out[..n] == [0x4000_1000, 0x4000_2000, 0x4000_3000];
+
+    // A fault trace starts at the faulting PC; junk pointers end the walk
+    let fault = Backtrace::from_frame(Some(0x4008_0000), 0);
+    let from_pc = fault.frames() == [0x4008_0000];
+    let junk = backtrace::walk(0x1234_5677, &mut out) == 0 && backtrace::walk(0, &mut out) == 0;
+
+    console::print(&format!(
+        "  live: {}, synthetic: {}, from pc: {}, junk: {}\n",
+        live, synthetic, from_pc, junk
+    ));
+
+    let ok = live && synthetic && from_pc && junk;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Panic actions parse and the policy can be switched
+fn test_panic_policy() -> bool {
+    use crate::crashdump::{self, PanicAction};
+    console::print("\n[TEST] Panic policy\n");
+
+    let parsed = [PanicAction::Halt, PanicAction::Reset, PanicAction::DumpReset]
+        .iter()
+        .all(|a| PanicAction::parse(a.as_str()) == Some(*a))
+        && PanicAction::parse("reboot").is_none();
+
+    let original = crashdump::panic_action();
+    crashdump::set_panic_action(PanicAction::DumpReset);
+    let switched = crashdump::panic_action() == PanicAction::DumpReset;
+    crashdump::set_panic_action(original);
+    let restored = crashdump::panic_action() == original;
+
+    console::print(&format!(
+        "  parsed: {}, switched: {}, restored: {}, current: {} (delay {} s)\n",
+        parsed,
+        switched,
+        restored,
+        original.as_str(),
+        crashdump::reset_delay_s()
+    ));
+
+    let ok = parsed && switched && restored;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static NESTED_TEST_RUNS: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+
+/// Spins across a few timer ticks so the tick has to preempt it
+fn nested_test_handler(_irq: u32) {
+    NESTED_TEST_RUNS.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    crate::timer::delay_ms(35);
+}
+
+/// Test: The timer tick preempts a slow handler; the priority mask holds
+/// device interrupts back
+fn test_nested_irqs() -> bool {
+    use crate::{gic, irq};
+    use core::sync::atomic::Ordering;
+    console::print("\n[TEST] Nested interrupts\n");
+
+    const TEST_SGI: u32 = 1;
+    let priorities = gic::priority(30) == gic::PRIORITY_TIMER
+        && gic::priority(gic::SGI_SCHEDULER) == gic::PRIORITY_SCHEDULER
+        && gic::priority(TEST_SGI) == gic::PRIORITY_DEFAULT;
+
+    irq::register_handler(TEST_SGI, nested_test_handler);
+    NESTED_TEST_RUNS.store(0, Ordering::Relaxed);
+
+    // Masked at the device priority, the SGI stays pending
+    let old_mask = gic::set_priority_mask(gic::PRIORITY_DEFAULT);
+    gic::trigger_sgi(TEST_SGI);
+    crate::timer::delay_ms(1);
+    let held = NESTED_TEST_RUNS.load(Ordering::Relaxed) == 0;
+
+    // Unmasked, it runs, and the tick lands inside it
+    let before = irq::nested_count();
+    gic::set_priority_mask(old_mask);
+    crate::timer::delay_ms(1);
+    let ran = NESTED_TEST_RUNS.load(Ordering::Relaxed) == 1;
+    let nested = irq::nested_count() - before;
+    let idle = gic::running_priority() == 0xFF;
+    irq::unregister_handler(TEST_SGI);
+
+    console::print(&format!(
+        "  priorities: {}, held by mask: {}, ran: {}, ticks nested: {}, idle after: {}\n",
+        priorities, held, ran, nested, idle
+    ));
+
+    let ok = priorities && held && ran && (nested >= 1 || !irq::nesting()) && idle;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Busy for a measurable time so the stats have something to record
+fn stats_test_handler(_irq: u32) {
+    crate::timer::delay_us(2000);
+}
+
+/// Test: Per-line fire counts, handler times and unhandled counts
+fn test_irq_stats() -> bool {
+    use crate::{gic, irq};
+    console::print("\n[TEST] IRQ statistics\n");
+
+    const TEST_SGI: u32 = 1;
+    const UNUSED_SGI: u32 = 2;
+
+    irq::reset_stats();
+    let cleared = irq::irq_stats(TEST_SGI) == irq::IrqStats::default();
+    irq::register_handler(TEST_SGI, stats_test_handler);
+    for _ in 0..2 {
+        gic::trigger_sgi(TEST_SGI);
+        crate::timer::delay_ms(5);
+    }
+    irq::unregister_handler(TEST_SGI);
+    let after = irq::irq_stats(TEST_SGI);
+
+    // Ticks that preempt the handler come off its time, so allow some slack
+    let timed = after.total_ns >= 3_000_000 && after.max_ns >= 1_500_000;
+
+    // Dispatching a line with no handler counts it as unhandled
+    irq::dispatch_irq(UNUSED_SGI);
+    let unhandled = irq::irq_stats(UNUSED_SGI).unhandled == 1;
+    let listed = irq::all_stats().iter().any(|(line, _)| *line == TEST_SGI);
+
+    console::print(&format!(
+        "  cleared: {}, fired: {}, total: {} us, max: {} us, unhandled counted: {}, listed: {}, spurious: {}\n",
+        cleared,
+        after.count,
+        after.total_ns / 1000,
+        after.max_ns / 1000,
+        unhandled,
+        listed,
+        irq::spurious_count()
+    ));
+
+    let ok = cleared && after.count == 2 && timed && unhandled && listed;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Probed reads survive unbacked addresses
+fn test_mmio_probe() -> bool {
+    use crate::probe;
+    console::print("\n[TEST] MMIO probing\n");
+
+    // uart0's PrimeCell ID register is always there
+    let present = probe::read32(0x0900_0FF0).map(|v| v & 0xff) == Ok(0x0D);
+
+    // Nothing is mapped between the virtio-mmio bank and the platform bus;
+    // QEMU either aborts (caught) or reads zero, but never panics
+    let missing = probe::read32(0x0B00_0000);
+    let survived = matches!(missing, Err(_) | Ok(0));
+
+    // The window closes after a fault, so the next probe reads normally
+    let again = probe::read32(0x0900_0FF0).is_ok();
+    let unaligned = probe::read32(0x0900_0FF1).is_err();
+
+    console::print(&format!(
+        "  present: {}, unbacked: {:?}, next probe ok: {}, unaligned rejected: {}\n",
+        present, missing, again, unaligned
+    ));
+
+    let ok = present && survived && again && unaligned && probe::is_readable(0x0900_0FF0);
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Named shared buffers are reference counted and freed with the last handle
+fn test_shared_memory() -> bool {
+    use crate::{error::ErrorKind, shm, vmm};
+    console::print("\n[TEST] Shared memory\n");
+
+    let Ok(writer) = shm::create("test-shm", 5000) else {
+        console::print("  create failed\n  Result: FAIL\n");
+        return false;
+    };
+    let base = writer.as_mut_ptr() as usize;
+    let sized = writer.size() == 5000;
+    let duplicate = matches!(shm::create("test-shm", 64), Err(e) if e.kind() == ErrorKind::AlreadyExists);
+
+    // A reader opened by name sees the writer's bytes in place
+    let written = writer.write(4096, b"shared").is_ok();
+    let reader = shm::open_reader("test-shm");
+    let mut buf = [0u8; 6];
+    let seen = reader
+        .as_ref()
+        .map(|r| r.read(4096, &mut buf).is_ok() && r.as_ptr() as usize == base && r.size() == 5000)
+        .unwrap_or(false)
+        && &buf == b"shared";
+    let bounded = writer.write(4998, b"abc").is_err();
+
+    let mut back = [0u8; 6];
+    let round_trip = writer.read(4096, &mut back).is_ok() && &back == b"shared";
+    let (refs, len) = shm::list()
+        .iter()
+        .find(|i| i.name == writer.name())
+        .map_or((0, 0), |i| (i.refs, i.len));
+    let backed = vmm::find(base).is_some();
+
+    // Dropping every handle releases the name and the pages
+    let second = writer.reader();
+    drop(writer);
+    let kept = shm::open("test-shm").is_ok() && second.as_ref().is_ok_and(|r| r.name() == "test-shm");
+    drop(second);
+    drop(reader);
+    let freed = shm::open("test-shm").is_err() && vmm::find(base).is_none();
+
+    console::print(&format!(
+        "  duplicate refused: {}, shared: {}, bounded: {}, refs: {}, kept by reader: {}, freed: {}\n",
+        duplicate, written && seen, bounded, refs, kept, freed
+    ));
+
+    let listed = refs == 2 && len == 5000 && backed && sized;
+    let ok = duplicate && written && seen && round_trip && bounded && listed && kept && freed;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: SPI target masks can be read and changed; SGIs/PPIs are fixed
+fn test_irq_affinity() -> bool {
+    use crate::gic;
+    console::print("\n[TEST] IRQ affinity\n");
+
+    const UART0_IRQ: u32 = 33;
+    let cpus = gic::cpu_count();
+    let me = gic::current_cpu_mask();
+    let original = gic::targets(UART0_IRQ);
+
+    // Re-targeting at ourselves keeps the console working whatever happens
+    let set = gic::set_targets(UART0_IRQ, me).is_ok() && gic::targets(UART0_IRQ) == me;
+    let empty = gic::set_targets(UART0_IRQ, 0).is_err();
+    let absent = cpus >= 8 || gic::set_targets(UART0_IRQ, 1 << cpus).is_err();
+    let fixed = gic::set_targets(30, me).is_err() && gic::set_targets(gic::SGI_SCHEDULER, me).is_err();
+    let restored = gic::set_targets(UART0_IRQ, original).is_ok();
+
+    console::print(&format!(
+        "  cpus: {}, our mask: {:#04x}, uart0 targets: {:#04x}, set: {}, bad masks refused: {}, PPI/SGI fixed: {}\n",
+        cpus,
+        me,
+        original,
+        set,
+        empty && absent,
+        fixed
+    ));
+
+    let ok = cpus >= 1 && me != 0 && set && empty && absent && fixed && restored;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static BG_RUNS: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+static BG_STOP: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+static HOG_DONE: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+/// Test: Background threads wait while a normal thread is ready
+fn test_background_class() -> bool {
+    use core::sync::atomic::Ordering;
+    use threading::SchedClass;
+    console::print("\n[TEST] Background scheduling class\n");
+
+    threading::cleanup_terminated();
+    BG_RUNS.store(0, Ordering::Relaxed);
+    BG_STOP.store(false, Ordering::Relaxed);
+    HOG_DONE.store(false, Ordering::Relaxed);
+
+    let bg = threading::spawn_fn_background("test-bg", || {
+        while !BG_STOP.load(Ordering::Relaxed) {
+            BG_RUNS.fetch_add(1, Ordering::Relaxed);
+            threading::yield_now();
+        }
+    });
+    // Busy for a few ticks without yielding, so it is always ready
+    let hog = threading::spawn_fn(|| {
+        crate::timer::delay_ms(50);
+        HOG_DONE.store(true, Ordering::Relaxed);
+    });
+    let (Ok(bg), Ok(_)) = (bg, hog) else {
+        console::print("  spawn failed\n  Result: FAIL\n");
+        BG_STOP.store(true, Ordering::Relaxed);
+        return false;
+    };
+    let classified = threading::thread_class(bg) == Some(SchedClass::Background);
+
+    let wait = crate::timer::Stopwatch::start();
+    while !HOG_DONE.load(Ordering::Relaxed) && wait.elapsed_us() < 1_000_000 {
+        threading::yield_now();
+    }
+    let during_hog = BG_RUNS.load(Ordering::Relaxed);
+
+    // With only the main loop left, its yields let the background run
+    for _ in 0..10 {
+        threading::yield_now();
+    }
+    let after_hog = BG_RUNS.load(Ordering::Relaxed);
+
+    BG_STOP.store(true, Ordering::Relaxed);
+    for _ in 0..10 {
+        threading::yield_now();
+    }
+    threading::cleanup_terminated();
+
+    console::print(&format!(
+        "  class: {}, runs while normal thread ready: {}, runs after: {}\n",
+        threading::thread_class(bg).map_or("gone", |c| c.as_str()),
+        during_hog,
+        after_hog
+    ));
+
+    let ok = classified && HOG_DONE.load(Ordering::Relaxed) && during_hog == 0 && after_hog > 0;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: The diagnostics bundle is a valid tar with secrets redacted
+fn test_sysreport() -> bool {
+    use crate::{config, sysreport, vfs};
+    console::print("\n[TEST] Diagnostics bundle\n");
+
+    config::set("test.report.password", "hunter2-sysreport");
+    let (archive, members) = sysreport::generate();
+    config::unset("test.report.password");
+
+    // Walk the headers: each names a member, checksums and points past its data
+    let mut names = Vec::new();
+    let mut offset = 0;
+    let mut valid = archive.len() % 512 == 0;
+    while valid && offset + 512 <= archive.len() && archive[offset] != 0 {
+        let header = &archive[offset..offset + 512];
+        let octal = |field: &[u8]| {
+            field
+                .iter()
+                .take_while(|&&b| (b'0'..=b'7').contains(&b))
+                .fold(0usize, |acc, &b| acc * 8 + (b - b'0') as usize)
+        };
+        let sum: usize = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' as usize } else { b as usize })
+            .sum();
+        valid = &header[257..263] == b"ustar\0" && octal(&header[148..156]) == sum;
+        let name_len = header[..100].iter().position(|&b| b == 0).unwrap_or(100);
+        names.push(String::from_utf8_lossy(&header[..name_len]).into_owned());
+        offset += 512 + octal(&header[124..136]).div_ceil(512) * 512;
+    }
+    let counted = names.len() == members
+        && names.iter().any(|n| n == "sysreport/config.txt")
+        && names.iter().any(|n| n == "sysreport/dmesg.txt");
+
+    let text = String::from_utf8_lossy(&archive);
+    let redacted = !text.contains("hunter2-sysreport") && text.contains("test.report.password=<redacted>");
+
+    let written = sysreport::write()
+        .ok()
+        .and_then(|(size, _)| vfs::read_file(sysreport::REPORT_PATH).ok().map(|d| d.len() == size))
+        .unwrap_or(false);
+    let _ = vfs::remove(sysreport::REPORT_PATH);
+
+    console::print(&format!(
+        "  {} bytes, members: {:?}\n  valid headers: {}, redacted: {}, written to /tmp: {}\n",
+        archive.len(),
+        names,
+        valid,
+        redacted,
+        written
+    ));
+
+    let ok = valid && counted && redacted && written;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static FIQ_TEST_RUNS: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+static FIQ_SEEN_IN_IRQ: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+const FIQ_TEST_SGI: u32 = 3;
+
+fn fiq_test_handler(_irq: u32) {
+    FIQ_TEST_RUNS.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+}
+
+/// Raises the FIQ and waits for it from inside an IRQ handler
+fn fiq_preempt_handler(_irq: u32) {
+    use core::sync::atomic::Ordering;
+    let before = FIQ_TEST_RUNS.load(Ordering::Relaxed);
+    crate::gic::trigger_sgi(FIQ_TEST_SGI);
+    let wait = crate::timer::Stopwatch::start();
+    while wait.elapsed_us() < 5_000 {
+        if FIQ_TEST_RUNS.load(Ordering::Relaxed) != before {
+            FIQ_SEEN_IN_IRQ.store(true, Ordering::Relaxed);
+            break;
+        }
+    }
+}
+
+/// Test: A Group 0 line arrives as FIQ, even with IRQs masked or an IRQ
+/// handler running
+fn test_fiq() -> bool {
+    use crate::{gic, irq};
+    use core::sync::atomic::Ordering;
+    console::print("\n[TEST] FIQ delivery\n");
+
+    FIQ_TEST_RUNS.store(0, Ordering::Relaxed);
+    FIQ_SEEN_IN_IRQ.store(false, Ordering::Relaxed);
+    let before = irq::fiq_count();
+
+    if let Err(e) = irq::register_fiq(FIQ_TEST_SGI, fiq_test_handler) {
+        console::print(&format!("  register failed: {}\n  Result: FAIL\n", e));
+        return false;
+    }
+    let single = irq::register_fiq(4, fiq_test_handler).is_err();
+    let grouped = gic::is_fiq(FIQ_TEST_SGI) && irq::fiq_line() == Some(FIQ_TEST_SGI);
+
+    gic::trigger_sgi(FIQ_TEST_SGI);
+    crate::timer::delay_ms(1);
+    let delivered = FIQ_TEST_RUNS.load(Ordering::Relaxed) == 1;
+
+    // IRQs masked: the FIQ still gets through
+    unsafe { core::arch::asm!("msr daifset, #2") };
+    gic::trigger_sgi(FIQ_TEST_SGI);
+    crate::timer::delay_ms(1);
+    let through_mask = FIQ_TEST_RUNS.load(Ordering::Relaxed) == 2;
+    unsafe { core::arch::asm!("msr daifclr, #2") };
+
+    // Inside an IRQ handler: the FIQ preempts it
+    irq::register_handler(1, fiq_preempt_handler);
+    gic::trigger_sgi(1);
+    crate::timer::delay_ms(10);
+    irq::unregister_handler(1);
+    let preempted = FIQ_SEEN_IN_IRQ.load(Ordering::Relaxed);
+
+    let released = irq::unregister_fiq(FIQ_TEST_SGI).is_ok()
+        && !gic::is_fiq(FIQ_TEST_SGI)
+        && irq::fiq_line().is_none();
+    let counted = irq::fiq_count() - before;
+
+    console::print(&format!(
+        "  delivered: {}, through IRQ mask: {}, preempts IRQ handler: {}, single handler: {}, handled: {}\n",
+        delivered && grouped,
+        through_mask,
+        preempted,
+        single,
+        counted
+    ));
+
+    let ok = grouped && delivered && through_mask && preempted && single && released && counted == 3;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Closures owning their state can be registered as IRQ handlers
+fn test_irq_closure() -> bool {
+    use crate::{gic, irq};
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicU32;
+    console::print("\n[TEST] Closure IRQ handlers\n");
+
+    const TEST_SGI: u32 = 1;
+
+    // Stand-in for a driver's device state, owned by the handler
+    struct Device {
+        fired: Arc<AtomicU32>,
+        calls: u32,
+    }
+    let fired = Arc::new(AtomicU32::new(0));
+    let mut device = Device {
+        fired: fired.clone(),
+        calls: 0,
+    };
+    // The handler mutates its own state and publishes calls x line
+    irq::register_handler(TEST_SGI, move |line| {
+        device.calls += 1;
+        device.fired.store(device.calls * line, Ordering::Relaxed);
+    });
+    for _ in 0..3 {
+        gic::trigger_sgi(TEST_SGI);
+        crate::timer::delay_ms(2);
+    }
+    let counted = fired.load(Ordering::Relaxed) == 3 * TEST_SGI;
+
+    // Replacing the handler drops the old one and its state
+    let second = Arc::new(AtomicU32::new(0));
+    let captured = second.clone();
+    irq::register_handler(TEST_SGI, move |_| {
+        captured.fetch_add(1, Ordering::Relaxed);
+    });
+    let released = Arc::strong_count(&fired) == 1;
+    gic::trigger_sgi(TEST_SGI);
+    crate::timer::delay_ms(2);
+    let replaced = second.load(Ordering::Relaxed) == 1 && fired.load(Ordering::Relaxed) == 3 * TEST_SGI;
+
+    irq::unregister_handler(TEST_SGI);
+    let dropped = Arc::strong_count(&second) == 1;
+
+    console::print(&format!(
+        "  counted: {}, old state released: {}, replaced: {}, dropped on unregister: {}\n",
+        counted, released, replaced, dropped
+    ));
+
+    let ok = counted && released && replaced && dropped;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static POLICY_TEST_GO: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+static POLICY_TEST_RAN: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+/// Test: Each policy's pick order, and swapping the live policy
+fn test_sched_policy() -> bool {
+    use crate::sched_policy::{self, Edf, Priority, RoundRobin, SchedParams, SchedPolicy};
+    use core::sync::atomic::Ordering;
+    console::print("\n[TEST] Scheduling policies\n");
+
+    let any = |_: usize| true;
+    let params = |priority: u8, relative_deadline_us: u64| SchedParams {
+        priority,
+        relative_deadline_us,
+    };
+
+    // Round-robin: the next queued tid after the current one, wrapping
+    let mut rr = RoundRobin::new();
+    for tid in [1, 3, 5] {
+        rr.enqueue(tid, SchedParams::new(), 0);
+    }
+    rr.dequeue(5);
+    let rr_ok = rr.pick_next(1, &any) == Some(3)
+        && rr.pick_next(3, &any) == Some(1)
+        && rr.pick_next(3, &|tid| tid != 1) == Some(3);
+
+    // Priority: the most urgent first, equal priorities in turn
+    let mut prio = Priority::new();
+    prio.enqueue(1, params(100, 0), 0);
+    prio.enqueue(2, params(200, 0), 0);
+    prio.enqueue(3, params(200, 0), 0);
+    let prio_ok = prio.pick_next(1, &any) == Some(2)
+        && prio.pick_next(2, &any) == Some(3)
+        && prio.pick_next(3, &|tid| tid == 1) == Some(1);
+
+    // EDF: earliest deadline first; yielding starts the next period
+    let mut edf = Edf::new();
+    edf.enqueue(1, params(0, 0), 0);
+    edf.enqueue(2, params(0, 5_000), 0);
+    edf.enqueue(3, params(0, 2_000), 0);
+    let first = edf.pick_next(1, &any);
+    edf.put_prev(3, 1_000, true); // next deadline 3_000
+    let second = edf.pick_next(3, &any);
+    edf.put_prev(2, 1_500, false); // preempted: keeps 5_000
+    let third = edf.pick_next(2, &any);
+    let best_effort = edf.pick_next(1, &|tid| tid == 1);
+    let edf_ok = first == Some(3) && second == Some(3) && third == Some(3) && best_effort == Some(1);
+
+    let named = ["rr", "priority", "edf"]
+        .iter()
+        .all(|name| sched_policy::by_name(name).map(|p| p.name()) == Some(*name))
+        && sched_policy::by_name("lottery").is_none();
+
+    // Reinstall the running policy: live threads must keep being scheduled
+    let active = threading::policy_name();
+    let swapped = match sched_policy::by_name(active) {
+        Some(policy) => {
+            threading::set_policy(policy);
+            true
+        }
+        None => false,
+    };
+    POLICY_TEST_GO.store(false, Ordering::Relaxed);
+    POLICY_TEST_RAN.store(false, Ordering::Relaxed);
+    let tid = threading::spawn_fn(|| {
+        // Stay alive until the parameters have been checked
+        while !POLICY_TEST_GO.load(Ordering::Relaxed) {
+            threading::yield_now();
+        }
+        POLICY_TEST_RAN.store(true, Ordering::Relaxed);
+    });
+    let tuned = tid
+        .as_ref()
+        .map(|&tid| {
+            threading::set_sched_params(tid, params(90, 0)).is_ok()
+                && threading::sched_params(tid) == Some(params(90, 0))
+        })
+        .unwrap_or(false);
+    POLICY_TEST_GO.store(true, Ordering::Relaxed);
+    let wait = crate::timer::Stopwatch::start();
+    while !POLICY_TEST_RAN.load(Ordering::Relaxed) && wait.elapsed_us() < 1_000_000 {
+        threading::yield_now();
+    }
+    let ran = POLICY_TEST_RAN.load(Ordering::Relaxed);
+    threading::cleanup_terminated();
+
+    console::print(&format!(
+        "  rr: {}, priority: {}, edf: {}, by name: {}, active: {}, swapped: {}, params: {}, ran after swap: {}\n",
+        rr_ok, prio_ok, edf_ok, named, active, swapped, tuned, ran
+    ));
+
+    let ok = rr_ok && prio_ok && edf_ok && named && swapped && tuned && ran;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static SOFTIRQ_RAN: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+static SOFTIRQ_IN_IRQ: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(true);
+static SOFTIRQ_MASKED: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(true);
+
+/// Test: Work deferred from a hard IRQ runs later in thread context
+fn test_softirq() -> bool {
+    use crate::softirq::{self, SoftIrq};
+    use crate::{gic, irq};
+    console::print("\n[TEST] Deferred interrupt work\n");
+
+    const TEST_SGI: u32 = 1;
+
+    SOFTIRQ_RAN.store(false, Ordering::Relaxed);
+    SOFTIRQ_IN_IRQ.store(true, Ordering::Relaxed);
+    SOFTIRQ_MASKED.store(true, Ordering::Relaxed);
+    let before = softirq::stats(SoftIrq::Deferred);
+
+    irq::register_handler(TEST_SGI, |_| {
+        let _ = softirq::defer(|| {
+            let daif: u64;
+            unsafe { core::arch::asm!("mrs {}, daif", out(reg) daif) };
+            SOFTIRQ_MASKED.store(daif & (1 << 7) != 0, Ordering::Relaxed);
+            SOFTIRQ_IN_IRQ.store(irq::in_irq(), Ordering::Relaxed);
+            SOFTIRQ_RAN.store(true, Ordering::Relaxed);
+        });
+    });
+    gic::trigger_sgi(TEST_SGI);
+    crate::timer::delay_ms(2);
+    irq::unregister_handler(TEST_SGI);
+    // The hard IRQ only queued it
+    let deferred = !SOFTIRQ_RAN.load(Ordering::Relaxed);
+
+    let wait = crate::timer::Stopwatch::start();
+    while !SOFTIRQ_RAN.load(Ordering::Relaxed) && wait.elapsed_us() < 1_000_000 {
+        softirq::run_pending();
+        threading::yield_now();
+    }
+    let ran = SOFTIRQ_RAN.load(Ordering::Relaxed);
+    let in_thread = !SOFTIRQ_IN_IRQ.load(Ordering::Relaxed);
+    let unmasked = !SOFTIRQ_MASKED.load(Ordering::Relaxed);
+    let after = softirq::stats(SoftIrq::Deferred);
+    let counted = after.raised > before.raised && after.runs > before.runs;
+
+    console::print(&format!(
+        "  deferred: {}, ran: {}, thread context: {}, IRQs enabled: {}, {} raised/runs: {}/{}, dropped: {}\n",
+        deferred,
+        ran,
+        in_thread,
+        unmasked,
+        SoftIrq::Deferred.as_str(),
+        after.raised,
+        after.runs,
+        softirq::dropped()
+    ));
+
+    let ok = deferred && ran && in_thread && unmasked && counted;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: subsystems refuse a second init, and the network refuses a
+/// shutdown before it is up
+fn test_double_init() -> bool {
+    use crate::error::ErrorKind;
+    console::print("\n[TEST] Double initialization\n");
+
+    let threads = threading::thread_count();
+    let refused = |result: crate::error::KResult<()>| {
+        result.err().map(|e| e.kind()) == Some(ErrorKind::AlreadyInitialized)
+    };
+    let gic = refused(crate::gic::init());
+    let timer = refused(crate::timer::init());
+    let threads_kept = refused(threading::init()) && threading::thread_count() == threads;
+    let net_down = !crate::async_net::is_up()
+        && crate::async_net::shutdown().err().map(|e| e.kind()) == Some(ErrorKind::NotInitialized);
+
+    console::print(&format!(
+        "  gic refused: {}, timer refused: {}, threading refused: {} ({} threads), network shutdown refused: {}\n",
+        gic, timer, threads_kept, threads, net_down
+    ));
+
+    let ok = gic && timer && threads_kept && net_down;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Unexpected vector entries and SError syndromes are described
+fn test_unexpected_vectors() -> bool {
+    use crate::exceptions::{Esr, Vector, spsr_mode};
+    console::print("\n[TEST] Unexpected exception vectors\n");
+
+    let vectors: [(u8, &str, bool); 4] = [
+        (0, "Synchronous exception from current EL (SP0)", true),
+        (7, "SError from current EL (SPx)", true),
+        (9, "IRQ from lower EL (AArch64)", false),
+        (14, "FIQ from lower EL (AArch32)", false),
+    ];
+    let mut named = true;
+    for (index, expected, syndrome) in vectors {
+        let vector = Vector(index);
+        let text = format!("{}", vector);
+        if text != expected || vector.has_syndrome() != syndrome {
+            console::print(&format!("  vector {}: got '{}', expected '{}'\n", index, text, expected));
+            named = false;
+        }
+    }
+
+    let serrors: [(u64, &str); 3] = [
+        (0xbe00_0011, "SError: asynchronous SError, uncontainable"),
+        (0xbe00_0a11, "SError: asynchronous SError, restartable (external)"),
+        (0xbf00_1234, "SError: implementation defined syndrome 0x1234"),
+    ];
+    let mut decoded = true;
+    for (esr, expected) in serrors {
+        let text = format!("{}", Esr(esr));
+        if text != expected {
+            console::print(&format!("  {:#x}: got '{}', expected '{}'\n", esr, text, expected));
+            decoded = false;
+        }
+    }
+
+    let modes = spsr_mode(0x3c5) == "EL1h" && spsr_mode(0x3c4) == "EL1t" && spsr_mode(0x10) == "AArch32";
+
+    console::print(&format!(
+        "  vectors named: {}, SError decoded: {}, modes: {}\n",
+        named, decoded, modes
+    ));
+
+    let ok = named && decoded && modes;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Raw mode keeps text off the wire and write_bytes out of dmesg
+fn test_console_raw_mode() -> bool {
+    console::print("\n[TEST] Console raw mode\n");
+
+    let contains = |log: &[u8], needle: &[u8]| log.windows(needle.len()).any(|w| w == needle);
+    let held_before = console::raw_held_back();
+
+    let raw = console::enter_raw();
+    let exclusive = raw.is_ok() && console::enter_raw().is_err() && console::is_raw();
+    console::print("  [raw-test] held back\n");
+    console::write_bytes(b"  [raw-test] binary path\r\n");
+    let held = console::raw_held_back() - held_before;
+    drop(raw);
+    let restored = !console::is_raw();
+
+    let log = crate::dmesg::read_all();
+    let text_logged = contains(&log, b"[raw-test] held back");
+    let binary_unlogged = !contains(&log, b"[raw-test] binary path");
+
+    console::print(&format!(
+        "  exclusive: {}, held back: {} bytes, restored: {}, text in dmesg: {}, binary kept out: {}\n",
+        exclusive, held, restored, text_logged, binary_unlogged
+    ));
+
+    let ok = exclusive && held >= 23 && restored && text_logged && binary_unlogged;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static JOIN_TEST_GO: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+/// Test: Joinable threads hand their return value to the spawner
+fn test_join_handle() -> bool {
+    console::print("\n[TEST] Join handles\n");
+
+    let sum = match threading::spawn_joinable(|| {
+        threading::yield_now();
+        (1..=100u64).sum::<u64>()
+    }) {
+        Ok(handle) => handle.join().ok(),
+        Err(e) => {
+            console::print(&format!("  spawn failed: {}\n", e));
+            None
+        }
+    };
+
+    // Non-Copy results move across too; the handle only reports finished
+    // once the closure has returned
+    JOIN_TEST_GO.store(false, Ordering::Release);
+    let (text, waited) = match threading::spawn_joinable(|| {
+        while !JOIN_TEST_GO.load(Ordering::Acquire) {
+            threading::yield_now();
+        }
+        alloc::string::String::from("joined")
+    }) {
+        Ok(handle) => {
+            threading::yield_now();
+            let early = handle.is_finished();
+            JOIN_TEST_GO.store(true, Ordering::Release);
+            (handle.join().ok(), !early)
+        }
+        Err(_) => (None, false),
+    };
+
+    console::print(&format!(
+        "  sum: {:?}, text: {:?}, not finished early: {}\n",
+        sum, text, waited
+    ));
+
+    let ok = sum == Some(5050) && text.as_deref() == Some("joined") && waited;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Sleeping threads stay off the CPU until their wake time
+fn test_sleep() -> bool {
+    console::print("\n[TEST] Thread sleep\n");
+
+    // This thread: at least the requested time passes
+    let start = crate::timer::uptime_us();
+    threading::sleep_ms(20);
+    let slept_us = crate::timer::uptime_us() - start;
+
+    // Another thread: shows up as Sleeping while this one keeps running
+    let start = crate::timer::uptime_us();
+    let (seen_asleep, other_us) = match threading::spawn_joinable(move || {
+        threading::sleep_ms(30);
+        crate::timer::uptime_us() - start
+    }) {
+        Ok(handle) => {
+            let mut seen = false;
+            while !handle.is_finished() {
+                seen |= threading::sleeping_count() > 0;
+                threading::yield_now();
+            }
+            (seen, handle.join().unwrap_or(0))
+        }
+        Err(e) => {
+            console::print(&format!("  spawn failed: {}\n", e));
+            (false, 0)
+        }
+    };
+
+    console::print(&format!(
+        "  slept: {} us (asked 20000), other thread: {} us (asked 30000), seen asleep: {}\n",
+        slept_us, other_us, seen_asleep
+    ));
+
+    let ok = slept_us >= 20_000 && other_us >= 30_000 && seen_asleep;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: Ticks mid-slice don't raise the scheduler SGI
+fn test_tick_coalescing() -> bool {
+    console::print("\n[TEST] Scheduler tick coalescing\n");
+
+    let busy = |us: u64| {
+        let start = crate::timer::uptime_us();
+        while crate::timer::uptime_us() - start < us {
+            core::hint::spin_loop();
+        }
+    };
+    let saved = threading::slice_us();
+
+    // A long slice: most ticks of a 60 ms busy stretch are skipped
+    threading::set_slice_us(1_000_000);
+    threading::yield_now();
+    let before = threading::coalesced_ticks();
+    busy(60_000);
+    let long_slice = threading::coalesced_ticks() - before;
+
+    // No slice: every tick runs the scheduler
+    threading::set_slice_us(0);
+    let before = threading::coalesced_ticks();
+    busy(30_000);
+    let every_tick = threading::coalesced_ticks() - before;
+
+    threading::set_slice_us(saved);
+
+    console::print(&format!(
+        "  coalesced with 1 s slice: {} of ~6 ticks, with slice 0: {}\n",
+        long_slice, every_tick
+    ));
+
+    let ok = long_slice >= 3 && every_tick == 0;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static PREEMPT_MODE_SPINS: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+static PREEMPT_MODE_STOP: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+/// Test: A thread can turn cooperative for a while and back
+fn test_set_preemptible() -> bool {
+    console::print("\n[TEST] Runtime preemption mode\n");
+
+    let busy = |us: u64| {
+        let start = crate::timer::uptime_us();
+        while crate::timer::uptime_us() - start < us {
+            core::hint::spin_loop();
+        }
+    };
+    let me = threading::current_thread_id();
+    let was_preemptible = threading::is_preemptible(me) == Some(true);
+
+    PREEMPT_MODE_SPINS.store(0, Ordering::Relaxed);
+    PREEMPT_MODE_STOP.store(false, Ordering::Relaxed);
+    let other = threading::spawn_joinable(|| {
+        while !PREEMPT_MODE_STOP.load(Ordering::Relaxed) {
+            PREEMPT_MODE_SPINS.fetch_add(1, Ordering::Relaxed);
+            core::hint::spin_loop();
+        }
+    });
+    // Let it start
+    threading::yield_now();
+
+    // Cooperative: the other thread doesn't run while this one is busy
+    let made_coop = threading::set_preemptible(me, false).is_ok()
+        && threading::is_preemptible(me) == Some(false);
+    let before = PREEMPT_MODE_SPINS.load(Ordering::Relaxed);
+    busy(40_000);
+    let held = PREEMPT_MODE_SPINS.load(Ordering::Relaxed) == before;
+
+    // Preemptible again: it gets time slices
+    let restored = threading::set_preemptible(me, true).is_ok()
+        && threading::is_preemptible(me) == Some(true);
+    let before = PREEMPT_MODE_SPINS.load(Ordering::Relaxed);
+    busy(60_000);
+    let shared = PREEMPT_MODE_SPINS.load(Ordering::Relaxed) > before;
+
+    PREEMPT_MODE_STOP.store(true, Ordering::Relaxed);
+    if let Ok(handle) = other {
+        let _ = handle.join();
+    }
+    let _ = threading::set_preemptible(me, was_preemptible);
+    let missing = threading::set_preemptible(threading::max_threads(), true).is_err();
+
+    console::print(&format!(
+        "  cooperative: {}, not preempted: {}, preemptible again: {}, preempted: {}, unknown tid refused: {}\n",
+        made_coop, held, restored, shared, missing
+    ));
+
+    let ok = was_preemptible && made_coop && held && restored && shared && missing;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static PRIORITY_TEST_SPINS: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+static PRIORITY_TEST_STOP: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+/// Test: A bulk-priority thread doesn't take the CPU from a busier,
+/// more urgent one
+fn test_thread_priority() -> bool {
+    use crate::sched_policy::{PRIORITY_BULK, Priority, SchedParams, SchedPolicy};
+    console::print("\n[TEST] Thread priorities\n");
+
+    // The tick only interrupts for a more urgent thread
+    let mut prio = Priority::new();
+    prio.enqueue(1, SchedParams { priority: PRIORITY_BULK, ..SchedParams::new() }, 0);
+    prio.enqueue(2, SchedParams::new(), 0);
+    let any = |_: usize| true;
+    let preempts = prio.preempts(1, &any) && !prio.preempts(2, &any);
+
+    PRIORITY_TEST_SPINS.store(0, Ordering::Relaxed);
+    PRIORITY_TEST_STOP.store(false, Ordering::Relaxed);
+    let worker = threading::spawn_joinable(|| {
+        while !PRIORITY_TEST_STOP.load(Ordering::Relaxed) {
+            PRIORITY_TEST_SPINS.fetch_add(1, Ordering::Relaxed);
+            core::hint::spin_loop();
+        }
+    });
+    let (tuned, held) = match &worker {
+        Ok(handle) => {
+            let tid = handle.thread_id();
+            let tuned = threading::set_priority(tid, PRIORITY_BULK).is_ok()
+                && threading::priority(tid) == Some(PRIORITY_BULK);
+            // Only the priority policy ranks threads; under the others the
+            // worker gets its turn
+            let ranked = threading::policy_name() == "priority";
+            let before = PRIORITY_TEST_SPINS.load(Ordering::Relaxed);
+            let start = crate::timer::uptime_us();
+            while crate::timer::uptime_us() - start < 40_000 {
+                core::hint::spin_loop();
+            }
+            let ran = PRIORITY_TEST_SPINS.load(Ordering::Relaxed) != before;
+            (tuned, !ranked || !ran)
+        }
+        Err(_) => (false, false),
+    };
+    PRIORITY_TEST_STOP.store(true, Ordering::Relaxed);
+    if let Ok(handle) = worker {
+        let _ = handle.join();
+    }
+
+    console::print(&format!(
+        "  policy: {}, tick preemption: {}, priority set: {}, bulk thread held off: {}\n",
+        threading::policy_name(),
+        preempts,
+        tuned,
+        held
+    ));
+
+    let ok = preempts && tuned && held;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: a contended Mutex blocks the waiter instead of letting it spin
+fn test_blocking_mutex() -> bool {
+    console::print("\n[TEST] Blocking mutex\n");
+
+    static MUTEX_TEST: threading::Mutex<u64> = threading::Mutex::new(0);
+
+    let contentions = threading::mutex_contentions();
+    let Ok(guard) = MUTEX_TEST.lock() else {
+        console::print("  Lock failed\n");
+        return false;
+    };
+    let refused = MUTEX_TEST.try_lock().is_none();
+
+    let worker = threading::spawn_joinable(|| {
+        if let Ok(mut value) = MUTEX_TEST.lock() {
+            *value += 1;
+        }
+    });
+    // Yield until the worker has found the mutex held and blocked on it
+    let mut blocked = false;
+    let start = crate::timer::uptime_us();
+    while crate::timer::uptime_us() - start < 100_000 {
+        threading::yield_now();
+        if MUTEX_TEST.waiters() == 1 && threading::blocked_count() > 0 {
+            blocked = true;
+            break;
+        }
+    }
+    let untouched = *guard == 0;
+    drop(guard);
+
+    let finished = worker.is_ok_and(|handle| handle.join().is_ok());
+    let value = MUTEX_TEST.lock().map_or(0, |value| *value);
+    let counted = threading::mutex_contentions() > contentions;
+
+    console::print(&format!(
+        "  try_lock refused: {}, waiter blocked: {}, data untouched while held: {}\n",
+        refused, blocked, untouched
+    ));
+    console::print(&format!(
+        "  worker finished: {}, value: {}, contention counted: {}, still locked: {}\n",
+        finished,
+        value,
+        counted,
+        MUTEX_TEST.is_locked()
+    ));
+
+    let ok = refused
+        && blocked
+        && untouched
+        && finished
+        && value == 1
+        && counted
+        && !MUTEX_TEST.is_locked();
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: connection futures are size-checked and split state is charged
+fn test_conn_budget() -> bool {
+    console::print("\n[TEST] Connection memory budget\n");
+
+    let service = network::Service::Other;
+    let before = conn_budget::stats(service);
+
+    let payload = [1u8; 64];
+    let future = conn_budget::admit::<256, _>(service, async move {
+        let _ = payload.len();
+    });
+    let admitted = conn_budget::stats(service);
+    let recorded = admitted.future_budget == 256
+        && admitted.future_bytes == core::mem::size_of_val(&future)
+        && admitted.future_bytes >= 64;
+    drop(future);
+
+    let split = HeapState::new(service, [0u8; 4096]);
+    let during = conn_budget::stats(service);
+    let charged = split.as_ref().is_ok_and(|buf| buf.len() == 4096)
+        && during.heap_bytes == before.heap_bytes + 4096
+        && during.splits == before.splits + 1
+        && during.peak_heap_bytes >= during.heap_bytes;
+    drop(split);
+    let after = conn_budget::stats(service);
+    let released = after.heap_bytes == before.heap_bytes && after.splits == before.splits;
+    let reported = conn_budget::report().contains("other future");
+
+    console::print(&format!(
+        "  future: {}/{} bytes, split charged: {}, released: {}, reported: {}\n",
+        admitted.future_bytes, admitted.future_budget, charged, released, reported
+    ));
+
+    let ok = recorded && charged && released && reported;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: boot URLs, fetched config files and authorized_keys are parsed
+fn test_netboot_parsing() -> bool {
+    use crate::config;
+    use crate::netboot::{self, BootUrl, Scheme};
+
+    console::print("\n[TEST] Network boot configuration parsing\n");
+
+    let tftp = BootUrl::parse("tftp://10.0.2.2/akuma.conf");
+    let http = BootUrl::parse("http://10.0.2.2:8000/keys");
+    let urls = tftp.as_ref().is_ok_and(|u| {
+        u.scheme == Scheme::Tftp && u.addr == [10, 0, 2, 2] && u.port == 69 && u.path == "/akuma.conf"
+    }) && http
+        .as_ref()
+        .is_ok_and(|u| u.scheme == Scheme::Http && u.port == 8000 && u.path == "/keys");
+    let rejected = ["ftp://10.0.2.2/x", "tftp://example.com/x", "tftp://10.0.2.2", "http://10.0.2/x"]
+        .iter()
+        .all(|bad| BootUrl::parse(bad).is_err());
+
+    let applied = netboot::apply_config(
+        "# comment\n\ntest.netboot.a = 1\ntest.netboot.b=two # trailing\nnot a setting\n",
+    );
+    let settings = applied == 2
+        && config::get("test.netboot.a").as_deref() == Some("1")
+        && config::get("test.netboot.b").as_deref() == Some("two");
+    config::unset("test.netboot.a");
+    config::unset("test.netboot.b");
+
+    // Key bytes 0..32
+    let line = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f test-netboot@host";
+    let expected: [u8; 32] = core::array::from_fn(|i| i as u8);
+    let parsed = netboot::parse_authorized_key(line)
+        .is_some_and(|(user, key)| user == "test-netboot" && key == expected);
+    let skipped = netboot::parse_authorized_key("ssh-rsa AAAAB3NzaC1yc2E bob").is_none()
+        && netboot::parse_authorized_key("ssh-ed25519 !!!! bob").is_none();
+    let installed = netboot::apply_authorized_keys(line) == 1
+        && config::get("auth.test-netboot.ed25519").as_deref()
+            == Some("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+    config::unset("auth.test-netboot.ed25519");
+
+    console::print(&format!(
+        "  urls: {}, bad urls rejected: {}, config lines applied: {}\n",
+        urls, rejected, settings
+    ));
+    console::print(&format!(
+        "  key parsed: {}, other lines skipped: {}, key installed: {}\n",
+        parsed, skipped, installed
+    ));
+
+    let ok = urls && rejected && settings && parsed && skipped && installed;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: a Condvar waiter blocks until notified, without polling
+fn test_condvar() -> bool {
+    console::print("\n[TEST] Condition variable\n");
+
+    static QUEUE: threading::Mutex<VecDeque<u32>> = threading::Mutex::new(VecDeque::new());
+    static NOT_EMPTY: threading::Condvar = threading::Condvar::new();
+
+    let nobody = !NOT_EMPTY.notify_one() && NOT_EMPTY.notify_all() == 0;
+
+    let consumer = threading::spawn_joinable(|| {
+        let mut queue = QUEUE
+            .lock()
+            .and_then(|queue| NOT_EMPTY.wait_while(queue, |queue| queue.is_empty()))
+            .ok()?;
+        queue.pop_front()
+    });
+    // Yield until the consumer has found the queue empty and blocked
+    let mut waited = false;
+    let start = crate::timer::uptime_us();
+    while crate::timer::uptime_us() - start < 100_000 {
+        threading::yield_now();
+        if NOT_EMPTY.waiters() == 1 {
+            waited = true;
+            break;
+        }
+    }
+
+    if let Ok(mut queue) = QUEUE.lock() {
+        queue.push_back(42);
+    }
+    let notified = NOT_EMPTY.notify_one();
+    let received = match consumer {
+        Ok(handle) => handle.join().ok().flatten(),
+        Err(_) => None,
+    };
+
+    console::print(&format!(
+        "  notify without waiters: {}, consumer waited: {}, notified: {}, received: {:?}\n",
+        nobody, waited, notified, received
+    ));
+
+    let ok = nobody
+        && waited
+        && notified
+        && received == Some(42)
+        && QUEUE.lock().is_ok_and(|queue| queue.is_empty());
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: driver hooks run in registration order (reverse going down) and
+/// a failed suspend is rolled back
+fn test_driver_hooks() -> bool {
+    use crate::driver::{self, Driver};
+    use crate::error::{ErrorKind, KError, KResult};
+    use alloc::sync::Arc;
+
+    console::print("\n[TEST] Driver power hooks\n");
+
+    static CALLS: threading::Mutex<Vec<&'static str>> = threading::Mutex::new(Vec::new());
+    static FAIL_SUSPEND: AtomicBool = AtomicBool::new(false);
+
+    struct TestDriver(&'static str, &'static str, &'static str, &'static str);
+
+    impl Driver for TestDriver {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn suspend(&self) -> KResult<()> {
+            if self.0 == "test-bus" && FAIL_SUSPEND.load(Ordering::Relaxed) {
+                return Err(KError::with_context(ErrorKind::Unsupported, "test"));
+            }
+            CALLS.lock()?.push(self.1);
+            Ok(())
+        }
+
+        fn resume(&self) -> KResult<()> {
+            CALLS.lock()?.push(self.2);
+            Ok(())
+        }
+
+        fn shutdown(&self) -> KResult<()> {
+            CALLS.lock()?.push(self.3);
+            Ok(())
+        }
+    }
+
+    let registered = driver::register(Arc::new(TestDriver("test-bus", "bus-", "bus+", "bus!"))).is_ok()
+        && driver::register(Arc::new(TestDriver("test-dev", "dev-", "dev+", "dev!"))).is_ok();
+    let duplicate = driver::register(Arc::new(TestDriver("test-dev", "", "", "")))
+        .is_err_and(|e| e.kind() == ErrorKind::AlreadyExists);
+
+    let suspended = driver::suspend_all().is_ok();
+    let resumed = driver::resume_all() == 0;
+    let down = driver::shutdown_all() == 0;
+    let order_ok = CALLS
+        .lock()
+        .is_ok_and(|calls| *calls == ["dev-", "bus-", "bus+", "dev+", "dev!", "bus!"]);
+
+    // The bus refuses: the device, already suspended, is resumed again
+    if let Ok(mut calls) = CALLS.lock() {
+        calls.clear();
+    }
+    FAIL_SUSPEND.store(true, Ordering::Relaxed);
+    let refused = driver::suspend_all()
+        .is_err_and(|e| e.kind() == ErrorKind::Unsupported && e.context() == Some("test"));
+    let rolled_back = CALLS.lock().is_ok_and(|calls| *calls == ["dev-", "dev+"]);
+
+    let removed = driver::unregister("test-dev").is_ok()
+        && driver::unregister("test-bus").is_ok()
+        && !driver::names().contains(&"test-dev");
+
+    console::print(&format!(
+        "  registered: {}, duplicate refused: {}, suspend/resume/shutdown ok: {}, order: {}\n",
+        registered,
+        duplicate,
+        suspended && resumed && down,
+        order_ok
+    ));
+    console::print(&format!(
+        "  failed suspend: {}, rolled back: {}, unregistered: {}\n",
+        refused, rolled_back, removed
+    ));
+
+    let ok = registered
+        && duplicate
+        && suspended
+        && resumed
+        && down
+        && order_ok
+        && refused
+        && rolled_back
+        && removed;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: before the worker pool starts, offloaded closures run inline and
+/// resolve on the first poll
+fn test_worker_offload() -> bool {
+    use crate::workers;
+    use core::future::Future;
+    use core::task::{Context, Poll, Waker};
+
+    console::print("\n[TEST] Worker offload\n");
+
+    let before = workers::stats();
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut future = core::pin::pin!(workers::offload(|| (1..=10u32).product::<u32>()));
+    let result = future.as_mut().poll(&mut cx);
+    let after = workers::stats();
+
+    console::print(&format!(
+        "  workers: {}, result: {:?}, inline runs: {} -> {}\n",
+        after.workers, result, before.inline, after.inline
+    ));
+
+    let ok = before.workers == 0
+        && result == Poll::Ready(3_628_800)
+        && after.inline == before.inline + 1
+        && after.completed == before.completed;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: a named thread shows up in `for_each_thread` with its name,
+/// state, priority and stack
+fn test_thread_names() -> bool {
+    console::print("\n[TEST] Thread names and enumeration\n");
+
+    static NAMED_STOP: AtomicBool = AtomicBool::new(false);
+    NAMED_STOP.store(false, Ordering::Relaxed);
+    let spawned = threading::spawn_named("test-named", || {
+        while !NAMED_STOP.load(Ordering::Relaxed) {
+            threading::yield_now();
+        }
+    })
+    .ok();
+
+    let mut listed = 0;
+    let mut main_named = false;
+    let mut found = None;
+    threading::for_each_thread(|t| {
+        listed += 1;
+        if t.tid == 0 {
+            main_named = t.name == "main" && t.stack.is_none();
+        }
+        if Some(t.tid) == spawned {
+            found = Some(*t);
+        }
+    });
+    NAMED_STOP.store(true, Ordering::Relaxed);
+
+    console::print(&format!(
+        "  listed: {} (count {}), main named: {}, spawned: {:?}\n",
+        listed,
+        threading::thread_count(),
+        main_named,
+        found
+    ));
+
+    let ok = main_named
+        && listed >= 2
+        && found.is_some_and(|t| {
+            t.name == "test-named"
+                && t.state != threading::ThreadState::Terminated
+                && t.priority == crate::sched_policy::DEFAULT_PRIORITY
+                && t.stack.is_some_and(|(base, top)| top > base)
+        });
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+static DEFRAG_DROPS: AtomicUsize = AtomicUsize::new(0);
+static DEFRAG_MOVES: AtomicUsize = AtomicUsize::new(0);
+
+fn defrag_test_handler(hint: Hint) -> usize {
+    match hint {
+        Hint::DropCaches => DEFRAG_DROPS.fetch_add(1, Ordering::Relaxed),
+        Hint::Reallocate => DEFRAG_MOVES.fetch_add(1, Ordering::Relaxed),
+    };
+    100
+}
+
+/// Test: Defrag hints reach every handler once per hint, and reallocating
+/// keeps a buffer's contents in a right-sized allocation
+fn test_defrag_hints() -> bool {
+    console::print("\n[TEST] Defrag hints\n");
+
+    let mut vec: Vec<u32> = Vec::with_capacity(64);
+    vec.extend(0..10);
+    let moved = defrag::reallocate_vec(&mut vec);
+    let vec_ok = moved == 40 && vec.capacity() < 64 && vec.iter().copied().eq(0..10);
+
+    let mut ring: VecDeque<u32> = VecDeque::with_capacity(16);
+    ring.extend(0..8);
+    ring.drain(..4);
+    ring.extend(8..12);
+    defrag::reallocate_deque(&mut ring);
+    let ring_ok = ring.iter().copied().eq(4..12);
+
+    DEFRAG_DROPS.store(0, Ordering::Relaxed);
+    DEFRAG_MOVES.store(0, Ordering::Relaxed);
+    let registered = defrag::register("test", defrag_test_handler).is_ok();
+    let duplicate = defrag::register("test", defrag_test_handler).is_err();
+    let report = defrag::run();
+    let removed = defrag::unregister("test").is_ok();
+
+    let calls_ok =
+        DEFRAG_DROPS.load(Ordering::Relaxed) == 1 && DEFRAG_MOVES.load(Ordering::Relaxed) == 1;
+    let report_ok = report.handlers >= 1
+        && report.released >= 100
+        && report.moved >= 100
+        && report.after.largest <= report.after.free_bytes;
+
+    console::print(&format!(
+        "  vec moved {} bytes ok {}, ring order ok {}\n",
+        moved, vec_ok, ring_ok
+    ));
+    console::print(&format!(
+        "  {} handler(s), released {}, moved {}, {}% -> {}% fragmented\n",
+        report.handlers,
+        report.released,
+        report.moved,
+        report.before.fragmentation_percent,
+        report.after.fragmentation_percent
+    ));
+
+    let ok = vec_ok && ring_ok && registered && duplicate && removed && calls_ok && report_ok;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Whether thread `tid` has terminated (or already been reclaimed)
+fn thread_finished(tid: usize) -> bool {
+    let mut finished = true;
+    threading::for_each_thread(|t| {
+        if t.tid == tid && t.state != threading::ThreadState::Terminated {
+            finished = false;
+        }
+    });
+    finished
+}
+
+/// Test: `threading::exit` stops a thread on the spot, and returning from
+/// the closure terminates it the same way
+fn test_thread_exit() -> bool {
+    console::print("\n[TEST] Thread exit\n");
+
+    static EXIT_EARLY: AtomicBool = AtomicBool::new(true);
+    static PAST_EXIT: AtomicBool = AtomicBool::new(false);
+    static RETURNED: AtomicBool = AtomicBool::new(false);
+    PAST_EXIT.store(false, Ordering::Relaxed);
+    RETURNED.store(false, Ordering::Relaxed);
+
+    let exiting = threading::spawn_fn(|| {
+        if EXIT_EARLY.load(Ordering::Relaxed) {
+            threading::exit();
+        }
+        PAST_EXIT.store(true, Ordering::Relaxed);
+    });
+    let returning = threading::spawn_fn(|| {
+        RETURNED.store(true, Ordering::Relaxed);
+    });
+    let (Ok(exiting), Ok(returning)) = (exiting, returning) else {
+        console::print("  Spawn failed\n");
+        return false;
+    };
+
+    let mut finished = false;
+    for _ in 0..100 {
+        threading::yield_now();
+        if thread_finished(exiting) && thread_finished(returning) {
+            finished = true;
+            break;
+        }
+    }
+    let past_exit = PAST_EXIT.load(Ordering::Relaxed);
+    let returned = RETURNED.load(Ordering::Relaxed);
+
+    console::print(&format!(
+        "  finished: {}, ran past exit: {}, returned: {}\n",
+        finished, past_exit, returned
+    ));
+
+    let ok = finished && !past_exit && returned;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: `spawn_with_stack` runs the thread on a stack of the requested
+/// size and rejects sizes out of range
+fn test_thread_stack_size() -> bool {
+    console::print("\n[TEST] Per-thread stack size\n");
+
+    static STACK_SEEN: AtomicUsize = AtomicUsize::new(0);
+    STACK_SEEN.store(0, Ordering::Relaxed);
+
+    let too_small = threading::spawn_with_stack(|| {}, 1024).is_err();
+    let too_large = threading::spawn_with_stack(|| {}, 64 * 1024 * 1024).is_err();
+
+    let tid = threading::spawn_with_stack(
+        || {
+            if let Some((base, top)) = threading::current_stack_bounds() {
+                STACK_SEEN.store(top - base, Ordering::Relaxed);
+            }
+        },
+        64 * 1024,
+    );
+    let Ok(tid) = tid else {
+        console::print("  Spawn failed\n");
+        return false;
+    };
+    for _ in 0..100 {
+        threading::yield_now();
+        if thread_finished(tid) {
+            break;
+        }
+    }
+    let seen = STACK_SEEN.load(Ordering::Relaxed);
+
+    // Reaping gives the slot a default stack back, so plain spawns still fit
+    threading::cleanup_terminated();
+    let plain = threading::spawn_fn(|| {}).is_ok();
+
+    console::print(&format!(
+        "  stack seen: {} bytes, rejected 1 KiB: {}, rejected 64 MiB: {}, plain spawn after: {}\n",
+        seen, too_small, too_large, plain
+    ));
+
+    let ok = seen == 64 * 1024 && too_small && too_large && plain;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: the stack high-water mark grows with the deepest frame a thread
+/// used and stays put after it returns from it
+fn test_stack_high_water() -> bool {
+    console::print("\n[TEST] Stack high-water mark\n");
+
+    static HW_PHASE: AtomicUsize = AtomicUsize::new(0);
+    static HW_STOP: AtomicBool = AtomicBool::new(false);
+    HW_PHASE.store(0, Ordering::Relaxed);
+    HW_STOP.store(false, Ordering::Relaxed);
+
+    #[inline(never)]
+    fn touch_deep() {
+        let buf = [0x11u8; 8 * 1024];
+        core::hint::black_box(&buf);
+    }
+
+    let tid = threading::spawn_fn(|| {
+        HW_PHASE.store(1, Ordering::Release);
+        while HW_PHASE.load(Ordering::Acquire) == 1 {
+            threading::yield_now();
+        }
+        touch_deep();
+        HW_PHASE.store(3, Ordering::Release);
+        while !HW_STOP.load(Ordering::Acquire) {
+            threading::yield_now();
+        }
+    });
+    let Ok(tid) = tid else {
+        console::print("  Spawn failed\n");
+        return false;
+    };
+
+    let wait_for = |phase: usize| {
+        for _ in 0..100 {
+            if HW_PHASE.load(Ordering::Acquire) == phase {
+                return true;
+            }
+            threading::yield_now();
+        }
+        false
+    };
+
+    let started = wait_for(1);
+    let shallow = threading::stack_high_water(tid);
+    HW_PHASE.store(2, Ordering::Release);
+    let touched = wait_for(3);
+    let deep = threading::stack_high_water(tid);
+    HW_STOP.store(true, Ordering::Release);
+    let boot = threading::stack_high_water(0);
+
+    console::print(&format!(
+        "  shallow: {:?}, after 8 KiB frame: {:?}, boot: {:?}\n",
+        shallow, deep, boot
+    ));
+
+    let ok = started
+        && touched
+        && boot.is_none()
+        && match (shallow, deep) {
+            (Some((low, size)), Some((high, _))) => {
+                low > 0 && high >= low && high >= 8 * 1024 && high < size
+            }
+            _ => false,
+        };
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: each thread sees its own `ThreadLocal` value, starting from 0
+fn test_thread_local() -> bool {
+    console::print("\n[TEST] Thread-local storage\n");
+
+    static TLS_VALUE: threading::ThreadLocal = threading::ThreadLocal::new();
+
+    let main_set = TLS_VALUE.set(7).is_ok();
+    let spawn = |value: usize| {
+        threading::spawn_joinable(move || {
+            let initial = TLS_VALUE.get();
+            let _ = TLS_VALUE.set(value);
+            for _ in 0..5 {
+                threading::yield_now();
+            }
+            (initial, TLS_VALUE.get())
+        })
+    };
+    let (Ok(a), Ok(b)) = (spawn(100), spawn(200)) else {
+        console::print("  Spawn failed\n");
+        return false;
+    };
+    let (Ok((a_initial, a_after)), Ok((b_initial, b_after))) = (a.join(), b.join()) else {
+        console::print("  Join failed\n");
+        return false;
+    };
+    let main_after = TLS_VALUE.get();
+    let _ = TLS_VALUE.set(0);
+
+    console::print(&format!(
+        "  thread a: {} -> {}, thread b: {} -> {}, main: {}\n",
+        a_initial, a_after, b_initial, b_after, main_after
+    ));
+
+    let ok = main_set
+        && a_initial == 0
+        && b_initial == 0
+        && a_after == 100
+        && b_after == 200
+        && main_after == 7;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: a parked thread stays off the CPU until unparked, and an unpark
+/// that comes first isn't lost
+fn test_park_unpark() -> bool {
+    console::print("\n[TEST] Park and unpark\n");
+
+    // Token first: park returns at once
+    let me = threading::current_thread_id();
+    let token_ok = threading::unpark(me).is_ok();
+    threading::park();
+
+    static PARK_GO: AtomicBool = AtomicBool::new(false);
+    static PARK_WAKEUPS: AtomicUsize = AtomicUsize::new(0);
+    PARK_GO.store(false, Ordering::Release);
+    PARK_WAKEUPS.store(0, Ordering::Relaxed);
+
+    let parks_before = threading::parks();
+    let Ok(tid) = threading::spawn_fn(|| {
+        while !PARK_GO.load(Ordering::Acquire) {
+            threading::park();
+            PARK_WAKEUPS.fetch_add(1, Ordering::Relaxed);
+        }
+    }) else {
+        console::print("  Spawn failed\n");
+        return false;
+    };
+
+    // Let it park, then check it stays parked while others run
+    for _ in 0..10 {
+        threading::yield_now();
+    }
+    let stayed_parked =
+        PARK_WAKEUPS.load(Ordering::Relaxed) == 0 && threading::blocked_count() >= 1;
+
+    PARK_GO.store(true, Ordering::Release);
+    let unparked = threading::unpark(tid).is_ok();
+    let mut finished = false;
+    for _ in 0..100 {
+        threading::yield_now();
+        if thread_finished(tid) {
+            finished = true;
+            break;
+        }
+    }
+    let parked = threading::parks() - parks_before;
+    let bad_tid = threading::unpark(threading::max_threads()).is_err();
+
+    console::print(&format!(
+        "  token: {}, stayed parked: {}, woken: {}, finished: {}, parks: {}\n",
+        token_ok,
+        stayed_parked,
+        PARK_WAKEUPS.load(Ordering::Relaxed),
+        finished,
+        parked
+    ));
+
+    let ok = token_ok && stayed_parked && unparked && finished && parked >= 1 && bad_tid;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: `idle_unless` only waits for an interrupt when the caller has no
+/// work and no other thread is ready, and then returns on the next one
+fn test_idle_unless() -> bool {
+    console::print("\n[TEST] Idle until interrupt\n");
+
+    let busy_skipped = !threading::idle_unless(|| true);
+
+    static IDLE_STOP: AtomicBool = AtomicBool::new(false);
+    IDLE_STOP.store(false, Ordering::Release);
+    let Ok(tid) = threading::spawn_fn(|| {
+        while !IDLE_STOP.load(Ordering::Acquire) {
+            threading::yield_now();
+        }
+    }) else {
+        console::print("  Spawn failed\n");
+        return false;
+    };
+    // Spawned and not yet run, so it is Ready
+    let ready_skipped = !threading::idle_unless(|| false);
+    IDLE_STOP.store(true, Ordering::Release);
+    for _ in 0..100 {
+        threading::yield_now();
+        if thread_finished(tid) {
+            break;
+        }
+    }
+
+    // Whatever else is ready now, a wait must be counted and must end
+    let waits_before = threading::idle_waits();
+    let start = crate::timer::uptime_us();
+    let waited = threading::idle_unless(|| false);
+    let elapsed = crate::timer::uptime_us() - start;
+    let counted = threading::idle_waits() - waits_before == waited as u64;
+
+    console::print(&format!(
+        "  busy skipped: {}, ready thread skipped: {}, waited: {} ({} us), counted: {}\n",
+        busy_skipped, ready_skipped, waited, elapsed, counted
+    ));
+
+    let ok = busy_skipped && ready_skipped && counted;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: the tick interval and per-priority slices can be changed at
+/// runtime, and a band without its own slice follows the global one
+fn test_sched_tunables() -> bool {
+    console::print("\n[TEST] Scheduler tunables\n");
+
+    let tick = crate::timer::tick_interval_us();
+    let bad_ticks_refused = crate::timer::set_tick_interval_us(0).is_err()
+        && crate::timer::set_tick_interval_us(crate::timer::MAX_TICK_US + 1).is_err()
+        && crate::timer::tick_interval_us() == tick;
+    let tick_set = crate::timer::set_tick_interval_us(crate::timer::MIN_TICK_US).is_ok()
+        && crate::timer::tick_interval_us() == crate::timer::MIN_TICK_US;
+    // Let a few ticks run at the short interval before restoring it
+    threading::sleep_ms(5);
+    let tick_restored = crate::timer::set_tick_interval_us(tick).is_ok();
+
+    let bulk = crate::sched_policy::PRIORITY_BULK;
+    let network = crate::sched_policy::PRIORITY_NETWORK;
+    let global = threading::slice_us();
+    let bulk_before = threading::priority_slice_us(bulk);
+    threading::set_priority_slice_us(bulk, 40_000);
+    let band_set = threading::priority_slice_us(bulk) == 40_000
+        && threading::priority_slice_us(bulk + 1) == 40_000
+        && threading::priority_slice_us(network) != 40_000;
+    threading::set_priority_slice_us(bulk, 0);
+    let band_cleared = threading::priority_slice_us(bulk) == global;
+    if bulk_before != global {
+        threading::set_priority_slice_us(bulk, bulk_before);
+    }
+
+    console::print(&format!(
+        "  tick {} us, bad ticks refused: {}, set: {}, restored: {}\n",
+        tick, bad_ticks_refused, tick_set, tick_restored
+    ));
+    console::print(&format!(
+        "  global slice {} us, band set: {}, band cleared: {}\n",
+        global, band_set, band_cleared
+    ));
+
+    let ok = bad_ticks_refused && tick_set && tick_restored && band_set && band_cleared;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: a thread that spins is charged for its CPU time, and the time
+/// shows up in `for_each_thread`
+fn test_cpu_time() -> bool {
+    console::print("\n[TEST] Per-thread CPU time\n");
+
+    static SPUN: AtomicBool = AtomicBool::new(false);
+    static CPU_STOP: AtomicBool = AtomicBool::new(false);
+    SPUN.store(false, Ordering::Release);
+    CPU_STOP.store(false, Ordering::Release);
+    let Ok(tid) = threading::spawn_fn(|| {
+        let start = crate::timer::uptime_us();
+        while crate::timer::uptime_us() - start < 20_000 {
+            core::hint::spin_loop();
+        }
+        SPUN.store(true, Ordering::Release);
+        while !CPU_STOP.load(Ordering::Acquire) {
+            threading::yield_now();
+        }
+    }) else {
+        console::print("  Spawn failed\n");
+        return false;
+    };
+    for _ in 0..1000 {
+        if SPUN.load(Ordering::Acquire) {
+            break;
+        }
+        threading::yield_now();
+    }
+
+    let spun = SPUN.load(Ordering::Acquire);
+    let cpu = threading::cpu_time_us(tid).unwrap_or(0);
+    let mut listed = 0;
+    threading::for_each_thread(|t| {
+        if t.tid == tid {
+            listed = t.cpu_time_us;
+        }
+    });
+    let main_cpu = threading::cpu_time_us(0).unwrap_or(0);
+    CPU_STOP.store(true, Ordering::Release);
+    for _ in 0..100 {
+        threading::yield_now();
+        if thread_finished(tid) {
+            break;
+        }
+    }
+    let bad_tid = threading::cpu_time_us(threading::max_threads()).is_none();
+
+    console::print(&format!(
+        "  spun: {}, cpu: {} us, listed: {} us, main: {} us\n",
+        spun, cpu, listed, main_cpu
+    ));
+
+    let ok = spun && cpu >= 20_000 && listed >= cpu && main_cpu > 0 && bad_tid;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: a low-priority Mutex holder runs at the priority of a waiting
+/// high-priority thread until it unlocks
+fn test_priority_inheritance() -> bool {
+    use crate::sched_policy::{PRIORITY_BULK, PRIORITY_NETWORK};
+    console::print("\n[TEST] Mutex priority inheritance\n");
+
+    static PI_MUTEX: threading::Mutex<u32> = threading::Mutex::new(0);
+    static PI_HOLDING: AtomicBool = AtomicBool::new(false);
+    static PI_RELEASE: AtomicBool = AtomicBool::new(false);
+    static PI_AFTER: AtomicUsize = AtomicUsize::new(0);
+    PI_HOLDING.store(false, Ordering::Release);
+    PI_RELEASE.store(false, Ordering::Release);
+    PI_AFTER.store(0, Ordering::Release);
+
+    let boosts_before = threading::priority_boosts();
+    let Ok(low) = threading::spawn_fn(|| {
+        let Ok(mut guard) = PI_MUTEX.lock() else {
+            return;
+        };
+        PI_HOLDING.store(true, Ordering::Release);
+        while !PI_RELEASE.load(Ordering::Acquire) {
+            threading::yield_now();
+        }
+        *guard += 1;
+        drop(guard);
+        let own = threading::priority(threading::current_thread_id()).unwrap_or(0);
+        PI_AFTER.store(own as usize, Ordering::Release);
+    }) else {
+        console::print("  Spawn failed\n");
+        return false;
+    };
+    let _ = threading::set_priority(low, PRIORITY_BULK);
+    // Sleep rather than yield, so the bulk thread gets the CPU under the
+    // priority policy too
+    for _ in 0..100 {
+        if PI_HOLDING.load(Ordering::Acquire) {
+            break;
+        }
+        threading::sleep_ms(1);
+    }
+
+    let high = threading::spawn_fn(|| {
+        if let Ok(mut value) = PI_MUTEX.lock() {
+            *value += 1;
+        }
+    });
+    if let Ok(tid) = high {
+        let _ = threading::set_priority(tid, PRIORITY_NETWORK);
+    }
+    for _ in 0..100 {
+        if PI_MUTEX.waiters() == 1 {
+            break;
+        }
+        threading::sleep_ms(1);
+    }
+    let boosted = threading::priority(low) == Some(PRIORITY_NETWORK);
+
+    PI_RELEASE.store(true, Ordering::Release);
+    for _ in 0..100 {
+        if PI_MUTEX.lock().is_ok_and(|value| *value == 2) {
+            break;
+        }
+        threading::sleep_ms(1);
+    }
+    let both_ran = PI_MUTEX.lock().is_ok_and(|value| *value == 2);
+    for _ in 0..100 {
+        if PI_AFTER.load(Ordering::Acquire) != 0 {
+            break;
+        }
+        threading::sleep_ms(1);
+    }
+    let restored = PI_AFTER.load(Ordering::Acquire) == PRIORITY_BULK as usize;
+    let counted = threading::priority_boosts() > boosts_before;
+
+    console::print(&format!(
+        "  holder boosted: {}, restored after unlock: {}, both ran: {}, counted: {}\n",
+        boosted, restored, both_ran, counted
+    ));
+
+    let ok = high.is_ok() && boosted && restored && both_ran && counted;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: `kill` stops a thread that never yields, exit hooks see it
+/// reaped, and a killed Mutex waiter doesn't swallow the next wakeup
+fn test_thread_kill() -> bool {
+    console::print("\n[TEST] Kill thread\n");
+
+    static KILL_SPINS: AtomicU64 = AtomicU64::new(0);
+    static KILL_HOOKED: AtomicU32 = AtomicU32::new(0);
+    KILL_SPINS.store(0, Ordering::Relaxed);
+    KILL_HOOKED.store(0, Ordering::Relaxed);
+    fn hook(tid: usize) {
+        KILL_HOOKED.fetch_or(1 << tid, Ordering::Relaxed);
+    }
+    let hooked = threading::register_exit_hook("test", hook).is_ok();
+
+    // A spinner that never yields
+    let Ok(spinner) = threading::spawn_fn(|| {
+        loop {
+            KILL_SPINS.fetch_add(1, Ordering::Relaxed);
+            core::hint::spin_loop();
+        }
+    }) else {
+        console::print("  Spawn failed\n");
+        return false;
+    };
+    let start = crate::timer::uptime_us();
+    while KILL_SPINS.load(Ordering::Relaxed) == 0
+        && crate::timer::uptime_us() - start < 100_000
+    {
+        threading::yield_now();
+    }
+    let spun = KILL_SPINS.load(Ordering::Relaxed) > 0;
+    let killed = threading::kill(spinner).is_ok();
+    let spins = KILL_SPINS.load(Ordering::Relaxed);
+    threading::sleep_ms(30);
+    let stopped = KILL_SPINS.load(Ordering::Relaxed) == spins && thread_finished(spinner);
+    let again_refused = threading::kill(spinner).is_err();
+    let boot_refused = threading::kill(0).is_err();
+
+    // Kill the first of two Mutex waiters; unlock must reach the second
+    static KILL_MUTEX: threading::Mutex<u32> = threading::Mutex::new(0);
+    let Ok(guard) = KILL_MUTEX.lock() else {
+        console::print("  Lock failed\n");
+        return false;
+    };
+    let first = threading::spawn_fn(|| {
+        if let Ok(mut value) = KILL_MUTEX.lock() {
+            *value += 100;
+        }
+    });
+    for _ in 0..100 {
+        if KILL_MUTEX.waiters() == 1 {
+            break;
+        }
+        threading::sleep_ms(1);
+    }
+    let second = threading::spawn_fn(|| {
+        if let Ok(mut value) = KILL_MUTEX.lock() {
+            *value += 1;
+        }
+    });
+    for _ in 0..100 {
+        if KILL_MUTEX.waiters() == 2 {
+            break;
+        }
+        threading::sleep_ms(1);
+    }
+    let waiter_killed = first.is_ok_and(|tid| threading::kill(tid).is_ok());
+    drop(guard);
+    let mut handed_on = false;
+    for _ in 0..100 {
+        threading::sleep_ms(1);
+        if KILL_MUTEX.lock().is_ok_and(|value| *value == 1) {
+            handed_on = true;
+            break;
+        }
+    }
+
+    // A killed waiter's queue entry must not wake whoever gets its slot
+    static KILL_REUSE: threading::Mutex<()> = threading::Mutex::new(());
+    static REUSE_WOKEN: AtomicBool = AtomicBool::new(false);
+    REUSE_WOKEN.store(false, Ordering::Release);
+    let Ok(guard) = KILL_REUSE.lock() else {
+        console::print("  Lock failed
+");
+        return false;
+    };
+    let doomed = threading::spawn_fn(|| {
+        let _ = KILL_REUSE.lock();
+    });
+    for _ in 0..100 {
+        if KILL_REUSE.waiters() == 1 {
+            break;
+        }
+        threading::sleep_ms(1);
+    }
+    let doomed_killed = doomed.is_ok_and(|tid| threading::kill(tid).is_ok());
+    let queue_emptied = KILL_REUSE.waiters() == 0;
+    threading::cleanup_terminated();
+    let parks = threading::parks();
+    let parker = threading::spawn_fn(|| {
+        threading::park();
+        REUSE_WOKEN.store(true, Ordering::Release);
+    });
+    for _ in 0..100 {
+        if threading::parks() > parks {
+            break;
+        }
+        threading::sleep_ms(1);
+    }
+    drop(guard);
+    threading::sleep_ms(10);
+    let not_woken = !REUSE_WOKEN.load(Ordering::Acquire);
+    let _ = parker.and_then(threading::unpark);
+
+    // Killed before it ever ran: reaping frees its closure and what that
+    // captured
+    let captured = alloc::sync::Arc::new(());
+    let unstarted = {
+        let capture = captured.clone();
+        sched::no_preempt(|| threading::spawn_fn(move || drop(capture)).and_then(threading::kill))
+    };
+
+    threading::cleanup_terminated();
+    let hook_ran = KILL_HOOKED.load(Ordering::Relaxed) & (1 << spinner) != 0;
+    let _ = threading::unregister_exit_hook("test");
+    let closure_freed = unstarted.is_ok() && alloc::sync::Arc::strong_count(&captured) == 1;
+
+    console::print(&format!(
+        "  spun: {}, killed: {}, stopped: {}, refused again: {}, thread 0 refused: {}\n",
+        spun, killed, stopped, again_refused, boot_refused
+    ));
+    console::print(&format!(
+        "  waiter killed: {}, lock handed on: {}, exit hook: {}, unstarted closure freed: {}\n",
+        waiter_killed, handed_on, hook_ran, closure_freed
+    ));
+    console::print(&format!(
+        "  killed waiter dequeued: {}, slot reused by {:?}, reused slot left parked: {}\n",
+        doomed_killed && queue_emptied,
+        parker,
+        not_woken
+    ));
+
+    let ok = hooked
+        && spun
+        && killed
+        && stopped
+        && again_refused
+        && boot_refused
+        && second.is_ok()
+        && waiter_killed
+        && handed_on
+        && doomed_killed
+        && queue_emptied
+        && parker.is_ok()
+        && not_woken
+        && hook_ran
+        && closure_freed;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: a cooperative thread that stops yielding is reported with a
+/// backtrace of where the tick caught it
+fn test_lockup_backtrace() -> bool {
+    console::print("\n[TEST] Cooperative lockup backtrace\n");
+
+    static LOCKUP_STARTED: AtomicBool = AtomicBool::new(false);
+    LOCKUP_STARTED.store(false, Ordering::Release);
+    let old_timeout = threading::cooperative_timeout_us();
+    threading::set_cooperative_timeout_us(30_000);
+    let timeouts_before = threading::cooperative_timeouts();
+    let start = klog::next_seq();
+
+    // Preempt policy: reported, then made preemptible until killed
+    let spawned = threading::spawn_fn_cooperative_with_policy(
+        || {
+            LOCKUP_STARTED.store(true, Ordering::Release);
+            loop {
+                core::hint::spin_loop();
+            }
+        },
+        threading::TimeoutPolicy::Preempt,
+    );
+    threading::set_cooperative_timeout_us(old_timeout);
+    let Ok(tid) = spawned else {
+        console::print("  Spawn failed\n");
+        return false;
+    };
+
+    for _ in 0..1000 {
+        if threading::cooperative_timeouts() > timeouts_before {
+            break;
+        }
+        threading::yield_now();
+    }
+    let reported = threading::cooperative_timeouts() > timeouts_before;
+    let killed = threading::kill(tid).is_ok();
+
+    // The report was staged in interrupt context; give it time to land
+    let filter = Filter {
+        max_level: Level::Trace,
+        module: Some("threading"),
+    };
+    let mut frames = 0;
+    for _ in 0..50 {
+        let (records, _) = klog::records_since(start, &filter);
+        frames = records.iter().filter(|r| r.text.starts_with("  #")).count();
+        if frames > 0 {
+            break;
+        }
+        threading::sleep_ms(2);
+    }
+
+    console::print(&format!(
+        "  started: {}, reported: {}, frames logged: {}, killed: {}\n",
+        LOCKUP_STARTED.load(Ordering::Acquire),
+        reported,
+        frames,
+        killed
+    ));
+
+    let ok = LOCKUP_STARTED.load(Ordering::Acquire) && reported && frames >= 1 && killed;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: lockdep records the order two locks were taken in and flags the
+/// reverse (skipped without the `lockdep` feature)
+fn test_lockdep_order() -> bool {
+    use crate::lockdep::{self, Spinlock};
+    use core::any::type_name;
+    console::print("\n[TEST] Lock dependency order\n");
+
+    if !lockdep::ENABLED {
+        console::print("  lockdep feature off, skipped\n");
+        console::print("  Result: PASS\n");
+        return true;
+    }
+
+    struct LockdepFirst;
+    struct LockdepSecond;
+    static FIRST: Spinlock<LockdepFirst> = Spinlock::new(LockdepFirst);
+    static SECOND: threading::Mutex<LockdepSecond> = threading::Mutex::new(LockdepSecond);
+    let first = type_name::<Spinlock<LockdepFirst>>();
+    let second = type_name::<threading::Mutex<LockdepSecond>>();
+
+    let before = lockdep::stats();
+    {
+        let _first = FIRST.lock();
+        let _second = SECOND.lock();
+    }
+    let after = lockdep::stats();
+    let recorded = after.classes >= before.classes + 2 && after.dependencies > before.dependencies;
+    let reverse_flagged = lockdep::inversion(second, first);
+    let forward_fine = !lockdep::inversion(first, second);
+
+    console::print(&format!(
+        "  classes: {}, dependencies: {}, recorded: {}, reverse flagged: {}, forward fine: {}\n",
+        after.classes, after.dependencies, recorded, reverse_flagged, forward_fine
+    ));
+
+    let ok = recorded && reverse_flagged && forward_fine;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: queued work runs, delayed work waits for its delay and cancelled
+/// work never runs
+fn test_workqueue() -> bool {
+    use crate::workqueue;
+    console::print("\n[TEST] Work queue\n");
+
+    static RAN: AtomicU32 = AtomicU32::new(0);
+    const NOW: u32 = 1;
+    const DELAYED: u32 = 2;
+    const CANCELLED: u32 = 4;
+
+    let before = workqueue::stats();
+    let queued = workqueue::queue(|| {
+        RAN.fetch_or(NOW, Ordering::AcqRel);
+    })
+    .is_ok();
+    let delayed = workqueue::queue_delayed(
+        || {
+            RAN.fetch_or(DELAYED, Ordering::AcqRel);
+        },
+        20_000,
+    );
+    let withdrawn = workqueue::queue_delayed(
+        || {
+            RAN.fetch_or(CANCELLED, Ordering::AcqRel);
+        },
+        20_000,
+    );
+    let cancelled = withdrawn.is_ok_and(workqueue::cancel);
+
+    // Before the workers start (the boot tests), flush the queue here
+    workqueue::run_pending();
+    let early = RAN.load(Ordering::Acquire);
+
+    for _ in 0..50 {
+        workqueue::run_pending();
+        if RAN.load(Ordering::Acquire) & DELAYED != 0 {
+            break;
+        }
+        threading::sleep_ms(2);
+    }
+    let ran = RAN.load(Ordering::Acquire);
+    let after = workqueue::stats();
+
+    console::print(&format!(
+        "  queued: {}, delayed: {}, cancelled: {}, ran early: {:#x}, ran: {:#x}, completed: {}\n",
+        queued,
+        delayed.is_ok(),
+        cancelled,
+        early,
+        ran,
+        after.completed - before.completed
+    ));
+
+    let ok = queued
+        && delayed.is_ok()
+        && cancelled
+        && early & NOW != 0
+        && early & DELAYED == 0
+        && ran & DELAYED != 0
+        && ran & CANCELLED == 0
+        && after.cancelled == before.cancelled + 1;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: a barrier holds every thread until all have arrived, names one
+/// leader per round and can be reused
+fn test_barrier() -> bool {
+    console::print("\n[TEST] Barrier\n");
+
+    const PARTIES: usize = 4;
+    const ROUNDS: usize = 2;
+    static BARRIER: threading::Barrier = threading::Barrier::new(PARTIES);
+    static ARRIVED: AtomicUsize = AtomicUsize::new(0);
+    static EARLY: AtomicUsize = AtomicUsize::new(0);
+    static LEADERS: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    ARRIVED.store(0, Ordering::Release);
+    EARLY.store(0, Ordering::Release);
+    LEADERS.store(0, Ordering::Release);
+    DONE.store(0, Ordering::Release);
+
+    fn rendezvous() {
+        for round in 1..=ROUNDS {
+            ARRIVED.fetch_add(1, Ordering::AcqRel);
+            let Ok(result) = BARRIER.wait() else {
+                return;
+            };
+            if ARRIVED.load(Ordering::Acquire) < round * PARTIES {
+                EARLY.fetch_add(1, Ordering::AcqRel);
+            }
+            if result.is_leader() {
+                LEADERS.fetch_add(1, Ordering::AcqRel);
+            }
+        }
+        DONE.fetch_add(1, Ordering::AcqRel);
+    }
+
+    for _ in 1..PARTIES {
+        if threading::spawn_fn(rendezvous).is_err() {
+            console::print("  Spawn failed\n");
+            return false;
+        }
+    }
+    rendezvous();
+    for _ in 0..100 {
+        if DONE.load(Ordering::Acquire) == PARTIES {
+            break;
+        }
+        threading::sleep_ms(1);
+    }
+
+    let early = EARLY.load(Ordering::Acquire);
+    let leaders = LEADERS.load(Ordering::Acquire);
+    let done = DONE.load(Ordering::Acquire);
+    console::print(&format!(
+        "  arrived: {}, released early: {}, leaders: {}, finished: {}\n",
+        ARRIVED.load(Ordering::Acquire),
+        early,
+        leaders,
+        done
+    ));
+
+    let ok = early == 0 && leaders == ROUNDS && done == PARTIES;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
+
+/// Test: a deadline thread runs ahead of normal threads until its budget
+/// is used up, bad or over-committed parameters are refused, and a thread
+/// that exits stops reserving bandwidth
+fn test_deadline_class() -> bool {
+    use crate::error::ErrorKind;
+    use threading::{DeadlineParams, SchedClass};
+    console::print("\n[TEST] Deadline scheduling class\n");
+
+    static DL_STOP: AtomicBool = AtomicBool::new(false);
+    static DL_SPINS: AtomicU64 = AtomicU64::new(0);
+    DL_STOP.store(false, Ordering::Release);
+    DL_SPINS.store(0, Ordering::Release);
+
+    let Ok(tid) = threading::spawn_fn(|| {
+        while !DL_STOP.load(Ordering::Acquire) {
+            DL_SPINS.fetch_add(1, Ordering::Relaxed);
+        }
+    }) else {
+        console::print("  Spawn failed\n");
+        return false;
+    };
+
+    let params = DeadlineParams {
+        period_us: 20_000,
+        budget_us: 10_000,
+    };
+    let over_budget = matches!(
+        threading::set_deadline_class(
+            tid,
+            Some(DeadlineParams {
+                period_us: 10_000,
+                budget_us: 20_000,
+            }),
+        ),
+        Err(e) if e.kind() == ErrorKind::InvalidArgument
+    );
+    let overruns_before = threading::deadline_overruns();
+    let set = threading::set_deadline_class(tid, Some(params)).is_ok();
+    let classified = threading::thread_class(tid) == Some(SchedClass::Deadline)
+        && threading::deadline_params(tid) == Some(params);
+    // Half the CPU is taken; another 90% doesn't fit
+    let over_committed = matches!(
+        threading::set_deadline_class(
+            threading::current_thread_id(),
+            Some(DeadlineParams {
+                period_us: 10_000,
+                budget_us: 9_000,
+            }),
+        ),
+        Err(e) if e.kind() == ErrorKind::LimitReached
+    );
+
+    // Sleeping hands it the CPU; its budget running out hands it back
+    threading::sleep_ms(100);
+    let spins = DL_SPINS.load(Ordering::Acquire);
+    let overruns = threading::deadline_overruns() - overruns_before;
+
+    let cleared = threading::set_deadline_class(tid, None).is_ok()
+        && threading::thread_class(tid) == Some(SchedClass::Normal);
+
+    // This one exits while still in the class
+    let large = DeadlineParams {
+        period_us: 10_000,
+        budget_us: 8_000,
+    };
+    let exiting = threading::spawn_fn(|| {
+        while !DL_STOP.load(Ordering::Acquire) {
+            threading::sleep_ms(1);
+        }
+    });
+    let exiting_set = exiting.is_ok_and(|t| threading::set_deadline_class(t, Some(large)).is_ok());
+    DL_STOP.store(true, Ordering::Release);
+    for _ in 0..100 {
+        if thread_finished(tid) && exiting.is_ok_and(thread_finished) {
+            break;
+        }
+        threading::sleep_ms(1);
+    }
+    let me = threading::current_thread_id();
+    let released = threading::set_deadline_class(me, Some(large)).is_ok();
+    let _ = threading::set_deadline_class(me, None);
+
+    console::print(&format!(
+        "  set: {}, classified: {}, bad params refused: {}, over-commit refused: {}\n",
+        set, classified, over_budget, over_committed
+    ));
+    console::print(&format!(
+        "  spins: {}, overruns: {}, cleared: {}, exited thread's bandwidth released: {}\n",
+        spins,
+        overruns,
+        cleared,
+        exiting_set && released
+    ));
+
+    let ok = set
+        && classified
+        && over_budget
+        && over_committed
+        && spins > 0
+        && overruns >= 1
+        && cleared
+        && exiting_set
+        && released;
+    console::print(&format!("  Result: {}\n", if ok { "PASS" } else { "FAIL" }));
+    ok
+}
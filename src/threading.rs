@@ -1,25 +1,105 @@
 // Preemptive threading with fixed-size thread pool
 // No dynamic allocation during spawn/cleanup - all memory pre-allocated at init
 
+use crate::backtrace::Backtrace;
+use crate::error::{ErrorKind, KError, KResult};
+use crate::events::Event;
+use crate::exceptions::IrqFrame;
+use crate::lockdep::Spinlock;
+use crate::sched_policy::{self, SchedParams, SchedPolicy};
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::arch::global_asm;
-use core::sync::atomic::{AtomicBool, Ordering};
-use spinning_top::Spinlock;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 
 /// Default timeout for cooperative threads in microseconds (5 seconds)
 pub const COOPERATIVE_TIMEOUT_US: u64 = 5_000_000;
 
-/// Stack size per thread (32KB)
+/// What to do when a cooperative thread runs past its timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutPolicy {
+    /// Warn once per run and let the thread keep the CPU
+    Log,
+    /// Switch away this once; the thread stays cooperative and its timeout
+    /// restarts when it runs again (the default)
+    Yield,
+    /// Convert the thread to preemptible and switch away
+    Preempt,
+    /// Terminate the thread
+    Kill,
+}
+
+impl TimeoutPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeoutPolicy::Log => "log",
+            TimeoutPolicy::Yield => "yield",
+            TimeoutPolicy::Preempt => "preempt",
+            TimeoutPolicy::Kill => "kill",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "log" => Some(TimeoutPolicy::Log),
+            "yield" => Some(TimeoutPolicy::Yield),
+            "preempt" => Some(TimeoutPolicy::Preempt),
+            "kill" => Some(TimeoutPolicy::Kill),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => TimeoutPolicy::Log,
+            2 => TimeoutPolicy::Preempt,
+            3 => TimeoutPolicy::Kill,
+            _ => TimeoutPolicy::Yield,
+        }
+    }
+}
+
+/// Default stack size per thread (32KB)
 const STACK_SIZE: usize = 32 * 1024;
 
-/// Maximum threads - with 32KB stacks, 32 threads = 1MB
+/// Range of stack sizes `spawn_with_stack` accepts
+const MIN_STACK_SIZE: usize = 8 * 1024;
+const MAX_STACK_SIZE: usize = 1024 * 1024;
+
+/// Pattern written at the bottom of every thread stack
+const STACK_CANARY: u64 = 0x57AC_CA4A_DEAD_C0DE;
+
+/// Canary words at the stack bottom (64 bytes)
+const CANARY_WORDS: usize = 8;
+
+/// Written over the rest of a stack at spawn; the deepest word that no
+/// longer holds it marks the high-water mark
+#[cfg(all(feature = "fs", feature = "shell"))]
+const STACK_FILL: u64 = 0x5A5A_5A5A_5A5A_5A5A;
+
+/// Maximum threads - with default 32KB stacks, 32 threads = 1MB
 /// Reasonable for 120MB heap
-const MAX_THREADS: usize = 32;
+pub(crate) const MAX_THREADS: usize = 32;
 
 /// Thread 0 is the boot/idle thread - always protected, never terminated
 const IDLE_THREAD_IDX: usize = 0;
 
+/// `ThreadLocal` statics at most
+const TLS_SLOTS: usize = 8;
+
+/// Default time a ready thread may wait before it is reported as starved (2 seconds)
+pub const DEFAULT_STARVATION_THRESHOLD_US: u64 = 2_000_000;
+
+/// How often the timer tick scans the run queue for starved threads
+const STARVATION_CHECK_INTERVAL_US: u64 = 100_000;
+
 /// Run a closure with IRQs disabled to prevent scheduler lock deadlocks
 #[inline]
 fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
@@ -106,7 +186,7 @@ thread_start:
     // Call the thread entry function (in x19)
     blr x19
     
-    // Thread returned - mark as terminated and yield
+    // Thread returned - terminate it
     // (This shouldn't happen for -> ! functions, but just in case)
     b thread_exit_asm
 
@@ -123,12 +203,11 @@ thread_start_closure:
     mov x0, x20
     blr x19
     
-    // Thread returned - should not happen for -> ! closures
+    // Thread returned - should not happen, the trampoline exits
     b thread_exit_asm
 
 thread_exit_asm:
-    wfi
-    b thread_exit_asm
+    bl thread_exit_from_asm
 "#
 );
 
@@ -189,27 +268,169 @@ pub enum ThreadState {
     Free,       // Slot is available
     Ready,      // Ready to run
     Running,    // Currently running
+    Sleeping,   // In `sleep_us`, off the run queue until its wake time
+    Blocked,    // Waiting on a `Mutex`, `Condvar` or in `park`, off the run queue until woken
     Terminated, // Finished, slot can be reclaimed
 }
 
+impl ThreadState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThreadState::Free => "Free",
+            ThreadState::Ready => "Ready",
+            ThreadState::Running => "Running",
+            ThreadState::Sleeping => "Sleeping",
+            ThreadState::Blocked => "Blocked",
+            ThreadState::Terminated => "Terminated",
+        }
+    }
+}
+
+/// Scheduling class of a thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedClass {
+    Normal,
+    /// Only runs when no normal thread is ready (housekeeping)
+    Background,
+    /// Periodic work with a CPU budget per period (see `set_deadline_class`)
+    Deadline,
+}
+
+impl SchedClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SchedClass::Normal => "normal",
+            SchedClass::Background => "background",
+            SchedClass::Deadline => "deadline",
+        }
+    }
+}
+
+/// Period and CPU budget of a `SchedClass::Deadline` thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineParams {
+    /// A new period starts this often; its end is the thread's deadline
+    pub period_us: u64,
+    /// CPU time the thread may use per period ahead of normal threads
+    pub budget_us: u64,
+}
+
+/// Shortest period `set_deadline_class` accepts
+pub const MIN_DEADLINE_PERIOD_US: u64 = 1_000;
+
+/// Share of the CPU, in per mille, deadline threads may reserve together;
+/// the rest stays for normal threads
+pub const MAX_DEADLINE_PERMILLE: u64 = 900;
+
+impl DeadlineParams {
+    /// Share of the CPU reserved, in per mille (rounded up)
+    pub fn permille(&self) -> u64 {
+        (self.budget_us * 1000).div_ceil(self.period_us.max(1))
+    }
+}
+
 /// Thread slot in the pool
 #[repr(C)]
 pub struct ThreadSlot {
     pub state: ThreadState,
+    /// Set by `spawn_named` for diagnostics (empty = unnamed)
+    pub name: &'static str,
     pub context: Context,
     pub cooperative: bool,
+    pub class: SchedClass,
+    /// Handed to the scheduling policy (priority, relative deadline)
+    pub params: SchedParams,
     pub start_time_us: u64,
     pub timeout_us: u64,
+    /// Timeout policy for this thread (None = global default)
+    pub timeout_policy: Option<TimeoutPolicy>,
+    /// Set once a Log-policy timeout has been reported for the current run
+    pub timeout_logged: bool,
+    /// When the thread last became Ready (0 = not waiting)
+    pub ready_since_us: u64,
+    /// Set once this wait has been reported, cleared when the thread runs
+    pub starvation_reported: bool,
+    /// Saved `no_preempt` nesting depth while switched out
+    pub preempt_depth: u32,
+    /// Saved `pin_current` nesting depth while switched out
+    pub pin_depth: u32,
+    /// Set once the stack canary was found damaged
+    pub stack_overflow: bool,
+    /// `with_deadline` expiry in uptime microseconds (0 = none)
+    pub deadline_us: u64,
+    /// Set once the scheduler force-woke the thread for its deadline
+    pub deadline_fired: bool,
+    /// Uptime at which a Sleeping thread becomes Ready again
+    pub wake_at_us: u64,
+    /// Blocked in `park` (rather than on a `Mutex` or `Condvar`)
+    pub parked: bool,
+    /// An `unpark` arrived while not parked; the next `park` returns at once
+    pub unpark_token: bool,
+    /// When the thread last got the CPU (kept apart from `start_time_us`,
+    /// which timeouts restart)
+    pub run_since_us: u64,
+    /// CPU time of the runs that have ended
+    pub cpu_time_us: u64,
+    /// Own priority while a waiter's is lent to it (priority inheritance)
+    pub base_priority: Option<u8>,
+    /// `Mutex`es the thread holds
+    pub mutexes_held: u32,
+    /// Period and budget while in `SchedClass::Deadline`
+    pub deadline_params: DeadlineParams,
+    /// End of the current period in uptime microseconds
+    pub period_end_us: u64,
+    /// Budget used in the current period (by runs that have ended)
+    pub budget_used_us: u64,
+    /// Boxed closure the thread hasn't started yet, freed on reap if it
+    /// was killed before it could
+    closure: Option<PendingClosure>,
+    /// Bumped every time the slot gets a new thread, so a wait queue entry
+    /// left by a killed thread can't wake the next one
+    pub generation: u32,
+}
+
+/// A spawned closure still on the heap, with the function that frees it
+#[derive(Clone, Copy)]
+struct PendingClosure {
+    ptr: usize,
+    drop: unsafe fn(*mut ()),
 }
 
 impl ThreadSlot {
     pub const fn empty() -> Self {
         Self {
             state: ThreadState::Free,
+            name: "",
             context: Context::zero(),
             cooperative: false,
+            class: SchedClass::Normal,
+            params: SchedParams::new(),
             start_time_us: 0,
             timeout_us: 0,
+            timeout_policy: None,
+            timeout_logged: false,
+            ready_since_us: 0,
+            starvation_reported: false,
+            preempt_depth: 0,
+            pin_depth: 0,
+            stack_overflow: false,
+            deadline_us: 0,
+            deadline_fired: false,
+            wake_at_us: 0,
+            parked: false,
+            unpark_token: false,
+            run_since_us: 0,
+            cpu_time_us: 0,
+            base_priority: None,
+            mutexes_held: 0,
+            deadline_params: DeadlineParams {
+                period_us: 0,
+                budget_us: 0,
+            },
+            period_end_us: 0,
+            budget_used_us: 0,
+            closure: None,
+            generation: 0,
         }
     }
 }
@@ -218,8 +439,17 @@ impl ThreadSlot {
 pub struct ThreadPool {
     slots: [ThreadSlot; MAX_THREADS],
     stacks: [usize; MAX_THREADS], // Pointers to pre-allocated stacks
+    stack_sizes: [usize; MAX_THREADS],
     current_idx: usize,
     initialized: bool,
+    /// Picks the next thread; owns the run queue (set by `init`)
+    policy: Option<Box<dyn SchedPolicy>>,
+    /// Last timeout enforcement, logged by the SGI handler outside the lock
+    timeout_event: Option<TimeoutEvent>,
+    /// Last stack overflow detected, logged by the SGI handler outside the lock
+    overflow_event: Option<StackOverflowEvent>,
+    /// Earliest `wake_at_us` of any sleeping thread (0 = none asleep)
+    next_wake_us: u64,
 }
 
 impl ThreadPool {
@@ -227,16 +457,28 @@ impl ThreadPool {
         Self {
             slots: [const { ThreadSlot::empty() }; MAX_THREADS],
             stacks: [0; MAX_THREADS],
+            stack_sizes: [0; MAX_THREADS],
             current_idx: 0,
             initialized: false,
+            policy: None,
+            timeout_event: None,
+            overflow_event: None,
+            next_wake_us: 0,
         }
     }
 
     /// Initialize the pool - allocate all stacks upfront
-    pub fn init(&mut self) {
+    pub fn init(&mut self, mut policy: Box<dyn SchedPolicy>) {
         // Slot 0 is the idle/boot thread (uses boot stack, never terminated)
         self.slots[IDLE_THREAD_IDX].state = ThreadState::Running;
+        self.slots[IDLE_THREAD_IDX].name = "main";
         self.stacks[IDLE_THREAD_IDX] = 0; // Boot stack, don't allocate
+        policy.enqueue(
+            IDLE_THREAD_IDX,
+            self.slots[IDLE_THREAD_IDX].params,
+            crate::timer::uptime_us(),
+        );
+        self.policy = Some(policy);
 
         // Pre-allocate stacks for all other slots using Vec
         for i in 1..MAX_THREADS {
@@ -245,150 +487,430 @@ impl ThreadPool {
             let stack_box = stack_vec.into_boxed_slice();
             let stack_ptr = Box::into_raw(stack_box) as *mut u8;
             self.stacks[i] = stack_ptr as usize;
+            self.stack_sizes[i] = STACK_SIZE;
         }
 
         self.initialized = true;
     }
 
+    /// Free slot for a new thread
+    ///
+    /// Without `stack` only a slot holding a default-size stack qualifies.
+    /// With one any free slot does: `stack` (base, size) is installed there
+    /// and the slot's old stack is handed back in its place to be freed.
+    fn take_free_slot(&mut self, stack: &mut Option<(usize, usize)>) -> Option<usize> {
+        // Skip slot 0 = idle
+        let i = (1..MAX_THREADS).find(|&i| {
+            self.slots[i].state == ThreadState::Free
+                && (stack.is_some() || self.stack_sizes[i] == STACK_SIZE)
+        })?;
+        if let Some((base, size)) = stack.take() {
+            *stack = Some((self.stacks[i], self.stack_sizes[i]));
+            self.stacks[i] = base;
+            self.stack_sizes[i] = size;
+        }
+        Some(i)
+    }
+
     /// Spawn a new thread with extern "C" entry function
     pub fn spawn(
         &mut self,
         entry: extern "C" fn() -> !,
         cooperative: bool,
-    ) -> Result<usize, &'static str> {
+        policy: Option<TimeoutPolicy>,
+        class: SchedClass,
+        params: SchedParams,
+    ) -> KResult<usize> {
         if !self.initialized {
-            return Err("Thread pool not initialized");
-        }
-
-        // Find first free slot (skip slot 0 = idle)
-        for i in 1..MAX_THREADS {
-            if self.slots[i].state == ThreadState::Free {
-                // Setup the thread - write all fields point-by-point to avoid
-                // large struct copies which can hang due to compiler optimization issues
-                let stack_base = self.stacks[i];
-                let stack_top = stack_base + STACK_SIZE;
-                let sp = (stack_top & !0xF) as u64;
-                let entry_addr = entry as *const () as u64;
-
-                // Write context fields individually (128-byte struct copy was hanging)
-                self.slots[i].context.x19 = entry_addr;
-                self.slots[i].context.x20 = 0;
-                self.slots[i].context.x21 = 0;
-                self.slots[i].context.x22 = 0;
-                self.slots[i].context.x23 = 0;
-                self.slots[i].context.x24 = 0;
-                self.slots[i].context.x25 = 0;
-                self.slots[i].context.x26 = 0;
-                self.slots[i].context.x27 = 0;
-                self.slots[i].context.x28 = 0;
-                self.slots[i].context.x29 = 0; // Frame pointer
-                self.slots[i].context.x30 = thread_start as *const () as u64;
-                self.slots[i].context.sp = sp;
-                self.slots[i].context.daif = 0;
-                self.slots[i].context.elr = 0;
-                self.slots[i].context.spsr = 0;
-
-                // Write slot metadata
-                self.slots[i].cooperative = cooperative;
-                self.slots[i].start_time_us = 0;
-                self.slots[i].timeout_us = if cooperative {
-                    COOPERATIVE_TIMEOUT_US
-                } else {
-                    0
-                };
-
-                // Set state last (makes thread visible to scheduler)
-                self.slots[i].state = ThreadState::Ready;
-
-                return Ok(i);
-            }
+            return Err(KError::with_context(ErrorKind::NotInitialized, "thread pool"));
         }
 
-        Err("No free thread slots")
+        let Some(i) = self.take_free_slot(&mut None) else {
+            return Err(KError::new(ErrorKind::NoFreeSlots));
+        };
+
+        // Setup the thread - write all fields point-by-point to avoid
+        // large struct copies which can hang due to compiler optimization issues
+        let stack_base = self.stacks[i];
+        let stack_top = stack_base + self.stack_sizes[i];
+        let sp = (stack_top & !0xF) as u64;
+        let entry_addr = entry as *const () as u64;
+
+        // Write context fields individually (128-byte struct copy was hanging)
+        self.slots[i].context.x19 = entry_addr;
+        self.slots[i].context.x20 = 0;
+        self.slots[i].context.x21 = 0;
+        self.slots[i].context.x22 = 0;
+        self.slots[i].context.x23 = 0;
+        self.slots[i].context.x24 = 0;
+        self.slots[i].context.x25 = 0;
+        self.slots[i].context.x26 = 0;
+        self.slots[i].context.x27 = 0;
+        self.slots[i].context.x28 = 0;
+        self.slots[i].context.x29 = 0; // Frame pointer
+        self.slots[i].context.x30 = thread_start as *const () as u64;
+        self.slots[i].context.sp = sp;
+        self.slots[i].context.daif = 0;
+        self.slots[i].context.elr = 0;
+        self.slots[i].context.spsr = 0;
+
+        // Write slot metadata
+        self.slots[i].name = "";
+        self.slots[i].cooperative = cooperative;
+        self.slots[i].class = class;
+        self.slots[i].params = params;
+        self.slots[i].start_time_us = 0;
+        self.slots[i].timeout_us = if cooperative {
+            cooperative_timeout_us()
+        } else {
+            0
+        };
+        self.slots[i].timeout_policy = policy;
+        self.slots[i].timeout_logged = false;
+        self.slots[i].ready_since_us = crate::timer::uptime_us();
+        self.slots[i].starvation_reported = false;
+        self.slots[i].preempt_depth = 0;
+        self.slots[i].pin_depth = 0;
+        self.slots[i].stack_overflow = false;
+        self.slots[i].deadline_us = 0;
+        self.slots[i].deadline_fired = false;
+        self.slots[i].wake_at_us = 0;
+        self.slots[i].parked = false;
+        self.slots[i].unpark_token = false;
+        self.slots[i].run_since_us = 0;
+        self.slots[i].cpu_time_us = 0;
+        self.slots[i].base_priority = None;
+        self.slots[i].mutexes_held = 0;
+        self.slots[i].period_end_us = 0;
+        self.slots[i].budget_used_us = 0;
+        self.slots[i].closure = None;
+        self.slots[i].generation = self.slots[i].generation.wrapping_add(1);
+        fill_stack(stack_base, self.stack_sizes[i]);
+        write_canary(stack_base);
+        reset_tls(i);
+
+        // Set state last (makes thread visible to scheduler)
+        self.slots[i].state = ThreadState::Ready;
+        self.enqueue(i);
+
+        Ok(i)
     }
 
     /// Spawn a new thread with a boxed closure
     /// trampoline_fn: function that takes raw closure pointer and calls it
     /// closure_ptr: raw pointer to the boxed closure
+    /// drop_fn: frees the boxed closure if the thread is killed before
+    /// its trampoline takes it
+    /// stack: custom stack to install, swapped for the slot's old one
+    #[allow(clippy::too_many_arguments)]
     pub fn spawn_closure(
         &mut self,
         trampoline_fn: fn(*mut ()) -> !,
         closure_ptr: *mut (),
+        drop_fn: unsafe fn(*mut ()),
         cooperative: bool,
-    ) -> Result<usize, &'static str> {
+        policy: Option<TimeoutPolicy>,
+        class: SchedClass,
+        params: SchedParams,
+        stack: &mut Option<(usize, usize)>,
+    ) -> KResult<usize> {
         if !self.initialized {
-            return Err("Thread pool not initialized");
+            return Err(KError::with_context(ErrorKind::NotInitialized, "thread pool"));
         }
 
-        // Find first free slot (skip slot 0 = idle)
-        for i in 1..MAX_THREADS {
-            if self.slots[i].state == ThreadState::Free {
-                let stack_base = self.stacks[i];
-                let stack_top = stack_base + STACK_SIZE;
-                let sp = (stack_top & !0xF) as u64;
-
-                // x19 = trampoline function pointer
-                // x20 = closure data pointer
-                self.slots[i].context.x19 = trampoline_fn as *const () as u64;
-                self.slots[i].context.x20 = closure_ptr as u64;
-                self.slots[i].context.x21 = 0;
-                self.slots[i].context.x22 = 0;
-                self.slots[i].context.x23 = 0;
-                self.slots[i].context.x24 = 0;
-                self.slots[i].context.x25 = 0;
-                self.slots[i].context.x26 = 0;
-                self.slots[i].context.x27 = 0;
-                self.slots[i].context.x28 = 0;
-                self.slots[i].context.x29 = 0;
-                self.slots[i].context.x30 = thread_start_closure as *const () as u64;
-                self.slots[i].context.sp = sp;
-                self.slots[i].context.daif = 0;
-                self.slots[i].context.elr = 0;
-                self.slots[i].context.spsr = 0;
-
-                self.slots[i].cooperative = cooperative;
-                self.slots[i].start_time_us = 0;
-                self.slots[i].timeout_us = if cooperative {
-                    COOPERATIVE_TIMEOUT_US
-                } else {
-                    0
+        let Some(i) = self.take_free_slot(stack) else {
+            return Err(KError::new(ErrorKind::NoFreeSlots));
+        };
+
+        let stack_base = self.stacks[i];
+        let stack_top = stack_base + self.stack_sizes[i];
+        let sp = (stack_top & !0xF) as u64;
+
+        // x19 = trampoline function pointer
+        // x20 = closure data pointer
+        self.slots[i].context.x19 = trampoline_fn as *const () as u64;
+        self.slots[i].context.x20 = closure_ptr as u64;
+        self.slots[i].context.x21 = 0;
+        self.slots[i].context.x22 = 0;
+        self.slots[i].context.x23 = 0;
+        self.slots[i].context.x24 = 0;
+        self.slots[i].context.x25 = 0;
+        self.slots[i].context.x26 = 0;
+        self.slots[i].context.x27 = 0;
+        self.slots[i].context.x28 = 0;
+        self.slots[i].context.x29 = 0;
+        self.slots[i].context.x30 = thread_start_closure as *const () as u64;
+        self.slots[i].context.sp = sp;
+        self.slots[i].context.daif = 0;
+        self.slots[i].context.elr = 0;
+        self.slots[i].context.spsr = 0;
+
+        self.slots[i].name = "";
+        self.slots[i].cooperative = cooperative;
+        self.slots[i].class = class;
+        self.slots[i].params = params;
+        self.slots[i].start_time_us = 0;
+        self.slots[i].timeout_us = if cooperative {
+            cooperative_timeout_us()
+        } else {
+            0
+        };
+        self.slots[i].timeout_policy = policy;
+        self.slots[i].timeout_logged = false;
+        self.slots[i].ready_since_us = crate::timer::uptime_us();
+        self.slots[i].starvation_reported = false;
+        self.slots[i].preempt_depth = 0;
+        self.slots[i].pin_depth = 0;
+        self.slots[i].stack_overflow = false;
+        self.slots[i].deadline_us = 0;
+        self.slots[i].deadline_fired = false;
+        self.slots[i].wake_at_us = 0;
+        self.slots[i].parked = false;
+        self.slots[i].unpark_token = false;
+        self.slots[i].run_since_us = 0;
+        self.slots[i].cpu_time_us = 0;
+        self.slots[i].base_priority = None;
+        self.slots[i].mutexes_held = 0;
+        self.slots[i].period_end_us = 0;
+        self.slots[i].budget_used_us = 0;
+        self.slots[i].closure = Some(PendingClosure {
+            ptr: closure_ptr as usize,
+            drop: drop_fn,
+        });
+        self.slots[i].generation = self.slots[i].generation.wrapping_add(1);
+        fill_stack(stack_base, self.stack_sizes[i]);
+        write_canary(stack_base);
+        reset_tls(i);
+
+        self.slots[i].state = ThreadState::Ready;
+        self.enqueue(i);
+
+        Ok(i)
+    }
+
+    /// Reclaim a terminated thread slot (just mark as Free)
+    pub fn reclaim(&mut self, idx: usize) {
+        if idx > 0 && idx < MAX_THREADS && self.slots[idx].state == ThreadState::Terminated {
+            self.free_slot(idx);
+            // Stack stays allocated - will be reused
+        }
+    }
+
+    /// Mark terminated slot `idx` Free, dropping what its thread left set
+    fn free_slot(&mut self, idx: usize) {
+        let slot = &mut self.slots[idx];
+        slot.state = ThreadState::Free;
+        // A reaped deadline thread no longer reserves bandwidth
+        slot.class = SchedClass::Normal;
+        self.dequeue(idx);
+    }
+
+    /// Put `idx` in the policy's run queue with its current parameters
+    fn enqueue(&mut self, idx: usize) {
+        let params = self.slots[idx].params;
+        if let Some(policy) = self.policy.as_mut() {
+            policy.enqueue(idx, params, crate::timer::uptime_us());
+        }
+    }
+
+    fn dequeue(&mut self, idx: usize) {
+        if let Some(policy) = self.policy.as_mut() {
+            policy.dequeue(idx);
+        }
+    }
+
+    /// Change the parameters of a live thread
+    pub fn set_params(&mut self, idx: usize, params: SchedParams) -> KResult<()> {
+        match self.slots.get(idx).map(|slot| slot.state) {
+            None | Some(ThreadState::Free) | Some(ThreadState::Terminated) => {
+                Err(KError::with_context(ErrorKind::NotFound, "thread"))
+            }
+            Some(_) => {
+                let slot = &mut self.slots[idx];
+                // While boosted the new priority is the thread's own, and
+                // it keeps the lent one if that is higher
+                let priority = match slot.base_priority {
+                    Some(_) => {
+                        slot.base_priority = Some(params.priority);
+                        params.priority.max(slot.params.priority)
+                    }
+                    None => params.priority,
                 };
+                slot.params = SchedParams { priority, ..params };
+                self.enqueue(idx);
+                Ok(())
+            }
+        }
+    }
+
+    /// Lend `priority` to thread `idx` if it runs at less
+    ///
+    /// Returns whether it was raised.
+    fn boost_priority(&mut self, idx: usize, priority: u8) -> bool {
+        let slot = &mut self.slots[idx];
+        if slot.state == ThreadState::Free || slot.params.priority >= priority {
+            return false;
+        }
+        slot.base_priority.get_or_insert(slot.params.priority);
+        slot.params.priority = priority;
+        self.enqueue(idx);
+        true
+    }
 
-                self.slots[i].state = ThreadState::Ready;
+    /// Give thread `idx` back its own priority
+    fn restore_priority(&mut self, idx: usize) {
+        if let Some(base) = self.slots[idx].base_priority.take() {
+            self.slots[idx].params.priority = base;
+            self.enqueue(idx);
+        }
+    }
 
-                return Ok(i);
+    /// Switch a live thread between preemptible and cooperative
+    ///
+    /// A thread turning cooperative gets the current cooperative timeout,
+    /// counted from now if it is the running thread, so the watchdog
+    /// still catches it if it never yields.
+    pub fn set_preemptible(&mut self, idx: usize, preemptible: bool) -> KResult<()> {
+        match self.slots.get(idx).map(|slot| slot.state) {
+            None | Some(ThreadState::Free) | Some(ThreadState::Terminated) => {
+                return Err(KError::with_context(ErrorKind::NotFound, "thread"));
+            }
+            Some(_) => {}
+        }
+        let running = idx == self.current_idx;
+        let slot = &mut self.slots[idx];
+        slot.cooperative = !preemptible;
+        slot.timeout_logged = false;
+        if preemptible {
+            slot.timeout_us = 0;
+        } else {
+            slot.timeout_us = cooperative_timeout_us();
+            if running {
+                slot.start_time_us = crate::timer::uptime_us();
             }
         }
+        Ok(())
+    }
 
-        Err("No free thread slots")
+    /// Move thread `idx` into the deadline class with `params`, or back to
+    /// the normal class with None
+    ///
+    /// Refuses parameters that would let deadline threads reserve more
+    /// than `MAX_DEADLINE_PERMILLE` of the CPU between them.
+    pub fn set_deadline_class(
+        &mut self,
+        idx: usize,
+        params: Option<DeadlineParams>,
+    ) -> KResult<()> {
+        match self.slots.get(idx).map(|slot| slot.state) {
+            None | Some(ThreadState::Free) | Some(ThreadState::Terminated) => {
+                return Err(KError::with_context(ErrorKind::NotFound, "thread"));
+            }
+            Some(_) => {}
+        }
+        let Some(params) = params else {
+            if self.slots[idx].class == SchedClass::Deadline {
+                self.slots[idx].class = SchedClass::Normal;
+            }
+            return Ok(());
+        };
+        if params.period_us < MIN_DEADLINE_PERIOD_US
+            || params.budget_us == 0
+            || params.budget_us > params.period_us
+        {
+            return Err(KError::with_context(ErrorKind::InvalidArgument, "deadline params"));
+        }
+        let reserved: u64 = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|(i, slot)| {
+                *i != idx
+                    && slot.class == SchedClass::Deadline
+                    && !matches!(slot.state, ThreadState::Free | ThreadState::Terminated)
+            })
+            .map(|(_, slot)| slot.deadline_params.permille())
+            .sum();
+        if reserved + params.permille() > MAX_DEADLINE_PERMILLE {
+            return Err(KError::with_context(ErrorKind::LimitReached, "deadline bandwidth"));
+        }
+        let now = crate::timer::uptime_us();
+        let slot = &mut self.slots[idx];
+        slot.class = SchedClass::Deadline;
+        slot.deadline_params = params;
+        slot.period_end_us = now.saturating_add(params.period_us);
+        slot.budget_used_us = 0;
+        // Budget is only charged from here on
+        if idx == self.current_idx {
+            slot.cpu_time_us += now.saturating_sub(slot.run_since_us);
+            slot.run_since_us = now;
+        }
+        Ok(())
     }
 
-    /// Reclaim a terminated thread slot (just mark as Free)
-    pub fn reclaim(&mut self, idx: usize) {
-        if idx > 0 && idx < MAX_THREADS && self.slots[idx].state == ThreadState::Terminated {
-            self.slots[idx].state = ThreadState::Free;
-            // Stack stays allocated - will be reused
+    /// Install `policy`, moving every live thread into its run queue
+    /// Returns the policy it replaces.
+    pub fn replace_policy(
+        &mut self,
+        mut policy: Box<dyn SchedPolicy>,
+    ) -> Option<Box<dyn SchedPolicy>> {
+        let now = crate::timer::uptime_us();
+        for (i, slot) in self.slots.iter().enumerate() {
+            if matches!(
+                slot.state,
+                ThreadState::Ready
+                    | ThreadState::Running
+                    | ThreadState::Sleeping
+                    | ThreadState::Blocked
+            ) {
+                policy.enqueue(i, slot.params, now);
+            }
         }
+        self.policy.replace(policy)
     }
 
-    /// Clean up all terminated threads
-    pub fn cleanup_terminated(&mut self) -> usize {
-        let mut count = 0;
+    /// Bitmask of terminated thread slots
+    pub fn terminated_mask(&self) -> u32 {
+        const _: () = assert!(MAX_THREADS <= 32, "thread masks are u32");
+
+        let mut mask = 0;
         for i in 1..MAX_THREADS {
             if self.slots[i].state == ThreadState::Terminated {
-                self.slots[i].state = ThreadState::Free;
+                mask |= 1 << i;
+            }
+        }
+        mask
+    }
+
+    /// Free the terminated slots in `mask`, returning how many were freed
+    pub fn reclaim_mask(&mut self, mask: u32) -> usize {
+        let mut count = 0;
+        for i in 1..MAX_THREADS {
+            if mask & (1 << i) != 0 && self.slots[i].state == ThreadState::Terminated {
+                self.free_slot(i);
                 count += 1;
             }
         }
         count
     }
 
-    /// Select next ready thread (round-robin)
+    /// Select the next thread to run, as chosen by the scheduling policy
     /// Thread 0 (boot/main) is a regular thread that can be scheduled
     pub fn schedule_indices(&mut self, voluntary: bool) -> Option<(usize, usize)> {
+        self.wake_sleepers(crate::timer::uptime_us());
+
         let current_idx = self.current_idx;
         let current = &self.slots[current_idx];
 
+        // Inside a no_preempt scope: defer the tick until the scope ends
+        if !voluntary
+            && current.state == ThreadState::Running
+            && PREEMPT_DEPTH.load(Ordering::Relaxed) > 0
+        {
+            PREEMPT_PENDING.store(true, Ordering::Relaxed);
+            return None;
+        }
+
         // Check cooperative timeout
         if !voluntary && current.cooperative && current.state == ThreadState::Running {
             let timeout = current.timeout_us;
@@ -398,46 +920,454 @@ impl ThreadPool {
                 if elapsed < timeout {
                     return None; // Not timed out yet
                 }
-                // Debug: cooperative thread timed out
-                // crate::console::print("[SCHED] coop timeout\n");
+                if !self.enforce_timeout(current_idx, elapsed) {
+                    return None; // Policy lets it keep running
+                }
             } else {
                 return None; // No timeout, can't preempt
             }
         }
 
-        // Find next ready thread (including thread 0)
-        let mut next_idx = (current_idx + 1) % MAX_THREADS;
-        let start_idx = next_idx;
-
-        loop {
-            let state = self.slots[next_idx].state;
-            if state == ThreadState::Ready || state == ThreadState::Running {
-                break;
-            }
-
-            next_idx = (next_idx + 1) % MAX_THREADS;
-
-            if next_idx == start_idx {
-                return None; // No ready threads
-            }
+        let now = crate::timer::uptime_us();
+        if let Some(policy) = self.policy.as_mut() {
+            policy.put_prev(current_idx, now, voluntary);
+        }
+        // A deadline thread that yields is done with this period
+        let current = &mut self.slots[current_idx];
+        if voluntary
+            && current.class == SchedClass::Deadline
+            && current.state == ThreadState::Running
+        {
+            current.budget_used_us = current.deadline_params.budget_us;
         }
+        self.replenish_deadlines(now);
+
+        // Find next ready thread (including thread 0); a thread whose
+        // deadline just passed goes first so its wait can return TimedOut,
+        // then deadline threads with budget left, earliest period end
+        // first. Background threads - and deadline threads out of budget -
+        // only get a turn when no normal thread is ready, and the main loop
+        // in thread 0 yielding counts as idle.
+        let idle_pass = voluntary && current_idx == IDLE_THREAD_IDX;
+        let next_idx = match self
+            .expired_deadline(current_idx)
+            .or_else(|| self.next_deadline(now))
+        {
+            Some(idx) => idx,
+            None => {
+                let normal = self.next_ready(current_idx, |i, slot| {
+                    slot.class == SchedClass::Normal && !(idle_pass && i == IDLE_THREAD_IDX)
+                });
+                // No ready threads: nothing to switch to
+                normal.or_else(|| self.next_ready(current_idx, |_, _| true))?
+            }
+        };
 
         if next_idx == current_idx {
             return None;
         }
 
-        // Update states - ALL threads get set to Ready when switching away
-        // (except terminated threads)
-        if self.slots[current_idx].state != ThreadState::Terminated {
+        // The outgoing thread's stack must not have grown past its bottom
+        self.check_canary(current_idx);
+
+        // Update states - a running thread becomes Ready when switched
+        // away from; terminated, sleeping and blocked threads keep their state
+        if self.slots[current_idx].state == ThreadState::Running {
             self.slots[current_idx].state = ThreadState::Ready;
+            self.slots[current_idx].ready_since_us = now;
+        }
+        if self.slots[next_idx].ready_since_us > 0 {
+            let waited_us = now.saturating_sub(self.slots[next_idx].ready_since_us);
+            crate::latency::record(crate::latency::Metric::WakeToRun, waited_us * 1000);
+        }
+        // Charge the outgoing thread for its run
+        let ran_us = now.saturating_sub(self.slots[current_idx].run_since_us);
+        let outgoing = &mut self.slots[current_idx];
+        outgoing.cpu_time_us += ran_us;
+        if outgoing.class == SchedClass::Deadline {
+            outgoing.budget_used_us += ran_us;
+            if !voluntary && outgoing.budget_used_us >= outgoing.deadline_params.budget_us {
+                DEADLINE_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+            }
         }
         self.slots[next_idx].state = ThreadState::Running;
-        self.slots[next_idx].start_time_us = crate::timer::uptime_us();
+        self.slots[next_idx].start_time_us = now;
+        self.slots[next_idx].run_since_us = now;
+        self.slots[next_idx].ready_since_us = 0;
+        self.slots[next_idx].starvation_reported = false;
+        self.slots[next_idx].timeout_logged = false;
+
+        // Swap the scope depths of the outgoing and incoming threads
+        self.slots[current_idx].preempt_depth = PREEMPT_DEPTH.load(Ordering::Relaxed);
+        self.slots[current_idx].pin_depth = PIN_DEPTH.load(Ordering::Relaxed);
+        PREEMPT_DEPTH.store(self.slots[next_idx].preempt_depth, Ordering::Relaxed);
+        PIN_DEPTH.store(self.slots[next_idx].pin_depth, Ordering::Relaxed);
+        PREEMPT_PENDING.store(false, Ordering::Relaxed);
 
         self.current_idx = next_idx;
         Some((current_idx, next_idx))
     }
 
+    /// Whether a timer tick at `now` has anything for the scheduler to do,
+    /// or the current thread can keep the CPU
+    ///
+    /// The scheduler is needed once the running thread has used its
+    /// `slice_us` (a cooperative thread: its timeout), or earlier if a
+    /// sleeper is due, a waiter's deadline passed, a normal thread waits
+    /// behind background work, a deadline thread used up its budget or has
+    /// an earlier deadline than the current thread, or the policy wants a
+    /// more urgent thread.
+    pub fn tick_needs_schedule(&self, now: u64) -> bool {
+        let current_idx = self.current_idx;
+        let current = &self.slots[current_idx];
+        if current.state != ThreadState::Running {
+            return true;
+        }
+        if self.next_wake_us != 0 && now >= self.next_wake_us {
+            return true;
+        }
+        let waiting = self.slots.iter().any(|slot| {
+            slot.state == ThreadState::Ready
+                && ((slot.deadline_us > 0 && !slot.deadline_fired && now >= slot.deadline_us)
+                    || (slot.class == SchedClass::Normal && current.class == SchedClass::Background))
+        });
+        if waiting {
+            return true;
+        }
+        let current_window = self.deadline_window(current_idx, now);
+        if current.class == SchedClass::Deadline && current_window.is_none() {
+            return true;
+        }
+        let earlier_deadline = (0..MAX_THREADS).any(|i| {
+            self.slots[i].state == ThreadState::Ready
+                && self
+                    .deadline_window(i, now)
+                    .is_some_and(|end| current_window.is_none_or(|current_end| end < current_end))
+        });
+        if earlier_deadline {
+            return true;
+        }
+        let slots = &self.slots;
+        if let Some(policy) = self.policy.as_ref()
+            && policy.preempts(current_idx, &|i| slots[i].state == ThreadState::Ready)
+        {
+            return true;
+        }
+
+        let elapsed = now.saturating_sub(current.start_time_us);
+        if current.cooperative {
+            current.timeout_us > 0 && elapsed >= current.timeout_us
+        } else {
+            elapsed >= priority_slice_us(current.params.priority)
+        }
+    }
+
+    /// Mark the current thread Sleeping until `wake_at_us`; it keeps
+    /// running until the next switch
+    fn sleep_current(&mut self, wake_at_us: u64) {
+        let idx = self.current_idx;
+        self.slots[idx].state = ThreadState::Sleeping;
+        self.slots[idx].wake_at_us = wake_at_us;
+        if self.next_wake_us == 0 || wake_at_us < self.next_wake_us {
+            self.next_wake_us = wake_at_us;
+        }
+    }
+
+    /// Mark the current thread Blocked until `unblock` (or its deadline,
+    /// if it has one); it keeps running until the next switch
+    ///
+    /// Returns the entry to queue where its waker will find it.
+    fn block_current(&mut self) -> Waiter {
+        let idx = self.current_idx;
+        self.slots[idx].state = ThreadState::Blocked;
+        let deadline = self.slots[idx].deadline_us;
+        if deadline > 0 && (self.next_wake_us == 0 || deadline < self.next_wake_us) {
+            self.next_wake_us = deadline;
+        }
+        self.current_waiter()
+    }
+
+    /// Wait queue entry for the running thread
+    fn current_waiter(&self) -> Waiter {
+        let idx = self.current_idx;
+        Waiter {
+            tid: idx,
+            generation: self.slots[idx].generation,
+        }
+    }
+
+    /// Whether `waiter` is still a thread blocked on what it queued for
+    /// (false once it was killed, even if its slot has a new thread)
+    fn is_waiting(&self, waiter: Waiter) -> bool {
+        let slot = &self.slots[waiter.tid];
+        slot.generation == waiter.generation && slot.state == ThreadState::Blocked
+    }
+
+    /// `unblock` the thread behind a wait queue entry; false if it is gone
+    fn wake(&mut self, waiter: Waiter) -> bool {
+        self.slots[waiter.tid].generation == waiter.generation && self.unblock(waiter.tid)
+    }
+
+    /// Make a Blocked thread runnable again (no-op for any other state)
+    fn unblock(&mut self, idx: usize) -> bool {
+        let slot = &mut self.slots[idx];
+        if slot.state != ThreadState::Blocked {
+            return false;
+        }
+        // A blocker nothing was switched to yet is still on the CPU
+        if idx == self.current_idx {
+            slot.state = ThreadState::Running;
+        } else {
+            slot.state = ThreadState::Ready;
+            slot.ready_since_us = crate::timer::uptime_us();
+        }
+        true
+    }
+
+    /// Terminate thread `idx`, whatever it is doing; returns the mutexes it
+    /// held. It must not be the running thread.
+    #[cfg(feature = "shell")]
+    fn kill(&mut self, idx: usize) -> KResult<u32> {
+        match self.slots.get(idx).map(|slot| slot.state) {
+            None | Some(ThreadState::Free) | Some(ThreadState::Terminated) => {
+                return Err(KError::with_context(ErrorKind::NotFound, "thread"));
+            }
+            Some(_) if idx == IDLE_THREAD_IDX => {
+                return Err(KError::with_context(ErrorKind::InvalidArgument, "thread 0"));
+            }
+            Some(_) => {}
+        }
+        debug_assert!(idx != self.current_idx, "kill of the running thread");
+        let slot = &mut self.slots[idx];
+        slot.state = ThreadState::Terminated;
+        slot.parked = false;
+        // Nothing to wake any more; wait queue entries it left are told
+        // apart by the slot's generation
+        slot.wake_at_us = 0;
+        slot.deadline_us = 0;
+        Ok(slot.mutexes_held)
+    }
+
+    /// Make sleepers whose wake time has come, and blocked threads whose
+    /// deadline has passed, runnable again
+    ///
+    /// Runs on every scheduler pass (each tick among them); `next_wake_us`
+    /// keeps it to a single comparison until the earliest of them is due.
+    fn wake_sleepers(&mut self, now: u64) {
+        if self.next_wake_us == 0 || now < self.next_wake_us {
+            return;
+        }
+        let current_idx = self.current_idx;
+        let mut next = 0;
+        for (i, slot) in self.slots.iter_mut().enumerate() {
+            let wake_at_us = match slot.state {
+                ThreadState::Sleeping => slot.wake_at_us,
+                ThreadState::Blocked if slot.deadline_us > 0 => slot.deadline_us,
+                _ => continue,
+            };
+            if now >= wake_at_us {
+                // A sleeper nothing was switched to is still on the CPU
+                if i == current_idx {
+                    slot.state = ThreadState::Running;
+                } else {
+                    slot.state = ThreadState::Ready;
+                    slot.ready_since_us = now;
+                }
+            } else if next == 0 || wake_at_us < next {
+                next = wake_at_us;
+            }
+        }
+        self.next_wake_us = next;
+    }
+
+    /// The runnable thread the policy picks to follow `current_idx`
+    /// among those `eligible` accepts
+    fn next_ready(
+        &mut self,
+        current_idx: usize,
+        eligible: impl Fn(usize, &ThreadSlot) -> bool,
+    ) -> Option<usize> {
+        let slots = &self.slots;
+        let policy = self.policy.as_mut()?;
+        policy.pick_next(current_idx, &|i| {
+            let slot = &slots[i];
+            matches!(slot.state, ThreadState::Ready | ThreadState::Running) && eligible(i, slot)
+        })
+    }
+
+    /// Apply the timeout policy to a cooperative thread that overran
+    /// Returns true if the scheduler should switch away from it.
+    fn enforce_timeout(&mut self, idx: usize, elapsed_us: u64) -> bool {
+        let slot = &mut self.slots[idx];
+        let policy = slot.timeout_policy.unwrap_or_else(default_timeout_policy);
+
+        let policy = if idx == IDLE_THREAD_IDX && policy == TimeoutPolicy::Kill {
+            TimeoutPolicy::Yield // Never kill the boot/idle thread
+        } else {
+            policy
+        };
+
+        let switch = match policy {
+            TimeoutPolicy::Log => {
+                if slot.timeout_logged {
+                    return false;
+                }
+                slot.timeout_logged = true;
+                false
+            }
+            TimeoutPolicy::Yield => true,
+            TimeoutPolicy::Preempt => {
+                slot.cooperative = false;
+                slot.timeout_us = 0;
+                true
+            }
+            TimeoutPolicy::Kill => {
+                slot.state = ThreadState::Terminated;
+                true
+            }
+        };
+
+        self.timeout_event = Some(TimeoutEvent {
+            tid: idx,
+            elapsed_us,
+            policy,
+            backtrace: None,
+        });
+        switch
+    }
+
+    /// A ready thread (other than `current_idx`) whose deadline has passed
+    /// and hasn't been force-woken yet
+    fn expired_deadline(&mut self, current_idx: usize) -> Option<usize> {
+        let now = crate::timer::uptime_us();
+        let idx = self.slots.iter().enumerate().position(|(i, slot)| {
+            i != current_idx
+                && slot.state == ThreadState::Ready
+                && slot.deadline_us > 0
+                && !slot.deadline_fired
+                && now >= slot.deadline_us
+        })?;
+        self.slots[idx].deadline_fired = true;
+        Some(idx)
+    }
+
+    /// Start a new period, with a full budget, for every deadline thread
+    /// whose period has ended
+    fn replenish_deadlines(&mut self, now: u64) {
+        let current_idx = self.current_idx;
+        for (i, slot) in self.slots.iter_mut().enumerate() {
+            if slot.class == SchedClass::Deadline && now >= slot.period_end_us {
+                // The running thread's time so far belongs to the old period
+                if i == current_idx && slot.state == ThreadState::Running {
+                    slot.cpu_time_us += now.saturating_sub(slot.run_since_us);
+                    slot.run_since_us = now;
+                }
+                let period_us = slot.deadline_params.period_us;
+                slot.period_end_us = next_period_end(slot.period_end_us, period_us, now);
+                slot.budget_used_us = 0;
+            }
+        }
+    }
+
+    /// End of thread `idx`'s current period if it is a deadline thread with
+    /// budget left at `now` (counting a period that has ended as renewed)
+    fn deadline_window(&self, idx: usize, now: u64) -> Option<u64> {
+        let slot = &self.slots[idx];
+        if slot.class != SchedClass::Deadline {
+            return None;
+        }
+        if now >= slot.period_end_us {
+            return Some(next_period_end(slot.period_end_us, slot.deadline_params.period_us, now));
+        }
+        let mut used = slot.budget_used_us;
+        if idx == self.current_idx && slot.state == ThreadState::Running {
+            used += now.saturating_sub(slot.run_since_us);
+        }
+        (used < slot.deadline_params.budget_us).then_some(slot.period_end_us)
+    }
+
+    /// The runnable deadline thread with budget left whose period ends first
+    fn next_deadline(&self, now: u64) -> Option<usize> {
+        (0..MAX_THREADS)
+            .filter(|&i| matches!(self.slots[i].state, ThreadState::Ready | ThreadState::Running))
+            .filter_map(|i| self.deadline_window(i, now).map(|end| (end, i)))
+            .min()
+            .map(|(_, i)| i)
+    }
+
+    /// Verify the stack canary of `idx`, flagging (or killing) it if damaged
+    fn check_canary(&mut self, idx: usize) {
+        let base = self.stacks[idx];
+        if base == 0 || self.slots[idx].stack_overflow {
+            return; // Boot stack has no canary; damage is reported once
+        }
+        let damaged = canary_damage(base);
+        if damaged == 0 {
+            return;
+        }
+
+        let killed = stack_overflow_kill();
+        self.slots[idx].stack_overflow = true;
+        if killed {
+            self.slots[idx].state = ThreadState::Terminated;
+        }
+        self.overflow_event = Some(StackOverflowEvent {
+            tid: idx,
+            damaged_words: damaged,
+            killed,
+        });
+    }
+
+    /// Find ready threads that have waited at least `threshold_us`
+    /// Each wait is reported once; returns a run-queue snapshot if any are new.
+    /// Background threads are expected to wait and aren't reported.
+    pub fn check_starvation(&mut self, now: u64, threshold_us: u64) -> Option<StarvationReport> {
+        let mut found = false;
+        for slot in self.slots.iter_mut() {
+            if slot.state == ThreadState::Ready
+                && slot.class == SchedClass::Normal
+                && slot.ready_since_us > 0
+                && !slot.starvation_reported
+                && now.saturating_sub(slot.ready_since_us) >= threshold_us
+            {
+                slot.starvation_reported = true;
+                found = true;
+            }
+        }
+
+        if !found {
+            return None;
+        }
+
+        let mut entries = [None; MAX_THREADS];
+        for (i, slot) in self.slots.iter().enumerate() {
+            if slot.state == ThreadState::Free {
+                continue;
+            }
+            let waiting_us = if slot.state == ThreadState::Ready && slot.ready_since_us > 0 {
+                now.saturating_sub(slot.ready_since_us)
+            } else {
+                0
+            };
+            entries[i] = Some(RunQueueEntry {
+                tid: i,
+                state: slot.state,
+                cooperative: slot.cooperative,
+                class: slot.class,
+                waiting_us,
+            });
+        }
+
+        let current = &self.slots[self.current_idx];
+        Some(StarvationReport {
+            threshold_us,
+            current: self.current_idx,
+            current_running_us: now.saturating_sub(current.start_time_us),
+            entries,
+        })
+    }
+
+    #[cfg(feature = "shell")]
     pub fn thread_stats(&self) -> (usize, usize, usize) {
         let mut ready = 0;
         let mut running = 0;
@@ -447,12 +1377,24 @@ impl ThreadPool {
                 ThreadState::Free => {}
                 ThreadState::Ready => ready += 1,
                 ThreadState::Running => running += 1,
+                ThreadState::Sleeping | ThreadState::Blocked => {}
                 ThreadState::Terminated => terminated += 1,
             }
         }
         (ready, running, terminated)
     }
 
+    /// CPU time of thread `idx`, including its current run if it is running
+    pub fn cpu_time_us(&self, idx: usize, now: u64) -> u64 {
+        let slot = &self.slots[idx];
+        if idx == self.current_idx {
+            slot.cpu_time_us + now.saturating_sub(slot.run_since_us)
+        } else {
+            slot.cpu_time_us
+        }
+    }
+
+    #[cfg(feature = "shell")]
     pub fn thread_count(&self) -> usize {
         self.slots
             .iter()
@@ -471,77 +1413,949 @@ impl ThreadPool {
     }
 }
 
-static POOL: Spinlock<ThreadPool> = Spinlock::new(ThreadPool::new());
-static VOLUNTARY_SCHEDULE: AtomicBool = AtomicBool::new(false);
+// ============================================================================
+// Cooperative Timeout Enforcement
+// ============================================================================
 
-/// Initialize the thread pool
-pub fn init() {
-    let mut pool = POOL.lock();
-    pool.init();
+/// A cooperative thread overran its timeout and the policy was applied
+#[derive(Debug, Clone, Copy)]
+pub struct TimeoutEvent {
+    pub tid: usize,
+    pub elapsed_us: u64,
+    pub policy: TimeoutPolicy,
+    /// Where the thread was when the tick caught it
+    pub backtrace: Option<Backtrace>,
 }
 
-/// Spawn a new preemptible thread with extern "C" entry
-pub fn spawn(entry: extern "C" fn() -> !) -> Result<usize, &'static str> {
-    spawn_with_options(entry, false)
+impl TimeoutEvent {
+    fn print(&self) {
+        let action = match self.policy {
+            TimeoutPolicy::Log => "still running",
+            TimeoutPolicy::Yield => "switched away",
+            TimeoutPolicy::Preempt => "now preemptible",
+            TimeoutPolicy::Kill => "killed",
+        };
+        crate::kevent!(
+            Event::CoopTimeout,
+            "[SCHED] Cooperative thread {} ran {} ms without yielding - {}",
+            self.tid,
+            self.elapsed_us / 1000,
+            action
+        );
+        if let Some(backtrace) = &self.backtrace {
+            for (i, addr) in backtrace.frames().iter().enumerate() {
+                crate::kwarn!("  #{:<2} {:#018x}", i, addr);
+            }
+        }
+    }
 }
 
-/// Spawn a cooperative thread (only yields voluntarily) with extern "C" entry
-pub fn spawn_cooperative(entry: extern "C" fn() -> !) -> Result<usize, &'static str> {
-    spawn_with_options(entry, true)
+static COOP_TIMEOUT_US: AtomicU64 = AtomicU64::new(COOPERATIVE_TIMEOUT_US);
+static COOP_TIMEOUT_POLICY: AtomicU8 = AtomicU8::new(TimeoutPolicy::Yield as u8);
+static COOP_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+/// Timeout given to cooperative threads spawned from now on (0 = none)
+pub fn cooperative_timeout_us() -> u64 {
+    COOP_TIMEOUT_US.load(Ordering::Relaxed)
 }
 
-/// Spawn with options (extern "C" entry)
-pub fn spawn_with_options(
-    entry: extern "C" fn() -> !,
-    cooperative: bool,
-) -> Result<usize, &'static str> {
-    with_irqs_disabled(|| {
-        let mut pool = POOL.lock();
-        pool.spawn(entry, cooperative)
-    })
+/// Set the timeout for cooperative threads spawned from now on (0 = none)
+pub fn set_cooperative_timeout_us(timeout_us: u64) {
+    COOP_TIMEOUT_US.store(timeout_us, Ordering::Relaxed);
 }
 
-/// Trampoline function that calls a boxed FnOnce closure
-/// Called from assembly with the closure pointer in x0
-fn closure_trampoline<F: FnOnce() -> ! + Send + 'static>(closure_ptr: *mut ()) -> ! {
-    // SAFETY: The pointer was created from Box::into_raw in spawn_fn
-    // and is only called once (the thread runs the closure and never returns)
-    let closure = unsafe { Box::from_raw(closure_ptr as *mut F) };
-    closure()
+/// Policy for cooperative threads spawned without one
+pub fn default_timeout_policy() -> TimeoutPolicy {
+    TimeoutPolicy::from_u8(COOP_TIMEOUT_POLICY.load(Ordering::Relaxed))
 }
 
-/// Spawn a new preemptible thread with a Rust closure
-///
-/// # Example
-/// ```
-/// spawn_fn(|| {
-///     loop {
-///         // thread work
-///         yield_now();
-///     }
-/// })
-/// ```
-pub fn spawn_fn<F>(f: F) -> Result<usize, &'static str>
-where
-    F: FnOnce() -> ! + Send + 'static,
-{
-    spawn_fn_with_options(f, false)
+/// Set the policy for cooperative threads spawned without one
+pub fn set_default_timeout_policy(policy: TimeoutPolicy) {
+    COOP_TIMEOUT_POLICY.store(policy.to_u8(), Ordering::Relaxed);
 }
 
-/// Spawn a cooperative thread with a Rust closure
-pub fn spawn_fn_cooperative<F>(f: F) -> Result<usize, &'static str>
-where
-    F: FnOnce() -> ! + Send + 'static,
-{
-    spawn_fn_with_options(f, true)
+/// Number of cooperative timeouts enforced since boot
+#[cfg(feature = "shell")]
+pub fn cooperative_timeouts() -> u64 {
+    COOP_TIMEOUTS.load(Ordering::Relaxed)
 }
 
-/// Spawn a thread with a Rust closure and options
-pub fn spawn_fn_with_options<F>(f: F, cooperative: bool) -> Result<usize, &'static str>
-where
-    F: FnOnce() -> ! + Send + 'static,
-{
-    // Box the closure and get a raw pointer
+/// Apply scheduler settings from the kernel command line
+///
+/// - `sched.coop_timeout_ms` - cooperative timeout (0 disables)
+/// - `sched.coop_timeout_policy` - `log`, `yield`, `preempt` or `kill`
+/// - `sched.slice_us` - time slice of preemptible threads (0 = every tick)
+/// - `sched.priority_slices` - per-band slices, `priority:us,...`
+/// - `sched.tick_us` - timer interrupt interval
+/// - `sched.starvation_ms` - starvation report threshold (0 disables)
+/// - `sched.stack_overflow` - `log` or `kill` a thread whose canary is damaged
+///
+/// `sched.policy` (`rr`, `priority` or `edf`) is read by `init` itself.
+fn apply_config() {
+    if let Some(ms) = crate::config::get_u64("sched.coop_timeout_ms") {
+        set_cooperative_timeout_us(ms * 1000);
+    }
+    if let Some(value) = crate::config::get("sched.coop_timeout_policy") {
+        match TimeoutPolicy::parse(&value) {
+            Some(policy) => set_default_timeout_policy(policy),
+            None => crate::kwarn!(
+                "[SCHED] Unknown sched.coop_timeout_policy '{}', using {}",
+                value,
+                default_timeout_policy().as_str()
+            ),
+        }
+    }
+    if let Some(us) = crate::config::get_u64("sched.slice_us") {
+        set_slice_us(us);
+    }
+    if let Some(value) = crate::config::get("sched.priority_slices") {
+        for entry in value.split(',') {
+            let parsed = entry.split_once(':').and_then(|(priority, us)| {
+                Some((priority.trim().parse::<u8>().ok()?, us.trim().parse::<u64>().ok()?))
+            });
+            match parsed {
+                Some((priority, us)) => set_priority_slice_us(priority, us),
+                None => crate::kwarn!("[SCHED] Ignoring sched.priority_slices entry '{}'", entry),
+            }
+        }
+    }
+    if let Some(us) = crate::config::get_u64("sched.tick_us")
+        && let Err(e) = crate::timer::set_tick_interval_us(us)
+    {
+        crate::kwarn!("[SCHED] sched.tick_us={} not applied: {}", us, e);
+    }
+    if let Some(ms) = crate::config::get_u64("sched.starvation_ms") {
+        set_starvation_threshold_us(ms * 1000);
+    }
+    if let Some(value) = crate::config::get("sched.stack_overflow") {
+        match value.as_str() {
+            "log" => set_stack_overflow_kill(false),
+            "kill" => set_stack_overflow_kill(true),
+            _ => crate::kwarn!(
+                "[SCHED] Unknown sched.stack_overflow '{}', using log",
+                value
+            ),
+        }
+    }
+}
+
+// ============================================================================
+// Stack Canaries
+// ============================================================================
+
+/// A thread's stack canary was found damaged on a context switch
+#[derive(Debug, Clone, Copy)]
+pub struct StackOverflowEvent {
+    pub tid: usize,
+    pub damaged_words: usize,
+    pub killed: bool,
+}
+
+impl StackOverflowEvent {
+    fn print(&self) {
+        crate::kevent!(
+            Event::StackOverflow,
+            "[SCHED] Stack overflow in thread {}: {}/{} canary words damaged - {}",
+            self.tid,
+            self.damaged_words,
+            CANARY_WORDS,
+            if self.killed { "killed" } else { "still running" }
+        );
+    }
+}
+
+static STACK_OVERFLOWS: AtomicU64 = AtomicU64::new(0);
+static STACK_OVERFLOW_KILL: AtomicBool = AtomicBool::new(false);
+
+/// Fill the canary at the bottom of the stack starting at `base`
+fn write_canary(base: usize) {
+    for i in 0..CANARY_WORDS {
+        // SAFETY: the first CANARY_WORDS words of a pool stack are reserved for the canary
+        unsafe { core::ptr::write_volatile((base as *mut u64).add(i), STACK_CANARY) };
+    }
+}
+
+/// Number of canary words no longer holding the pattern
+fn canary_damage(base: usize) -> usize {
+    (0..CANARY_WORDS)
+        .filter(|&i| {
+            // SAFETY: see write_canary
+            unsafe { core::ptr::read_volatile((base as *const u64).add(i)) != STACK_CANARY }
+        })
+        .count()
+}
+
+/// Whether a thread with a damaged canary is terminated (default: only flagged)
+pub fn stack_overflow_kill() -> bool {
+    STACK_OVERFLOW_KILL.load(Ordering::Relaxed)
+}
+
+pub fn set_stack_overflow_kill(kill: bool) {
+    STACK_OVERFLOW_KILL.store(kill, Ordering::Relaxed);
+}
+
+/// Number of damaged stack canaries detected since boot
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn stack_overflows() -> u64 {
+    STACK_OVERFLOWS.load(Ordering::Relaxed)
+}
+
+/// True if `tid`'s stack canary was found damaged since it was spawned
+pub fn stack_overflowed(tid: usize) -> bool {
+    tid < MAX_THREADS && with_irqs_disabled(|| POOL.lock().slots[tid].stack_overflow)
+}
+
+// ============================================================================
+// Stack Usage
+// ============================================================================
+
+/// Fill the stack starting at `base` with `STACK_FILL`
+fn fill_stack(base: usize, size: usize) {
+    // SAFETY: the stack belongs to a free slot, so nothing runs on it
+    unsafe { core::ptr::write_bytes(base as *mut u64, 0x5A, size / 8) };
+}
+
+/// Bytes below the top of the stack at `base` that were ever written
+#[cfg(all(feature = "fs", feature = "shell"))]
+fn stack_used(base: usize, size: usize) -> usize {
+    let words = size / 8;
+    (CANARY_WORDS..words)
+        .find(|&i| {
+            // SAFETY: i is inside the stack, which stays allocated while its slot is live
+            unsafe { core::ptr::read_volatile((base as *const u64).add(i)) != STACK_FILL }
+        })
+        .map_or(0, |i| (words - i) * 8)
+}
+
+/// Deepest stack use of thread `tid` since it was spawned, as (bytes
+/// used, stack size)
+///
+/// None for free slots and the boot thread, whose stack isn't filled. A
+/// thread that reaches its canary shows the whole stack as used. Scans the
+/// stack under the scheduler lock, so call it for diagnostics, not per
+/// packet.
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn stack_high_water(tid: usize) -> Option<(usize, usize)> {
+    if tid >= MAX_THREADS {
+        return None;
+    }
+    with_irqs_disabled(|| {
+        let pool = POOL.lock();
+        let base = pool.stacks[tid];
+        if base == 0 || pool.slots[tid].state == ThreadState::Free {
+            return None;
+        }
+        let size = pool.stack_sizes[tid];
+        let used = if canary_damage(base) > 0 {
+            size
+        } else {
+            stack_used(base, size)
+        };
+        Some((used, size))
+    })
+}
+
+// ============================================================================
+// Thread-Local Storage
+// ============================================================================
+
+/// Values of every `ThreadLocal` per thread, indexed by tid
+///
+/// Only the owning thread touches its row (and `reset_tls` before it
+/// runs), so the atomics are never contended.
+static TLS: [[AtomicUsize; TLS_SLOTS]; MAX_THREADS] =
+    [const { [const { AtomicUsize::new(0) }; TLS_SLOTS] }; MAX_THREADS];
+
+/// Slots handed out to `ThreadLocal` statics so far
+static TLS_NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Record `tid` as the running thread in TPIDR_EL1, where `ThreadLocal`
+/// finds its row without taking the scheduler lock
+fn set_tls_thread(tid: usize) {
+    // SAFETY: TPIDR_EL1 is a scratch register reserved for this
+    unsafe { core::arch::asm!("msr tpidr_el1, {}", in(reg) tid as u64) };
+}
+
+/// Running thread's tid as recorded in TPIDR_EL1
+///
+/// Doesn't take the scheduler lock, so code that lock goes through
+/// (lockdep) can tell threads apart too.
+pub fn tls_tid() -> usize {
+    let tid: u64;
+    // SAFETY: reading TPIDR_EL1 has no side effects
+    unsafe { core::arch::asm!("mrs {}, tpidr_el1", out(reg) tid) };
+    // Whatever firmware left there counts as the boot thread until `init`
+    if (tid as usize) < MAX_THREADS {
+        tid as usize
+    } else {
+        IDLE_THREAD_IDX
+    }
+}
+
+/// TLS row of the running thread
+fn tls_row() -> &'static [AtomicUsize; TLS_SLOTS] {
+    &TLS[tls_tid()]
+}
+
+/// Zero the TLS row of slot `idx` for a new thread
+fn reset_tls(idx: usize) {
+    for value in &TLS[idx] {
+        value.store(0, Ordering::Relaxed);
+    }
+}
+
+/// A `usize` with a separate value per thread, e.g. an errno-style last
+/// error, RNG state or a logging context
+///
+/// Declare it as a static: `static LAST_ERROR: ThreadLocal = ThreadLocal::new();`.
+/// Every thread starts out reading 0. A static claims one of `TLS_SLOTS`
+/// slots the first time it is set; once all are claimed, `set` fails with
+/// `NoFreeSlots` and `get` keeps returning 0.
+pub struct ThreadLocal {
+    /// Claimed slot + 1 (0 = none yet)
+    slot: AtomicUsize,
+}
+
+impl ThreadLocal {
+    pub const fn new() -> Self {
+        Self {
+            slot: AtomicUsize::new(0),
+        }
+    }
+
+    fn slot(&self) -> Option<usize> {
+        self.slot.load(Ordering::Acquire).checked_sub(1)
+    }
+
+    /// Claim a slot; if two threads race, the loser's slot goes unused
+    fn claim(&self) -> KResult<usize> {
+        if let Some(slot) = self.slot() {
+            return Ok(slot);
+        }
+        let slot = TLS_NEXT.fetch_add(1, Ordering::AcqRel);
+        if slot >= TLS_SLOTS {
+            return Err(KError::with_context(ErrorKind::NoFreeSlots, "thread-local"));
+        }
+        match self
+            .slot
+            .compare_exchange(0, slot + 1, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => Ok(slot),
+            Err(winner) => Ok(winner - 1),
+        }
+    }
+
+    /// This thread's value
+    pub fn get(&self) -> usize {
+        self.slot()
+            .map_or(0, |slot| tls_row()[slot].load(Ordering::Relaxed))
+    }
+
+    /// Set this thread's value
+    pub fn set(&self, value: usize) -> KResult<()> {
+        let slot = self.claim()?;
+        tls_row()[slot].store(value, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Default for ThreadLocal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ============================================================================
+// Deadlines
+// ============================================================================
+
+/// Deadline of the current thread in uptime microseconds (0 = none)
+pub fn deadline_us() -> u64 {
+    with_irqs_disabled(|| {
+        let pool = POOL.lock();
+        pool.slots[pool.current_idx].deadline_us
+    })
+}
+
+/// Set the current thread's deadline (0 clears it); see `sched::with_deadline`
+pub fn set_deadline_us(deadline_us: u64) {
+    with_irqs_disabled(|| {
+        let mut pool = POOL.lock();
+        let idx = pool.current_idx;
+        pool.slots[idx].deadline_us = deadline_us;
+        pool.slots[idx].deadline_fired = false;
+    })
+}
+
+/// True if the current thread has a deadline and it has passed
+pub fn deadline_expired() -> bool {
+    let deadline = deadline_us();
+    deadline > 0 && crate::timer::uptime_us() >= deadline
+}
+
+/// Stack range of the current thread (None on the boot stack)
+pub fn current_stack_bounds() -> Option<(usize, usize)> {
+    with_irqs_disabled(|| {
+        let pool = POOL.lock();
+        let idx = pool.current_idx;
+        let base = pool.stacks[idx];
+        (base != 0).then_some((base, base + pool.stack_sizes[idx]))
+    })
+}
+
+// ============================================================================
+// Starvation Detection
+// ============================================================================
+
+/// One thread in a run-queue snapshot
+#[derive(Debug, Clone, Copy)]
+pub struct RunQueueEntry {
+    pub tid: usize,
+    pub state: ThreadState,
+    pub cooperative: bool,
+    pub class: SchedClass,
+    /// Time spent Ready without running (0 unless Ready)
+    pub waiting_us: u64,
+}
+
+/// Run-queue snapshot taken when a ready thread is found starved
+#[derive(Debug, Clone, Copy)]
+pub struct StarvationReport {
+    pub threshold_us: u64,
+    /// Thread that was running when starvation was detected
+    pub current: usize,
+    pub current_running_us: u64,
+    pub entries: [Option<RunQueueEntry>; MAX_THREADS],
+}
+
+impl StarvationReport {
+    /// Log the report with one line per live thread
+    pub fn print(&self) {
+        let cur = self.entries[self.current];
+        crate::kevent!(
+            Event::Starvation,
+            "[SCHED] WARNING: thread starvation (threshold {} ms) - thread {} running for {} ms{}",
+            self.threshold_us / 1000,
+            self.current,
+            self.current_running_us / 1000,
+            if cur.is_some_and(|e| e.cooperative) {
+                " (cooperative)"
+            } else {
+                ""
+            }
+        );
+        for entry in self.entries.iter().flatten() {
+            let starved = entry.waiting_us >= self.threshold_us;
+            crate::kwarn!(
+                "  [{:>2}] {:<10} {:<5} {:<10} waiting {:>6} ms{}",
+                entry.tid,
+                entry.state.as_str(),
+                if entry.cooperative { "coop" } else { "pre" },
+                entry.class.as_str(),
+                entry.waiting_us / 1000,
+                if starved { "  <- STARVED" } else { "" }
+            );
+        }
+    }
+}
+
+/// Ready threads waiting longer than this are reported (0 = disabled)
+static STARVATION_THRESHOLD_US: AtomicU64 = AtomicU64::new(DEFAULT_STARVATION_THRESHOLD_US);
+static NEXT_STARVATION_CHECK_US: AtomicU64 = AtomicU64::new(0);
+static STARVATION_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Set how long a ready thread may wait before it is reported (0 disables)
+pub fn set_starvation_threshold_us(threshold_us: u64) {
+    STARVATION_THRESHOLD_US.store(threshold_us, Ordering::Relaxed);
+}
+
+/// Current starvation threshold in microseconds (0 = disabled)
+pub fn starvation_threshold_us() -> u64 {
+    STARVATION_THRESHOLD_US.load(Ordering::Relaxed)
+}
+
+/// Number of starvation reports since boot
+#[cfg(feature = "shell")]
+pub fn starvation_events() -> u64 {
+    STARVATION_EVENTS.load(Ordering::Relaxed)
+}
+
+/// Rate-limited run-queue scan, called from the timer-driven schedule path
+/// Runs before the cooperative check so a hogging cooperative thread is caught.
+fn check_starvation(pool: &mut ThreadPool) -> Option<StarvationReport> {
+    let threshold = STARVATION_THRESHOLD_US.load(Ordering::Relaxed);
+    if threshold == 0 {
+        return None;
+    }
+
+    let now = crate::timer::uptime_us();
+    if now < NEXT_STARVATION_CHECK_US.load(Ordering::Relaxed) {
+        return None;
+    }
+    NEXT_STARVATION_CHECK_US.store(now + STARVATION_CHECK_INTERVAL_US, Ordering::Relaxed);
+
+    let report = pool.check_starvation(now, threshold)?;
+    STARVATION_EVENTS.fetch_add(1, Ordering::Relaxed);
+    Some(report)
+}
+
+// ============================================================================
+// Tick Coalescing
+// ============================================================================
+
+/// Default time slice of a preemptible thread
+pub const DEFAULT_SLICE_US: u64 = 10_000;
+
+static SLICE_US: AtomicU64 = AtomicU64::new(DEFAULT_SLICE_US);
+static COALESCED_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Time slice of preemptible threads (0 = run the scheduler every tick)
+pub fn slice_us() -> u64 {
+    SLICE_US.load(Ordering::Relaxed)
+}
+
+pub fn set_slice_us(slice_us: u64) {
+    SLICE_US.store(slice_us, Ordering::Relaxed);
+}
+
+/// Priority bands with their own slice: 0-63, 64-127, 128-191, 192-255
+pub const PRIORITY_BANDS: usize = 4;
+
+/// Slice per priority band (0 = the global `slice_us`)
+static PRIORITY_SLICE_US: [AtomicU64; PRIORITY_BANDS] =
+    [const { AtomicU64::new(0) }; PRIORITY_BANDS];
+
+fn priority_band(priority: u8) -> usize {
+    priority as usize * PRIORITY_BANDS / 256
+}
+
+/// Time slice of a preemptible thread at `priority`
+pub fn priority_slice_us(priority: u8) -> u64 {
+    match PRIORITY_SLICE_US[priority_band(priority)].load(Ordering::Relaxed) {
+        0 => slice_us(),
+        slice => slice,
+    }
+}
+
+/// Give every priority in `priority`'s band its own slice (0 = back to
+/// the global `slice_us`)
+///
+/// Takes effect at the next tick, for threads already running too. A
+/// global slice of 0 still runs the scheduler on every tick.
+pub fn set_priority_slice_us(priority: u8, slice_us: u64) {
+    PRIORITY_SLICE_US[priority_band(priority)].store(slice_us, Ordering::Relaxed);
+}
+
+/// Timer ticks that didn't raise the scheduler SGI
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn coalesced_ticks() -> u64 {
+    COALESCED_TICKS.load(Ordering::Relaxed)
+}
+
+/// Called by the timer tick: whether to raise the scheduler SGI
+///
+/// Most ticks under load find the running thread mid-slice with nobody
+/// more urgent waiting; skipping the SGI for those saves a pass through
+/// the scheduler (and the switch a round-robin pass would make).
+pub fn tick_should_schedule() -> bool {
+    let slice = slice_us();
+    if slice == 0 {
+        return true;
+    }
+    let now = crate::timer::uptime_us();
+    // The starvation scan only runs from the scheduler
+    if STARVATION_THRESHOLD_US.load(Ordering::Relaxed) > 0
+        && now >= NEXT_STARVATION_CHECK_US.load(Ordering::Relaxed)
+    {
+        return true;
+    }
+    // The tick may interrupt the scheduler SGI itself; if the pool is
+    // busy, let the scheduler decide
+    let Some(pool) = POOL.try_lock() else {
+        return true;
+    };
+    let needed = pool.tick_needs_schedule(now);
+    drop(pool);
+    if !needed {
+        COALESCED_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    needed
+}
+
+// ============================================================================
+// Preemption and Migration Scopes
+// ============================================================================
+
+/// `no_preempt` depth of the running thread (saved in its slot on switch)
+static PREEMPT_DEPTH: AtomicU32 = AtomicU32::new(0);
+/// `pin_current` depth of the running thread (saved in its slot on switch)
+static PIN_DEPTH: AtomicU32 = AtomicU32::new(0);
+/// A timer tick was skipped because preemption was disabled
+static PREEMPT_PENDING: AtomicBool = AtomicBool::new(false);
+/// Blocking calls made while preemption was disabled or the thread pinned
+static SCOPE_VIOLATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Disable preemption of the current thread (nests)
+///
+/// Timer ticks still fire and IRQ handlers still run; the scheduler just
+/// won't switch away until the matching `preempt_enable`. Prefer
+/// `sched::no_preempt`, which pairs the calls.
+pub fn preempt_disable() {
+    PREEMPT_DEPTH.fetch_add(1, Ordering::Acquire);
+}
+
+/// Re-enable preemption; runs a deferred reschedule when the outermost
+/// scope ends
+pub fn preempt_enable() {
+    let prev = PREEMPT_DEPTH.fetch_sub(1, Ordering::Release);
+    debug_assert!(prev > 0, "preempt_enable without preempt_disable");
+    if prev == 1 && PREEMPT_PENDING.swap(false, Ordering::AcqRel) {
+        yield_now();
+    }
+}
+
+/// Current `no_preempt` nesting depth of this thread
+pub fn preempt_depth() -> u32 {
+    PREEMPT_DEPTH.load(Ordering::Relaxed)
+}
+
+/// Keep the current thread on this CPU (nests)
+///
+/// With a single CPU nothing migrates yet, so this only tracks the depth
+/// for the blocking checks; SMP scheduling must honour it.
+pub fn migrate_disable() {
+    PIN_DEPTH.fetch_add(1, Ordering::Acquire);
+}
+
+/// Allow the current thread to migrate again
+pub fn migrate_enable() {
+    let prev = PIN_DEPTH.fetch_sub(1, Ordering::Release);
+    debug_assert!(prev > 0, "migrate_enable without migrate_disable");
+}
+
+/// Current `pin_current` nesting depth of this thread
+pub fn pin_depth() -> u32 {
+    PIN_DEPTH.load(Ordering::Relaxed)
+}
+
+/// Number of blocking calls made inside a no_preempt/pin scope
+pub fn scope_violations() -> u64 {
+    SCOPE_VIOLATIONS.load(Ordering::Relaxed)
+}
+
+/// Debug check: a blocking call must not happen inside a scope
+fn check_blocking_allowed(what: &str) {
+    let preempt = PREEMPT_DEPTH.load(Ordering::Relaxed);
+    let pin = PIN_DEPTH.load(Ordering::Relaxed);
+    if preempt == 0 && pin == 0 {
+        return;
+    }
+    SCOPE_VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+    if cfg!(debug_assertions) {
+        crate::kwarn!(
+            "[SCHED] {} inside no_preempt/pin scope (preempt depth {}, pin depth {})",
+            what, preempt, pin
+        );
+    }
+}
+
+static POOL: Spinlock<ThreadPool> = Spinlock::new(ThreadPool::new());
+static VOLUNTARY_SCHEDULE: AtomicBool = AtomicBool::new(false);
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Initialize the thread pool
+///
+/// Only once: re-initializing would forget every thread already spawned.
+pub fn init() -> KResult<()> {
+    if INITIALIZED.swap(true, Ordering::AcqRel) {
+        return Err(KError::with_context(ErrorKind::AlreadyInitialized, "threading"));
+    }
+    apply_config();
+    let policy = configured_policy();
+    let mut pool = POOL.lock();
+    pool.init(policy);
+    set_tls_thread(IDLE_THREAD_IDX);
+    Ok(())
+}
+
+/// The policy named by `sched.policy`, priority if unset or unknown
+fn configured_policy() -> Box<dyn SchedPolicy> {
+    if let Some(name) = crate::config::get("sched.policy") {
+        match sched_policy::by_name(&name) {
+            Some(policy) => return policy,
+            None => crate::kwarn!("[SCHED] Unknown sched.policy '{}', using priority", name),
+        }
+    }
+    Box::new(sched_policy::Priority::new())
+}
+
+/// Switch scheduling policy; live threads move to its run queue
+pub fn set_policy(policy: Box<dyn SchedPolicy>) {
+    let old = with_irqs_disabled(|| POOL.lock().replace_policy(policy));
+    // Freed outside the lock
+    drop(old);
+}
+
+/// Name of the scheduling policy in use
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn policy_name() -> &'static str {
+    with_irqs_disabled(|| {
+        POOL.lock()
+            .policy
+            .as_ref()
+            .map(|policy| policy.name())
+            .unwrap_or("none")
+    })
+}
+
+/// Change the scheduling parameters of thread `tid`
+pub fn set_sched_params(tid: usize, params: SchedParams) -> KResult<()> {
+    with_irqs_disabled(|| POOL.lock().set_params(tid, params))
+}
+
+/// Scheduling parameters of thread `tid` (None if the slot is free)
+pub fn sched_params(tid: usize) -> Option<SchedParams> {
+    with_irqs_disabled(|| {
+        let pool = POOL.lock();
+        pool.slots
+            .get(tid)
+            .filter(|slot| slot.state != ThreadState::Free)
+            .map(|slot| slot.params)
+    })
+}
+
+/// Make thread `tid` preemptible or cooperative from now on
+///
+/// E.g. a driver thread turns cooperative while it holds a device through
+/// a sequence that must not be interleaved, and back afterwards. The
+/// cooperative timeout keeps applying while it is cooperative.
+pub fn set_preemptible(tid: usize, preemptible: bool) -> KResult<()> {
+    with_irqs_disabled(|| POOL.lock().set_preemptible(tid, preemptible))
+}
+
+/// Whether thread `tid` can be preempted, if it exists
+pub fn is_preemptible(tid: usize) -> Option<bool> {
+    with_irqs_disabled(|| {
+        let pool = POOL.lock();
+        pool.slots
+            .get(tid)
+            .filter(|slot| slot.state != ThreadState::Free)
+            .map(|slot| !slot.cooperative)
+    })
+}
+
+/// Set the priority of thread `tid`, keeping its other parameters
+///
+/// Higher runs first under the `priority` policy; see
+/// `sched_policy::PRIORITY_NETWORK` and `PRIORITY_BULK`.
+pub fn set_priority(tid: usize, priority: u8) -> KResult<()> {
+    let params = sched_params(tid).ok_or(KError::with_context(ErrorKind::NotFound, "thread"))?;
+    set_sched_params(tid, SchedParams { priority, ..params })
+}
+
+/// Priority of thread `tid`, if it exists
+pub fn priority(tid: usize) -> Option<u8> {
+    sched_params(tid).map(|params| params.priority)
+}
+
+// ============================================================================
+// Deadline Class
+// ============================================================================
+
+/// Times a deadline thread was switched out for using up its budget
+static DEADLINE_OVERRUNS: AtomicU64 = AtomicU64::new(0);
+
+/// End of the period that contains `now`, after one that ended at `end`
+///
+/// Periods follow each other back to back; a thread that missed whole
+/// periods (blocked, or starved by cooperative threads) starts afresh.
+fn next_period_end(end: u64, period_us: u64, now: u64) -> u64 {
+    let next = end.saturating_add(period_us);
+    if now < next { next } else { now.saturating_add(period_us) }
+}
+
+/// Put thread `tid` in the deadline class, or back in the normal one
+/// with None
+///
+/// Each `period_us` the thread gets `budget_us` of CPU time ahead of every
+/// normal thread, and deadline threads with budget left run earliest
+/// period end first, so a thread that is runnable when its period starts
+/// is on the CPU within that period. A thread that has used its budget,
+/// or yielded, waits like a background thread until its next period.
+/// Budgets are enforced at the timer tick, so periods shorter than
+/// `timer::tick_interval_us` aren't kept. Fails with `LimitReached` if
+/// deadline threads would reserve over `MAX_DEADLINE_PERMILLE` of the CPU.
+pub fn set_deadline_class(tid: usize, params: Option<DeadlineParams>) -> KResult<()> {
+    with_irqs_disabled(|| POOL.lock().set_deadline_class(tid, params))
+}
+
+/// Period and budget of thread `tid` if it is in the deadline class
+pub fn deadline_params(tid: usize) -> Option<DeadlineParams> {
+    with_irqs_disabled(|| {
+        let pool = POOL.lock();
+        pool.slots
+            .get(tid)
+            .filter(|slot| slot.class == SchedClass::Deadline && slot.state != ThreadState::Free)
+            .map(|slot| slot.deadline_params)
+    })
+}
+
+/// Times a deadline thread used up its budget since boot
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn deadline_overruns() -> u64 {
+    DEADLINE_OVERRUNS.load(Ordering::Relaxed)
+}
+
+/// Spawn a new preemptible thread with extern "C" entry
+pub fn spawn(entry: extern "C" fn() -> !) -> KResult<usize> {
+    spawn_with_options(entry, false)
+}
+
+/// Spawn a cooperative thread (only yields voluntarily) with extern "C" entry
+pub fn spawn_cooperative(entry: extern "C" fn() -> !) -> KResult<usize> {
+    spawn_with_options(entry, true)
+}
+
+/// Spawn with options (extern "C" entry)
+pub fn spawn_with_options(
+    entry: extern "C" fn() -> !,
+    cooperative: bool,
+) -> KResult<usize> {
+    with_irqs_disabled(|| {
+        let mut pool = POOL.lock();
+        pool.spawn(entry, cooperative, None, SchedClass::Normal, SchedParams::new())
+    })
+}
+
+/// Trampoline function that calls a boxed FnOnce closure
+/// Called from assembly with the closure pointer in x0
+fn closure_trampoline<F: FnOnce() + Send + 'static>(closure_ptr: *mut ()) -> ! {
+    // Take the closure out of the slot and off the heap with IRQs masked,
+    // so a kill can't land in between: reaping frees it only if we didn't
+    let closure = with_irqs_disabled(|| {
+        let mut pool = POOL.lock();
+        let idx = pool.current_idx;
+        pool.slots[idx].closure = None;
+        drop(pool);
+        // SAFETY: The pointer was created from Box::into_raw in spawn_fn
+        // and is only taken once (the slot no longer refers to it)
+        unsafe { *Box::from_raw(closure_ptr as *mut F) }
+    });
+    closure();
+    exit()
+}
+
+/// Free the boxed closure of a thread killed before it started
+///
+/// # Safety
+/// `closure_ptr` must come from `Box::into_raw` of a `Box<F>` that
+/// nothing else frees.
+unsafe fn drop_closure<F>(closure_ptr: *mut ()) {
+    drop(unsafe { Box::from_raw(closure_ptr as *mut F) });
+}
+
+/// Spawn a new preemptible thread with a Rust closure
+///
+/// The thread terminates when the closure returns (or calls `exit`).
+///
+/// # Example
+/// ```
+/// spawn_fn(|| {
+///     while !done() {
+///         // thread work
+///         yield_now();
+///     }
+/// })
+/// ```
+pub fn spawn_fn<F>(f: F) -> KResult<usize>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_fn_with_options(f, false)
+}
+
+/// Spawn a cooperative thread with a Rust closure
+pub fn spawn_fn_cooperative<F>(f: F) -> KResult<usize>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_fn_with_options(f, true)
+}
+
+/// Spawn a thread with a Rust closure and options
+pub fn spawn_fn_with_options<F>(f: F, cooperative: bool) -> KResult<usize>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_closure_inner(f, cooperative, None, SchedClass::Normal, "", None)
+}
+
+/// Spawn a preemptible thread with a Rust closure, called `name` in
+/// diagnostics (`for_each_thread`, the sysreport)
+pub fn spawn_named<F>(name: &'static str, f: F) -> KResult<usize>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_closure_inner(f, false, None, SchedClass::Normal, name, None)
+}
+
+/// Spawn a preemptible thread with a Rust closure on a stack of `size`
+/// bytes instead of the default 32 KiB
+///
+/// For threads that need a deep stack (crypto) or barely any. `size` is
+/// rounded up to 16 bytes and must lie between 8 KiB and 1 MiB. Once the
+/// thread has been reaped its slot gets a default stack again.
+pub fn spawn_with_stack<F>(f: F, size: usize) -> KResult<usize>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let size = (size + 15) & !15;
+    if !(MIN_STACK_SIZE..=MAX_STACK_SIZE).contains(&size) {
+        return Err(KError::with_context(ErrorKind::InvalidArgument, "stack size"));
+    }
+    spawn_closure_inner(f, false, None, SchedClass::Normal, "", Some(size))
+}
+
+/// Spawn a background thread called `name` with a Rust closure
+///
+/// It only runs when no normal thread is ready, so housekeeping (log
+/// flushing, heap scrubbing, reaping) never delays the network path.
+pub fn spawn_fn_background<F>(name: &'static str, f: F) -> KResult<usize>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_closure_inner(f, false, None, SchedClass::Background, name, None)
+}
+
+/// Spawn a cooperative thread with its own timeout policy
+pub fn spawn_fn_cooperative_with_policy<F>(
+    f: F,
+    policy: TimeoutPolicy,
+) -> KResult<usize>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_closure_inner(f, true, Some(policy), SchedClass::Normal, "", None)
+}
+
+fn spawn_closure_inner<F>(
+    f: F,
+    cooperative: bool,
+    policy: Option<TimeoutPolicy>,
+    class: SchedClass,
+    name: &'static str,
+    stack_size: Option<usize>,
+) -> KResult<usize>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut stack = match stack_size {
+        Some(size) => Some((alloc_stack(size)?, size)),
+        None => None,
+    };
+
+    // Box the closure and get a raw pointer
     let boxed: Box<F> = Box::new(f);
     let closure_ptr = Box::into_raw(boxed) as *mut ();
 
@@ -550,64 +2364,414 @@ where
 
     let result = with_irqs_disabled(|| {
         let mut pool = POOL.lock();
-        pool.spawn_closure(trampoline, closure_ptr, cooperative)
+        let tid = pool.spawn_closure(
+            trampoline,
+            closure_ptr,
+            drop_closure::<F>,
+            cooperative,
+            policy,
+            class,
+            SchedParams::new(),
+            &mut stack,
+        )?;
+        // Named before it can run
+        pool.slots[tid].name = name;
+        Ok(tid)
     });
 
-    // If spawn failed, we need to clean up the boxed closure
-    if result.is_err() {
-        // SAFETY: We just created this pointer and spawn failed, so we own it
-        unsafe {
-            let _ = Box::from_raw(closure_ptr as *mut F);
-        }
+    // If spawn failed, we need to clean up the boxed closure
+    if result.is_err() {
+        // SAFETY: We just created this pointer and spawn failed, so we own it
+        unsafe {
+            let _ = Box::from_raw(closure_ptr as *mut F);
+        }
+    }
+
+    // The stack the custom one displaced, or the custom one if spawn failed
+    if let Some((base, size)) = stack {
+        // SAFETY: no slot refers to it any more
+        unsafe { free_stack(base, size) };
+    }
+
+    result
+}
+
+/// Allocate a zeroed stack of `size` bytes, returning its base
+fn alloc_stack(size: usize) -> KResult<usize> {
+    let stack = crate::allocator::try_zeroed_vec(size)
+        .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "thread stack"))?
+        .into_boxed_slice();
+    Ok(Box::into_raw(stack) as *mut u8 as usize)
+}
+
+/// Free a stack allocated by `alloc_stack` or `ThreadPool::init`
+///
+/// # Safety
+/// `base` and `size` must describe such a stack, and no thread may run on it.
+unsafe fn free_stack(base: usize, size: usize) {
+    let stack = core::ptr::slice_from_raw_parts_mut(base as *mut u8, size);
+    // SAFETY: the caller guarantees it came from a leaked boxed slice
+    drop(unsafe { Box::from_raw(stack) });
+}
+
+// ============================================================================
+// Join Handles
+// ============================================================================
+
+/// Owned permission to wait for a thread spawned with `spawn_joinable`
+/// and take the value it returned
+///
+/// Dropping the handle detaches the thread: it still runs to completion
+/// and its result is dropped with the last reference.
+pub struct JoinHandle<T> {
+    tid: usize,
+    result: Arc<JoinSlot<T>>,
+}
+
+/// Where a joinable thread leaves its return value
+struct JoinSlot<T> {
+    value: Mutex<Option<T>>,
+    done: Condvar,
+}
+
+impl<T> JoinHandle<T> {
+    /// Thread the handle waits for (the slot may be reused once it has
+    /// finished and been reaped)
+    pub fn thread_id(&self) -> usize {
+        self.tid
+    }
+
+    /// Whether the thread has returned
+    pub fn is_finished(&self) -> bool {
+        self.result.value.lock().is_ok_and(|value| value.is_some())
+    }
+
+    /// Block until the thread returns and take its result
+    ///
+    /// Inside `sched::with_deadline` it fails with `TimedOut` once the
+    /// deadline passes; the thread is then detached, as if the handle had
+    /// been dropped.
+    pub fn join(self) -> KResult<T> {
+        let slot = &self.result;
+        let mut value = slot.done.wait_while(slot.value.lock()?, |value| value.is_none())?;
+        Ok(value.take().expect("joined thread left no result"))
+    }
+}
+
+/// Spawn a preemptible thread that runs `f` to completion
+///
+/// Like `spawn_fn`, but `JoinHandle::join` hands the closure's return
+/// value to the spawner, like `std::thread::spawn`.
+pub fn spawn_joinable<F, T>(f: F) -> KResult<JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(JoinSlot {
+        value: Mutex::new(None),
+        done: Condvar::new(),
+    });
+    let slot = result.clone();
+    let tid = spawn_fn(move || {
+        let value = f();
+        // A new thread starts without a deadline, so this can't time out
+        if let Ok(mut result) = slot.value.lock() {
+            *result = Some(value);
+        }
+        slot.done.notify_all();
+    })?;
+    Ok(JoinHandle { tid, result })
+}
+
+/// SGI handler for scheduling; `frame` is what the SGI interrupted
+pub fn sgi_scheduler_handler(irq: u32, frame: &IrqFrame) {
+    crate::gic::end_of_interrupt(irq);
+
+    let voluntary = VOLUNTARY_SCHEDULE.swap(false, Ordering::Acquire);
+
+    let (switch_info, starvation, timeout, overflow, pool_ptr) = {
+        let mut pool = POOL.lock();
+        let ptr = &mut *pool as *mut ThreadPool;
+        let starvation = if voluntary {
+            None
+        } else {
+            check_starvation(&mut pool)
+        };
+        let switch_info = pool.schedule_indices(voluntary);
+        let overflow = pool.overflow_event.take();
+        (switch_info, starvation, pool.timeout_event.take(), overflow, ptr)
+    };
+
+    // Log outside the pool lock; as interrupt context, so the records are
+    // staged rather than printed over whatever the interrupted thread was
+    // writing
+    crate::irq::as_handler(|| {
+        if let Some(report) = starvation {
+            report.print();
+        }
+        if let Some(mut event) = timeout {
+            COOP_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+            // Timeouts are only enforced on a tick, so the interrupted
+            // code is the thread that ran too long
+            event.backtrace = Some(Backtrace::from_frame(
+                Some(frame.elr as usize),
+                frame.x29 as usize,
+            ));
+            event.print();
+        }
+        if let Some(event) = overflow {
+            STACK_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+            event.print();
+        }
+    });
+
+    if let Some((old_idx, new_idx)) = switch_info {
+        set_tls_thread(new_idx);
+        unsafe {
+            let pool = &mut *pool_ptr;
+            let (old_ptr, new_ptr) = pool.get_context_ptrs(old_idx, new_idx);
+            switch_context(old_ptr, new_ptr);
+        }
+    }
+}
+
+/// Yield to another thread
+pub fn yield_now() {
+    check_blocking_allowed("yield_now");
+    VOLUNTARY_SCHEDULE.store(true, Ordering::Release);
+    crate::gic::trigger_sgi(crate::gic::SGI_SCHEDULER);
+}
+
+/// Block the current thread for at least `us` microseconds
+///
+/// The thread is Sleeping meanwhile and never picked to run; the scheduler
+/// makes it Ready again on the first pass after its wake time, so it can
+/// wake up to one timer tick (10 ms) late. If no other thread can run,
+/// the CPU waits for interrupts instead of spinning.
+pub fn sleep_us(us: u64) {
+    let wake_at = crate::timer::uptime_us().saturating_add(us);
+    loop {
+        let due = with_irqs_disabled(|| {
+            let mut pool = POOL.lock();
+            if crate::timer::uptime_us() >= wake_at {
+                let idx = pool.current_idx;
+                pool.slots[idx].state = ThreadState::Running;
+                true
+            } else {
+                pool.sleep_current(wake_at);
+                false
+            }
+        });
+        if due {
+            return;
+        }
+        yield_now();
+        // Back before the wake time: nothing else was runnable, so nothing
+        // was switched to; wait for the tick that wakes us
+        if crate::timer::uptime_us() < wake_at {
+            unsafe { core::arch::asm!("wfi") };
+        }
+    }
+}
+
+/// Block the current thread for at least `ms` milliseconds
+pub fn sleep_ms(ms: u64) {
+    sleep_us(ms.saturating_mul(1000));
+}
+
+/// Times `idle_unless` waited for an interrupt
+static IDLE_WAITS: AtomicU64 = AtomicU64::new(0);
+
+/// Wait for an interrupt unless another thread is ready or `busy` says
+/// the caller has work; returns whether it waited
+///
+/// For threads that poll rather than block (the network loop): call it
+/// after a pass that found nothing to do. IRQs stay masked from the checks
+/// to the `wfi`, so an interrupt that makes work or a thread ready can't
+/// slip in between - it ends the `wfi` and is taken right after. The
+/// timer tick bounds the wait.
+pub fn idle_unless(busy: impl FnOnce() -> bool) -> bool {
+    with_irqs_disabled(|| {
+        let others_ready = {
+            let pool = POOL.lock();
+            let current = pool.current_idx;
+            pool.slots
+                .iter()
+                .enumerate()
+                .any(|(i, slot)| i != current && slot.state == ThreadState::Ready)
+        };
+        if others_ready || busy() {
+            return false;
+        }
+        IDLE_WAITS.fetch_add(1, Ordering::Relaxed);
+        // SAFETY: wfi only waits; a pending IRQ ends it even while masked
+        unsafe { core::arch::asm!("wfi") };
+        true
+    })
+}
+
+/// Times `idle_unless` waited since boot
+pub fn idle_waits() -> u64 {
+    IDLE_WAITS.load(Ordering::Relaxed)
+}
+
+/// Threads currently in `sleep_us`
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn sleeping_count() -> usize {
+    with_irqs_disabled(|| {
+        let pool = POOL.lock();
+        pool.slots
+            .iter()
+            .filter(|slot| slot.state == ThreadState::Sleeping)
+            .count()
+    })
+}
+
+/// Threads currently waiting on a `Mutex` or `Condvar` or parked
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn blocked_count() -> usize {
+    with_irqs_disabled(|| {
+        let pool = POOL.lock();
+        pool.slots
+            .iter()
+            .filter(|slot| slot.state == ThreadState::Blocked)
+            .count()
+    })
+}
+
+/// Get thread stats (ready, running, terminated)
+#[cfg(feature = "shell")]
+pub fn thread_stats() -> (usize, usize, usize) {
+    with_irqs_disabled(|| {
+        let pool = POOL.lock();
+        pool.thread_stats()
+    })
+}
+
+/// CPU time thread `tid` has used since it was spawned (None if the slot
+/// is free)
+///
+/// Charged at every context switch; time spent in IRQ handlers goes to
+/// whichever thread they interrupted.
+pub fn cpu_time_us(tid: usize) -> Option<u64> {
+    if tid >= MAX_THREADS {
+        return None;
     }
-
-    result
+    with_irqs_disabled(|| {
+        let pool = POOL.lock();
+        (pool.slots[tid].state != ThreadState::Free)
+            .then(|| pool.cpu_time_us(tid, crate::timer::uptime_us()))
+    })
 }
 
-/// SGI handler for scheduling
-pub fn sgi_scheduler_handler(irq: u32) {
-    crate::gic::end_of_interrupt(irq);
+/// Clean up terminated threads (mark slots as free)
+///
+/// Handles still owned by a terminated thread are released and the exit
+/// hooks run first, while its slot can't be reused, so nothing it held
+/// outlives it.
+pub fn cleanup_terminated() -> usize {
+    let mask = with_irqs_disabled(|| {
+        let pool = POOL.lock();
+        pool.terminated_mask()
+    });
+    let hooks = with_irqs_disabled(|| *EXIT_HOOKS.lock());
 
-    let voluntary = VOLUNTARY_SCHEDULE.swap(false, Ordering::Acquire);
+    for tid in 1..MAX_THREADS {
+        if mask & (1 << tid) != 0 {
+            for (_, hook) in hooks.iter().flatten() {
+                hook(tid);
+            }
+            let released = crate::handles::release_owner(crate::handles::Owner::Thread(tid));
+            if released > 0 {
+                crate::kinfo!(
+                    "[Thread] Released {} handle(s) left by thread {}",
+                    released, tid
+                );
+            }
+        }
+    }
 
-    let (switch_info, pool_ptr) = {
+    let mut closures = [None; MAX_THREADS];
+    let reclaimed = with_irqs_disabled(|| {
         let mut pool = POOL.lock();
-        let ptr = &mut *pool as *mut ThreadPool;
-        (pool.schedule_indices(voluntary), ptr)
-    };
-
-    if let Some((old_idx, new_idx)) = switch_info {
-        unsafe {
-            let pool = &mut *pool_ptr;
-            let (old_ptr, new_ptr) = pool.get_context_ptrs(old_idx, new_idx);
-            switch_context(old_ptr, new_ptr);
+        for (tid, closure) in closures.iter_mut().enumerate() {
+            if mask & (1 << tid) != 0 && pool.slots[tid].state == ThreadState::Terminated {
+                *closure = pool.slots[tid].closure.take();
+            }
         }
+        pool.reclaim_mask(mask)
+    });
+    // Closures of threads killed before they started; dropped outside the
+    // pool lock, since their captures may take locks of their own
+    for closure in closures.into_iter().flatten() {
+        // SAFETY: the thread never took it and its slot is gone
+        unsafe { (closure.drop)(closure.ptr as *mut ()) };
     }
+    restore_default_stacks();
+    reclaimed
 }
 
-/// Yield to another thread
-pub fn yield_now() {
-    VOLUNTARY_SCHEDULE.store(true, Ordering::Release);
-    crate::gic::trigger_sgi(crate::gic::SGI_SCHEDULER);
+/// Give free slots left with a `spawn_with_stack` stack a default one
+/// again, so plain spawns can use them
+///
+/// Allocates, so it runs here in thread context rather than under the pool
+/// lock; on OOM the slots wait for the next pass.
+fn restore_default_stacks() {
+    for _ in 1..MAX_THREADS {
+        let custom = with_irqs_disabled(|| {
+            let pool = POOL.lock();
+            (1..MAX_THREADS).find(|&i| {
+                pool.slots[i].state == ThreadState::Free && pool.stack_sizes[i] != STACK_SIZE
+            })
+        });
+        let Some(tid) = custom else {
+            return;
+        };
+        let Ok(base) = alloc_stack(STACK_SIZE) else {
+            return;
+        };
+        let mut unused = (base, STACK_SIZE);
+        with_irqs_disabled(|| {
+            let mut pool = POOL.lock();
+            if pool.slots[tid].state == ThreadState::Free && pool.stack_sizes[tid] != STACK_SIZE {
+                unused = (pool.stacks[tid], pool.stack_sizes[tid]);
+                pool.stacks[tid] = base;
+                pool.stack_sizes[tid] = STACK_SIZE;
+            }
+        });
+        // SAFETY: either the slot's old stack, which is free, or ours
+        unsafe { free_stack(unused.0, unused.1) };
+    }
 }
 
-/// Get thread stats (ready, running, terminated)
-pub fn thread_stats() -> (usize, usize, usize) {
-    with_irqs_disabled(|| {
-        let pool = POOL.lock();
-        pool.thread_stats()
+/// Pause between reaper passes
+const REAP_INTERVAL_MS: u64 = 10;
+
+/// Start the background thread that reclaims terminated threads
+///
+/// It sleeps between passes, so the CPU can idle.
+pub fn start_reaper() -> KResult<usize> {
+    spawn_fn_background("reaper", || {
+        loop {
+            cleanup_terminated();
+            sleep_ms(REAP_INTERVAL_MS);
+        }
     })
 }
 
-/// Clean up terminated threads (mark slots as free)
-pub fn cleanup_terminated() -> usize {
+/// Scheduling class of thread `tid`, if it exists
+pub fn thread_class(tid: usize) -> Option<SchedClass> {
     with_irqs_disabled(|| {
-        let mut pool = POOL.lock();
-        pool.cleanup_terminated()
+        let pool = POOL.lock();
+        pool.slots
+            .get(tid)
+            .filter(|slot| slot.state != ThreadState::Free)
+            .map(|slot| slot.class)
     })
 }
 
 /// Get active thread count
+#[cfg(feature = "shell")]
 pub fn thread_count() -> usize {
     with_irqs_disabled(|| {
         let pool = POOL.lock();
@@ -615,15 +2779,121 @@ pub fn thread_count() -> usize {
     })
 }
 
-/// Mark current thread as terminated (thread 0 cannot be terminated)
-pub fn mark_current_terminated() {
-    with_irqs_disabled(|| {
+/// Terminate the calling thread
+///
+/// It is never scheduled again; the reaper reclaims its slot and releases
+/// the handles it still owns. Returning from a closure passed to `spawn_fn`
+/// and friends does the same. Panics on thread 0 (boot/idle thread), which
+/// must never terminate.
+pub fn exit() -> ! {
+    let terminated = with_irqs_disabled(|| {
         let mut pool = POOL.lock();
         let idx = pool.current_idx;
-        // Never allow terminating thread 0 (boot/idle thread)
-        if idx != IDLE_THREAD_IDX {
-            pool.slots[idx].state = ThreadState::Terminated;
+        if idx == IDLE_THREAD_IDX {
+            return false;
+        }
+        pool.slots[idx].state = ThreadState::Terminated;
+        true
+    });
+    assert!(terminated, "thread 0 cannot exit");
+    // The scheduler skips terminated threads, so this doesn't come back
+    loop {
+        yield_now();
+    }
+}
+
+/// Where `thread_start` lands if an entry function returns
+#[unsafe(no_mangle)]
+extern "C" fn thread_exit_from_asm() -> ! {
+    exit()
+}
+
+#[cfg(feature = "shell")]
+static KILLS: AtomicU64 = AtomicU64::new(0);
+
+/// Terminate thread `tid` without its cooperation
+///
+/// It is never scheduled again, even if it never yields: a thread that is
+/// not on the CPU when this is called is already off it for good, and
+/// killing the calling thread is `exit`. Its stack is not unwound, so what
+/// it owns leaks unless something tracks it per thread - handles and the
+/// exit hooks run when the reaper reclaims the slot, as for any exit, and
+/// the closure of a thread that never started is freed then too.
+/// Mutexes it holds stay locked (their waiters block for good) and a
+/// `JoinHandle::join` on it never returns, so this is for hung or runaway
+/// threads, not a way to stop a healthy one.
+#[cfg(feature = "shell")]
+pub fn kill(tid: usize) -> KResult<()> {
+    if tid == IDLE_THREAD_IDX {
+        return Err(KError::with_context(ErrorKind::InvalidArgument, "thread 0"));
+    }
+    if tid == current_thread_id() {
+        exit();
+    }
+    let (name, mutexes) = with_irqs_disabled(|| {
+        let mut pool = POOL.lock();
+        let mutexes = pool.kill(tid)?;
+        Ok::<_, KError>((pool.slots[tid].name, mutexes))
+    })?;
+    KILLS.fetch_add(1, Ordering::Relaxed);
+    let name = if name.is_empty() { "-" } else { name };
+    if mutexes > 0 {
+        crate::kevent!(
+            Event::ThreadKilled,
+            "[Thread] Killed thread {} ({}) holding {} mutex(es)",
+            tid,
+            name,
+            mutexes
+        );
+    } else {
+        crate::kevent!(Event::ThreadKilled, "[Thread] Killed thread {} ({})", tid, name);
+    }
+    Ok(())
+}
+
+/// Threads terminated with `kill` since boot
+#[cfg(feature = "shell")]
+pub fn kills() -> u64 {
+    KILLS.load(Ordering::Relaxed)
+}
+
+/// Releases what a terminated thread left behind; runs in the reaper
+/// with the tid, before the slot is reused
+pub type ExitHook = fn(usize);
+
+/// Registered exit hooks at most
+const MAX_EXIT_HOOKS: usize = 8;
+
+static EXIT_HOOKS: Spinlock<[Option<(&'static str, ExitHook)>; MAX_EXIT_HOOKS]> =
+    Spinlock::new([None; MAX_EXIT_HOOKS]);
+
+/// Run `hook` for every thread that terminates, by `exit`, returning or
+/// `kill`
+pub fn register_exit_hook(name: &'static str, hook: ExitHook) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut hooks = EXIT_HOOKS.lock();
+        if hooks.iter().flatten().any(|(n, _)| *n == name) {
+            return Err(KError::with_context(ErrorKind::AlreadyExists, name));
         }
+        let slot = hooks
+            .iter_mut()
+            .find(|h| h.is_none())
+            .ok_or(KError::with_context(ErrorKind::NoFreeSlots, "exit hooks"))?;
+        *slot = Some((name, hook));
+        Ok(())
+    })
+}
+
+/// Remove the exit hook registered under `name`
+pub fn unregister_exit_hook(name: &str) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut hooks = EXIT_HOOKS.lock();
+        let slot = hooks
+            .iter_mut()
+            .find(|h| h.is_some_and(|(n, _)| n == name))
+            .ok_or(KError::with_context(ErrorKind::NotFound, "exit hook"))?;
+        *slot = None;
+        Ok(())
     })
 }
 
@@ -635,7 +2905,608 @@ pub fn current_thread_id() -> usize {
     })
 }
 
+/// Current thread ID, or None if the scheduler lock is held (for fault
+/// handlers, which may have interrupted its holder)
+pub fn try_current_thread_id() -> Option<usize> {
+    POOL.try_lock().map(|pool| pool.current_idx)
+}
+
 /// Get max thread count
+#[cfg(feature = "shell")]
 pub fn max_threads() -> usize {
     MAX_THREADS
 }
+
+/// One thread as seen by `for_each_thread`
+#[cfg(feature = "shell")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadInfo {
+    pub tid: usize,
+    /// Empty unless spawned with a name
+    pub name: &'static str,
+    pub state: ThreadState,
+    pub class: SchedClass,
+    pub priority: u8,
+    /// Stack range (None for the boot stack)
+    pub stack: Option<(usize, usize)>,
+    /// Time spent on the CPU since spawn (since boot for thread 0)
+    pub cpu_time_us: u64,
+}
+
+/// Call `f` for every live thread, in tid order
+///
+/// The pool is copied under its lock first, so `f` may allocate, print or
+/// spawn; threads that start or exit meanwhile may be missed or shown.
+#[cfg(feature = "shell")]
+pub fn for_each_thread(mut f: impl FnMut(&ThreadInfo)) {
+    let infos: [Option<ThreadInfo>; MAX_THREADS] = with_irqs_disabled(|| {
+        let pool = POOL.lock();
+        let now = crate::timer::uptime_us();
+        core::array::from_fn(|tid| {
+            let slot = &pool.slots[tid];
+            let base = pool.stacks[tid];
+            (slot.state != ThreadState::Free).then_some(ThreadInfo {
+                tid,
+                name: slot.name,
+                state: slot.state,
+                class: slot.class,
+                priority: slot.params.priority,
+                stack: (base != 0).then_some((base, base + pool.stack_sizes[tid])),
+                cpu_time_us: pool.cpu_time_us(tid, now),
+            })
+        })
+    });
+    for info in infos.iter().flatten() {
+        f(info);
+    }
+}
+
+// ============================================================================
+// Blocking Mutex
+// ============================================================================
+
+/// Mutex lockers that found it held and had to block
+static MUTEX_CONTENTIONS: AtomicU64 = AtomicU64::new(0);
+/// Holders raised to a waiter's priority
+static PRIORITY_BOOSTS: AtomicU64 = AtomicU64::new(0);
+
+/// A thread in a wait queue, tagged with its slot's generation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Waiter {
+    tid: usize,
+    generation: u32,
+}
+
+/// Threads blocked on one object, in arrival order
+///
+/// A thread waits on at most one object at a time, and a killed waiter's
+/// entry is dropped when its slot is queued again, so `MAX_THREADS`
+/// entries always suffice and pushing never allocates.
+struct WaitQueue {
+    waiters: [Waiter; MAX_THREADS],
+    head: usize,
+    len: usize,
+}
+
+impl WaitQueue {
+    const fn new() -> Self {
+        Self {
+            waiters: [Waiter { tid: 0, generation: 0 }; MAX_THREADS],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, waiter: Waiter) {
+        if self.iter().any(|w| w == waiter) {
+            return;
+        }
+        // An entry a killed thread left for the same slot
+        let stale = self.iter().find(|w| w.tid == waiter.tid);
+        if let Some(stale) = stale {
+            self.remove(stale);
+        }
+        self.waiters[(self.head + self.len) % MAX_THREADS] = waiter;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Waiter> {
+        if self.len == 0 {
+            return None;
+        }
+        let waiter = self.waiters[self.head];
+        self.head = (self.head + 1) % MAX_THREADS;
+        self.len -= 1;
+        Some(waiter)
+    }
+
+    /// Take `waiter` out of the queue, keeping the others in order
+    fn remove(&mut self, waiter: Waiter) -> bool {
+        let Some(pos) = self.iter().position(|w| w == waiter) else {
+            return false;
+        };
+        for i in pos..self.len - 1 {
+            self.waiters[(self.head + i) % MAX_THREADS] =
+                self.waiters[(self.head + i + 1) % MAX_THREADS];
+        }
+        self.len -= 1;
+        true
+    }
+
+    /// Entries whose thread is still blocked waiting
+    fn live(&self, pool: &ThreadPool) -> usize {
+        self.iter().filter(|&w| pool.is_waiting(w)).count()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = Waiter> + '_ {
+        (0..self.len).map(|i| self.waiters[(self.head + i) % MAX_THREADS])
+    }
+}
+
+/// The `TimedOut` error of a blocking wait whose deadline passed
+fn deadline_error() -> KError {
+    KError::with_context(ErrorKind::TimedOut, "deadline")
+}
+
+/// Give up the CPU until another thread calls `unblock` on this one
+///
+/// The caller must have marked itself Blocked (under the lock its waker
+/// takes), so a wakeup that lands before the switch isn't lost: it just
+/// sets the thread Running again and the yield returns at once.
+fn wait_unblocked() {
+    loop {
+        yield_now();
+        let blocked = with_irqs_disabled(|| {
+            let pool = POOL.lock();
+            pool.slots[pool.current_idx].state == ThreadState::Blocked
+        });
+        if !blocked {
+            return;
+        }
+        // Still blocked after the yield: nothing else was runnable, so
+        // nothing was switched to; wait for an interrupt to change that
+        unsafe { core::arch::asm!("wfi") };
+    }
+}
+
+struct MutexState {
+    locked: bool,
+    /// Holder's tid, to catch a thread locking twice
+    owner: usize,
+    waiters: WaitQueue,
+}
+
+/// Mutual exclusion that puts contended lockers to sleep
+///
+/// Unlike `spinning_top::Spinlock`, a thread that finds the mutex held is
+/// Blocked - taken off the CPU until the holder unlocks - instead of
+/// spinning away its time slice. Unlock hands the wakeup to the longest
+/// waiter, which then competes for the lock again.
+///
+/// A waiter more urgent than the holder lends it its priority (priority
+/// inheritance), so bulk work holding a lock the network thread needs
+/// can't be held off by everything in between. The holder keeps the
+/// boost until it releases the last mutex it holds; the boost isn't
+/// passed on if the holder is itself waiting for another mutex.
+///
+/// Thread context only: never lock one from an interrupt handler, and
+/// keep `Spinlock` for data shared with one. Locking a mutex the current
+/// thread already holds panics rather than deadlocking.
+pub struct Mutex<T> {
+    state: Spinlock<MutexState>,
+    data: UnsafeCell<T>,
+}
+
+// Access to `data` is serialized by `state.locked`
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: Spinlock::new(MutexState {
+                locked: false,
+                owner: 0,
+                waiters: WaitQueue::new(),
+            }),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Lock, blocking the current thread while another one holds it
+    ///
+    /// Inside `sched::with_deadline` it gives up with `TimedOut` once the
+    /// deadline passes; outside one it can't fail.
+    pub fn lock(&self) -> KResult<MutexGuard<'_, T>> {
+        crate::lockdep::acquire(core::any::type_name::<Self>(), self.addr(), false);
+        loop {
+            let acquired = with_irqs_disabled(|| {
+                let mut state = self.state.lock();
+                let mut pool = POOL.lock();
+                let tid = pool.current_idx;
+                if !state.locked {
+                    state.locked = true;
+                    state.owner = tid;
+                    pool.slots[tid].mutexes_held += 1;
+                    return Some(true);
+                }
+                if state.owner == tid {
+                    drop(pool);
+                    drop(state);
+                    panic!("threading::Mutex locked twice by thread {}", tid);
+                }
+                let deadline = pool.slots[tid].deadline_us;
+                if deadline > 0 && crate::timer::uptime_us() >= deadline {
+                    // Out of the queue, so a later unlock can't wake us
+                    // out of some unrelated wait
+                    state.waiters.remove(pool.current_waiter());
+                    return None;
+                }
+                // Blocked and queued under the same lock the unlocker takes
+                let waiter = pool.block_current();
+                state.waiters.push(waiter);
+                let priority = pool.slots[tid].params.priority;
+                if pool.boost_priority(state.owner, priority) {
+                    PRIORITY_BOOSTS.fetch_add(1, Ordering::Relaxed);
+                }
+                Some(false)
+            });
+            match acquired {
+                Some(true) => return Ok(MutexGuard { mutex: self }),
+                Some(false) => {}
+                None => {
+                    crate::lockdep::release(self.addr());
+                    return Err(deadline_error());
+                }
+            }
+            MUTEX_CONTENTIONS.fetch_add(1, Ordering::Relaxed);
+            wait_unblocked();
+        }
+    }
+
+    /// Lock if nobody holds it, without blocking
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let guard = with_irqs_disabled(|| {
+            let mut state = self.state.lock();
+            if state.locked {
+                return None;
+            }
+            let mut pool = POOL.lock();
+            let tid = pool.current_idx;
+            state.locked = true;
+            state.owner = tid;
+            pool.slots[tid].mutexes_held += 1;
+            Some(MutexGuard { mutex: self })
+        })?;
+        // Outside the state and pool locks, which aren't held with it
+        crate::lockdep::acquire(core::any::type_name::<Self>(), self.addr(), true);
+        Some(guard)
+    }
+
+    /// Release the lock and wake the longest waiter; the holder drops any
+    /// lent priority once this was the last mutex it held
+    fn unlock(&self) {
+        crate::lockdep::release(self.addr());
+        with_irqs_disabled(|| {
+            let mut state = self.state.lock();
+            let mut pool = POOL.lock();
+            state.locked = false;
+            let owner = state.owner;
+            let held = &mut pool.slots[owner].mutexes_held;
+            *held = held.saturating_sub(1);
+            if *held == 0 {
+                pool.restore_priority(owner);
+            }
+            // Killed waiters are left in the queue; skip past them
+            while let Some(waiter) = state.waiters.pop() {
+                if pool.wake(waiter) {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Whether some thread holds the lock
+    pub fn is_locked(&self) -> bool {
+        with_irqs_disabled(|| self.state.lock().locked)
+    }
+
+    /// Threads blocked waiting for the lock
+    pub fn waiters(&self) -> usize {
+        with_irqs_disabled(|| {
+            let state = self.state.lock();
+            state.waiters.live(&POOL.lock())
+        })
+    }
+}
+
+/// Held lock on a `Mutex`; unlocks on drop
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// Times a `Mutex::lock` found the mutex held and blocked
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn mutex_contentions() -> u64 {
+    MUTEX_CONTENTIONS.load(Ordering::Relaxed)
+}
+
+/// Times a `Mutex` holder was raised to a waiter's priority
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn priority_boosts() -> u64 {
+    PRIORITY_BOOSTS.load(Ordering::Relaxed)
+}
+
+// ============================================================================
+// Condition Variables
+// ============================================================================
+
+/// Lets threads holding a `Mutex` sleep until another thread changes the
+/// data it guards
+///
+/// A waiter is Blocked, like a contended locker, until `notify_one` or
+/// `notify_all`. Wakeups can be spurious (and another thread may run in
+/// between), so wait in a loop on the actual condition - `wait_while`
+/// does that.
+pub struct Condvar {
+    waiters: Spinlock<WaitQueue>,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Spinlock::new(WaitQueue::new()),
+        }
+    }
+
+    /// Unlock `guard`'s mutex, block until notified, then lock it again
+    ///
+    /// Inside `sched::with_deadline` it fails with `TimedOut` once the
+    /// deadline passes, leaving the mutex unlocked.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> KResult<MutexGuard<'a, T>> {
+        let mutex = guard.mutex;
+        if deadline_expired() {
+            return Err(deadline_error());
+        }
+        // Queue, block and unlock in one step: a notify can't slip in
+        // before we are queued, and a tick can't switch us out while
+        // we still hold the mutex
+        with_irqs_disabled(|| {
+            let mut waiters = self.waiters.lock();
+            waiters.push(POOL.lock().block_current());
+            drop(waiters);
+            mutex.unlock();
+        });
+        core::mem::forget(guard);
+        wait_unblocked();
+        if deadline_expired() {
+            // Woken by the deadline rather than a notify: leave the queue
+            with_irqs_disabled(|| {
+                let waiter = POOL.lock().current_waiter();
+                self.waiters.lock().remove(waiter);
+            });
+            return Err(deadline_error());
+        }
+        mutex.lock()
+    }
+
+    /// Wait for as long as `condition` holds for the guarded data
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> KResult<MutexGuard<'a, T>> {
+        while condition(&mut guard) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    /// Threads blocked in `wait`
+    pub fn waiters(&self) -> usize {
+        with_irqs_disabled(|| {
+            let waiters = self.waiters.lock();
+            waiters.live(&POOL.lock())
+        })
+    }
+
+    /// Wake the longest waiter; false if nobody was waiting
+    #[cfg(feature = "ssh")]
+    pub fn notify_one(&self) -> bool {
+        with_irqs_disabled(|| {
+            let mut waiters = self.waiters.lock();
+            let mut pool = POOL.lock();
+            // Killed waiters are left in the queue; skip past them
+            while let Some(waiter) = waiters.pop() {
+                if pool.wake(waiter) {
+                    return true;
+                }
+            }
+            false
+        })
+    }
+
+    /// Wake every waiter; returns how many
+    pub fn notify_all(&self) -> usize {
+        with_irqs_disabled(|| {
+            let mut waiters = self.waiters.lock();
+            let mut pool = POOL.lock();
+            let mut woken = 0;
+            while let Some(waiter) = waiters.pop() {
+                if pool.wake(waiter) {
+                    woken += 1;
+                }
+            }
+            woken
+        })
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ============================================================================
+// Barriers
+// ============================================================================
+
+/// Holds `n` threads in `wait` until all `n` have arrived
+///
+/// Waiters are Blocked on a `Condvar` meanwhile. The barrier can be used
+/// again as soon as it releases: the next `n` arrivals form a new round.
+/// Thread context only, like `Mutex`.
+pub struct Barrier {
+    state: Mutex<BarrierState>,
+    released: Condvar,
+    parties: usize,
+}
+
+struct BarrierState {
+    arrived: usize,
+    /// Bumped every time the barrier releases
+    round: u64,
+}
+
+/// What `Barrier::wait` returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult {
+    leader: bool,
+}
+
+impl BarrierWaitResult {
+    /// Whether this thread was the one that released the round (exactly
+    /// one per round)
+    pub fn is_leader(&self) -> bool {
+        self.leader
+    }
+}
+
+impl Barrier {
+    /// A barrier for `n` threads; with 0 or 1 `wait` never blocks
+    pub const fn new(n: usize) -> Self {
+        Self {
+            state: Mutex::new(BarrierState { arrived: 0, round: 0 }),
+            released: Condvar::new(),
+            parties: n,
+        }
+    }
+
+    /// Block until `n` threads, this one included, have called `wait`
+    ///
+    /// Inside `sched::with_deadline` it fails with `TimedOut` once the
+    /// deadline passes, and this thread no longer counts as arrived.
+    pub fn wait(&self) -> KResult<BarrierWaitResult> {
+        let mut state = self.state.lock()?;
+        let round = state.round;
+        state.arrived += 1;
+        if state.arrived < self.parties {
+            // Wait on the round, not the count: it is reset for the next
+            // round before the waiters get to look
+            if let Err(e) = self.released.wait_while(state, |s| s.round == round) {
+                // Take the arrival back (the deadline is lifted for that,
+                // or the lock would time out too) unless the round released
+                // in the meantime
+                let deadline = deadline_us();
+                set_deadline_us(0);
+                let state = self.state.lock();
+                set_deadline_us(deadline);
+                let mut state = state?;
+                if state.round == round {
+                    state.arrived -= 1;
+                    return Err(e);
+                }
+            }
+            return Ok(BarrierWaitResult { leader: false });
+        }
+        state.arrived = 0;
+        state.round = state.round.wrapping_add(1);
+        drop(state);
+        self.released.notify_all();
+        Ok(BarrierWaitResult { leader: true })
+    }
+}
+
+// ============================================================================
+// Parking
+// ============================================================================
+
+/// Times `park` actually blocked (rather than consuming a token)
+static PARKS: AtomicU64 = AtomicU64::new(0);
+
+/// Block the current thread until `unpark` is called for it
+///
+/// Each thread has one wakeup token, as with `std::thread::park`: an
+/// `unpark` that arrives before the `park` is remembered and makes it
+/// return at once, so "check the condition, then park" can't miss a
+/// wakeup. Re-check the condition after waking all the same.
+pub fn park() {
+    check_blocking_allowed("park");
+    let blocked = with_irqs_disabled(|| {
+        let mut pool = POOL.lock();
+        let idx = pool.current_idx;
+        if core::mem::take(&mut pool.slots[idx].unpark_token) {
+            return false;
+        }
+        pool.slots[idx].parked = true;
+        pool.block_current();
+        true
+    });
+    if blocked {
+        PARKS.fetch_add(1, Ordering::Relaxed);
+        wait_unblocked();
+    }
+}
+
+/// Wake thread `tid` from `park`, or make its next `park` return at once
+///
+/// Safe to call from interrupt handlers, so a driver's IRQ can wake just
+/// the thread waiting for its device instead of everyone polling.
+pub fn unpark(tid: usize) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut pool = POOL.lock();
+        match pool.slots.get(tid).map(|slot| slot.state) {
+            None | Some(ThreadState::Free) | Some(ThreadState::Terminated) => {
+                return Err(KError::with_context(ErrorKind::NotFound, "thread"));
+            }
+            Some(_) => {}
+        }
+        if pool.slots[tid].parked {
+            pool.slots[tid].parked = false;
+            pool.unblock(tid);
+        } else {
+            pool.slots[tid].unpark_token = true;
+        }
+        Ok(())
+    })
+}
+
+/// Times a `park` blocked since boot
+pub fn parks() -> u64 {
+    PARKS.load(Ordering::Relaxed)
+}
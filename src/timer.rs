@@ -1,8 +1,10 @@
 use alloc::string::String;
 use arm_pl031::Rtc;
 use core::arch::asm;
-use core::sync::atomic::{AtomicU64, Ordering};
-use spinning_top::Spinlock;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
 
 // Manual tick counter (u64)
 // Overflow times at different frequencies:
@@ -19,18 +21,50 @@ static UTC_OFFSET_US: Spinlock<Option<u64>> = Spinlock::new(None);
 // The standard PL031 address for QEMU virt machine is 0x9010000
 static RTC: Spinlock<Option<Rtc>> = Spinlock::new(None);
 
-pub fn init() {
-    // Initialize the PL031 RTC
+// PL031 RTC and its PrimeCell ID register 0 (low byte 0x0D on every PrimeCell)
+const RTC_BASE: usize = 0x9010000;
+const RTC_PCELL_ID0: usize = 0xFF0;
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+pub fn init() -> KResult<()> {
+    if INITIALIZED.swap(true, Ordering::AcqRel) {
+        return Err(KError::with_context(ErrorKind::AlreadyInitialized, "timer"));
+    }
+    // Initialize the PL031 RTC, if the machine has one: the read is probed,
+    // so a missing RTC leaves UTC unset instead of aborting
+    if crate::probe::read32(RTC_BASE + RTC_PCELL_ID0).map(|v| v & 0xff) != Ok(0x0D) {
+        return Ok(());
+    }
     // SAFETY: 0x9010000 is the standard PL031 RTC address on QEMU virt machine
     unsafe {
-        let rtc = Rtc::new(0x9010000 as *mut _);
+        let rtc = Rtc::new(RTC_BASE as *mut _);
         *RTC.lock() = Some(rtc);
     }
+    Ok(())
 }
 
 // Enable timer interrupts for preemptive scheduling
 // Store configured interval for use in handler
-static TIMER_INTERVAL_US: AtomicU64 = AtomicU64::new(10_000); // Default 10ms
+pub const DEFAULT_TICK_US: u64 = 10_000; // 10ms
+pub const MIN_TICK_US: u64 = 1_000;
+pub const MAX_TICK_US: u64 = 100_000;
+static TIMER_INTERVAL_US: AtomicU64 = AtomicU64::new(DEFAULT_TICK_US);
+
+// Interval between timer interrupts, in microseconds
+pub fn tick_interval_us() -> u64 {
+    TIMER_INTERVAL_US.load(Ordering::Relaxed)
+}
+
+// Change the interval between timer interrupts at runtime; the handler
+// re-arms with it, so it takes effect from the next tick on
+pub fn set_tick_interval_us(interval_us: u64) -> KResult<()> {
+    if !(MIN_TICK_US..=MAX_TICK_US).contains(&interval_us) {
+        return Err(KError::with_context(ErrorKind::InvalidArgument, "tick interval"));
+    }
+    TIMER_INTERVAL_US.store(interval_us, Ordering::Relaxed);
+    Ok(())
+}
 
 // interval_us: interval in microseconds between interrupts
 pub fn enable_timer_interrupts(interval_us: u64) {
@@ -63,8 +97,11 @@ pub fn timer_irq_handler(_irq: u32) {
     // memory which could deadlock if main code is in the middle of an allocation.
     // Cleanup should be done from user code via threading::cleanup_terminated().
 
-    // Trigger SGI for scheduling - scheduler will decide if switch is needed
-    crate::gic::trigger_sgi(crate::gic::SGI_SCHEDULER);
+    // Trigger SGI for scheduling, unless the running thread is mid-slice
+    // and nothing more urgent is waiting
+    if crate::threading::tick_should_schedule() {
+        crate::gic::trigger_sgi(crate::gic::SGI_SCHEDULER);
+    }
 }
 
 pub fn tick() {
@@ -209,6 +246,99 @@ pub fn delay_ns(ns: u64) {
     }
 }
 
+// Cycle counter
+//
+// cycles() is the timing source for measurements (profilers, benchmarks,
+// tracing): the virtual counter (CNTVCT_EL0), clamped so it never goes
+// backwards even on cores with counter read errata or, later, across CPUs
+// whose counters aren't exactly in step. Subtract two readings and convert
+// with cycles_to_ns/cycles_to_us, or use a Stopwatch. uptime_us() stays
+// the clock for timestamps.
+
+// Highest value cycles() has returned
+static LAST_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+// Counter frequency used for conversions, read once
+static CYCLE_FREQ: AtomicU64 = AtomicU64::new(0);
+
+// Assumed if firmware never programmed CNTFRQ_EL0 (reads as 0): QEMU's rate
+const FALLBACK_CYCLE_FREQ: u64 = 62_500_000;
+
+// Read the counter; never less than any earlier reading
+#[inline]
+pub fn cycles() -> u64 {
+    let raw: u64;
+    unsafe {
+        // isb keeps the read from being done ahead of earlier instructions
+        asm!("isb", "mrs {}, cntvct_el0", out(reg) raw);
+    }
+    let last = LAST_CYCLES.fetch_max(raw, Ordering::Relaxed);
+    raw.max(last)
+}
+
+// Counter ticks per second
+pub fn cycle_frequency() -> u64 {
+    let freq = CYCLE_FREQ.load(Ordering::Relaxed);
+    if freq != 0 {
+        return freq;
+    }
+    let freq = match read_frequency() {
+        0 => FALLBACK_CYCLE_FREQ,
+        f => f,
+    };
+    CYCLE_FREQ.store(freq, Ordering::Relaxed);
+    freq
+}
+
+// Convert between cycles and time (u128 intermediates; saturates at u64::MAX)
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    scale(cycles, 1_000_000_000, cycle_frequency())
+}
+
+pub fn cycles_to_us(cycles: u64) -> u64 {
+    scale(cycles, 1_000_000, cycle_frequency())
+}
+
+pub fn ns_to_cycles(ns: u64) -> u64 {
+    scale(ns, cycle_frequency(), 1_000_000_000)
+}
+
+pub fn us_to_cycles(us: u64) -> u64 {
+    scale(us, cycle_frequency(), 1_000_000)
+}
+
+fn scale(value: u64, mul: u64, div: u64) -> u64 {
+    (value as u128 * mul as u128 / div as u128).min(u64::MAX as u128) as u64
+}
+
+// Measures elapsed time from when it was started
+#[derive(Debug, Clone, Copy)]
+pub struct Stopwatch {
+    start: u64,
+}
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        Self { start: cycles() }
+    }
+
+    pub fn elapsed_cycles(&self) -> u64 {
+        cycles().saturating_sub(self.start)
+    }
+
+    pub fn elapsed_ns(&self) -> u64 {
+        cycles_to_ns(self.elapsed_cycles())
+    }
+
+    pub fn elapsed_us(&self) -> u64 {
+        cycles_to_us(self.elapsed_cycles())
+    }
+
+    pub fn elapsed_ms(&self) -> u64 {
+        self.elapsed_us() / 1000
+    }
+}
+
 // Get current time as u64 microseconds since boot
 // Overflows after ~584 years
 pub fn uptime_us() -> u64 {
@@ -246,8 +376,14 @@ pub fn set_utc_time_us(unix_epoch_us: u64) {
 // Get current UTC time in microseconds since Unix epoch
 // Returns None if UTC time has not been set
 pub fn utc_time_us() -> Option<u64> {
+    utc_at_uptime_us(uptime_us())
+}
+
+// UTC time (microseconds since Unix epoch) at a given uptime
+// Returns None if UTC time has not been set
+pub fn utc_at_uptime_us(uptime_us: u64) -> Option<u64> {
     let offset = UTC_OFFSET_US.lock();
-    offset.map(|off| off.wrapping_add(uptime_us()))
+    offset.map(|off| off.wrapping_add(uptime_us))
 }
 
 // DateTime structure for ISO 8601 formatting
@@ -316,53 +452,140 @@ impl DateTime {
             microsecond: micros,
         }
     }
+}
+
+// Check if a year is a leap year
+fn is_leap_year(year: u32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0)
+}
+
+// ============================================================================
+// ISO 8601 formatting cache
+// ============================================================================
+
+// Log lines with UTC timestamps format the time constantly, and almost all
+// of them fall in the same hour. The "YYYY-MM-DDTHH:" prefix is cached by
+// absolute hour since the epoch, so only minutes and seconds are rendered
+// per call. Keying on the hour (not on when it was cached) keeps it right
+// across rollovers and clock adjustments: a different hour is a miss.
+
+// Longest prefix: 5-digit year, "-MM-DDTHH:"
+const PREFIX_MAX: usize = 15;
+
+struct PrefixCache {
+    hour: u64,
+    prefix: [u8; PREFIX_MAX],
+    len: usize,
+}
+
+static PREFIX_CACHE: Spinlock<PrefixCache> = Spinlock::new(PrefixCache {
+    hour: u64::MAX,
+    prefix: [0; PREFIX_MAX],
+    len: 0,
+});
+
+// Times a prefix had to be rendered (cache misses and contention)
+static PREFIX_RENDERS: AtomicU64 = AtomicU64::new(0);
+
+// Fixed buffer for rendering a prefix without allocating
+struct PrefixWriter<'a> {
+    buf: &'a mut [u8; PREFIX_MAX],
+    len: usize,
+}
+
+impl core::fmt::Write for PrefixWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > PREFIX_MAX {
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
 
-    // Format as ISO 8601: YYYY-MM-DDTHH:MM:SS.ssssssZ
-    pub fn to_iso8601(&self) -> String {
-        alloc::format!(
-            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
-            self.year,
-            self.month,
-            self.day,
-            self.hour,
-            self.minute,
-            self.second,
-            self.microsecond
-        )
+fn render_prefix(hour: u64, buf: &mut [u8; PREFIX_MAX]) -> usize {
+    PREFIX_RENDERS.fetch_add(1, Ordering::Relaxed);
+    let t = DateTime::from_unix_us(hour.saturating_mul(3_600_000_000));
+    let mut w = PrefixWriter { buf, len: 0 };
+    let _ = core::fmt::Write::write_fmt(
+        &mut w,
+        format_args!("{:04}-{:02}-{:02}T{:02}:", t.year, t.month, t.day, t.hour),
+    );
+    w.len
+}
+
+// Number of prefixes rendered so far
+pub fn utc_prefix_renders() -> u64 {
+    PREFIX_RENDERS.load(Ordering::Relaxed)
+}
+
+// A UTC time (microseconds since Unix epoch) displayed as ISO 8601,
+// e.g. 2026-01-02T03:04:05.678901Z; formats without allocating and is
+// safe to use from any context (a contended cache is bypassed, not waited on)
+#[derive(Debug, Clone, Copy)]
+pub struct Iso8601 {
+    utc_us: u64,
+    subsec: bool,
+}
+
+impl Iso8601 {
+    // With microseconds
+    pub fn new(utc_us: u64) -> Self {
+        Self { utc_us, subsec: true }
     }
 
-    // Format as ISO 8601 without microseconds: YYYY-MM-DDTHH:MM:SSZ
-    pub fn to_iso8601_simple(&self) -> String {
-        alloc::format!(
-            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
-            self.year,
-            self.month,
-            self.day,
-            self.hour,
-            self.minute,
-            self.second
-        )
+    // Whole seconds only: YYYY-MM-DDTHH:MM:SSZ
+    #[cfg(all(feature = "fs", feature = "shell"))]
+    pub fn seconds(utc_us: u64) -> Self {
+        Self { utc_us, subsec: false }
     }
 }
 
-// Check if a year is a leap year
-fn is_leap_year(year: u32) -> bool {
-    (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0)
+impl core::fmt::Display for Iso8601 {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let secs = self.utc_us / 1_000_000;
+        let hour = secs / 3600;
+
+        let mut prefix = [0u8; PREFIX_MAX];
+        let len = match PREFIX_CACHE.try_lock() {
+            Some(mut cache) => {
+                if cache.hour != hour {
+                    let mut fresh = [0u8; PREFIX_MAX];
+                    cache.len = render_prefix(hour, &mut fresh);
+                    cache.prefix = fresh;
+                    cache.hour = hour;
+                }
+                prefix = cache.prefix;
+                cache.len
+            }
+            None => render_prefix(hour, &mut prefix),
+        };
+        f.write_str(core::str::from_utf8(&prefix[..len]).unwrap_or(""))?;
+
+        write!(f, "{:02}:{:02}", (secs % 3600) / 60, secs % 60)?;
+        if self.subsec {
+            write!(f, ".{:06}", self.utc_us % 1_000_000)?;
+        }
+        f.write_str("Z")
+    }
 }
 
 // Get current UTC time as ISO 8601 string
 // Returns "NOT_SET" if UTC time hasn't been configured
 pub fn utc_iso8601() -> String {
     match utc_time_us() {
-        Some(us) => DateTime::from_unix_us(us).to_iso8601(),
+        Some(us) => alloc::format!("{}", Iso8601::new(us)),
         None => String::from("NOT_SET"),
     }
 }
 
 // Get current UTC time as simple ISO 8601 string (no microseconds)
+#[cfg(all(feature = "fs", feature = "shell"))]
 pub fn utc_iso8601_simple() -> String {
     match utc_time_us() {
-        Some(us) => DateTime::from_unix_us(us).to_iso8601_simple(),
+        Some(us) => alloc::format!("{}", Iso8601::seconds(us)),
         None => String::from("NOT_SET"),
     }
 }
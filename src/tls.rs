@@ -0,0 +1,951 @@
+//! TLS 1.3 Client
+//!
+//! Outbound connections that must not be read or altered on the way -
+//! uploading telemetry, fetching signed updates over HTTPS - run TLS 1.3
+//! (RFC 8446) on top of `TcpStream::connect`:
+//!
+//! ```text
+//! let trust = TrustStore::from_config()?;
+//! let name = ServerName::Ip([10, 0, 2, 2]);
+//! let mut tls = TlsStream::connect(stack, endpoint, name, &trust, timeout).await?;
+//! tls.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+//! ```
+//!
+//! Only the mandatory parameters are offered: x25519 key exchange and
+//! TLS_AES_128_GCM_SHA256. The server's certificate chain is checked by
+//! `x509` against the trust settings:
+//!
+//! - `tls.roots` - comma-separated root certificates, base64 DER (the body
+//!   of a PEM file joined onto one line). The chain must lead to one of
+//!   them and the leaf must name the server in its subjectAltName.
+//! - `tls.pins` - comma-separated SHA-256 hashes (hex) of public keys
+//!   (SubjectPublicKeyInfo). A chain reaching a pinned key is trusted
+//!   without a root or a name check, e.g. a self-signed server.
+//!
+//! Validity periods are checked once the wall clock is set. There is no
+//! session resumption, 0-RTT or client certificate; a server asking for
+//! one gets an empty Certificate message.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce, Tag};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_time::Duration;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey as X25519Public, StaticSecret};
+
+use crate::async_net::TcpStream;
+use crate::config;
+use crate::error::{ErrorKind, KError, KResult};
+use crate::secret::{self, SecretBytes};
+use crate::x509::{self, Anchor, Certificate, PublicKey, SignatureScheme};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+const CONTENT_CHANGE_CIPHER_SPEC: u8 = 20;
+const CONTENT_ALERT: u8 = 21;
+const CONTENT_HANDSHAKE: u8 = 22;
+const CONTENT_APPLICATION_DATA: u8 = 23;
+
+const HS_CLIENT_HELLO: u8 = 1;
+const HS_SERVER_HELLO: u8 = 2;
+const HS_NEW_SESSION_TICKET: u8 = 4;
+const HS_ENCRYPTED_EXTENSIONS: u8 = 8;
+const HS_CERTIFICATE: u8 = 11;
+const HS_CERTIFICATE_REQUEST: u8 = 13;
+const HS_CERTIFICATE_VERIFY: u8 = 15;
+const HS_FINISHED: u8 = 20;
+const HS_KEY_UPDATE: u8 = 24;
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_KEY_SHARE: u16 = 51;
+
+const TLS13: u16 = 0x0304;
+const TLS_AES_128_GCM_SHA256: u16 = 0x1301;
+const GROUP_X25519: u16 = 0x001d;
+
+const ALERT_CLOSE_NOTIFY: u8 = 0;
+const ALERT_UNEXPECTED_MESSAGE: u8 = 10;
+const ALERT_BAD_RECORD_MAC: u8 = 20;
+const ALERT_HANDSHAKE_FAILURE: u8 = 40;
+const ALERT_BAD_CERTIFICATE: u8 = 42;
+const ALERT_DECRYPT_ERROR: u8 = 51;
+
+/// Signature schemes offered, for certificates and CertificateVerify
+const SIGNATURE_ALGORITHMS: [u16; 9] = [
+    0x0807, // ed25519
+    0x0403, // ecdsa_secp256r1_sha256
+    0x0503, // ecdsa_secp384r1_sha384
+    0x0804, // rsa_pss_rsae_sha256
+    0x0805, // rsa_pss_rsae_sha384
+    0x0806, // rsa_pss_rsae_sha512
+    0x0401, // rsa_pkcs1_sha256 (certificates only)
+    0x0501, // rsa_pkcs1_sha384
+    0x0601, // rsa_pkcs1_sha512
+];
+
+/// ServerHello.random of a HelloRetryRequest (SHA-256 of "HelloRetryRequest")
+const HELLO_RETRY_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+/// Largest record plaintext, and ciphertext with its expansion
+const MAX_PLAINTEXT: usize = 16384;
+const MAX_CIPHERTEXT: usize = MAX_PLAINTEXT + 256;
+
+/// Largest handshake message accepted (a certificate chain)
+const MAX_HANDSHAKE: usize = 64 * 1024;
+
+/// Bytes `handshake_seeded` takes: client random, session ID, x25519 key
+pub const SEED_LEN: usize = 96;
+
+// ============================================================================
+// Trust
+// ============================================================================
+
+/// What server certificates are checked against
+#[derive(Debug, Clone, Default)]
+pub struct TrustStore {
+    /// DER root certificates
+    roots: Vec<Vec<u8>>,
+    /// SubjectPublicKeyInfo SHA-256 hashes
+    pins: Vec<[u8; 32]>,
+}
+
+impl TrustStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust chains leading to the root certificate `der`
+    pub fn add_root(&mut self, der: &[u8]) -> KResult<()> {
+        Certificate::parse(der)?;
+        self.roots.push(der.to_vec());
+        Ok(())
+    }
+
+    /// Trust chains reaching the public key whose SPKI hashes to `spki_sha256`
+    pub fn add_pin(&mut self, spki_sha256: [u8; 32]) {
+        self.pins.push(spki_sha256);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty() && self.pins.is_empty()
+    }
+
+    /// Roots and pins from `tls.roots` and `tls.pins`
+    pub fn from_config() -> KResult<Self> {
+        let bad = |key| KError::with_context(ErrorKind::InvalidArgument, key);
+        let mut trust = Self::new();
+        for root in config::get("tls.roots").unwrap_or_default().split(',') {
+            let root = root.trim();
+            if !root.is_empty() {
+                let der = crate::netboot::decode_base64(root).ok_or(bad("tls.roots"))?;
+                trust.add_root(&der).map_err(|_| bad("tls.roots"))?;
+            }
+        }
+        for pin in config::get("tls.pins").unwrap_or_default().split(',') {
+            let pin = pin.trim();
+            if !pin.is_empty() {
+                trust.add_pin(decode_hash(pin).ok_or(bad("tls.pins"))?);
+            }
+        }
+        Ok(trust)
+    }
+}
+
+/// 64 hex digits
+fn decode_hash(text: &str) -> Option<[u8; 32]> {
+    if text.len() != 64 {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+/// Who the server must prove to be, when trust comes from a root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerName {
+    /// Matched against iPAddress subjectAltNames; no SNI is sent
+    Ip([u8; 4]),
+    /// Sent as SNI and matched against dNSName subjectAltNames
+    Dns(String),
+}
+
+impl ServerName {
+    fn matches(&self, cert: &Certificate) -> bool {
+        match self {
+            ServerName::Ip(addr) => cert.has_ip(*addr),
+            ServerName::Dns(host) => cert.has_dns_name(host),
+        }
+    }
+}
+
+// ============================================================================
+// Key Schedule and Record Protection
+// ============================================================================
+
+static RANDOM_CALLS: AtomicU64 = AtomicU64::new(0);
+
+/// Fill `out` for client randoms and key shares
+///
+/// The virt machine has no RNG device. Like the SSH server this draws on
+/// the counter, but hashed together with a per-call sequence number so
+/// no two handshakes reuse a key share even within one counter tick.
+fn random_bytes(out: &mut [u8]) {
+    let call = RANDOM_CALLS.fetch_add(1, Ordering::Relaxed);
+    for (i, chunk) in out.chunks_mut(32).enumerate() {
+        let mut hasher = Sha256::new();
+        hasher.update(b"akuma tls");
+        hasher.update(call.to_le_bytes());
+        hasher.update((i as u64).to_le_bytes());
+        hasher.update(crate::timer::read_counter().to_le_bytes());
+        hasher.update(crate::timer::uptime_us().to_le_bytes());
+        chunk.copy_from_slice(&hasher.finalize()[..chunk.len()]);
+    }
+}
+
+/// HKDF-Expand-Label (RFC 8446 section 7.1)
+fn expand_label(secret: &[u8], label: &str, context: &[u8], out: &mut [u8]) {
+    let mut info = Vec::with_capacity(4 + 6 + label.len() + context.len());
+    info.extend_from_slice(&(out.len() as u16).to_be_bytes());
+    info.push((6 + label.len()) as u8);
+    info.extend_from_slice(b"tls13 ");
+    info.extend_from_slice(label.as_bytes());
+    info.push(context.len() as u8);
+    info.extend_from_slice(context);
+    // Secrets here are all hash-sized and outputs far below the HKDF limit
+    let hkdf = Hkdf::<Sha256>::from_prk(secret).expect("hash-sized PRK");
+    hkdf.expand(&info, out).expect("short HKDF output");
+}
+
+fn derive_secret(secret: &[u8], label: &str, transcript_hash: &[u8]) -> SecretBytes {
+    let mut out = SecretBytes::from(&[0u8; 32][..]);
+    expand_label(secret, label, transcript_hash, &mut out);
+    out
+}
+
+fn extract(salt: &[u8], ikm: &[u8]) -> SecretBytes {
+    let (prk, _) = Hkdf::<Sha256>::extract(Some(salt), ikm);
+    SecretBytes::from(&prk[..])
+}
+
+/// Key schedule without PSKs, from the (EC)DHE secret on
+struct KeySchedule {
+    handshake_secret: SecretBytes,
+}
+
+impl KeySchedule {
+    fn new(shared_secret: &[u8]) -> Self {
+        let early = extract(&[0; 32], &[0; 32]);
+        let derived = derive_secret(&early, "derived", &Sha256::digest([]));
+        Self {
+            handshake_secret: extract(&derived, shared_secret),
+        }
+    }
+
+    /// Client and server handshake traffic secrets
+    fn handshake_traffic(&self, hello_hash: &[u8]) -> (SecretBytes, SecretBytes) {
+        (
+            derive_secret(&self.handshake_secret, "c hs traffic", hello_hash),
+            derive_secret(&self.handshake_secret, "s hs traffic", hello_hash),
+        )
+    }
+
+    /// Client and server application traffic secrets
+    fn application_traffic(&self, finished_hash: &[u8]) -> (SecretBytes, SecretBytes) {
+        let derived = derive_secret(&self.handshake_secret, "derived", &Sha256::digest([]));
+        let master = extract(&derived, &[0; 32]);
+        (
+            derive_secret(&master, "c ap traffic", finished_hash),
+            derive_secret(&master, "s ap traffic", finished_hash),
+        )
+    }
+}
+
+/// HMAC over the transcript that makes up Finished.verify_data for the
+/// side owning `traffic_secret`
+fn finished_mac(traffic_secret: &[u8], transcript_hash: &[u8]) -> HmacSha256 {
+    let mut key = [0u8; 32];
+    expand_label(traffic_secret, "finished", &[], &mut key);
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(&key).expect("HMAC takes any key length");
+    secret::wipe(&mut key);
+    mac.update(transcript_hash);
+    mac
+}
+
+/// Traffic secret after a KeyUpdate
+fn next_traffic_secret(secret: &[u8]) -> SecretBytes {
+    let mut out = SecretBytes::from(&[0u8; 32][..]);
+    expand_label(secret, "traffic upd", &[], &mut out);
+    out
+}
+
+/// AEAD state of one direction of the connection
+struct RecordCipher {
+    cipher: Aes128Gcm,
+    iv: [u8; 12],
+    seq: u64,
+}
+
+impl RecordCipher {
+    fn new(traffic_secret: &[u8]) -> Self {
+        let mut key = [0u8; 16];
+        let mut iv = [0u8; 12];
+        expand_label(traffic_secret, "key", &[], &mut key);
+        expand_label(traffic_secret, "iv", &[], &mut iv);
+        let cipher = Aes128Gcm::new(&key.into());
+        secret::wipe(&mut key);
+        Self { cipher, iv, seq: 0 }
+    }
+
+    /// Per-record nonce: the IV XORed with the sequence number
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = self.iv;
+        for (n, s) in nonce[4..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *n ^= s;
+        }
+        self.seq += 1;
+        nonce
+    }
+
+    /// Append `data` to `out` as one protected record of `content_type`
+    fn seal(&mut self, content_type: u8, data: &[u8], out: &mut Vec<u8>) -> KResult<()> {
+        let len = (data.len() + 1 + 16) as u16;
+        let start = out.len();
+        out.extend_from_slice(&[CONTENT_APPLICATION_DATA, 3, 3]);
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(data);
+        out.push(content_type);
+        let nonce = self.next_nonce();
+        let (header, body) = out[start..].split_at_mut(5);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), header, body)
+            .map_err(|_| KError::with_context(ErrorKind::Crypto, "tls seal"))?;
+        out.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    /// Decrypt a record body in place, leaving the plaintext; returns the
+    /// inner content type
+    fn open(&mut self, header: &[u8; 5], body: &mut Vec<u8>) -> KResult<u8> {
+        if body.len() < 1 + 16 {
+            return Err(KError::with_context(ErrorKind::Protocol, "tls record"));
+        }
+        let tag_start = body.len() - 16;
+        let tag = *Tag::from_slice(&body[tag_start..]);
+        body.truncate(tag_start);
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt_in_place_detached(Nonce::from_slice(&nonce), header, body, &tag)
+            .map_err(|_| KError::with_context(ErrorKind::Crypto, "tls record"))?;
+        // The content type is the last non-zero byte; zeros after it are padding
+        let end = body
+            .iter()
+            .rposition(|&b| b != 0)
+            .ok_or(KError::with_context(ErrorKind::Protocol, "tls record"))?;
+        let content_type = body[end];
+        body.truncate(end);
+        Ok(content_type)
+    }
+}
+
+// ============================================================================
+// Handshake Messages
+// ============================================================================
+
+/// Cursor over a TLS structure
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn bytes(&mut self, n: usize) -> KResult<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(KError::with_context(ErrorKind::Protocol, "tls decode"));
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn uint(&mut self, n: usize) -> KResult<usize> {
+        Ok(self.bytes(n)?.iter().fold(0, |acc, &b| acc << 8 | b as usize))
+    }
+
+    fn u8(&mut self) -> KResult<u8> {
+        Ok(self.uint(1)? as u8)
+    }
+
+    fn u16(&mut self) -> KResult<u16> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    /// Vector with an `n`-byte length prefix
+    fn vector(&mut self, n: usize) -> KResult<&'a [u8]> {
+        let len = self.uint(n)?;
+        self.bytes(len)
+    }
+
+    fn end(&self) -> KResult<()> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(KError::with_context(ErrorKind::Protocol, "tls decode"))
+        }
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_extension(out: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    push_u16(out, kind);
+    push_u16(out, data.len() as u16);
+    out.extend_from_slice(data);
+}
+
+fn handshake_message(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(4 + body.len());
+    msg.push(kind);
+    msg.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    msg.extend_from_slice(body);
+    msg
+}
+
+/// Body of handshake message `msg`, which must be of `kind`
+fn message_body(msg: &[u8], kind: u8) -> KResult<Reader<'_>> {
+    let mut reader = Reader::new(msg);
+    if reader.u8()? != kind {
+        return Err(KError::with_context(ErrorKind::Protocol, "tls unexpected message"));
+    }
+    let body = reader.vector(3)?;
+    reader.end()?;
+    Ok(Reader::new(body))
+}
+
+fn client_hello(seed: &[u8; SEED_LEN], key_share: &[u8; 32], name: &ServerName) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    if let ServerName::Dns(host) = name {
+        let mut list = Vec::new();
+        push_u16(&mut list, host.len() as u16 + 3);
+        list.push(0); // host_name
+        push_u16(&mut list, host.len() as u16);
+        list.extend_from_slice(host.as_bytes());
+        push_extension(&mut extensions, EXT_SERVER_NAME, &list);
+    }
+    push_extension(&mut extensions, EXT_SUPPORTED_GROUPS, &[0, 2, 0x00, 0x1d]);
+    let mut algorithms = Vec::new();
+    push_u16(&mut algorithms, 2 * SIGNATURE_ALGORITHMS.len() as u16);
+    for code in SIGNATURE_ALGORITHMS {
+        push_u16(&mut algorithms, code);
+    }
+    push_extension(&mut extensions, EXT_SIGNATURE_ALGORITHMS, &algorithms);
+    push_extension(&mut extensions, EXT_SUPPORTED_VERSIONS, &[2, 0x03, 0x04]);
+    let mut share = Vec::new();
+    push_u16(&mut share, 36);
+    push_u16(&mut share, GROUP_X25519);
+    push_u16(&mut share, 32);
+    share.extend_from_slice(key_share);
+    push_extension(&mut extensions, EXT_KEY_SHARE, &share);
+
+    let mut body = Vec::new();
+    push_u16(&mut body, 0x0303); // legacy_version
+    body.extend_from_slice(&seed[..32]);
+    body.push(32);
+    body.extend_from_slice(&seed[32..64]); // legacy_session_id, for middleboxes
+    push_u16(&mut body, 2);
+    push_u16(&mut body, TLS_AES_128_GCM_SHA256);
+    body.extend_from_slice(&[1, 0]); // null compression
+    push_u16(&mut body, extensions.len() as u16);
+    body.extend_from_slice(&extensions);
+    handshake_message(HS_CLIENT_HELLO, &body)
+}
+
+/// The server's x25519 key share from its ServerHello
+fn parse_server_hello<'a>(msg: &'a [u8], session_id: &[u8]) -> KResult<&'a [u8]> {
+    let bad = || KError::with_context(ErrorKind::Protocol, "tls server hello");
+    let mut body = message_body(msg, HS_SERVER_HELLO)?;
+    body.u16()?; // legacy_version
+    if body.bytes(32)? == HELLO_RETRY_RANDOM {
+        // Only asked for when the server wants another group than x25519
+        return Err(KError::with_context(ErrorKind::Unsupported, "tls key exchange group"));
+    }
+    if body.vector(1)? != session_id {
+        return Err(bad());
+    }
+    if body.u16()? != TLS_AES_128_GCM_SHA256 || body.u8()? != 0 {
+        return Err(bad());
+    }
+    let mut extensions = Reader::new(body.vector(2)?);
+    body.end()?;
+
+    let (mut version, mut share) = (None, None);
+    while !extensions.is_empty() {
+        let kind = extensions.u16()?;
+        let mut data = Reader::new(extensions.vector(2)?);
+        match kind {
+            EXT_SUPPORTED_VERSIONS => version = Some(data.u16()?),
+            EXT_KEY_SHARE => {
+                if data.u16()? != GROUP_X25519 {
+                    return Err(bad());
+                }
+                share = Some(data.vector(2)?);
+            }
+            _ => {}
+        }
+    }
+    // Without supported_versions the server picked TLS 1.2 or older
+    if version != Some(TLS13) {
+        return Err(KError::with_context(ErrorKind::Unsupported, "tls version"));
+    }
+    share.filter(|s| s.len() == 32).ok_or_else(bad)
+}
+
+/// Certificate entries of a Certificate message, leaf first
+fn parse_certificate(msg: &[u8]) -> KResult<Vec<&[u8]>> {
+    let mut body = message_body(msg, HS_CERTIFICATE)?;
+    if !body.vector(1)?.is_empty() {
+        return Err(KError::with_context(ErrorKind::Protocol, "tls certificate"));
+    }
+    let mut list = Reader::new(body.vector(3)?);
+    body.end()?;
+    let mut chain = Vec::new();
+    while !list.is_empty() {
+        chain.push(list.vector(3)?);
+        list.vector(2)?; // per-certificate extensions
+    }
+    Ok(chain)
+}
+
+/// Scheme of a CertificateVerify signature, if TLS 1.3 allows `code`
+/// with the leaf's key (PKCS#1 v1.5 is for certificates only)
+fn certificate_verify_scheme(code: u16, key: &PublicKey) -> Option<SignatureScheme> {
+    match (code, key) {
+        (0x0807, PublicKey::Ed25519(_)) => Some(SignatureScheme::Ed25519),
+        (0x0403, PublicKey::EcdsaP256(_)) => Some(SignatureScheme::EcdsaSha256),
+        (0x0503, PublicKey::EcdsaP384(_)) => Some(SignatureScheme::EcdsaSha384),
+        (0x0804, PublicKey::Rsa(_)) => Some(SignatureScheme::RsaPssSha256),
+        (0x0805, PublicKey::Rsa(_)) => Some(SignatureScheme::RsaPssSha384),
+        (0x0806, PublicKey::Rsa(_)) => Some(SignatureScheme::RsaPssSha512),
+        _ => None,
+    }
+}
+
+/// Alert to send the server when the handshake fails with `kind`
+fn alert_for(kind: ErrorKind) -> Option<u8> {
+    match kind {
+        ErrorKind::AuthFailed => Some(ALERT_BAD_CERTIFICATE),
+        ErrorKind::Crypto => Some(ALERT_DECRYPT_ERROR),
+        ErrorKind::Protocol => Some(ALERT_UNEXPECTED_MESSAGE),
+        ErrorKind::Unsupported | ErrorKind::LimitReached => Some(ALERT_HANDSHAKE_FAILURE),
+        _ => None,
+    }
+}
+
+// ============================================================================
+// Streams
+// ============================================================================
+
+/// Byte stream a TLS connection runs over
+pub trait Transport {
+    /// Read what is available into `buf`; 0 at end of stream
+    fn recv(&mut self, buf: &mut [u8]) -> impl Future<Output = KResult<usize>>;
+    fn send(&mut self, data: &[u8]) -> impl Future<Output = KResult<()>>;
+}
+
+impl Transport for TcpStream {
+    async fn recv(&mut self, buf: &mut [u8]) -> KResult<usize> {
+        Ok(self.read(buf).await?)
+    }
+
+    async fn send(&mut self, data: &[u8]) -> KResult<()> {
+        Ok(self.write_all(data).await?)
+    }
+}
+
+/// Client side of a TLS 1.3 connection
+pub struct TlsStream<T: Transport = TcpStream> {
+    transport: T,
+    /// None until the server's keys are known (ServerHello)
+    read_cipher: Option<RecordCipher>,
+    write_cipher: Option<RecordCipher>,
+    /// Application traffic secrets, for KeyUpdate
+    read_secret: SecretBytes,
+    write_secret: SecretBytes,
+    connected: bool,
+    /// close_notify received
+    peer_closed: bool,
+    /// Handshake bytes not yet assembled into a message
+    handshake: Vec<u8>,
+    /// Application data decrypted but not yet read
+    plaintext: Vec<u8>,
+    plaintext_pos: usize,
+}
+
+impl TlsStream<TcpStream> {
+    /// Connect to `remote` and run the handshake within `timeout`
+    pub async fn connect(
+        stack: Stack<'static>,
+        remote: IpEndpoint,
+        name: ServerName,
+        trust: &TrustStore,
+        timeout: Duration,
+    ) -> KResult<Self> {
+        let tcp = TcpStream::connect(stack, remote, timeout).await?;
+        embassy_time::with_timeout(timeout, Self::handshake(tcp, &name, trust))
+            .await
+            .map_err(|_| KError::with_context(ErrorKind::TimedOut, "tls handshake"))?
+    }
+
+    /// Send close_notify and close the TCP connection
+    pub async fn close(&mut self) {
+        let _ = self.shutdown().await;
+        self.transport.close();
+    }
+}
+
+impl<T: Transport> TlsStream<T> {
+    /// Run the client handshake over a connected `transport`
+    pub async fn handshake(transport: T, name: &ServerName, trust: &TrustStore) -> KResult<Self> {
+        let mut seed = [0u8; SEED_LEN];
+        random_bytes(&mut seed);
+        let result = Self::handshake_seeded(transport, name, trust, &seed).await;
+        secret::wipe(&mut seed);
+        result
+    }
+
+    /// `handshake` with the client random, session ID and x25519 private
+    /// key taken from `seed` rather than the RNG, so a recorded exchange
+    /// can be replayed; never reuse a seed with a real server
+    pub async fn handshake_seeded(
+        transport: T,
+        name: &ServerName,
+        trust: &TrustStore,
+        seed: &[u8; SEED_LEN],
+    ) -> KResult<Self> {
+        let mut stream = Self {
+            transport,
+            read_cipher: None,
+            write_cipher: None,
+            read_secret: SecretBytes::new(),
+            write_secret: SecretBytes::new(),
+            connected: false,
+            peer_closed: false,
+            handshake: Vec::new(),
+            plaintext: Vec::new(),
+            plaintext_pos: 0,
+        };
+        match stream.run_handshake(name, trust, seed).await {
+            Ok(()) => Ok(stream),
+            Err(e) => {
+                if let Some(alert) = alert_for(e.kind()) {
+                    let _ = stream.send_record(CONTENT_ALERT, &[2, alert]).await;
+                }
+                crate::kwarn!("[TLS] Handshake failed: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn run_handshake(
+        &mut self,
+        name: &ServerName,
+        trust: &TrustStore,
+        seed: &[u8; SEED_LEN],
+    ) -> KResult<()> {
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&seed[64..]);
+        let ephemeral = StaticSecret::from(key);
+        secret::wipe(&mut key);
+        let hello = client_hello(seed, X25519Public::from(&ephemeral).as_bytes(), name);
+        let mut transcript = Sha256::new();
+        transcript.update(&hello);
+        self.send_record(CONTENT_HANDSHAKE, &hello).await?;
+
+        let msg = self.read_handshake().await?;
+        let share: [u8; 32] = parse_server_hello(&msg, &seed[32..64])?
+            .try_into()
+            .map_err(|_| KError::with_context(ErrorKind::Protocol, "tls server hello"))?;
+        transcript.update(&msg);
+        let shared = ephemeral.diffie_hellman(&X25519Public::from(share));
+        if !shared.was_contributory() {
+            return Err(KError::with_context(ErrorKind::Crypto, "tls key share"));
+        }
+        let schedule = KeySchedule::new(shared.as_bytes());
+        let (client_hs, server_hs) = schedule.handshake_traffic(&transcript.clone().finalize());
+        self.read_cipher = Some(RecordCipher::new(&server_hs));
+        // Installed now so a failure alert goes out under handshake keys
+        self.write_cipher = Some(RecordCipher::new(&client_hs));
+
+        let msg = self.read_handshake().await?;
+        let mut extensions = message_body(&msg, HS_ENCRYPTED_EXTENSIONS)?;
+        extensions.vector(2)?;
+        extensions.end()?;
+        transcript.update(&msg);
+
+        // An optional CertificateRequest; its context is echoed back
+        let mut msg = self.read_handshake().await?;
+        let mut request_context = None;
+        if msg.first() == Some(&HS_CERTIFICATE_REQUEST) {
+            let mut request = message_body(&msg, HS_CERTIFICATE_REQUEST)?;
+            request_context = Some(request.vector(1)?.to_vec());
+            transcript.update(&msg);
+            msg = self.read_handshake().await?;
+        }
+
+        let chain = parse_certificate(&msg)?;
+        let now = crate::timer::utc_time_us().map(|us| us / 1_000_000);
+        let anchor = x509::verify_chain(&chain, &trust.roots, &trust.pins, now)?;
+        let leaf = Certificate::parse(chain[0])?;
+        if anchor == Anchor::Root && !name.matches(&leaf) {
+            return Err(KError::with_context(ErrorKind::AuthFailed, "certificate name mismatch"));
+        }
+        let leaf_key = leaf
+            .public_key
+            .ok_or(KError::with_context(ErrorKind::AuthFailed, "certificate key type"))?;
+        transcript.update(&msg);
+
+        let msg = self.read_handshake().await?;
+        let mut verify = message_body(&msg, HS_CERTIFICATE_VERIFY)?;
+        let scheme = certificate_verify_scheme(verify.u16()?, &leaf_key)
+            .ok_or(KError::with_context(ErrorKind::Protocol, "tls signature scheme"))?;
+        let signature = verify.vector(2)?;
+        verify.end()?;
+        let mut signed = vec![0x20u8; 64];
+        signed.extend_from_slice(b"TLS 1.3, server CertificateVerify\0");
+        signed.extend_from_slice(&transcript.clone().finalize());
+        if !x509::verify_signature(&leaf_key, scheme, &signed, signature) {
+            return Err(KError::with_context(ErrorKind::Crypto, "tls certificate verify"));
+        }
+        transcript.update(&msg);
+
+        let msg = self.read_handshake().await?;
+        let mut finished = message_body(&msg, HS_FINISHED)?;
+        finished_mac(&server_hs, &transcript.clone().finalize())
+            .verify_slice(finished.bytes(32)?)
+            .map_err(|_| KError::with_context(ErrorKind::Crypto, "tls server finished"))?;
+        finished.end()?;
+        transcript.update(&msg);
+        // A key change must fall on a record boundary
+        if !self.handshake.is_empty() {
+            return Err(KError::with_context(ErrorKind::Protocol, "tls server finished"));
+        }
+        let (client_ap, server_ap) = schedule.application_traffic(&transcript.clone().finalize());
+
+        if let Some(context) = request_context {
+            let mut body = vec![context.len() as u8];
+            body.extend_from_slice(&context);
+            body.extend_from_slice(&[0, 0, 0]); // no certificates
+            let msg = handshake_message(HS_CERTIFICATE, &body);
+            transcript.update(&msg);
+            self.send_record(CONTENT_HANDSHAKE, &msg).await?;
+        }
+        let verify_data = finished_mac(&client_hs, &transcript.finalize()).finalize();
+        self.send_record(CONTENT_HANDSHAKE, &handshake_message(HS_FINISHED, &verify_data.into_bytes()))
+            .await?;
+
+        self.read_cipher = Some(RecordCipher::new(&server_ap));
+        self.write_cipher = Some(RecordCipher::new(&client_ap));
+        self.read_secret = server_ap;
+        self.write_secret = client_ap;
+        self.connected = true;
+        Ok(())
+    }
+
+    async fn read_exact(&mut self, buf: &mut [u8]) -> KResult<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = self.transport.recv(&mut buf[filled..]).await?;
+            if n == 0 {
+                return Err(KError::with_context(ErrorKind::ConnectionClosed, "tls"));
+            }
+            filled += n;
+        }
+        Ok(())
+    }
+
+    /// Next record as (content type, plaintext); alerts become errors
+    async fn read_record(&mut self) -> KResult<(u8, Vec<u8>)> {
+        loop {
+            let mut header = [0u8; 5];
+            self.read_exact(&mut header).await?;
+            let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+            if header[1] != 3 || len > MAX_CIPHERTEXT {
+                return Err(KError::with_context(ErrorKind::Protocol, "tls record header"));
+            }
+            let mut body = vec![0u8; len];
+            self.read_exact(&mut body).await?;
+
+            let content_type = match (header[0], self.read_cipher.as_mut()) {
+                // Middlebox compatibility; meaningless in TLS 1.3
+                (CONTENT_CHANGE_CIPHER_SPEC, _) if !self.connected && body == [1] => continue,
+                (CONTENT_APPLICATION_DATA, Some(cipher)) => cipher.open(&header, &mut body)?,
+                (CONTENT_HANDSHAKE | CONTENT_ALERT, None) => header[0],
+                _ => return Err(KError::with_context(ErrorKind::Protocol, "tls record type")),
+            };
+            if content_type == CONTENT_ALERT {
+                if body.len() == 2 && body[1] == ALERT_CLOSE_NOTIFY {
+                    self.peer_closed = true;
+                    return Err(KError::with_context(ErrorKind::ConnectionClosed, "tls"));
+                }
+                crate::kwarn!("[TLS] Alert {} from server", body.get(1).copied().unwrap_or(0));
+                return Err(KError::with_context(ErrorKind::Protocol, "tls alert"));
+            }
+            if body.is_empty() && content_type != CONTENT_APPLICATION_DATA {
+                return Err(KError::with_context(ErrorKind::Protocol, "tls empty record"));
+            }
+            return Ok((content_type, body));
+        }
+    }
+
+    /// Next complete handshake message, if buffered
+    fn take_handshake_message(&mut self) -> KResult<Option<Vec<u8>>> {
+        let Some(header) = self.handshake.get(..4) else {
+            return Ok(None);
+        };
+        let len = Reader::new(&header[1..]).uint(3)?;
+        if len > MAX_HANDSHAKE {
+            return Err(KError::with_context(ErrorKind::LimitReached, "tls handshake message"));
+        }
+        if self.handshake.len() < 4 + len {
+            return Ok(None);
+        }
+        Ok(Some(self.handshake.drain(..4 + len).collect()))
+    }
+
+    async fn read_handshake(&mut self) -> KResult<Vec<u8>> {
+        loop {
+            if let Some(msg) = self.take_handshake_message()? {
+                return Ok(msg);
+            }
+            match self.read_record().await? {
+                (CONTENT_HANDSHAKE, data) => self.handshake.extend_from_slice(&data),
+                _ => return Err(KError::with_context(ErrorKind::Protocol, "tls unexpected record")),
+            }
+        }
+    }
+
+    /// Send `data` as records of `content_type`, protected once keys exist
+    async fn send_record(&mut self, content_type: u8, data: &[u8]) -> KResult<()> {
+        let mut out = Vec::with_capacity(data.len() + 32);
+        for chunk in data.chunks(MAX_PLAINTEXT) {
+            match self.write_cipher.as_mut() {
+                Some(cipher) => cipher.seal(content_type, chunk, &mut out)?,
+                None => {
+                    // legacy_record_version 0x0301 for the ClientHello
+                    out.extend_from_slice(&[content_type, 3, 1]);
+                    out.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                    out.extend_from_slice(chunk);
+                }
+            }
+        }
+        self.transport.send(&out).await
+    }
+
+    /// Handle NewSessionTicket and KeyUpdate after the handshake
+    async fn post_handshake(&mut self) -> KResult<()> {
+        while let Some(msg) = self.take_handshake_message()? {
+            match msg[0] {
+                // No resumption, so tickets are of no use
+                HS_NEW_SESSION_TICKET => {}
+                HS_KEY_UPDATE => {
+                    let mut body = message_body(&msg, HS_KEY_UPDATE)?;
+                    let update_requested = match body.u8()? {
+                        0 => false,
+                        1 => true,
+                        _ => return Err(KError::with_context(ErrorKind::Protocol, "tls key update")),
+                    };
+                    body.end()?;
+                    if !self.handshake.is_empty() {
+                        return Err(KError::with_context(ErrorKind::Protocol, "tls key update"));
+                    }
+                    self.read_secret = next_traffic_secret(&self.read_secret);
+                    self.read_cipher = Some(RecordCipher::new(&self.read_secret));
+                    if update_requested {
+                        let reply = handshake_message(HS_KEY_UPDATE, &[0]);
+                        self.send_record(CONTENT_HANDSHAKE, &reply).await?;
+                        self.write_secret = next_traffic_secret(&self.write_secret);
+                        self.write_cipher = Some(RecordCipher::new(&self.write_secret));
+                    }
+                }
+                _ => return Err(KError::with_context(ErrorKind::Protocol, "tls unexpected message")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read application data into `buf`; 0 once the server has closed
+    pub async fn read(&mut self, buf: &mut [u8]) -> KResult<usize> {
+        loop {
+            if self.plaintext_pos < self.plaintext.len() {
+                let n = buf.len().min(self.plaintext.len() - self.plaintext_pos);
+                buf[..n].copy_from_slice(&self.plaintext[self.plaintext_pos..self.plaintext_pos + n]);
+                self.plaintext_pos += n;
+                return Ok(n);
+            }
+            if self.peer_closed {
+                return Ok(0);
+            }
+            match self.read_record().await {
+                Ok((CONTENT_APPLICATION_DATA, data)) => {
+                    self.plaintext = data;
+                    self.plaintext_pos = 0;
+                }
+                Ok((CONTENT_HANDSHAKE, data)) => {
+                    self.handshake.extend_from_slice(&data);
+                    self.post_handshake().await?;
+                }
+                Ok(_) => {
+                    let _ = self.send_record(CONTENT_ALERT, &[2, ALERT_UNEXPECTED_MESSAGE]).await;
+                    return Err(KError::with_context(ErrorKind::Protocol, "tls unexpected record"));
+                }
+                Err(_) if self.peer_closed => return Ok(0),
+                Err(e) => {
+                    if e.kind() == ErrorKind::Crypto {
+                        let _ = self.send_record(CONTENT_ALERT, &[2, ALERT_BAD_RECORD_MAC]).await;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Send all of `data` as application data
+    pub async fn write_all(&mut self, data: &[u8]) -> KResult<()> {
+        self.send_record(CONTENT_APPLICATION_DATA, data).await
+    }
+
+    /// Send close_notify; the transport stays open
+    pub async fn shutdown(&mut self) -> KResult<()> {
+        self.send_record(CONTENT_ALERT, &[1, ALERT_CLOSE_NOTIFY]).await
+    }
+}
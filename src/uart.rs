@@ -0,0 +1,181 @@
+//! UART Ports
+//!
+//! The PL011 instances on the board and who owns each. uart0 always
+//! belongs to the console; the others (QEMU virt adds a second PL011 at
+//! 0x0904_0000 when started with two `-serial` options) can be claimed by
+//! one user at a time - a debug log channel, a GDB stub, a second shell -
+//! through a `Port`, which releases the UART when dropped.
+//!
+//! With `log.uart=uart1` kernel log records go to that port instead of the
+//! console, leaving the serial console to the interactive shell.
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::klog::{self, Record, Timestamp};
+use crate::lockdep::Spinlock;
+use crate::pl011::{Pl011, UartConfig};
+
+/// A PL011 on the board
+#[derive(Debug, Clone, Copy)]
+pub struct PortInfo {
+    pub name: &'static str,
+    pub base: usize,
+    /// GIC interrupt number
+    pub irq: u32,
+}
+
+/// UARTs on QEMU virt, console first
+pub const PORTS: [PortInfo; 2] = [
+    PortInfo {
+        name: "uart0",
+        base: 0x0900_0000,
+        irq: 33,
+    },
+    PortInfo {
+        name: "uart1",
+        base: 0x0904_0000,
+        irq: 40,
+    },
+];
+
+/// Current owner of each port
+static OWNERS: Spinlock<[Option<&'static str>; PORTS.len()]> =
+    Spinlock::new([Some("console"), None]);
+
+/// Run a closure with IRQs disabled (ports may be released from any context)
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+/// Index of the port called `name`
+pub fn find(name: &str) -> Option<usize> {
+    PORTS.iter().position(|p| p.name == name)
+}
+
+/// True if the port's UART responds (uart0 is assumed to)
+pub fn is_present(index: usize) -> bool {
+    match index {
+        0 => true,
+        i if i < PORTS.len() => {
+            // SAFETY: only reads the ID registers of a fixed MMIO window
+            let uart = unsafe { Pl011::new(PORTS[i].base) };
+            uart.is_present()
+        }
+        _ => false,
+    }
+}
+
+/// Who has the port, if anyone
+pub fn owner(index: usize) -> Option<&'static str> {
+    with_irqs_disabled(|| OWNERS.lock().get(index).copied().flatten())
+}
+
+// ============================================================================
+// Claimed Ports
+// ============================================================================
+
+/// Exclusive use of a UART until dropped
+pub struct Port {
+    index: usize,
+    uart: Pl011,
+}
+
+/// Take the port called `name` for `owner`
+///
+/// Fails with `NotFound` for an unknown name, `NoDevice` if the UART isn't
+/// there and `AlreadyExists` if someone else has it.
+pub fn claim(name: &str, owner: &'static str) -> KResult<Port> {
+    let index = find(name).ok_or(KError::with_context(ErrorKind::NotFound, "uart"))?;
+    if !is_present(index) {
+        return Err(KError::with_context(ErrorKind::NoDevice, PORTS[index].name));
+    }
+    with_irqs_disabled(|| {
+        let mut owners = OWNERS.lock();
+        if owners[index].is_some() {
+            return Err(KError::with_context(ErrorKind::AlreadyExists, "uart in use"));
+        }
+        owners[index] = Some(owner);
+        Ok(())
+    })?;
+    Ok(Port {
+        index,
+        // SAFETY: the port is claimed, so nothing else drives this UART
+        uart: unsafe { Pl011::new(PORTS[index].base) },
+    })
+}
+
+impl Port {
+    #[cfg(feature = "shell")]
+    pub fn info(&self) -> &'static PortInfo {
+        &PORTS[self.index]
+    }
+
+    /// Program line settings (see `Pl011::init`)
+    pub fn configure(&self, config: &UartConfig) -> KResult<()> {
+        self.uart.init(config)
+    }
+
+    /// Send bytes, spinning while the FIFO is full
+    pub fn write(&self, bytes: &[u8]) {
+        for &b in bytes {
+            self.uart.write_byte_blocking(b);
+        }
+    }
+}
+
+impl core::fmt::Write for Port {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        with_irqs_disabled(|| OWNERS.lock()[self.index] = None);
+    }
+}
+
+// ============================================================================
+// Log Port
+// ============================================================================
+
+static LOG_PORT: Spinlock<Option<Port>> = Spinlock::new(None);
+
+fn log_sink(record: &Record) {
+    // Sinks run in thread context (IRQ records are staged), so waiting
+    // for the lock only ever waits for another thread's line
+    if let Some(port) = LOG_PORT.lock().as_mut() {
+        let _ = core::fmt::Write::write_fmt(port, format_args!("{} ", Timestamp::of(record)));
+        if let Some(event) = record.event {
+            let _ = core::fmt::Write::write_fmt(port, format_args!("[E{}] ", event.id()));
+        }
+        port.write(record.text.as_bytes());
+        port.write(b"\n");
+    }
+}
+
+/// Send kernel log records to `name` instead of the console
+pub fn route_logs(name: &str, config: &UartConfig) -> KResult<()> {
+    let port = claim(name, "klog")?;
+    port.configure(config)?;
+    *LOG_PORT.lock() = Some(port);
+    klog::register_sink("uart", log_sink)?;
+    let _ = klog::unregister_sink("console");
+    Ok(())
+}
+
+/// Name of the port log records go to, if not the console
+#[cfg(feature = "shell")]
+pub fn log_port() -> Option<&'static str> {
+    LOG_PORT.lock().as_ref().map(|p| p.info().name)
+}
@@ -0,0 +1,523 @@
+//! Virtual File System
+//!
+//! A mount table of `FileSystem`s and an open-file interface with both
+//! blocking and async I/O. Servers running on the shared executor (HTTP,
+//! later SFTP) use the `_async` variants so a large transfer yields
+//! between chunks instead of stalling every other connection; thread code
+//! uses the blocking ones.
+//!
+//! RAM-backed filesystems complete async requests immediately (the default
+//! implementations). A block-backed filesystem overrides them with futures
+//! that wait on its device queue; there is no block driver in the tree yet.
+//!
+//! Mounted at boot:
+//! - `/` - kernel-generated files (crash report, console log, config, statistics)
+//! - `/tmp` - in-memory scratch files
+//!
+//! A mount belongs to the thread or task that made it (see `handles`) and
+//! is removed when that owner goes away, unless unmounted first.
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::handles::{self, Handle, ResourceKind};
+use crate::kobject::{self, KObjType, KObject};
+use crate::lockdep::Spinlock;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Future returned by the async file operations
+pub type IoFuture<'a, T> = Pin<Box<dyn Future<Output = KResult<T>> + 'a>>;
+
+// ============================================================================
+// Traits
+// ============================================================================
+
+/// An open file
+pub trait File: Send {
+    /// Current size in bytes
+    fn size(&self) -> usize;
+
+    /// Read at `offset`, returning the bytes read (0 at end of file)
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> KResult<usize>;
+
+    /// Write at `offset`, returning the bytes written
+    fn write_at(&mut self, _offset: usize, _data: &[u8]) -> KResult<usize> {
+        Err(KError::with_context(ErrorKind::Unsupported, "read-only file"))
+    }
+
+    /// Async `read_at`; by default completes immediately
+    fn read_at_async<'a>(&'a mut self, offset: usize, buf: &'a mut [u8]) -> IoFuture<'a, usize> {
+        let result = self.read_at(offset, buf);
+        Box::pin(core::future::ready(result))
+    }
+
+    /// Async `write_at`; by default completes immediately
+    fn write_at_async<'a>(&'a mut self, offset: usize, data: &'a [u8]) -> IoFuture<'a, usize> {
+        let result = self.write_at(offset, data);
+        Box::pin(core::future::ready(result))
+    }
+}
+
+/// One entry of a directory listing
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: usize,
+}
+
+/// A mountable filesystem; paths are relative to its mount point
+/// and have no leading slash ("" is the root)
+pub trait FileSystem: Send + Sync {
+    /// Short type name for listings
+    fn name(&self) -> &'static str;
+
+    /// Open a file, creating it first if `create` is set and it doesn't exist
+    fn open(&self, path: &str, create: bool) -> KResult<Box<dyn File>>;
+
+    fn list(&self, path: &str) -> KResult<Vec<DirEntry>>;
+
+    fn remove(&self, _path: &str) -> KResult<()> {
+        Err(KError::with_context(ErrorKind::Unsupported, self.name()))
+    }
+}
+
+// ============================================================================
+// Mount Table
+// ============================================================================
+
+struct Mount {
+    id: u32,
+    /// Absolute, no trailing slash ("" for the root)
+    point: String,
+    fs: Arc<dyn FileSystem>,
+    /// Tracks the mount under the owner that made it
+    handle: Handle,
+    _kobj: Option<Arc<KObject>>,
+}
+
+static MOUNTS: Spinlock<Vec<Mount>> = Spinlock::new(Vec::new());
+static NEXT_MOUNT: AtomicU32 = AtomicU32::new(1);
+
+fn normalize(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+/// Mount `fs` at `point` (e.g. "/" or "/tmp")
+pub fn mount(point: &str, fs: Arc<dyn FileSystem>) -> KResult<()> {
+    let point = normalize(point);
+    let id = NEXT_MOUNT.fetch_add(1, Ordering::Relaxed);
+    let handle = handles::track(ResourceKind::Mount, move || remove_mount(id))?;
+
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|m| m.point == point) {
+        drop(mounts);
+        handles::untrack(handle);
+        return Err(KError::with_context(ErrorKind::AlreadyExists, "mount point"));
+    }
+    let name = alloc::format!("/{} ({})", point, fs.name());
+    mounts.push(Mount {
+        id,
+        point: String::from(point),
+        fs,
+        handle,
+        _kobj: kobject::create(KObjType::Mount, &name, None).ok(),
+    });
+    Ok(())
+}
+
+/// Remove the filesystem mounted at `point`
+pub fn unmount(point: &str) -> KResult<()> {
+    let point = normalize(point);
+    let mut mounts = MOUNTS.lock();
+    let pos = mounts
+        .iter()
+        .position(|m| m.point == point)
+        .ok_or(KError::with_context(ErrorKind::NotFound, "mount point"))?;
+    let mount = mounts.remove(pos);
+    drop(mounts);
+    handles::untrack(mount.handle);
+    Ok(())
+}
+
+/// Reclaim action of a mount whose owner went away
+fn remove_mount(id: u32) {
+    let mut mounts = MOUNTS.lock();
+    if let Some(pos) = mounts.iter().position(|m| m.id == id) {
+        let mount = mounts.remove(pos);
+        drop(mounts);
+        drop(mount);
+    }
+}
+
+/// Mount points and filesystem names
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|m| (alloc::format!("/{}", m.point), m.fs.name()))
+        .collect()
+}
+
+/// Filesystem responsible for `path` and the path within it (longest match)
+fn resolve(path: &str) -> KResult<(Arc<dyn FileSystem>, String)> {
+    let path = normalize(path);
+    let mounts = MOUNTS.lock();
+    let mount = mounts
+        .iter()
+        .filter(|m| {
+            m.point.is_empty()
+                || path == m.point
+                || path.strip_prefix(m.point.as_str()).is_some_and(|rest| rest.starts_with('/'))
+        })
+        .max_by_key(|m| m.point.len())
+        .ok_or(KError::with_context(ErrorKind::NotFound, "no filesystem"))?;
+    let rest = normalize(&path[mount.point.len()..]);
+    Ok((mount.fs.clone(), String::from(rest)))
+}
+
+// ============================================================================
+// Path API
+// ============================================================================
+
+/// Open `path` for reading and writing
+pub fn open(path: &str) -> KResult<Box<dyn File>> {
+    let (fs, rel) = resolve(path)?;
+    fs.open(&rel, false)
+}
+
+/// Open `path`, creating an empty file if needed
+pub fn create(path: &str) -> KResult<Box<dyn File>> {
+    let (fs, rel) = resolve(path)?;
+    fs.open(&rel, true)
+}
+
+pub fn remove(path: &str) -> KResult<()> {
+    let (fs, rel) = resolve(path)?;
+    fs.remove(&rel)
+}
+
+/// List a directory, including mount points directly below it
+pub fn list(path: &str) -> KResult<Vec<DirEntry>> {
+    let (fs, rel) = resolve(path)?;
+    let mut entries = fs.list(&rel)?;
+
+    let dir = normalize(path);
+    for (point, _) in mounts() {
+        let point = normalize(&point);
+        let child = if dir.is_empty() {
+            Some(point)
+        } else {
+            point.strip_prefix(dir).and_then(|rest| rest.strip_prefix('/'))
+        };
+        if let Some(name) = child.filter(|n| !n.is_empty() && !n.contains('/')) {
+            entries.push(DirEntry {
+                name: String::from(name),
+                is_dir: true,
+                size: 0,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Read a whole file (blocking)
+pub fn read_file(path: &str) -> KResult<Vec<u8>> {
+    let mut file = open(path)?;
+    let mut data = Vec::new();
+    data.try_reserve(file.size())
+        .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "read_file"))?;
+    data.resize(file.size(), 0);
+    let n = file.read_at(0, &mut data)?;
+    data.truncate(n);
+    Ok(data)
+}
+
+/// Replace a file's contents (blocking)
+pub fn write_file(path: &str, data: &[u8]) -> KResult<()> {
+    let (fs, rel) = resolve(path)?;
+    let _ = fs.remove(&rel);
+    let mut file = fs.open(&rel, true)?;
+    let n = file.write_at(0, data)?;
+    if n != data.len() {
+        return Err(KError::with_context(ErrorKind::NoSpace, "write_file"));
+    }
+    Ok(())
+}
+
+// ============================================================================
+// RAM Filesystem
+// ============================================================================
+
+/// Contents of one RAM file, shared by its open handles
+type RamData = Arc<Spinlock<Vec<u8>>>;
+
+/// Flat in-memory filesystem
+pub struct RamFs {
+    files: Spinlock<Vec<(String, RamData)>>,
+}
+
+impl RamFs {
+    pub const fn new() -> Self {
+        Self {
+            files: Spinlock::new(Vec::new()),
+        }
+    }
+}
+
+struct RamFile {
+    data: RamData,
+}
+
+impl File for RamFile {
+    fn size(&self) -> usize {
+        self.data.lock().len()
+    }
+
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> KResult<usize> {
+        let data = self.data.lock();
+        if offset >= data.len() {
+            return Ok(0);
+        }
+        let n = buf.len().min(data.len() - offset);
+        buf[..n].copy_from_slice(&data[offset..offset + n]);
+        Ok(n)
+    }
+
+    fn write_at(&mut self, offset: usize, src: &[u8]) -> KResult<usize> {
+        let mut data = self.data.lock();
+        let end = offset + src.len();
+        let len = data.len();
+        if end > len {
+            data.try_reserve(end - len)
+                .map_err(|_| KError::with_context(ErrorKind::NoSpace, "ramfs"))?;
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(src);
+        Ok(src.len())
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn open(&self, path: &str, create: bool) -> KResult<Box<dyn File>> {
+        if path.is_empty() {
+            return Err(KError::with_context(ErrorKind::IsADirectory, "ramfs"));
+        }
+        if path.contains('/') {
+            return Err(KError::with_context(ErrorKind::NotADirectory, "ramfs"));
+        }
+        let mut files = self.files.lock();
+        let data = match files.iter().find(|(name, _)| name == path) {
+            Some((_, data)) => data.clone(),
+            None if create => {
+                let data = Arc::new(Spinlock::new(Vec::new()));
+                files.push((String::from(path), data.clone()));
+                data
+            }
+            None => return Err(KError::with_context(ErrorKind::NotFound, "ramfs")),
+        };
+        Ok(Box::new(RamFile { data }))
+    }
+
+    fn list(&self, path: &str) -> KResult<Vec<DirEntry>> {
+        if !path.is_empty() {
+            return Err(KError::with_context(ErrorKind::NotFound, "ramfs"));
+        }
+        Ok(self
+            .files
+            .lock()
+            .iter()
+            .map(|(name, data)| DirEntry {
+                name: name.clone(),
+                is_dir: false,
+                size: data.lock().len(),
+            })
+            .collect())
+    }
+
+    fn remove(&self, path: &str) -> KResult<()> {
+        let mut files = self.files.lock();
+        let pos = files
+            .iter()
+            .position(|(name, _)| name == path)
+            .ok_or(KError::with_context(ErrorKind::NotFound, "ramfs"))?;
+        files.remove(pos);
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Kernel Filesystem
+// ============================================================================
+
+/// Read-only files generated by the kernel
+///
+/// Contents are generated when a file is opened, so one open file reads a
+/// consistent snapshot however many chunks it's read in.
+pub struct KernelFs;
+
+struct KernelFile {
+    name: &'static str,
+    generate: fn() -> Vec<u8>,
+}
+
+const KERNEL_FILES: &[KernelFile] = &[
+    #[cfg(feature = "ssh")]
+    KernelFile {
+        name: "connbudget.txt",
+        generate: connbudget_file,
+    },
+    KernelFile {
+        name: "crashdump.txt",
+        generate: crashdump_file,
+    },
+    KernelFile {
+        name: "dmesg.txt",
+        generate: crate::dmesg::read_all,
+    },
+    KernelFile {
+        name: "config.txt",
+        generate: config_file,
+    },
+    KernelFile {
+        name: "meminfo.txt",
+        generate: meminfo_file,
+    },
+    KernelFile {
+        name: "netstats.txt",
+        generate: netstats_file,
+    },
+];
+
+#[cfg(feature = "ssh")]
+fn connbudget_file() -> Vec<u8> {
+    crate::conn_budget::report().into_bytes()
+}
+
+fn crashdump_file() -> Vec<u8> {
+    match crate::crashdump::previous_report() {
+        Some(report) => Vec::from(report.as_bytes()),
+        None => Vec::from(&b"No crash report\n"[..]),
+    }
+}
+
+fn config_file() -> Vec<u8> {
+    let mut out = String::new();
+    for (key, value) in crate::config::entries() {
+        out.push_str(&alloc::format!("{}={}\n", key, value));
+    }
+    out.into_bytes()
+}
+
+fn meminfo_file() -> Vec<u8> {
+    let heap = crate::allocator::stats();
+    let pages = crate::pmm::stats();
+    let mut out = alloc::format!(
+        "heap_total {}\nheap_used {}\nheap_peak {}\nheap_largest_free {}\npages_total {}\npages_free {}\n",
+        heap.total,
+        heap.used,
+        heap.peak,
+        heap.largest_free,
+        pages.total_pages,
+        pages.free_pages
+    );
+    for r in crate::vmm::list() {
+        out.push_str(&alloc::format!(
+            "region {:#x} {:#x} {} {}\n",
+            r.base,
+            r.size,
+            r.kind.as_str(),
+            r.name
+        ));
+    }
+    out.into_bytes()
+}
+
+fn netstats_file() -> Vec<u8> {
+    let (connections, rx, tx) = crate::network::get_stats();
+    let mut out = alloc::format!("connections {}\nbytes_rx {}\nbytes_tx {}\n", connections, rx, tx);
+    out.push_str(&alloc::format!(
+        "rx_interrupts {}\nrestarts {}\n",
+        crate::embassy_virtio_driver::rx_interrupts(),
+        crate::network::restarts()
+    ));
+    for service in crate::network::Service::ALL {
+        let st = crate::network::service_stats(service);
+        out.push_str(&alloc::format!(
+            "{} rx {} tx {} throttled {}\n",
+            service.as_str(),
+            st.bytes_rx,
+            st.bytes_tx,
+            st.throttled
+        ));
+    }
+    out.into_bytes()
+}
+
+/// Snapshot of a generated file
+struct Snapshot(Vec<u8>);
+
+impl File for Snapshot {
+    fn size(&self) -> usize {
+        self.0.len()
+    }
+
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> KResult<usize> {
+        if offset >= self.0.len() {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.0.len() - offset);
+        buf[..n].copy_from_slice(&self.0[offset..offset + n]);
+        Ok(n)
+    }
+}
+
+impl FileSystem for KernelFs {
+    fn name(&self) -> &'static str {
+        "kernelfs"
+    }
+
+    fn open(&self, path: &str, _create: bool) -> KResult<Box<dyn File>> {
+        if path.is_empty() {
+            return Err(KError::with_context(ErrorKind::IsADirectory, "kernelfs"));
+        }
+        let file = KERNEL_FILES
+            .iter()
+            .find(|f| f.name == path)
+            .ok_or(KError::with_context(ErrorKind::NotFound, "kernelfs"))?;
+        Ok(Box::new(Snapshot((file.generate)())))
+    }
+
+    fn list(&self, path: &str) -> KResult<Vec<DirEntry>> {
+        if !path.is_empty() {
+            return Err(KError::with_context(ErrorKind::NotFound, "kernelfs"));
+        }
+        Ok(KERNEL_FILES
+            .iter()
+            .map(|f| DirEntry {
+                name: String::from(f.name),
+                is_dir: false,
+                size: 0, // Generated on open
+            })
+            .collect())
+    }
+}
+
+// ============================================================================
+// Setup
+// ============================================================================
+
+/// Mount the boot filesystems
+pub fn init() -> KResult<()> {
+    mount("/", Arc::new(KernelFs))?;
+    mount("/tmp", Arc::new(RamFs::new()))?;
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! Virtio Console
+//!
+//! A second console backend for hosts without a usable PL011, or to give
+//! the kernel console its own window next to a serial port
+//! (`-device virtio-serial-device -device virtconsole,chardev=...`).
+//! The console module decides what goes here (`console.backend`); this
+//! module only owns the device.
+//!
+//! Output is sent a byte per request and waits for the device, which is
+//! slow but needs no buffering and works with interrupts masked. Only port
+//! 0 is driven; the multiport feature isn't negotiated.
+
+use virtio_drivers::device::console::VirtIOConsole;
+use virtio_drivers::transport::mmio::{MmioTransport, VirtIOHeader};
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+use crate::virtio_hal::{self, VIRTIO_MMIO_ADDRS, VirtioHal};
+
+struct ConsoleDevice(VirtIOConsole<VirtioHal, MmioTransport>);
+
+// SAFETY: the device is only reached through DEVICE's lock, with IRQs masked
+unsafe impl Send for ConsoleDevice {}
+
+static DEVICE: Spinlock<Option<ConsoleDevice>> = Spinlock::new(None);
+
+/// Run a closure with IRQs disabled (console output comes from IRQ handlers too)
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+/// Find and initialize the first virtio console
+pub fn init() -> KResult<()> {
+    if is_present() {
+        return Err(KError::with_context(ErrorKind::AlreadyInitialized, "virtio-console"));
+    }
+
+    for &addr in VIRTIO_MMIO_ADDRS.iter() {
+        if virtio_hal::mmio_device_id(addr) != virtio_hal::DEVICE_ID_CONSOLE {
+            continue;
+        }
+        let header = match core::ptr::NonNull::new(addr as *mut VirtIOHeader) {
+            Some(p) => p,
+            None => continue,
+        };
+        // SAFETY: addr is a virtio MMIO slot holding a console device
+        let transport = match unsafe { MmioTransport::new(header) } {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        let device = VirtIOConsole::<VirtioHal, MmioTransport>::new(transport)
+            .map_err(|_| KError::with_context(ErrorKind::DeviceInit, "virtio-console"))?;
+        with_irqs_disabled(|| *DEVICE.lock() = Some(ConsoleDevice(device)));
+        return Ok(());
+    }
+
+    Err(KError::with_context(ErrorKind::NoDevice, "virtio-console"))
+}
+
+pub fn is_present() -> bool {
+    with_irqs_disabled(|| DEVICE.lock().is_some())
+}
+
+/// Send bytes to the console, waiting for the device
+///
+/// Dropped if the device is busy, which only happens when this interrupts
+/// another write on the way to a panic.
+pub fn write(bytes: &[u8]) {
+    with_irqs_disabled(|| {
+        if let Some(mut slot) = DEVICE.try_lock()
+            && let Some(ConsoleDevice(device)) = slot.as_mut()
+        {
+            for &b in bytes {
+                if device.send(b).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// Next received byte, if any (never blocks)
+pub fn read_byte() -> Option<u8> {
+    with_irqs_disabled(|| {
+        let mut device = DEVICE.lock();
+        device.as_mut()?.0.recv(true).ok().flatten()
+    })
+}
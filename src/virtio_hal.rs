@@ -1,33 +1,153 @@
 // HAL implementation for virtio-drivers crate
 
+use crate::lockdep::Spinlock;
+use crate::pmm;
 use core::ptr::NonNull;
-use spinning_top::Spinlock;
-use virtio_drivers::Hal;
+use virtio_drivers::{BufferDirection, Hal};
 
 // Track which IRQs are registered for cleanup
 static REGISTERED_IRQS: Spinlock<alloc::vec::Vec<u32>> = Spinlock::new(alloc::vec::Vec::new());
 
+// ============================================================================
+// MMIO Slots
+// ============================================================================
+
+/// QEMU virt machine virtio MMIO addresses
+pub const VIRTIO_MMIO_ADDRS: [usize; 8] = [
+    0x0a000000, 0x0a000200, 0x0a000400, 0x0a000600, 0x0a000800, 0x0a000a00, 0x0a000c00, 0x0a000e00,
+];
+
+/// GIC interrupt of slot 0; each slot has the next one (SPI 16 onward)
+const MMIO_IRQ_BASE: u32 = 48;
+
+/// Virtio device IDs we drive
+pub const DEVICE_ID_NET: u32 = 1;
+pub const DEVICE_ID_CONSOLE: u32 = 3;
+
+/// Device ID of the transport at `addr` (0 = empty slot)
+///
+/// The read is probed: a machine without the virtio-mmio bank aborts
+/// rather than reading zero. The device ID register is at offset 0x008.
+pub fn mmio_device_id(addr: usize) -> u32 {
+    crate::probe::read32(addr + 0x008).unwrap_or(0)
+}
+
+/// Register the interrupt handler of the device in MMIO slot `slot`
+pub fn register_irq(slot: usize, handler: impl FnMut(u32) + Send + 'static) {
+    let irq = MMIO_IRQ_BASE + slot as u32;
+    crate::irq::register_handler(irq, handler);
+    REGISTERED_IRQS.lock().push(irq);
+}
+
+/// Release the interrupt `register_irq` took for `slot`
+pub fn unregister_irq(slot: usize) {
+    let irq = MMIO_IRQ_BASE + slot as u32;
+    crate::irq::unregister_handler(irq);
+    REGISTERED_IRQS.lock().retain(|&registered| registered != irq);
+}
+
+// ============================================================================
+// Cache Maintenance
+// ============================================================================
+
+/// Smallest data cache line size in bytes (CTR_EL0.DminLine)
+fn dcache_line_size() -> usize {
+    let ctr: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack));
+    }
+    4 << ((ctr >> 16) & 0xF)
+}
+
+/// Write back and invalidate lines covering [addr, addr + len): the device
+/// sees CPU writes, and no line is left to be evicted over what it writes
+pub fn dcache_clean_invalidate_range(addr: usize, len: usize) {
+    let line = dcache_line_size();
+    let mut va = addr & !(line - 1);
+    while va < addr + len {
+        unsafe {
+            core::arch::asm!("dc civac, {}", in(reg) va, options(nostack));
+        }
+        va += line;
+    }
+    unsafe {
+        core::arch::asm!("dsb sy", options(nostack));
+    }
+}
+
+/// Discard lines covering [addr, addr + len) without writing them back, so
+/// the CPU re-reads what the device wrote
+///
+/// Lines are dropped whole: the range should not share a cache line with
+/// data the CPU wrote since the last clean.
+pub fn dcache_invalidate_range(addr: usize, len: usize) {
+    let line = dcache_line_size();
+    let mut va = addr & !(line - 1);
+    while va < addr + len {
+        unsafe {
+            core::arch::asm!("dc ivac, {}", in(reg) va, options(nostack));
+        }
+        va += line;
+    }
+    unsafe {
+        core::arch::asm!("dsb sy", options(nostack));
+    }
+}
+
+// ============================================================================
+// DMA-Coherent Memory
+// ============================================================================
+
+/// Allocate zeroed, physically contiguous memory shared with a device
+///
+/// Memory comes from the DMA pool (`pmm::alloc_dma_pages`), never the byte
+/// heap. `mmu` maps the pool Normal Non-cacheable, so the CPU and the
+/// device see the same bytes without cache maintenance; heap buffers
+/// handed to a device go through `share`/`unshare` instead.
+/// Returns (physical address, virtual pointer).
+pub fn dma_alloc_coherent(len: usize, align: usize) -> Option<(usize, NonNull<u8>)> {
+    if len == 0 || !align.is_power_of_two() {
+        return None;
+    }
+
+    // Buddy blocks are aligned to their own size
+    let order = pmm::order_for_size(len.max(align));
+    let addr = pmm::alloc_dma_pages(order)?;
+    if addr % align != 0 {
+        unsafe { pmm::free_dma_pages(addr, order) };
+        return None;
+    }
+
+    let size = pmm::PAGE_SIZE << order;
+    unsafe {
+        core::ptr::write_bytes(addr as *mut u8, 0, size);
+    }
+
+    // On QEMU ARM64 virt machine, physical == virtual for RAM
+    Some((addr, NonNull::new(addr as *mut u8)?))
+}
+
+/// Release memory from `dma_alloc_coherent`
+///
+/// # Safety
+/// `vaddr` and `len` must match the allocation (any `align` is fine), and the
+/// device must no longer access it.
+pub unsafe fn dma_free_coherent(vaddr: NonNull<u8>, len: usize, align: usize) {
+    let order = pmm::order_for_size(len.max(align));
+    unsafe { pmm::free_dma_pages(vaddr.as_ptr() as usize, order) };
+}
+
 pub struct VirtioHal;
 
 unsafe impl Hal for VirtioHal {
     fn dma_alloc(
         pages: usize,
-        _direction: virtio_drivers::BufferDirection,
+        _direction: BufferDirection,
     ) -> (virtio_drivers::PhysAddr, NonNull<u8>) {
-        use alloc::alloc::{Layout, alloc_zeroed};
-
-        let layout = Layout::from_size_align(pages * 4096, 4096).unwrap();
-        let virt = unsafe { alloc_zeroed(layout) };
-
-        if virt.is_null() {
-            panic!("DMA allocation failed");
+        match dma_alloc_coherent(pages * pmm::PAGE_SIZE, pmm::PAGE_SIZE) {
+            Some(region) => region,
+            None => panic!("DMA allocation failed"),
         }
-
-        // On QEMU ARM64 virt machine, physical == virtual for RAM
-        let phys = virt as usize;
-        let ptr = unsafe { NonNull::new_unchecked(virt) };
-
-        (phys, ptr)
     }
 
     unsafe fn dma_dealloc(
@@ -35,12 +155,7 @@ unsafe impl Hal for VirtioHal {
         vaddr: NonNull<u8>,
         pages: usize,
     ) -> i32 {
-        use alloc::alloc::{Layout, dealloc};
-
-        let layout = Layout::from_size_align(pages * 4096, 4096).unwrap();
-        unsafe {
-            dealloc(vaddr.as_ptr(), layout);
-        }
+        unsafe { dma_free_coherent(vaddr, pages * pmm::PAGE_SIZE, pmm::PAGE_SIZE) };
         0
     }
 
@@ -51,17 +166,26 @@ unsafe impl Hal for VirtioHal {
 
     unsafe fn share(
         buffer: NonNull<[u8]>,
-        _direction: virtio_drivers::BufferDirection,
+        _direction: BufferDirection,
     ) -> virtio_drivers::PhysAddr {
         // Physical == virtual
-        buffer.as_ptr() as *mut u8 as usize
+        let addr = buffer.as_ptr() as *mut u8 as usize;
+
+        // Heap buffers aren't coherent: push CPU writes out, and drop the
+        // lines too so none is evicted over data the device writes
+        dcache_clean_invalidate_range(addr, buffer.len());
+        addr
     }
 
     unsafe fn unshare(
         _paddr: virtio_drivers::PhysAddr,
-        _buffer: NonNull<[u8]>,
-        _direction: virtio_drivers::BufferDirection,
+        buffer: NonNull<[u8]>,
+        direction: BufferDirection,
     ) {
-        // No-op for identity mapping
+        // Drop lines speculatively refilled during the transfer so the CPU
+        // sees what the device wrote; `share` already wrote back our data
+        if direction != BufferDirection::DriverToDevice {
+            dcache_invalidate_range(buffer.as_ptr() as *mut u8 as usize, buffer.len());
+        }
     }
 }
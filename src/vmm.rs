@@ -0,0 +1,397 @@
+//! Address-Space Region Manager
+//!
+//! One registry of every range the kernel uses: the kernel image, boot
+//! stack and crash region, MMIO windows, and memory handed out at runtime.
+//! Subsystems ask for anonymous memory, device mappings or stacks here
+//! instead of picking addresses themselves, and overlaps are refused.
+//!
+//! The address space is identity mapped (see `mmu`): RAM outside the
+//! kernel image is mapped read-write, all of the low GB is device memory.
+//! Region permissions are recorded for `regions` listings and lookups;
+//! hardware enforcement is still per kernel section only.
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+use crate::mmu::{Perm, BOOT_STACK_TOP};
+use crate::pmm;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Device (MMIO) window on QEMU virt: everything below RAM
+const DEVICE_WINDOW: core::ops::Range<usize> = 0..0x4000_0000;
+
+/// Fixed QEMU virt devices the kernel drives
+const PLATFORM_DEVICES: [(usize, usize, &str); 6] = [
+    (0x0800_0000, 0x1_0000, "gicd"),
+    (0x0801_0000, 0x1_0000, "gicc"),
+    (0x0900_0000, 0x1000, "uart0"),
+    (0x0901_0000, 0x1000, "rtc"),
+    (0x0904_0000, 0x1000, "uart1"),
+    (0x0a00_0000, 0x1000, "virtio-mmio"),
+];
+
+/// Run a closure with IRQs disabled (regions can be queried from IRQ context)
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// Identifies a region for `commit`/`unmap`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionId(u32);
+
+/// What a region is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Kernel image sections
+    Kernel,
+    /// Thread or boot stack
+    Stack,
+    /// Inaccessible page below a stack
+    Guard,
+    /// Anonymous memory from the page allocator
+    Anonymous,
+    /// Memory-mapped device registers
+    Device,
+    /// Fixed-purpose RAM (crash region)
+    Fixed,
+}
+
+#[cfg(any(feature = "shell", feature = "fs"))]
+impl RegionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RegionKind::Kernel => "kernel",
+            RegionKind::Stack => "stack",
+            RegionKind::Guard => "guard",
+            RegionKind::Anonymous => "anon",
+            RegionKind::Device => "device",
+            RegionKind::Fixed => "fixed",
+        }
+    }
+}
+
+/// Reserved ranges are claimed but not usable yet; committed ones are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionState {
+    Reserved,
+    Committed,
+}
+
+#[cfg(feature = "shell")]
+impl RegionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RegionState::Reserved => "reserved",
+            RegionState::Committed => "committed",
+        }
+    }
+}
+
+/// One tracked range
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub id: RegionId,
+    pub base: usize,
+    pub size: usize,
+    pub kind: RegionKind,
+    pub state: RegionState,
+    /// None = no access (guard pages)
+    pub perm: Option<Perm>,
+    pub name: &'static str,
+    /// Page allocator order backing this region (anonymous and stacks)
+    order: Option<usize>,
+}
+
+impl Region {
+    pub fn end(&self) -> usize {
+        self.base + self.size
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.base && addr < self.end()
+    }
+
+    fn overlaps(&self, base: usize, size: usize) -> bool {
+        base < self.end() && self.base < base + size
+    }
+}
+
+static REGIONS: Spinlock<Vec<Region>> = Spinlock::new(Vec::new());
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+// ============================================================================
+// Boot Layout
+// ============================================================================
+
+/// Register the kernel image, boot stack, crash region and platform devices
+///
+/// `heap_start` is where the page allocator's RAM begins; every fixed
+/// range must end below it. Needs the heap.
+pub fn init(heap_start: usize) -> KResult<()> {
+    let s = crate::mmu::sections();
+    let committed = |base: usize, end: usize, kind, perm, name| {
+        if end > heap_start {
+            return Err(KError::with_context(ErrorKind::InvalidRegion, name));
+        }
+        insert(base, end - base, kind, RegionState::Committed, Some(perm), name, None)
+    };
+
+    committed(s.text.0, s.text.1, RegionKind::Kernel, Perm::ReadExecute, ".text")?;
+    committed(s.rodata.0, s.rodata.1, RegionKind::Kernel, Perm::ReadOnly, ".rodata")?;
+    committed(s.rodata.1, s.kernel_end, RegionKind::Kernel, Perm::ReadWrite, ".data/.bss")?;
+    if s.kernel_end >= BOOT_STACK_TOP {
+        return Err(KError::with_context(ErrorKind::InvalidRegion, "boot stack"));
+    }
+    committed(s.kernel_end, BOOT_STACK_TOP, RegionKind::Stack, Perm::ReadWrite, "boot stack")?;
+    committed(
+        crate::crashdump::CRASH_REGION_BASE,
+        crate::crashdump::CRASH_REGION_BASE + crate::crashdump::CRASH_REGION_SIZE,
+        RegionKind::Fixed,
+        Perm::ReadWrite,
+        "crash region",
+    )?;
+
+    for (base, size, name) in PLATFORM_DEVICES {
+        map_device(base, size, name)?;
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Registration
+// ============================================================================
+
+fn insert(
+    base: usize,
+    size: usize,
+    kind: RegionKind,
+    state: RegionState,
+    perm: Option<Perm>,
+    name: &'static str,
+    order: Option<usize>,
+) -> KResult<RegionId> {
+    if size == 0 || base.checked_add(size).is_none() {
+        return Err(KError::with_context(ErrorKind::InvalidArgument, name));
+    }
+
+    let id = RegionId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let region = Region {
+        id,
+        base,
+        size,
+        kind,
+        state,
+        perm,
+        name,
+        order,
+    };
+
+    with_irqs_disabled(|| {
+        let mut regions = REGIONS.lock();
+        if regions.iter().any(|r| r.overlaps(base, size)) {
+            return Err(KError::with_context(ErrorKind::InvalidRegion, name));
+        }
+        regions
+            .try_reserve(1)
+            .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "region table"))?;
+        // Keep sorted by base for listings
+        let pos = regions.partition_point(|r| r.base < base);
+        regions.insert(pos, region);
+        Ok(id)
+    })
+}
+
+/// Claim a fixed range (kernel image, crash region, ...)
+///
+/// Fails with `InvalidRegion` if it overlaps anything already registered.
+pub fn reserve(
+    base: usize,
+    size: usize,
+    kind: RegionKind,
+    perm: Option<Perm>,
+    name: &'static str,
+) -> KResult<RegionId> {
+    insert(base, size, kind, RegionState::Reserved, perm, name, None)
+}
+
+/// Mark a reserved region ready for use
+pub fn commit(id: RegionId) -> KResult<()> {
+    with_irqs_disabled(|| {
+        let mut regions = REGIONS.lock();
+        let region = regions
+            .iter_mut()
+            .find(|r| r.id == id)
+            .ok_or(KError::with_context(ErrorKind::NotFound, "region"))?;
+        region.state = RegionState::Committed;
+        Ok(())
+    })
+}
+
+/// Register an MMIO window (must lie in the device window)
+pub fn map_device(base: usize, size: usize, name: &'static str) -> KResult<RegionId> {
+    if !DEVICE_WINDOW.contains(&base) || size > DEVICE_WINDOW.end - base {
+        return Err(KError::with_context(ErrorKind::InvalidRegion, name));
+    }
+    insert(
+        base,
+        size,
+        RegionKind::Device,
+        RegionState::Committed,
+        Some(Perm::ReadWrite),
+        name,
+        None,
+    )
+}
+
+/// Allocate zeroed, page-aligned anonymous memory
+///
+/// The size is rounded up to a power-of-two number of pages.
+pub fn map_anonymous(size: usize, perm: Perm, name: &'static str) -> KResult<Region> {
+    let order = pmm::order_for_size(size);
+    let base = pmm::alloc_pages(order)
+        .ok_or(KError::with_context(ErrorKind::OutOfMemory, name))?;
+    let size = pmm::PAGE_SIZE << order;
+
+    // Claim the range first; it is only usable once zeroed
+    let id = match insert(
+        base,
+        size,
+        RegionKind::Anonymous,
+        RegionState::Reserved,
+        Some(perm),
+        name,
+        Some(order),
+    ) {
+        Ok(id) => id,
+        Err(e) => {
+            // SAFETY: allocated above and never handed out
+            unsafe { pmm::free_pages(base, order) };
+            return Err(e);
+        }
+    };
+
+    // SAFETY: freshly allocated block of `size` bytes
+    unsafe {
+        core::ptr::write_bytes(base as *mut u8, 0, size);
+    }
+
+    commit(id)?;
+    Ok(find_by_id(id).expect("region just inserted"))
+}
+
+/// Allocate a stack with a guard page below it
+///
+/// Returns the stack region; its `end()` is the initial stack pointer.
+pub fn alloc_stack(size: usize, name: &'static str) -> KResult<Region> {
+    let order = pmm::order_for_size(size + pmm::PAGE_SIZE);
+    let base = pmm::alloc_pages(order)
+        .ok_or(KError::with_context(ErrorKind::OutOfMemory, name))?;
+    let total = pmm::PAGE_SIZE << order;
+
+    // The stack carries the backing allocation's order; `unmap` of the stack
+    // frees the whole block, guard page included, and drops the guard region
+    let stack = insert(
+        base + pmm::PAGE_SIZE,
+        total - pmm::PAGE_SIZE,
+        RegionKind::Stack,
+        RegionState::Committed,
+        Some(Perm::ReadWrite),
+        name,
+        Some(order),
+    );
+    let stack = match stack {
+        Ok(id) => id,
+        Err(e) => {
+            // SAFETY: allocated above and never handed out
+            unsafe { pmm::free_pages(base, order) };
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = insert(
+        base,
+        pmm::PAGE_SIZE,
+        RegionKind::Guard,
+        RegionState::Reserved,
+        None,
+        name,
+        None,
+    ) {
+        let _ = unmap(stack);
+        return Err(e);
+    }
+
+    Ok(find_by_id(stack).expect("region just inserted"))
+}
+
+/// Remove a region, returning anonymous memory and stacks to the page allocator
+pub fn unmap(id: RegionId) -> KResult<()> {
+    let region = with_irqs_disabled(|| {
+        let mut regions = REGIONS.lock();
+        let pos = regions
+            .iter()
+            .position(|r| r.id == id)
+            .ok_or(KError::with_context(ErrorKind::NotFound, "region"))?;
+        let region = regions.remove(pos);
+
+        // A stack's guard page goes with it
+        if region.kind == RegionKind::Stack
+            && let Some(guard) = regions
+                .iter()
+                .position(|r| r.kind == RegionKind::Guard && r.end() == region.base)
+        {
+            regions.remove(guard);
+        }
+        Ok::<_, KError>(region)
+    })?;
+
+    if let Some(order) = region.order {
+        let block = match region.kind {
+            RegionKind::Stack => region.base - pmm::PAGE_SIZE,
+            _ => region.base,
+        };
+        // SAFETY: the block was allocated for this region, which is gone now
+        unsafe { pmm::free_pages(block, order) };
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Lookup
+// ============================================================================
+
+fn find_by_id(id: RegionId) -> Option<Region> {
+    with_irqs_disabled(|| REGIONS.lock().iter().find(|r| r.id == id).copied())
+}
+
+/// Region containing `addr`
+pub fn find(addr: usize) -> Option<Region> {
+    with_irqs_disabled(|| REGIONS.lock().iter().find(|r| r.contains(addr)).copied())
+}
+
+/// Snapshot of all regions, sorted by base address
+#[cfg(any(feature = "shell", feature = "fs"))]
+pub fn list() -> Vec<Region> {
+    let count = with_irqs_disabled(|| REGIONS.lock().len());
+    let mut out = Vec::with_capacity(count);
+    with_irqs_disabled(|| {
+        for region in REGIONS.lock().iter().take(out.capacity()) {
+            out.push(*region);
+        }
+    });
+    out
+}
@@ -0,0 +1,234 @@
+//! Worker Pool
+//!
+//! Every network connection is polled from the one network thread, so a
+//! CPU-heavy step in one of them - an SSH key exchange, encrypting a bulk
+//! transfer - stalls all the others while it runs. `offload(f).await`
+//! hands the closure to a small pool of worker threads instead and
+//! resolves with its result, leaving the network loop free to poll in the
+//! meantime. Workers run at `PRIORITY_BULK`, so they use the time the
+//! network loop doesn't; once SMP lands they spread across cores.
+//!
+//! The queue is bounded: while `MAX_QUEUED` jobs wait, `offload` waits for
+//! space. Until `start` (e.g. during the boot tests) closures run inline.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+use crate::sched_policy::PRIORITY_BULK;
+use crate::threading::{self, Condvar, Mutex};
+
+/// Worker threads
+const WORKERS: usize = 2;
+
+/// Jobs waiting for a worker before `offload` holds new ones back
+#[cfg(feature = "ssh")]
+const MAX_QUEUED: usize = 16;
+
+/// Run a closure with IRQs disabled
+#[inline]
+#[cfg(feature = "ssh")]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Queue
+// ============================================================================
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    /// Submitters waiting for the queue to have room
+    space_wakers: Vec<Waker>,
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue {
+    jobs: VecDeque::new(),
+    space_wakers: Vec::new(),
+});
+
+/// Signalled when a job is queued
+static WORK: Condvar = Condvar::new();
+
+/// Workers started (0 = run inline)
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+#[cfg(any(all(feature = "fs", feature = "shell"), feature = "ssh"))]
+static INLINE: AtomicU64 = AtomicU64::new(0);
+#[cfg(any(all(feature = "fs", feature = "shell"), feature = "ssh"))]
+static QUEUE_FULL: AtomicU64 = AtomicU64::new(0);
+
+fn worker() {
+    loop {
+        let (job, space_wakers) = {
+            // Workers never run under a deadline, so neither wait fails
+            let Ok(mut queue) =
+                QUEUE.lock().and_then(|queue| WORK.wait_while(queue, |queue| queue.jobs.is_empty()))
+            else {
+                continue;
+            };
+            let job = queue.jobs.pop_front();
+            (job, core::mem::take(&mut queue.space_wakers))
+        };
+        for waker in space_wakers {
+            waker.wake();
+        }
+        if let Some(job) = job {
+            job();
+            COMPLETED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Start the worker threads; until then `offload` runs closures inline
+pub fn start() -> KResult<()> {
+    if STARTED.load(Ordering::Acquire) != 0 {
+        return Err(KError::with_context(ErrorKind::AlreadyInitialized, "workers"));
+    }
+    for _ in 0..WORKERS {
+        let tid = threading::spawn_named("worker", worker)?;
+        threading::set_priority(tid, PRIORITY_BULK)?;
+        STARTED.fetch_add(1, Ordering::AcqRel);
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Offloading
+// ============================================================================
+
+#[cfg(feature = "ssh")]
+struct Shared<T> {
+    result: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// Future of a closure running on a worker (see `offload`)
+#[cfg(feature = "ssh")]
+pub struct Offload<T> {
+    /// Until queued
+    job: Option<Job>,
+    shared: Arc<Spinlock<Shared<T>>>,
+}
+
+/// Run `f` on a worker thread and resolve with what it returns
+///
+/// Dropping the future before it resolves doesn't stop the closure; its
+/// result is discarded.
+#[cfg(feature = "ssh")]
+pub fn offload<F, T>(f: F) -> Offload<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let shared = Arc::new(Spinlock::new(Shared {
+        result: None,
+        waker: None,
+    }));
+    let slot = shared.clone();
+    let job: Job = Box::new(move || {
+        let value = f();
+        let waker = with_irqs_disabled(|| {
+            let mut slot = slot.lock();
+            slot.result = Some(value);
+            slot.waker.take()
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    });
+    Offload {
+        job: Some(job),
+        shared,
+    }
+}
+
+#[cfg(feature = "ssh")]
+impl<T> Future for Offload<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        if let Some(job) = self.job.take() {
+            if STARTED.load(Ordering::Acquire) == 0 {
+                INLINE.fetch_add(1, Ordering::Relaxed);
+                job();
+            } else {
+                let Ok(mut queue) = QUEUE.lock() else {
+                    // Polled under an expired deadline; try again later
+                    self.job = Some(job);
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                };
+                if queue.jobs.len() >= MAX_QUEUED {
+                    QUEUE_FULL.fetch_add(1, Ordering::Relaxed);
+                    queue.space_wakers.push(cx.waker().clone());
+                    drop(queue);
+                    self.job = Some(job);
+                    return Poll::Pending;
+                }
+                queue.jobs.push_back(job);
+                drop(queue);
+                WORK.notify_one();
+            }
+        }
+
+        with_irqs_disabled(|| {
+            let mut shared = self.shared.lock();
+            match shared.result.take() {
+                Some(value) => Poll::Ready(value),
+                None => {
+                    shared.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+// ============================================================================
+// Statistics
+// ============================================================================
+
+#[cfg(all(feature = "fs", feature = "shell"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkerStats {
+    pub workers: usize,
+    /// Jobs waiting for a worker now
+    pub queued: usize,
+    /// Jobs workers have finished
+    pub completed: u64,
+    /// Closures run inline because no worker was started
+    pub inline: u64,
+    /// Times `offload` found the queue full and waited
+    pub queue_full: u64,
+}
+
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn stats() -> WorkerStats {
+    WorkerStats {
+        workers: STARTED.load(Ordering::Acquire),
+        queued: QUEUE.lock().map_or(0, |queue| queue.jobs.len()),
+        completed: COMPLETED.load(Ordering::Relaxed),
+        inline: INLINE.load(Ordering::Relaxed),
+        queue_full: QUEUE_FULL.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,316 @@
+//! Work Queues
+//!
+//! Subsystems that need something done later in thread context - a timer
+//! callback that has to allocate, a session torn down after its
+//! connection closed, IRQ work too slow to run on the network loop -
+//! `queue` a closure here instead of spawning a thread of their own for
+//! it. A fixed pool of `kworker` threads runs queued work in the order it
+//! was submitted; `queue_delayed` holds a closure back until its delay has
+//! passed, and `cancel` withdraws delayed work that hasn't come due.
+//!
+//! Both may be called from IRQ handlers. Unlike `softirq::defer` the work
+//! may block as long as it likes, since it doesn't run on the network
+//! loop; unlike `workers::offload` nothing waits for a result. Delays can
+//! run up to one scheduler tick late, as with `threading::sleep_us`.
+//! Work queued before `start` waits for the workers.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::error::{ErrorKind, KError, KResult};
+use crate::lockdep::Spinlock;
+use crate::threading;
+
+/// Worker threads
+const WORKERS: usize = 2;
+
+/// Closures waiting for a worker; `queue` fails beyond this
+const MAX_PENDING: usize = 64;
+
+/// Delayed closures held at once; `queue_delayed` fails beyond this
+const MAX_DELAYED: usize = 32;
+
+/// Longest the delay thread sleeps between looks at the delayed list, so
+/// work queued with a short delay isn't held up by an earlier long one
+const DELAY_POLL_US: u64 = 10_000;
+
+/// Run a closure with IRQs disabled (the queues are filled from IRQ context)
+#[inline]
+fn with_irqs_disabled<T, F: FnOnce() -> T>(f: F) -> T {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Disable IRQs
+    }
+    let result = f();
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
+
+// ============================================================================
+// Queues
+// ============================================================================
+
+type Work = Box<dyn FnOnce() + Send>;
+
+/// Names a closure queued with `queue_delayed`, for `cancel`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DelayedWork(u64);
+
+struct Delayed {
+    id: u64,
+    due_us: u64,
+    work: Work,
+}
+
+struct Queues {
+    pending: VecDeque<Work>,
+    delayed: Vec<Delayed>,
+}
+
+static QUEUES: Spinlock<Queues> = Spinlock::new(Queues {
+    pending: VecDeque::new(),
+    delayed: Vec::new(),
+});
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Thread ID of each worker
+static WORKER_TIDS: [AtomicUsize; WORKERS] = [const { AtomicUsize::new(0) }; WORKERS];
+
+/// Bit per worker that is parked, or about to park, for lack of work
+static IDLE: AtomicU32 = AtomicU32::new(0);
+
+/// Thread ID of the delay thread (0 = not started)
+static DELAY_TID: AtomicUsize = AtomicUsize::new(0);
+
+static QUEUED: AtomicU64 = AtomicU64::new(0);
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+static CANCELLED: AtomicU64 = AtomicU64::new(0);
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// Unpark one idle worker, if any; a busy one picks the work up when done
+fn wake_worker() {
+    let idle = IDLE.load(Ordering::SeqCst);
+    if idle == 0 {
+        return;
+    }
+    let bit = idle & idle.wrapping_neg();
+    if IDLE.fetch_and(!bit, Ordering::SeqCst) & bit != 0 {
+        let tid = WORKER_TIDS[bit.trailing_zeros() as usize].load(Ordering::Acquire);
+        let _ = threading::unpark(tid);
+    }
+}
+
+/// Run `work` on a worker thread as soon as one is free
+pub fn queue(work: impl FnOnce() + Send + 'static) -> KResult<()> {
+    let work: Work = Box::new(work);
+    let queued = with_irqs_disabled(|| {
+        let mut queues = QUEUES.lock();
+        if queues.pending.len() >= MAX_PENDING {
+            return Err(KError::with_context(ErrorKind::LimitReached, "workqueue"));
+        }
+        queues
+            .pending
+            .try_reserve(1)
+            .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "workqueue"))?;
+        queues.pending.push_back(work);
+        Ok(())
+    });
+    match queued {
+        Ok(()) => {
+            QUEUED.fetch_add(1, Ordering::Relaxed);
+            wake_worker();
+        }
+        Err(_) => {
+            REJECTED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    queued
+}
+
+/// Run `work` on a worker thread once `delay_us` microseconds have passed
+pub fn queue_delayed(work: impl FnOnce() + Send + 'static, delay_us: u64) -> KResult<DelayedWork> {
+    let work: Work = Box::new(work);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let due_us = crate::timer::uptime_us().saturating_add(delay_us);
+    let queued = with_irqs_disabled(|| {
+        let mut queues = QUEUES.lock();
+        if queues.delayed.len() >= MAX_DELAYED {
+            return Err(KError::with_context(ErrorKind::LimitReached, "workqueue delayed"));
+        }
+        queues
+            .delayed
+            .try_reserve(1)
+            .map_err(|_| KError::with_context(ErrorKind::OutOfMemory, "workqueue delayed"))?;
+        queues.delayed.push(Delayed { id, due_us, work });
+        Ok(())
+    });
+    if let Err(e) = queued {
+        REJECTED.fetch_add(1, Ordering::Relaxed);
+        return Err(e);
+    }
+    QUEUED.fetch_add(1, Ordering::Relaxed);
+    let tid = DELAY_TID.load(Ordering::Acquire);
+    if tid != 0 {
+        let _ = threading::unpark(tid);
+    }
+    Ok(DelayedWork(id))
+}
+
+/// Withdraw delayed work that hasn't come due yet
+///
+/// Returns false if it has already been handed to a worker (or cancelled);
+/// it then runs, or has run, as usual.
+pub fn cancel(work: DelayedWork) -> bool {
+    let removed = with_irqs_disabled(|| {
+        let mut queues = QUEUES.lock();
+        let pos = queues.delayed.iter().position(|d| d.id == work.0)?;
+        Some(queues.delayed.remove(pos).work)
+    });
+    // Drop the closure outside the lock: it may own anything
+    match removed {
+        Some(_) => {
+            CANCELLED.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Move delayed work due by `now` to the pending queue
+///
+/// Returns how many moved and the earliest due time still waiting.
+fn promote_due(now: u64) -> (usize, Option<u64>) {
+    with_irqs_disabled(|| {
+        let mut queues = QUEUES.lock();
+        let Queues { pending, delayed } = &mut *queues;
+        let mut moved = 0;
+        let mut next = None;
+        let mut i = 0;
+        while i < delayed.len() {
+            // Leave it delayed if the pending queue can't take it yet
+            let due_us = delayed[i].due_us;
+            if due_us <= now && pending.try_reserve(1).is_ok() {
+                pending.push_back(delayed.remove(i).work);
+                moved += 1;
+            } else {
+                next = Some(next.map_or(due_us, |n: u64| n.min(due_us)));
+                i += 1;
+            }
+        }
+        (moved, next)
+    })
+}
+
+// ============================================================================
+// Running
+// ============================================================================
+
+/// Run everything pending, including delayed work now due, on the caller
+///
+/// Returns how many closures ran. The workers do this whenever woken;
+/// call it directly to flush the queue, e.g. before `start`.
+pub fn run_pending() -> usize {
+    promote_due(crate::timer::uptime_us());
+    let mut ran = 0;
+    while let Some(work) = with_irqs_disabled(|| QUEUES.lock().pending.pop_front()) {
+        work();
+        COMPLETED.fetch_add(1, Ordering::Relaxed);
+        ran += 1;
+    }
+    ran
+}
+
+fn has_pending() -> bool {
+    with_irqs_disabled(|| !QUEUES.lock().pending.is_empty())
+}
+
+fn worker(index: usize) {
+    let bit = 1 << index;
+    loop {
+        if run_pending() > 0 {
+            continue;
+        }
+        // Advertise as idle before the last look, so work queued after it
+        // finds the bit set and unparks us
+        IDLE.fetch_or(bit, Ordering::SeqCst);
+        if !has_pending() {
+            threading::park();
+        }
+        IDLE.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+/// Hands delayed work to the workers as it comes due
+fn delay_thread() {
+    loop {
+        let now = crate::timer::uptime_us();
+        let (moved, next) = promote_due(now);
+        for _ in 0..moved.min(WORKERS) {
+            wake_worker();
+        }
+        match next {
+            // `queue_delayed` unparks us
+            None => threading::park(),
+            Some(due) => threading::sleep_us(due.saturating_sub(now).min(DELAY_POLL_US)),
+        }
+    }
+}
+
+/// Start the worker threads and the delay thread
+pub fn start() -> KResult<()> {
+    if STARTED.swap(true, Ordering::AcqRel) {
+        return Err(KError::with_context(ErrorKind::AlreadyInitialized, "workqueue"));
+    }
+    for (index, slot) in WORKER_TIDS.iter().enumerate() {
+        let tid = threading::spawn_named("kworker", move || worker(index))?;
+        slot.store(tid, Ordering::Release);
+    }
+    let tid = threading::spawn_named("kworker-delay", delay_thread)?;
+    DELAY_TID.store(tid, Ordering::Release);
+    Ok(())
+}
+
+// ============================================================================
+// Statistics
+// ============================================================================
+
+#[cfg(all(feature = "fs", feature = "shell"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkQueueStats {
+    pub workers: usize,
+    /// Closures waiting for a worker now
+    pub pending: usize,
+    /// Delayed closures not due yet
+    pub delayed: usize,
+    /// `queue` and `queue_delayed` calls accepted
+    pub queued: u64,
+    pub completed: u64,
+    pub cancelled: u64,
+    /// Calls refused because a queue was full
+    pub rejected: u64,
+}
+
+#[cfg(all(feature = "fs", feature = "shell"))]
+pub fn stats() -> WorkQueueStats {
+    let (pending, delayed) = with_irqs_disabled(|| {
+        let queues = QUEUES.lock();
+        (queues.pending.len(), queues.delayed.len())
+    });
+    WorkQueueStats {
+        workers: WORKER_TIDS.iter().filter(|t| t.load(Ordering::Acquire) != 0).count(),
+        pending,
+        delayed,
+        queued: QUEUED.load(Ordering::Relaxed),
+        completed: COMPLETED.load(Ordering::Relaxed),
+        cancelled: CANCELLED.load(Ordering::Relaxed),
+        rejected: REJECTED.load(Ordering::Relaxed),
+    }
+}
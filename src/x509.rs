@@ -0,0 +1,576 @@
+//! X.509 Certificates
+//!
+//! Just enough of RFC 5280 for the TLS client to decide whether to trust
+//! a server: DER parsing of the fields path validation needs, signature
+//! checks and subjectAltName matching. A chain is trusted when it reaches
+//! either a configured root certificate or a pinned public key (SHA-256
+//! of the certificate's SubjectPublicKeyInfo, as `openssl pkey -pubin
+//! -outform der | sha256sum` prints it).
+//!
+//! Names are compared as encoded (issuer and subject DER must match byte
+//! for byte), certificates with unknown critical extensions are refused
+//! rather than half-understood, and there is no revocation checking.
+//!
+//! Keys and signatures: Ed25519, ECDSA on P-256 and P-384, RSA (2048 to
+//! 4096 bits) with PKCS#1 v1.5 or PSS padding and SHA-256/384/512.
+
+use alloc::vec::Vec;
+use p256::ecdsa::signature::hazmat::PrehashVerifier;
+use rsa::pkcs1::DecodeRsaPublicKey;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use crate::error::{ErrorKind, KError, KResult};
+
+/// Longest chain followed from the leaf, intermediates included
+const MAX_CHAIN_DEPTH: usize = 6;
+
+/// Smallest RSA modulus accepted, in bytes (2048 bits)
+const MIN_RSA_BYTES: usize = 256;
+
+// ============================================================================
+// DER
+// ============================================================================
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+
+/// Context-specific tags: `[n] EXPLICIT` and `[n] IMPLICIT` primitives
+const fn explicit(n: u8) -> u8 {
+    0xa0 | n
+}
+const fn implicit(n: u8) -> u8 {
+    0x80 | n
+}
+
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+const OID_SHA384_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const OID_SHA512_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_P384: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_ECDSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+const OID_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x0f];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+
+/// Reader over a run of DER TLVs
+#[derive(Clone, Copy)]
+struct Der<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    /// Next TLV as (tag, contents, whole encoding)
+    ///
+    /// Only definite, minimal lengths are DER; anything else is refused.
+    fn any(&mut self) -> Option<(u8, &'a [u8], &'a [u8])> {
+        let tag = *self.data.first()?;
+        let first = *self.data.get(1)?;
+        let (len, header) = if first < 0x80 {
+            (first as usize, 2)
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > 3 {
+                return None;
+            }
+            let bytes = self.data.get(2..2 + n)?;
+            let len = bytes.iter().fold(0usize, |acc, &b| acc << 8 | b as usize);
+            if bytes[0] == 0 || len < 0x80 {
+                return None;
+            }
+            (len, 2 + n)
+        };
+        let raw = self.data.get(..header + len)?;
+        self.data = &self.data[raw.len()..];
+        Some((tag, &raw[header..], raw))
+    }
+
+    /// Contents of the next TLV, which must have `tag`
+    fn read(&mut self, tag: u8) -> Option<&'a [u8]> {
+        let (t, contents, _) = self.any()?;
+        (t == tag).then_some(contents)
+    }
+
+    /// Whole encoding of the next TLV, which must have `tag`
+    fn read_raw(&mut self, tag: u8) -> Option<&'a [u8]> {
+        let (t, _, raw) = self.any()?;
+        (t == tag).then_some(raw)
+    }
+
+    /// Contents of the next TLV if it has `tag`
+    fn optional(&mut self, tag: u8) -> Option<&'a [u8]> {
+        if self.peek_tag() == Some(tag) {
+            self.read(tag)
+        } else {
+            None
+        }
+    }
+}
+
+// ============================================================================
+// Keys and Signatures
+// ============================================================================
+
+/// Subject public key of a certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublicKey<'a> {
+    Ed25519(&'a [u8]),
+    /// SEC1 encoded point
+    EcdsaP256(&'a [u8]),
+    EcdsaP384(&'a [u8]),
+    /// PKCS#1 RSAPublicKey
+    Rsa(&'a [u8]),
+}
+
+/// Signature algorithms, in certificates and TLS CertificateVerify
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureScheme {
+    RsaPkcs1Sha256,
+    RsaPkcs1Sha384,
+    RsaPkcs1Sha512,
+    RsaPssSha256,
+    RsaPssSha384,
+    RsaPssSha512,
+    EcdsaSha256,
+    EcdsaSha384,
+    Ed25519,
+}
+
+/// Digest of `message` for schemes that sign a hash
+fn digest(scheme: SignatureScheme, message: &[u8]) -> Vec<u8> {
+    use SignatureScheme::*;
+    match scheme {
+        RsaPkcs1Sha256 | RsaPssSha256 | EcdsaSha256 => Sha256::digest(message).to_vec(),
+        RsaPkcs1Sha384 | RsaPssSha384 | EcdsaSha384 => Sha384::digest(message).to_vec(),
+        RsaPkcs1Sha512 | RsaPssSha512 => Sha512::digest(message).to_vec(),
+        Ed25519 => Vec::new(),
+    }
+}
+
+/// Fixed-size `r || s` from a DER ECDSA-Sig-Value
+fn ecdsa_fixed(der: &[u8], size: usize) -> Option<Vec<u8>> {
+    let mut outer = Der::new(der);
+    let mut seq = Der::new(outer.read(TAG_SEQUENCE)?);
+    let mut out = Vec::with_capacity(2 * size);
+    for _ in 0..2 {
+        let mut int = seq.read(TAG_INTEGER)?;
+        while int.len() > size && int[0] == 0 {
+            int = &int[1..];
+        }
+        if int.is_empty() || int.len() > size {
+            return None;
+        }
+        out.resize(out.len() + size - int.len(), 0);
+        out.extend_from_slice(int);
+    }
+    (outer.is_empty() && seq.is_empty()).then_some(out)
+}
+
+/// Check `signature` over `message` with `key`
+///
+/// ECDSA signatures are DER encoded, as in certificates and TLS.
+pub fn verify_signature(
+    key: &PublicKey,
+    scheme: SignatureScheme,
+    message: &[u8],
+    signature: &[u8],
+) -> bool {
+    use SignatureScheme::*;
+    match (*key, scheme) {
+        (PublicKey::Ed25519(bytes), Ed25519) => {
+            let (Ok(bytes), Ok(sig)) = (<[u8; 32]>::try_from(bytes), <[u8; 64]>::try_from(signature))
+            else {
+                return false;
+            };
+            ed25519_dalek::VerifyingKey::from_bytes(&bytes).is_ok_and(|key| {
+                key.verify_strict(message, &ed25519_dalek::Signature::from_bytes(&sig))
+                    .is_ok()
+            })
+        }
+        (PublicKey::EcdsaP256(point), EcdsaSha256 | EcdsaSha384) => {
+            let Some(sig) = ecdsa_fixed(signature, 32) else {
+                return false;
+            };
+            match (
+                p256::ecdsa::VerifyingKey::from_sec1_bytes(point),
+                p256::ecdsa::Signature::from_slice(&sig),
+            ) {
+                (Ok(key), Ok(sig)) => key.verify_prehash(&digest(scheme, message), &sig).is_ok(),
+                _ => false,
+            }
+        }
+        (PublicKey::EcdsaP384(point), EcdsaSha256 | EcdsaSha384) => {
+            let Some(sig) = ecdsa_fixed(signature, 48) else {
+                return false;
+            };
+            match (
+                p384::ecdsa::VerifyingKey::from_sec1_bytes(point),
+                p384::ecdsa::Signature::from_slice(&sig),
+            ) {
+                (Ok(key), Ok(sig)) => key.verify_prehash(&digest(scheme, message), &sig).is_ok(),
+                _ => false,
+            }
+        }
+        (
+            PublicKey::Rsa(der),
+            RsaPkcs1Sha256 | RsaPkcs1Sha384 | RsaPkcs1Sha512 | RsaPssSha256 | RsaPssSha384
+            | RsaPssSha512,
+        ) => {
+            let Ok(key) = rsa::RsaPublicKey::from_pkcs1_der(der) else {
+                return false;
+            };
+            if rsa::traits::PublicKeyParts::size(&key) < MIN_RSA_BYTES {
+                return false;
+            }
+            let hashed = digest(scheme, message);
+            let result = match scheme {
+                RsaPkcs1Sha256 => key.verify(rsa::Pkcs1v15Sign::new::<Sha256>(), &hashed, signature),
+                RsaPkcs1Sha384 => key.verify(rsa::Pkcs1v15Sign::new::<Sha384>(), &hashed, signature),
+                RsaPkcs1Sha512 => key.verify(rsa::Pkcs1v15Sign::new::<Sha512>(), &hashed, signature),
+                RsaPssSha256 => key.verify(rsa::Pss::new::<Sha256>(), &hashed, signature),
+                RsaPssSha384 => key.verify(rsa::Pss::new::<Sha384>(), &hashed, signature),
+                _ => key.verify(rsa::Pss::new::<Sha512>(), &hashed, signature),
+            };
+            result.is_ok()
+        }
+        _ => false,
+    }
+}
+
+// ============================================================================
+// Certificates
+// ============================================================================
+
+/// Unix seconds of a civil date (UTC)
+fn unix_seconds(year: u64, month: u64, day: u64, hour: u64, min: u64, sec: u64) -> u64 {
+    // Days from 1970-01-01, counting years from March so leap days come last
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y / 400;
+    let yoe = y % 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    days * 86_400 + hour * 3600 + min * 60 + sec
+}
+
+/// UTCTime (`YYMMDDHHMMSSZ`) or GeneralizedTime (`YYYYMMDDHHMMSSZ`)
+fn parse_time(der: &mut Der) -> Option<u64> {
+    let (tag, text, _) = der.any()?;
+    let digits = match tag {
+        TAG_UTC_TIME if text.len() == 13 => &text[..12],
+        TAG_GENERALIZED_TIME if text.len() == 15 => &text[..14],
+        _ => return None,
+    };
+    if text.last() != Some(&b'Z') || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let num = |i: usize| (digits[i] - b'0') as u64 * 10 + (digits[i + 1] - b'0') as u64;
+    let (year, rest) = if tag == TAG_UTC_TIME {
+        // RFC 5280: 50-99 are 19xx
+        let yy = num(0);
+        (if yy >= 50 { 1900 + yy } else { 2000 + yy }, 2)
+    } else {
+        (num(0) * 100 + num(2), 4)
+    };
+    let (month, day) = (num(rest), num(rest + 2));
+    let (hour, min, sec) = (num(rest + 4), num(rest + 6), num(rest + 8));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60
+    {
+        return None;
+    }
+    Some(unix_seconds(year, month, day, hour, min, sec))
+}
+
+/// AlgorithmIdentifier of a certificate signature
+fn parse_signature_algorithm(der: &mut Der) -> Option<Option<SignatureScheme>> {
+    let mut alg = Der::new(der.read(TAG_SEQUENCE)?);
+    let oid = alg.read(TAG_OID)?;
+    Some(match oid {
+        OID_SHA256_WITH_RSA => Some(SignatureScheme::RsaPkcs1Sha256),
+        OID_SHA384_WITH_RSA => Some(SignatureScheme::RsaPkcs1Sha384),
+        OID_SHA512_WITH_RSA => Some(SignatureScheme::RsaPkcs1Sha512),
+        OID_ECDSA_SHA256 => Some(SignatureScheme::EcdsaSha256),
+        OID_ECDSA_SHA384 => Some(SignatureScheme::EcdsaSha384),
+        OID_ED25519 => Some(SignatureScheme::Ed25519),
+        _ => None,
+    })
+}
+
+/// SubjectPublicKeyInfo contents; None for unsupported key types
+fn parse_public_key(spki: &[u8]) -> Option<PublicKey<'_>> {
+    let mut spki = Der::new(spki);
+    let mut alg = Der::new(spki.read(TAG_SEQUENCE)?);
+    let bits = spki.read(TAG_BIT_STRING)?;
+    // No unused bits in a key
+    let (&0, key) = bits.split_first()? else {
+        return None;
+    };
+    match alg.read(TAG_OID)? {
+        OID_ED25519 => Some(PublicKey::Ed25519(key)),
+        OID_RSA_ENCRYPTION => Some(PublicKey::Rsa(key)),
+        OID_EC_PUBLIC_KEY => match alg.read(TAG_OID)? {
+            OID_P256 => Some(PublicKey::EcdsaP256(key)),
+            OID_P384 => Some(PublicKey::EcdsaP384(key)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Fields of a parsed certificate, borrowing from its DER encoding
+#[derive(Debug, Clone)]
+pub struct Certificate<'a> {
+    pub der: &'a [u8],
+    /// Signed part (TBSCertificate, whole encoding)
+    tbs: &'a [u8],
+    /// None for algorithms we can't check
+    signature_scheme: Option<SignatureScheme>,
+    signature: &'a [u8],
+    pub issuer: &'a [u8],
+    pub subject: &'a [u8],
+    /// Validity period, Unix seconds
+    pub not_before: u64,
+    pub not_after: u64,
+    /// Whole SubjectPublicKeyInfo, which pins hash
+    pub spki: &'a [u8],
+    /// None for unsupported key types
+    pub public_key: Option<PublicKey<'a>>,
+    pub is_ca: bool,
+    /// Intermediates allowed below this CA
+    pub path_len: Option<usize>,
+    /// keyUsage allows keyCertSign (or there is no keyUsage)
+    pub can_sign_certs: bool,
+    /// GeneralNames of the subjectAltName extension
+    alt_names: &'a [u8],
+}
+
+impl<'a> Certificate<'a> {
+    pub fn parse(der: &'a [u8]) -> KResult<Self> {
+        Self::parse_der(der).ok_or(KError::with_context(ErrorKind::Protocol, "x509 certificate"))
+    }
+
+    fn parse_der(der: &'a [u8]) -> Option<Self> {
+        let mut outer = Der::new(der);
+        let mut cert = Der::new(outer.read(TAG_SEQUENCE)?);
+        if !outer.is_empty() {
+            return None;
+        }
+        let tbs = cert.read_raw(TAG_SEQUENCE)?;
+        let signature_scheme = parse_signature_algorithm(&mut cert)?;
+        let (&0, signature) = cert.read(TAG_BIT_STRING)?.split_first()? else {
+            return None;
+        };
+
+        let mut fields = Der::new(Der::new(tbs).read(TAG_SEQUENCE)?);
+        let version = match fields.optional(explicit(0)) {
+            Some(v) => *Der::new(v).read(TAG_INTEGER)?.first()?,
+            None => 0,
+        };
+        fields.read(TAG_INTEGER)?; // serial number
+        // Must repeat the outer algorithm
+        if parse_signature_algorithm(&mut fields)? != signature_scheme {
+            return None;
+        }
+        let issuer = fields.read_raw(TAG_SEQUENCE)?;
+        let mut validity = Der::new(fields.read(TAG_SEQUENCE)?);
+        let not_before = parse_time(&mut validity)?;
+        let not_after = parse_time(&mut validity)?;
+        let subject = fields.read_raw(TAG_SEQUENCE)?;
+        let spki_raw = fields.read_raw(TAG_SEQUENCE)?;
+        let public_key = parse_public_key(Der::new(spki_raw).read(TAG_SEQUENCE)?);
+        fields.optional(implicit(1)); // issuerUniqueID
+        fields.optional(implicit(2)); // subjectUniqueID
+
+        let mut cert = Self {
+            der,
+            tbs,
+            signature_scheme,
+            signature,
+            issuer,
+            subject,
+            not_before,
+            not_after,
+            spki: spki_raw,
+            public_key,
+            is_ca: false,
+            path_len: None,
+            can_sign_certs: true,
+            alt_names: &[],
+        };
+        if let Some(extensions) = fields.optional(explicit(3)) {
+            if version != 2 {
+                return None;
+            }
+            cert.parse_extensions(Der::new(extensions).read(TAG_SEQUENCE)?)?;
+        }
+        fields.is_empty().then_some(cert)
+    }
+
+    fn parse_extensions(&mut self, extensions: &'a [u8]) -> Option<()> {
+        let mut extensions = Der::new(extensions);
+        while !extensions.is_empty() {
+            let mut ext = Der::new(extensions.read(TAG_SEQUENCE)?);
+            let oid = ext.read(TAG_OID)?;
+            let critical = ext.optional(TAG_BOOLEAN).is_some_and(|b| b == [0xff]);
+            let mut value = Der::new(ext.read(TAG_OCTET_STRING)?);
+            match oid {
+                OID_BASIC_CONSTRAINTS => {
+                    let mut bc = Der::new(value.read(TAG_SEQUENCE)?);
+                    self.is_ca = bc.optional(TAG_BOOLEAN).is_some_and(|b| b == [0xff]);
+                    if let Some(len) = bc.optional(TAG_INTEGER) {
+                        let &[len] = len else {
+                            return None;
+                        };
+                        self.path_len = Some(len as usize);
+                    }
+                }
+                OID_KEY_USAGE => {
+                    // keyCertSign is bit 5 of the first byte
+                    let bits = value.read(TAG_BIT_STRING)?;
+                    self.can_sign_certs = bits.get(1).is_some_and(|b| b & 0x04 != 0);
+                }
+                OID_SUBJECT_ALT_NAME => {
+                    self.alt_names = value.read(TAG_SEQUENCE)?;
+                }
+                _ if critical => return None,
+                _ => {}
+            }
+        }
+        Some(())
+    }
+
+    /// SHA-256 of the SubjectPublicKeyInfo, what `tls.pins` lists
+    pub fn spki_sha256(&self) -> [u8; 32] {
+        Sha256::digest(self.spki).into()
+    }
+
+    pub fn valid_at(&self, unix_seconds: u64) -> bool {
+        (self.not_before..=self.not_after).contains(&unix_seconds)
+    }
+
+    /// Was this certificate signed by `issuer`'s key?
+    pub fn signed_by(&self, issuer: &Certificate) -> bool {
+        match (self.signature_scheme, issuer.public_key) {
+            (Some(scheme), Some(key)) => verify_signature(&key, scheme, self.tbs, self.signature),
+            _ => false,
+        }
+    }
+
+    /// Does a dNSName entry cover `host`? `*.example.com` matches exactly
+    /// one label.
+    pub fn has_dns_name(&self, host: &str) -> bool {
+        self.alt_names(2).any(|name| {
+            let Ok(name) = core::str::from_utf8(name) else {
+                return false;
+            };
+            match name.strip_prefix("*.") {
+                Some(suffix) => host
+                    .split_once('.')
+                    .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(suffix)),
+                None => name.eq_ignore_ascii_case(host),
+            }
+        })
+    }
+
+    /// Does an iPAddress entry hold `addr`?
+    pub fn has_ip(&self, addr: [u8; 4]) -> bool {
+        self.alt_names(7).any(|ip| ip == addr)
+    }
+
+    /// subjectAltName entries of GeneralName choice `[kind]`
+    fn alt_names(&self, kind: u8) -> impl Iterator<Item = &'a [u8]> {
+        let mut names = Der::new(self.alt_names);
+        core::iter::from_fn(move || names.any()).filter_map(move |(tag, name, _)| {
+            (tag == implicit(kind)).then_some(name)
+        })
+    }
+}
+
+// ============================================================================
+// Path Validation
+// ============================================================================
+
+/// What a trusted chain ended at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anchor {
+    /// One of the root certificates; the leaf's names still need checking
+    Root,
+    /// A pinned key, which identifies the server by itself
+    Pin,
+}
+
+fn untrusted(what: &'static str) -> KError {
+    KError::with_context(ErrorKind::AuthFailed, what)
+}
+
+/// Check a server's chain (leaf first, as TLS sends it) against `roots`
+/// (DER certificates) and `pins` (SPKI SHA-256 hashes)
+///
+/// `now` is the time in Unix seconds, if known; validity periods aren't
+/// checked without it. Extra certificates in the chain are ignored.
+pub fn verify_chain(
+    chain: &[&[u8]],
+    roots: &[Vec<u8>],
+    pins: &[[u8; 32]],
+    now: Option<u64>,
+) -> KResult<Anchor> {
+    let certs = chain
+        .iter()
+        .map(|der| Certificate::parse(der))
+        .collect::<KResult<Vec<_>>>()?;
+    let roots = roots
+        .iter()
+        .filter_map(|der| Certificate::parse(der).ok())
+        .collect::<Vec<_>>();
+    let current_time = |cert: &Certificate| now.is_none_or(|now| cert.valid_at(now));
+
+    let mut current = certs.first().ok_or(untrusted("empty certificate chain"))?;
+    for depth in 0..MAX_CHAIN_DEPTH {
+        if !current_time(current) {
+            return Err(untrusted("certificate expired or not yet valid"));
+        }
+        if pins.contains(&current.spki_sha256()) {
+            return Ok(Anchor::Pin);
+        }
+        // `depth` certificates below `current` are intermediates
+        let may_issue = |issuer: &Certificate| {
+            issuer.subject == current.issuer
+                && issuer.is_ca
+                && issuer.can_sign_certs
+                && issuer.path_len.is_none_or(|max| depth <= max)
+                && current.signed_by(issuer)
+        };
+        if roots
+            .iter()
+            .any(|root| root.der == current.der || (current_time(root) && may_issue(root)))
+        {
+            return Ok(Anchor::Root);
+        }
+        current = certs[1..]
+            .iter()
+            .find(|issuer| may_issue(issuer))
+            .ok_or(untrusted("certificate issuer not trusted"))?;
+    }
+    Err(KError::with_context(ErrorKind::LimitReached, "certificate chain"))
+}